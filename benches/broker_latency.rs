@@ -0,0 +1,97 @@
+// Round-trip latency through the broker over inproc, default vs low-latency
+// mode. The client and worker share one identity so the broker's
+// identity-based routing delivers the request to the worker and the reply
+// back to the client.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use corky_zmq::broker::run_broker;
+use corky_zmq::config::{Config, LatencyMode};
+use corky_zmq::runtime::Runtime;
+
+const PEER_ID: &[u8] = b"bench";
+
+struct Harness {
+    client: zmq::Socket,
+    worker: zmq::Socket,
+    shutdown: Arc<AtomicBool>,
+    broker: Option<thread::JoinHandle<()>>,
+}
+
+impl Harness {
+    fn start(mode: LatencyMode, tag: &str) -> Self {
+        let context = zmq::Context::new();
+        let mut config = Config::default();
+        config.broker.latency_mode = mode;
+        config.network.client_to_client_endpoint = format!("inproc://{tag}-direct");
+        config.network.client_facing_endpoint = format!("inproc://{tag}-client");
+        config.network.worker_facing_endpoint = format!("inproc://{tag}-worker");
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let broker = {
+            let (context, config, shutdown) =
+                (context.clone(), Arc::new(config.clone()), shutdown.clone());
+            thread::spawn(move || {
+                run_broker(&context, &config, &Runtime::new(&config), &shutdown).expect("broker")
+            })
+        };
+        // inproc connect needs the bind to exist first.
+        thread::sleep(std::time::Duration::from_millis(100));
+
+        let client = context.socket(zmq::DEALER).unwrap();
+        client.set_identity(PEER_ID).unwrap();
+        client
+            .connect(&config.network.client_facing_endpoint)
+            .unwrap();
+        let worker = context.socket(zmq::DEALER).unwrap();
+        worker.set_identity(PEER_ID).unwrap();
+        worker
+            .connect(&config.network.worker_facing_endpoint)
+            .unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+
+        Self {
+            client,
+            worker,
+            shutdown,
+            broker: Some(broker),
+        }
+    }
+
+    fn round_trip(&self, payload: &[u8]) {
+        self.client.send(payload, 0).unwrap();
+        let request = self.worker.recv_bytes(0).unwrap();
+        self.worker
+            .send_multipart([PEER_ID, request.as_slice()], 0)
+            .unwrap();
+        // The broker echoes the reply to the worker before forwarding it.
+        self.worker.recv_multipart(0).unwrap();
+        self.client.recv_bytes(0).unwrap();
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.broker.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn bench_round_trip(c: &mut Criterion) {
+    let payload = br#"{"type":"quote","symbol":"BTCUSD","bid":67012.5,"ask":67013.0}"#;
+    let mut group = c.benchmark_group("broker_round_trip");
+    for (name, mode) in [("default", LatencyMode::Default), ("low", LatencyMode::Low)] {
+        let harness = Harness::start(mode, name);
+        group.bench_function(name, |b| b.iter(|| harness.round_trip(payload)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use corky_zmq::format::{crop_value, format_message, format_part, Envelope, RenderStyle};
+
+mod support;
+
+fn bench_format_part(c: &mut Criterion) {
+    let mut group = c.benchmark_group("format_part");
+    let cases = [
+        ("json_rows_1mb", support::json_rows_1mb()),
+        ("binary_blob_1mb", support::binary_blob_1mb()),
+        ("object_2kb", support::object_2kb()),
+    ];
+    for (name, payload) in &cases {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), payload, |b, p| {
+            b.iter(|| format_part(black_box(p), RenderStyle::Compact))
+        });
+    }
+    group.finish();
+}
+
+fn bench_crop_value(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crop_value");
+    let deep = support::deep_value(64);
+    let wide = support::wide_value(200, 500);
+    group.bench_function("deep_64_levels", |b| {
+        b.iter(|| crop_value(black_box(&deep), 0))
+    });
+    group.bench_function("wide_200x500", |b| {
+        b.iter(|| crop_value(black_box(&wide), 0))
+    });
+    group.finish();
+}
+
+fn bench_format_message(c: &mut Criterion) {
+    let envelope = support::broker_envelope();
+    c.bench_function("format_message/broker_envelope_4_frames", |b| {
+        b.iter(|| format_message(black_box(&envelope), Envelope::Routed, RenderStyle::Compact))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_format_part,
+    bench_crop_value,
+    bench_format_message
+);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+// Per-message cost of the metrics hot path: one counter bump and one
+// histogram observation, as done in SocketChannel::recv.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use corky_zmq::metrics::{Registry, SIZE_BUCKETS};
+
+fn bench_metrics(c: &mut Criterion) {
+    let registry = Registry::new();
+    let counter = registry.counter("bench_total", &[("socket", "bench")]);
+    let histogram = registry.histogram("bench_bytes", &[("socket", "bench")], SIZE_BUCKETS);
+
+    let mut group = c.benchmark_group("metrics");
+    group.bench_function("counter_inc", |b| b.iter(|| black_box(&counter).inc()));
+    group.bench_function("histogram_observe", |b| {
+        let mut size = 0u64;
+        b.iter(|| {
+            size = (size + 4099) % (8 * 1024 * 1024);
+            black_box(&histogram).observe(size)
+        })
+    });
+    group.bench_function("per_message", |b| {
+        b.iter(|| {
+            counter.inc();
+            histogram.observe(black_box(1500));
+        })
+    });
+    group.bench_function("snapshot", |b| b.iter(|| registry.snapshot()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_metrics);
+criterion_main!(benches);
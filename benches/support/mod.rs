@@ -0,0 +1,86 @@
+// Fixture builders shared by the criterion benches and tests/bench_smoke.rs.
+//
+// The checked-in fixtures stay small; the 1MB payloads are grown from them
+// deterministically so every run measures the same bytes.
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+
+pub const OHLCV_ROWS: &str = include_str!("../../tests/fixtures/ohlcv_rows.json");
+pub const ORDER_OBJECT: &str = include_str!("../../tests/fixtures/order_object.json");
+pub const ENVELOPE_HEADER: &str = include_str!("../../tests/fixtures/envelope_header.json");
+
+const ONE_MB: usize = 1024 * 1024;
+
+// Five-byte libzmq-style ROUTER identity (leading zero byte, random tail).
+pub const ROUTER_IDENTITY: [u8; 5] = [0x00, 0x6b, 0x08, 0x41, 0x65];
+
+// ~1MB JSON document shaped like an OHLCV response: a few header keys plus a
+// long array-of-arrays built by repeating the fixture rows.
+pub fn json_rows_1mb() -> Vec<u8> {
+    let fixture: Value = serde_json::from_str(OHLCV_ROWS).expect("ohlcv fixture");
+    let rows = fixture["data"].as_array().expect("ohlcv rows").clone();
+    let row_len = serde_json::to_vec(&rows[0])
+        .map(|r| r.len() + 1)
+        .unwrap_or(64);
+
+    let mut data = Vec::with_capacity(ONE_MB / row_len + rows.len());
+    while data.len() * row_len < ONE_MB {
+        data.extend(rows.iter().cloned());
+    }
+    let mut doc = fixture;
+    doc["data"] = Value::Array(data);
+    serde_json::to_vec(&doc).expect("serialize rows")
+}
+
+// 1MB of deterministic non-UTF8 bytes (xorshift), so format_part falls all
+// the way through to the byte-preview branch.
+pub fn binary_blob_1mb() -> Vec<u8> {
+    let mut state: u32 = 0x9e37_79b9;
+    let mut out = Vec::with_capacity(ONE_MB);
+    out.push(0xff); // never valid UTF-8
+    while out.len() < ONE_MB {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        out.extend_from_slice(&state.to_le_bytes());
+    }
+    out.truncate(ONE_MB);
+    out
+}
+
+pub fn object_2kb() -> Vec<u8> {
+    ORDER_OBJECT.as_bytes().to_vec()
+}
+
+// Narrow but deep: each level is a small object holding a short array and the
+// next level.
+pub fn deep_value(depth: usize) -> Value {
+    let mut v = json!({ "leaf": true, "values": [1, 2, 3] });
+    for level in 0..depth {
+        v = json!({ "level": level, "items": [level, level + 1], "child": v });
+    }
+    v
+}
+
+// Shallow but wide: many top-level keys, each holding a long scalar array.
+pub fn wide_value(keys: usize, array_len: usize) -> Value {
+    let mut map = serde_json::Map::with_capacity(keys);
+    for k in 0..keys {
+        let arr: Vec<Value> = (0..array_len).map(|i| json!(i as f64 * 0.5)).collect();
+        map.insert(format!("series_{k:03}"), Value::Array(arr));
+    }
+    Value::Object(map)
+}
+
+// Realistic 4-frame broker envelope: ROUTER identity, empty delimiter, JSON
+// header, JSON payload.
+pub fn broker_envelope() -> Vec<Vec<u8>> {
+    vec![
+        ROUTER_IDENTITY.to_vec(),
+        Vec::new(),
+        ENVELOPE_HEADER.trim_end().as_bytes().to_vec(),
+        OHLCV_ROWS.as_bytes().to_vec(),
+    ]
+}
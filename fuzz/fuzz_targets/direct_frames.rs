@@ -0,0 +1,32 @@
+// Multipart messages as they arrive on the direct (client-to-client) socket:
+// the chunk header lookup, the schedule header after [target], and receiver
+// side reassembly of whatever claims to be a chunk. Each frame is prefixed by
+// a one-byte length.
+#![no_main]
+
+use std::time::{Instant, SystemTime};
+
+use corky_zmq::chunk::{ChunkAssembler, ChunkHeader};
+use corky_zmq::schedule::ScheduleSpec;
+use libfuzzer_sys::fuzz_target;
+
+fn split_frames(mut data: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let len = (len as usize).min(rest.len());
+        frames.push(rest[..len].to_vec());
+        data = &rest[len..];
+    }
+    frames
+}
+
+fuzz_target!(|data: &[u8]| {
+    let message = split_frames(data);
+    let _ = ChunkHeader::find(&message);
+    if let Some(Ok(spec)) = message.get(1).and_then(|frame| ScheduleSpec::parse(frame)) {
+        let _ = spec.deadline(Instant::now(), SystemTime::now());
+    }
+    let mut assembler = ChunkAssembler::new();
+    let _ = assembler.push(message.clone());
+    let _ = assembler.push(message);
+});
@@ -0,0 +1,21 @@
+// The log formatter on arbitrary frames: one frame as-is, then the input cut
+// into a multipart message (each frame prefixed by a one-byte length).
+#![no_main]
+
+use corky_zmq::format::{format_message, format_part};
+use libfuzzer_sys::fuzz_target;
+
+fn split_frames(mut data: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let len = (len as usize).min(rest.len());
+        frames.push(rest[..len].to_vec());
+        data = &rest[len..];
+    }
+    frames
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = format_part(data);
+    let _ = format_message(&split_frames(data));
+});
@@ -0,0 +1,18 @@
+// The journal reader on arbitrary segment files, and the record decoder on
+// arbitrary record payloads. Inputs that do not start with the segment magic
+// get it prepended, so most runs get past the header.
+#![no_main]
+
+use corky_zmq::journal::{scan_segment, JournalRecord, SEGMENT_MAGIC};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = JournalRecord::decode_payload(0, data);
+    let _ = scan_segment(data, "fuzz", None);
+    if !data.starts_with(SEGMENT_MAGIC) {
+        let mut segment = SEGMENT_MAGIC.to_vec();
+        segment.extend_from_slice(&1u32.to_le_bytes());
+        segment.extend_from_slice(data);
+        let _ = scan_segment(&segment, "fuzz", None);
+    }
+});
@@ -0,0 +1,44 @@
+// Every header the broker parses out of a single client frame: chunk headers
+// and errors, fan-out specs and tags, hellos, compression, peer-table keys, and
+// schedule headers and cancels. Parsed specs are also put to use, since the
+// panics found so far were in what the broker does with a parsed value.
+#![no_main]
+
+use std::time::{Instant, SystemTime};
+
+use corky_zmq::chunk::{parse_chunk_error, ChunkHeader};
+use corky_zmq::compress::{advertises_compression, decompress_frame};
+use corky_zmq::fanout::{parse_tag, FanoutSpec, ScatterGather};
+use corky_zmq::hello::{negotiate, Capabilities, Capability, Offer};
+use corky_zmq::metrics::Registry;
+use corky_zmq::peers::split_key;
+use corky_zmq::schedule::{parse_cancel, ScheduleSpec};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let now = Instant::now();
+    let _ = ChunkHeader::parse(data);
+    let _ = parse_chunk_error(data);
+    let _ = parse_tag(data);
+    if let Some(Ok(spec)) = FanoutSpec::parse(data) {
+        let metrics = Registry::new();
+        let mut sg = ScatterGather::new(&metrics);
+        sg.workers.add(b"w1");
+        sg.workers.add(b"w2");
+        let _ = sg.start(b"fuzz", spec, &[data.to_vec()], now, |_| Ok(()));
+        let _ = sg.expire(now);
+    }
+    let offer = Offer {
+        features: Capabilities::of(&Capability::ALL),
+        max_message_bytes: 1 << 20,
+        max_frame_bytes: 1 << 20,
+    };
+    let _ = negotiate(Some(data), &offer);
+    let _ = advertises_compression(Some(data));
+    let _ = decompress_frame(data, 1 << 20);
+    let _ = split_key(data);
+    if let Some(Ok(spec)) = ScheduleSpec::parse(data) {
+        let _ = spec.deadline(now, SystemTime::now());
+    }
+    let _ = parse_cancel(data);
+});
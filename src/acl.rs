@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::config::PrincipalAcl;
+
+//
+// ------------------------------ Topic ACLs -----------------------------------
+//
+// Per-principal topic prefixes for the pub/sub plane. The principal is the
+// ZAP User-Id of the connection a message arrived on (see crate::zap), which
+// libzmq attaches to every message as metadata, so the proxy can attribute
+// each publication on XSUB and each subscription on XPUB to its peer.
+//
+// A subscription is allowed when its topic starts with one of the
+// principal's subscribe prefixes; subscribing to "" (everything) therefore
+// needs "" in the list. Publications are checked the same way against the
+// publish prefixes, using the first frame as the topic. Principals without
+// an entry fall back to "*"; with no "*" entry they may do nothing.
+// Unauthenticated peers have the empty principal.
+//
+// Rules are shared with the admin socket and copied into the proxy thread
+// whenever their version changes, like the sample rules.
+
+pub const DEFAULT_PRINCIPAL: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Subscribe,
+    Publish,
+}
+
+impl AclAction {
+    pub fn label(self) -> &'static str {
+        match self {
+            AclAction::Subscribe => "subscribe",
+            AclAction::Publish => "publish",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AclRules {
+    principals: BTreeMap<String, PrincipalAcl>,
+}
+
+impl AclRules {
+    pub fn new(principals: BTreeMap<String, PrincipalAcl>) -> Self {
+        Self { principals }
+    }
+
+    pub fn allows(&self, principal: &str, action: AclAction, topic: &[u8]) -> bool {
+        let Some(entry) = self
+            .principals
+            .get(principal)
+            .or_else(|| self.principals.get(DEFAULT_PRINCIPAL))
+        else {
+            return false;
+        };
+        let prefixes = match action {
+            AclAction::Subscribe => &entry.subscribe,
+            AclAction::Publish => &entry.publish,
+        };
+        prefixes.iter().any(|p| topic.starts_with(p.as_bytes()))
+    }
+
+    // One line per principal, for the admin `acl list` command.
+    pub fn describe(&self) -> Vec<String> {
+        self.principals
+            .iter()
+            .map(|(principal, entry)| {
+                format!(
+                    "{} subscribe={:?} publish={:?}",
+                    principal, entry.subscribe, entry.publish
+                )
+            })
+            .collect()
+    }
+}
+
+// Each principal's subscribe and publish prefixes, replaced by the admin
+// `acl reload` command and read by the proxy when `version` moves.
+#[derive(Default)]
+pub struct Acl {
+    version: AtomicU64,
+    rules: Mutex<AclRules>,
+}
+
+impl Acl {
+    pub fn new(rules: AclRules) -> Self {
+        Self {
+            version: AtomicU64::new(0),
+            rules: Mutex::new(rules),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn rules(&self) -> AclRules {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, rules: AclRules) {
+        *self.rules.lock().unwrap_or_else(|e| e.into_inner()) = rules;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+// The proxy thread's copy of the rules.
+pub struct AclView {
+    version: u64,
+    rules: AclRules,
+}
+
+impl AclView {
+    pub fn new(shared: &Acl) -> Self {
+        Self {
+            version: shared.version(),
+            rules: shared.rules(),
+        }
+    }
+
+    pub fn allows(
+        &mut self,
+        shared: &Acl,
+        principal: &str,
+        action: AclAction,
+        topic: &[u8],
+    ) -> bool {
+        let version = shared.version();
+        if version != self.version {
+            self.version = version;
+            self.rules = shared.rules();
+        }
+        self.rules.allows(principal, action, topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(subscribe: &[&str], publish: &[&str]) -> PrincipalAcl {
+        PrincipalAcl {
+            subscribe: subscribe.iter().map(|s| s.to_string()).collect(),
+            publish: publish.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn rules() -> AclRules {
+        let mut principals = BTreeMap::new();
+        principals.insert(
+            "alice".to_string(),
+            entry(&["alice.", "public."], &["alice."]),
+        );
+        principals.insert("*".to_string(), entry(&["public."], &[]));
+        AclRules::new(principals)
+    }
+
+    #[test]
+    fn prefixes_bound_each_principal() {
+        let rules = rules();
+        assert!(rules.allows("alice", AclAction::Subscribe, b"alice.orders"));
+        assert!(rules.allows("alice", AclAction::Publish, b"alice.orders"));
+        assert!(!rules.allows("alice", AclAction::Publish, b"public.news"));
+        // Subscribing to everything would include other namespaces.
+        assert!(!rules.allows("alice", AclAction::Subscribe, b""));
+        assert!(!rules.allows("alice", AclAction::Subscribe, b"alic"));
+    }
+
+    #[test]
+    fn unknown_principals_use_the_default_entry() {
+        let rules = rules();
+        assert!(rules.allows("bob", AclAction::Subscribe, b"public.news"));
+        assert!(!rules.allows("bob", AclAction::Subscribe, b"alice.orders"));
+        assert!(!rules.allows("", AclAction::Publish, b"public.news"));
+        assert!(!AclRules::default().allows("alice", AclAction::Subscribe, b"x"));
+    }
+
+    #[test]
+    fn view_picks_up_replaced_rules() {
+        let shared = Acl::new(AclRules::default());
+        let mut view = AclView::new(&shared);
+        assert!(!view.allows(&shared, "alice", AclAction::Publish, b"alice.x"));
+        shared.replace(rules());
+        assert!(view.allows(&shared, "alice", AclAction::Publish, b"alice.x"));
+        assert_eq!(shared.rules().describe().len(), 2);
+    }
+}
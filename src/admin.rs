@@ -0,0 +1,656 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, warn};
+
+use crate::acl::AclRules;
+use crate::chaos::{Direction, Fault};
+use crate::config::{find_config_path, load_config_from, Config};
+use crate::ingress::PEER_ADDRESS;
+use crate::limits::socket_name;
+use crate::logfilter::{self, parse_level, Levels, COMPONENTS};
+use crate::metrics::render_prometheus;
+use crate::multipart::Multipart;
+use crate::quota::now_ms;
+use crate::runtime::Runtime;
+use crate::selftest;
+
+const ADMIN_POLL_TIMEOUT_MS: i64 = 100; // shutdown check interval
+
+//
+// ------------------------------ Admin socket ---------------------------------
+//
+// A REP socket taking one-line text commands and answering with text. It runs
+// on its own thread and only touches state shared through Runtime, so it never
+// stalls the proxy or broker loops.
+
+const HELP: &str = "\
+commands:
+  help                       this text
+  stats                      metrics in Prometheus text format, after the
+                             routing ids of the broker's connecting sockets
+  sample list                show traffic sample rules
+  sample set <prefix> <rate> sample publications whose topic starts with <prefix>
+  sample clear [<prefix>]    remove one or all sample rules
+  acl list                   show the topic ACL of each principal
+  acl reload [<path>]        replace the ACLs with [acl] from the config file
+                             the service was started from
+  topics rules list          show the proxy's topic allowlist and rewrites
+  topics allow add <prefix>  forward publications on <prefix> (with no allow
+                             rules everything is forwarded)
+  topics allow remove <prefix>
+                             stop forwarding <prefix>
+  topics rewrite add <from> <to>
+                             forward publications on <from>... as <to>...
+  topics rewrite remove <from>
+                             drop the rewrite of <from>
+  quota show <principal>     requests used and left in the principal's quota
+  quota grant <principal> <n>
+                             allow <n> requests beyond the quota
+  schedule list              show messages waiting for their delivery time
+  schedule cancel <id>       drop the scheduled messages with cancel_id <id>
+  quiesce                    refuse new connections while existing ones drain
+  unquiesce                  accept new connections again
+  health                     \"ok\", \"near_limit\" or \"draining\", then the
+                             use of each connection limit
+  limits                     show connection limits and counts
+  limits socket <name> <max> cap connections to a broker socket (0: no cap)
+  limits address <max>       cap connections from one address (0: no cap)
+  chaos list                 show the fault injection rates
+  chaos set <dir> <fault> <p>
+                             inject <fault> (drop, duplicate, malformed or
+                             delay) into <dir> (requests or replies) with
+                             probability <p>
+  chaos delay <dir> <min_ms> <max_ms>
+                             range of injected delays
+  chaos off                  stop injecting faults
+  log level                  show the log level, then each component's own
+  log level <level>          log every component at <level> (trace, debug,
+                             info, warn or error) until a reload or restart
+  log level <component> <level>
+                             log one component, e.g. broker, at <level>
+  selftest                   exercise every enabled feature as a client would
+                             and report each check as JSON";
+
+pub fn handle_command(runtime: &Runtime, line: &str) -> String {
+    handle_command_from(runtime, line, None)
+}
+
+// handle_command for a request from `peer`, its Peer-Address, which commands
+// that change the log levels name in their warning.
+pub fn handle_command_from(runtime: &Runtime, line: &str, peer: Option<&str>) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let result = match words.as_slice() {
+        [] | ["help"] => Ok(HELP.to_string()),
+        ["stats"] => Ok(stats(runtime)),
+        ["sample", rest @ ..] => sample_command(runtime, rest),
+        ["acl", rest @ ..] => acl_command(runtime, rest),
+        ["topics", rest @ ..] => topics_command(runtime, rest),
+        ["quota", rest @ ..] => quota_command(runtime, rest),
+        ["schedule", rest @ ..] => schedule_command(runtime, rest),
+        ["quiesce"] => Ok(set_draining(runtime, true)),
+        ["unquiesce"] => Ok(set_draining(runtime, false)),
+        ["health"] => Ok(health(runtime)),
+        ["limits", rest @ ..] => limits_command(runtime, rest),
+        ["chaos", rest @ ..] => chaos_command(runtime, rest),
+        ["log", rest @ ..] => log_command(rest, peer),
+        _ => Err(format!("unknown command {:?}, try \"help\"", line.trim())),
+    };
+    match result {
+        Ok(text) => text,
+        Err(e) => format!("ERROR: {}", e),
+    }
+}
+
+// Identities go first as comments, which Prometheus parsers skip.
+fn stats(runtime: &Runtime) -> String {
+    let mut out = String::new();
+    for (socket, identity) in runtime.identities.list() {
+        out.push_str(&format!(
+            "# identity {} {}\n",
+            socket,
+            String::from_utf8_lossy(&identity)
+        ));
+    }
+    out.push_str(&render_prometheus(&runtime.metrics.snapshot()));
+    out
+}
+
+fn sample_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let rules = &runtime.sampler;
+    match args {
+        ["list"] => {
+            let lines: Vec<String> = rules
+                .list()
+                .iter()
+                .map(|r| format!("{} {}", String::from_utf8_lossy(&r.prefix), r.rate))
+                .collect();
+            Ok(if lines.is_empty() {
+                "no sample rules".to_string()
+            } else {
+                lines.join("\n")
+            })
+        }
+        ["set", prefix, rate] => {
+            let rate: f64 = rate
+                .parse()
+                .map_err(|_| format!("invalid sample rate {:?}", rate))?;
+            rules.set(prefix.as_bytes(), rate)?;
+            Ok("OK".to_string())
+        }
+        ["clear"] => {
+            rules.clear();
+            Ok("OK".to_string())
+        }
+        ["clear", prefix] => {
+            rules.set(prefix.as_bytes(), 0.0)?;
+            Ok("OK".to_string())
+        }
+        _ => {
+            Err("usage: sample list | sample set <prefix> <rate> | sample clear [<prefix>]".into())
+        }
+    }
+}
+
+// Reloading only replaces the rules; whether ACLs are enforced at all is
+// fixed when the proxy starts.
+fn acl_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    match args {
+        ["list"] => {
+            let lines = runtime.acl.rules().describe();
+            Ok(if lines.is_empty() {
+                "no ACL entries".to_string()
+            } else {
+                lines.join("\n")
+            })
+        }
+        ["reload", path @ ..] if path.len() <= 1 => {
+            let path = match path.first() {
+                Some(path) => PathBuf::from(path),
+                None => match &runtime.config_path {
+                    Some(path) => path.clone(),
+                    None => find_config_path()?,
+                },
+            };
+            let config = load_config_from(&path)?;
+            let principals = config.acl.principals.len();
+            runtime.acl.replace(AclRules::new(config.acl.principals));
+            info!(
+                "(Admin) Reloaded ACLs for {} principals from {}",
+                principals,
+                path.display()
+            );
+            Ok("OK".to_string())
+        }
+        _ => Err("usage: acl list | acl reload [<path>]".into()),
+    }
+}
+
+// Changes are saved to the [topics] state file before they take effect.
+fn topics_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let topics = &runtime.topics;
+    match args {
+        ["rules", "list"] => {
+            let lines = topics.rules().describe();
+            return Ok(if lines.is_empty() {
+                "no topic rules".to_string()
+            } else {
+                lines.join("\n")
+            });
+        }
+        ["allow", "add", prefix] => topics.update(|rules| rules.allow_add(prefix))?,
+        ["allow", "remove", prefix] => topics.update(|rules| rules.allow_remove(prefix))?,
+        ["rewrite", "add", from, to] => topics.update(|rules| rules.rewrite_add(from, to))?,
+        ["rewrite", "remove", from] => topics.update(|rules| rules.rewrite_remove(from))?,
+        _ => {
+            return Err(
+                "usage: topics rules list | topics allow add|remove <prefix> | \
+                        topics rewrite add <from> <to> | topics rewrite remove <from>"
+                    .into(),
+            )
+        }
+    }
+    info!(
+        "(Admin) Topic rules: {}",
+        topics.rules().describe().join("; ")
+    );
+    Ok("OK".to_string())
+}
+
+fn quota_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let quotas = &runtime.quotas;
+    if !quotas.enabled() {
+        return Err("quotas are not enabled".into());
+    }
+    match args {
+        ["show", principal] => quotas.show(principal, now_ms()),
+        ["grant", principal, requests] => {
+            let requests = requests
+                .parse::<u64>()
+                .map_err(|_| format!("invalid request count {:?}", requests))?;
+            quotas.grant(principal, requests)?;
+            info!(
+                "(Admin) Granted {} extra requests to principal {:?}",
+                requests, principal
+            );
+            Ok("OK".to_string())
+        }
+        _ => Err("usage: quota show <principal> | quota grant <principal> <n>".into()),
+    }
+}
+
+fn schedule_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let mut scheduler = runtime.scheduler.lock().unwrap_or_else(|e| e.into_inner());
+    match args {
+        ["list"] => {
+            let lines = scheduler.describe(Instant::now());
+            Ok(if lines.is_empty() {
+                "no scheduled messages".to_string()
+            } else {
+                lines.join("\n")
+            })
+        }
+        ["cancel", cancel_id] => Ok(format!("cancelled {}", scheduler.cancel(None, cancel_id))),
+        _ => Err("usage: schedule list | schedule cancel <cancel_id>".into()),
+    }
+}
+
+// The first line is the status a load balancer acts on.
+fn health(runtime: &Runtime) -> String {
+    let (usage, near_limit) = runtime.limits.usage();
+    let status = if runtime.quiesce.is_draining() || !near_limit {
+        runtime.quiesce.health()
+    } else {
+        "near_limit"
+    };
+    std::iter::once(status.to_string())
+        .chain(usage)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn limits_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let max = |value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("invalid limit {:?}", value))
+    };
+    match args {
+        [] | ["list"] => return Ok(runtime.limits.describe().join("\n")),
+        ["socket", name, value] => {
+            let socket = socket_name(name).ok_or_else(|| format!("unknown socket {:?}", name))?;
+            runtime.limits.set_socket(socket, max(value)?);
+        }
+        ["address", value] => runtime.limits.set_address(max(value)?),
+        _ => {
+            return Err("usage: limits | limits socket <name> <max> | limits address <max>".into())
+        }
+    }
+    info!(
+        "(Admin) Connection limits: {}",
+        runtime.limits.describe().join("; ")
+    );
+    Ok("OK".to_string())
+}
+
+// Only a service started with [chaos] enabled accepts changes.
+fn chaos_command(runtime: &Runtime, args: &[&str]) -> Result<String, String> {
+    let direction =
+        |name: &str| Direction::parse(name).ok_or_else(|| format!("unknown direction {:?}", name));
+    let number = |value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| format!("invalid delay {:?}", value))
+    };
+    match args {
+        ["list"] => return Ok(runtime.chaos.describe().join("\n")),
+        ["set", dir, fault, probability] => {
+            let fault = Fault::parse(fault).ok_or_else(|| format!("unknown fault {:?}", fault))?;
+            let probability = probability
+                .parse::<f64>()
+                .map_err(|_| format!("invalid probability {:?}", probability))?;
+            runtime.chaos.set(direction(dir)?, fault, probability)?;
+        }
+        ["delay", dir, min_ms, max_ms] => {
+            runtime
+                .chaos
+                .set_delay(direction(dir)?, number(min_ms)?, number(max_ms)?)?;
+        }
+        ["off"] => runtime.chaos.off()?,
+        _ => {
+            return Err("usage: chaos list | chaos set <dir> <fault> <p> | \
+                        chaos delay <dir> <min_ms> <max_ms> | chaos off"
+                .into())
+        }
+    }
+    warn!(
+        "(Admin) Chaos rates changed: {}",
+        runtime.chaos.describe().join("; ")
+    );
+    Ok("OK".to_string())
+}
+
+// The levels `log level ...` asks for, None when it only asks what they are.
+fn requested_levels(current: &Levels, args: &[&str]) -> Result<Option<Levels>, String> {
+    let level = |name: &str| parse_level(name).ok_or_else(|| format!("unknown level {:?}", name));
+    match args {
+        ["level"] => Ok(None),
+        ["level", name] => Ok(Some(Levels::uniform(level(name)?))),
+        ["level", component, name] => {
+            if !COMPONENTS.contains(component) {
+                return Err(format!(
+                    "unknown component {:?}, one of {}",
+                    component,
+                    COMPONENTS.join(", ")
+                ));
+            }
+            Ok(Some(current.clone().with(component, level(name)?)))
+        }
+        _ => Err("usage: log level | log level <level> | log level <component> <level>".into()),
+    }
+}
+
+// Levels set here last until a reload changes them in the file or the
+// service restarts. With RUST_LOG set, RUST_LOG keeps deciding.
+fn log_command(args: &[&str], peer: Option<&str>) -> Result<String, String> {
+    let current = logfilter::current().ok_or("RUST_LOG decides the log levels")?;
+    let Some(levels) = requested_levels(&current, args)? else {
+        return Ok(current.describe().join("\n"));
+    };
+    logfilter::set_levels(levels.clone());
+    let requester = match peer.filter(|peer| !peer.is_empty()) {
+        Some(peer) => format!("from {}", peer),
+        None => "without a peer address".to_string(),
+    };
+    warn!(
+        "(Admin) Log levels changed by a request {}: {}",
+        requester,
+        levels.describe().join("; ")
+    );
+    Ok("OK".to_string())
+}
+
+fn set_draining(runtime: &Runtime, draining: bool) -> String {
+    if runtime.quiesce.set_draining(draining) {
+        if draining {
+            info!("(Admin) Draining: refusing new connections");
+        } else {
+            info!("(Admin) Accepting new connections again");
+        }
+    }
+    "OK".to_string()
+}
+
+pub fn run_admin(
+    context: &zmq::Context,
+    config: &Arc<Config>,
+    runtime: &Runtime,
+    shutdown: &Arc<AtomicBool>,
+) -> Result<(), zmq::Error> {
+    let socket = context.socket(zmq::REP)?;
+    socket.set_linger(0)?;
+    socket.bind(&config.admin.endpoint)?;
+    info!("(Admin) REP bound to {}", config.admin.endpoint);
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match socket.poll(zmq::POLLIN, ADMIN_POLL_TIMEOUT_MS) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(zmq::Error::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+        let (request, peer) = Multipart::recv_with_property(&socket, 0, PEER_ADDRESS)?;
+        let line = request
+            .first()
+            .map(|f| String::from_utf8_lossy(f).into_owned())
+            .unwrap_or_default();
+        // The self-test needs the service's context and config, which
+        // handle_command does without.
+        let reply = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["selftest"] => selftest::run(context, config, runtime)
+                .to_json()
+                .to_string(),
+            _ => handle_command_from(runtime, &line, peer.as_deref()),
+        };
+        if reply.starts_with("ERROR") {
+            warn!("(Admin) {:?}: {}", line, reply);
+        } else {
+            info!("(Admin) {}", line.trim());
+        }
+        socket.send(reply.as_bytes(), 0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::multipart::Multipart;
+    use crate::schedule::{Destination, ScheduleSpec};
+
+    #[test]
+    fn sample_commands_update_shared_rules() {
+        let runtime = Runtime::new(&Config::default());
+        assert_eq!(handle_command(&runtime, "sample set prices. 0.01"), "OK");
+        assert_eq!(handle_command(&runtime, "sample list"), "prices. 0.01");
+        assert!(handle_command(&runtime, "sample set prices. 2").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "sample set prices. x").starts_with("ERROR"));
+        assert_eq!(handle_command(&runtime, "sample clear prices."), "OK");
+        assert_eq!(handle_command(&runtime, "sample list"), "no sample rules");
+        assert!(handle_command(&runtime, "frobnicate").starts_with("ERROR"));
+        runtime.identities.set("ha", b"corky-1/ha");
+        assert!(handle_command(&runtime, "stats").starts_with("# identity ha corky-1/ha\n"));
+    }
+
+    #[test]
+    fn acl_reload_replaces_the_rules() {
+        let runtime = Runtime::new(&Config::default());
+        assert_eq!(handle_command(&runtime, "acl list"), "no ACL entries");
+
+        let path = std::env::temp_dir().join(format!("corky-acl-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[acl.principals.alice]\nsubscribe = [\"alice.\"]\npublish = [\"alice.\"]\n",
+        )
+        .unwrap();
+        let reply = handle_command(&runtime, &format!("acl reload {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reply, "OK");
+        assert_eq!(
+            handle_command(&runtime, "acl list"),
+            r#"alice subscribe=["alice."] publish=["alice."]"#
+        );
+        assert_eq!(runtime.acl.version(), 1);
+
+        // A bad file leaves the rules as they were.
+        assert!(
+            handle_command(&runtime, "acl reload /nonexistent/corky.toml").starts_with("ERROR")
+        );
+        assert_eq!(runtime.acl.version(), 1);
+    }
+
+    #[test]
+    fn topics_commands_edit_the_rules() {
+        let mut config = Config::default();
+        config.topics.ephemeral = true;
+        let runtime = Runtime::new(&config);
+        assert_eq!(
+            handle_command(&runtime, "topics rules list"),
+            "no topic rules"
+        );
+        assert_eq!(handle_command(&runtime, "topics allow add news."), "OK");
+        assert_eq!(
+            handle_command(&runtime, "topics rewrite add legacy. news."),
+            "OK"
+        );
+        assert_eq!(
+            handle_command(&runtime, "topics rules list"),
+            "allow \"news.\"\nrewrite \"legacy.\" -> \"news.\""
+        );
+        assert_eq!(runtime.topics.version(), 2);
+        assert!(handle_command(&runtime, "topics allow add news.").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "topics allow remove orders.").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "topics rewrite add legacy.").starts_with("ERROR"));
+        assert_eq!(runtime.topics.version(), 2);
+        assert_eq!(handle_command(&runtime, "topics allow remove news."), "OK");
+        assert_eq!(
+            handle_command(&runtime, "topics rewrite remove legacy."),
+            "OK"
+        );
+        assert_eq!(
+            handle_command(&runtime, "topics rules list"),
+            "no topic rules"
+        );
+    }
+
+    #[test]
+    fn quota_commands_show_and_grant() {
+        let runtime = Runtime::new(&Config::default());
+        assert!(handle_command(&runtime, "quota show alice").starts_with("ERROR"));
+
+        let path = std::env::temp_dir().join(format!("corky-quota-admin-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            "[quota]\nenabled = true\nstate_file = {:?}\n\
+             [quota.principals.alice]\nlimit = 10\nwindow = \"rolling\"\n",
+            path.display().to_string()
+        ))
+        .unwrap();
+        let runtime = Runtime::new(&config);
+        assert_eq!(handle_command(&runtime, "quota grant alice 5"), "OK");
+        let shown = handle_command(&runtime, "quota show alice");
+        assert!(
+            shown.starts_with("alice used=0 limit=10 window=rolling granted=5 reset_ms="),
+            "{}",
+            shown
+        );
+        assert!(handle_command(&runtime, "quota show bob").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "quota grant alice lots").starts_with("ERROR"));
+    }
+
+    #[test]
+    fn schedule_commands_list_and_cancel() {
+        let runtime = Runtime::new(&Config::default());
+        assert_eq!(
+            handle_command(&runtime, "schedule list"),
+            "no scheduled messages"
+        );
+        let spec = match ScheduleSpec::parse(br#"{"delay_ms": 60000, "cancel_id": "r1"}"#) {
+            Some(Ok(spec)) => spec,
+            _ => unreachable!(),
+        };
+        let message = Multipart::new(vec![b"bob".to_vec(), b"alice".to_vec(), b"hi".to_vec()]);
+        runtime
+            .scheduler
+            .lock()
+            .unwrap()
+            .schedule(
+                Destination::Direct,
+                b"alice",
+                spec,
+                message,
+                Instant::now(),
+                SystemTime::now(),
+            )
+            .unwrap();
+        let listed = handle_command(&runtime, "schedule list");
+        assert!(listed.starts_with("1 direct bob due_in_ms="), "{}", listed);
+        assert!(listed.ends_with("cancel_id=r1 bytes=10"), "{}", listed);
+        assert_eq!(
+            handle_command(&runtime, "schedule cancel r1"),
+            "cancelled 1"
+        );
+        assert_eq!(
+            handle_command(&runtime, "schedule list"),
+            "no scheduled messages"
+        );
+        assert!(handle_command(&runtime, "schedule cancel").starts_with("ERROR"));
+    }
+
+    #[test]
+    fn chaos_commands_need_chaos_mode() {
+        let runtime = Runtime::new(&Config::default());
+        assert!(handle_command(&runtime, "chaos set requests drop 0.5").starts_with("ERROR"));
+
+        let mut config = Config::default();
+        config.chaos.enabled = true;
+        let runtime = Runtime::new(&config);
+        assert_eq!(
+            handle_command(&runtime, "chaos set requests drop 0.5"),
+            "OK"
+        );
+        assert_eq!(handle_command(&runtime, "chaos delay replies 5 50"), "OK");
+        assert_eq!(
+            handle_command(&runtime, "chaos list"),
+            "requests drop=0.5 duplicate=0 malformed=0 delay=0 delay_ms=10..1000\n\
+             replies drop=0 duplicate=0 malformed=0 delay=0 delay_ms=5..50"
+        );
+        assert!(handle_command(&runtime, "chaos set sideways drop 0.5").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "chaos set requests explode 1").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "chaos set requests drop 2").starts_with("ERROR"));
+        assert_eq!(handle_command(&runtime, "chaos off"), "OK");
+        assert!(handle_command(&runtime, "chaos list").starts_with("requests drop=0 "));
+    }
+
+    #[test]
+    fn limits_commands_and_health() {
+        let runtime = Runtime::new(&Config::default());
+        assert_eq!(
+            handle_command(&runtime, "limits socket client_router 1"),
+            "OK"
+        );
+        assert_eq!(handle_command(&runtime, "limits address 3"), "OK");
+        assert_eq!(
+            handle_command(&runtime, "limits"),
+            "direct_router max=0 connections=0\n\
+             client_router max=1 connections=0\n\
+             worker_router max=0 connections=0\n\
+             address max=3 busiest=0"
+        );
+        assert_eq!(
+            handle_command(&runtime, "health"),
+            "ok\nclient_router 0/1\naddress 0/3"
+        );
+        runtime.limits.accepted("client_router", "10.0.0.1");
+        assert!(handle_command(&runtime, "health").starts_with("near_limit\n"));
+        assert!(handle_command(&runtime, "limits socket pub 1").starts_with("ERROR"));
+        assert!(handle_command(&runtime, "limits address lots").starts_with("ERROR"));
+    }
+
+    #[test]
+    fn quiesce_flips_health() {
+        let runtime = Runtime::new(&Config::default());
+        assert_eq!(handle_command(&runtime, "health"), "ok");
+        assert_eq!(handle_command(&runtime, "quiesce"), "OK");
+        assert_eq!(handle_command(&runtime, "health"), "draining");
+        assert!(runtime.quiesce.is_draining());
+        assert_eq!(handle_command(&runtime, "unquiesce"), "OK");
+        assert_eq!(handle_command(&runtime, "health"), "ok");
+    }
+
+    #[test]
+    fn log_level_sets_every_component_or_one() {
+        use log::LevelFilter;
+
+        let current = Levels::uniform(LevelFilter::Info).with("proxy", LevelFilter::Warn);
+        assert_eq!(requested_levels(&current, &["level"]), Ok(None));
+        assert_eq!(
+            requested_levels(&current, &["level", "debug"]),
+            Ok(Some(Levels::uniform(LevelFilter::Debug)))
+        );
+        let one = requested_levels(&current, &["level", "broker", "trace"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(one.describe(), ["info", "broker trace", "proxy warn"]);
+        assert!(requested_levels(&current, &["level", "loud"]).is_err());
+        assert!(requested_levels(&current, &["level", "brokr", "debug"]).is_err());
+        // No logger of logfilter's in the tests, as with RUST_LOG set.
+        assert_eq!(
+            handle_command(&Runtime::new(&Config::default()), "log level debug"),
+            "ERROR: RUST_LOG decides the log levels"
+        );
+    }
+}
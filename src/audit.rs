@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use log::{error, info};
+use serde_json::{Map, Value};
+
+use crate::broker::{CLIENT_ROUTER, DIRECT_ROUTER};
+use crate::config::{AuditConfig, LogTimestamp, DEFAULT_AUDIT_FILE};
+use crate::logfile::{expand_home, RotatingFile};
+use crate::logtime;
+use crate::monitor::{ConnectionEvent, ConnectionTap};
+
+//
+// --------------------------- Connection audit log ----------------------------
+//
+// [logging.audit] keeps a record of the peers that connect to and disconnect
+// from the client-facing sockets, apart from the log and whatever its level:
+//
+//     {"event":"accepted","fd":17,"peer_address":"10.0.0.7:51234",
+//      "socket":"client_router","ts":"2026-10-14T11:05:27.481Z"}
+//
+// one object a line, in UTC whatever logging.timestamp says. It is a tap of
+// ConnectionLimits, which owns the monitors of those sockets. Each record is
+// written to the file in one write, as it happens, and rotated like the log
+// file. A record that cannot be written is dropped: the first failure is
+// logged, and the next record written after it says so too; the broker runs
+// on either way.
+
+pub const AUDITED_SOCKETS: [&str; 2] = [CLIENT_ROUTER, DIRECT_ROUTER];
+
+const MB: u64 = 1024 * 1024;
+
+struct Writer {
+    file: RotatingFile,
+    failing: bool,
+}
+
+pub struct AuditLog {
+    writer: Mutex<Writer>,
+}
+
+// One line of the audit log.
+pub fn record(
+    ts: Timestamp,
+    socket: &str,
+    event: ConnectionEvent,
+    fd: u32,
+    peer: Option<SocketAddr>,
+) -> String {
+    let mut fields = Map::new();
+    if let Some(ts) = logtime::timestamp(LogTimestamp::Rfc3339Utc, ts, &TimeZone::UTC) {
+        fields.insert("ts".to_string(), ts);
+    }
+    fields.insert("socket".to_string(), Value::from(socket));
+    fields.insert("event".to_string(), Value::from(event.name()));
+    fields.insert(
+        "peer_address".to_string(),
+        peer.map_or(Value::Null, |p| Value::String(p.to_string())),
+    );
+    fields.insert("fd".to_string(), Value::from(fd));
+    Value::Object(fields).to_string()
+}
+
+impl AuditLog {
+    // None when the audit log is off; Err when its file cannot be opened.
+    pub fn open(config: &AuditConfig) -> Result<Option<Self>, String> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let path = expand_home(config.file_path.as_deref().unwrap_or(DEFAULT_AUDIT_FILE));
+        let file = RotatingFile::open(
+            &path,
+            config.max_size_mb.saturating_mul(MB),
+            config.max_files,
+        )
+        .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        Ok(Some(Self {
+            writer: Mutex::new(Writer {
+                file,
+                failing: false,
+            }),
+        }))
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.writer().file.path().to_path_buf()
+    }
+
+    fn writer(&self) -> std::sync::MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self, line: &str) {
+        let mut writer = self.writer();
+        let mut record = line.as_bytes().to_vec();
+        record.push(b'\n');
+        let written = writer.file.write_record(&record);
+        let path = writer.file.path().display().to_string();
+        match (written, writer.failing) {
+            (Err(e), false) => {
+                error!(
+                    "(Audit) Cannot write to {}: {}; dropping records until it works again",
+                    path, e
+                );
+                writer.failing = true;
+            }
+            (Ok(()), true) => {
+                info!("(Audit) Writing to {} again", path);
+                writer.failing = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ConnectionTap for AuditLog {
+    fn connection(
+        &self,
+        socket: &'static str,
+        event: ConnectionEvent,
+        fd: u32,
+        peer: Option<SocketAddr>,
+    ) {
+        if AUDITED_SOCKETS.contains(&socket) {
+            self.write(&record(Timestamp::now(), socket, event, fd, peer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::broker::WORKER_ROUTER;
+
+    #[test]
+    fn client_facing_connections_are_appended_one_per_line() {
+        let dir = std::env::temp_dir().join(format!("corky-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("logs").join("audit.jsonl");
+        let config = AuditConfig {
+            enabled: true,
+            file_path: Some(path.to_string_lossy().into_owned()),
+            ..AuditConfig::default()
+        };
+        assert!(AuditLog::open(&AuditConfig::default()).unwrap().is_none());
+        let audit = AuditLog::open(&config).unwrap().unwrap();
+        assert_eq!(audit.path(), path);
+
+        let peer: SocketAddr = "10.0.0.7:51234".parse().unwrap();
+        audit.connection(CLIENT_ROUTER, ConnectionEvent::Accepted, 17, Some(peer));
+        audit.connection(WORKER_ROUTER, ConnectionEvent::Accepted, 18, Some(peer));
+        audit.connection(DIRECT_ROUTER, ConnectionEvent::Disconnected, 19, None);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2, "{}", content);
+        assert_eq!(lines[0]["socket"], "client_router");
+        assert_eq!(lines[0]["event"], "accepted");
+        assert_eq!(lines[0]["peer_address"], "10.0.0.7:51234");
+        assert_eq!(lines[0]["fd"], 17);
+        assert!(lines[0]["ts"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["socket"], "direct_router");
+        assert_eq!(lines[1]["event"], "disconnected");
+        assert_eq!(lines[1]["peer_address"], Value::Null);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_record_shows_the_time_in_utc() {
+        let ts: Timestamp = "2026-10-14T11:05:27.481Z".parse().unwrap();
+        let peer = "[::1]:4000".parse().ok();
+        assert_eq!(
+            record(ts, CLIENT_ROUTER, ConnectionEvent::Accepted, 9, peer),
+            "{\"event\":\"accepted\",\"fd\":9,\"peer_address\":\"[::1]:4000\",\
+             \"socket\":\"client_router\",\"ts\":\"2026-10-14T11:05:27.481Z\"}"
+        );
+    }
+}
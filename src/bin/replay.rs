@@ -0,0 +1,105 @@
+// Offline tools over what the broker wrote to disk.
+//
+//     replay journal inspect [--dir DIR] [--since MS] [--until MS]
+//                            [--peer ID] [--correlation ID]
+//     replay journal verify [--dir DIR]
+//
+// Times are Unix milliseconds. Without --dir the journal directory comes from
+// the service config, which also supplies the key for sealed journals.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use corky_zmq::config::{load_config, Config};
+use corky_zmq::journal::{inspect, journal_dir, verify, JournalFilter};
+use corky_zmq::seal::Keyring;
+
+const USAGE: &str = "usage: replay journal inspect [--dir DIR] [--since MS] [--until MS] \
+[--peer ID] [--correlation ID]\n       replay journal verify [--dir DIR]";
+
+struct Options {
+    dir: Option<PathBuf>,
+    filter: JournalFilter,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        dir: None,
+        filter: JournalFilter::default(),
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let millis = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} takes Unix milliseconds, got {}", flag, value))
+        };
+        match flag.as_str() {
+            "--dir" => options.dir = Some(PathBuf::from(value)),
+            "--since" => options.filter.since_ms = Some(millis()?),
+            "--until" => options.filter.until_ms = Some(millis()?),
+            "--peer" => options.filter.peer = Some(value.as_bytes().to_vec()),
+            "--correlation" => options.filter.correlation = Some(value.clone()),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    Ok(options)
+}
+
+fn run(args: &[String]) -> Result<ExitCode, String> {
+    let (command, rest) = match args {
+        [tool, command, rest @ ..] if tool == "journal" => (command.as_str(), rest),
+        _ => return Err(USAGE.to_string()),
+    };
+    let options = parse_options(rest)?;
+    if command == "verify" && options.filter != JournalFilter::default() {
+        return Err("verify checks the whole journal; filters apply to inspect".to_string());
+    }
+    let config = load_config().unwrap_or_else(|_| Config::default());
+    let keyring = Keyring::from_config(&config.encryption)?;
+    let dir = match options.dir {
+        Some(dir) => dir,
+        None => journal_dir(&config.journal)?,
+    };
+    let unreadable = |e: std::io::Error| format!("cannot read {}: {}", dir.display(), e);
+    match command {
+        "inspect" => {
+            let (records, problems) =
+                inspect(&dir, keyring.as_ref(), &options.filter).map_err(unreadable)?;
+            for record in &records {
+                println!("{}", record.render());
+            }
+            for problem in &problems {
+                eprintln!("warning: {}", problem);
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        "verify" => {
+            let problems = verify(&dir, keyring.as_ref()).map_err(unreadable)?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if problems.is_empty() {
+                println!("{}: OK", dir.display());
+                Ok(ExitCode::SUCCESS)
+            } else {
+                Ok(ExitCode::FAILURE)
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
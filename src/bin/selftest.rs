@@ -0,0 +1,92 @@
+// Runs the self-test of a running service over its admin socket.
+//
+//     selftest [--endpoint ENDPOINT] [--timeout-ms MS]
+//
+// Prints the JSON report and exits 0 when every check passed or was skipped,
+// 1 when one failed, and 2 when the service could not be asked. Without
+// --endpoint the admin endpoint comes from the service config.
+
+use std::process::ExitCode;
+
+use corky_zmq::config::{load_config, Config};
+
+const USAGE: &str = "usage: selftest [--endpoint ENDPOINT] [--timeout-ms MS]";
+const DEFAULT_TIMEOUT_MS: i32 = 30_000;
+
+struct Options {
+    endpoint: Option<String>,
+    timeout_ms: i32,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        endpoint: None,
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value\n{}", flag, USAGE))?;
+        match flag.as_str() {
+            "--endpoint" => options.endpoint = Some(value.clone()),
+            "--timeout-ms" => {
+                options.timeout_ms = value
+                    .parse()
+                    .map_err(|_| format!("--timeout-ms takes milliseconds, got {}", value))?
+            }
+            _ => return Err(format!("unknown option {}\n{}", flag, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+fn run(args: &[String]) -> Result<ExitCode, String> {
+    let options = parse_options(args)?;
+    let endpoint = match options.endpoint {
+        Some(endpoint) => endpoint,
+        None => {
+            load_config()
+                .unwrap_or_else(|_| Config::default())
+                .admin
+                .endpoint
+        }
+    };
+    if endpoint.starts_with("inproc://") {
+        return Err(format!(
+            "the admin endpoint {} is in-process; pass --endpoint",
+            endpoint
+        ));
+    }
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REQ).map_err(|e| e.to_string())?;
+    let asked = socket
+        .set_linger(0)
+        .and_then(|_| socket.set_rcvtimeo(options.timeout_ms))
+        .and_then(|_| socket.connect(&endpoint))
+        .and_then(|_| socket.send("selftest", 0));
+    asked.map_err(|e| format!("cannot ask {}: {}", endpoint, e))?;
+    let reply = match socket.recv_bytes(0) {
+        Ok(reply) => String::from_utf8_lossy(&reply).into_owned(),
+        Err(zmq::Error::EAGAIN) => return Err(format!("no answer from {}", endpoint)),
+        Err(e) => return Err(format!("no answer from {}: {}", endpoint, e)),
+    };
+    let report: serde_json::Value =
+        serde_json::from_str(&reply).map_err(|_| format!("{}: {}", endpoint, reply))?;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap_or(reply));
+    match report["status"] == "pass" {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
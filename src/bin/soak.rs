@@ -0,0 +1,111 @@
+// Long-running soak of an in-process broker and proxy with invariant checks
+// (see corky_zmq::soak). Meant to be run by hand, e.g. nightly:
+//
+//     soak [--duration SECS] [--rate OPS] [--mix RR:C2C:PUB] [--pairs N]
+//          [--direct-pairs N] [--churn-ms MS] [--check-ms MS]
+//          [--timeout-ms MS] [--max-rss-growth-kb KB] [--report-dir DIR]
+//          [--seed N] [--smoke]
+//
+// --smoke starts from the few-second configuration the test suite runs;
+// later options override it. Exits 1 when an invariant was violated.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use corky_zmq::soak::{run, SoakConfig};
+
+const USAGE: &str = "usage: soak [--duration SECS] [--rate OPS] [--mix RR:C2C:PUB] [--pairs N] \
+[--direct-pairs N] [--churn-ms MS] [--check-ms MS] [--timeout-ms MS] [--max-rss-growth-kb KB] \
+[--report-dir DIR] [--seed N] [--smoke]";
+
+fn parse_mix(value: &str) -> Result<[u32; 3], String> {
+    let weights: Vec<u32> = value
+        .split(':')
+        .map(|w| w.parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("--mix takes three weights like 6:3:1, got {}", value))?;
+    match weights[..] {
+        [rr, c2c, pubsub] if rr + c2c + pubsub > 0 => Ok([rr, c2c, pubsub]),
+        _ => Err(format!(
+            "--mix takes three weights like 6:3:1, got {}",
+            value
+        )),
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<SoakConfig, String> {
+    let mut config = match args.iter().any(|a| a == "--smoke") {
+        true => SoakConfig::smoke(),
+        false => SoakConfig::default(),
+    };
+    let mut args = args.iter().filter(|a| *a != "--smoke");
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} takes a number, got {}", flag, value))
+        };
+        match flag.as_str() {
+            "--duration" => config.duration = Duration::from_secs(number()?),
+            "--rate" => config.rate = number()? as u32,
+            "--mix" => config.mix = parse_mix(value)?,
+            "--pairs" => config.pairs = number()? as usize,
+            "--direct-pairs" => config.direct_pairs = number()? as usize,
+            "--churn-ms" => config.churn_every = Duration::from_millis(number()?.max(1)),
+            "--check-ms" => config.check_every = Duration::from_millis(number()?.max(1)),
+            "--timeout-ms" => config.request_timeout = Duration::from_millis(number()?),
+            "--max-rss-growth-kb" => config.max_rss_growth_kb = number()?,
+            "--report-dir" => config.report_dir = PathBuf::from(value),
+            "--seed" => config.seed = number()?,
+            _ => return Err(format!("unknown option {}\n{}", flag, USAGE)),
+        }
+    }
+    Ok(config)
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match parse_options(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let report = match run(&config) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("soak could not start: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let tally = &report.tally;
+    println!(
+        "{:?}: {} requests / {} replies, {} direct sent / {} received, \
+{} published / {} delivered, {} churned, rss +{} kB",
+        report.elapsed,
+        tally.requests,
+        tally.replies,
+        tally.direct_sent,
+        tally.direct_received,
+        tally.published,
+        tally.delivered,
+        tally.churned,
+        report.rss_growth_kb
+    );
+    for violation in &report.violations {
+        println!("violation: {}", violation);
+    }
+    match &report.report {
+        Some(dir) => {
+            println!("report: {}", dir.display());
+            ExitCode::FAILURE
+        }
+        None => ExitCode::SUCCESS,
+    }
+}
@@ -0,0 +1,1830 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error, info, warn, Level};
+
+use crate::chaos::{correlation_field, Chaos, Direction};
+use crate::chunk::{ChunkHeader, ChunkPath, ChunkTracker, ExpiredTransfer, PeerTransfers};
+use crate::compress::{advertises_compression, decompression_error, is_compressed, Compressor};
+use crate::config::{Config, EndpointConfig, LatencyMode};
+use crate::events::{BrokerEvent, EventStream};
+use crate::fanout::{
+    error_reply, parse_tag, FanoutSpec, ScatterGather, WorkerPool, WORKER_DISCONNECT, WORKER_READY,
+};
+use crate::format::{
+    format_message, grouped, size_summary, summarize_message, Envelope, RenderStyle,
+};
+use crate::gc::IdleGc;
+use crate::ha::{BinaryStar, HaLink};
+use crate::hello::{negotiate, Capabilities, Capability, Offer, HELLO};
+use crate::identity;
+use crate::ingress::{self, IngressStamper, PEER_ADDRESS, USER_ID};
+use crate::journal::{Journal, JournalDirection};
+use crate::logjson;
+use crate::metrics::{label_value, Counter, Gauge, Histogram, Label, Registry, SIZE_BUCKETS};
+use crate::multipart::Multipart;
+use crate::peers::{peer_key, split_key, PeerKey, PeerRole, PeerTable};
+use crate::pipeline::{PipelineSockets, TaskRelay};
+use crate::quota::{self, Quotas};
+use crate::runtime::Runtime;
+use crate::schedule::{
+    cancel_reply, parse_cancel, rejected_reply, Destination, OfflineQueue, ScheduleSpec, Scheduler,
+    SCHEDULER_ID,
+};
+use crate::socket::{
+    apply_socket_options, bind_all, bind_with_retry, configure_endpoint, configure_socket,
+    ROLE_CLIENT_FACING, ROLE_CLIENT_TO_CLIENT, ROLE_WORKER_FACING,
+};
+use crate::suppress::{self, Callsite};
+use crate::timer::Periodic;
+use crate::watch::{self, WatchCommand, Watches};
+use crate::zap::broker_domain;
+
+const LOW_LATENCY_POLL_TIMEOUT_MS: i64 = 0; // busy-poll in latency_mode = "low"
+const STATS_INTERVAL_MS: u64 = 10_000; // loop statistics log interval
+const MIN_CHUNK_SWEEP_MS: u64 = 10; // bounds for the chunk-timeout sweep interval
+const MAX_CHUNK_SWEEP_MS: u64 = 1000;
+const PEER_SWEEP_MS: u64 = 1000; // idle-state collection interval
+const FANOUT_SWEEP_MS: u64 = 10; // fan-out deadline check interval
+const SCHEDULE_TICK_MS: u64 = 1; // due scheduled message check interval
+const OFFLINE_RETRY_MS: u64 = 100; // offline queue resend interval
+const DRAIN_PROGRESS_MS: u64 = 500; // connection and in-flight gauge interval
+const MONITOR_TICK_MS: u64 = 5; // connection event (and limit) check interval
+const REPEAT_FLUSH_MS: u64 = 1000; // repeated-error summary check interval
+
+// Socket labels used in log lines
+pub const DIRECT_ROUTER: &str = "direct_router";
+pub const CLIENT_ROUTER: &str = "client_router";
+pub const WORKER_ROUTER: &str = "worker_router";
+const GC_EVENTS: &str = "gc_events";
+
+// Send errors that can repeat for as long as a peer is away; their repeats
+// are summarised, see crate::suppress.
+static RELAY_BLOCKED: Callsite = callsite("relay_blocked", Level::Warn);
+static RELAY_FAILED: Callsite = callsite("relay_failed", Level::Error);
+static DIRECT_BLOCKED: Callsite = callsite("direct_blocked", Level::Warn);
+static DIRECT_FAILED: Callsite = callsite("direct_failed", Level::Error);
+static RETRY_FAILED: Callsite = callsite("retry_failed", Level::Warn);
+
+const fn callsite(name: &'static str, level: Level) -> Callsite {
+    Callsite {
+        name,
+        level,
+        target: module_path!(),
+    }
+}
+
+// Poll index constants for broker
+const IDX_CLIENT_ROUTER: usize = 0;
+const IDX_WORKER_ROUTER: usize = 1;
+
+//
+// ----------------------------- Message logging -------------------------------
+//
+
+// How much the broker logs of each message it handles: at info level its
+// size, at debug its rendered payload too. Checked once per loop pass.
+//
+// With [logging] sample_every = N, only the first of every N messages from
+// each socket is logged, so a busy direction does not drown out a quiet one
+// and neither spends its time rendering. Every message is counted, and a
+// note with the count comes with the first sampled line and every
+// SAMPLE_NOTE_EVERY-th after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogDetail {
+    Off,
+    Summary,
+    Payload,
+}
+
+static SAMPLE_EVERY: AtomicU64 = AtomicU64::new(1);
+const SAMPLE_NOTE_EVERY: u64 = 1000;
+
+// Set once at startup; 1 logs every message.
+pub fn set_sample_every(every: u64) {
+    SAMPLE_EVERY.store(every.max(1), Ordering::Relaxed);
+}
+
+impl LogDetail {
+    // Off in latency_mode = "low", which never formats per message.
+    pub fn current(low_latency: bool) -> Self {
+        if low_latency {
+            LogDetail::Off
+        } else if log::log_enabled!(Level::Debug) {
+            LogDetail::Payload
+        } else if log::log_enabled!(Level::Info) {
+            LogDetail::Summary
+        } else {
+            LogDetail::Off
+        }
+    }
+
+    // Counts a message from `src`; Off unless it is one sample_every logs.
+    fn sampled(self, src: &SocketChannel) -> Self {
+        let every = SAMPLE_EVERY.load(Ordering::Relaxed);
+        if self == LogDetail::Off || every == 1 {
+            return self;
+        }
+        let seen = src.log_seen.fetch_add(1, Ordering::Relaxed);
+        if !seen.is_multiple_of(every) {
+            return LogDetail::Off;
+        }
+        if (seen / every).is_multiple_of(SAMPLE_NOTE_EVERY) {
+            info!(
+                "(Broker) Sampled 1 of every {} messages from {}, {} seen so far",
+                every,
+                src.name,
+                grouped(seen + 1)
+            );
+        }
+        self
+    }
+
+    // JSON payloads on one line at debug, indented at trace.
+    fn style() -> RenderStyle {
+        match log::log_enabled!(Level::Trace) {
+            true => RenderStyle::Pretty,
+            false => RenderStyle::Compact,
+        }
+    }
+
+    // "(Broker) Received from direct_router 3 frames, 41KB [id, empty, json]"
+    // at info, frames told apart without parsing them, and "(Broker) Received
+    // from direct_router [3 frames, 41,932 bytes]: payload" at debug.
+    fn log(self, what: std::fmt::Arguments, message: &[Vec<u8>], envelope: Envelope) {
+        match self {
+            LogDetail::Off => {}
+            LogDetail::Summary => {
+                let summary = match envelope {
+                    Envelope::Routed => summarize_message(message).routed(),
+                    _ => summarize_message(message),
+                };
+                info!("(Broker) {} {}", what, summary)
+            }
+            LogDetail::Payload => debug!(
+                "(Broker) {} {}: {}",
+                what,
+                size_summary(message),
+                format_message(message, envelope, LogDetail::style())
+            ),
+        }
+    }
+}
+
+//
+// --------------------------- Socket channels ---------------------------------
+//
+
+// A broker socket plus the state that travels with it: a static label for log
+// lines and per-socket metrics, registered once here so the hot path only
+// bumps atomics. Handles need no &mut, so channels can be used while the poll
+// items hold shared borrows of the sockets.
+pub struct SocketChannel {
+    pub socket: zmq::Socket,
+    pub name: &'static str,
+    // Routed on ROUTER sockets, whose messages start with the peer identity.
+    pub envelope: Envelope,
+    received: Counter,
+    sent: Counter,
+    dropped: Counter,
+    message_bytes: Histogram,
+    // Messages counted for [logging] sample_every.
+    log_seen: AtomicU64,
+}
+
+impl SocketChannel {
+    pub fn new(socket: zmq::Socket, name: &'static str, metrics: &Registry) -> Self {
+        Self::labelled(socket, name, &[("socket", name)], metrics)
+    }
+
+    // One of several sockets sharing a name, told apart in the metrics by
+    // the endpoint it listens on.
+    pub fn on_endpoint(
+        socket: zmq::Socket,
+        name: &'static str,
+        endpoint: &str,
+        metrics: &Registry,
+    ) -> Self {
+        let labels = [("socket", name), ("endpoint", label_value(endpoint))];
+        Self::labelled(socket, name, &labels, metrics)
+    }
+
+    fn labelled(
+        socket: zmq::Socket,
+        name: &'static str,
+        labels: &[Label],
+        metrics: &Registry,
+    ) -> Self {
+        let envelope = match socket.get_socket_type() {
+            Ok(zmq::ROUTER) => Envelope::Routed,
+            _ => Envelope::Topic,
+        };
+        Self {
+            socket,
+            name,
+            envelope,
+            received: metrics.counter("corky_broker_received_total", labels),
+            sent: metrics.counter("corky_broker_sent_total", labels),
+            dropped: metrics.counter("corky_broker_dropped_total", labels),
+            message_bytes: metrics.histogram("corky_broker_message_bytes", labels, SIZE_BUCKETS),
+            log_seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.get()
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.get()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    // Receive one multipart message. Errors are logged here; EINTR is not an
+    // error and yields None silently.
+    pub fn recv(&self) -> Option<Multipart> {
+        let received = Multipart::recv(&self.socket, 0).map(|message| (message, Vec::new()));
+        self.counted(received).map(|(message, _)| message)
+    }
+
+    // Like `recv`, with message properties such as crate::ingress::USER_ID.
+    pub fn recv_with_properties(
+        &self,
+        properties: &[&str],
+    ) -> Option<(Multipart, Vec<Option<String>>)> {
+        self.counted(Multipart::recv_with_properties(&self.socket, 0, properties))
+    }
+
+    fn counted(
+        &self,
+        received: Result<(Multipart, Vec<Option<String>>), zmq::Error>,
+    ) -> Option<(Multipart, Vec<Option<String>>)> {
+        match received {
+            Ok((message, properties)) => {
+                self.received.inc();
+                self.message_bytes
+                    .observe(message.iter().map(|f| f.len() as u64).sum());
+                Some((message, properties))
+            }
+            Err(zmq::Error::EINTR) => None,
+            Err(e) => {
+                error!("(Broker) Error receiving from {}: {}", self.name, e);
+                None
+            }
+        }
+    }
+
+    // Non-blocking send; the caller decides how to report a failure.
+    pub fn send(&self, message: Multipart) -> Result<(), zmq::Error> {
+        let result = message.send(&self.socket, zmq::DONTWAIT);
+        self.count(result)
+    }
+
+    // Like `send`, but copies the frames so the caller keeps the message.
+    pub fn send_copy(&self, message: &Multipart) -> Result<(), zmq::Error> {
+        let result = message.send_copy(&self.socket, zmq::DONTWAIT);
+        self.count(result)
+    }
+
+    fn count(&self, result: Result<(), zmq::Error>) -> Result<(), zmq::Error> {
+        match result {
+            Ok(()) => self.sent.inc(),
+            Err(_) => self.dropped.inc(),
+        }
+        result
+    }
+
+    // For callers that send on `socket` directly.
+    pub fn record_send(&self) {
+        self.sent.inc();
+    }
+
+    pub fn record_drop(&self) {
+        self.dropped.inc();
+    }
+
+    // Receive one message and drop it, as when this instance is passive.
+    pub fn discard(&self) {
+        if self.recv().is_some() {
+            self.record_drop();
+        }
+    }
+
+    // Receive one message and relay it to `dst` unchanged.
+    pub fn forward_to(&self, dst: &SocketChannel, detail: LogDetail) {
+        if let Some(message) = self.recv() {
+            self.relay(message, dst, detail);
+        }
+    }
+
+    // Relay an already-received message to `dst`; returns whether it was sent.
+    pub fn relay(&self, message: Multipart, dst: &SocketChannel, detail: LogDetail) -> bool {
+        let detail = detail.sampled(self);
+        if detail != LogDetail::Off && logjson::json() {
+            let (frames, bytes) = (message.len(), message.iter().map(Vec::len).sum::<usize>());
+            if detail == LogDetail::Payload {
+                let payload = format_message(&message, self.envelope, LogDetail::style());
+                debug!(
+                    src = self.name, dst = dst.name, frames = frames, bytes = bytes,
+                    payload = payload.as_str();
+                    "(Broker) Forwarding"
+                );
+            } else {
+                info!(
+                    src = self.name, dst = dst.name, frames = frames, bytes = bytes;
+                    "(Broker) Forwarding"
+                );
+            }
+        } else {
+            detail.log(
+                format_args!("Forwarding {} -> {}", self.name, dst.name),
+                &message,
+                self.envelope,
+            );
+        }
+        match dst.send(message) {
+            Ok(_) => return true,
+            Err(zmq::Error::EAGAIN) => {
+                suppress::log(
+                    &RELAY_BLOCKED,
+                    format_args!("(Broker) Send would block, dropping message"),
+                );
+            }
+            Err(zmq::Error::EHOSTUNREACH) => {
+                debug!(
+                    "(Broker) No route {} -> {}, dropping message",
+                    self.name, dst.name
+                );
+            }
+            Err(e) => {
+                suppress::log(
+                    &RELAY_FAILED,
+                    format_args!(
+                        "(Broker) Error forwarding {} -> {}: {}",
+                        self.name, dst.name, e
+                    ),
+                );
+            }
+        }
+        false
+    }
+}
+
+//
+// ------------------------------- Peers ---------------------------------------
+//
+
+// Everything the broker remembers about one identity on one socket.
+#[derive(Default)]
+pub struct PeerState {
+    pub chunks: PeerTransfers,
+    pub messages: u64,
+    pub bytes: u64,
+    // Accepts compressed frames: a worker that advertised it, or a client
+    // that sent one.
+    pub compression: bool,
+    // Requests relayed to a worker that have not been answered yet.
+    pub in_flight: u32,
+    // The client-facing socket a client's last message came in on.
+    pub ingress: usize,
+    // What a client agreed to in its last hello; None if it never sent one.
+    pub capabilities: Option<Capabilities>,
+}
+
+// The peer table plus the features whose state lives in it. Transfers of
+// evicted peers are queued in `aborted` for the loop to report, and, once
+// `announce_joins` is called, peers new to the table in `joined`, workers
+// that sent DISCONNECT in `departed` and direct peers the table forgot, idle
+// or evicted, in `forgotten`.
+pub struct Peers {
+    pub table: PeerTable<PeerState>,
+    pub chunks: ChunkTracker,
+    aborted: Vec<ExpiredTransfer>,
+    joined: Option<Vec<(PeerRole, Vec<u8>)>>,
+    departed: Option<Vec<Vec<u8>>>,
+    forgotten: Option<Vec<Vec<u8>>>,
+    in_flight: u64,
+}
+
+impl Peers {
+    pub fn new(max_peers: usize, chunk_timeout: Duration) -> Self {
+        Self {
+            table: PeerTable::new(max_peers),
+            chunks: ChunkTracker::new(chunk_timeout),
+            aborted: Vec::new(),
+            joined: None,
+            departed: None,
+            forgotten: None,
+            in_flight: 0,
+        }
+    }
+
+    pub fn announce_joins(&mut self) {
+        self.joined.get_or_insert_with(Vec::new);
+        self.departed.get_or_insert_with(Vec::new);
+        self.forgotten.get_or_insert_with(Vec::new);
+    }
+
+    // Peers seen for the first time since the last call.
+    pub fn take_joined(&mut self) -> Vec<(PeerRole, Vec<u8>)> {
+        self.joined.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Workers that deregistered since the last call.
+    pub fn take_departed(&mut self) -> Vec<Vec<u8>> {
+        self.departed
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Direct peers forgotten since the last call.
+    pub fn take_forgotten(&mut self) -> Vec<Vec<u8>> {
+        self.forgotten
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Drop a worker that said goodbye, as the idle GC would after its TTL.
+    pub fn depart(&mut self, worker: &[u8]) {
+        let key = peer_key(PeerRole::Worker, worker);
+        if let Some(state) = self.table.remove(&key) {
+            self.forget(key, state);
+        }
+        if let Some(departed) = self.departed.as_mut() {
+            departed.push(worker.to_vec());
+        }
+    }
+
+    // Count one message from `identity`.
+    pub fn record(&mut self, role: PeerRole, identity: &[u8], message: &[Vec<u8>], now: Instant) {
+        let key = peer_key(role, identity);
+        if let Some(joined) = self.joined.as_mut() {
+            if self.table.get(&key).is_none() {
+                joined.push((role, identity.to_vec()));
+            }
+        }
+        let touched = self.table.touch(&key, now);
+        touched.state.messages += 1;
+        touched.state.bytes += message.iter().map(|f| f.len() as u64).sum::<u64>();
+        if let Some((key, state)) = touched.evicted {
+            self.forget(key, state);
+        }
+    }
+
+    pub fn accepts_compression(&self, role: PeerRole, identity: &[u8]) -> bool {
+        self.table
+            .get(&peer_key(role, identity))
+            .is_some_and(|state| state.compression)
+    }
+
+    pub fn set_compression(&mut self, role: PeerRole, identity: &[u8], enabled: bool) {
+        if let Some(state) = self.table.get_mut(&peer_key(role, identity)) {
+            state.compression = enabled;
+        }
+    }
+
+    // Track a chunk from a peer already seen by `record`.
+    pub fn observe_chunk(
+        &mut self,
+        role: PeerRole,
+        identity: &[u8],
+        path: ChunkPath,
+        header: ChunkHeader,
+        data_len: usize,
+        envelope: impl FnOnce() -> Vec<Vec<u8>>,
+    ) {
+        let now = Instant::now();
+        if let Some(state) = self.table.get_mut(&peer_key(role, identity)) {
+            self.chunks
+                .observe(&mut state.chunks, path, header, data_len, now, envelope);
+        }
+    }
+
+    // A client's hello decides whether compressed replies reach it as they are.
+    pub fn set_capabilities(&mut self, client: &[u8], capabilities: Capabilities) {
+        if let Some(state) = self.table.get_mut(&peer_key(PeerRole::Client, client)) {
+            state.capabilities = Some(capabilities);
+            state.compression = capabilities.contains(Capability::Compression);
+        }
+    }
+
+    pub fn capabilities(&self, client: &[u8]) -> Option<Capabilities> {
+        self.table
+            .get(&peer_key(PeerRole::Client, client))
+            .and_then(|state| state.capabilities)
+    }
+
+    pub fn set_ingress(&mut self, client: &[u8], ingress: usize) {
+        if let Some(state) = self.table.get_mut(&peer_key(PeerRole::Client, client)) {
+            state.ingress = ingress;
+        }
+    }
+
+    pub fn ingress(&self, client: &[u8]) -> Option<usize> {
+        self.table
+            .get(&peer_key(PeerRole::Client, client))
+            .map(|state| state.ingress)
+    }
+
+    // A client request reached a worker.
+    pub fn request_forwarded(&mut self, key: &[u8]) {
+        if let Some(state) = self.table.get_mut(key) {
+            state.in_flight += 1;
+            self.in_flight += 1;
+        }
+    }
+
+    // A worker answered; replies nobody is waiting for are not counted.
+    pub fn reply_delivered(&mut self, key: &[u8]) {
+        if let Some(state) = self.table.get_mut(key).filter(|s| s.in_flight > 0) {
+            state.in_flight -= 1;
+            self.in_flight -= 1;
+        }
+    }
+
+    // Unanswered client requests across all tracked clients.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight
+    }
+
+    // Transfers that timed out or whose sender was evicted since the last call.
+    pub fn expired(&mut self, now: Instant) -> Vec<ExpiredTransfer> {
+        let mut out = std::mem::take(&mut self.aborted);
+        let slots = self.table.iter_mut().map(|(_, state)| &mut state.chunks);
+        out.extend(self.chunks.expire(slots, now));
+        out
+    }
+
+    // One idle-GC tick over the table and the fan-out pool.
+    pub fn collect_idle(&mut self, gc: &mut IdleGc, workers: &mut WorkerPool, now: Instant) {
+        for (key, state) in gc.collect(now, &mut self.table, workers) {
+            self.forget(key, state);
+        }
+    }
+
+    fn forget(&mut self, key: PeerKey, state: PeerState) {
+        debug!("(Broker) Forgetting peer {:?}", key.as_slice());
+        self.in_flight -= u64::from(state.in_flight);
+        self.aborted.extend(self.chunks.forget(state.chunks));
+        if let (Some(forgotten), Some((PeerRole::Direct, identity))) =
+            (self.forgotten.as_mut(), split_key(&key))
+        {
+            forgotten.push(identity.to_vec());
+        }
+    }
+}
+
+//
+// ----------------------------- Client ingress --------------------------------
+//
+
+// The client-facing ROUTERs, one per [[network.client_facing]] entry, each
+// with its own socket options. A ROUTER identity only means something on the
+// socket the client connected to, so messages for a client go out on the
+// ingress its last message came in on, and a client the peer table has
+// forgotten is looked for on each ingress in turn. An identity used on two
+// ingresses at once gets its replies on the one it last sent on.
+pub struct ClientIngress {
+    pub routers: Vec<SocketChannel>,
+    pub endpoints: Vec<EndpointConfig>,
+    // The addresses bound, in order, wildcards resolved.
+    pub bound: Vec<String>,
+}
+
+impl ClientIngress {
+    pub fn bind(
+        context: &zmq::Context,
+        config: &Config,
+        metrics: &Registry,
+    ) -> Result<Self, zmq::Error> {
+        let endpoints = config.network.client_endpoints();
+        // A single client_facing_endpoint keeps its metrics unlabelled.
+        let labelled = !config.network.client_facing.is_empty();
+        let mut routers = Vec::with_capacity(endpoints.len());
+        let mut bound = Vec::new();
+        for endpoint in &endpoints {
+            let socket = context.socket(zmq::ROUTER)?;
+            let router = if labelled {
+                SocketChannel::on_endpoint(socket, CLIENT_ROUTER, &endpoint.address, metrics)
+            } else {
+                SocketChannel::new(socket, CLIENT_ROUTER, metrics)
+            };
+            configure_socket(&router.socket)?;
+            let options = &config.network.socket_options;
+            apply_socket_options(&router.socket, options, "Broker", CLIENT_ROUTER)?;
+            router.socket.set_router_mandatory(true)?; // Fail if routing identity doesn't exist
+            router
+                .socket
+                .set_zap_domain(&broker_domain(CLIENT_ROUTER))?;
+            configure_endpoint(&router.socket, endpoint, &config.auth)?;
+            let addresses = match labelled {
+                true => vec![endpoint.address.clone()],
+                false => config.network.client_facing_addresses(),
+            };
+            let what = format!(
+                "{} (ROUTER{}{})",
+                router.name,
+                if endpoint.curve { ", CURVE" } else { "" },
+                match endpoint.maxmsgsize {
+                    size if size >= 0 => format!(", max message {} bytes", size),
+                    _ => String::new(),
+                }
+            );
+            let retry = &config.retry.bind;
+            bound.extend(bind_all(&router.socket, &addresses, retry, "Broker", &what)?);
+            routers.push(router);
+        }
+        Ok(Self {
+            routers,
+            endpoints,
+            bound,
+        })
+    }
+
+    // Send [client_id, ..] to the client, on whichever ingress it uses.
+    pub fn send(&self, peers: &Peers, message: Multipart) -> Result<(), zmq::Error> {
+        let (last, rest) = self.routers.split_last().expect("at least one ingress");
+        if rest.is_empty() {
+            return last.send(message);
+        }
+        if let Some(router) = peers.ingress(&message[0]).and_then(|i| self.routers.get(i)) {
+            return router.send(message);
+        }
+        for router in rest {
+            match message.send_copy(&router.socket, zmq::DONTWAIT) {
+                Ok(()) => {
+                    router.record_send();
+                    return Ok(());
+                }
+                Err(zmq::Error::EHOSTUNREACH) => continue,
+                Err(e) => {
+                    router.record_drop();
+                    return Err(e);
+                }
+            }
+        }
+        last.send(message)
+    }
+}
+
+//
+// ------------------------------ Broker ---------------------------------------
+//
+
+#[allow(clippy::too_many_arguments)]
+fn route_client_message(
+    clients: &ClientIngress,
+    ingress: usize,
+    worker_router: &SocketChannel,
+    peers: &mut Peers,
+    scatter: &mut ScatterGather,
+    compressor: &Compressor,
+    chaos: Option<&mut Chaos>,
+    journal: Option<&Journal>,
+    stamper: Option<&IngressStamper>,
+    quotas: Option<&Quotas>,
+    detail: LogDetail,
+) {
+    let client_router = &clients.routers[ingress];
+    let received = match stamper.is_some() || quotas.is_some() {
+        true => client_router.recv_with_properties(&[USER_ID, PEER_ADDRESS]),
+        false => client_router.recv().map(|message| (message, Vec::new())),
+    };
+    let Some((mut message, properties)) = received else {
+        return;
+    };
+    peers.record(PeerRole::Client, &message[0], &message, Instant::now());
+    peers.set_ingress(&message[0], ingress);
+    // [client_id, HELLO] or [client_id, HELLO, request]: answered here.
+    if message.len() <= 3 && message[1] == HELLO {
+        answer_hello(clients, ingress, peers, compressor, message);
+        return;
+    }
+    if let Some(quotas) = quotas {
+        let principal = properties.first().cloned().flatten().unwrap_or_default();
+        let now_ms = quota::now_ms();
+        if let Err(exceeded) =
+            quotas.admit(&principal, &String::from_utf8_lossy(&message[0]), now_ms)
+        {
+            debug!(
+                "(Broker) Quota exceeded for {} {:?}, rejecting request from {}",
+                exceeded.scope.label(),
+                exceeded.name,
+                String::from_utf8_lossy(&message[0])
+            );
+            if let Err(e) = client_router.send(exceeded.reply(&message[0], now_ms)) {
+                debug!("(Broker) Cannot deliver quota rejection: {}", e);
+            }
+            return;
+        }
+    }
+    if let Some(journal) = journal {
+        let (client, frames) = message.split_first().expect("ROUTER identity frame");
+        journal.record(
+            JournalDirection::Request,
+            client,
+            correlation_field(frames),
+            frames,
+        );
+    }
+    // [client_id, fanout header, payload..]: copies go to pool workers.
+    if let Some(spec) = message.get(1).and_then(|h| FanoutSpec::parse(h)) {
+        start_fanout(client_router, worker_router, scatter, message, spec);
+        return;
+    }
+    // [client_id, header, data]: routed to the worker by the client identity.
+    if message.len() >= 3 {
+        if let Some(header) = ChunkHeader::find(&message) {
+            let data_len = message[message.len() - 1].len();
+            peers.observe_chunk(
+                PeerRole::Client,
+                &message[0],
+                ChunkPath::ClientToWorker,
+                header,
+                data_len,
+                || vec![message[0].clone()],
+            );
+        }
+    }
+    if compressor.enabled() {
+        // A client sending compressed frames handles them end to end.
+        if message[1..].iter().any(|f| is_compressed(f)) {
+            peers.set_compression(PeerRole::Client, &message[0], true);
+        }
+        if peers.accepts_compression(PeerRole::Worker, &message[0]) {
+            compressor.compress(&mut message[1..]);
+        }
+    }
+    if let Some(stamper) = stamper {
+        let [principal, address] = <[_; 2]>::try_from(properties).unwrap_or_default();
+        message = stamper.stamp(message, ingress, principal, address, Instant::now());
+    }
+    let client = peer_key(PeerRole::Client, &message[0]);
+    let forwarded = match chaos {
+        Some(chaos) => chaos
+            .inject(Direction::Requests, message, Instant::now())
+            .into_iter()
+            .fold(false, |sent, m| {
+                client_router.relay(m, worker_router, detail) | sent
+            }),
+        None => client_router.relay(message, worker_router, detail),
+    };
+    if forwarded {
+        peers.request_forwarded(&client);
+    }
+}
+
+fn answer_hello(
+    clients: &ClientIngress,
+    ingress: usize,
+    peers: &mut Peers,
+    compressor: &Compressor,
+    message: Multipart,
+) {
+    let features = match compressor.enabled() {
+        true => Capabilities::of(&Capability::ALL),
+        false => Capabilities::of(&[Capability::Chunking, Capability::Fanout]),
+    };
+    let offer = Offer {
+        features,
+        max_message_bytes: clients.endpoints[ingress].maxmsgsize,
+        max_frame_bytes: compressor.max_frame_bytes(),
+    };
+    let (reply, agreed) = negotiate(message.get(2).map(Vec::as_slice), &offer);
+    let client = String::from_utf8_lossy(&message[0]).into_owned();
+    match agreed {
+        Some(agreed) => {
+            debug!("(Broker) Hello from {}: {:?}", client, agreed.labels());
+            peers.set_capabilities(&message[0], agreed);
+        }
+        None => warn!("(Broker) Rejected hello from {}", client),
+    }
+    let reply = Multipart::new(vec![message[0].clone(), HELLO.to_vec(), reply]);
+    if let Err(e) = clients.routers[ingress].send(reply) {
+        debug!("(Broker) Cannot answer hello from {}: {}", client, e);
+    }
+}
+
+// Decompress a reply for a client that did not opt in to compression.
+fn restore_for_client(
+    peers: &Peers,
+    compressor: &Compressor,
+    client: &[u8],
+    frames: &mut [Vec<u8>],
+) -> Result<(), String> {
+    if !compressor.enabled()
+        || peers.accepts_compression(PeerRole::Client, client)
+        || !frames.iter().any(|f| is_compressed(f))
+    {
+        return Ok(());
+    }
+    compressor.decompress(frames)
+}
+
+fn start_fanout(
+    client_router: &SocketChannel,
+    worker_router: &SocketChannel,
+    scatter: &mut ScatterGather,
+    message: Multipart,
+    spec: Result<FanoutSpec, String>,
+) {
+    let reply = match spec {
+        Ok(spec) => scatter.start(&message[0], spec, &message[2..], Instant::now(), |copy| {
+            let result = worker_router.send(copy);
+            if let Err(e) = &result {
+                debug!("(Broker) Fan-out dispatch failed: {}", e);
+            }
+            result
+        }),
+        Err(e) => {
+            warn!("(Broker) Rejecting fan-out request: {}", e);
+            Some(error_reply(&message[0], &e))
+        }
+    };
+    if let Some(reply) = reply {
+        if let Err(e) = client_router.send(reply) {
+            debug!("(Broker) Cannot deliver fan-out reply: {}", e);
+        }
+    }
+}
+
+fn save_quotas(quotas: &Quotas) {
+    if let Err(e) = quotas.save() {
+        warn!("(Broker) Cannot save quota usage: {}", e);
+    }
+}
+
+fn send_fanout_reply(clients: &ClientIngress, peers: &Peers, reply: Multipart) {
+    if let Err(e) = clients.send(peers, reply) {
+        debug!("(Broker) Cannot deliver fan-out reply: {}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route_worker_message(
+    worker_router: &SocketChannel,
+    clients: &ClientIngress,
+    peers: &mut Peers,
+    scatter: &mut ScatterGather,
+    compressor: &Compressor,
+    chaos: Option<&mut Chaos>,
+    journal: Option<&Journal>,
+    strip_metadata: bool,
+    detail: LogDetail,
+) {
+    let Some(message) = worker_router.recv() else {
+        return;
+    };
+    if message.len() < 2 {
+        warn!(
+            "(Broker) Worker message too short ({} frames): {}",
+            message.len(),
+            format_message(&message, worker_router.envelope, RenderStyle::Compact)
+        );
+        return;
+    }
+    // [worker_id, DISCONNECT]: handled before `record`, which would add a
+    // worker that is leaving back to the table.
+    if message.len() == 2 && message[1] == WORKER_DISCONNECT {
+        let worker = String::from_utf8_lossy(&message[0]);
+        let (done, known) = scatter.worker_left(&message[0], |m| worker_router.send(m));
+        for reply in done {
+            send_fanout_reply(clients, peers, reply);
+        }
+        if known
+            || peers
+                .table
+                .get(&peer_key(PeerRole::Worker, &message[0]))
+                .is_some()
+        {
+            info!("(Broker) Worker {} disconnected", worker);
+            peers.depart(&message[0]);
+        } else {
+            debug!("(Broker) DISCONNECT from unknown worker {}", worker);
+        }
+        return;
+    }
+    peers.record(PeerRole::Worker, &message[0], &message, Instant::now());
+    // [worker_id, READY] or [worker_id, READY, capabilities]
+    if message.len() <= 3 && message[1] == WORKER_READY {
+        debug!(
+            "(Broker) Worker {} ready",
+            String::from_utf8_lossy(&message[0])
+        );
+        scatter.workers.add(&message[0]);
+        let compression = advertises_compression(message.get(2).map(Vec::as_slice));
+        peers.set_compression(PeerRole::Worker, &message[0], compression);
+        return;
+    }
+    // [worker_id, client_id, ..]: a worker may echo the request's metadata.
+    let message = match strip_metadata {
+        true => ingress::strip(message, 2),
+        false => message,
+    };
+
+    // [worker_id, client_id, fanout tag, reply..]: collected for the
+    // client, neither echoed nor forwarded.
+    if let Some(id) = message.get(2).and_then(|t| parse_tag(t)) {
+        let mut frames = message.into_frames().into_iter();
+        let worker = frames.next().unwrap_or_default();
+        let client = frames.next().unwrap_or_default();
+        let mut reply: Vec<Vec<u8>> = frames.skip(1).collect();
+        if let Err(e) = restore_for_client(peers, compressor, &client, &mut reply) {
+            warn!(
+                "(Broker) Fan-out reply from {}: {}",
+                String::from_utf8_lossy(&worker),
+                e
+            );
+            reply = decompression_error(&client, &e).into_frames().split_off(1);
+        }
+        if let Some(journal) = journal {
+            let correlation = correlation_field(&reply);
+            journal.record(JournalDirection::Reply, &client, correlation, &reply);
+        }
+        if let Some(done) = scatter.on_reply(id, &worker, reply) {
+            send_fanout_reply(clients, peers, done);
+        }
+        return;
+    }
+
+    // First frame is the worker's identity (added by ROUTER), so the received
+    // message is already addressed for the echo.
+    detail.sampled(worker_router).log(
+        format_args!("Received from {}", worker_router.name),
+        &message,
+        worker_router.envelope,
+    );
+
+    // Echo back to the worker (for testing/acknowledgment)
+    match worker_router.send_copy(&message) {
+        Ok(_) => {}
+        Err(zmq::Error::EAGAIN) => {
+            warn!("(Broker) Send would block, dropping message");
+        }
+        Err(e) => {
+            error!("(Broker) Error echoing to worker: {}", e);
+        }
+    }
+
+    // [worker_id, client_id, header, data]
+    if message.len() >= 4 {
+        if let Some(header) = ChunkHeader::find(&message) {
+            let data_len = message[message.len() - 1].len();
+            peers.observe_chunk(
+                PeerRole::Worker,
+                &message[0],
+                ChunkPath::WorkerToClient,
+                header,
+                data_len,
+                || vec![message[1].clone()],
+            );
+        }
+    }
+
+    // Forward to client_router if there's a valid routing identity
+    if message.len() >= 3 {
+        let mut reply = message.without_first();
+        let (client, frames) = reply.split_first_mut().expect("at least two frames");
+        if let Err(e) = restore_for_client(peers, compressor, client, frames) {
+            warn!(
+                "(Broker) Reply for {}: {}",
+                String::from_utf8_lossy(client),
+                e
+            );
+            reply = decompression_error(client, &e);
+        }
+        if let Some(journal) = journal {
+            let (client, frames) = reply.split_first().expect("client frame");
+            journal.record(
+                JournalDirection::Reply,
+                client,
+                correlation_field(frames),
+                frames,
+            );
+        }
+        let client = peer_key(PeerRole::Client, &reply[0]);
+        let delivered = match chaos {
+            Some(chaos) => chaos
+                .inject(Direction::Replies, reply, Instant::now())
+                .into_iter()
+                .fold(false, |sent, m| send_reply(clients, peers, m) | sent),
+            None => send_reply(clients, peers, reply),
+        };
+        if delivered {
+            peers.reply_delivered(&client);
+        }
+    }
+}
+
+// [client_id, reply..] to the client's ingress.
+fn send_reply(clients: &ClientIngress, peers: &Peers, reply: Multipart) -> bool {
+    match clients.send(peers, reply) {
+        Ok(_) => return true,
+        Err(zmq::Error::EAGAIN) => {
+            warn!("(Broker) Send would block, dropping message");
+        }
+        Err(e) => {
+            debug!("(Broker) Cannot forward to client: {}", e);
+        }
+    }
+    false
+}
+
+fn route_direct_message(
+    router: &SocketChannel,
+    peers: &mut Peers,
+    scheduler: Option<&Mutex<Scheduler>>,
+    watches: Option<&mut Watches>,
+    detail: LogDetail,
+) {
+    let Some(msg) = router.recv() else {
+        return;
+    };
+    peers.record(PeerRole::Direct, &msg[0], &msg, Instant::now());
+    detail
+        .sampled(router)
+        .log(
+            format_args!("Received from {}", router.name),
+            &msg,
+            router.envelope,
+        );
+    if let Some(handled) = scheduler.and_then(|s| schedule_direct_message(s, &msg)) {
+        if let Some(reply) = handled {
+            if let Err(e) = router.send(reply) {
+                debug!(
+                    "(Broker) Cannot answer {} scheduler request: {}",
+                    router.name, e
+                );
+            }
+        }
+        return;
+    }
+    // [sender_id, {"cmd": "watch"|"unwatch", ..}]
+    if let (2, Some(watches)) = (msg.len(), watches) {
+        if let Some(command) = WatchCommand::parse(&msg[1]) {
+            let reply = match command {
+                Ok(command) => watches.command(&msg[0], command),
+                Err(e) => watch::rejected_reply(&msg[0], &e),
+            };
+            if let Err(e) = router.send(reply) {
+                debug!(
+                    "(Broker) Cannot answer {} watch request: {}",
+                    router.name, e
+                );
+            }
+            return;
+        }
+    }
+
+    // Chunked transfers add a header frame: [sender_id, target_id, header, data].
+    let chunk = match msg.len() {
+        4 => ChunkHeader::find(&msg),
+        _ => None,
+    };
+
+    if msg.len() == 3 || chunk.is_some() {
+        // Protocol: client sends [target_id, payload] via DEALER.
+        // ROUTER prepends sender identity → [sender_id, target_id, payload].
+        // Swap the two identities to address the target.
+        if let Some(header) = chunk {
+            peers.observe_chunk(
+                PeerRole::Direct,
+                &msg[0],
+                ChunkPath::Direct,
+                header,
+                msg[3].len(),
+                || vec![msg[1].clone(), msg[0].clone()],
+            );
+        }
+        let mut msg = msg;
+        msg.swap(0, 1);
+
+        match router.send(msg) {
+            Ok(_) => {}
+            Err(zmq::Error::EAGAIN) => {
+                suppress::log(
+                    &DIRECT_BLOCKED,
+                    format_args!("(Broker) Send would block, dropping message"),
+                );
+            }
+            Err(zmq::Error::EHOSTUNREACH) => {
+                debug!("(Broker) Direct target not connected, dropping message");
+            }
+            Err(e) => {
+                suppress::log(
+                    &DIRECT_FAILED,
+                    format_args!("(Broker) Error sending to {}: {}", router.name, e),
+                );
+            }
+        }
+    } else {
+        warn!(
+            "(Broker) Unexpected {} message ({} frames): {}",
+            router.name,
+            msg.len(),
+            format_message(&msg, router.envelope, RenderStyle::Compact)
+        );
+    }
+}
+
+// Handle a cancel command or a scheduled direct message. None when `msg` is
+// not for the scheduler, otherwise the reply for the sender, if any.
+fn schedule_direct_message(
+    scheduler: &Mutex<Scheduler>,
+    msg: &Multipart,
+) -> Option<Option<Multipart>> {
+    // [sender_id, __corky_scheduler__, {"cancel_id"}]
+    if msg.len() == 3 && msg[1] == SCHEDULER_ID {
+        let mut scheduler = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+        return Some(Some(match parse_cancel(&msg[2]) {
+            Ok(cancel_id) => cancel_reply(&msg[0], scheduler.cancel(Some(&msg[0]), &cancel_id)),
+            Err(e) => rejected_reply(&msg[0], &e),
+        }));
+    }
+    // [sender_id, target_id, schedule header, payload..]
+    if msg.len() < 4 {
+        return None;
+    }
+    let spec = match ScheduleSpec::parse(&msg[2])? {
+        Ok(spec) => spec,
+        Err(e) => return Some(Some(rejected_reply(&msg[0], &e))),
+    };
+    let mut frames = msg.to_vec();
+    frames.remove(2);
+    frames.swap(0, 1);
+    let mut scheduler = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    match scheduler.schedule(Destination::Direct, &msg[0], spec, frames.into(), now, wall) {
+        Ok(_) => Some(None),
+        Err(e) => Some(Some(rejected_reply(&msg[0], &e))),
+    }
+}
+
+// Hand on every scheduled message that is due. Direct messages whose target
+// is not connected go to the offline queue.
+fn deliver_due(
+    scheduler: &Mutex<Scheduler>,
+    direct_router: &SocketChannel,
+    offline: &mut OfflineQueue,
+    pipeline: Option<&PipelineSockets>,
+    relay: &mut TaskRelay,
+    now: Instant,
+) -> Option<Instant> {
+    let (due, next) = {
+        let mut scheduler = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+        (scheduler.due(now), scheduler.next_deadline())
+    };
+    for entry in due {
+        match (entry.destination, pipeline) {
+            (Destination::Direct, _) => {
+                if !send_direct(direct_router, &entry.message) {
+                    offline.push(entry.message, now);
+                }
+            }
+            (Destination::Pipeline, Some(sockets)) => {
+                let bytes = entry.message.iter().map(|f| f.len() as u64).sum();
+                relay.submit(sockets, entry.message, bytes);
+            }
+            (Destination::Pipeline, None) => {
+                warn!("(Broker) Dropping scheduled pipeline task: pipeline is disabled");
+            }
+        }
+    }
+    next
+}
+
+// Send one direct message; false while the target is not connected.
+fn send_direct(router: &SocketChannel, message: &Multipart) -> bool {
+    match router.send_copy(message) {
+        Ok(()) => true,
+        Err(zmq::Error::EHOSTUNREACH) | Err(zmq::Error::EAGAIN) => false,
+        Err(e) => {
+            suppress::log(
+                &RETRY_FAILED,
+                format_args!(
+                    "(Broker) Dropping scheduled message on {}: {}",
+                    router.name, e
+                ),
+            );
+            true
+        }
+    }
+}
+
+fn publish_bound(runtime: &Runtime, role: &str, bound: Vec<String>) {
+    if let Err(e) = runtime.endpoints.set(role, bound) {
+        warn!("(Broker) {}", e);
+    }
+}
+
+pub fn run_broker(
+    context: &zmq::Context,
+    config: &Arc<Config>,
+    runtime: &Runtime,
+    shutdown: &Arc<AtomicBool>,
+) -> Result<(), zmq::Error> {
+    let metrics = &runtime.metrics;
+    set_sample_every(config.logging.sample_every);
+
+    // (1) ROUTER for direct client<->client messaging
+    let direct_router = SocketChannel::new(context.socket(zmq::ROUTER)?, DIRECT_ROUTER, metrics);
+    configure_socket(&direct_router.socket)?;
+    let socket_options = &config.network.socket_options;
+    apply_socket_options(&direct_router.socket, socket_options, "Broker", DIRECT_ROUTER)?;
+    if config.schedule.enabled || config.watch.enabled {
+        // Due scheduled messages and watch notifications must learn that
+        // their target is offline.
+        direct_router.socket.set_router_mandatory(true)?;
+    }
+    // Consult the ZAP handler, which refuses new clients while quiescing or
+    // over a connection limit.
+    direct_router
+        .socket
+        .set_zap_domain(&broker_domain(DIRECT_ROUTER))?;
+    let bind_retry = &config.retry.bind;
+    // Without direct messaging the ROUTER stays unbound and out of the poll
+    // set; sends to it find no peer.
+    let direct_messaging = config.broker.direct_messaging;
+    if direct_messaging {
+        let bound = bind_all(
+            &direct_router.socket,
+            &config.network.client_to_client_addresses(),
+            bind_retry,
+            "Broker",
+            &format!("{} (ROUTER)", direct_router.name),
+        )?;
+        publish_bound(runtime, ROLE_CLIENT_TO_CLIENT, bound);
+    } else {
+        info!("(Broker) Direct messaging disabled; client-to-client endpoint not bound");
+    }
+
+    // (2) Client-facing ROUTERs (frontend), one per configured endpoint
+    let clients = ClientIngress::bind(context, config, metrics)?;
+    publish_bound(runtime, ROLE_CLIENT_FACING, clients.bound.clone());
+
+    // (3) Worker-facing ROUTER (backend)
+    let worker_router = SocketChannel::new(context.socket(zmq::ROUTER)?, WORKER_ROUTER, metrics);
+    configure_socket(&worker_router.socket)?;
+    apply_socket_options(&worker_router.socket, socket_options, "Broker", WORKER_ROUTER)?;
+    worker_router.socket.set_router_mandatory(true)?; // Detect departed workers on send
+    worker_router
+        .socket
+        .set_zap_domain(&broker_domain(WORKER_ROUTER))?;
+    let bound = bind_all(
+        &worker_router.socket,
+        &config.network.worker_facing_addresses(),
+        bind_retry,
+        "Broker",
+        &format!("{} (ROUTER)", worker_router.name),
+    )?;
+    publish_bound(runtime, ROLE_WORKER_FACING, bound);
+
+    if direct_messaging {
+        runtime
+            .limits
+            .watch(context, &[&direct_router.socket], direct_router.name)?;
+    }
+    runtime
+        .limits
+        .watch(context, &[&worker_router.socket], worker_router.name)?;
+    // The caps are per role, so every client ingress counts towards one.
+    let client_sockets: Vec<&zmq::Socket> = clients.routers.iter().map(|r| &r.socket).collect();
+    runtime
+        .limits
+        .watch(context, &client_sockets, CLIENT_ROUTER)?;
+    let mut monitor_tick = Periodic::new(Duration::from_millis(MONITOR_TICK_MS));
+
+    let low_latency = config.broker.latency_mode == LatencyMode::Low;
+    let poll_timeout = if low_latency {
+        LOW_LATENCY_POLL_TIMEOUT_MS
+    } else {
+        i64::try_from(config.broker.poll_timeout_ms).unwrap_or(i64::MAX)
+    };
+    if low_latency {
+        warn!("(Broker) Low-latency mode: busy-polling, expect one core at 100%");
+    }
+
+    info!("(Broker) Broker loop started. Polling for messages...");
+
+    // Optional active/passive pairing; without it this instance is always active.
+    let ha_link = if config.ha.enabled {
+        let routing_id =
+            identity::routing_id(&config.identity, config.ha.routing_id.as_deref(), "ha").map_err(
+                |e| {
+                    error!("(Broker) HA link identity: {}", e);
+                    zmq::Error::EINVAL
+                },
+            )?;
+        runtime.identities.set("ha", &routing_id);
+        Some(HaLink::new(
+            context,
+            &config.ha,
+            &config.resolve,
+            bind_retry,
+            &routing_id,
+        )?)
+    } else {
+        None
+    };
+    let mut ha = ha_link.as_ref().map(|_| {
+        BinaryStar::new(
+            config.ha.role,
+            Duration::from_millis(config.ha.failover_ms),
+            Instant::now(),
+        )
+    });
+    let mut ha_heartbeat = Periodic::new(Duration::from_millis(config.ha.heartbeat_ms));
+    let ha_active: Gauge = metrics.gauge("corky_ha_active", &[]);
+    ha_active.set(ha.as_ref().is_none_or(BinaryStar::is_active) as i64);
+
+    let mut poll_items = vec![
+        clients.routers[0].socket.as_poll_item(zmq::POLLIN),
+        worker_router.socket.as_poll_item(zmq::POLLIN),
+    ];
+    // Further client ingresses follow the fixed sockets.
+    let idx_more_clients = poll_items.len();
+    for router in &clients.routers[1..] {
+        poll_items.push(router.socket.as_poll_item(zmq::POLLIN));
+    }
+    let ingress_of = |idx: usize| match idx {
+        IDX_CLIENT_ROUTER => Some(0),
+        idx if (idx_more_clients..idx_more_clients + clients.routers.len() - 1).contains(&idx) => {
+            Some(idx - idx_more_clients + 1)
+        }
+        _ => None,
+    };
+    // Optional sockets follow the fixed ones; their slots depend on config.
+    let mut idx_direct = None;
+    if direct_messaging {
+        idx_direct = Some(poll_items.len());
+        poll_items.push(direct_router.socket.as_poll_item(zmq::POLLIN));
+    }
+    let mut idx_ha = None;
+    if let Some(link) = &ha_link {
+        idx_ha = Some(poll_items.len());
+        poll_items.push(link.as_poll_item());
+    }
+
+    let pipeline = if config.pipeline.enabled {
+        Some(PipelineSockets::bind(
+            context,
+            &config.pipeline,
+            bind_retry,
+            metrics,
+        )?)
+    } else {
+        None
+    };
+    let mut relay = TaskRelay::new(&config.pipeline, &runtime.budget, metrics);
+    let mut idx_pull = None;
+    let mut idx_push = None;
+    if let Some(sockets) = &pipeline {
+        idx_pull = Some(poll_items.len());
+        poll_items.push(sockets.pull.socket.as_poll_item(zmq::POLLIN));
+        idx_push = Some(poll_items.len());
+        poll_items.push(sockets.push.socket.as_poll_item(zmq::POLLOUT));
+    }
+
+    // Periodic work is deadline-based so it fires on wall-clock time whether
+    // poll() returns every 10ms or spins with a zero timeout.
+    let mut stats_tick = Periodic::new(Duration::from_millis(STATS_INTERVAL_MS));
+    let mut polls = 0u64;
+    let mut events = 0u64;
+
+    let chunk_timeout = Duration::from_millis(config.broker.chunk_timeout_ms);
+    let mut peers = Peers::new(config.broker.max_peers, chunk_timeout);
+    let peer_ttl = Duration::from_millis(config.broker.peer_idle_ttl_ms);
+    let mut gc = IdleGc::new(&config.gc, peer_ttl, metrics);
+    let gc_events = if config.gc.notify_endpoint.is_empty() {
+        None
+    } else {
+        let events = SocketChannel::new(context.socket(zmq::PUB)?, GC_EVENTS, metrics);
+        configure_socket(&events.socket)?;
+        bind_with_retry(
+            &events.socket,
+            &config.gc.notify_endpoint,
+            bind_retry,
+            "Broker",
+        )?;
+        info!(
+            "(Broker) {} (PUB) bound to {}",
+            events.name, config.gc.notify_endpoint
+        );
+        Some(events)
+    };
+    let mut event_stream = if config.events.endpoint.is_empty() {
+        None
+    } else {
+        peers.announce_joins();
+        gc.queue_gone();
+        Some(EventStream::bind(
+            context,
+            &config.events,
+            bind_retry,
+            metrics,
+        )?)
+    };
+    let mut watches = config.watch.enabled.then(|| {
+        peers.announce_joins();
+        gc.queue_gone();
+        Watches::new(&config.watch, metrics)
+    });
+    let journal = if config.journal.enabled {
+        let journal = Journal::open(&config.journal, runtime.keyring.clone(), metrics);
+        Some(journal.map_err(|e| {
+            error!("(Broker) Journal: {}", e);
+            zmq::Error::EINVAL
+        })?)
+    } else {
+        None
+    };
+    let stamper = config
+        .ingress
+        .metadata
+        .then(|| IngressStamper::new(&clients.endpoints, Instant::now()));
+    let quotas = runtime.quotas.enabled().then_some(&*runtime.quotas);
+    let mut quota_persist = Periodic::new(Duration::from_millis(
+        config.quota.persist_interval_ms.max(1),
+    ));
+    let mut was_active = ha.as_ref().is_none_or(BinaryStar::is_active);
+    let mut peer_sweep = Periodic::new(Duration::from_millis(PEER_SWEEP_MS));
+    let peers_tracked: Gauge = metrics.gauge("corky_broker_peers", &[]);
+    let chunks_active: Gauge = metrics.gauge("corky_broker_chunk_transfers_active", &[]);
+    let mut scatter = ScatterGather::new(metrics).with_hedging(&config.hedge, metrics);
+    let compressor = Compressor::new(&config.compression, metrics);
+    let mut fanout_sweep = Periodic::new(Duration::from_millis(FANOUT_SWEEP_MS));
+    let scheduler = config.schedule.enabled.then_some(&*runtime.scheduler);
+    let mut offline = OfflineQueue::new(&config.schedule, &runtime.budget, metrics);
+    if let Some(store) = &runtime.store {
+        offline = offline.with_store(Arc::clone(store), Instant::now(), SystemTime::now());
+    }
+    let mut schedule_tick = Periodic::new(Duration::from_millis(SCHEDULE_TICK_MS));
+    let mut offline_retry = Periodic::new(Duration::from_millis(OFFLINE_RETRY_MS));
+    let mut next_scheduled: Option<Instant> = None;
+    let mut chaos = config.chaos.enabled.then(|| {
+        warn!("(Broker) Chaos mode on: injecting faults into requests and replies");
+        let seed = match config.chaos.seed {
+            0 => {
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64)
+                    ^ std::process::id() as u64
+            }
+            seed => seed,
+        };
+        info!("(Broker) Chaos seed {}", seed);
+        Chaos::new(&runtime.chaos, seed, metrics)
+    });
+    let mut drain_progress = Periodic::new(Duration::from_millis(DRAIN_PROGRESS_MS));
+    let mut repeat_flush = Periodic::new(Duration::from_millis(REPEAT_FLUSH_MS));
+    let in_flight: Gauge = metrics.gauge("corky_broker_in_flight_requests", &[]);
+    let mut drained = (0, 0);
+    let mut chunk_sweep = Periodic::new((chunk_timeout / 4).clamp(
+        Duration::from_millis(MIN_CHUNK_SWEEP_MS),
+        Duration::from_millis(MAX_CHUNK_SWEEP_MS),
+    ));
+
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            info!("(Broker) Shutdown requested...");
+            if let Some(quotas) = quotas {
+                save_quotas(quotas);
+            }
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if peer_sweep.poll(now) {
+            peers.collect_idle(&mut gc, &mut scatter.workers, now);
+            for gone in gc.take_gone() {
+                let event = BrokerEvent::gone(&gone);
+                if let Some(watches) = watches.as_mut() {
+                    watches.notify(&event);
+                }
+                if let Some(stream) = event_stream.as_mut() {
+                    stream.push(event);
+                }
+                if let Some(events) = &gc_events {
+                    if let Err(e) = events.send(gone.into_message()) {
+                        debug!("(Broker) Cannot publish peer_gone: {}", e);
+                    }
+                }
+            }
+            peers_tracked.set(peers.table.len() as i64);
+            chunks_active.set(peers.chunks.stats().active as i64);
+        }
+
+        if chunk_sweep.poll(now) {
+            for expired in peers.expired(now) {
+                let name = match expired.path {
+                    ChunkPath::ClientToWorker => worker_router.name,
+                    ChunkPath::WorkerToClient => CLIENT_ROUTER,
+                    ChunkPath::Direct => direct_router.name,
+                };
+                warn!(
+                    "(Broker) Chunked transfer {:016x} timed out after {}/{} chunks, notifying receiver on {}",
+                    expired.transfer_id, expired.received, expired.total, name
+                );
+                if let Some(stream) = event_stream.as_mut() {
+                    stream.push(BrokerEvent::transfer_timeout(&expired));
+                }
+                let path = expired.path;
+                let message = expired.into_error_message().into();
+                let sent = match path {
+                    ChunkPath::ClientToWorker => worker_router.send(message),
+                    ChunkPath::WorkerToClient => clients.send(&peers, message),
+                    ChunkPath::Direct => direct_router.send(message),
+                };
+                if let Err(e) = sent {
+                    warn!(
+                        "(Broker) Could not deliver chunk timeout on {}: {}",
+                        name, e
+                    );
+                }
+            }
+        }
+
+        if let Some(quotas) = quotas.filter(|_| quota_persist.poll(now)) {
+            save_quotas(quotas);
+        }
+
+        if fanout_sweep.poll(now) {
+            let hedged = scatter.hedge(now, |copy| worker_router.send(copy));
+            if hedged > 0 {
+                debug!("(Broker) Hedged {} slow fan-out(s)", hedged);
+            }
+            for reply in scatter.expire(now) {
+                if let Some(stream) = event_stream.as_mut() {
+                    stream.push(BrokerEvent::RequestTimeout {
+                        client: reply[0].to_vec(),
+                    });
+                }
+                send_fanout_reply(&clients, &peers, reply);
+            }
+        }
+
+        if let Some(scheduler) = scheduler {
+            if schedule_tick.poll(now) {
+                next_scheduled = deliver_due(
+                    scheduler,
+                    &direct_router,
+                    &mut offline,
+                    pipeline.as_ref(),
+                    &mut relay,
+                    now,
+                );
+            }
+            if !offline.is_empty() && offline_retry.poll(now) {
+                offline.retry(now, |message| send_direct(&direct_router, message));
+                if runtime.budget.over_budget() {
+                    runtime.budget.enforce(&mut [&mut offline]);
+                }
+            }
+        }
+
+        if let Some(chaos) = chaos.as_mut() {
+            for (direction, message) in chaos.due(now) {
+                match direction {
+                    Direction::Requests => {
+                        clients.routers[0].relay(message, &worker_router, LogDetail::Off);
+                    }
+                    Direction::Replies => {
+                        send_reply(&clients, &peers, message);
+                    }
+                }
+            }
+        }
+
+        if monitor_tick.poll(now) {
+            runtime.limits.refresh();
+        }
+
+        if repeat_flush.poll(now) {
+            suppress::flush(now);
+        }
+
+        if drain_progress.poll(now) {
+            // Client-side sockets only: those are the connections a drain waits for.
+            let connections =
+                runtime.limits.open(DIRECT_ROUTER) + runtime.limits.open(CLIENT_ROUTER);
+            let requests = peers.in_flight() as i64 + scatter.inflight() as i64;
+            in_flight.set(requests);
+            // Report while draining, whenever the numbers move.
+            if runtime.quiesce.is_draining() && (connections, requests) != drained {
+                info!(
+                    "(Broker) Draining: {} connections, {} requests in flight",
+                    connections, requests
+                );
+            }
+            drained = (connections, requests);
+        }
+
+        if let (Some(link), Some(ha)) = (&ha_link, &ha) {
+            if ha_heartbeat.poll(now) {
+                link.send_state(ha.state());
+            }
+        }
+
+        if stats_tick.poll(now) {
+            debug!(
+                "(Broker) {} polls, {} readable events in the last {}ms",
+                polls, events, STATS_INTERVAL_MS
+            );
+            let ingress = clients.routers.iter().zip(&clients.endpoints);
+            for (channel, endpoint) in ingress {
+                debug!(
+                    "(Broker) {} on {}: {} received, {} sent, {} dropped",
+                    channel.name,
+                    endpoint.address,
+                    channel.received(),
+                    channel.sent(),
+                    channel.dropped()
+                );
+            }
+            for channel in [&direct_router, &worker_router] {
+                debug!(
+                    "(Broker) {}: {} received, {} sent, {} dropped",
+                    channel.name,
+                    channel.received(),
+                    channel.sent(),
+                    channel.dropped()
+                );
+            }
+            debug!(
+                "(Broker) peers: {} tracked (cap {}), {} evicted",
+                peers.table.len(),
+                peers.table.max_peers(),
+                peers.table.evicted()
+            );
+            let c = peers.chunks.stats();
+            debug!(
+                "(Broker) chunks: {} active, {} completed, {} timed out, {} out of order, {} bytes",
+                c.active, c.completed, c.expired, c.out_of_order, c.bytes
+            );
+            polls = 0;
+            events = 0;
+        }
+
+        if let (Some(pull), Some(push)) = (idx_pull, idx_push) {
+            // Stop reading tasks while one is stuck or while passive.
+            let active = ha.as_ref().is_none_or(BinaryStar::is_active);
+            let read = if relay.wants_input() && active {
+                zmq::POLLIN
+            } else {
+                zmq::PollEvents::empty()
+            };
+            poll_items[pull].set_events(read);
+            let write = if relay.is_blocked() {
+                zmq::POLLOUT
+            } else {
+                zmq::PollEvents::empty()
+            };
+            poll_items[push].set_events(write);
+        }
+
+        let joined = peers
+            .take_joined()
+            .into_iter()
+            .map(|(role, identity)| BrokerEvent::Connected { role, identity });
+        let departed = peers
+            .take_departed()
+            .into_iter()
+            .map(|identity| BrokerEvent::Disconnected { identity });
+        for event in joined.chain(departed) {
+            if let Some(watches) = watches.as_mut() {
+                watches.notify(&event);
+            }
+            if let Some(stream) = event_stream.as_mut() {
+                stream.push(event);
+            }
+        }
+        let forgotten = peers.take_forgotten();
+        if let Some(watches) = watches.as_mut() {
+            for watcher in forgotten {
+                watches.forget(&watcher);
+            }
+            watches.flush(|message| direct_router.send_copy(message));
+        }
+        if let Some(stream) = event_stream.as_mut() {
+            let active = ha.as_ref().is_none_or(BinaryStar::is_active);
+            if active != was_active {
+                stream.push(BrokerEvent::HaState { active });
+                was_active = active;
+            }
+            stream.flush();
+        }
+
+        // Wake up for the next scheduled or delayed delivery rather than a
+        // full timeout.
+        let wake = next_scheduled
+            .into_iter()
+            .chain(chaos.as_ref().and_then(Chaos::next_deadline));
+        let timeout = match wake.min() {
+            Some(due) => poll_timeout.min(due.saturating_duration_since(now).as_millis() as i64),
+            None => poll_timeout,
+        };
+        match zmq::poll(&mut poll_items, timeout) {
+            Ok(_) => polls += 1,
+            Err(zmq::Error::EINTR) => continue, // Signal interrupted, just retry
+            Err(e) => return Err(e),
+        }
+
+        // Logging a message allocates, so low-latency mode never does it.
+        let detail = LogDetail::current(low_latency);
+
+        for (idx, poll_item) in poll_items.iter().enumerate() {
+            if poll_item.get_revents().is_empty() {
+                continue;
+            }
+            events += 1;
+            if let Some(sockets) = &pipeline {
+                if Some(idx) == idx_pull {
+                    relay.on_task(sockets, scheduler);
+                    continue;
+                }
+                if Some(idx) == idx_push {
+                    relay.flush(sockets);
+                    continue;
+                }
+            }
+            if let Some(ha) = ha.as_mut() {
+                if Some(idx) == idx_ha {
+                    let now = Instant::now();
+                    while let Some(peer) = ha_link.as_ref().and_then(HaLink::recv_state) {
+                        let _ = ha.on_peer(peer, now);
+                    }
+                    ha_active.set(ha.is_active() as i64);
+                    continue;
+                }
+                // Passive: traffic is dropped, but still counts as client
+                // activity that may confirm a failover.
+                if !ha.on_client_request(Instant::now()) {
+                    match (idx, ingress_of(idx)) {
+                        (idx, _) if Some(idx) == idx_direct => direct_router.discard(),
+                        (IDX_WORKER_ROUTER, _) => worker_router.discard(),
+                        (_, Some(ingress)) => clients.routers[ingress].discard(),
+                        _ => {}
+                    }
+                    continue;
+                }
+                ha_active.set(ha.is_active() as i64);
+            }
+            match idx {
+                idx if Some(idx) == idx_direct => route_direct_message(
+                    &direct_router,
+                    &mut peers,
+                    scheduler,
+                    watches.as_mut(),
+                    detail,
+                ),
+                IDX_WORKER_ROUTER => route_worker_message(
+                    &worker_router,
+                    &clients,
+                    &mut peers,
+                    &mut scatter,
+                    &compressor,
+                    chaos.as_mut(),
+                    journal.as_ref(),
+                    stamper.is_some(),
+                    detail,
+                ),
+                idx => match ingress_of(idx) {
+                    Some(ingress) => route_client_message(
+                        &clients,
+                        ingress,
+                        &worker_router,
+                        &mut peers,
+                        &mut scatter,
+                        &compressor,
+                        chaos.as_mut(),
+                        journal.as_ref(),
+                        stamper.as_ref(),
+                        quotas,
+                        detail,
+                    ),
+                    None => error!("(Broker) Unexpected poll index {}, skipping", idx),
+                },
+            }
+        }
+    }
+}
+
+// Pin the calling thread to one CPU core (for latency_mode = "low").
+pub fn pin_to_core(core: usize) -> Result<(), String> {
+    let ids = core_affinity::get_core_ids().ok_or("could not enumerate CPU cores")?;
+    let id = ids
+        .into_iter()
+        .find(|c| c.id == core)
+        .ok_or_else(|| format!("CPU core {} does not exist", core))?;
+    if core_affinity::set_for_current(id) {
+        Ok(())
+    } else {
+        Err(format!("failed to pin thread to CPU core {}", core))
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{Counter, Gauge, Registry};
+
+//
+// ---------------------------- Memory budget ----------------------------------
+//
+// Every broker-side queue charges the bytes it holds to one shared budget.
+// Charging and releasing are atomic add/sub, so queues can account from any
+// thread. When the total goes over the limit, queues are shed oldest-first in
+// the configured pool order until it fits again. In-flight replies are
+// charged like everything else but never shed.
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pool {
+    // Last-value caches and other keyed latest-state stores.
+    Lvc,
+    // Messages held for peers that are not connected yet or for their
+    // delivery time.
+    Offline,
+    // Replies being delivered; never shed.
+    #[serde(skip)]
+    InFlight,
+}
+
+impl Pool {
+    pub const ALL: [Pool; 3] = [Pool::Lvc, Pool::Offline, Pool::InFlight];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Pool::Lvc => "lvc",
+            Pool::Offline => "offline",
+            Pool::InFlight => "in_flight",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+pub const DEFAULT_SHED_ORDER: [Pool; 2] = [Pool::Lvc, Pool::Offline];
+
+struct PoolAccount {
+    bytes: Gauge,
+    shed_messages: Counter,
+    shed_bytes: Counter,
+}
+
+pub struct MemoryBudget {
+    limit: u64,
+    used: AtomicU64,
+    pools: [PoolAccount; 3],
+    order: Vec<Pool>,
+    shedding: AtomicBool,
+    shedding_gauge: Gauge,
+}
+
+impl MemoryBudget {
+    // `order` lists the pools to shed from first; InFlight is ignored.
+    pub fn new(limit: u64, order: &[Pool], metrics: &Registry) -> Arc<Self> {
+        let pools = Pool::ALL.map(|pool| {
+            let labels = [("pool", pool.label())];
+            PoolAccount {
+                bytes: metrics.gauge("corky_memory_bytes", &labels),
+                shed_messages: metrics.counter("corky_memory_shed_messages_total", &labels),
+                shed_bytes: metrics.counter("corky_memory_shed_bytes_total", &labels),
+            }
+        });
+        let mut unique = Vec::new();
+        for &pool in order {
+            if pool != Pool::InFlight && !unique.contains(&pool) {
+                unique.push(pool);
+            }
+        }
+        Arc::new(Self {
+            limit,
+            used: AtomicU64::new(0),
+            pools,
+            order: unique,
+            shedding: AtomicBool::new(false),
+            shedding_gauge: metrics.gauge("corky_memory_shedding", &[]),
+        })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn pool_bytes(&self, pool: Pool) -> u64 {
+        self.pools[pool.index()].bytes.get().max(0) as u64
+    }
+
+    pub fn shed_messages(&self, pool: Pool) -> u64 {
+        self.pools[pool.index()].shed_messages.get()
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.used() > self.limit
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    pub fn charge(&self, pool: Pool, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        self.pools[pool.index()].bytes.add(bytes as i64);
+    }
+
+    pub fn release(&self, pool: Pool, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.pools[pool.index()].bytes.add(-(bytes as i64));
+    }
+
+    pub fn record_shed(&self, pool: Pool, bytes: u64) {
+        let account = &self.pools[pool.index()];
+        account.shed_messages.inc();
+        account.shed_bytes.add(bytes);
+    }
+
+    // Shed from `queues` in pool order until the budget fits or nothing
+    // sheddable is left. Returns the number of messages dropped.
+    pub fn enforce(&self, queues: &mut [&mut dyn Shed]) -> usize {
+        let mut shed = 0;
+        if self.over_budget() {
+            self.set_shedding(true);
+        }
+        'pools: for &pool in &self.order {
+            for queue in queues.iter_mut().filter(|q| q.pool() == pool) {
+                while self.over_budget() {
+                    if queue.shed_oldest().is_none() {
+                        break;
+                    }
+                    shed += 1;
+                }
+                if !self.over_budget() {
+                    break 'pools;
+                }
+            }
+        }
+        if !self.over_budget() {
+            self.set_shedding(false);
+        }
+        shed
+    }
+
+    fn set_shedding(&self, on: bool) {
+        if self.shedding.swap(on, Ordering::Relaxed) == on {
+            return;
+        }
+        self.shedding_gauge.set(on as i64);
+        if on {
+            warn!(
+                "(Broker) Memory budget exceeded ({} of {} bytes), shedding queued messages",
+                self.used(),
+                self.limit
+            );
+        } else {
+            info!(
+                "(Broker) Memory back under budget ({} of {} bytes), shedding stopped",
+                self.used(),
+                self.limit
+            );
+        }
+    }
+}
+
+// A queue the budget can shed from.
+pub trait Shed {
+    fn pool(&self) -> Pool;
+    // Drop the oldest entry, returning its size.
+    fn shed_oldest(&mut self) -> Option<u64>;
+}
+
+// FIFO whose entries are charged to a pool of the memory budget.
+pub struct BudgetedQueue<T> {
+    pool: Pool,
+    budget: Arc<MemoryBudget>,
+    items: VecDeque<(T, u64)>,
+    bytes: u64,
+}
+
+impl<T> BudgetedQueue<T> {
+    pub fn new(pool: Pool, budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            pool,
+            budget: Arc::clone(budget),
+            items: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn push_back(&mut self, item: T, bytes: u64) {
+        self.budget.charge(self.pool, bytes);
+        self.bytes += bytes;
+        self.items.push_back((item, bytes));
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let (item, bytes) = self.items.pop_front()?;
+        self.budget.release(self.pool, bytes);
+        self.bytes -= bytes;
+        Some(item)
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.items.front().map(|(item, _)| item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter().map(|(item, _)| item)
+    }
+
+    // Keep only entries for which `keep` is true, releasing the rest.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        let (budget, pool) = (&self.budget, self.pool);
+        let mut released = 0;
+        self.items.retain(|(item, bytes)| {
+            let kept = keep(item);
+            if !kept {
+                released += *bytes;
+            }
+            kept
+        });
+        budget.release(pool, released);
+        self.bytes -= released;
+    }
+}
+
+impl<T> Shed for BudgetedQueue<T> {
+    fn pool(&self) -> Pool {
+        self.pool
+    }
+
+    fn shed_oldest(&mut self) -> Option<u64> {
+        if self.pool == Pool::InFlight {
+            return None;
+        }
+        let (_, bytes) = self.items.pop_front()?;
+        self.budget.release(self.pool, bytes);
+        self.budget.record_shed(self.pool, bytes);
+        self.bytes -= bytes;
+        Some(bytes)
+    }
+}
+
+impl<T> Drop for BudgetedQueue<T> {
+    fn drop(&mut self) {
+        self.budget.release(self.pool, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(queue: &mut BudgetedQueue<u32>, count: u32, bytes: u64) {
+        for i in 0..count {
+            queue.push_back(i, bytes);
+        }
+    }
+
+    #[test]
+    fn sheds_in_priority_order_and_never_in_flight() {
+        let budget = MemoryBudget::new(1000, &DEFAULT_SHED_ORDER, &Registry::new());
+        let mut lvc = BudgetedQueue::new(Pool::Lvc, &budget);
+        let mut offline = BudgetedQueue::new(Pool::Offline, &budget);
+        let mut replies = BudgetedQueue::new(Pool::InFlight, &budget);
+        fill(&mut lvc, 3, 100);
+        fill(&mut offline, 9, 100);
+        fill(&mut replies, 3, 100);
+        assert_eq!(budget.used(), 1500);
+
+        let shed = budget.enforce(&mut [&mut replies, &mut offline, &mut lvc]);
+        // 500 over: all 3 LVC entries go first, then the 2 oldest offline ones.
+        assert_eq!(shed, 5);
+        assert_eq!((lvc.len(), offline.len(), replies.len()), (0, 7, 3));
+        assert_eq!(offline.front(), Some(&2));
+        assert_eq!(budget.shed_messages(Pool::Lvc), 3);
+        assert_eq!(budget.shed_messages(Pool::Offline), 2);
+        assert_eq!(budget.used(), 1000);
+        assert!(!budget.is_shedding());
+
+        // Only in-flight replies left: the budget stays exceeded.
+        let (mut empty_lvc, mut empty_offline) = (
+            BudgetedQueue::<u32>::new(Pool::Lvc, &budget),
+            BudgetedQueue::<u32>::new(Pool::Offline, &budget),
+        );
+        drop(offline);
+        fill(&mut replies, 10, 100);
+        assert_eq!(
+            budget.enforce(&mut [&mut replies, &mut empty_lvc, &mut empty_offline]),
+            0
+        );
+        assert!(budget.is_shedding());
+        assert_eq!(replies.len(), 13);
+    }
+
+    #[test]
+    fn accounting_is_exact_after_drains() {
+        let budget = MemoryBudget::new(u64::MAX, &DEFAULT_SHED_ORDER, &Registry::new());
+        let mut offline = BudgetedQueue::new(Pool::Offline, &budget);
+        let mut lvc = BudgetedQueue::new(Pool::Lvc, &budget);
+        for i in 0..100u32 {
+            offline.push_back(i, i as u64 + 1);
+            lvc.push_back(i, 7);
+        }
+        assert_eq!(budget.pool_bytes(Pool::Offline), 5050);
+        while offline.pop_front().is_some() {}
+        lvc.retain(|&i| i % 2 == 0);
+        assert_eq!(budget.pool_bytes(Pool::Offline), 0);
+        assert_eq!(budget.pool_bytes(Pool::Lvc), 350);
+        assert_eq!(budget.used(), 350);
+        drop(lvc);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn custom_order_is_respected() {
+        let budget = MemoryBudget::new(
+            100,
+            &[Pool::Offline, Pool::InFlight, Pool::Lvc],
+            &Registry::new(),
+        );
+        let mut lvc = BudgetedQueue::new(Pool::Lvc, &budget);
+        let mut offline = BudgetedQueue::new(Pool::Offline, &budget);
+        fill(&mut lvc, 2, 50);
+        fill(&mut offline, 2, 50);
+        assert_eq!(budget.enforce(&mut [&mut lvc, &mut offline]), 2);
+        assert_eq!((lvc.len(), offline.len()), (2, 0));
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use serde_json::{Map, Number, Value};
+
+//
+// ----------------------------------- CBOR ------------------------------------
+//
+// CBOR frames, decoded with ciborium, for format_part() to show as JSON the
+// way it shows MessagePack (crate::msgpack), and with the same renderings:
+// byte strings as their size and hex, "<4 bytes> deadbeef", map keys that are
+// not strings as their JSON text, and floats JSON cannot hold as null. A
+// tagged value shows the value alone. Only a frame that starts as a map or
+// an array (major types 5 and 4) is tried, and one nested past MAX_NESTING,
+// malformed, or followed by more bytes is not CBOR.
+
+const MAX_NESTING: usize = 128;
+
+// A map or an array header, of a definite length or not.
+pub fn looks_like_cbor(bytes: &[u8]) -> bool {
+    matches!(bytes.first(), Some(b) if b >> 5 == 4 || b >> 5 == 5)
+}
+
+// The one CBOR value `bytes` holds, if that is all it holds.
+pub fn decode(bytes: &[u8]) -> Option<Value> {
+    let mut rest = bytes;
+    let value: ciborium::Value =
+        ciborium::de::from_reader_with_recursion_limit(&mut rest, MAX_NESTING).ok()?;
+    rest.is_empty().then(|| json(value))
+}
+
+fn json(value: ciborium::Value) -> Value {
+    use ciborium::Value as Cbor;
+    match value {
+        Cbor::Integer(n) => {
+            let n = i128::from(n);
+            match (i64::try_from(n), u64::try_from(n)) {
+                (Ok(n), _) => Value::from(n),
+                (_, Ok(n)) => Value::from(n),
+                // Below i64::MIN: CBOR goes down to -2^64.
+                _ => Value::String(n.to_string()),
+            }
+        }
+        Cbor::Bytes(bytes) => {
+            let mut text = format!("<{} bytes> ", bytes.len());
+            for b in &bytes {
+                let _ = write!(text, "{:02x}", b);
+            }
+            Value::String(text)
+        }
+        Cbor::Float(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Tag(_, value) => json(*value),
+        Cbor::Array(items) => Value::Array(items.into_iter().map(json).collect()),
+        Cbor::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let key = match json(key) {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                map.insert(key, json(value));
+            }
+            Value::Object(map)
+        }
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decodes_maps_arrays_and_what_they_hold() {
+        // RFC 8949, appendix A: {"a": 1, "b": [2, 3]}.
+        let example = b"\xa2\x61\x61\x01\x61\x62\x82\x02\x03";
+        assert!(looks_like_cbor(example));
+        assert_eq!(decode(example), Some(json!({ "a": 1, "b": [2, 3] })));
+
+        let cases: &[(&[u8], Value)] = &[
+            // Half, single and double floats, and NaN.
+            (
+                b"\x84\xf9\x3e\x00\xfa\x47\xc3\x50\x00\
+                  \xfb\x3f\xf1\x99\x99\x99\x99\x99\x9a\xf9\x7e\x00",
+                json!([1.5, 100000.0, 1.1, null]),
+            ),
+            // Integers at the ends of their ranges.
+            (
+                b"\x83\x1b\xff\xff\xff\xff\xff\xff\xff\xff\x38\x63\
+                  \x3b\xff\xff\xff\xff\xff\xff\xff\xff",
+                json!([u64::MAX, -100, "-18446744073709551616"]),
+            ),
+            // Bytes, a tag (an epoch time) and non-string keys.
+            (
+                b"\xa3\x01\x43\x00\x7f\xff\xf5\xc1\x1a\x51\x4b\x67\xb0\x61\x6b\xf6",
+                json!({ "1": "<3 bytes> 007fff", "true": 1363896240, "k": null }),
+            ),
+            // Indefinite lengths.
+            (b"\x9f\x01\xbf\x61\x78\x02\xff\xff", json!([1, { "x": 2 }])),
+        ];
+        for (bytes, value) in cases {
+            assert_eq!(decode(bytes).as_ref(), Some(value), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn only_whole_maps_and_arrays_are_cbor() {
+        assert!(!looks_like_cbor(b""));
+        assert!(!looks_like_cbor(b"\x01"));
+        assert!(!looks_like_cbor(b"\x61\x61"));
+        assert!(looks_like_cbor(b"\x9f"));
+        assert!(looks_like_cbor(b"\xbf"));
+        let cases: &[&[u8]] = &[
+            b"\x82\x01",
+            b"\x82\x01\x02\x03",
+            b"\xa1\x61",
+            b"\x9f\x01",
+            // A reserved additional value.
+            b"\x9c",
+        ];
+        for bytes in cases {
+            assert_eq!(decode(bytes), None, "{:02x?}", bytes);
+        }
+        let deep = [vec![0x81; MAX_NESTING + 1], vec![0x01]].concat();
+        assert_eq!(decode(&deep), None);
+    }
+}
@@ -0,0 +1,576 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+use smallvec::SmallVec;
+
+use crate::config::{ChaosConfig, FaultRates};
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+
+//
+// ---------------------------- Fault injection --------------------------------
+//
+// Opt-in misbehaviour for testing client retry and timeout logic. With
+// `[chaos] enabled = true` the broker rolls, independently per message and
+// direction, whether to drop it, duplicate it, append CHAOS_FRAME to it or
+// hold it for a random delay. Requests are client messages on their way to a
+// worker, replies are worker messages on their way to a client; fan-out,
+// direct and pipeline traffic is left alone.
+//
+// Chaos is off unless the config enables it *and* the environment sets
+// ALLOW_ENV=1, so a config file copied to production cannot turn it on; the
+// service refuses to start with only one of the two. The rates start from
+// the config and can be changed from the admin socket, but only in a service
+// started with chaos enabled. Every fault is counted in
+// corky_chaos_faults_total{direction, fault} and logged with the message's
+// correlation_id (a JSON payload field), or else its client identity.
+//
+// Decisions come from a seeded xorshift generator, so a fixed `seed` replays
+// the same faults for the same traffic. A probability of 0 never rolls.
+
+pub const ALLOW_ENV: &str = "CORKY_ALLOW_CHAOS";
+pub const CHAOS_FRAME: &[u8] = b"\xffcorky-chaos";
+// Held messages beyond this are passed through undelayed.
+pub const MAX_DELAYED: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Requests,
+    Replies,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 2] = [Direction::Requests, Direction::Replies];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Requests => "requests",
+            Direction::Replies => "replies",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| d.as_str() == name)
+    }
+
+    fn noun(self) -> &'static str {
+        match self {
+            Direction::Requests => "request",
+            Direction::Replies => "reply",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Drop,
+    Duplicate,
+    Malformed,
+    Delay,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 4] = [
+        Fault::Drop,
+        Fault::Duplicate,
+        Fault::Malformed,
+        Fault::Delay,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fault::Drop => "drop",
+            Fault::Duplicate => "duplicate",
+            Fault::Malformed => "malformed",
+            Fault::Delay => "delay",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.as_str() == name)
+    }
+}
+
+fn validate(rates: &FaultRates) -> Result<(), String> {
+    for (name, p) in [
+        ("drop", rates.drop),
+        ("duplicate", rates.duplicate),
+        ("malformed", rates.malformed),
+        ("delay", rates.delay),
+    ] {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(format!(
+                "{} probability must be between 0 and 1, got {}",
+                name, p
+            ));
+        }
+    }
+    if rates.delay_min_ms > rates.delay_max_ms {
+        return Err(format!(
+            "delay_min_ms {} is above delay_max_ms {}",
+            rates.delay_min_ms, rates.delay_max_ms
+        ));
+    }
+    Ok(())
+}
+
+// The startup guard: `allow` is the value of ALLOW_ENV.
+pub fn check_allowed(config: &ChaosConfig, allow: Option<&str>) -> Result<(), String> {
+    if !config.enabled {
+        return Ok(());
+    }
+    if allow != Some("1") {
+        return Err(format!(
+            "[chaos] enabled = true also requires {}=1 in the environment",
+            ALLOW_ENV
+        ));
+    }
+    for direction in Direction::ALL {
+        let rates = match direction {
+            Direction::Requests => &config.requests,
+            Direction::Replies => &config.replies,
+        };
+        validate(rates).map_err(|e| format!("[chaos.{}] {}", direction.as_str(), e))?;
+    }
+    Ok(())
+}
+
+fn describe_rates(direction: Direction, r: &FaultRates) -> String {
+    format!(
+        "{} drop={} duplicate={} malformed={} delay={} delay_ms={}..{}",
+        direction.as_str(),
+        r.drop,
+        r.duplicate,
+        r.malformed,
+        r.delay,
+        r.delay_min_ms,
+        r.delay_max_ms
+    )
+}
+
+// Rates shared between the admin socket and the broker.
+pub struct ChaosRules {
+    enabled: bool,
+    version: AtomicU64,
+    rates: Mutex<[FaultRates; 2]>,
+}
+
+impl ChaosRules {
+    pub fn new(config: &ChaosConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            version: AtomicU64::new(0),
+            rates: Mutex::new([config.requests.clone(), config.replies.clone()]),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn rates(&self) -> [FaultRates; 2] {
+        self.rates.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // One line per direction.
+    pub fn describe(&self) -> Vec<String> {
+        let rates = self.rates();
+        Direction::ALL
+            .into_iter()
+            .map(|d| describe_rates(d, &rates[d.index()]))
+            .collect()
+    }
+
+    fn update(&self, change: impl FnOnce(&mut [FaultRates; 2])) -> Result<(), String> {
+        if !self.enabled {
+            return Err("chaos mode is off; start the service with [chaos] enabled".into());
+        }
+        let mut rates = self.rates.lock().unwrap_or_else(|e| e.into_inner());
+        let mut changed = rates.clone();
+        change(&mut changed);
+        for rates in &changed {
+            validate(rates)?;
+        }
+        *rates = changed;
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn set(&self, direction: Direction, fault: Fault, probability: f64) -> Result<(), String> {
+        self.update(|rates| {
+            let rates = &mut rates[direction.index()];
+            *match fault {
+                Fault::Drop => &mut rates.drop,
+                Fault::Duplicate => &mut rates.duplicate,
+                Fault::Malformed => &mut rates.malformed,
+                Fault::Delay => &mut rates.delay,
+            } = probability;
+        })
+    }
+
+    pub fn set_delay(&self, direction: Direction, min_ms: u64, max_ms: u64) -> Result<(), String> {
+        self.update(|rates| {
+            rates[direction.index()].delay_min_ms = min_ms;
+            rates[direction.index()].delay_max_ms = max_ms;
+        })
+    }
+
+    // Every probability back to 0; the delay ranges stay.
+    pub fn off(&self) -> Result<(), String> {
+        self.update(|rates| {
+            for r in rates.iter_mut() {
+                r.drop = 0.0;
+                r.duplicate = 0.0;
+                r.malformed = 0.0;
+                r.delay = 0.0;
+            }
+        })
+    }
+}
+
+// Rolls succeed when the next random u64 is below these.
+#[derive(Default, Clone, Copy)]
+struct Thresholds {
+    drop: u64,
+    duplicate: u64,
+    malformed: u64,
+    delay: u64,
+    delay_min_ms: u64,
+    delay_max_ms: u64,
+}
+
+fn threshold(p: f64) -> u64 {
+    (p * u64::MAX as f64) as u64
+}
+
+impl From<&FaultRates> for Thresholds {
+    fn from(r: &FaultRates) -> Self {
+        Self {
+            drop: threshold(r.drop),
+            duplicate: threshold(r.duplicate),
+            malformed: threshold(r.malformed),
+            delay: threshold(r.delay),
+            delay_min_ms: r.delay_min_ms,
+            delay_max_ms: r.delay_max_ms,
+        }
+    }
+}
+
+// The correlation_id field of the first JSON payload frame that has one.
+pub fn correlation_field(frames: &[Vec<u8>]) -> Option<String> {
+    frames.iter().find_map(|frame| {
+        if frame.first() != Some(&b'{') {
+            return None;
+        }
+        let value: serde_json::Value = serde_json::from_slice(frame).ok()?;
+        value.get("correlation_id")?.as_str().map(str::to_string)
+    })
+}
+
+// The correlation_id of a JSON payload frame, or the client identity.
+pub fn correlation_id(message: &[Vec<u8>]) -> String {
+    match correlation_field(message.get(1..).unwrap_or_default()) {
+        Some(id) => format!("correlation_id={}", id),
+        None => format!(
+            "client={}",
+            String::from_utf8_lossy(message.first().map_or(&[][..], Vec::as_slice))
+        ),
+    }
+}
+
+// The broker thread's fault injector.
+pub struct Chaos {
+    shared: Arc<ChaosRules>,
+    version: u64,
+    thresholds: [Thresholds; 2],
+    rng: u64,
+    // Delayed messages by release time; the id breaks ties in arrival order.
+    heap: BinaryHeap<Reverse<(Instant, u64)>>,
+    held: HashMap<u64, (Direction, Multipart)>,
+    next_id: u64,
+    faults: [[Counter; 4]; 2],
+}
+
+impl Chaos {
+    pub fn new(shared: &Arc<ChaosRules>, seed: u64, metrics: &Registry) -> Self {
+        let faults = Direction::ALL.map(|d| {
+            Fault::ALL.map(|f| {
+                metrics.counter(
+                    "corky_chaos_faults_total",
+                    &[("direction", d.as_str()), ("fault", f.as_str())],
+                )
+            })
+        });
+        let mut chaos = Self {
+            shared: Arc::clone(shared),
+            version: 0,
+            thresholds: Default::default(),
+            rng: seed | 1,
+            heap: BinaryHeap::new(),
+            held: HashMap::new(),
+            next_id: 0,
+            faults,
+        };
+        chaos.reload();
+        chaos
+    }
+
+    fn reload(&mut self) {
+        self.version = self.shared.version();
+        let rates = self.shared.rates();
+        self.thresholds = [(&rates[0]).into(), (&rates[1]).into()];
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+
+    fn roll(&mut self, threshold: u64) -> bool {
+        match threshold {
+            0 => false,
+            u64::MAX => true,
+            t => self.next_random() < t,
+        }
+    }
+
+    fn record(&self, direction: Direction, fault: Fault, message: &[Vec<u8>], detail: &str) {
+        self.faults[direction.index()][fault as usize].inc();
+        info!(
+            "(Chaos) {} {} {}{}",
+            fault.as_str(),
+            direction.noun(),
+            correlation_id(message),
+            detail
+        );
+    }
+
+    // What to send now in place of `message`: nothing, the message, or two
+    // copies. Delayed messages come back from `due` instead.
+    pub fn inject(
+        &mut self,
+        direction: Direction,
+        mut message: Multipart,
+        now: Instant,
+    ) -> SmallVec<[Multipart; 2]> {
+        if self.shared.version() != self.version {
+            self.reload();
+        }
+        let t = self.thresholds[direction.index()];
+        let mut out = SmallVec::new();
+        if self.roll(t.drop) {
+            self.record(direction, Fault::Drop, &message, "");
+            return out;
+        }
+        if self.roll(t.malformed) {
+            self.record(direction, Fault::Malformed, &message, "");
+            message.push(CHAOS_FRAME.to_vec());
+        }
+        if self.roll(t.duplicate) {
+            self.record(direction, Fault::Duplicate, &message, "");
+            out.push(message.clone());
+        }
+        out.push(message);
+        if self.held.len() < MAX_DELAYED && self.roll(t.delay) {
+            let span = t.delay_max_ms - t.delay_min_ms;
+            let ms = t.delay_min_ms + self.next_random() % (span + 1);
+            self.record(direction, Fault::Delay, &out[0], &format!(" by {}ms", ms));
+            let due = now + Duration::from_millis(ms);
+            for message in out.drain(..) {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.heap.push(Reverse((due, id)));
+                self.held.insert(id, (direction, message));
+            }
+        }
+        out
+    }
+
+    // Delayed messages whose time has come, in release order.
+    pub fn due(&mut self, now: Instant) -> Vec<(Direction, Multipart)> {
+        let mut out = Vec::new();
+        while let Some(&Reverse((due, id))) = self.heap.peek() {
+            if due > now {
+                break;
+            }
+            self.heap.pop();
+            out.extend(self.held.remove(&id));
+        }
+        out
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((due, _))| *due)
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(requests: FaultRates) -> Arc<ChaosRules> {
+        Arc::new(ChaosRules::new(&ChaosConfig {
+            enabled: true,
+            requests,
+            ..Default::default()
+        }))
+    }
+
+    fn message() -> Multipart {
+        Multipart::new(vec![b"alice".to_vec(), b"payload".to_vec()])
+    }
+
+    fn faults(metrics: &Registry, fault: &'static str) -> u64 {
+        metrics
+            .counter(
+                "corky_chaos_faults_total",
+                &[("direction", "requests"), ("fault", fault)],
+            )
+            .get()
+    }
+
+    #[test]
+    fn zero_probabilities_change_nothing() {
+        let metrics = Registry::new();
+        let mut chaos = Chaos::new(&rules(FaultRates::default()), 42, &metrics);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            let out = chaos.inject(Direction::Requests, message(), now);
+            assert_eq!(out.len(), 1);
+            assert_eq!(&out[0][..], &message()[..]);
+        }
+        assert_eq!(chaos.rng, 43, "no roll consumed randomness");
+        assert!(chaos.next_deadline().is_none());
+        for fault in Fault::ALL {
+            assert_eq!(faults(&metrics, fault.as_str()), 0);
+        }
+    }
+
+    #[test]
+    fn certain_faults_always_apply() {
+        let metrics = Registry::new();
+        let now = Instant::now();
+        let certain = |fault: Fault| {
+            let mut rates = FaultRates::default();
+            *match fault {
+                Fault::Drop => &mut rates.drop,
+                Fault::Duplicate => &mut rates.duplicate,
+                Fault::Malformed => &mut rates.malformed,
+                Fault::Delay => &mut rates.delay,
+            } = 1.0;
+            Chaos::new(&rules(rates), 7, &metrics)
+        };
+
+        assert!(certain(Fault::Drop)
+            .inject(Direction::Requests, message(), now)
+            .is_empty());
+        let copies = certain(Fault::Duplicate).inject(Direction::Requests, message(), now);
+        assert_eq!(copies.len(), 2);
+        let malformed = certain(Fault::Malformed).inject(Direction::Requests, message(), now);
+        assert_eq!(malformed[0].last().unwrap(), CHAOS_FRAME);
+        // Replies have their own rates.
+        let reply = certain(Fault::Drop).inject(Direction::Replies, message(), now);
+        assert_eq!(reply.len(), 1);
+
+        let mut delayed = certain(Fault::Delay);
+        assert!(delayed
+            .inject(Direction::Requests, message(), now)
+            .is_empty());
+        let due = delayed.next_deadline().unwrap();
+        assert!(due >= now + Duration::from_millis(10) && due <= now + Duration::from_secs(1));
+        assert!(delayed.due(due - Duration::from_millis(1)).is_empty());
+        assert_eq!(delayed.due(due).len(), 1);
+        assert_eq!(delayed.held(), 0);
+
+        for fault in Fault::ALL {
+            assert_eq!(faults(&metrics, fault.as_str()), 1, "{:?}", fault);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let run = |seed| {
+            let rates = FaultRates {
+                drop: 0.3,
+                duplicate: 0.3,
+                ..Default::default()
+            };
+            let mut chaos = Chaos::new(&rules(rates), seed, &Registry::new());
+            (0..200)
+                .map(|_| {
+                    chaos
+                        .inject(Direction::Requests, message(), Instant::now())
+                        .len()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(99), run(99));
+        assert_ne!(run(99), run(100));
+        let outcomes = run(99);
+        assert!(outcomes.contains(&0) && outcomes.contains(&1) && outcomes.contains(&2));
+    }
+
+    #[test]
+    fn rules_need_chaos_enabled_and_valid_rates() {
+        let off = ChaosRules::new(&ChaosConfig::default());
+        assert!(off.set(Direction::Requests, Fault::Drop, 0.5).is_err());
+
+        let shared = rules(FaultRates::default());
+        let metrics = Registry::new();
+        let mut chaos = Chaos::new(&shared, 1, &metrics);
+        shared.set(Direction::Requests, Fault::Drop, 1.0).unwrap();
+        assert!(chaos
+            .inject(Direction::Requests, message(), Instant::now())
+            .is_empty());
+        assert!(shared.set(Direction::Replies, Fault::Delay, 1.5).is_err());
+        assert!(shared.set_delay(Direction::Replies, 50, 10).is_err());
+        shared.off().unwrap();
+        assert_eq!(
+            chaos
+                .inject(Direction::Requests, message(), Instant::now())
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn startup_requires_the_environment_opt_in() {
+        let mut config = ChaosConfig::default();
+        assert!(check_allowed(&config, None).is_ok());
+        config.enabled = true;
+        assert!(check_allowed(&config, None).is_err());
+        assert!(check_allowed(&config, Some("yes")).is_err());
+        assert!(check_allowed(&config, Some("1")).is_ok());
+        config.replies.delay_min_ms = 5000;
+        let err = check_allowed(&config, Some("1")).unwrap_err();
+        assert!(err.starts_with("[chaos.replies]"), "{}", err);
+    }
+
+    #[test]
+    fn correlation_id_prefers_the_payload_field() {
+        let tagged = vec![b"alice".to_vec(), br#"{"correlation_id": "r-17"}"#.to_vec()];
+        assert_eq!(correlation_id(&tagged), "correlation_id=r-17");
+        assert_eq!(correlation_id(&message()), "client=alice");
+    }
+}
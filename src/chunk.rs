@@ -0,0 +1,593 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//
+// ----------------------------- Chunk protocol --------------------------------
+//
+// A chunked transfer splits one large payload into N messages. Each message
+// carries a fixed-size header frame immediately before its data frame:
+//
+//     [...envelope, header, data]
+//     header = "CRKCHNK1" | transfer_id: u64 BE | index: u32 BE | total: u32 BE
+//
+// The broker forwards every chunk as an ordinary message and only watches the
+// headers to track progress. When a transfer stalls it sends the receiver an
+// error frame in place of the header/data pair:
+//
+//     error = "CRKCHERR" | transfer_id: u64 BE | UTF-8 reason
+
+pub const CHUNK_MAGIC: &[u8; 8] = b"CRKCHNK1";
+pub const CHUNK_ERROR_MAGIC: &[u8; 8] = b"CRKCHERR";
+pub const CHUNK_HEADER_LEN: usize = 24;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024; // 1MB per chunk
+pub const DEFAULT_CHUNK_THRESHOLD: usize = 4 * 1024 * 1024; // chunk payloads above 4MB
+                                                            // Reassembly preallocates from the first chunk's header, which the sender
+                                                            // controls; beyond this the buffer grows as chunks actually arrive.
+pub const MAX_REASSEMBLY_RESERVE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub transfer_id: u64,
+    pub index: u32,
+    pub total: u32,
+}
+
+impl ChunkHeader {
+    pub fn encode(&self) -> [u8; CHUNK_HEADER_LEN] {
+        let mut out = [0u8; CHUNK_HEADER_LEN];
+        out[..8].copy_from_slice(CHUNK_MAGIC);
+        out[8..16].copy_from_slice(&self.transfer_id.to_be_bytes());
+        out[16..20].copy_from_slice(&self.index.to_be_bytes());
+        out[20..24].copy_from_slice(&self.total.to_be_bytes());
+        out
+    }
+
+    pub fn parse(frame: &[u8]) -> Option<ChunkHeader> {
+        if frame.len() != CHUNK_HEADER_LEN || &frame[..8] != CHUNK_MAGIC {
+            return None;
+        }
+        let header = ChunkHeader {
+            transfer_id: u64::from_be_bytes(frame[8..16].try_into().ok()?),
+            index: u32::from_be_bytes(frame[16..20].try_into().ok()?),
+            total: u32::from_be_bytes(frame[20..24].try_into().ok()?),
+        };
+        (header.total > 0 && header.index < header.total).then_some(header)
+    }
+
+    // The header sits immediately before the data frame, after any envelope.
+    pub fn find(message: &[Vec<u8>]) -> Option<ChunkHeader> {
+        if message.len() < 2 {
+            return None;
+        }
+        ChunkHeader::parse(&message[message.len() - 2])
+    }
+}
+
+pub fn encode_chunk_error(transfer_id: u64, reason: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + reason.len());
+    out.extend_from_slice(CHUNK_ERROR_MAGIC);
+    out.extend_from_slice(&transfer_id.to_be_bytes());
+    out.extend_from_slice(reason.as_bytes());
+    out
+}
+
+pub fn parse_chunk_error(frame: &[u8]) -> Option<(u64, String)> {
+    if frame.len() < 16 || &frame[..8] != CHUNK_ERROR_MAGIC {
+        return None;
+    }
+    let transfer_id = u64::from_be_bytes(frame[8..16].try_into().ok()?);
+    Some((
+        transfer_id,
+        String::from_utf8_lossy(&frame[16..]).into_owned(),
+    ))
+}
+
+// Transfer IDs only need to be unique per sender; mix in the pid and start
+// time so restarted senders don't reuse IDs the broker may still track.
+pub fn next_transfer_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    (u64::from(std::process::id()) << 40) ^ (seed & 0xff_ffff_0000) ^ n
+}
+
+//
+// ---------------------------- Broker tracking --------------------------------
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkPath {
+    ClientToWorker,
+    WorkerToClient,
+    Direct,
+}
+
+struct Transfer {
+    path: ChunkPath,
+    // Routing frames that address the receiver on the path's outbound socket.
+    envelope: Vec<Vec<u8>>,
+    total: u32,
+    received: u32,
+    last_activity: Instant,
+}
+
+// A transfer that timed out; the broker sends `envelope + [error frame]` on
+// the socket for `path` to tell the receiver.
+pub struct ExpiredTransfer {
+    pub path: ChunkPath,
+    pub envelope: Vec<Vec<u8>>,
+    pub transfer_id: u64,
+    pub received: u32,
+    pub total: u32,
+}
+
+impl ExpiredTransfer {
+    pub fn into_error_message(self) -> Vec<Vec<u8>> {
+        let reason = format!(
+            "chunked transfer timed out after {}/{} chunks",
+            self.received, self.total
+        );
+        let mut message = self.envelope;
+        message.push(encode_chunk_error(self.transfer_id, &reason));
+        message
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkStats {
+    pub active: usize,
+    pub completed: u64,
+    pub expired: u64,
+    pub out_of_order: u64,
+    pub bytes: u64,
+}
+
+// In-flight transfers from one sender, keyed by transfer id. Lives in the
+// sender's PeerTable slot so forgetting a peer drops its transfers too.
+#[derive(Default)]
+pub struct PeerTransfers {
+    transfers: HashMap<u64, Transfer>,
+}
+
+impl PeerTransfers {
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+}
+
+// Broker-wide chunk accounting. Holds no payload data; chunks are forwarded
+// as they arrive and progress is kept in each sender's PeerTransfers.
+pub struct ChunkTracker {
+    timeout: Duration,
+    stats: ChunkStats,
+}
+
+impl ChunkTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            stats: ChunkStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ChunkStats {
+        self.stats
+    }
+
+    // Record one forwarded chunk from the sender owning `slot`. `envelope` is
+    // only evaluated for the first chunk of a transfer.
+    pub fn observe(
+        &mut self,
+        slot: &mut PeerTransfers,
+        path: ChunkPath,
+        header: ChunkHeader,
+        data_len: usize,
+        now: Instant,
+        envelope: impl FnOnce() -> Vec<Vec<u8>>,
+    ) {
+        self.stats.bytes += data_len as u64;
+        let transfer = slot.transfers.entry(header.transfer_id).or_insert_with(|| {
+            self.stats.active += 1;
+            Transfer {
+                path,
+                envelope: envelope(),
+                total: header.total,
+                received: 0,
+                last_activity: now,
+            }
+        });
+        if header.index != transfer.received {
+            self.stats.out_of_order += 1;
+        }
+        transfer.received += 1;
+        transfer.last_activity = now;
+
+        if transfer.received >= transfer.total {
+            slot.transfers.remove(&header.transfer_id);
+            self.stats.active -= 1;
+            self.stats.completed += 1;
+        }
+    }
+
+    // Drop transfers idle for longer than the timeout and return them so the
+    // caller can notify the receivers.
+    pub fn expire<'a>(
+        &mut self,
+        slots: impl Iterator<Item = &'a mut PeerTransfers>,
+        now: Instant,
+    ) -> Vec<ExpiredTransfer> {
+        let mut expired = Vec::new();
+        if self.stats.active == 0 {
+            return expired;
+        }
+        let timeout = self.timeout;
+        for slot in slots.filter(|s| !s.is_empty()) {
+            slot.transfers.retain(|&transfer_id, t| {
+                if now.duration_since(t.last_activity) < timeout {
+                    return true;
+                }
+                expired.push(ExpiredTransfer {
+                    path: t.path,
+                    envelope: std::mem::take(&mut t.envelope),
+                    transfer_id,
+                    received: t.received,
+                    total: t.total,
+                });
+                false
+            });
+        }
+        self.stats.active -= expired.len();
+        self.stats.expired += expired.len() as u64;
+        expired
+    }
+
+    // Abort every transfer of a peer that is being forgotten.
+    pub fn forget(&mut self, slot: PeerTransfers) -> Vec<ExpiredTransfer> {
+        let expired: Vec<ExpiredTransfer> = slot
+            .transfers
+            .into_iter()
+            .map(|(transfer_id, t)| ExpiredTransfer {
+                path: t.path,
+                envelope: t.envelope,
+                transfer_id,
+                received: t.received,
+                total: t.total,
+            })
+            .collect();
+        self.stats.active -= expired.len();
+        self.stats.expired += expired.len() as u64;
+        expired
+    }
+}
+
+//
+// ----------------------------- Peer helpers ----------------------------------
+//
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkOptions {
+    // Payloads above this size are chunked; smaller ones go out as one frame.
+    pub threshold: usize,
+    pub chunk_size: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_CHUNK_THRESHOLD,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChunkError {
+    Zmq(zmq::Error),
+    // The broker gave up on the transfer before all chunks arrived.
+    Aborted { transfer_id: u64, reason: String },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::Zmq(e) => write!(f, "{}", e),
+            ChunkError::Aborted {
+                transfer_id,
+                reason,
+            } => write!(f, "transfer {:016x} aborted: {}", transfer_id, reason),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+impl From<zmq::Error> for ChunkError {
+    fn from(e: zmq::Error) -> Self {
+        ChunkError::Zmq(e)
+    }
+}
+
+// Send `envelope + [payload]`, splitting the payload into chunks when it is
+// larger than the threshold. Blocks on HWM like a normal send.
+pub fn send_chunked(
+    socket: &zmq::Socket,
+    envelope: &[&[u8]],
+    payload: &[u8],
+    options: ChunkOptions,
+) -> Result<(), zmq::Error> {
+    if payload.len() <= options.threshold {
+        let frames = envelope.iter().copied().chain(std::iter::once(payload));
+        return socket.send_multipart(frames, 0);
+    }
+
+    let chunk_size = options.chunk_size.max(1);
+    let total = payload.len().div_ceil(chunk_size) as u32;
+    let transfer_id = next_transfer_id();
+    for (index, data) in payload.chunks(chunk_size).enumerate() {
+        let header = ChunkHeader {
+            transfer_id,
+            index: index as u32,
+            total,
+        }
+        .encode();
+        let frames = envelope.iter().copied().chain([header.as_slice(), data]);
+        socket.send_multipart(frames, 0)?;
+    }
+    Ok(())
+}
+
+// A reassembled payload and the envelope frames that preceded it (e.g. the
+// sender identity on the direct-messaging socket).
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChunkDelivery {
+    pub envelope: Vec<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+struct Partial {
+    total: u32,
+    next: u32,
+    data: Vec<u8>,
+}
+
+// Receiver-side reassembly. Transfers are keyed by envelope and transfer id,
+// so interleaved transfers from different senders stay separate.
+#[derive(Default)]
+pub struct ChunkAssembler {
+    partial: HashMap<(Vec<Vec<u8>>, u64), Partial>,
+}
+
+impl ChunkAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    // Feed one received message; returns a delivery when a payload is
+    // complete (immediately for unchunked messages).
+    pub fn push(&mut self, mut message: Vec<Vec<u8>>) -> Result<Option<ChunkDelivery>, ChunkError> {
+        let Some(last) = message.pop() else {
+            return Ok(None);
+        };
+
+        if let Some((transfer_id, reason)) = parse_chunk_error(&last) {
+            self.partial.retain(|(_, id), _| *id != transfer_id);
+            return Err(ChunkError::Aborted {
+                transfer_id,
+                reason,
+            });
+        }
+
+        let header = message.last().and_then(|h| ChunkHeader::parse(h));
+        let Some(header) = header else {
+            return Ok(Some(ChunkDelivery {
+                envelope: message,
+                payload: last,
+            }));
+        };
+        message.pop(); // header frame
+
+        let key = (message, header.transfer_id);
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            total: header.total,
+            next: 0,
+            data: Vec::new(),
+        });
+        if header.index != partial.next || header.total != partial.total {
+            let expected = partial.next;
+            self.partial.remove(&key);
+            return Err(ChunkError::Aborted {
+                transfer_id: header.transfer_id,
+                reason: format!(
+                    "unexpected chunk {}/{} (expected {})",
+                    header.index, header.total, expected
+                ),
+            });
+        }
+        if partial.data.is_empty() {
+            let expected = last.len().saturating_mul(partial.total as usize);
+            partial.data.reserve(expected.min(MAX_REASSEMBLY_RESERVE));
+        }
+        partial.data.extend_from_slice(&last);
+        partial.next += 1;
+
+        if partial.next < partial.total {
+            return Ok(None);
+        }
+        let done = self
+            .partial
+            .remove(&key)
+            .map(|p| p.data)
+            .unwrap_or_default();
+        Ok(Some(ChunkDelivery {
+            envelope: key.0,
+            payload: done,
+        }))
+    }
+}
+
+// Block until one complete payload has been received on `socket`.
+pub fn recv_chunked(
+    socket: &zmq::Socket,
+    assembler: &mut ChunkAssembler,
+) -> Result<ChunkDelivery, ChunkError> {
+    loop {
+        let message = socket.recv_multipart(0)?;
+        if let Some(delivery) = assembler.push(message)? {
+            return Ok(delivery);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_message(envelope: &[u8], header: ChunkHeader, data: &[u8]) -> Vec<Vec<u8>> {
+        vec![envelope.to_vec(), header.encode().to_vec(), data.to_vec()]
+    }
+
+    #[test]
+    fn header_round_trips_and_rejects_garbage() {
+        let header = ChunkHeader {
+            transfer_id: 0xdead_beef_0102_0304,
+            index: 3,
+            total: 9,
+        };
+        assert_eq!(ChunkHeader::parse(&header.encode()), Some(header));
+        assert_eq!(ChunkHeader::parse(b"CRKCHNK1"), None);
+        assert_eq!(ChunkHeader::parse(&[0u8; CHUNK_HEADER_LEN]), None);
+
+        let mut bad = header.encode();
+        bad[16..20].copy_from_slice(&9u32.to_be_bytes()); // index == total
+        assert_eq!(ChunkHeader::parse(&bad), None);
+    }
+
+    #[test]
+    fn tracker_completes_and_expires() {
+        let start = Instant::now();
+        let mut tracker = ChunkTracker::new(Duration::from_millis(100));
+        let (mut a, mut b) = (PeerTransfers::default(), PeerTransfers::default());
+        let h = |index| ChunkHeader {
+            transfer_id: 7,
+            index,
+            total: 2,
+        };
+
+        tracker.observe(&mut a, ChunkPath::Direct, h(0), 10, start, Vec::new);
+        tracker.observe(&mut a, ChunkPath::Direct, h(1), 10, start, Vec::new);
+        assert_eq!(tracker.stats().completed, 1);
+        assert_eq!(tracker.stats().active, 0);
+
+        tracker.observe(&mut b, ChunkPath::Direct, h(0), 10, start, || {
+            vec![b"target".to_vec(), b"b".to_vec()]
+        });
+        let soon = start + Duration::from_millis(50);
+        assert!(tracker
+            .expire([&mut a, &mut b].into_iter(), soon)
+            .is_empty());
+        let later = start + Duration::from_millis(100);
+        let expired = tracker.expire([&mut a, &mut b].into_iter(), later);
+        assert_eq!(expired.len(), 1);
+        assert!(b.is_empty());
+        let message = expired.into_iter().next().unwrap().into_error_message();
+        assert_eq!(&message[..2], &[b"target".to_vec(), b"b".to_vec()]);
+        assert_eq!(parse_chunk_error(&message[2]).map(|(id, _)| id), Some(7));
+        assert_eq!(tracker.stats().expired, 1);
+    }
+
+    #[test]
+    fn tracker_forgets_evicted_senders() {
+        let now = Instant::now();
+        let mut tracker = ChunkTracker::new(Duration::from_secs(30));
+        let mut slot = PeerTransfers::default();
+        for transfer_id in [1, 2] {
+            let header = ChunkHeader {
+                transfer_id,
+                index: 0,
+                total: 3,
+            };
+            tracker.observe(&mut slot, ChunkPath::Direct, header, 1, now, Vec::new);
+        }
+        assert_eq!(tracker.stats().active, 2);
+        assert_eq!(tracker.forget(slot).len(), 2);
+        assert_eq!(tracker.stats().active, 0);
+        assert_eq!(tracker.stats().expired, 2);
+    }
+
+    #[test]
+    fn assembler_separates_interleaved_senders() {
+        let mut asm = ChunkAssembler::new();
+        let h = |id, index| ChunkHeader {
+            transfer_id: id,
+            index,
+            total: 2,
+        };
+        // Both senders happen to pick the same transfer id.
+        assert!(asm
+            .push(chunk_message(b"a", h(1, 0), b"A0"))
+            .unwrap()
+            .is_none());
+        assert!(asm
+            .push(chunk_message(b"b", h(1, 0), b"B0"))
+            .unwrap()
+            .is_none());
+        let b = asm
+            .push(chunk_message(b"b", h(1, 1), b"B1"))
+            .unwrap()
+            .unwrap();
+        let a = asm
+            .push(chunk_message(b"a", h(1, 1), b"A1"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(a.payload, b"A0A1");
+        assert_eq!(b.payload, b"B0B1");
+        assert_eq!(asm.pending(), 0);
+    }
+
+    #[test]
+    fn assembler_passes_unchunked_and_reports_errors() {
+        let mut asm = ChunkAssembler::new();
+        let plain = asm.push(vec![b"a".to_vec(), b"hello".to_vec()]);
+        assert_eq!(
+            plain.unwrap(),
+            Some(ChunkDelivery {
+                envelope: vec![b"a".to_vec()],
+                payload: b"hello".to_vec()
+            })
+        );
+
+        let err = asm.push(vec![encode_chunk_error(9, "timed out")]);
+        assert!(matches!(
+            err,
+            Err(ChunkError::Aborted { transfer_id: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn assembler_does_not_trust_the_announced_total() {
+        let mut asm = ChunkAssembler::new();
+        let header = ChunkHeader {
+            transfer_id: 1,
+            index: 0,
+            total: u32::MAX,
+        };
+        let first = chunk_message(b"a", header, &vec![0u8; 1 << 20]);
+        assert_eq!(asm.push(first).unwrap(), None);
+        let partial = asm.partial.values().next().unwrap();
+        assert!(partial.data.capacity() <= MAX_REASSEMBLY_RESERVE);
+    }
+}
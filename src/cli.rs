@@ -0,0 +1,433 @@
+use std::path::PathBuf;
+
+use crate::config::ENDPOINT_FLAGS;
+
+//
+// ------------------------------ Command line ---------------------------------
+//
+// The service binary's arguments. The config file comes from --config, else
+// from $CORKY_CONFIG (crate::config::CONFIG_ENV), else ~/.corky/config.toml.
+// `init-config` writes a default config file there, or to --path.
+// `check-config` loads and validates that file without starting anything.
+// `print-config`, or --print-config-and-exit, prints the effective config.
+// `migrate-config` rewrites that file for the current config_version, or with
+// --dry-run prints the result.
+// `keygen` writes a CURVE keypair to --out-dir, or prints the public key of
+// the secret key file given to --show (see crate::keys).
+// `monitor` prints the broker's [events] stream published at --events, and
+// the proxy's traffic samples published at --sample.
+// --lenient-config warns about unknown config keys instead of failing.
+// --profile picks a [profile.NAME] from the file, else $CORKY_PROFILE does.
+// --use-defaults runs on the built-in defaults when ~/.corky has no config.
+// --no-color turns off colored log lines, whatever [logging] color says.
+// --client-facing-endpoint ADDRESS and the other flags of ENDPOINT_FLAGS
+// replace that [network] endpoint, over the file and the environment.
+
+pub const USAGE: &str = "\
+usage: corky-zmq [--config PATH] [--profile NAME] [--lenient-config] [--use-defaults]
+                 [--print-config-and-exit] [--no-color] [ENDPOINT FLAGS]
+       corky-zmq init-config [--path PATH] [--force] [--config PATH]
+       corky-zmq check-config [--quiet] [--config PATH] [--profile NAME] [ENDPOINT FLAGS]
+       corky-zmq print-config [--config PATH] [--profile NAME] [ENDPOINT FLAGS]
+       corky-zmq migrate-config [--dry-run] [--config PATH]
+       corky-zmq keygen [--out-dir DIR] [--name NAME] [--force]
+       corky-zmq keygen --show PATH
+       corky-zmq monitor [--events ADDRESS] [--sample ADDRESS]
+endpoint flags, each taking an ADDRESS such as tcp://*:7001:
+       --proxy-xsub-endpoint --proxy-xpub-endpoint --client-to-client-endpoint
+       --client-facing-endpoint --worker-facing-endpoint";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub enum Command {
+    // Run the proxy and broker.
+    #[default]
+    Run,
+    InitConfig {
+        path: Option<PathBuf>,
+        force: bool,
+    },
+    // Print nothing on success with `quiet`.
+    CheckConfig {
+        quiet: bool,
+    },
+    PrintConfig,
+    // Print the migrated file instead of writing it with `dry_run`.
+    MigrateConfig {
+        dry_run: bool,
+    },
+    // Write NAME.key and NAME.key.pub, or with `show` print the public key
+    // of that secret key file.
+    Keygen {
+        out_dir: Option<PathBuf>,
+        name: Option<String>,
+        force: bool,
+        show: Option<PathBuf>,
+    },
+    // Print each event published at `events` (crate::events) and each
+    // sample published at `sample` (crate::sample) as it arrives.
+    Monitor {
+        events: Option<String>,
+        sample: Option<String>,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub lenient_config: bool,
+    pub use_defaults: bool,
+    pub no_color: bool,
+    // Run mode prints the effective config instead of starting.
+    pub print_config_and_exit: bool,
+    // (key, address) of each endpoint flag, in the order given.
+    pub endpoints: Vec<(&'static str, String)>,
+    pub help: bool,
+}
+
+// The value of `--flag VALUE` or `--flag=VALUE`, if `arg` is that flag.
+// `what` names the value in the error when it is missing.
+fn value_of(
+    flag: &str,
+    what: &str,
+    arg: &str,
+    rest: &mut std::slice::Iter<String>,
+) -> Result<Option<String>, String> {
+    if arg == flag {
+        let value = rest.next().ok_or(format!("{} needs {}", flag, what))?;
+        return Ok(Some(value.clone()));
+    }
+    Ok(arg
+        .strip_prefix(flag)
+        .and_then(|tail| tail.strip_prefix('='))
+        .map(str::to_string))
+}
+
+// The (key, address) of an endpoint flag, if `arg` is one.
+fn endpoint_flag(
+    arg: &str,
+    rest: &mut std::slice::Iter<String>,
+) -> Result<Option<(&'static str, String)>, String> {
+    for (flag, key) in ENDPOINT_FLAGS {
+        if let Some(address) = value_of(flag, "an address", arg, rest)? {
+            return Ok(Some((*key, address)));
+        }
+    }
+    Ok(None)
+}
+
+pub fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            parsed.help = true;
+        } else if arg == "--lenient-config" {
+            parsed.lenient_config = true;
+        } else if arg == "--use-defaults" {
+            parsed.use_defaults = true;
+        } else if arg == "--no-color" {
+            parsed.no_color = true;
+        } else if arg == "--print-config-and-exit" {
+            parsed.print_config_and_exit = true;
+        } else if let Some(path) = value_of("--config", "a path", arg, &mut args)? {
+            parsed.config = Some(PathBuf::from(path));
+        } else if let Some(name) = value_of("--profile", "a name", arg, &mut args)? {
+            parsed.profile = Some(name);
+        } else if let Some(endpoint) = endpoint_flag(arg, &mut args)? {
+            parsed.endpoints.push(endpoint);
+        } else if arg == "init-config" && parsed.command == Command::Run {
+            parsed.command = Command::InitConfig {
+                path: None,
+                force: false,
+            };
+        } else if arg == "print-config" && parsed.command == Command::Run {
+            parsed.command = Command::PrintConfig;
+        } else if arg == "check-config" && parsed.command == Command::Run {
+            parsed.command = Command::CheckConfig { quiet: false };
+        } else if arg == "migrate-config" && parsed.command == Command::Run {
+            parsed.command = Command::MigrateConfig { dry_run: false };
+        } else if let Command::MigrateConfig { dry_run } = &mut parsed.command {
+            match arg.as_str() {
+                "--dry-run" => *dry_run = true,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        } else if arg == "keygen" && parsed.command == Command::Run {
+            parsed.command = Command::Keygen {
+                out_dir: None,
+                name: None,
+                force: false,
+                show: None,
+            };
+        } else if arg == "monitor" && parsed.command == Command::Run {
+            parsed.command = Command::Monitor {
+                events: None,
+                sample: None,
+            };
+        } else if let Command::Monitor { events, sample } = &mut parsed.command {
+            if let Some(address) = value_of("--events", "an address", arg, &mut args)? {
+                *events = Some(address);
+            } else if let Some(address) = value_of("--sample", "an address", arg, &mut args)? {
+                *sample = Some(address);
+            } else {
+                return Err(format!("unknown argument {}", arg));
+            }
+        } else if let Command::CheckConfig { quiet } = &mut parsed.command {
+            match arg.as_str() {
+                "-q" | "--quiet" => *quiet = true,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        } else if let Command::InitConfig { path, force } = &mut parsed.command {
+            if arg == "--force" {
+                *force = true;
+            } else if let Some(value) = value_of("--path", "a path", arg, &mut args)? {
+                *path = Some(PathBuf::from(value));
+            } else {
+                return Err(format!("unknown argument {}", arg));
+            }
+        } else if let Command::Keygen {
+            out_dir,
+            name,
+            force,
+            show,
+        } = &mut parsed.command
+        {
+            if arg == "--force" {
+                *force = true;
+            } else if let Some(value) = value_of("--out-dir", "a directory", arg, &mut args)? {
+                *out_dir = Some(PathBuf::from(value));
+            } else if let Some(value) = value_of("--name", "a name", arg, &mut args)? {
+                *name = Some(value);
+            } else if let Some(value) = value_of("--show", "a path", arg, &mut args)? {
+                *show = Some(PathBuf::from(value));
+            } else {
+                return Err(format!("unknown argument {}", arg));
+            }
+        } else {
+            return Err(format!("unknown argument {}", arg));
+        }
+    }
+    if let Command::Keygen {
+        show: Some(_),
+        out_dir,
+        name,
+        force,
+    } = &parsed.command
+    {
+        if out_dir.is_some() || name.is_some() || *force {
+            return Err("keygen --show takes no other keygen option".to_string());
+        }
+    }
+    if let Command::Monitor {
+        events: None,
+        sample: None,
+    } = &parsed.command
+    {
+        return Err("monitor needs --events or --sample ADDRESS".to_string());
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_args(&args)
+    }
+
+    #[test]
+    fn config_takes_a_path_either_way() {
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+        let expected = Some(PathBuf::from("broker-a.toml"));
+        assert_eq!(
+            parse(&["--config", "broker-a.toml"]).unwrap().config,
+            expected
+        );
+        assert_eq!(parse(&["--config=broker-a.toml"]).unwrap().config, expected);
+        assert!(parse(&["--help"]).unwrap().help);
+        assert!(parse(&["--lenient-config"]).unwrap().lenient_config);
+        assert!(parse(&["--use-defaults"]).unwrap().use_defaults);
+        assert!(parse(&["--no-color"]).unwrap().no_color);
+        assert!(
+            parse(&["--print-config-and-exit"])
+                .unwrap()
+                .print_config_and_exit
+        );
+        assert_eq!(
+            parse(&["print-config", "--profile", "dev"])
+                .unwrap()
+                .command,
+            Command::PrintConfig
+        );
+        assert_eq!(
+            parse(&["--profile", "dev"]).unwrap().profile.as_deref(),
+            Some("dev")
+        );
+        assert_eq!(
+            parse(&["--profile=prod"]).unwrap().profile.as_deref(),
+            Some("prod")
+        );
+    }
+
+    #[test]
+    fn init_config_takes_a_path_and_force() {
+        assert_eq!(
+            parse(&["init-config"]).unwrap().command,
+            Command::InitConfig {
+                path: None,
+                force: false
+            }
+        );
+        assert_eq!(
+            parse(&["init-config", "--force", "--path=/etc/corky/a.toml"])
+                .unwrap()
+                .command,
+            Command::InitConfig {
+                path: Some(PathBuf::from("/etc/corky/a.toml")),
+                force: true
+            }
+        );
+        assert_eq!(parse(&["--force"]).unwrap_err(), "unknown argument --force");
+    }
+
+    #[test]
+    fn check_config_takes_quiet_and_the_global_flags() {
+        let args = parse(&["check-config", "--config", "a.json", "-q", "--profile=prod"]).unwrap();
+        assert_eq!(args.command, Command::CheckConfig { quiet: true });
+        assert_eq!(args.config, Some(PathBuf::from("a.json")));
+        assert_eq!(args.profile.as_deref(), Some("prod"));
+        assert_eq!(
+            parse(&["check-config", "--force"]).unwrap_err(),
+            "unknown argument --force"
+        );
+    }
+
+    #[test]
+    fn migrate_config_takes_dry_run() {
+        let args = parse(&["migrate-config", "--dry-run", "--config=old.toml"]).unwrap();
+        assert_eq!(args.command, Command::MigrateConfig { dry_run: true });
+        assert_eq!(args.config, Some(PathBuf::from("old.toml")));
+        assert_eq!(
+            parse(&["migrate-config", "-q"]).unwrap_err(),
+            "unknown argument -q"
+        );
+    }
+
+    #[test]
+    fn keygen_writes_or_shows() {
+        assert_eq!(
+            parse(&[
+                "keygen",
+                "--out-dir",
+                "/etc/corky/keys",
+                "--name=edge",
+                "--force"
+            ])
+            .unwrap()
+            .command,
+            Command::Keygen {
+                out_dir: Some(PathBuf::from("/etc/corky/keys")),
+                name: Some("edge".to_string()),
+                force: true,
+                show: None
+            }
+        );
+        assert_eq!(
+            parse(&["keygen", "--show", "edge.key"]).unwrap().command,
+            Command::Keygen {
+                out_dir: None,
+                name: None,
+                force: false,
+                show: Some(PathBuf::from("edge.key"))
+            }
+        );
+        assert_eq!(
+            parse(&["keygen", "--show", "edge.key", "--force"]).unwrap_err(),
+            "keygen --show takes no other keygen option"
+        );
+        assert_eq!(
+            parse(&["keygen", "--out-dir"]).unwrap_err(),
+            "--out-dir needs a directory"
+        );
+    }
+
+    #[test]
+    fn monitor_takes_an_events_and_a_sample_address() {
+        assert_eq!(
+            parse(&["monitor", "--events", "tcp://127.0.0.1:5566"])
+                .unwrap()
+                .command,
+            Command::Monitor {
+                events: Some("tcp://127.0.0.1:5566".to_string()),
+                sample: None
+            }
+        );
+        assert_eq!(
+            parse(&[
+                "monitor",
+                "--sample=tcp://127.0.0.1:5567",
+                "--events=ipc:///tmp/e"
+            ])
+            .unwrap()
+            .command,
+            Command::Monitor {
+                events: Some("ipc:///tmp/e".to_string()),
+                sample: Some("tcp://127.0.0.1:5567".to_string())
+            }
+        );
+        assert_eq!(
+            parse(&["monitor"]).unwrap_err(),
+            "monitor needs --events or --sample ADDRESS"
+        );
+        assert_eq!(
+            parse(&["monitor", "--events"]).unwrap_err(),
+            "--events needs an address"
+        );
+        assert_eq!(
+            parse(&["monitor", "--force"]).unwrap_err(),
+            "unknown argument --force"
+        );
+    }
+
+    #[test]
+    fn endpoint_flags_keep_their_order() {
+        let args = parse(&[
+            "--client-facing-endpoint",
+            "tcp://*:7001",
+            "check-config",
+            "--worker-facing-endpoint=tcp://*:7002",
+            "--client-facing-endpoint=ipc:///tmp/c",
+        ])
+        .unwrap();
+        assert_eq!(args.command, Command::CheckConfig { quiet: false });
+        assert_eq!(
+            args.endpoints,
+            [
+                ("network.client_facing_endpoint", "tcp://*:7001".to_string()),
+                ("network.worker_facing_endpoint", "tcp://*:7002".to_string()),
+                ("network.client_facing_endpoint", "ipc:///tmp/c".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse(&["--proxy-xsub-endpoint"]).unwrap_err(),
+            "--proxy-xsub-endpoint needs an address"
+        );
+    }
+
+    #[test]
+    fn bad_arguments_are_refused() {
+        assert_eq!(parse(&["--config"]).unwrap_err(), "--config needs a path");
+        assert_eq!(parse(&["--profile"]).unwrap_err(), "--profile needs a name");
+        assert_eq!(
+            parse(&["--verbose"]).unwrap_err(),
+            "unknown argument --verbose"
+        );
+        assert_eq!(
+            parse(&["init-config", "--path"]).unwrap_err(),
+            "--path needs a path"
+        );
+    }
+}
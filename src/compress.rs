@@ -0,0 +1,265 @@
+use std::io::Read;
+
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::CompressionConfig;
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+
+//
+// --------------------------- Payload compression -----------------------------
+//
+// Optional zstd compression on the broker <-> worker hop. A compressed frame
+// is flagged by an 8-byte prefix, so every frame describes itself:
+//
+//     "CRKZSTD1" | zstd frame
+//
+// Workers advertise support with a capabilities frame after READY:
+//
+//     ["__corky_ready__", {"compression": ["zstd"]}]
+//
+// Client payload frames of at least threshold_bytes are then compressed on
+// the way to such a worker. Compressed replies are decompressed for clients
+// that never sent a compressed frame themselves; a client that does has opted
+// in end to end, and its compressed frames and replies pass through
+// untouched. A reply that fails to decompress is replaced by
+//
+//     [{"error": {"code": "decompression_failed", "message": ...}}]
+
+pub const COMPRESSED_MAGIC: &[u8; 8] = b"CRKZSTD1";
+pub const COMPRESSION_ZSTD: &str = "zstd";
+// Smaller frames (identities, chunk headers, fan-out tags) are never
+// compressed; zstd cannot shrink them anyway.
+pub const MIN_COMPRESS_BYTES: usize = 64;
+
+pub fn is_compressed(frame: &[u8]) -> bool {
+    frame.starts_with(COMPRESSED_MAGIC)
+}
+
+pub fn compress_frame(frame: &[u8], level: i32) -> Result<Vec<u8>, String> {
+    let mut out = COMPRESSED_MAGIC.to_vec();
+    let body = zstd::bulk::compress(frame, level).map_err(|e| format!("zstd: {}", e))?;
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+// Decompress a flagged frame, refusing output larger than `max_bytes`.
+pub fn decompress_frame(frame: &[u8], max_bytes: u64) -> Result<Vec<u8>, String> {
+    let body = frame
+        .strip_prefix(COMPRESSED_MAGIC.as_slice())
+        .ok_or("frame is not compressed")?;
+    let decoder = zstd::stream::read::Decoder::new(body).map_err(|e| format!("zstd: {}", e))?;
+    let mut out = Vec::new();
+    decoder
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut out)
+        .map_err(|e| format!("corrupt compressed frame: {}", e))?;
+    if out.len() as u64 > max_bytes {
+        return Err(format!("decompressed frame exceeds {} bytes", max_bytes));
+    }
+    Ok(out)
+}
+
+// Decompress every flagged frame in place, for peers that opted in.
+pub fn decompress_frames(frames: &mut [Vec<u8>], max_bytes: u64) -> Result<(), String> {
+    for frame in frames.iter_mut().filter(|f| is_compressed(f)) {
+        *frame = decompress_frame(frame, max_bytes)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Capabilities {
+    #[serde(default)]
+    compression: Vec<String>,
+}
+
+// Whether a READY capabilities frame advertises zstd.
+pub fn advertises_compression(capabilities: Option<&[u8]>) -> bool {
+    capabilities
+        .and_then(|c| serde_json::from_slice::<Capabilities>(c).ok())
+        .is_some_and(|c| c.compression.iter().any(|a| a == COMPRESSION_ZSTD))
+}
+
+// What a client receives instead of a reply that could not be decompressed.
+pub fn decompression_error(client: &[u8], reason: &str) -> Multipart {
+    let error = json!({"error": {"code": "decompression_failed", "message": reason}});
+    Multipart::new(vec![client.to_vec(), error.to_string().into_bytes()])
+}
+
+pub struct Compressor {
+    enabled: bool,
+    level: i32,
+    threshold: usize,
+    max_bytes: u64,
+    saved: Counter,
+    compressed: Counter,
+    decompressed: Counter,
+    errors: Counter,
+}
+
+impl Compressor {
+    pub fn new(config: &CompressionConfig, metrics: &Registry) -> Self {
+        Self {
+            enabled: config.enabled,
+            level: config.level,
+            threshold: config.threshold_bytes.max(MIN_COMPRESS_BYTES),
+            max_bytes: config.max_frame_bytes,
+            saved: metrics.counter("corky_compression_saved_bytes_total", &[]),
+            compressed: metrics.counter("corky_compression_frames_total", &[("op", "compress")]),
+            decompressed: metrics
+                .counter("corky_compression_frames_total", &[("op", "decompress")]),
+            errors: metrics.counter("corky_compression_errors_total", &[]),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn max_frame_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    // Compress the frames above the threshold that are not compressed yet.
+    // A frame that does not shrink is left as it is.
+    pub fn compress(&self, frames: &mut [Vec<u8>]) {
+        for frame in frames.iter_mut() {
+            if frame.len() < self.threshold || is_compressed(frame) {
+                continue;
+            }
+            match compress_frame(frame, self.level) {
+                Ok(packed) if packed.len() < frame.len() => {
+                    self.saved.add((frame.len() - packed.len()) as u64);
+                    self.compressed.inc();
+                    *frame = packed;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.errors.inc();
+                    warn!("(Broker) Sending frame uncompressed: {}", e);
+                }
+            }
+        }
+    }
+
+    // Decompress every flagged frame; on error the frames are unusable.
+    pub fn decompress(&self, frames: &mut [Vec<u8>]) -> Result<(), String> {
+        for frame in frames.iter_mut().filter(|f| is_compressed(f)) {
+            match decompress_frame(frame, self.max_bytes) {
+                Ok(plain) => {
+                    // The worker hop carried the compressed size.
+                    self.saved
+                        .add(plain.len().saturating_sub(frame.len()) as u64);
+                    self.decompressed.inc();
+                    *frame = plain;
+                }
+                Err(e) => {
+                    self.errors.inc();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_payload(len: usize) -> Vec<u8> {
+        let mut out = b"[".to_vec();
+        while out.len() < len {
+            out.extend_from_slice(br#"{"symbol":"EURUSD","bid":1.0842,"ask":1.0844},"#);
+        }
+        out.push(b'{');
+        out.push(b'}');
+        out.push(b']');
+        out
+    }
+
+    fn compressor(metrics: &Registry) -> Compressor {
+        let config = CompressionConfig {
+            enabled: true,
+            threshold_bytes: 256,
+            ..CompressionConfig::default()
+        };
+        Compressor::new(&config, metrics)
+    }
+
+    #[test]
+    fn frames_round_trip_and_count_savings() {
+        let metrics = Registry::new();
+        let compressor = compressor(&metrics);
+        let payload = json_payload(10_000);
+        let mut frames = vec![b"small".to_vec(), payload.clone()];
+        compressor.compress(&mut frames);
+        assert_eq!(frames[0], b"small", "below the threshold");
+        assert!(is_compressed(&frames[1]));
+        assert!(frames[1].len() * 10 < payload.len(), "{}", frames[1].len());
+        let saved = metrics
+            .counter("corky_compression_saved_bytes_total", &[])
+            .get();
+        assert_eq!(saved, (payload.len() - frames[1].len()) as u64);
+
+        // Already compressed frames are not compressed twice.
+        let packed = frames[1].clone();
+        compressor.compress(&mut frames);
+        assert_eq!(frames[1], packed);
+
+        compressor.decompress(&mut frames).unwrap();
+        assert_eq!(frames, vec![b"small".to_vec(), payload]);
+    }
+
+    #[test]
+    fn incompressible_frames_are_left_alone() {
+        let metrics = Registry::new();
+        let compressor = compressor(&metrics);
+        let mut noise = Vec::with_capacity(4096);
+        let mut x = 0x9e3779b97f4a7c15u64;
+        while noise.len() < 4096 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            noise.extend_from_slice(&x.to_le_bytes());
+        }
+        let mut frames = vec![noise.clone()];
+        compressor.compress(&mut frames);
+        assert_eq!(frames[0], noise);
+    }
+
+    #[test]
+    fn corrupt_and_oversized_frames_are_errors() {
+        let metrics = Registry::new();
+        let compressor = compressor(&metrics);
+        let mut corrupt = COMPRESSED_MAGIC.to_vec();
+        corrupt.extend_from_slice(b"definitely not zstd");
+        let mut frames = vec![corrupt];
+        assert!(compressor.decompress(&mut frames).is_err());
+        assert_eq!(
+            metrics.counter("corky_compression_errors_total", &[]).get(),
+            1
+        );
+
+        let bomb = compress_frame(&vec![0u8; 1 << 20], 3).unwrap();
+        let err = decompress_frame(&bomb, 1024).unwrap_err();
+        assert!(err.contains("exceeds"), "{}", err);
+        assert!(decompress_frame(b"plain", 1024).is_err());
+    }
+
+    #[test]
+    fn ready_capabilities_are_parsed() {
+        assert!(advertises_compression(Some(
+            br#"{"compression": ["zstd"]}"#
+        )));
+        assert!(!advertises_compression(Some(
+            br#"{"compression": ["lz4"]}"#
+        )));
+        assert!(!advertises_compression(Some(b"{}")));
+        assert!(!advertises_compression(Some(b"garbage")));
+        assert!(!advertises_compression(None));
+    }
+}
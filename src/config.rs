@@ -0,0 +1,3988 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{Pool, DEFAULT_SHED_ORDER};
+use crate::format;
+use crate::fs_util;
+use crate::ha::HaRole;
+use crate::journal::FsyncPolicy;
+use crate::logfilter::{parse_level, COMPONENTS};
+use crate::quota::QuotaWindow;
+
+//
+// ------------------------------- Constants -----------------------------------
+//
+
+pub const DEFAULT_PROXY_XSUB_ENDPOINT: &str = "tcp://*:5557";
+pub const DEFAULT_PROXY_XPUB_ENDPOINT: &str = "tcp://*:5558";
+pub const DEFAULT_CLIENT_TO_CLIENT_ENDPOINT: &str = "tcp://*:6565";
+pub const DEFAULT_CLIENT_FACING_ENDPOINT: &str = "tcp://*:5559";
+pub const DEFAULT_WORKER_FACING_ENDPOINT: &str = "tcp://*:5560";
+pub const DEFAULT_ADMIN_ENDPOINT: &str = "tcp://127.0.0.1:5562";
+pub const DEFAULT_SAMPLE_ENDPOINT: &str = "tcp://127.0.0.1:5567";
+pub const DEFAULT_CAPTURE_ENDPOINT: &str = "inproc://corky/capture";
+pub const DEFAULT_STATE_SNAPSHOT_ENDPOINT: &str = "tcp://*:5561";
+pub const DEFAULT_STATE_TOPIC_PREFIX: &str = "$state/";
+pub const DEFAULT_STATE_MAX_KEYS: usize = 100_000;
+pub const DEFAULT_REPLAY_DEPTH: usize = 500;
+pub const DEFAULT_REPLAY_MAX_TOPIC_BYTES: u64 = 1024 * 1024;
+pub const DEFAULT_REPLAY_MAX_TOPICS: usize = 10_000;
+pub const DEFAULT_REPLAY_IDLE_TTL_MS: u64 = 3_600_000;
+pub const DEFAULT_HA_LOCAL_ENDPOINT: &str = "tcp://*:5563";
+pub const DEFAULT_PIPELINE_PRODUCER_ENDPOINT: &str = "tcp://*:5564";
+pub const DEFAULT_PIPELINE_CONSUMER_ENDPOINT: &str = "tcp://*:5565";
+pub const DEFAULT_IPC_DIR_MODE: u32 = 0o755;
+
+pub const DEFAULT_POLL_TIMEOUT_MS: u64 = 10;
+pub const DEFAULT_RESTART_ATTEMPTS: u32 = 10;
+pub const DEFAULT_RESTART_BACKOFF_MS: u64 = 3000;
+pub const DEFAULT_CHUNK_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_MAX_PEERS: usize = 100_000;
+pub const DEFAULT_PEER_IDLE_TTL_MS: u64 = 600_000;
+pub const DEFAULT_MEMORY_BUDGET_MB: u64 = 512;
+pub const DEFAULT_HA_HEARTBEAT_MS: u64 = 1000;
+pub const DEFAULT_HA_FAILOVER_MS: u64 = 2000;
+pub const DEFAULT_MAX_TASK_BYTES: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_GC_MAX_WORK_PER_TICK: usize = 1024;
+pub const DEFAULT_WORKER_IDLE_TTL_MS: u64 = 600_000;
+pub const DEFAULT_WATCH_QUEUE: usize = 256;
+pub const DEFAULT_WATCH_MAX_WATCHERS: usize = 1024;
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+pub const DEFAULT_COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+pub const DEFAULT_MAX_DECOMPRESSED_FRAME_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_HEDGE_DELAY_MS: u64 = 50;
+pub const DEFAULT_HEDGE_MAX_PERCENT: u32 = 5;
+pub const DEFAULT_QUOTA_WINDOW_MS: u64 = 24 * 3600 * 1000;
+pub const DEFAULT_QUOTA_PERSIST_INTERVAL_MS: u64 = 10_000;
+pub const DEFAULT_ZAP_DOMAIN: &str = "corky";
+pub const DEFAULT_SCHEDULE_MAX_DELAY_MS: u64 = 7 * 24 * 3600 * 1000;
+pub const DEFAULT_SCHEDULE_MAX_PENDING: usize = 100_000;
+pub const DEFAULT_OFFLINE_TTL_MS: u64 = 60_000;
+pub const DEFAULT_CHAOS_DELAY_MIN_MS: u64 = 10;
+pub const DEFAULT_CHAOS_DELAY_MAX_MS: u64 = 1000;
+pub const DEFAULT_BIND_RETRY_ATTEMPTS: u32 = 20;
+pub const DEFAULT_BIND_RETRY_BACKOFF_MS: u64 = 100;
+pub const DEFAULT_BIND_RETRY_MAX_BACKOFF_MS: u64 = 1000;
+pub const DEFAULT_BIND_RETRY_DEADLINE_MS: u64 = 10_000;
+pub const DEFAULT_EVENT_QUEUE: usize = 10_000;
+pub const DEFAULT_JOURNAL_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_JOURNAL_SEGMENTS: usize = 16;
+pub const DEFAULT_JOURNAL_MAX_AGE_MS: u64 = 7 * 24 * 3600 * 1000;
+pub const DEFAULT_JOURNAL_FSYNC_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_JOURNAL_QUEUE: usize = 100_000;
+
+//
+// ------------------------------- Config --------------------------------------
+//
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Schema the file was written for; unset means 1 (see schema_version).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_version: Option<u32>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub formatting: FormattingConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub broker: BrokerConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub ha: HaConfig,
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub topics: TopicsConfig,
+    #[serde(default)]
+    pub hedge: HedgeConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub resolve: ResolveConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub ingress: IngressConfig,
+    // The [profile.NAME] merged over the file, if one was chosen.
+    #[serde(skip)]
+    pub profile: Option<String>,
+    // Sections added after the file's schema version that it leaves out, so
+    // they run on their defaults.
+    #[serde(skip)]
+    pub defaulted_sections: Vec<String>,
+    // The files read, the main one first, in the order they were merged;
+    // more than one when it has an include list.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub format: LogFormat,
+    pub timestamp: LogTimestamp,
+    pub color: LogColor,
+    pub target: LogTarget,
+    // Also log to this file, rotated by size; see crate::logfile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_path: Option<String>,
+    // Size a file may reach before it is rotated; 0 never rotates.
+    pub max_size_mb: u64,
+    // Rotated files kept as FILE.1 (newest) to FILE.N.
+    pub max_files: u32,
+    // Repeats of one error logged before they are only counted, and how
+    // often the count is logged; see crate::suppress.
+    pub repeat_limit: u64,
+    pub repeat_summary_secs: u64,
+    // Log 1 of every N messages the broker forwards; see crate::broker.
+    pub sample_every: u64,
+    // Levels of their own for components, by name; see crate::logfilter.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub filters: BTreeMap<String, String>,
+    // Files of their own for components, by name; see crate::logfile.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub components: BTreeMap<String, String>,
+    // Connections to the client-facing sockets; see crate::audit.
+    #[serde(skip_serializing_if = "AuditConfig::is_off")]
+    pub audit: AuditConfig,
+}
+
+// Written apart from the log, with its own rotation.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    // Defaults to ~/.corky/logs/audit.jsonl.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_path: Option<String>,
+    // As in [logging]: 0 never rotates.
+    pub max_size_mb: u64,
+    pub max_files: u32,
+}
+
+pub const DEFAULT_AUDIT_FILE: &str = "~/.corky/logs/audit.jsonl";
+
+impl AuditConfig {
+    fn is_off(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_path: None,
+            max_size_mb: DEFAULT_LOG_MAX_SIZE_MB,
+            max_files: DEFAULT_LOG_MAX_FILES,
+        }
+    }
+}
+
+// How log lines are written; see crate::logjson for the JSON one.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    // One JSON object per line, for log shippers.
+    Json,
+}
+
+// How log lines give their time; see crate::logtime.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTimestamp {
+    // Local time with its UTC offset, to the millisecond.
+    Rfc3339,
+    #[default]
+    Rfc3339Utc,
+    EpochMs,
+    // For the journal, which adds its own.
+    None,
+}
+
+// Whether the plain format colors its lines on stderr; see
+// crate::logfile::use_color. The log file never gets color.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogColor {
+    // Only when stderr is a terminal and NO_COLOR is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// Where log lines go. With stderr, file_path gets a copy of them.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    // file_path alone.
+    File,
+    // The local syslog socket; see crate::syslog.
+    Syslog,
+}
+
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+pub const DEFAULT_LOG_MAX_FILES: u32 = 5;
+pub const DEFAULT_REPEAT_LIMIT: u64 = 10;
+pub const DEFAULT_REPEAT_SUMMARY_SECS: u64 = 30;
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Plain,
+            timestamp: LogTimestamp::Rfc3339Utc,
+            color: LogColor::Auto,
+            target: LogTarget::Stderr,
+            file_path: None,
+            max_size_mb: DEFAULT_LOG_MAX_SIZE_MB,
+            max_files: DEFAULT_LOG_MAX_FILES,
+            repeat_limit: DEFAULT_REPEAT_LIMIT,
+            repeat_summary_secs: DEFAULT_REPEAT_SUMMARY_SECS,
+            sample_every: 1,
+            filters: BTreeMap::new(),
+            components: BTreeMap::new(),
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+// How message payloads are cropped for the debug log; see crate::format for
+// what each limit does. Reloaded on SIGHUP.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FormattingConfig {
+    // false logs whole JSON documents, uncropped.
+    pub enabled: bool,
+    pub max_depth: usize,
+    // What values past max_depth are logged as.
+    pub deep_strategy: DeepStrategy,
+    pub outer_head: usize,
+    pub outer_tail: usize,
+    pub outer_min_crop_len: usize,
+    pub inner_min_crop_len: usize,
+    pub row_list_head: usize,
+    pub row_list_tail: usize,
+    pub scalar_list_head: usize,
+    pub scalar_list_tail: usize,
+    // Lists of numbers only, where they would be cropped, as their count,
+    // min, max, first and last.
+    pub numeric_summary: bool,
+    pub max_object_keys: usize,
+    // The same for objects below the top level.
+    pub inner_max_object_keys: usize,
+    // JSON strings longer than this, at any depth, are cut; 0 never cuts.
+    pub max_string_chars: usize,
+    // JSON strings this long that are base64 are shown as their decoded
+    // size and hash; 0 never.
+    pub base64_min_chars: usize,
+    // Plain-text frames longer than this are cut; 0 logs them whole.
+    pub max_text_chars: usize,
+    // Text frames of more lines are not tried as NDJSON below trace; 0
+    // never tries.
+    pub ndjson_max_lines: usize,
+    // Kept first when a top-level object is trimmed to max_object_keys.
+    pub important_keys: Vec<String>,
+    pub bytes_preview_len: usize,
+    // Keys whose values are logged as "<redacted>", at any depth.
+    pub redact_keys: Vec<String>,
+    // Epoch times under timestamp_keys shown with their UTC time too.
+    pub humanize_timestamps: bool,
+    pub timestamp_keys: Vec<String>,
+    // Binary frames as hex dumps, as they always are at trace level.
+    pub hexdump: bool,
+    // Bytes of a frame a hex dump shows.
+    pub hexdump_max_bytes: usize,
+    // Larger binary frames are not tried as MessagePack; 0 never tries.
+    pub msgpack_max_bytes: usize,
+    // Larger binary frames are not tried as CBOR; 0 never tries.
+    pub cbor_max_bytes: usize,
+    // gzip and zlib frames that decompress to more are shown as bytes; 0
+    // never decompresses.
+    pub decompress_max_bytes: usize,
+    // Of a whole message, across its frames; 0 renders everything.
+    pub max_output_bytes: usize,
+    // Each frame of a message of 3 or more led by its index and size.
+    pub frame_labels: bool,
+    // JSON indented at debug too, not only at trace.
+    pub pretty: bool,
+}
+
+impl Default for FormattingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_depth: format::MAX_DEPTH,
+            deep_strategy: DeepStrategy::Summarize,
+            outer_head: format::OUTER_HEAD,
+            outer_tail: format::OUTER_TAIL,
+            outer_min_crop_len: format::OUTER_MIN_CROP_LEN,
+            inner_min_crop_len: format::INNER_MIN_CROP_LEN,
+            row_list_head: format::ROW_LIST_HEAD,
+            row_list_tail: format::ROW_LIST_TAIL,
+            scalar_list_head: format::SCALAR_LIST_HEAD,
+            scalar_list_tail: format::SCALAR_LIST_TAIL,
+            numeric_summary: false,
+            max_object_keys: format::MAX_OBJECT_KEYS,
+            inner_max_object_keys: format::INNER_MAX_OBJECT_KEYS,
+            max_string_chars: format::MAX_STRING_CHARS,
+            base64_min_chars: format::BASE64_MIN_CHARS,
+            max_text_chars: format::MAX_TEXT_CHARS,
+            ndjson_max_lines: format::NDJSON_MAX_LINES,
+            important_keys: format::IMPORTANT_KEYS.iter().map(|k| k.to_string()).collect(),
+            bytes_preview_len: format::BYTES_PREVIEW_LEN,
+            redact_keys: format::REDACT_KEYS.iter().map(|k| k.to_string()).collect(),
+            humanize_timestamps: false,
+            timestamp_keys: format::TIMESTAMP_KEYS.iter().map(|k| k.to_string()).collect(),
+            hexdump: false,
+            hexdump_max_bytes: format::HEXDUMP_MAX_BYTES,
+            msgpack_max_bytes: format::MSGPACK_MAX_BYTES,
+            cbor_max_bytes: format::CBOR_MAX_BYTES,
+            decompress_max_bytes: format::DECOMPRESS_MAX_BYTES,
+            max_output_bytes: format::MAX_OUTPUT_BYTES,
+            frame_labels: true,
+            pretty: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeepStrategy {
+    // "[array: 100000 items]", "{object: 37 keys}"; scalars as they are.
+    #[default]
+    Summarize,
+    // The whole value, uncropped.
+    Clone,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)] // missing fields inherit from NetworkConfig::default()
+pub struct NetworkConfig {
+    pub proxy_xsub_endpoint: String,
+    pub proxy_xpub_endpoint: String,
+    pub client_to_client_endpoint: String,
+    pub client_facing_endpoint: String,
+    pub worker_facing_endpoint: String,
+    // Every address one socket binds, replacing the matching single endpoint
+    // above when set, e.g. a tcp and an ipc address for local clients.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub proxy_xsub_endpoints: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub proxy_xpub_endpoints: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_to_client_endpoints: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_facing_endpoints: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub worker_facing_endpoints: Vec<String>,
+    // Client-facing sockets with their own options, replacing
+    // client_facing_endpoint when set.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_facing: Vec<EndpointConfig>,
+    #[serde(skip_serializing_if = "SocketOptionsConfig::is_unset")]
+    pub socket_options: SocketOptionsConfig,
+    // Mode of the directories created for ipc:// socket files.
+    pub ipc_dir_mode: u32,
+    // JSON file the bound addresses of the five sockets are written to, with
+    // wildcard ports resolved; none when unset.
+    pub endpoints_file: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            proxy_xsub_endpoint: DEFAULT_PROXY_XSUB_ENDPOINT.to_string(),
+            proxy_xpub_endpoint: DEFAULT_PROXY_XPUB_ENDPOINT.to_string(),
+            client_to_client_endpoint: DEFAULT_CLIENT_TO_CLIENT_ENDPOINT.to_string(),
+            client_facing_endpoint: DEFAULT_CLIENT_FACING_ENDPOINT.to_string(),
+            worker_facing_endpoint: DEFAULT_WORKER_FACING_ENDPOINT.to_string(),
+            proxy_xsub_endpoints: Vec::new(),
+            proxy_xpub_endpoints: Vec::new(),
+            client_to_client_endpoints: Vec::new(),
+            client_facing_endpoints: Vec::new(),
+            worker_facing_endpoints: Vec::new(),
+            client_facing: Vec::new(),
+            socket_options: SocketOptionsConfig::default(),
+            ipc_dir_mode: DEFAULT_IPC_DIR_MODE,
+            endpoints_file: None,
+        }
+    }
+}
+
+// The list when set, else the single endpoint.
+fn addresses(single: &str, list: &[String]) -> Vec<String> {
+    match list.is_empty() {
+        true => vec![single.to_string()],
+        false => list.to_vec(),
+    }
+}
+
+impl NetworkConfig {
+    // The client-facing sockets. Without [[network.client_facing]] there is
+    // one, binding every client_facing_address(); its name is the first.
+    pub fn client_endpoints(&self) -> Vec<EndpointConfig> {
+        if !self.client_facing.is_empty() {
+            return self.client_facing.clone();
+        }
+        vec![EndpointConfig {
+            address: self.client_facing_addresses().remove(0),
+            ..EndpointConfig::default()
+        }]
+    }
+
+    // The addresses each socket binds, never empty.
+    pub fn proxy_xsub_addresses(&self) -> Vec<String> {
+        addresses(&self.proxy_xsub_endpoint, &self.proxy_xsub_endpoints)
+    }
+
+    pub fn proxy_xpub_addresses(&self) -> Vec<String> {
+        addresses(&self.proxy_xpub_endpoint, &self.proxy_xpub_endpoints)
+    }
+
+    pub fn client_to_client_addresses(&self) -> Vec<String> {
+        addresses(&self.client_to_client_endpoint, &self.client_to_client_endpoints)
+    }
+
+    pub fn client_facing_addresses(&self) -> Vec<String> {
+        addresses(&self.client_facing_endpoint, &self.client_facing_endpoints)
+    }
+
+    pub fn worker_facing_addresses(&self) -> Vec<String> {
+        addresses(&self.worker_facing_endpoint, &self.worker_facing_endpoints)
+    }
+}
+
+// One listening endpoint and the socket options that apply to it alone.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct EndpointConfig {
+    pub address: String,
+    // Reported to workers in ingress metadata; defaults to the address.
+    pub name: String,
+    // CURVE server with [auth] curve_secret_key.
+    pub curve: bool,
+    // ZMQ_MAXMSGSIZE in bytes; -1 is no limit.
+    pub maxmsgsize: i64,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            name: String::new(),
+            curve: false,
+            maxmsgsize: -1,
+        }
+    }
+}
+
+// ZMQ options of one socket. An option left out keeps what
+// crate::socket::configure_socket sets (high-water marks of 10000, a 1s
+// linger, TCP keepalive after 60s idle), or else the libzmq default.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    // Messages queued per peer before sends block or drop; 0 is no limit.
+    pub sndhwm: Option<i64>,
+    pub rcvhwm: Option<i64>,
+    // How long unsent messages are kept once the socket closes.
+    pub linger_ms: Option<i64>,
+    pub tcp_keepalive: Option<bool>,
+    // Seconds idle before the first keepalive probe.
+    pub tcp_keepalive_idle: Option<i64>,
+    pub reconnect_ivl_ms: Option<i64>,
+}
+
+impl SocketOptions {
+    // Each numeric option with its value and the smallest it may be; all
+    // must fit in a C int.
+    pub fn numeric(&self) -> [(&'static str, Option<i64>, i64); 5] {
+        [
+            ("sndhwm", self.sndhwm, 0),
+            ("rcvhwm", self.rcvhwm, 0),
+            ("linger_ms", self.linger_ms, 0),
+            ("tcp_keepalive_idle", self.tcp_keepalive_idle, 1),
+            ("reconnect_ivl_ms", self.reconnect_ivl_ms, 0),
+        ]
+    }
+
+    // `self` with the options `over` sets replaced.
+    pub fn overridden_by(&self, over: &SocketOptions) -> SocketOptions {
+        SocketOptions {
+            sndhwm: over.sndhwm.or(self.sndhwm),
+            rcvhwm: over.rcvhwm.or(self.rcvhwm),
+            linger_ms: over.linger_ms.or(self.linger_ms),
+            tcp_keepalive: over.tcp_keepalive.or(self.tcp_keepalive),
+            tcp_keepalive_idle: over.tcp_keepalive_idle.or(self.tcp_keepalive_idle),
+            reconnect_ivl_ms: over.reconnect_ivl_ms.or(self.reconnect_ivl_ms),
+        }
+    }
+
+    // The options set, as "name value" pairs for the log.
+    pub fn describe(&self) -> String {
+        let mut set: Vec<String> = self
+            .numeric()
+            .iter()
+            .filter_map(|(name, value, _)| value.map(|v| format!("{} {}", name, v)))
+            .collect();
+        if let Some(keepalive) = self.tcp_keepalive {
+            set.push(format!("tcp_keepalive {}", keepalive));
+        }
+        match set.is_empty() {
+            true => "none set".to_string(),
+            false => set.join(", "),
+        }
+    }
+}
+
+// [network.socket_options]: options for every proxy and broker socket, and
+// per-socket tables named after the sockets that override them key by key.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptionsConfig {
+    pub sndhwm: Option<i64>,
+    pub rcvhwm: Option<i64>,
+    pub linger_ms: Option<i64>,
+    pub tcp_keepalive: Option<bool>,
+    pub tcp_keepalive_idle: Option<i64>,
+    pub reconnect_ivl_ms: Option<i64>,
+    pub xsub: SocketOptions,
+    pub xpub: SocketOptions,
+    pub direct_router: SocketOptions,
+    pub client_router: SocketOptions,
+    pub worker_router: SocketOptions,
+}
+
+impl SocketOptionsConfig {
+    pub const SOCKETS: [&'static str; 5] =
+        ["xsub", "xpub", "direct_router", "client_router", "worker_router"];
+
+    fn is_unset(&self) -> bool {
+        *self == SocketOptionsConfig::default()
+    }
+
+    // The options for every socket.
+    pub fn common(&self) -> SocketOptions {
+        SocketOptions {
+            sndhwm: self.sndhwm,
+            rcvhwm: self.rcvhwm,
+            linger_ms: self.linger_ms,
+            tcp_keepalive: self.tcp_keepalive,
+            tcp_keepalive_idle: self.tcp_keepalive_idle,
+            reconnect_ivl_ms: self.reconnect_ivl_ms,
+        }
+    }
+
+    // The table of one socket in SOCKETS.
+    pub fn own(&self, socket: &str) -> Option<&SocketOptions> {
+        match socket {
+            "xsub" => Some(&self.xsub),
+            "xpub" => Some(&self.xpub),
+            "direct_router" => Some(&self.direct_router),
+            "client_router" => Some(&self.client_router),
+            "worker_router" => Some(&self.worker_router),
+            _ => None,
+        }
+    }
+
+    // What applies to `socket`: its own table over the common options.
+    pub fn for_socket(&self, socket: &str) -> SocketOptions {
+        match self.own(socket) {
+            Some(own) => self.common().overridden_by(own),
+            None => self.common(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyMode {
+    #[default]
+    Default,
+    // Zero poll timeout; burns a full core in exchange for minimal jitter.
+    Low,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BrokerConfig {
+    // False runs the service without the broker loop: the proxy alone.
+    pub enabled: bool,
+    // False leaves the client-to-client ROUTER unbound; clients and workers
+    // are still served.
+    pub direct_messaging: bool,
+    pub latency_mode: LatencyMode,
+    // CPU core to pin the broker thread to; only honored in low-latency mode.
+    pub cpu_core: Option<usize>,
+    // Poll timeout of the broker loop outside low-latency mode, at least 1.
+    pub poll_timeout_ms: u64,
+    // Runs of the proxy and broker loops that may fail before the service
+    // gives up, and the wait after the first failure, doubling after each
+    // (capped at 30s unless the first wait is longer).
+    pub retry_attempts: u32,
+    pub retry_backoff_ms: u64,
+    // Chunked transfers idle for longer than this are aborted.
+    pub chunk_timeout_ms: u64,
+    // Cap on tracked peer identities; the least recently seen is evicted.
+    pub max_peers: usize,
+    // Peers silent for longer than this are forgotten.
+    pub peer_idle_ttl_ms: u64,
+    // Upper bound on bytes held in all broker-side queues together.
+    pub memory_budget_mb: u64,
+    // Pools to shed from, first to last, when over the memory budget.
+    pub shed_order: Vec<Pool>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            direct_messaging: true,
+            latency_mode: LatencyMode::Default,
+            cpu_core: None,
+            poll_timeout_ms: DEFAULT_POLL_TIMEOUT_MS,
+            retry_attempts: DEFAULT_RESTART_ATTEMPTS,
+            retry_backoff_ms: DEFAULT_RESTART_BACKOFF_MS,
+            chunk_timeout_ms: DEFAULT_CHUNK_TIMEOUT_MS,
+            max_peers: DEFAULT_MAX_PEERS,
+            peer_idle_ttl_ms: DEFAULT_PEER_IDLE_TTL_MS,
+            memory_budget_mb: DEFAULT_MEMORY_BUDGET_MB,
+            shed_order: DEFAULT_SHED_ORDER.to_vec(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ProxyConfig {
+    // False runs the service without the XSUB/XPUB proxy.
+    pub enabled: bool,
+    // PUB socket carrying sampled publications (see `sample` admin commands),
+    // for `corky-zmq monitor --sample` or any other subscriber.
+    pub sample_endpoint: String,
+    // Copy every message the proxy forwards to capture_endpoint, and log it
+    // at debug.
+    pub capture: bool,
+    // PUB socket carrying the copies; a tcp:// endpoint lets other tools tap
+    // the stream.
+    pub capture_endpoint: String,
+    // Sent to every subscriber as it connects; unset or "" sends nothing.
+    pub welcome_message: Option<WelcomeMessage>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_endpoint: DEFAULT_SAMPLE_ENDPOINT.to_string(),
+            capture: false,
+            capture_endpoint: DEFAULT_CAPTURE_ENDPOINT.to_string(),
+            welcome_message: None,
+        }
+    }
+}
+
+// [proxy] welcome_message, as a string or as a table: `welcome_message =
+// "hello"`, or `welcome_message = { type = "welcome", v = 1 }`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum WelcomeMessage {
+    Text(String),
+    Object(serde_json::Map<String, serde_json::Value>),
+}
+
+impl WelcomeMessage {
+    // The frame subscribers receive: a table, or a string holding a JSON
+    // object, as compact JSON, and any other string as it is. None for "".
+    pub fn frame(&self) -> Option<String> {
+        let object = match self {
+            WelcomeMessage::Text(text) if text.is_empty() => return None,
+            WelcomeMessage::Text(text) => match serde_json::from_str(text) {
+                Ok(serde_json::Value::Object(object)) => object,
+                _ => return Some(text.clone()),
+            },
+            WelcomeMessage::Object(object) => object.clone(),
+        };
+        Some(serde_json::Value::Object(object).to_string())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    // REP socket for text admin commands; keep it on a private interface.
+    pub endpoint: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            endpoint: DEFAULT_ADMIN_ENDPOINT.to_string(),
+        }
+    }
+}
+
+// Snapshot-plus-updates state service, hosted by the proxy.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StateConfig {
+    pub enabled: bool,
+    // ROUTER endpoint answering snapshot requests.
+    pub snapshot_endpoint: String,
+    // Topics starting with this prefix carry keyed state updates.
+    pub topic_prefix: String,
+    // Keys across all namespaces; updates adding more are dropped.
+    pub max_keys: usize,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            snapshot_endpoint: DEFAULT_STATE_SNAPSHOT_ENDPOINT.to_string(),
+            topic_prefix: DEFAULT_STATE_TOPIC_PREFIX.to_string(),
+            max_keys: DEFAULT_STATE_MAX_KEYS,
+        }
+    }
+}
+
+// Recent-history replay to new subscribers, hosted by the proxy; see
+// crate::replay.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReplayConfig {
+    // Topics starting with one of these keep a history; empty disables replay.
+    pub prefixes: Vec<String>,
+    // Messages kept per topic.
+    pub depth: usize,
+    // Bytes kept per topic; the oldest messages go first.
+    pub max_topic_bytes: u64,
+    // Topics with a history; publications to further ones are not kept.
+    pub max_topics: usize,
+    // A topic without publications for this long loses its history.
+    pub idle_ttl_ms: u64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            prefixes: Vec::new(),
+            depth: DEFAULT_REPLAY_DEPTH,
+            max_topic_bytes: DEFAULT_REPLAY_MAX_TOPIC_BYTES,
+            max_topics: DEFAULT_REPLAY_MAX_TOPICS,
+            idle_ttl_ms: DEFAULT_REPLAY_IDLE_TTL_MS,
+        }
+    }
+}
+
+// Where persistent state lives; see crate::store.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct StorageConfig {
+    // Persist the state service's latest values and the offline queue.
+    pub enabled: bool,
+    pub backend: StorageBackend,
+    // Defaults to ~/.corky/state.
+    pub dir: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    // A directory of small files and append-only logs.
+    #[default]
+    Files,
+    // One redb database file; needs the `embedded` cargo feature.
+    Embedded,
+}
+
+// Active/passive broker pairing; see crate::ha.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HaConfig {
+    pub enabled: bool,
+    pub role: HaRole,
+    // Where this instance receives the peer's heartbeats.
+    pub local_endpoint: String,
+    // The peer's local_endpoint.
+    pub peer_endpoint: String,
+    pub heartbeat_ms: u64,
+    // Peer silence after which the passive instance may take over.
+    pub failover_ms: u64,
+    // ZMQ_ROUTING_ID of the heartbeat socket; defaults to "<identity>/ha".
+    pub routing_id: Option<String>,
+}
+
+impl Default for HaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: HaRole::Primary,
+            local_endpoint: DEFAULT_HA_LOCAL_ENDPOINT.to_string(),
+            peer_endpoint: String::new(),
+            heartbeat_ms: DEFAULT_HA_HEARTBEAT_MS,
+            failover_ms: DEFAULT_HA_FAILOVER_MS,
+            routing_id: None,
+        }
+    }
+}
+
+// PUSH/PULL task distribution relayed by the broker.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PipelineConfig {
+    pub enabled: bool,
+    // PULL socket producers push tasks to.
+    pub producer_endpoint: String,
+    // PUSH socket consumers pull tasks from.
+    pub consumer_endpoint: String,
+    // Larger tasks are dropped.
+    pub max_task_bytes: u64,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            producer_endpoint: DEFAULT_PIPELINE_PRODUCER_ENDPOINT.to_string(),
+            consumer_endpoint: DEFAULT_PIPELINE_CONSUMER_ENDPOINT.to_string(),
+            max_task_bytes: DEFAULT_MAX_TASK_BYTES,
+        }
+    }
+}
+
+// Where the generated base identity of connect-mode sockets is kept.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct IdentityConfig {
+    // Defaults to ~/.corky/state/identity.
+    pub file: Option<String>,
+}
+
+// Idle-state collection; see crate::gc.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct GcConfig {
+    // Entries examined per sweep, across all categories.
+    pub max_work_per_tick: usize,
+    // Workers silent for longer than this leave the fan-out pool.
+    pub worker_ttl_ms: u64,
+    // PUB socket for "peer_gone" events; empty disables them.
+    pub notify_endpoint: String,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            max_work_per_tick: DEFAULT_GC_MAX_WORK_PER_TICK,
+            worker_ttl_ms: DEFAULT_WORKER_IDLE_TTL_MS,
+            notify_endpoint: String::new(),
+        }
+    }
+}
+
+// Presence notifications on the client-to-client socket; see crate::watch.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WatchConfig {
+    pub enabled: bool,
+    // Notifications waiting for one watcher; the oldest go first.
+    pub queue: usize,
+    // Peers watching at once; more are refused until one unwatches or goes.
+    pub max_watchers: usize,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            queue: DEFAULT_WATCH_QUEUE,
+            max_watchers: DEFAULT_WATCH_MAX_WATCHERS,
+        }
+    }
+}
+
+// zstd on the broker <-> worker hop; see crate::compress.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // zstd level, 1 (fastest) to 22.
+    pub level: i32,
+    // Smaller payload frames are sent as they are.
+    pub threshold_bytes: usize,
+    // Compressed frames that would expand beyond this are rejected.
+    pub max_frame_bytes: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: DEFAULT_COMPRESSION_LEVEL,
+            threshold_bytes: DEFAULT_COMPRESSION_THRESHOLD_BYTES,
+            max_frame_bytes: DEFAULT_MAX_DECOMPRESSED_FRAME_BYTES,
+        }
+    }
+}
+
+// Encryption of on-disk records; see crate::seal.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    // Exactly one of these supplies the key new records are sealed with.
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
+    // Keys from before a rotation, still accepted when reading.
+    pub previous_key_files: Vec<String>,
+    pub previous_key_envs: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMechanism {
+    // No authentication; every peer is anonymous.
+    #[default]
+    Null,
+    // Username and password checked against `users`.
+    Plain,
+}
+
+// Authentication of pub/sub connections; see crate::zap.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub mechanism: AuthMechanism,
+    // Username -> password for PLAIN.
+    pub users: BTreeMap<String, String>,
+    pub zap_domain: String,
+    // Z85 secret key of broker endpoints with `curve = true`.
+    pub curve_secret_key: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            mechanism: AuthMechanism::Null,
+            users: BTreeMap::new(),
+            zap_domain: DEFAULT_ZAP_DOMAIN.to_string(),
+            curve_secret_key: String::new(),
+        }
+    }
+}
+
+// Topic prefixes one principal may use; see crate::acl.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct PrincipalAcl {
+    pub subscribe: Vec<String>,
+    pub publish: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct AclConfig {
+    pub enabled: bool,
+    // Principal (ZAP User-Id) -> allowed prefixes; "*" applies to the rest.
+    pub principals: BTreeMap<String, PrincipalAcl>,
+}
+
+// Publications whose topic starts with `from` are forwarded as `to` + the rest.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TopicRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+// Proxy-wide topic allowlist and rewrites, editable from the admin socket;
+// see crate::topics.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct TopicsConfig {
+    // Prefixes the proxy forwards; empty forwards everything.
+    pub allow: Vec<String>,
+    pub rewrite: Vec<TopicRewrite>,
+    // Where admin changes are saved and read back at startup, replacing the
+    // rules above. Defaults to ~/.corky/topics.toml.
+    pub state_file: Option<String>,
+    // Keep admin changes in memory only.
+    pub ephemeral: bool,
+}
+
+// Delayed delivery of direct messages and pipeline tasks; see crate::schedule.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    pub enabled: bool,
+    // Headers asking for a later delivery time are rejected.
+    pub max_delay_ms: u64,
+    // Cap on messages waiting for their delivery time.
+    pub max_pending: usize,
+    // Due direct messages wait this long for an offline target.
+    pub offline_ttl_ms: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_delay_ms: DEFAULT_SCHEDULE_MAX_DELAY_MS,
+            max_pending: DEFAULT_SCHEDULE_MAX_PENDING,
+            offline_ttl_ms: DEFAULT_OFFLINE_TTL_MS,
+        }
+    }
+}
+
+// The libzmq context the proxy and broker share; see
+// crate::socket::build_context. The defaults are libzmq's.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ContextConfig {
+    // Background threads doing socket I/O. Only tcp:// and the other network
+    // transports use them; inproc:// and ipc:// traffic does not.
+    pub io_threads: i32,
+    // Sockets open at once. The zmq crate offers no way to set
+    // ZMQ_MAX_SOCKETS, so only libzmq's default is accepted for now.
+    pub max_sockets: i32,
+}
+
+pub const DEFAULT_IO_THREADS: i32 = zmq_sys::ZMQ_IO_THREADS_DFLT as i32;
+pub const DEFAULT_MAX_SOCKETS: i32 = zmq_sys::ZMQ_MAX_SOCKETS_DFLT as i32;
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            io_threads: DEFAULT_IO_THREADS,
+            max_sockets: DEFAULT_MAX_SOCKETS,
+        }
+    }
+}
+
+// Caps on TCP connections to the broker; see crate::limits. 0 is unlimited.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // Connections per broker socket, keyed by socket name (client_router,
+    // direct_router, worker_router).
+    pub max_per_socket: BTreeMap<String, u32>,
+    // Connections from one source address across the broker's sockets.
+    pub max_per_address: u32,
+}
+
+// Re-resolution of host names in connect-mode TCP endpoints; see
+// crate::resolve. 0 turns a trigger off.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ResolveConfig {
+    pub interval_ms: u64,
+    // Consecutive reconnect attempts without a connection.
+    pub after_failed_reconnects: u32,
+}
+
+// Retry profiles, one per kind of operation.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RetryConfig {
+    // Startup binds that find their address still in use; see
+    // crate::socket::bind_with_retry.
+    pub bind: RetryProfile,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RetryProfile {
+    pub attempts: u32,
+    // Doubles after each attempt, up to max_backoff_ms.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Total time allowed for retries; 0 fails on the first error.
+    pub deadline_ms: u64,
+}
+
+impl Default for RetryProfile {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_BIND_RETRY_ATTEMPTS,
+            backoff_ms: DEFAULT_BIND_RETRY_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_BIND_RETRY_MAX_BACKOFF_MS,
+            deadline_ms: DEFAULT_BIND_RETRY_DEADLINE_MS,
+        }
+    }
+}
+
+// Broker lifecycle events; see crate::events.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct EventsConfig {
+    // PUB socket for the events; empty disables them.
+    pub endpoint: String,
+    // Events waiting to be published, and the socket's high-water mark.
+    pub queue: usize,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            queue: DEFAULT_EVENT_QUEUE,
+        }
+    }
+}
+
+// Append-only record of requests and replies; see crate::journal.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct JournalConfig {
+    pub enabled: bool,
+    // Defaults to ~/.corky/journal.
+    pub dir: Option<String>,
+    // A segment is closed and a new one started beyond this size.
+    pub max_segment_bytes: u64,
+    // Segments kept, the one being written included.
+    pub max_segments: usize,
+    // Closed segments last written longer ago are removed; 0 keeps them.
+    pub max_age_ms: u64,
+    pub fsync: FsyncPolicy,
+    pub fsync_interval_ms: u64,
+    // Records waiting for the writer thread; further ones are dropped.
+    pub queue: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            max_segment_bytes: DEFAULT_JOURNAL_SEGMENT_BYTES,
+            max_segments: DEFAULT_JOURNAL_SEGMENTS,
+            max_age_ms: DEFAULT_JOURNAL_MAX_AGE_MS,
+            fsync: FsyncPolicy::default(),
+            fsync_interval_ms: DEFAULT_JOURNAL_FSYNC_INTERVAL_MS,
+            queue: DEFAULT_JOURNAL_QUEUE,
+        }
+    }
+}
+
+// Requests one principal or service may make per window; see crate::quota.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaLimit {
+    pub limit: u64,
+    #[serde(default)]
+    pub window: QuotaWindow,
+    // Length of a rolling window.
+    #[serde(default = "default_quota_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_quota_window_ms() -> u64 {
+    DEFAULT_QUOTA_WINDOW_MS
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub enabled: bool,
+    // ZAP User-Id -> budget; "*" gives every other principal its own.
+    pub principals: BTreeMap<String, QuotaLimit>,
+    // Client routing id (the service a request goes to) -> budget; "*" as above.
+    pub services: BTreeMap<String, QuotaLimit>,
+    // Usage is saved here every persist_interval_ms and on shutdown, and read
+    // back at startup. Defaults to ~/.corky/quota.json.
+    pub state_file: Option<String>,
+    pub persist_interval_ms: u64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            principals: BTreeMap::new(),
+            services: BTreeMap::new(),
+            state_file: None,
+            persist_interval_ms: DEFAULT_QUOTA_PERSIST_INTERVAL_MS,
+        }
+    }
+}
+
+// Duplicating slow "first"-mode fan-outs to a second worker; see crate::fanout.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HedgeConfig {
+    pub enabled: bool,
+    // A fan-out still unanswered this long is sent to one more worker.
+    pub delay_ms: u64,
+    // Cap on hedges, as a percentage of "first"-mode fan-outs.
+    pub max_percent: u32,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_ms: DEFAULT_HEDGE_DELAY_MS,
+            max_percent: DEFAULT_HEDGE_MAX_PERCENT,
+        }
+    }
+}
+
+// Trusted metadata for workers; see crate::ingress.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct IngressConfig {
+    // Stamp each request with when and how it reached the broker.
+    pub metadata: bool,
+}
+
+// Fault injection for testing client resilience; see crate::chaos.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ChaosConfig {
+    pub enabled: bool,
+    // 0 picks a seed at startup; set one to replay the same faults.
+    pub seed: u64,
+    // Client requests on their way to a worker.
+    pub requests: FaultRates,
+    // Worker replies on their way to a client.
+    pub replies: FaultRates,
+}
+
+// Probabilities between 0 and 1, each rolled independently per message.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FaultRates {
+    pub drop: f64,
+    pub duplicate: f64,
+    pub malformed: f64,
+    pub delay: f64,
+    // Delays are uniform in this range.
+    pub delay_min_ms: u64,
+    pub delay_max_ms: u64,
+}
+
+impl Default for FaultRates {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            duplicate: 0.0,
+            malformed: 0.0,
+            delay: 0.0,
+            delay_min_ms: DEFAULT_CHAOS_DELAY_MIN_MS,
+            delay_max_ms: DEFAULT_CHAOS_DELAY_MAX_MS,
+        }
+    }
+}
+
+//
+// --------------------------- Default config file -----------------------------
+//
+// What `corky-zmq init-config` writes: the [logging] and [network] defaults,
+// serialized from the structs themselves so the file cannot drift from them,
+// with a comment above each key. A key without a comment here is an error,
+// which the tests catch, so a new field cannot be left out.
+
+const TEMPLATE_HEADER: &str = "\
+# Corky ZMQ configuration, written by `corky-zmq init-config`.
+# Every value below is the built-in default; the README describes the other
+# sections ([broker], [admin], [auth], ...), which default as well.
+";
+
+const TEMPLATE_SECTIONS: &[(&str, &str)] = &[
+    ("logging", "Logging"),
+    ("network", "Network endpoints"),
+];
+
+const TEMPLATE_COMMENTS: &[(&str, &str, &str)] = &[
+    (
+        "logging",
+        "level",
+        "trace, debug, info, warn or error; RUST_LOG takes precedence",
+    ),
+    (
+        "logging",
+        "format",
+        "plain, or json for one JSON object per line (read at startup)",
+    ),
+    (
+        "logging",
+        "timestamp",
+        "rfc3339 (local), rfc3339_utc, epoch_ms, or none when the journal adds\n\
+         its own (read at startup)",
+    ),
+    (
+        "logging",
+        "color",
+        "auto colors stderr when it is a terminal and NO_COLOR is unset;\n\
+         always or never decide alone, and --no-color beats them all",
+    ),
+    (
+        "logging",
+        "target",
+        "stderr, file for file_path alone, or syslog for the local syslog socket\n\
+         (read at startup)",
+    ),
+    (
+        "logging",
+        "max_size_mb",
+        "With file_path set, rotate the log file at this size; 0 never rotates",
+    ),
+    (
+        "logging",
+        "max_files",
+        "Rotated log files to keep, FILE.1 being the newest",
+    ),
+    (
+        "logging",
+        "repeat_limit",
+        "Times one error is logged in a row before its repeats are only counted;\n\
+         0 logs every one",
+    ),
+    (
+        "logging",
+        "repeat_summary_secs",
+        "How often the count of an error's repeats is logged",
+    ),
+    (
+        "logging",
+        "sample_every",
+        "Log only 1 of every N messages the broker forwards from each socket\n\
+         (read at startup)",
+    ),
+    (
+        "network",
+        "proxy_xsub_endpoint",
+        "XSUB socket publishers connect to (proxy)",
+    ),
+    (
+        "network",
+        "proxy_xpub_endpoint",
+        "XPUB socket subscribers connect to (proxy)",
+    ),
+    (
+        "network",
+        "client_to_client_endpoint",
+        "ROUTER for direct client-to-client messages, sent as [target, payload]",
+    ),
+    (
+        "network",
+        "client_facing_endpoint",
+        "ROUTER clients send requests to (broker)",
+    ),
+    (
+        "network",
+        "worker_facing_endpoint",
+        "ROUTER workers connect to; a request goes to the worker sharing the\n\
+         client's identity (broker)",
+    ),
+    (
+        "network",
+        "ipc_dir_mode",
+        "Mode of directories created for ipc:// socket files; 493 is 0o755,\n\
+         and TOML takes octal as written: ipc_dir_mode = 0o750",
+    ),
+];
+
+// Follows the keys of a section.
+const TEMPLATE_NOTES: &[(&str, &str)] = &[
+    (
+        "logging",
+        "\
+# Log to a file as well as stderr; its directory is created as needed.
+# file_path = \"/var/log/corky/corky.log\"
+",
+    ),
+    (
+        "logging",
+        "\
+# Levels for single components; level above stays that of the rest.
+# [logging.filters]
+# broker = \"debug\"
+# proxy = \"warn\"
+",
+    ),
+    (
+        "logging",
+        "\
+# Files for single components, rotated like file_path; the others log as
+# before. Opened at startup.
+# [logging.components]
+# broker = \"~/.corky/logs/broker.log\"
+# proxy = \"~/.corky/logs/proxy.log\"
+",
+    ),
+    (
+        "logging",
+        "\
+# Record each connection to the client-facing sockets, one JSON object a
+# line, flushed as written. Opened at startup.
+# [logging.audit]
+# enabled = true
+# file_path = \"~/.corky/logs/audit.jsonl\"
+# max_size_mb = 10
+# max_files = 5
+",
+    ),
+    (
+        "network",
+        "\
+# Bind one socket to several addresses: each *_endpoint above has an
+# *_endpoints list that replaces it when set.
+# client_facing_endpoints = [\"tcp://*:5559\", \"ipc:///run/corky/client.sock\"]
+",
+    ),
+    (
+        "network",
+        "\
+# Listen for clients on several endpoints, each with its own options; this
+# replaces client_facing_endpoint.
+# [[network.client_facing]]
+# address = \"tcp://*:5559\"
+# name = \"public\"
+# curve = false
+# maxmsgsize = -1
+",
+    ),
+];
+
+#[derive(Serialize)]
+struct Template {
+    logging: LoggingConfig,
+    network: NetworkConfig,
+}
+
+pub fn default_config_file() -> Result<String, String> {
+    let template = Template {
+        logging: LoggingConfig::default(),
+        network: NetworkConfig::default(),
+    };
+    let document = toml::Value::try_from(template).map_err(|e| e.to_string())?;
+    let mut out = TEMPLATE_HEADER.to_string();
+    out.push_str(&format!("\n{} = {}\n", VERSION_KEY, CONFIG_VERSION));
+    for (section, title) in TEMPLATE_SECTIONS {
+        let table = document
+            .get(section)
+            .and_then(toml::Value::as_table)
+            .ok_or_else(|| format!("no [{}] in the template", section))?;
+        out.push_str(&format!("\n# {}\n[{}]\n", title, section));
+        if let Some(key) = table
+            .keys()
+            .find(|key| !TEMPLATE_COMMENTS.iter().any(|(s, k, _)| s == section && k == key))
+        {
+            return Err(format!("no comment for {}.{}", section, key));
+        }
+        // In the order of the comments, which follows the structs.
+        for (_, key, comment) in TEMPLATE_COMMENTS.iter().filter(|(s, ..)| s == section) {
+            let Some(value) = table.get(*key) else {
+                continue;
+            };
+            out.push('\n');
+            for line in comment.lines() {
+                out.push_str(&format!("# {}\n", line));
+            }
+            out.push_str(&format!("{} = {}\n", key, value));
+        }
+        for (_, note) in TEMPLATE_NOTES.iter().filter(|(s, _)| s == section) {
+            out.push('\n');
+            out.push_str(note);
+        }
+    }
+    Ok(out)
+}
+
+// Writes the default config file to `path`, creating its directory; an
+// existing file is only replaced with `force`.
+pub fn write_default_config(path: &Path, force: bool) -> Result<(), String> {
+    let content = default_config_file()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            format!("{} already exists; pass --force to overwrite it", path.display())
+        }
+        _ => format!("Cannot write {}: {}", path.display(), e),
+    })?;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+// ~/.corky/config.toml
+pub fn config_path() -> Result<PathBuf, String> {
+    let home_dir = match dirs::home_dir() {
+        Some(dir) => dir,
+        None => return Err("Could not determine home directory".to_string()),
+    };
+    Ok(home_dir.join(".corky").join("config.toml"))
+}
+
+// Looked for in ~/.corky in this order; the first one present is read.
+pub const CONFIG_FILE_NAMES: [&str; 3] = ["config.toml", "config.json", "config.yaml"];
+
+// The config files present in `dir`, the one that would be read first.
+pub fn config_files_in(dir: &Path) -> Vec<PathBuf> {
+    CONFIG_FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+// The first config file present in ~/.corky, else ~/.corky/config.toml.
+pub fn find_config_path() -> Result<PathBuf, String> {
+    let default = config_path()?;
+    let found = default.parent().map(config_files_in).unwrap_or_default();
+    Ok(found.into_iter().next().unwrap_or(default))
+}
+
+// How a config file is parsed, from its extension. Anything other than
+// .json, .yaml or .yml is TOML.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => {
+                ConfigFormat::Yaml
+            }
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Yaml => "YAML",
+        }
+    }
+
+    // The file as the TOML document the rest of loading works on.
+    fn parse(self, content: &str) -> Result<toml::Value, String> {
+        match self {
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Json => {
+                let json: serde_json::Value =
+                    serde_json::from_str(content).map_err(|e| e.to_string())?;
+                toml::Value::try_from(json).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Yaml => {
+                let yaml: serde_json::Value =
+                    serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+                toml::Value::try_from(yaml).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+// Names the config file when --config does not.
+pub const CONFIG_ENV: &str = "CORKY_CONFIG";
+
+// The config file named by `flag` (--config) or else by `env` (CORKY_CONFIG),
+// made absolute against the working directory; None when neither names one.
+pub fn chosen_config_path(
+    flag: Option<&Path>,
+    env: Option<String>,
+) -> Result<Option<PathBuf>, String> {
+    let chosen = match flag {
+        Some(path) => path.to_path_buf(),
+        None => match env.filter(|value| !value.is_empty()) {
+            Some(value) => PathBuf::from(value),
+            None => return Ok(None),
+        },
+    };
+    std::path::absolute(&chosen)
+        .map(Some)
+        .map_err(|e| format!("Cannot resolve config path {}: {}", chosen.display(), e))
+}
+
+// Lets a missing ~/.corky config mean the built-in defaults, as
+// --use-defaults does; "1" or "true".
+pub const ALLOW_MISSING_CONFIG_ENV: &str = "CORKY_ALLOW_MISSING_CONFIG";
+
+static USE_DEFAULTS: AtomicBool = AtomicBool::new(false);
+
+// The --use-defaults flag. Process-wide, like set_lenient_config.
+pub fn set_use_defaults(use_defaults: bool) {
+    USE_DEFAULTS.store(use_defaults, Ordering::Relaxed);
+}
+
+// Whether a config file missing from ~/.corky may be replaced by the
+// defaults. A file named with --config or CORKY_CONFIG must always exist.
+pub fn defaults_allowed(env: &impl Fn(&str) -> Option<String>) -> bool {
+    USE_DEFAULTS.load(Ordering::Relaxed)
+        || matches!(env(ALLOW_MISSING_CONFIG_ENV).as_deref(), Some("1" | "true"))
+}
+
+// What to do about a missing ~/.corky/config.toml.
+pub fn missing_config_error(path: &Path) -> String {
+    format!(
+        "Configuration file not found at: {}. Write one with `corky-zmq init-config`, \
+         or pass --use-defaults (or set {}=1) to run on the built-in defaults",
+        path.display(),
+        ALLOW_MISSING_CONFIG_ENV
+    )
+}
+
+// The built-in defaults with the environment's endpoint overrides, checked as
+// a loaded file would be. A chosen profile has nothing to merge over them.
+pub fn default_config(env: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+    if let Some(profile) = selected_profile(&env) {
+        return Err(format!("profile {} needs a config file to come from", profile));
+    }
+    let mut config = Config::default();
+    apply_overrides(&mut config, &env)?;
+    check_config(&config)?;
+    Ok(config)
+}
+
+static CONFIG_FLAG: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// The --config flag. Process-wide, like set_use_defaults.
+pub fn set_config_flag(path: Option<PathBuf>) {
+    *CONFIG_FLAG.write().unwrap_or_else(|e| e.into_inner()) = path;
+}
+
+// The file named by --config or else $CORKY_CONFIG, otherwise the first of
+// ~/.corky/config.toml, config.json and config.yaml; the defaults when there
+// is none of those and defaults_allowed.
+pub fn load_config() -> Result<Config, String> {
+    let env = |name: &str| std::env::var(name).ok();
+    let flag = CONFIG_FLAG.read().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some(path) = chosen_config_path(flag.as_deref(), env(CONFIG_ENV))? {
+        return load_config_from(&path);
+    }
+    let path = find_config_path()?;
+    match path.exists() {
+        true => load_config_from(&path),
+        false if defaults_allowed(&env) => default_config(env),
+        false => Err(missing_config_error(&path)),
+    }
+}
+
+pub fn load_config_from(config_path: &Path) -> Result<Config, String> {
+    if !config_path.exists() {
+        return Err(format!(
+            "Configuration file not found at: {}",
+            config_path.display()
+        ));
+    }
+
+    let files = read_config_files(config_path)?;
+    let env = |name: &str| std::env::var(name).ok();
+    let mut config = config_from_document(ConfigFormat::of(config_path), merge_files(&files), env)
+        .map_err(|e| format!("{}: {}", config_path.display(), e))?;
+    config.files = files.into_iter().map(|(path, _)| path).collect();
+    apply_overrides(&mut config, env)?;
+    check_config(&config)?;
+    Ok(config)
+}
+
+// Parse, then expand environment variables in the string values before the
+// document is turned into a Config.
+pub fn parse_config(
+    content: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Config, String> {
+    parse_config_as(ConfigFormat::Toml, content, env)
+}
+
+pub fn parse_config_as(
+    format: ConfigFormat,
+    content: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Config, String> {
+    let failed = |e: String| format!("Failed to parse config as {}: {}", format.name(), e);
+    let mut document = format.parse(content).map_err(failed)?;
+    if !take_includes(&mut document)?.is_empty() {
+        return Err("include is only read from a config file, which it is relative to".to_string());
+    }
+    config_from_document(format, document, env)
+}
+
+// A parsed document, includes merged in, as a Config.
+fn config_from_document(
+    format: ConfigFormat,
+    mut document: toml::Value,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Config, String> {
+    let failed = |e: String| format!("Failed to parse config as {}: {}", format.name(), e);
+    let version = schema_version(&document)?;
+    let profile = selected_profile(&env);
+    apply_profile(&mut document, profile.as_deref())?;
+    let defaulted = defaulted_sections(&document, version);
+    check_unknown_keys(&mut document, LENIENT.load(Ordering::Relaxed))?;
+    interpolate(&mut document, "", &env)?;
+    let mut config: Config = document
+        .try_into()
+        .map_err(|e: toml::de::Error| failed(e.to_string()))?;
+    config.profile = profile;
+    config.defaulted_sections = defaulted;
+    Ok(config)
+}
+
+//
+// ------------------------------ Unknown keys ---------------------------------
+//
+// The top level, [logging] and [network] refuse keys they do not know, so a
+// misspelt key fails the load instead of leaving its default in place. The
+// error names the key, its section and the closest known key. Lenient mode
+// (--lenient-config, for older files) drops such keys with a warning on
+// stderr, as the logger may not be up yet. The known keys come from each
+// struct's Deserialize impl, so they cannot drift from the fields.
+
+static LENIENT: AtomicBool = AtomicBool::new(false);
+
+// Process-wide, so reloads treat the file as startup did.
+pub fn set_lenient_config(lenient: bool) {
+    LENIENT.store(lenient, Ordering::Relaxed);
+}
+
+// Records the field names deserialize_struct is given and fails everything.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(serde::de::Error::custom("fields recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+fn field_names<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The known key `unknown` is most likely a typo of, if any is close.
+fn closest_key(unknown: &str, known: &[&'static str]) -> Option<&'static str> {
+    let limit = (unknown.chars().count() / 3).max(2);
+    known
+        .iter()
+        .map(|k| (edit_distance(unknown, k), *k))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, k)| k)
+}
+
+fn unknown_key_messages(table: &toml::Table, section: &str, known: &[&'static str]) -> Vec<String> {
+    let mut messages = Vec::new();
+    for (key, value) in table.iter().filter(|(k, _)| !known.contains(&k.as_str())) {
+        let suggestion = closest_key(key, known);
+        let mut message = match (section, value.is_table()) {
+            ("", true) => format!("unknown section [{}]", key),
+            ("", false) => format!("unknown key `{}` at the top level", key),
+            (_, _) => format!("unknown key `{}` in [{}]", key, section),
+        };
+        match (section, value.is_table(), suggestion) {
+            ("", true, Some(s)) => message.push_str(&format!("; did you mean [{}]?", s)),
+            (_, _, Some(s)) => message.push_str(&format!("; did you mean `{}`?", s)),
+            _ => {}
+        }
+        messages.push(message);
+    }
+    messages
+}
+
+// Fails on keys the checked sections do not know, or drops them when
+// `lenient`.
+fn check_unknown_keys(document: &mut toml::Value, lenient: bool) -> Result<(), String> {
+    let Some(root) = document.as_table_mut() else {
+        return Ok(());
+    };
+    let sections: [(&str, &[&str]); 2] = [
+        ("logging", field_names::<LoggingConfig>()),
+        ("network", field_names::<NetworkConfig>()),
+    ];
+    let top_level = field_names::<Config>();
+    let mut messages = unknown_key_messages(root, "", top_level);
+    for (section, known) in sections {
+        if let Some(table) = root.get_mut(section).and_then(toml::Value::as_table_mut) {
+            messages.extend(unknown_key_messages(table, section, known));
+            if lenient {
+                table.retain(|key, _| known.contains(&key));
+            }
+        }
+    }
+    if messages.is_empty() {
+        return Ok(());
+    }
+    if !lenient {
+        return Err(format!("Unknown config keys:\n  {}", messages.join("\n  ")));
+    }
+    root.retain(|key, _| top_level.contains(&key));
+    for message in messages {
+        eprintln!("Config warning: {}, ignored", message);
+    }
+    Ok(())
+}
+
+//
+// -------------------------------- Profiles -----------------------------------
+//
+// `[profile.NAME.SECTION]` tables hold the settings that differ between
+// deployments. The profile chosen with --profile, else $CORKY_PROFILE, is
+// merged over the top-level sections key by key before anything else looks
+// at the document, so a profile that sets one endpoint keeps the rest of
+// [network]. Arrays and values replace what the file has; only tables merge.
+// Without a chosen profile the [profile] tables are ignored.
+
+pub const PROFILE_ENV: &str = "CORKY_PROFILE";
+
+static PROFILE: RwLock<Option<String>> = RwLock::new(None);
+
+// The --profile flag. Process-wide, so reloads apply the same profile.
+pub fn set_profile(profile: Option<String>) {
+    *PROFILE.write().unwrap_or_else(|e| e.into_inner()) = profile;
+}
+
+// The --profile flag, else `env`'s CORKY_PROFILE when it is not empty.
+pub fn selected_profile(env: &impl Fn(&str) -> Option<String>) -> Option<String> {
+    let flag = PROFILE.read().unwrap_or_else(|e| e.into_inner()).clone();
+    flag.or_else(|| env(PROFILE_ENV).filter(|name| !name.is_empty()))
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// Takes the [profile] tables out of `document` and merges `profile`'s over
+// the rest. Naming a profile the file does not have is an error.
+fn apply_profile(document: &mut toml::Value, profile: Option<&str>) -> Result<(), String> {
+    let Some(root) = document.as_table_mut() else {
+        return Ok(());
+    };
+    let mut profiles = match root.remove("profile") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err("Config key profile must be a table of profiles".to_string()),
+        None => toml::Table::new(),
+    };
+    let Some(name) = profile else {
+        return Ok(());
+    };
+    match profiles.remove(name) {
+        Some(toml::Value::Table(overlay)) => {
+            merge_tables(root, overlay);
+            Ok(())
+        }
+        Some(_) => Err(format!("Config key profile.{} must be a table", name)),
+        None if profiles.is_empty() => Err(format!(
+            "Unknown config profile \"{}\": the file defines none",
+            name
+        )),
+        None => {
+            let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            Err(format!(
+                "Unknown config profile \"{}\"; available: {}",
+                name,
+                available.join(", ")
+            ))
+        }
+    }
+}
+
+//
+// -------------------------------- Includes -----------------------------------
+//
+// A top-level `include = ["endpoints.toml", "/etc/corky/site.toml"]` names
+// more files to merge over the one that lists them, in order, so a later file
+// wins. A relative name is resolved from the including file's directory, and
+// each file is read as TOML, JSON or YAML by its own extension. An included
+// file may include others, which merge right after it. Tables merge key by
+// key as for profiles, and everything before the profile is applied, so an
+// included file may carry [profile] tables too. Each file's config_version is
+// checked, but only the main file's counts. A missing file or a cycle fails
+// the load with the chain of includes that led to it.
+
+const INCLUDE_KEY: &str = "include";
+
+// Takes the include list out of a parsed file.
+fn take_includes(document: &mut toml::Value) -> Result<Vec<String>, String> {
+    let Some(root) = document.as_table_mut() else {
+        return Ok(Vec::new());
+    };
+    let items = match root.remove(INCLUDE_KEY) {
+        Some(toml::Value::Array(items)) => items,
+        Some(_) => return Err("Config key include must be a list of file names".to_string()),
+        None => return Ok(Vec::new()),
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            toml::Value::String(name) if !name.trim().is_empty() => Ok(name),
+            other => Err(format!("Config key include lists {}, which is not a file name", other)),
+        })
+        .collect()
+}
+
+fn include_chain(chain: &[PathBuf], last: &Path) -> String {
+    let mut names: Vec<String> = chain.iter().map(|path| path.display().to_string()).collect();
+    names.push(last.display().to_string());
+    names.join(" -> ")
+}
+
+// Reads `path` and, depth first, the files it includes onto `files`.
+// `chain` holds the files being read, outermost first.
+fn read_included(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, toml::Value)>,
+) -> Result<(), String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+    let format = ConfigFormat::of(path);
+    let in_file = |e: String| format!("{}: {}", path.display(), e);
+    let mut document = format
+        .parse(&content)
+        .map_err(|e| in_file(format!("Failed to parse config as {}: {}", format.name(), e)))?;
+    schema_version(&document).map_err(in_file)?;
+    let includes = take_includes(&mut document).map_err(in_file)?;
+    if !chain.is_empty() {
+        if !document.is_table() {
+            return Err(in_file("an included config must be a table of settings".to_string()));
+        }
+        if let Some(root) = document.as_table_mut() {
+            root.remove(VERSION_KEY);
+        }
+    }
+    files.push((path.to_path_buf(), document));
+    chain.push(fs::canonicalize(path).unwrap_or(path.to_path_buf()));
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for name in includes {
+        let target = dir.join(&name);
+        let Ok(target) = fs::canonicalize(&target) else {
+            return Err(format!(
+                "Included config file not found at: {} (include chain: {})",
+                target.display(),
+                include_chain(chain, &target)
+            ));
+        };
+        if chain.contains(&target) {
+            return Err(format!("Config include cycle: {}", include_chain(chain, &target)));
+        }
+        read_included(&target, chain, files)?;
+    }
+    chain.pop();
+    Ok(())
+}
+
+// `path` and every file it includes, parsed, in the order they merge.
+pub fn read_config_files(path: &Path) -> Result<Vec<(PathBuf, toml::Value)>, String> {
+    let mut files = Vec::new();
+    read_included(path, &mut Vec::new(), &mut files)?;
+    Ok(files)
+}
+
+// The files merged into one document, each over the ones before it.
+fn merge_files(files: &[(PathBuf, toml::Value)]) -> toml::Value {
+    let mut documents = files.iter().map(|(_, document)| document.clone());
+    let mut merged = documents.next().unwrap_or(toml::Value::Table(toml::Table::new()));
+    for document in documents {
+        if let (Some(base), toml::Value::Table(overlay)) = (merged.as_table_mut(), document) {
+            merge_tables(base, overlay);
+        }
+    }
+    merged
+}
+
+//
+// ---------------------------- Schema versions --------------------------------
+//
+// A file states the schema it was written for as a top-level
+// `config_version`. Files from before the key existed are version 1, which
+// had [logging] and [network] only. An older file still loads, and the
+// sections added since that it leaves out are listed in Config so main can
+// say they run on defaults; a file newer than this build is refused. Every
+// section so far was added without changing an existing key, so migrating
+// only has to state the new version.
+
+pub const CONFIG_VERSION: u32 = 2;
+const VERSION_KEY: &str = "config_version";
+
+// The sections each version added, oldest first.
+const ADDED_SECTIONS: &[(u32, &[&str])] = &[(
+    2,
+    &[
+        "formatting",
+        "broker",
+        "state",
+        "replay",
+        "storage",
+        "proxy",
+        "admin",
+        "ha",
+        "pipeline",
+        "identity",
+        "gc",
+        "watch",
+        "compression",
+        "encryption",
+        "auth",
+        "acl",
+        "topics",
+        "hedge",
+        "quota",
+        "schedule",
+        "chaos",
+        "limits",
+        "resolve",
+        "retry",
+        "events",
+        "journal",
+        "ingress",
+        "context",
+    ],
+)];
+
+// The version a parsed file states, 1 when it states none.
+fn schema_version(document: &toml::Value) -> Result<u32, String> {
+    let Some(value) = document.get(VERSION_KEY) else {
+        return Ok(1);
+    };
+    match value.as_integer() {
+        Some(version) if version > i64::from(CONFIG_VERSION) => Err(format!(
+            "{} = {} is newer than this build of corky-zmq understands (up to {}); \
+             upgrade corky-zmq to use this file",
+            VERSION_KEY, version, CONFIG_VERSION
+        )),
+        Some(version) if version >= 1 => Ok(version as u32),
+        _ => Err(format!(
+            "{} = {} is not a schema version; versions start at 1",
+            VERSION_KEY, value
+        )),
+    }
+}
+
+// Sections newer than `version` that `document` does not set.
+fn defaulted_sections(document: &toml::Value, version: u32) -> Vec<String> {
+    ADDED_SECTIONS
+        .iter()
+        .filter(|(added, _)| *added > version)
+        .flat_map(|(_, sections)| sections.iter())
+        .filter(|section| document.get(**section).is_none())
+        .map(|section| section.to_string())
+        .collect()
+}
+
+impl Config {
+    pub fn schema_version(&self) -> u32 {
+        self.config_version.unwrap_or(1)
+    }
+}
+
+// A TOML file at `config_version` = CONFIG_VERSION: the key is rewritten in
+// place or added at the top, so comments and layout survive.
+fn migrate_toml(content: &str) -> String {
+    let current = format!("{} = {}\n", VERSION_KEY, CONFIG_VERSION);
+    let mut out = String::with_capacity(content.len() + current.len());
+    let mut replaced = false;
+    let mut in_tables = false;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        in_tables |= trimmed.starts_with('[');
+        let version_line = trimmed
+            .strip_prefix(VERSION_KEY)
+            .is_some_and(|rest| rest.trim_start().starts_with('='));
+        if !in_tables && !replaced && version_line {
+            out.push_str(&current);
+            replaced = true;
+        } else {
+            out.push_str(line);
+        }
+    }
+    match replaced {
+        true => out,
+        false => format!(
+            "# Schema of this file; `corky-zmq migrate-config` updates it.\n{}\n{}",
+            current, content
+        ),
+    }
+}
+
+// `content` rewritten for the current schema, or None when it is current.
+// The migrated file must parse to the same values plus the new version.
+pub fn migrate_config(format: ConfigFormat, content: &str) -> Result<Option<String>, String> {
+    let failed = |e: String| format!("Failed to parse config as {}: {}", format.name(), e);
+    let mut before = format.parse(content).map_err(failed)?;
+    if schema_version(&before)? == CONFIG_VERSION {
+        return Ok(None);
+    }
+    let migrated = match format {
+        ConfigFormat::Json => {
+            let mut json: serde_json::Value =
+                serde_json::from_str(content).map_err(|e| failed(e.to_string()))?;
+            let root = json.as_object_mut().ok_or("the JSON config is not an object")?;
+            root.insert(VERSION_KEY.to_string(), CONFIG_VERSION.into());
+            serde_json::to_string_pretty(&json).map_err(|e| e.to_string())? + "\n"
+        }
+        _ => migrate_toml(content),
+    };
+    let mut after = format.parse(&migrated).map_err(failed)?;
+    for document in [&mut before, &mut after] {
+        if let Some(root) = document.as_table_mut() {
+            root.remove(VERSION_KEY);
+        }
+    }
+    if before != after {
+        return Err("migrating would change other values; edit the file by hand".to_string());
+    }
+    Ok(Some(migrated))
+}
+
+// Replaces the config file at `path`. The file may hold passwords, so the
+// replacement is created with the original's mode.
+pub fn replace_config_file(path: &Path, content: &str) -> Result<(), String> {
+    let metadata =
+        fs::metadata(path).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = {
+        let _ = metadata;
+        None
+    };
+    fs_util::replace_file(path, content.as_bytes(), mode)
+}
+
+//
+// ----------------------------- Interpolation ---------------------------------
+//
+// `${VAR}` and `${VAR:-default}` in string values, with `$$` for a literal
+// dollar. As in the shell, an empty variable takes the default, and the
+// default may hold further references. Keys, numbers and booleans are left
+// alone.
+
+fn interpolate(
+    value: &mut toml::Value,
+    key: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => {
+            *text = expand(text, env).map_err(|e| format!("Config key {}: {}", key, e))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", key, i), env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, item) in table.iter_mut() {
+                let key = match key {
+                    "" => name.clone(),
+                    parent => format!("{}.{}", parent, name),
+                };
+                interpolate(item, &key, env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand(text: &str, env: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('$') {
+        expanded.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(body) = rest.strip_prefix("${") else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = closing_brace(body).ok_or_else(|| format!("unterminated ${{ in \"{}\"", text))?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name \"{}\"", name));
+        }
+        match (env(name), default) {
+            (Some(value), None) => expanded.push_str(&value),
+            (Some(value), Some(_)) if !value.is_empty() => expanded.push_str(&value),
+            (_, Some(default)) => expanded.push_str(&expand(default, env)?),
+            (None, None) => {
+                return Err(format!(
+                    "environment variable {} is not set and has no default",
+                    name
+                ))
+            }
+        }
+        rest = &body[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Index of the `}` closing a `${`, counting nested ones in a default.
+fn closing_brace(body: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in body.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+//
+// --------------------------- Endpoint overrides ------------------------------
+//
+// CORKY_<NAME>_ENDPOINT variables replace an endpoint from the file or the
+// defaults, for containers and units that would otherwise template the file.
+// A value must name a transport (tcp://, ipc://, ...) and an address, so a
+// typo fails at startup instead of as a bare error from bind().
+
+type EndpointField = fn(&mut Config) -> &mut String;
+
+pub const ENDPOINT_OVERRIDES: &[(&str, &str, EndpointField)] = &[
+    ("CORKY_PROXY_XSUB_ENDPOINT", "network.proxy_xsub_endpoint", |c| {
+        &mut c.network.proxy_xsub_endpoint
+    }),
+    ("CORKY_PROXY_XPUB_ENDPOINT", "network.proxy_xpub_endpoint", |c| {
+        &mut c.network.proxy_xpub_endpoint
+    }),
+    ("CORKY_CLIENT_TO_CLIENT_ENDPOINT", "network.client_to_client_endpoint", |c| {
+        &mut c.network.client_to_client_endpoint
+    }),
+    ("CORKY_CLIENT_FACING_ENDPOINT", "network.client_facing_endpoint", |c| {
+        &mut c.network.client_facing_endpoint
+    }),
+    ("CORKY_WORKER_FACING_ENDPOINT", "network.worker_facing_endpoint", |c| {
+        &mut c.network.worker_facing_endpoint
+    }),
+    ("CORKY_ADMIN_ENDPOINT", "admin.endpoint", |c| &mut c.admin.endpoint),
+    ("CORKY_STATE_SNAPSHOT_ENDPOINT", "state.snapshot_endpoint", |c| {
+        &mut c.state.snapshot_endpoint
+    }),
+    ("CORKY_SAMPLE_ENDPOINT", "proxy.sample_endpoint", |c| &mut c.proxy.sample_endpoint),
+    ("CORKY_HA_LOCAL_ENDPOINT", "ha.local_endpoint", |c| &mut c.ha.local_endpoint),
+    ("CORKY_HA_PEER_ENDPOINT", "ha.peer_endpoint", |c| &mut c.ha.peer_endpoint),
+    ("CORKY_PIPELINE_PRODUCER_ENDPOINT", "pipeline.producer_endpoint", |c| {
+        &mut c.pipeline.producer_endpoint
+    }),
+    ("CORKY_PIPELINE_CONSUMER_ENDPOINT", "pipeline.consumer_endpoint", |c| {
+        &mut c.pipeline.consumer_endpoint
+    }),
+];
+
+const TRANSPORTS: [&str; 7] = ["tcp", "ipc", "inproc", "pgm", "epgm", "tipc", "vmci"];
+
+// Where an effective setting came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingSource {
+    File,
+    Env,
+    Cli,
+    Default,
+}
+
+impl SettingSource {
+    pub fn label(self) -> &'static str {
+        match self {
+            SettingSource::File => "file",
+            SettingSource::Env => "env",
+            SettingSource::Cli => "cli",
+            SettingSource::Default => "default",
+        }
+    }
+}
+
+// Ok when `endpoint` has the shape zmq_bind expects.
+pub fn check_endpoint(endpoint: &str) -> Result<(), String> {
+    if endpoint.trim().is_empty() {
+        return Err("is empty".to_string());
+    }
+    let Some((transport, address)) = endpoint.split_once("://") else {
+        return Err(format!(
+            "{:?} has no transport; expected e.g. tcp://*:5559 or ipc:///run/corky/client",
+            endpoint
+        ));
+    };
+    if !TRANSPORTS.contains(&transport) {
+        return Err(format!(
+            "{:?} has unknown transport {:?}; expected one of {}",
+            endpoint,
+            transport,
+            TRANSPORTS.join(", ")
+        ));
+    }
+    if address.is_empty() || address != address.trim() {
+        return Err(format!("{:?} has no usable address after {}://", endpoint, transport));
+    }
+    Ok(())
+}
+
+// The list that takes the place of a single [network] endpoint, when set.
+fn replaced_by(network: &NetworkConfig, key: &str) -> Option<&'static str> {
+    let (list, name) = match key {
+        "network.proxy_xsub_endpoint" => {
+            (&network.proxy_xsub_endpoints, "network.proxy_xsub_endpoints")
+        }
+        "network.proxy_xpub_endpoint" => {
+            (&network.proxy_xpub_endpoints, "network.proxy_xpub_endpoints")
+        }
+        "network.client_to_client_endpoint" => {
+            (&network.client_to_client_endpoints, "network.client_to_client_endpoints")
+        }
+        "network.client_facing_endpoint" if !network.client_facing.is_empty() => {
+            return Some("[[network.client_facing]]");
+        }
+        "network.client_facing_endpoint" => {
+            (&network.client_facing_endpoints, "network.client_facing_endpoints")
+        }
+        "network.worker_facing_endpoint" => {
+            (&network.worker_facing_endpoints, "network.worker_facing_endpoints")
+        }
+        _ => return None,
+    };
+    (!list.is_empty()).then_some(name)
+}
+
+pub fn apply_env_overrides(
+    config: &mut Config,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    for (var, key, field) in ENDPOINT_OVERRIDES {
+        let Some(value) = env(var) else {
+            continue;
+        };
+        check_endpoint(&value).map_err(|e| format!("{} (for {}) {}", var, key, e))?;
+        if let Some(list) = replaced_by(&config.network, key) {
+            return Err(format!("{} has no effect while {} lists the endpoints", var, list));
+        }
+        *field(config) = value;
+    }
+    Ok(())
+}
+
+//
+// ---- Endpoint flags ----
+//
+// --client-facing-endpoint and the other [network] flags replace an endpoint
+// after the file and the CORKY_*_ENDPOINT variables, for ad-hoc runs. Like
+// --profile they are process-wide, so a reload keeps them. Two flags naming
+// the same address are caught by check_config like any other clash.
+
+pub const ENDPOINT_FLAGS: &[(&str, &str)] = &[
+    ("--proxy-xsub-endpoint", "network.proxy_xsub_endpoint"),
+    ("--proxy-xpub-endpoint", "network.proxy_xpub_endpoint"),
+    ("--client-to-client-endpoint", "network.client_to_client_endpoint"),
+    ("--client-facing-endpoint", "network.client_facing_endpoint"),
+    ("--worker-facing-endpoint", "network.worker_facing_endpoint"),
+];
+
+static ENDPOINT_FLAG_VALUES: RwLock<Vec<(&'static str, String)>> = RwLock::new(Vec::new());
+
+// The (key, value) of each endpoint flag given, in command-line order.
+pub fn set_endpoint_flags(flags: Vec<(&'static str, String)>) {
+    *ENDPOINT_FLAG_VALUES.write().unwrap_or_else(|e| e.into_inner()) = flags;
+}
+
+fn endpoint_flags() -> Vec<(&'static str, String)> {
+    ENDPOINT_FLAG_VALUES.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn flag_of(key: &str) -> &'static str {
+    ENDPOINT_FLAGS
+        .iter()
+        .find(|(_, k)| *k == key)
+        .map_or("an endpoint flag", |(flag, _)| flag)
+}
+
+// Applies `flags`; a key given twice takes the later value.
+pub fn apply_endpoint_flags(
+    config: &mut Config,
+    flags: &[(&'static str, String)],
+) -> Result<(), String> {
+    for (key, value) in flags {
+        let flag = flag_of(key);
+        let Some((.., field)) = ENDPOINT_OVERRIDES.iter().find(|(_, k, _)| k == key) else {
+            return Err(format!("{} is not an endpoint", key));
+        };
+        check_endpoint(value).map_err(|e| format!("{} {}", flag, e))?;
+        if let Some(list) = replaced_by(&config.network, key) {
+            return Err(format!("{} has no effect while {} lists the endpoints", flag, list));
+        }
+        *field(config) = value.clone();
+    }
+    Ok(())
+}
+
+// The file's values, then the environment's, then the command line's.
+fn apply_overrides(
+    config: &mut Config,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    apply_env_overrides(config, env)?;
+    apply_endpoint_flags(config, &endpoint_flags())
+}
+
+// Each overridable endpoint with its effective value and where that came
+// from; a value equal to the default counts as the default. An endpoint a
+// list replaces is reported as that list, from the file.
+pub fn endpoint_sources(
+    config: &Config,
+    env: impl Fn(&str) -> Option<String>,
+) -> Vec<(&'static str, String, SettingSource)> {
+    let (mut config, mut defaults) = (config.clone(), Config::default());
+    let network = config.network.clone();
+    let flags = endpoint_flags();
+    ENDPOINT_OVERRIDES
+        .iter()
+        .map(|(var, key, field)| {
+            if let Some(list) = replaced_by(&network, key) {
+                let addresses = match *key {
+                    "network.proxy_xsub_endpoint" => network.proxy_xsub_addresses(),
+                    "network.proxy_xpub_endpoint" => network.proxy_xpub_addresses(),
+                    "network.client_to_client_endpoint" => network.client_to_client_addresses(),
+                    "network.worker_facing_endpoint" => network.worker_facing_addresses(),
+                    _ if network.client_facing.is_empty() => network.client_facing_addresses(),
+                    _ => network.client_endpoints().into_iter().map(|e| e.address).collect(),
+                };
+                return (list, addresses.join(", "), SettingSource::File);
+            }
+            let value = field(&mut config).clone();
+            let source = match env(var) {
+                _ if flags.iter().any(|(k, _)| k == key) => SettingSource::Cli,
+                Some(_) => SettingSource::Env,
+                None if value == *field(&mut defaults) => SettingSource::Default,
+                None => SettingSource::File,
+            };
+            (*key, value, source)
+        })
+        .collect()
+}
+
+//
+// ---- Effective config ----
+//
+// print-config writes the loaded Config back out as TOML with each value's
+// origin as a trailing comment: an endpoint flag beats env, which beats the
+// chosen profile, which beats the file, and anything else is a default.
+// Secrets are masked, and a `$` that interpolation would take for a reference
+// is doubled, so the output loads back to the same Config once the masked
+// values are filled in.
+
+const MASKED: &str = "********";
+
+// Key paths whose values are secret; `*` matches any key.
+const SECRET_KEYS: &[&[&str]] = &[&["auth", "users", "*"], &["auth", "curve_secret_key"]];
+
+fn is_secret(path: &[String]) -> bool {
+    SECRET_KEYS.iter().any(|secret| {
+        secret.len() == path.len() && secret.iter().zip(path).all(|(s, p)| *s == "*" || s == p)
+    })
+}
+
+fn lookup<'a>(value: &'a toml::Value, path: &[String]) -> Option<&'a toml::Value> {
+    path.iter().try_fold(value, |value, segment| match value {
+        toml::Value::Table(table) => table.get(segment),
+        toml::Value::Array(items) => segment.parse().ok().and_then(|i: usize| items.get(i)),
+        _ => None,
+    })
+}
+
+struct Origins {
+    // "file", or "include PATH", with what that file sets, in merge order.
+    files: Vec<(String, toml::Value)>,
+    profile: Option<(String, toml::Value)>,
+    // (key, variable) of the endpoint overrides that were set.
+    env: Vec<(&'static str, &'static str)>,
+    // (key, flag) of the endpoint flags that were given.
+    flags: Vec<(&'static str, &'static str)>,
+}
+
+impl Origins {
+    fn of(&self, path: &[String]) -> String {
+        let key = path.join(".");
+        if let Some((_, flag)) = self.flags.iter().find(|(k, _)| *k == key) {
+            return format!("flag {}", flag);
+        }
+        if let Some((_, var)) = self.env.iter().find(|(k, _)| *k == key) {
+            return format!("env {}", var);
+        }
+        match &self.profile {
+            Some((name, overlay)) if lookup(overlay, path).is_some() => format!("profile {}", name),
+            _ => self
+                .files
+                .iter()
+                .rev()
+                .find(|(_, file)| lookup(file, path).is_some())
+                .map_or("default".to_string(), |(origin, _)| origin.clone()),
+        }
+    }
+}
+
+fn toml_key(key: &str) -> String {
+    match !key.is_empty()
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        true => key.to_string(),
+        false => toml::Value::String(key.to_string()).to_string(),
+    }
+}
+
+fn child(path: &[String], key: &str) -> Vec<String> {
+    let mut path = path.to_vec();
+    path.push(key.to_string());
+    path
+}
+
+fn is_table_array(value: &toml::Value) -> bool {
+    matches!(value, toml::Value::Array(items)
+        if !items.is_empty() && items.iter().all(toml::Value::is_table))
+}
+
+// `value` with `$` doubled in the strings that interpolation would change.
+fn literal(value: &toml::Value) -> toml::Value {
+    match value {
+        toml::Value::String(text) if text.contains("$$") || text.contains("${") => {
+            toml::Value::String(text.replace('$', "$$"))
+        }
+        toml::Value::Array(items) => toml::Value::Array(items.iter().map(literal).collect()),
+        toml::Value::Table(table) => toml::Value::Table(
+            table.iter().map(|(k, v)| (k.clone(), literal(v))).collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Values first, then tables as [header] sections and arrays of tables as
+// [[header]] ones. `path` may hold array indices, `header` never does.
+fn write_table(
+    out: &mut String,
+    path: &[String],
+    header: &[String],
+    table: &toml::Table,
+    origins: &Origins,
+) {
+    let nested = |value: &toml::Value| value.is_table() || is_table_array(value);
+    for (key, value) in table.iter().filter(|(_, value)| !nested(value)) {
+        let path = child(path, key);
+        let mut origin = origins.of(&path);
+        let mut value = value.clone();
+        if is_secret(&path) && value.as_str().is_some_and(|v| !v.is_empty()) {
+            value = toml::Value::String(MASKED.to_string());
+            origin.push_str(", masked");
+        }
+        out.push_str(&format!("{} = {}  # {}\n", toml_key(key), value, origin));
+    }
+    for (key, value) in table.iter().filter(|(_, value)| nested(value)) {
+        let (path, header) = (child(path, key), child(header, key));
+        let name: Vec<String> = header.iter().map(|k| toml_key(k)).collect();
+        match value {
+            toml::Value::Table(table) => {
+                out.push_str(&format!("\n[{}]\n", name.join(".")));
+                write_table(out, &path, &header, table, origins);
+            }
+            toml::Value::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&format!("\n[[{}]]\n", name.join(".")));
+                    let path = child(&path, &i.to_string());
+                    if let Some(table) = item.as_table() {
+                        write_table(out, &path, &header, table, origins);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// `config` as annotated TOML. `files` are those it was loaded from as
+// read_config_files returns them, none when it runs on defaults.
+pub fn print_config(
+    config: &Config,
+    files: &[(PathBuf, toml::Value)],
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut merged = merge_files(files);
+    let profiles = merged.as_table_mut().and_then(|root| root.remove("profile"));
+    let profile = config.profile.as_ref().and_then(|name| {
+        let overlay = profiles.as_ref()?.get(name)?.clone();
+        Some((name.clone(), overlay))
+    });
+    let labelled: Vec<(String, toml::Value)> = files
+        .iter()
+        .enumerate()
+        .map(|(i, (path, document))| {
+            let mut document = document.clone();
+            if let Some(root) = document.as_table_mut() {
+                root.remove("profile");
+            }
+            match i {
+                0 => ("file".to_string(), document),
+                _ => (format!("include {}", path.display()), document),
+            }
+        })
+        .collect();
+    let set = ENDPOINT_OVERRIDES.iter().filter(|(var, ..)| env(var).is_some());
+    let origins = Origins {
+        files: labelled,
+        profile,
+        env: set.map(|(var, key, _)| (*key, *var)).collect(),
+        flags: endpoint_flags().into_iter().map(|(key, _)| (key, flag_of(key))).collect(),
+    };
+    let document = literal(&toml::Value::try_from(config).map_err(|e| e.to_string())?);
+    let mut out = match (files.first(), &config.profile) {
+        (Some((path, _)), Some(name)) => {
+            format!("# Effective config from {} with profile {}\n", path.display(), name)
+        }
+        (Some((path, _)), None) => format!("# Effective config from {}\n", path.display()),
+        (None, _) => "# Effective config: defaults, no file\n".to_string(),
+    };
+    if files.len() > 1 {
+        let paths: Vec<String> = files.iter().map(|(path, _)| path.display().to_string()).collect();
+        out.push_str(&format!("# Files merged in this order: {}\n", paths.join(", ")));
+    }
+    if let Some(table) = document.as_table() {
+        write_table(&mut out, &[], &[], table, &origins);
+    }
+    Ok(out)
+}
+
+//
+// ---- Validation ----
+//
+// The [network] sockets and [broker] tunables are checked once the config is
+// loaded, so a typo fails at startup naming its key rather than later as an
+// opaque zmq::Error from bind(). Every problem is reported, not just the
+// first.
+
+const NETWORK_TRANSPORTS: [&str; 3] = ["tcp", "ipc", "inproc"];
+
+// A [network] value that cannot be bound, with the TOML key it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    pub value: String,
+    pub problem: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = {:?}: {}", self.key, self.value, self.problem)
+    }
+}
+
+// The host and port of a tcp endpoint, or why it has none. The port is None
+// for 0 and *, which let the OS pick a free one at bind time.
+fn tcp_host_port(address: &str) -> Result<(&str, Option<u16>), String> {
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return Err("has no port; expected host:port".to_string());
+    };
+    if host.is_empty() {
+        return Err("has no host; use * to listen on every interface".to_string());
+    }
+    match port.parse::<u16>() {
+        _ if port == "*" => Ok((host, None)),
+        Ok(0) => Ok((host, None)),
+        Ok(port) => Ok((host, Some(port))),
+        Err(_) => Err(format!(
+            "has port {:?}; expected 1 to 65535, or 0 or * for a free one",
+            port
+        )),
+    }
+}
+
+fn network_endpoint_problem(endpoint: &str) -> Option<String> {
+    if endpoint.trim().is_empty() {
+        return Some("is empty".to_string());
+    }
+    let Some((transport, address)) = endpoint.split_once("://") else {
+        return Some("has no transport; expected tcp://, ipc:// or inproc://".to_string());
+    };
+    if !NETWORK_TRANSPORTS.contains(&transport) {
+        return Some(format!(
+            "has unknown transport {:?}; expected one of {}",
+            transport,
+            NETWORK_TRANSPORTS.join(", ")
+        ));
+    }
+    if address.is_empty() || address != address.trim() {
+        return Some(format!("has no usable address after {}://", transport));
+    }
+    match transport {
+        "tcp" => tcp_host_port(address).err(),
+        _ => None,
+    }
+}
+
+// Two endpoints that would bind the same thing: equal strings, or the same
+// tcp port where either side listens on every interface. A free port picked
+// by the OS, or ipc://*, never clashes.
+fn same_binding(a: &str, b: &str) -> bool {
+    fn tcp(endpoint: &str) -> Option<(&str, Option<u16>)> {
+        endpoint.strip_prefix("tcp://").and_then(|a| tcp_host_port(a).ok())
+    }
+    let wildcard = |host: &str| matches!(host, "*" | "0.0.0.0" | "[::]" | "::");
+    match (tcp(a), tcp(b)) {
+        (Some((_, None)), _) | (_, Some((_, None))) => false,
+        (Some((host_a, port_a)), Some((host_b, port_b))) => {
+            port_a == port_b && (host_a == host_b || wildcard(host_a) || wildcard(host_b))
+        }
+        _ => a == b && a != "ipc://*",
+    }
+}
+
+pub fn validate_network_config(network: &NetworkConfig) -> Result<(), Vec<ConfigError>> {
+    let listed = !network.client_facing.is_empty();
+    let roles = [
+        ("proxy_xsub_endpoint", &network.proxy_xsub_endpoint, &network.proxy_xsub_endpoints),
+        ("proxy_xpub_endpoint", &network.proxy_xpub_endpoint, &network.proxy_xpub_endpoints),
+        (
+            "client_to_client_endpoint",
+            &network.client_to_client_endpoint,
+            &network.client_to_client_endpoints,
+        ),
+        (
+            "client_facing_endpoint",
+            &network.client_facing_endpoint,
+            &network.client_facing_endpoints,
+        ),
+        (
+            "worker_facing_endpoint",
+            &network.worker_facing_endpoint,
+            &network.worker_facing_endpoints,
+        ),
+    ];
+    // A single endpoint a list replaces, and the client-facing ones while
+    // [[network.client_facing]] is set, are checked but cannot clash.
+    let mut bound: Vec<(String, &str)> = Vec::new();
+    let mut unbound: Vec<(String, &str)> = Vec::new();
+    for (name, single, list) in roles {
+        let replaced = listed && name == "client_facing_endpoint";
+        let key = format!("network.{}", name);
+        match replaced || !list.is_empty() {
+            true => unbound.push((key, single)),
+            false => bound.push((key, single)),
+        }
+        for (i, value) in list.iter().enumerate() {
+            let entry = (format!("network.{}s[{}]", name, i), value.as_str());
+            match replaced {
+                true => unbound.push(entry),
+                false => bound.push(entry),
+            }
+        }
+    }
+    for (i, endpoint) in network.client_facing.iter().enumerate() {
+        let key = format!("network.client_facing[{}].address", i);
+        bound.push((key, &endpoint.address));
+    }
+
+    let mut errors = Vec::new();
+    let error = |key: &str, value: &str, problem: String| ConfigError {
+        key: key.to_string(),
+        value: value.to_string(),
+        problem,
+    };
+    for (key, value) in bound.iter().chain(&unbound) {
+        if let Some(problem) = network_endpoint_problem(value) {
+            errors.push(error(key, value, problem));
+        }
+    }
+    let options = &network.socket_options;
+    let tables = std::iter::once((String::new(), options.common())).chain(
+        SocketOptionsConfig::SOCKETS
+            .iter()
+            .filter_map(|socket| Some((format!("{}.", socket), options.own(socket)?.clone()))),
+    );
+    for (table, own) in tables {
+        for (name, value, min) in own.numeric() {
+            let Some(value) = value else {
+                continue;
+            };
+            if value < min || value > i64::from(i32::MAX) {
+                let key = format!("network.socket_options.{}{}", table, name);
+                let problem = format!("must be from {} to {}", min, i32::MAX);
+                errors.push(error(&key, &value.to_string(), problem));
+            }
+        }
+    }
+    if network.ipc_dir_mode > 0o7777 {
+        let (value, problem) = (format!("{:#o}", network.ipc_dir_mode), "is not a file mode");
+        errors.push(error("network.ipc_dir_mode", &value, problem.to_string()));
+    }
+    if listed && !network.client_facing_endpoints.is_empty() {
+        let problem = "has no effect while [[network.client_facing]] is set".to_string();
+        let value = network.client_facing_endpoints.join(", ");
+        errors.push(error("network.client_facing_endpoints", &value, problem));
+    }
+    for (i, (key, value)) in bound.iter().enumerate() {
+        let clash = bound[..i].iter().find(|(_, earlier)| {
+            network_endpoint_problem(earlier).is_none() && same_binding(earlier, value)
+        });
+        if let Some((earlier, _)) = clash {
+            let problem = format!("binds the same address as {}", earlier);
+            errors.push(error(key, value, problem));
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+// Why `name` cannot be given a level or file of its own, if it cannot.
+fn unknown_component(name: &str) -> Option<String> {
+    if COMPONENTS.contains(&name) {
+        return None;
+    }
+    Some(match closest_key(name, COMPONENTS) {
+        Some(known) => format!("is not a component that logs; did you mean `{}`?", known),
+        None => format!("is not a component that logs: {}", COMPONENTS.join(", ")),
+    })
+}
+
+pub fn validate_logging_config(logging: &LoggingConfig) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    match (logging.target, &logging.file_path) {
+        (LogTarget::File, None) => errors.push(ConfigError {
+            key: "logging.target".to_string(),
+            value: "file".to_string(),
+            problem: "needs [logging] file_path".to_string(),
+        }),
+        (LogTarget::Syslog, Some(path)) => errors.push(ConfigError {
+            key: "logging.file_path".to_string(),
+            value: path.clone(),
+            problem: "is not written with target = \"syslog\"".to_string(),
+        }),
+        _ => {}
+    }
+    if logging.repeat_summary_secs == 0 {
+        errors.push(ConfigError {
+            key: "logging.repeat_summary_secs".to_string(),
+            value: "0".to_string(),
+            problem: "must be at least 1".to_string(),
+        });
+    }
+    if logging.sample_every == 0 {
+        errors.push(ConfigError {
+            key: "logging.sample_every".to_string(),
+            value: "0".to_string(),
+            problem: "must be at least 1".to_string(),
+        });
+    }
+    for (name, level) in &logging.filters {
+        let key = format!("logging.filters.{}", name);
+        if let Some(problem) = unknown_component(name) {
+            errors.push(ConfigError {
+                key: key.clone(),
+                value: level.clone(),
+                problem,
+            });
+        }
+        if parse_level(level).is_none() {
+            errors.push(ConfigError {
+                key,
+                value: level.clone(),
+                problem: "must be trace, debug, info, warn or error".to_string(),
+            });
+        }
+    }
+    // Two writers rotating one file would lose lines.
+    let mut files: Vec<(&str, String)> = Vec::new();
+    if let Some(path) = &logging.file_path {
+        files.push((path, "logging.file_path".to_string()));
+    }
+    for (name, path) in &logging.components {
+        let key = format!("logging.components.{}", name);
+        if let Some(problem) = unknown_component(name) {
+            errors.push(ConfigError {
+                key: key.clone(),
+                value: path.clone(),
+                problem,
+            });
+        }
+        match files.iter().find(|(other, _)| *other == path) {
+            Some((_, other)) => errors.push(ConfigError {
+                key,
+                value: path.clone(),
+                problem: format!("is also the file of {}", other),
+            }),
+            None => files.push((path, key)),
+        }
+    }
+    if logging.audit.enabled {
+        let path = logging.audit.file_path.as_deref().unwrap_or(DEFAULT_AUDIT_FILE);
+        if let Some((_, other)) = files.iter().find(|(other, _)| *other == path) {
+            errors.push(ConfigError {
+                key: "logging.audit.file_path".to_string(),
+                value: path.to_string(),
+                problem: format!("is also the file of {}", other),
+            });
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+pub fn validate_context_config(context: &ContextConfig) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    if context.io_threads < 1 {
+        errors.push(ConfigError {
+            key: "context.io_threads".to_string(),
+            value: context.io_threads.to_string(),
+            problem: "must be at least 1".to_string(),
+        });
+    }
+    if context.max_sockets != DEFAULT_MAX_SOCKETS {
+        errors.push(ConfigError {
+            key: "context.max_sockets".to_string(),
+            value: context.max_sockets.to_string(),
+            problem: format!(
+                "cannot be changed yet, the zmq crate does not set ZMQ_MAX_SOCKETS; leave it at {}",
+                DEFAULT_MAX_SOCKETS
+            ),
+        });
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+pub fn validate_broker_config(broker: &BrokerConfig) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    if broker.poll_timeout_ms < 1 {
+        errors.push(ConfigError {
+            key: "broker.poll_timeout_ms".to_string(),
+            value: broker.poll_timeout_ms.to_string(),
+            problem: "must be at least 1; latency_mode = \"low\" busy-polls".to_string(),
+        });
+    }
+    if broker.retry_attempts == 0 {
+        errors.push(ConfigError {
+            key: "broker.retry_attempts".to_string(),
+            value: broker.retry_attempts.to_string(),
+            problem: "must be at least 1".to_string(),
+        });
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+// Every validation, as one message for the config error path.
+pub fn check_config(config: &Config) -> Result<(), String> {
+    let mut errors = validate_logging_config(&config.logging).err().unwrap_or_default();
+    errors.extend(validate_network_config(&config.network).err().unwrap_or_default());
+    errors.extend(validate_context_config(&config.context).err().unwrap_or_default());
+    errors.extend(validate_broker_config(&config.broker).err().unwrap_or_default());
+    if !config.proxy.enabled && !config.broker.enabled {
+        errors.push(ConfigError {
+            key: "broker.enabled".to_string(),
+            value: "false".to_string(),
+            problem: "and proxy.enabled is false too; nothing would run".to_string(),
+        });
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => {
+            let lines: Vec<String> = errors.iter().map(|e| format!("  {}", e)).collect();
+            Err(format!("Invalid settings:\n{}", lines.join("\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "PORT" => Some("6000".to_string()),
+            "HOST" => Some("10.0.0.1".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn defaults_stand_in_only_when_allowed() {
+        let allow = |value: &'static str| {
+            move |name: &str| (name == ALLOW_MISSING_CONFIG_ENV).then(|| value.to_string())
+        };
+        assert!(defaults_allowed(&allow("1")) && defaults_allowed(&allow("true")));
+        assert!(!defaults_allowed(&allow("0")) && !defaults_allowed(&env));
+        let config = default_config(|name| match name {
+            "CORKY_WORKER_FACING_ENDPOINT" => Some("ipc:///tmp/corky-w".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.network.worker_facing_endpoint, "ipc:///tmp/corky-w");
+        let e = default_config(profile_env("dev")).map(|_| ()).unwrap_err();
+        assert!(e.contains("profile dev needs a config file"), "{}", e);
+        let hint = missing_config_error(Path::new("/home/ops/.corky/config.toml"));
+        assert!(hint.contains("init-config") && hint.contains("--use-defaults"));
+    }
+
+    #[test]
+    fn the_extension_picks_the_parser() {
+        assert_eq!(ConfigFormat::of(Path::new("a/config.toml")), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::of(Path::new("broker-a.conf")), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::of(Path::new("generated.JSON")), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::of(Path::new("config.yml")), ConfigFormat::Yaml);
+
+        let json = r#"{
+            "logging": {"level": "debug"},
+            "network": {"client_facing_endpoints": ["tcp://${HOST}:5559", "ipc:///tmp/c"]},
+            "broker": {"max_peers": 7}
+        }"#;
+        let config = parse_config_as(ConfigFormat::Json, json, env).unwrap();
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(
+            config.network.client_facing_addresses(),
+            ["tcp://10.0.0.1:5559", "ipc:///tmp/c"]
+        );
+        assert_eq!(config.broker.max_peers, 7);
+        let typo = parse_config_as(ConfigFormat::Json, r#"{"netwrok": {}}"#, env);
+        assert!(typo.map(|_| ()).unwrap_err().contains("did you mean [network]?"));
+
+        let yaml = "logging:\n  level: debug\n\
+                    network:\n  client_facing_endpoints: [\"tcp://${HOST}:5559\", ipc:///tmp/c]\n\
+                    broker:\n  max_peers: 7\n";
+        let config = parse_config_as(ConfigFormat::Yaml, yaml, env).unwrap();
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(
+            config.network.client_facing_addresses(),
+            ["tcp://10.0.0.1:5559", "ipc:///tmp/c"]
+        );
+        assert_eq!(config.broker.max_peers, 7);
+        let typo = parse_config_as(ConfigFormat::Yaml, "netwrok: {}\n", env);
+        assert!(typo.map(|_| ()).unwrap_err().contains("did you mean [network]?"));
+    }
+
+    #[test]
+    fn a_parse_error_names_the_file_and_the_parser() {
+        let dir = std::env::temp_dir().join(format!("corky-formats-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert!(config_files_in(&dir).is_empty());
+        for (name, content) in [
+            ("config.yaml", "logging:\n  level: debug\n"),
+            ("config.json", "{\"logging\": "),
+            ("config.toml", "[logging]\nlevel = \"warn\"\n"),
+        ] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let found = config_files_in(&dir);
+        assert_eq!(found, CONFIG_FILE_NAMES.map(|name| dir.join(name)));
+        assert_eq!(load_config_from(&found[0]).unwrap().logging.level, "warn");
+
+        let e = load_config_from(&found[1]).map(|_| ()).unwrap_err();
+        let prefix = format!("{}: Failed to parse config as JSON: ", found[1].display());
+        assert!(e.starts_with(&prefix), "{}", e);
+        assert_eq!(load_config_from(&found[2]).unwrap().logging.level, "debug");
+        fs::write(&found[2], "logging: [level\n").unwrap();
+        let e = load_config_from(&found[2]).map(|_| ()).unwrap_err();
+        let prefix = format!("{}: Failed to parse config as YAML: ", found[2].display());
+        assert!(e.starts_with(&prefix), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn included_files_merge_in_order_and_name_their_chain() {
+        let dir = std::env::temp_dir().join(format!("corky-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("site")).unwrap();
+        for (name, content) in [
+            (
+                "main.toml",
+                "config_version = 2\ninclude = [\"endpoints.toml\", \"site/site.json\"]\n\
+                 [logging]\nlevel = \"debug\"\n\
+                 [network]\nclient_facing_endpoint = \"tcp://*:7001\"\n",
+            ),
+            (
+                "endpoints.toml",
+                "[network]\nclient_facing_endpoint = \"tcp://*:7002\"\n\
+                 worker_facing_endpoint = \"tcp://*:7003\"\n",
+            ),
+            ("site/site.json", r#"{"network": {"worker_facing_endpoint": "tcp://*:7004"}}"#),
+            ("a.toml", "include = [\"b.toml\"]\n"),
+            ("b.toml", "include = [\"a.toml\"]\n"),
+            ("c.toml", "include = [\"b.toml\", \"missing.toml\"]\n"),
+            ("d.toml", "include = [\"site/missing.toml\"]\n"),
+        ] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let dir = fs::canonicalize(&dir).unwrap();
+        let config = load_config_from(&dir.join("main.toml")).unwrap();
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(config.network.client_facing_endpoint, "tcp://*:7002");
+        assert_eq!(config.network.worker_facing_endpoint, "tcp://*:7004");
+        assert_eq!(config.schema_version(), 2);
+        let names = ["main.toml", "endpoints.toml", "site/site.json"];
+        assert_eq!(config.files, names.map(|name| dir.join(name)));
+
+        let e = load_config_from(&dir.join("a.toml")).map(|_| ()).unwrap_err();
+        let shown = |name: &str| dir.join(name).display().to_string();
+        let (a, b) = (shown("a.toml"), shown("b.toml"));
+        assert_eq!(e, format!("Config include cycle: {} -> {} -> {}", a, b, a));
+        let e = load_config_from(&dir.join("c.toml")).map(|_| ()).unwrap_err();
+        assert!(e.starts_with("Config include cycle: "), "{}", e);
+        let e = load_config_from(&dir.join("d.toml")).map(|_| ()).unwrap_err();
+        let missing = shown("site/missing.toml");
+        assert!(e.starts_with(&format!("Included config file not found at: {}", missing)));
+        assert!(e.contains(&format!("d.toml -> {})", missing)), "{}", e);
+
+        let e = parse_config("include = [\"a.toml\"]\n", |_| None).map(|_| ()).unwrap_err();
+        assert!(e.contains("only read from a config file"), "{}", e);
+        let e = parse_config("include = \"a.toml\"\n", |_| None).map(|_| ()).unwrap_err();
+        assert!(e.contains("must be a list of file names"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_flag_beats_the_environment_and_paths_become_absolute() {
+        let cwd = std::env::current_dir().unwrap();
+        let flag = Path::new("configs/broker-a.toml");
+        let env = || Some("/etc/corky/env.toml".to_string());
+        assert_eq!(
+            chosen_config_path(Some(flag), env()).unwrap(),
+            Some(cwd.join("configs/broker-a.toml"))
+        );
+        assert_eq!(
+            chosen_config_path(None, env()).unwrap(),
+            Some(PathBuf::from("/etc/corky/env.toml"))
+        );
+        assert_eq!(chosen_config_path(None, Some(String::new())).unwrap(), None);
+        assert_eq!(chosen_config_path(None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn an_unreadable_config_names_its_path() {
+        let missing = std::env::temp_dir().join("corky-no-such-config.toml");
+        let e = load_config_from(&missing).err().unwrap();
+        assert!(e.contains(&missing.display().to_string()), "{}", e);
+        let dir = std::env::temp_dir();
+        let e = load_config_from(&dir).err().unwrap();
+        assert!(e.contains(&dir.display().to_string()), "{}", e);
+    }
+
+    #[test]
+    fn endpoint_variables_win_and_report_their_source() {
+        let mut config = parse_config(
+            "[network]\nworker_facing_endpoint = \"tcp://*:7000\"",
+            env,
+        )
+        .unwrap();
+        let env = |name: &str| match name {
+            "CORKY_CLIENT_FACING_ENDPOINT" => Some("ipc:///run/corky/client".to_string()),
+            _ => None,
+        };
+        apply_env_overrides(&mut config, env).unwrap();
+        assert_eq!(config.network.client_facing_endpoint, "ipc:///run/corky/client");
+        let sources = endpoint_sources(&config, env);
+        let source = |key| sources.iter().find(|(k, ..)| *k == key).unwrap().2;
+        assert_eq!(source("network.client_facing_endpoint"), SettingSource::Env);
+        assert_eq!(source("network.worker_facing_endpoint"), SettingSource::File);
+        assert_eq!(source("network.proxy_xsub_endpoint"), SettingSource::Default);
+    }
+
+    #[test]
+    fn malformed_endpoint_variables_fail_fast() {
+        for bad in ["", "  ", "localhost:5559", "http://x:1", "tcp://"] {
+            let env = |name: &str| {
+                (name == "CORKY_WORKER_FACING_ENDPOINT").then(|| bad.to_string())
+            };
+            let e = apply_env_overrides(&mut Config::default(), env).unwrap_err();
+            assert!(e.starts_with("CORKY_WORKER_FACING_ENDPOINT"), "{}", e);
+        }
+        let mut listed = Config::default();
+        listed.network.client_facing = NetworkConfig::default().client_endpoints();
+        let env = |name: &str| {
+            (name == "CORKY_CLIENT_FACING_ENDPOINT").then(|| "tcp://*:1".to_string())
+        };
+        assert!(apply_env_overrides(&mut listed, env).is_err());
+    }
+
+    #[test]
+    fn endpoint_flags_beat_the_environment_and_clash_like_the_file() {
+        let mut config = Config::default();
+        let env = |name: &str| {
+            (name == "CORKY_CLIENT_FACING_ENDPOINT").then(|| "tcp://*:6001".to_string())
+        };
+        apply_env_overrides(&mut config, env).unwrap();
+        let flags = [
+            ("network.client_facing_endpoint", "tcp://*:7000".to_string()),
+            ("network.client_facing_endpoint", "tcp://*:7001".to_string()),
+            ("network.worker_facing_endpoint", "tcp://*:7002".to_string()),
+        ];
+        apply_endpoint_flags(&mut config, &flags).unwrap();
+        assert_eq!(config.network.client_facing_endpoint, "tcp://*:7001");
+        assert_eq!(config.network.worker_facing_endpoint, "tcp://*:7002");
+        assert_eq!(validate_network_config(&config.network), Ok(()));
+
+        let clash = [("network.worker_facing_endpoint", "tcp://127.0.0.1:7001".to_string())];
+        apply_endpoint_flags(&mut config, &clash).unwrap();
+        let errors = validate_network_config(&config.network).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].key, "network.worker_facing_endpoint");
+
+        let bad = [("network.proxy_xsub_endpoint", "localhost:1".to_string())];
+        let e = apply_endpoint_flags(&mut config, &bad).unwrap_err();
+        assert!(e.starts_with("--proxy-xsub-endpoint "), "{}", e);
+    }
+
+    #[test]
+    fn the_default_network_is_valid() {
+        assert_eq!(validate_network_config(&NetworkConfig::default()), Ok(()));
+        let network = NetworkConfig {
+            proxy_xsub_endpoint: "ipc:///run/corky/xsub".to_string(),
+            proxy_xpub_endpoint: "inproc://xpub".to_string(),
+            client_to_client_endpoint: "tcp://127.0.0.1:6565".to_string(),
+            client_facing_endpoint: "tcp://*:6565".to_string(),
+            client_facing: vec![EndpointConfig {
+                address: "tcp://[::1]:5559".to_string(),
+                ..EndpointConfig::default()
+            }],
+            ..NetworkConfig::default()
+        };
+        // client_facing_endpoint is not bound next to [[network.client_facing]].
+        assert_eq!(validate_network_config(&network), Ok(()));
+    }
+
+    #[test]
+    fn every_network_problem_is_named_at_once() {
+        let network = NetworkConfig {
+            proxy_xsub_endpoint: "tpc://*:5557".to_string(),
+            proxy_xpub_endpoint: "tcp://:5558".to_string(),
+            client_to_client_endpoint: "tcp://*:70000".to_string(),
+            client_facing_endpoint: "tcp://0.0.0.0:5560".to_string(),
+            worker_facing_endpoint: "tcp://*:5560".to_string(),
+            ..NetworkConfig::default()
+        };
+        let errors: Vec<String> = validate_network_config(&network)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "network.proxy_xsub_endpoint = \"tpc://*:5557\": has unknown transport \"tpc\"; \
+                 expected one of tcp, ipc, inproc",
+                "network.proxy_xpub_endpoint = \"tcp://:5558\": has no host; \
+                 use * to listen on every interface",
+                "network.client_to_client_endpoint = \"tcp://*:70000\": has port \"70000\"; \
+                 expected 1 to 65535, or 0 or * for a free one",
+                "network.worker_facing_endpoint = \"tcp://*:5560\": binds the same address as \
+                 network.client_facing_endpoint",
+            ]
+        );
+
+        let path = std::env::temp_dir().join(format!("corky-network-{}.toml", std::process::id()));
+        fs::write(&path, "[network]\nworker_facing_endpoint = \"tcp://*:5559\"\n").unwrap();
+        let e = load_config_from(&path).err().unwrap();
+        assert!(e.contains("network.worker_facing_endpoint = \"tcp://*:5559\""), "{}", e);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wildcard_ports_are_valid_and_never_clash() {
+        let network = NetworkConfig {
+            proxy_xsub_endpoint: "tcp://127.0.0.1:0".to_string(),
+            proxy_xpub_endpoint: "tcp://127.0.0.1:0".to_string(),
+            client_to_client_endpoint: "tcp://*:*".to_string(),
+            client_facing_endpoint: "ipc://*".to_string(),
+            worker_facing_endpoint: "ipc://*".to_string(),
+            ..NetworkConfig::default()
+        };
+        assert_eq!(validate_network_config(&network), Ok(()));
+        assert!(same_binding("tcp://*:5559", "tcp://127.0.0.1:5559"));
+        assert!(same_binding("ipc:///run/a", "ipc:///run/a"));
+        assert!(!same_binding("tcp://*:0", "tcp://*:0"));
+    }
+
+    #[test]
+    fn endpoint_lists_replace_the_single_endpoint() {
+        let config = parse_config(
+            "[network]\n\
+             client_facing_endpoint = \"tcp://*:5559\"\n\
+             client_facing_endpoints = [\"tcp://*:5559\", \"ipc:///run/corky/client\"]\n\
+             worker_facing_endpoints = [\"inproc://a\", \"tcp://*:6565\"]\n",
+            |_| None,
+        )
+        .unwrap();
+        let network = &config.network;
+        assert_eq!(
+            network.client_facing_addresses(),
+            ["tcp://*:5559", "ipc:///run/corky/client"]
+        );
+        assert_eq!(network.client_endpoints().len(), 1);
+        assert_eq!(network.proxy_xsub_addresses(), [DEFAULT_PROXY_XSUB_ENDPOINT]);
+        let errors = validate_network_config(network).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].key, "network.worker_facing_endpoints[1]");
+        assert_eq!(
+            errors[0].problem,
+            "binds the same address as network.client_to_client_endpoint"
+        );
+
+        let env = |name: &str| {
+            (name == "CORKY_CLIENT_FACING_ENDPOINT").then(|| "tcp://*:1".to_string())
+        };
+        let e = apply_env_overrides(&mut config.clone(), env).unwrap_err();
+        assert!(e.contains("network.client_facing_endpoints"), "{}", e);
+        let sources = endpoint_sources(&config, |_| None);
+        assert!(sources.contains(&(
+            "network.client_facing_endpoints",
+            "tcp://*:5559, ipc:///run/corky/client".to_string(),
+            SettingSource::File
+        )));
+    }
+
+    #[test]
+    fn socket_options_out_of_range_are_named() {
+        let config = parse_config(
+            "[network.socket_options]\n\
+             sndhwm = -1\n\
+             tcp_keepalive = true\n\
+             [network.socket_options.client_router]\n\
+             tcp_keepalive_idle = 0\n\
+             linger_ms = 4294967296\n",
+            |_| None,
+        )
+        .unwrap();
+        let options = &config.network.socket_options;
+        assert_eq!(options.for_socket("client_router").tcp_keepalive, Some(true));
+        let errors: Vec<String> = validate_network_config(&config.network)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "network.socket_options.sndhwm = \"-1\": must be from 0 to 2147483647",
+                "network.socket_options.client_router.linger_ms = \"4294967296\": \
+                 must be from 0 to 2147483647",
+                "network.socket_options.client_router.tcp_keepalive_idle = \"0\": \
+                 must be from 1 to 2147483647",
+            ]
+        );
+        let e = parse_config("[network.socket_options.worker_router]\nsndhvm = 1", |_| None);
+        assert!(e.err().unwrap().contains("unknown field `sndhvm`"));
+    }
+
+    #[test]
+    fn the_ipc_directory_mode_is_octal_and_checked() {
+        let config = parse_config("[network]\nipc_dir_mode = 0o750", |_| None).unwrap();
+        assert_eq!(config.network.ipc_dir_mode, 0o750);
+        validate_network_config(&config.network).unwrap();
+        let config = parse_config("[network]\nipc_dir_mode = 0o17777", |_| None).unwrap();
+        let errors = validate_network_config(&config.network).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "network.ipc_dir_mode = \"0o17777\": is not a file mode"
+        );
+    }
+
+    #[test]
+    fn every_section_belongs_to_a_schema_version() {
+        let added = ADDED_SECTIONS.iter().flat_map(|(_, sections)| sections.iter());
+        let mut known: Vec<&str> = ["config_version", "logging", "network"]
+            .into_iter()
+            .chain(added.copied())
+            .collect();
+        let mut fields = field_names::<Config>().to_vec();
+        known.sort_unstable();
+        fields.sort_unstable();
+        assert_eq!(known, fields);
+        assert_eq!(ADDED_SECTIONS.last().unwrap().0, CONFIG_VERSION);
+    }
+
+    #[test]
+    fn older_files_load_and_newer_ones_are_refused() {
+        let old = parse_config("[network]\n[broker]\nmax_peers = 7\n", |_| None).unwrap();
+        assert_eq!(old.schema_version(), 1);
+        assert!(!old.defaulted_sections.contains(&"broker".to_string()));
+        assert_eq!(old.defaulted_sections[..2], ["formatting", "state"]);
+        let current = parse_config("config_version = 2\n", |_| None).unwrap();
+        assert_eq!(current.schema_version(), 2);
+        assert!(current.defaulted_sections.is_empty());
+
+        let e = parse_config("config_version = 3\n", |_| None).map(|_| ()).unwrap_err();
+        assert!(e.contains("newer than this build of corky-zmq understands (up to 2)"), "{}", e);
+        assert!(e.contains("upgrade corky-zmq"), "{}", e);
+        let e = parse_config("config_version = 0\n", |_| None).map(|_| ()).unwrap_err();
+        assert!(e.contains("versions start at 1"), "{}", e);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_replaced_config_keeps_its_mode_and_no_temporary_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("corky-replace-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[logging]\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        replace_config_file(&path, "config_version = 2\n[logging]\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "config_version = 2\n[logging]\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A failed rename leaves no copy of the content behind.
+        let target = dir.join("occupied");
+        fs::create_dir_all(target.join("entry")).unwrap();
+        assert!(replace_config_file(&target, "secret = 1\n").is_err());
+        assert!(!dir.join("occupied.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrating_keeps_comments_and_values() {
+        let old = "# Broker A\n[logging]\nlevel = \"debug\"  # while testing\n";
+        let migrated = migrate_config(ConfigFormat::Toml, old).unwrap().unwrap();
+        assert!(migrated.contains("\nconfig_version = 2\n\n# Broker A\n"), "{}", migrated);
+        assert!(migrated.ends_with(old));
+        let config = parse_config(&migrated, |_| None).unwrap();
+        assert_eq!((config.schema_version(), config.logging.level.as_str()), (2, "debug"));
+        assert_eq!(migrate_config(ConfigFormat::Toml, &migrated), Ok(None));
+
+        let stated = "config_version = 1\n[network]\n";
+        assert_eq!(
+            migrate_config(ConfigFormat::Toml, stated).unwrap().unwrap(),
+            "config_version = 2\n[network]\n"
+        );
+        let json = migrate_config(ConfigFormat::Json, r#"{"logging": {"level": "warn"}}"#)
+            .unwrap()
+            .unwrap();
+        let config = parse_config_as(ConfigFormat::Json, &json, |_| None).unwrap();
+        assert_eq!((config.schema_version(), config.logging.level.as_str()), (2, "warn"));
+        assert!(migrate_config(ConfigFormat::Toml, "config_version = 9\n").is_err());
+    }
+
+    #[test]
+    fn context_options_default_to_libzmqs() {
+        let config = parse_config("[context]\nio_threads = 4\n", |_| None).unwrap();
+        assert_eq!(config.context.io_threads, 4);
+        assert_eq!(config.context.max_sockets, 1023);
+        assert_eq!(validate_context_config(&config.context), Ok(()));
+        let bad = ContextConfig {
+            io_threads: 0,
+            max_sockets: 4096,
+        };
+        let errors = validate_context_config(&bad).unwrap_err();
+        let keys: Vec<&str> = errors.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, ["context.io_threads", "context.max_sockets"]);
+    }
+
+    #[test]
+    fn log_filters_name_components_and_levels() {
+        let config = parse_config(
+            "[logging]\nlevel = \"warn\"\n[logging.filters]\nbroker = \"debug\"\n",
+            |_| None,
+        )
+        .unwrap();
+        assert_eq!(config.logging.filters["broker"], "debug");
+        assert_eq!(validate_logging_config(&config.logging), Ok(()));
+
+        let mut logging = config.logging;
+        logging.filters.insert("brokr".to_string(), "info".to_string());
+        logging.filters.insert("proxy".to_string(), "loud".to_string());
+        let errors: Vec<String> = validate_logging_config(&logging)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "logging.filters.brokr = \"info\": is not a component that logs; \
+                 did you mean `broker`?",
+                "logging.filters.proxy = \"loud\": must be trace, debug, info, warn or error",
+            ]
+        );
+    }
+
+    #[test]
+    fn component_log_files_name_components_and_distinct_files() {
+        let content = "[logging]\nfile_path = \"/var/log/corky.log\"\n\
+            [logging.components]\nbroker = \"~/.corky/logs/broker.log\"\n";
+        let config = parse_config(content, |_| None).unwrap();
+        assert_eq!(config.logging.components["broker"], "~/.corky/logs/broker.log");
+        assert_eq!(validate_logging_config(&config.logging), Ok(()));
+
+        let mut logging = config.logging;
+        let mut add = |name: &str, path: &str| {
+            logging.components.insert(name.to_string(), path.to_string());
+        };
+        add("proxy", "~/.corky/logs/broker.log");
+        add("admin", "/var/log/corky.log");
+        add("prxy", "/var/log/prxy.log");
+        let errors: Vec<String> = validate_logging_config(&logging)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "logging.components.admin = \"/var/log/corky.log\": \
+                 is also the file of logging.file_path",
+                "logging.components.proxy = \"~/.corky/logs/broker.log\": \
+                 is also the file of logging.components.broker",
+                "logging.components.prxy = \"/var/log/prxy.log\": \
+                 is not a component that logs; did you mean `proxy`?",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_log_target_needs_what_it_writes_to() {
+        let config = parse_config("[logging]\ntarget = \"syslog\"\n", |_| None).unwrap();
+        assert_eq!(config.logging.target, LogTarget::Syslog);
+        assert_eq!(validate_logging_config(&config.logging), Ok(()));
+        assert_eq!(LoggingConfig::default().target, LogTarget::Stderr);
+
+        let mut logging = config.logging;
+        logging.file_path = Some("/var/log/corky.log".to_string());
+        let keys = |logging: &LoggingConfig| -> Vec<String> {
+            validate_logging_config(logging)
+                .err()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| e.key)
+                .collect()
+        };
+        assert_eq!(keys(&logging), ["logging.file_path"]);
+        logging.target = LogTarget::File;
+        assert!(keys(&logging).is_empty());
+        logging.file_path = None;
+        assert_eq!(keys(&logging), ["logging.target"]);
+    }
+
+    #[test]
+    fn broker_tunables_refuse_zero() {
+        let mut config = parse_config(
+            "[broker]\npoll_timeout_ms = 2\nretry_attempts = 3\nretry_backoff_ms = 50\n",
+            |_| None,
+        )
+        .unwrap();
+        assert_eq!(config.broker.retry_attempts, 3);
+        assert_eq!(check_config(&config), Ok(()));
+        config.broker.poll_timeout_ms = 0;
+        config.broker.retry_attempts = 0;
+        let keys: Vec<String> = validate_broker_config(&config.broker)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(keys, ["broker.poll_timeout_ms", "broker.retry_attempts"]);
+    }
+
+    #[test]
+    fn the_shed_order_names_pools_that_are_charged() {
+        let config = parse_config("[broker]\nshed_order = [\"offline\"]\n", |_| None).unwrap();
+        assert_eq!(config.broker.shed_order, [Pool::Offline]);
+        let err = parse_config("[broker]\nshed_order = [\"dlq\"]\n", |_| None)
+            .err()
+            .unwrap();
+        assert!(err.contains("unknown variant `dlq`"), "{}", err);
+    }
+
+    #[test]
+    fn a_welcome_message_is_a_string_or_a_table() {
+        let welcome = |toml: &str| {
+            let config = parse_config(&format!("[proxy]\n{}", toml), |_| None).unwrap();
+            config.proxy.welcome_message.and_then(|w| w.frame())
+        };
+        assert_eq!(welcome(""), None);
+        assert_eq!(welcome("welcome_message = \"\""), None);
+        assert_eq!(welcome("welcome_message = \"hi\""), Some("hi".to_string()));
+        let compact = Some("{\"type\":\"welcome\",\"v\":1}".to_string());
+        assert_eq!(welcome("welcome_message = { type = \"welcome\", v = 1 }"), compact);
+        assert_eq!(welcome("welcome_message = '{ \"v\": 1, \"type\": \"welcome\" }'"), compact);
+        // Only objects are reformatted.
+        assert_eq!(welcome("welcome_message = '[1, 2]'"), Some("[1, 2]".to_string()));
+        assert_eq!(welcome("welcome_message = '{oops'"), Some("{oops".to_string()));
+    }
+
+    #[test]
+    fn one_component_must_stay_enabled() {
+        let mut config = parse_config(
+            "[proxy]\nenabled = false\n[broker]\ndirect_messaging = false\n",
+            |_| None,
+        )
+        .unwrap();
+        assert!(!config.proxy.enabled && config.broker.enabled);
+        assert_eq!(check_config(&config), Ok(()));
+        config.broker.enabled = false;
+        let e = check_config(&config).unwrap_err();
+        assert!(e.contains("broker.enabled = \"false\""), "{}", e);
+        assert!(e.contains("nothing would run"), "{}", e);
+    }
+
+    #[test]
+    fn unknown_keys_fail_with_the_closest_known_key() {
+        let fields = [
+            "level",
+            "format",
+            "timestamp",
+            "color",
+            "target",
+            "file_path",
+            "max_size_mb",
+            "max_files",
+            "repeat_limit",
+            "repeat_summary_secs",
+            "sample_every",
+            "filters",
+            "components",
+            "audit",
+        ];
+        assert_eq!(field_names::<LoggingConfig>(), fields);
+        let content = "\
+            flavour = 1\n\
+            [netwrok]\n\
+            [logging]\n\
+            levle = \"debug\"\n\
+            [network]\n\
+            worker_facing_endpont = \"tcp://*:7000\"\n\
+            colour = \"blue\"\n";
+        let e = parse_config(content, |_| None).err().unwrap();
+        assert_eq!(
+            e,
+            "Unknown config keys:\n  \
+             unknown key `flavour` at the top level\n  \
+             unknown section [netwrok]; did you mean [network]?\n  \
+             unknown key `levle` in [logging]; did you mean `level`?\n  \
+             unknown key `colour` in [network]\n  \
+             unknown key `worker_facing_endpont` in [network]; \
+             did you mean `worker_facing_endpoint`?"
+        );
+        assert!(parse_config(include_str!("../config.toml"), |_| None).is_ok());
+        // Absent keys still take their defaults.
+        let config = parse_config("[network]\nworker_facing_endpoint = \"tcp://*:7000\"", |_| None);
+        assert_eq!(config.unwrap().network.client_facing_endpoint, DEFAULT_CLIENT_FACING_ENDPOINT);
+    }
+
+    #[test]
+    fn lenient_mode_drops_unknown_keys() {
+        let mut document: toml::Value =
+            toml::from_str("[network]\nworker_facing_endpont = \"tcp://*:7000\"\n[netwrok]\n")
+                .unwrap();
+        check_unknown_keys(&mut document, true).unwrap();
+        let config: Config = document.try_into().unwrap();
+        assert_eq!(config.network.worker_facing_endpoint, DEFAULT_WORKER_FACING_ENDPOINT);
+    }
+
+    #[test]
+    fn the_default_file_round_trips_to_the_defaults() {
+        let file = default_config_file().unwrap();
+        let config = parse_config(&file, |_| None).unwrap();
+        assert_eq!(config.logging, LoggingConfig::default());
+        assert_eq!(config.network, NetworkConfig::default());
+        assert!(file.contains("\n# ROUTER clients send requests to (broker)\n"));
+        assert!(file.contains("\nclient_facing_endpoint = \"tcp://*:5559\"\n"));
+    }
+
+    #[test]
+    fn init_config_refuses_to_overwrite_without_force() {
+        let dir = std::env::temp_dir().join(format!("corky-init-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("config.toml");
+        write_default_config(&path, false).unwrap();
+        fs::write(&path, "[logging]\nlevel = \"debug\"\n").unwrap();
+        let e = write_default_config(&path, false).unwrap_err();
+        assert!(e.contains("--force"), "{}", e);
+        assert!(fs::read_to_string(&path).unwrap().contains("debug"));
+        write_default_config(&path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), default_config_file().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn variables_and_defaults_expand_in_string_values() {
+        let config = parse_config(
+            r#"
+            [network]
+            client_facing_endpoint = "tcp://${HOST}:${PORT}"
+            worker_facing_endpoint = "tcp://*:${WORKER_PORT:-5560}"
+            client_to_client_endpoint = "tcp://${UNSET:-${EMPTY:-${HOST}}}:${PORT:-1}"
+            "#,
+            env,
+        )
+        .unwrap();
+        assert_eq!(config.network.client_facing_endpoint, "tcp://10.0.0.1:6000");
+        assert_eq!(config.network.worker_facing_endpoint, "tcp://*:5560");
+        assert_eq!(config.network.client_to_client_endpoint, "tcp://10.0.0.1:6000");
+    }
+
+    const PROFILES: &str = r#"
+        [logging]
+        level = "info"
+        [network]
+        client_facing_endpoint = "tcp://10.0.0.1:5559"
+        worker_facing_endpoint = "tcp://10.0.0.1:5560"
+        [profile.dev.logging]
+        level = "trace"
+        [profile.dev.network]
+        client_facing_endpoint = "tcp://127.0.0.1:${PORT}"
+        [profile.prod.broker]
+        max_peers = 7
+        "#;
+
+    fn profile_env(profile: &'static str) -> impl Fn(&str) -> Option<String> {
+        move |name| match name {
+            PROFILE_ENV => Some(profile.to_string()),
+            _ => env(name),
+        }
+    }
+
+    #[test]
+    fn a_profile_merges_over_the_file_key_by_key() {
+        let config = parse_config(PROFILES, profile_env("dev")).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("dev"));
+        assert_eq!(config.logging.level, "trace");
+        assert_eq!(config.network.client_facing_endpoint, "tcp://127.0.0.1:6000");
+        assert_eq!(config.network.worker_facing_endpoint, "tcp://10.0.0.1:5560");
+        assert_eq!(config.broker.max_peers, DEFAULT_MAX_PEERS);
+
+        let config = parse_config(PROFILES, env).unwrap();
+        assert_eq!(config.profile, None);
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.network.client_facing_endpoint, "tcp://10.0.0.1:5559");
+        assert_eq!(parse_config(PROFILES, profile_env("")).unwrap().profile, None);
+    }
+
+    #[test]
+    fn an_unknown_profile_lists_the_available_ones() {
+        let e = parse_config(PROFILES, profile_env("stage")).map(|_| ()).unwrap_err();
+        assert_eq!(e, "Unknown config profile \"stage\"; available: dev, prod");
+        let e = parse_config("", profile_env("dev")).map(|_| ()).unwrap_err();
+        assert!(e.contains("defines none"), "{}", e);
+        // The chosen profile's keys are checked like the file's.
+        let typo = "[profile.dev.network]\nclient_facing_endpont = \"tcp://*:1\"";
+        let e = parse_config(typo, profile_env("dev")).map(|_| ()).unwrap_err();
+        assert!(e.contains("did you mean `client_facing_endpoint`?"), "{}", e);
+    }
+
+    #[test]
+    fn print_config_marks_origins_and_loads_back() {
+        let content = format!(
+            "{}\n[auth.users]\nalice = \"s3cret\"\n[state]\ntopic_prefix = \"$$$${{PORT}}/\"\n\
+             [[network.client_facing]]\nname = \"public\"\naddress = \"tcp://*:7000\"\n",
+            PROFILES
+        );
+        let env = |name: &str| match name {
+            "CORKY_CLIENT_TO_CLIENT_ENDPOINT" => Some("tcp://*:7565".to_string()),
+            _ => profile_env("dev")(name),
+        };
+        let mut config = parse_config(&content, env).unwrap();
+        apply_env_overrides(&mut config, env).unwrap();
+        let file = (PathBuf::from("a.toml"), ConfigFormat::Toml.parse(&content).unwrap());
+        let printed = print_config(&config, &[file], env).unwrap();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(lines[0], "# Effective config from a.toml with profile dev");
+        for line in [
+            "level = \"trace\"  # profile dev",
+            "worker_facing_endpoint = \"tcp://10.0.0.1:5560\"  # file",
+            "client_to_client_endpoint = \"tcp://*:7565\"  # env CORKY_CLIENT_TO_CLIENT_ENDPOINT",
+            "proxy_xsub_endpoint = \"tcp://*:5557\"  # default",
+            "alice = \"********\"  # file, masked",
+            "topic_prefix = \"$$$${PORT}/\"  # file",
+            "[[network.client_facing]]",
+            "address = \"tcp://*:7000\"  # file",
+        ] {
+            assert!(lines.contains(&line), "{} missing from\n{}", line, printed);
+        }
+        assert!(!printed.contains("s3cret"));
+
+        let mut reloaded = parse_config(&printed, |_| None).unwrap();
+        assert_eq!(reloaded.auth.users["alice"], MASKED);
+        assert_eq!(reloaded.state.topic_prefix, "$${PORT}/");
+        reloaded.auth.users = config.auth.users.clone();
+        assert_eq!(
+            toml::Value::try_from(&reloaded).unwrap(),
+            toml::Value::try_from(&config).unwrap()
+        );
+        let defaults = print_config(&Config::default(), &[], |_| None).unwrap();
+        assert!(defaults.starts_with("# Effective config: defaults, no file\n"));
+        assert!(!defaults.contains("# file") && !defaults.contains("# env"));
+        parse_config(&defaults, |_| None).unwrap();
+    }
+
+    #[test]
+    fn endpoint_lists_expand_before_validation() {
+        let config = parse_config(
+            r#"
+            [network]
+            client_facing_endpoints = ["tcp://${HOST}:${PORT}", "ipc://${UNSET:-/tmp}/c-${PORT}"]
+            "#,
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            config.network.client_facing_addresses(),
+            ["tcp://10.0.0.1:6000", "ipc:///tmp/c-6000"]
+        );
+        check_config(&config).unwrap();
+        // The expanded value is what gets checked.
+        let config = parse_config(
+            "[network]\nworker_facing_endpoint = \"tcp://${HOST}:${UNSET:-70000}\"",
+            env,
+        )
+        .unwrap();
+        let e = check_config(&config).unwrap_err();
+        assert!(e.contains("network.worker_facing_endpoint = \"tcp://10.0.0.1:70000\""), "{}", e);
+    }
+
+    #[test]
+    fn a_missing_variable_names_itself_and_the_key() {
+        let error = parse_config(
+            "[[network.client_facing]]\naddress = \"tcp://*:${FRONT_PORT}\"",
+            env,
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(error.contains("network.client_facing[0].address"), "{}", error);
+        assert!(error.contains("FRONT_PORT is not set"), "{}", error);
+        let unterminated = parse_config("[gc]\nnotify_endpoint = \"${PORT\"", env);
+        assert!(unterminated.map(|_| ()).unwrap_err().contains("unterminated"));
+    }
+
+    #[test]
+    fn double_dollars_are_literal() {
+        let config = parse_config(
+            "[auth.users]\nalice = \"pa$$word-$${PORT}-$\"",
+            env,
+        )
+        .unwrap();
+        assert_eq!(config.auth.users["alice"], "pa$word-${PORT}-$");
+    }
+
+    #[test]
+    fn numeric_fields_are_left_alone() {
+        let config = parse_config("[broker]\nmax_peers = 7", env).unwrap();
+        assert_eq!(config.broker.max_peers, 7);
+        // A reference cannot stand in for a number.
+        assert!(parse_config("[broker]\nmax_peers = \"${PORT}\"", env).is_err());
+    }
+}
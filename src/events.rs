@@ -0,0 +1,383 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use jiff::Timestamp;
+use log::{debug, info};
+use serde_json::{json, Map, Value};
+
+use crate::broker::SocketChannel;
+use crate::chunk::{ChunkPath, ExpiredTransfer};
+use crate::config::{EventsConfig, RetryProfile};
+use crate::gc::{GcCategory, PeerGone};
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+use crate::peers::PeerRole;
+use crate::socket::{bind_with_retry, configure_socket};
+
+//
+// ------------------------------ Event stream ---------------------------------
+//
+// Machine-readable lifecycle events on an optional PUB socket, [events]
+// endpoint. Each event is one message:
+//
+//     ["event.<name>", {"version", "event": "<name>", "ts_ms", ..fields}]
+//
+// so subscribers pick what they want by topic prefix ("event.client." or all
+// of "event."). The events and their fields:
+//
+//     client|worker|direct.connected  first message since the peer
+//                                     table last forgot it: identity
+//     client|worker|direct.expired    collected after idling: identity,
+//                                     idle_ms
+//     worker.pool_expired             left the fan-out pool idle: identity,
+//                                     idle_ms
+//     worker.disconnected             deregistered with DISCONNECT: identity
+//     request.timeout                 fan-out answered at its deadline: client
+//     transfer.timeout                chunked transfer abandoned: transfer_id,
+//                                     received, total, path
+//     ha.active, ha.passive           this instance's HA state changed
+//
+// `version` changes when a field is removed or changes meaning; new events
+// and fields do not change it. The loop queues events as they happen and
+// publishes them once per pass. At most [events] queue events wait; further
+// ones are dropped and counted in corky_events_dropped_total, and a
+// subscriber that falls behind loses messages at the socket's high-water
+// mark, so neither can hold up the broker.
+
+pub const EVENT_SCHEMA_VERSION: u64 = 1;
+pub const EVENT_TOPIC_PREFIX: &str = "event.";
+const EVENTS: &str = "events";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerEvent {
+    Connected {
+        role: PeerRole,
+        identity: Vec<u8>,
+    },
+    Expired {
+        role: PeerRole,
+        identity: Vec<u8>,
+        idle: Duration,
+    },
+    PoolExpired {
+        identity: Vec<u8>,
+        idle: Duration,
+    },
+    Disconnected {
+        identity: Vec<u8>,
+    },
+    RequestTimeout {
+        client: Vec<u8>,
+    },
+    TransferTimeout {
+        transfer_id: u64,
+        received: u32,
+        total: u32,
+        path: ChunkPath,
+    },
+    HaState {
+        active: bool,
+    },
+}
+
+impl BrokerEvent {
+    pub fn gone(gone: &PeerGone) -> Self {
+        match gone.category {
+            GcCategory::Peer => BrokerEvent::Expired {
+                role: gone.role,
+                identity: gone.identity.clone(),
+                idle: gone.idle,
+            },
+            GcCategory::Worker => BrokerEvent::PoolExpired {
+                identity: gone.identity.clone(),
+                idle: gone.idle,
+            },
+        }
+    }
+
+    pub fn transfer_timeout(expired: &ExpiredTransfer) -> Self {
+        BrokerEvent::TransferTimeout {
+            transfer_id: expired.transfer_id,
+            received: expired.received,
+            total: expired.total,
+            path: expired.path,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            BrokerEvent::Connected { role, .. } => format!("{}.connected", role.label()),
+            BrokerEvent::Expired { role, .. } => format!("{}.expired", role.label()),
+            BrokerEvent::PoolExpired { .. } => "worker.pool_expired".to_string(),
+            BrokerEvent::Disconnected { .. } => "worker.disconnected".to_string(),
+            BrokerEvent::RequestTimeout { .. } => "request.timeout".to_string(),
+            BrokerEvent::TransferTimeout { .. } => "transfer.timeout".to_string(),
+            BrokerEvent::HaState { active: true } => "ha.active".to_string(),
+            BrokerEvent::HaState { active: false } => "ha.passive".to_string(),
+        }
+    }
+
+    pub fn to_json(&self, ts_ms: u64) -> Value {
+        let mut event = json!({
+            "version": EVENT_SCHEMA_VERSION,
+            "event": self.name(),
+            "ts_ms": ts_ms,
+        });
+        let fields = match self {
+            BrokerEvent::Connected { identity, .. } | BrokerEvent::Disconnected { identity } => {
+                json!({
+                    "identity": String::from_utf8_lossy(identity),
+                })
+            }
+            BrokerEvent::Expired { identity, idle, .. }
+            | BrokerEvent::PoolExpired { identity, idle } => json!({
+                "identity": String::from_utf8_lossy(identity),
+                "idle_ms": idle.as_millis() as u64,
+            }),
+            BrokerEvent::RequestTimeout { client } => json!({
+                "client": String::from_utf8_lossy(client),
+            }),
+            BrokerEvent::TransferTimeout {
+                transfer_id,
+                received,
+                total,
+                path,
+            } => json!({
+                "transfer_id": format!("{:016x}", transfer_id),
+                "received": received,
+                "total": total,
+                "path": path_label(*path),
+            }),
+            BrokerEvent::HaState { .. } => json!({}),
+        };
+        if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), fields) {
+            event.extend(fields);
+        }
+        event
+    }
+
+    pub fn to_message(&self, ts_ms: u64) -> Multipart {
+        Multipart::new(vec![
+            format!("{}{}", EVENT_TOPIC_PREFIX, self.name()).into_bytes(),
+            self.to_json(ts_ms).to_string().into_bytes(),
+        ])
+    }
+}
+
+// One event as `corky-zmq monitor` prints it: the time, the name and the
+// other fields, such as `2026-10-14T11:05:27.481Z client.connected
+// identity=c-1`. The version is shown only when it is not this build's.
+pub fn describe(message: &[Vec<u8>]) -> Result<String, String> {
+    let [topic, body] = message else {
+        return Err(format!(
+            "expected [topic, json], got {} frames",
+            message.len()
+        ));
+    };
+    let topic = String::from_utf8_lossy(topic);
+    let mut fields: Map<String, Value> =
+        serde_json::from_slice(body).map_err(|e| format!("{}: {}", topic, e))?;
+    let name = match fields.remove("event") {
+        Some(Value::String(name)) => name,
+        _ => topic
+            .strip_prefix(EVENT_TOPIC_PREFIX)
+            .unwrap_or(&topic)
+            .to_string(),
+    };
+    let ts = fields
+        .remove("ts_ms")
+        .and_then(|ts| ts.as_i64())
+        .and_then(|ms| Timestamp::from_millisecond(ms).ok())
+        .map_or("-".to_string(), |ts| {
+            ts.strftime("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+        });
+    let mut line = format!("{} {}", ts, name);
+    match fields.remove("version") {
+        Some(version) if version == EVENT_SCHEMA_VERSION => {}
+        Some(version) => line.push_str(&format!(" version={}", version)),
+        None => line.push_str(" version=none"),
+    }
+    for (key, value) in fields {
+        match value {
+            Value::String(text) => line.push_str(&format!(" {}={}", key, text)),
+            other => line.push_str(&format!(" {}={}", key, other)),
+        }
+    }
+    Ok(line)
+}
+
+fn path_label(path: ChunkPath) -> &'static str {
+    match path {
+        ChunkPath::ClientToWorker => "client_to_worker",
+        ChunkPath::WorkerToClient => "worker_to_client",
+        ChunkPath::Direct => "direct",
+    }
+}
+
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub struct EventStream {
+    channel: SocketChannel,
+    queue: VecDeque<Multipart>,
+    capacity: usize,
+    dropped: Counter,
+}
+
+impl EventStream {
+    pub fn bind(
+        context: &zmq::Context,
+        config: &EventsConfig,
+        bind_retry: &RetryProfile,
+        metrics: &Registry,
+    ) -> Result<Self, zmq::Error> {
+        let channel = SocketChannel::new(context.socket(zmq::PUB)?, EVENTS, metrics);
+        configure_socket(&channel.socket)?;
+        channel.socket.set_sndhwm(config.queue as i32)?;
+        bind_with_retry(&channel.socket, &config.endpoint, bind_retry, "Broker")?;
+        info!(
+            "(Broker) {} (PUB) bound to {}",
+            channel.name, config.endpoint
+        );
+        Ok(Self {
+            channel,
+            queue: VecDeque::with_capacity(config.queue),
+            capacity: config.queue,
+            dropped: metrics.counter("corky_events_dropped_total", &[]),
+        })
+    }
+
+    // Stamp and queue one event, or count it dropped when the queue is full.
+    pub fn push(&mut self, event: BrokerEvent) {
+        if self.queue.len() >= self.capacity {
+            self.dropped.inc();
+            return;
+        }
+        self.queue.push_back(event.to_message(unix_ms()));
+    }
+
+    pub fn flush(&mut self) {
+        while let Some(message) = self.queue.pop_front() {
+            if let Err(e) = self.channel.send(message) {
+                self.dropped.inc();
+                debug!("(Broker) Cannot publish event: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_carry_topic_version_and_fields() {
+        let connected = BrokerEvent::Connected {
+            role: PeerRole::Client,
+            identity: b"c-1".to_vec(),
+        };
+        let message = connected.to_message(1_700_000_000_000);
+        assert_eq!(message[0], b"event.client.connected");
+        let json: Value = serde_json::from_slice(&message[1]).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "version": EVENT_SCHEMA_VERSION,
+                "event": "client.connected",
+                "ts_ms": 1_700_000_000_000u64,
+                "identity": "c-1",
+            })
+        );
+
+        let transfer = BrokerEvent::TransferTimeout {
+            transfer_id: 0xab,
+            received: 2,
+            total: 5,
+            path: ChunkPath::WorkerToClient,
+        };
+        let json = transfer.to_json(7);
+        assert_eq!(json["event"], "transfer.timeout");
+        assert_eq!(json["transfer_id"], "00000000000000ab");
+        assert_eq!(
+            (json["received"].as_u64(), json["total"].as_u64()),
+            (Some(2), Some(5))
+        );
+        assert_eq!(json["path"], "worker_to_client");
+    }
+
+    #[test]
+    fn gc_expirations_map_to_their_own_events() {
+        let gone = |category| PeerGone {
+            category,
+            role: PeerRole::Worker,
+            identity: b"w-1".to_vec(),
+            idle: Duration::from_millis(1500),
+        };
+        let table = BrokerEvent::gone(&gone(GcCategory::Peer));
+        assert_eq!(table.name(), "worker.expired");
+        assert_eq!(table.to_json(0)["idle_ms"], 1500);
+        let pool = BrokerEvent::gone(&gone(GcCategory::Worker));
+        assert_eq!(pool.name(), "worker.pool_expired");
+        assert_eq!(BrokerEvent::HaState { active: false }.name(), "ha.passive");
+        assert_eq!(
+            BrokerEvent::HaState { active: true }.to_json(0),
+            json!({"version": EVENT_SCHEMA_VERSION, "event": "ha.active", "ts_ms": 0})
+        );
+    }
+
+    #[test]
+    fn monitor_lines_show_the_time_name_and_fields() {
+        let connected = BrokerEvent::Connected {
+            role: PeerRole::Client,
+            identity: b"c-1".to_vec(),
+        };
+        let message = connected.to_message(1_791_975_927_481).into_frames();
+        assert_eq!(
+            describe(&message).unwrap(),
+            "2026-10-14T11:05:27.481Z client.connected identity=c-1"
+        );
+        let expired = BrokerEvent::PoolExpired {
+            identity: b"w-1".to_vec(),
+            idle: Duration::from_millis(1500),
+        };
+        assert_eq!(
+            describe(&expired.to_message(0).into_frames()).unwrap(),
+            "1970-01-01T00:00:00.000Z worker.pool_expired identity=w-1 idle_ms=1500"
+        );
+
+        let newer = [
+            b"event.ha.active".to_vec(),
+            br#"{"version": 2, "event": "ha.active"}"#.to_vec(),
+        ];
+        assert_eq!(describe(&newer).unwrap(), "- ha.active version=2");
+        assert!(describe(&[b"event.ha.active".to_vec()]).is_err());
+        let garbled = [b"event.ha.active".to_vec(), b"{".to_vec()];
+        assert!(describe(&garbled)
+            .unwrap_err()
+            .starts_with("event.ha.active: "));
+    }
+
+    #[test]
+    fn a_full_queue_drops_and_counts() {
+        let context = zmq::Context::new();
+        let metrics = Registry::new();
+        let config = EventsConfig {
+            endpoint: "inproc://events-queue-test".to_string(),
+            queue: 2,
+        };
+        let mut stream =
+            EventStream::bind(&context, &config, &RetryProfile::default(), &metrics).unwrap();
+        for client in [&b"a"[..], b"b", b"c"] {
+            stream.push(BrokerEvent::RequestTimeout {
+                client: client.to_vec(),
+            });
+        }
+        let dropped = metrics.counter("corky_events_dropped_total", &[]);
+        assert_eq!((stream.queue.len(), dropped.get()), (2, 1));
+        stream.flush();
+        assert!(stream.queue.is_empty());
+    }
+}
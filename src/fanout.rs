@@ -0,0 +1,832 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::HedgeConfig;
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+use crate::timer::deadline_after;
+
+//
+// ----------------------------- Scatter-gather --------------------------------
+//
+// Workers join the fan-out pool by sending a single READY frame on the
+// worker-facing socket (repeating it is harmless). A client asks for a fan-out
+// by putting a JSON header frame before its payload:
+//
+//     [{"fanout": {"count": N | "all", "timeout_ms": T, "mode": "collect" | "first"}}, ...payload]
+//
+// The broker sends each of up to N distinct pool workers
+//
+//     [client_id, tag, ...payload]    tag = "CRKFAN01" | fanout_id: u64 BE
+//
+// and a worker replies as usual with `[client_id, tag, ...reply]`, so a worker
+// that echoes the frames it received needs no changes. Once every worker has
+// replied (or the first reply in "first" mode, or at the deadline) the client
+// gets one message:
+//
+//     [summary, ...reply frames of every worker in order]
+//     summary = {"fanout": {"mode", "requested", "dispatched", "received",
+//                "partial", "replies": [{"worker", "frames"}], "missing": [...]}}
+//
+// "partial" is set when fewer than the requested replies arrived. Replies for
+// a finished fan-out are dropped. A worker that dies mid-fan-out simply never
+// replies and is reported in "missing"; one already gone at dispatch time is
+// removed from the pool and another is picked in its place.
+//
+// A worker shutting down cleanly sends a single DISCONNECT frame instead of
+// going quiet. It leaves the pool at once, and each fan-out still waiting on
+// it is handed to another pool worker, or completes without it when none is
+// left, so its clients do not wait for the deadline.
+//
+// With [hedge] enabled, a "first"-mode fan-out (with count 1, a load-balanced
+// request) still unanswered after the hedge delay is sent to one more pool
+// worker, provided one is idle, i.e. no unanswered fan-out waits on it. The
+// first reply wins as usual and the other is dropped. The extra copy carries
+// a CRKHDG01 tag with the same fan-out id, so a worker can tell it is a
+// duplicate someone else may be working on; replies echo it like any tag.
+// Each "first"-mode fan-out earns max_percent/100 of a hedge, up to a burst of
+// HEDGE_BURST, so hedges never exceed that share of requests; and none are
+// sent while every pool worker is busy, since then a hedge only adds load.
+
+pub const WORKER_READY: &[u8] = b"__corky_ready__";
+pub const WORKER_DISCONNECT: &[u8] = b"__corky_disconnect__";
+pub const FANOUT_TAG_MAGIC: &[u8; 8] = b"CRKFAN01";
+pub const HEDGE_TAG_MAGIC: &[u8; 8] = b"CRKHDG01";
+pub const FANOUT_TAG_LEN: usize = 16;
+pub const HEDGE_BURST: u64 = 10;
+pub const DEFAULT_FANOUT_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FanoutMode {
+    #[default]
+    Collect,
+    First,
+}
+
+impl FanoutMode {
+    fn label(self) -> &'static str {
+        match self {
+            FanoutMode::Collect => "collect",
+            FanoutMode::First => "first",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FanoutCount {
+    Workers(usize),
+    All(AllWorkers),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AllWorkers {
+    All,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FanoutSpec {
+    pub count: FanoutCount,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub mode: FanoutMode,
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_FANOUT_TIMEOUT_MS
+}
+
+#[derive(Deserialize)]
+struct FanoutHeader {
+    fanout: FanoutSpec,
+}
+
+impl FanoutSpec {
+    // None when `frame` is not a fan-out header at all, so ordinary payloads
+    // cost one byte comparison and a substring check.
+    pub fn parse(frame: &[u8]) -> Option<Result<FanoutSpec, String>> {
+        if frame.first() != Some(&b'{') || !contains(frame, b"\"fanout\"") {
+            return None;
+        }
+        Some(match serde_json::from_slice::<FanoutHeader>(frame) {
+            Ok(FanoutHeader { fanout }) if fanout.count == FanoutCount::Workers(0) => {
+                Err("fanout count must be at least 1".to_string())
+            }
+            Ok(header) => Ok(header.fanout),
+            Err(e) => Err(format!("invalid fanout header: {}", e)),
+        })
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+pub fn encode_tag(fanout_id: u64) -> Vec<u8> {
+    tag_with(FANOUT_TAG_MAGIC, fanout_id)
+}
+
+pub fn encode_hedge_tag(fanout_id: u64) -> Vec<u8> {
+    tag_with(HEDGE_TAG_MAGIC, fanout_id)
+}
+
+fn tag_with(magic: &[u8; 8], fanout_id: u64) -> Vec<u8> {
+    let mut tag = Vec::with_capacity(FANOUT_TAG_LEN);
+    tag.extend_from_slice(magic);
+    tag.extend_from_slice(&fanout_id.to_be_bytes());
+    tag
+}
+
+// The fan-out id of a tag frame, hedged copy or not.
+pub fn parse_tag(frame: &[u8]) -> Option<u64> {
+    if frame.len() != FANOUT_TAG_LEN
+        || (&frame[..8] != FANOUT_TAG_MAGIC && &frame[..8] != HEDGE_TAG_MAGIC)
+    {
+        return None;
+    }
+    Some(u64::from_be_bytes(frame[8..].try_into().ok()?))
+}
+
+pub fn is_hedge_tag(frame: &[u8]) -> bool {
+    frame.len() == FANOUT_TAG_LEN && &frame[..8] == HEDGE_TAG_MAGIC
+}
+
+// Reply to a fan-out request that could not be started.
+pub fn error_reply(client: &[u8], reason: &str) -> Multipart {
+    let summary = json!({"fanout": {"error": reason}});
+    Multipart::new(vec![client.to_vec(), summary.to_string().into_bytes()])
+}
+
+// Workers that announced READY, in dispatch order.
+#[derive(Default)]
+pub struct WorkerPool {
+    ready: VecDeque<Vec<u8>>,
+    // Where the next idle sweep resumes.
+    sweep_cursor: usize,
+}
+
+impl WorkerPool {
+    pub fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    pub fn add(&mut self, worker: &[u8]) {
+        if !self.ready.iter().any(|w| w == worker) {
+            self.ready.push_back(worker.to_vec());
+        }
+    }
+
+    pub fn contains(&self, worker: &[u8]) -> bool {
+        self.ready.iter().any(|w| w == worker)
+    }
+
+    pub fn remove(&mut self, worker: &[u8]) {
+        self.ready.retain(|w| w != worker);
+    }
+
+    // Check at most `max_checks` workers, resuming where the previous sweep
+    // stopped, and drop those `idle_for` reports as idle.
+    pub fn expire_idle(
+        &mut self,
+        max_checks: usize,
+        mut idle_for: impl FnMut(&[u8]) -> Option<Duration>,
+    ) -> Vec<(Vec<u8>, Duration)> {
+        let mut expired = Vec::new();
+        for _ in 0..max_checks.min(self.ready.len()) {
+            if self.ready.is_empty() {
+                break;
+            }
+            let idx = self.sweep_cursor % self.ready.len();
+            match idle_for(&self.ready[idx]) {
+                Some(idle) => {
+                    if let Some(worker) = self.ready.remove(idx) {
+                        expired.push((worker, idle));
+                    }
+                    self.sweep_cursor = idx;
+                }
+                None => self.sweep_cursor = idx + 1,
+            }
+        }
+        expired
+    }
+
+    // The next worker in round-robin order, moved to the back of the pool.
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let worker = self.ready.pop_front()?;
+        self.ready.push_back(worker.clone());
+        Some(worker)
+    }
+}
+
+// [worker_id, client_id, tag, ...payload]
+fn request_for(worker: &[u8], client: &[u8], id: u64, payload: &[Vec<u8>]) -> Multipart {
+    tagged_request(worker, client, encode_tag(id), payload)
+}
+
+fn tagged_request(worker: &[u8], client: &[u8], tag: Vec<u8>, payload: &[Vec<u8>]) -> Multipart {
+    let mut frames = Vec::with_capacity(payload.len() + 3);
+    frames.push(worker.to_vec());
+    frames.push(client.to_vec());
+    frames.push(tag);
+    frames.extend(payload.iter().cloned());
+    Multipart::new(frames)
+}
+
+struct Fanout {
+    client: Vec<u8>,
+    mode: FanoutMode,
+    requested: usize,
+    // Kept for handing the request to a replacement worker.
+    payload: Vec<Vec<u8>>,
+    dispatched: Vec<Vec<u8>>,
+    replies: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    deadline: Instant,
+    // When to consider a hedge; None once considered or without hedging.
+    hedge_at: Option<Instant>,
+    // The worker given the hedged copy.
+    hedge: Option<Vec<u8>>,
+}
+
+impl Fanout {
+    fn is_done(&self) -> bool {
+        match self.mode {
+            FanoutMode::First => !self.replies.is_empty() || self.dispatched.is_empty(),
+            FanoutMode::Collect => self.replies.len() >= self.dispatched.len(),
+        }
+    }
+
+    fn is_waiting_on(&self, worker: &[u8]) -> bool {
+        self.dispatched.iter().any(|w| w == worker)
+            && !self.replies.iter().any(|(w, _)| w == worker)
+    }
+
+    fn is_partial(&self) -> bool {
+        let wanted = match self.mode {
+            FanoutMode::First => 1,
+            FanoutMode::Collect => self.requested,
+        };
+        self.dispatched.is_empty() || self.replies.len() < wanted
+    }
+
+    // The aggregated reply for the client.
+    fn finish(self) -> Multipart {
+        let missing: Vec<String> = self
+            .dispatched
+            .iter()
+            .filter(|w| !self.replies.iter().any(|(r, _)| r == *w))
+            .map(|w| String::from_utf8_lossy(w).into_owned())
+            .collect();
+        let replies: Vec<_> = self
+            .replies
+            .iter()
+            .map(|(worker, frames)| {
+                json!({"worker": String::from_utf8_lossy(worker), "frames": frames.len()})
+            })
+            .collect();
+        let summary = json!({"fanout": {
+            "mode": self.mode.label(),
+            "requested": self.requested,
+            "dispatched": self.dispatched.len(),
+            "received": self.replies.len(),
+            "partial": self.is_partial(),
+            "hedged": self.hedge.is_some(),
+            "replies": replies,
+            "missing": if self.mode == FanoutMode::First { Vec::new() } else { missing },
+        }});
+        let mut frames = vec![self.client, summary.to_string().into_bytes()];
+        for (_, reply) in self.replies {
+            frames.extend(reply);
+        }
+        Multipart::new(frames)
+    }
+}
+
+// Hedge settings and the credit earned towards further hedges, in
+// hundredths of a hedge.
+struct Hedging {
+    delay: Duration,
+    max_percent: u64,
+    credit: u64,
+    hedged: Counter,
+    won: Counter,
+    skipped_busy: Counter,
+    skipped_cap: Counter,
+}
+
+// In-flight fan-outs plus the worker pool they draw from.
+pub struct ScatterGather {
+    pub workers: WorkerPool,
+    inflight: HashMap<u64, Fanout>,
+    next_id: u64,
+    started: Counter,
+    partial: Counter,
+    disconnected: Counter,
+    hedging: Option<Hedging>,
+}
+
+impl ScatterGather {
+    pub fn new(metrics: &Registry) -> Self {
+        Self {
+            workers: WorkerPool::default(),
+            inflight: HashMap::new(),
+            next_id: 1,
+            started: metrics.counter("corky_fanout_requests_total", &[]),
+            partial: metrics.counter("corky_fanout_partial_total", &[]),
+            disconnected: metrics.counter("corky_fanout_worker_disconnects_total", &[]),
+            hedging: None,
+        }
+    }
+
+    pub fn with_hedging(mut self, config: &HedgeConfig, metrics: &Registry) -> Self {
+        if !config.enabled {
+            return self;
+        }
+        let skipped =
+            |reason| metrics.counter("corky_fanout_hedges_skipped_total", &[("reason", reason)]);
+        self.hedging = Some(Hedging {
+            delay: Duration::from_millis(config.delay_ms),
+            max_percent: u64::from(config.max_percent.min(100)),
+            credit: 0,
+            hedged: metrics.counter("corky_fanout_hedged_total", &[]),
+            won: metrics.counter("corky_fanout_hedge_wins_total", &[]),
+            skipped_busy: skipped("busy"),
+            skipped_cap: skipped("cap"),
+        });
+        self
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    // Start a fan-out of `payload` for `client`. `dispatch` sends one copy to
+    // a worker; workers it reports as unreachable are dropped from the pool.
+    // Returns the client's reply right away when no worker could be reached.
+    pub fn start(
+        &mut self,
+        client: &[u8],
+        spec: FanoutSpec,
+        payload: &[Vec<u8>],
+        now: Instant,
+        mut dispatch: impl FnMut(Multipart) -> Result<(), zmq::Error>,
+    ) -> Option<Multipart> {
+        self.started.inc();
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let requested = match spec.count {
+            FanoutCount::Workers(n) => n,
+            FanoutCount::All(_) => self.workers.len(),
+        };
+        let mut dispatched: Vec<Vec<u8>> = Vec::new();
+        // Each pool worker is tried at most once.
+        for _ in 0..self.workers.len() {
+            if dispatched.len() >= requested {
+                break;
+            }
+            let Some(worker) = self.workers.next() else {
+                break;
+            };
+            match dispatch(request_for(&worker, client, id, payload)) {
+                Ok(()) => dispatched.push(worker),
+                Err(zmq::Error::EHOSTUNREACH) => self.workers.remove(&worker),
+                Err(_) => {}
+            }
+        }
+        // "all" means all reachable workers.
+        let requested = match spec.count {
+            FanoutCount::Workers(_) => requested,
+            FanoutCount::All(_) => dispatched.len(),
+        };
+        let fanout = Fanout {
+            client: client.to_vec(),
+            mode: spec.mode,
+            requested,
+            payload: payload.to_vec(),
+            dispatched,
+            replies: Vec::new(),
+            deadline: deadline_after(now, Duration::from_millis(spec.timeout_ms)),
+            hedge_at: None,
+            hedge: None,
+        };
+        if fanout.dispatched.is_empty() {
+            return Some(self.finish(fanout));
+        }
+        let mut fanout = fanout;
+        if let Some(hedging) = self
+            .hedging
+            .as_mut()
+            .filter(|_| spec.mode == FanoutMode::First)
+        {
+            hedging.credit = (hedging.credit + hedging.max_percent).min(HEDGE_BURST * 100);
+            fanout.hedge_at = Some(deadline_after(now, hedging.delay));
+        }
+        self.inflight.insert(id, fanout);
+        None
+    }
+
+    // Record `worker`'s reply to fan-out `id`; returns the client's reply
+    // once the fan-out is complete. Late and duplicate replies are dropped.
+    pub fn on_reply(&mut self, id: u64, worker: &[u8], reply: Vec<Vec<u8>>) -> Option<Multipart> {
+        let fanout = self.inflight.get_mut(&id)?;
+        if !fanout.is_waiting_on(worker) {
+            return None;
+        }
+        if let Some(hedging) = &self.hedging {
+            if fanout.replies.is_empty() && fanout.hedge.as_deref() == Some(worker) {
+                hedging.won.inc();
+            }
+        }
+        fanout.replies.push((worker.to_vec(), reply));
+        if !fanout.is_done() {
+            return None;
+        }
+        let fanout = self.inflight.remove(&id)?;
+        Some(self.finish(fanout))
+    }
+
+    // `worker` sent DISCONNECT: drop it from the pool and move its unanswered
+    // fan-outs to pool workers not already serving them. Returns the client
+    // replies of fan-outs that are complete without it, and whether the
+    // worker was known at all.
+    pub fn worker_left(
+        &mut self,
+        worker: &[u8],
+        mut dispatch: impl FnMut(Multipart) -> Result<(), zmq::Error>,
+    ) -> (Vec<Multipart>, bool) {
+        let mut known = self.workers.contains(worker);
+        self.workers.remove(worker);
+        let waiting: Vec<u64> = self
+            .inflight
+            .iter()
+            .filter(|(_, f)| f.is_waiting_on(worker))
+            .map(|(&id, _)| id)
+            .collect();
+        let mut done = Vec::new();
+        for id in waiting {
+            known = true;
+            let Some(fanout) = self.inflight.get_mut(&id) else {
+                continue;
+            };
+            fanout.dispatched.retain(|w| w != worker);
+            for _ in 0..self.workers.len() {
+                let Some(candidate) = self.workers.next() else {
+                    break;
+                };
+                if fanout.dispatched.contains(&candidate) {
+                    continue;
+                }
+                match dispatch(request_for(&candidate, &fanout.client, id, &fanout.payload)) {
+                    Ok(()) => {
+                        fanout.dispatched.push(candidate);
+                        break;
+                    }
+                    Err(zmq::Error::EHOSTUNREACH) => self.workers.remove(&candidate),
+                    Err(_) => {}
+                }
+            }
+            if fanout.is_done() {
+                if let Some(fanout) = self.inflight.remove(&id) {
+                    done.push(self.finish(fanout));
+                }
+            }
+        }
+        if known {
+            self.disconnected.inc();
+        }
+        (done, known)
+    }
+
+    // Send a hedged copy of every due "first"-mode fan-out still without a
+    // reply to an idle pool worker, within the cap. Each fan-out is
+    // considered once. Returns the number of copies sent.
+    pub fn hedge(
+        &mut self,
+        now: Instant,
+        mut dispatch: impl FnMut(Multipart) -> Result<(), zmq::Error>,
+    ) -> usize {
+        let Some(hedging) = self.hedging.as_mut() else {
+            return 0;
+        };
+        let due: Vec<u64> = self
+            .inflight
+            .iter()
+            .filter(|(_, f)| f.hedge_at.is_some_and(|at| at <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        let mut sent = 0;
+        for id in due {
+            if let Some(fanout) = self.inflight.get_mut(&id) {
+                fanout.hedge_at = None;
+                if !fanout.replies.is_empty() {
+                    continue;
+                }
+            }
+            if hedging.credit < 100 {
+                hedging.skipped_cap.inc();
+                continue;
+            }
+            let inflight = &self.inflight;
+            let idle = (self.workers.ready.iter())
+                .find(|w| !inflight.values().any(|f| f.is_waiting_on(w)))
+                .cloned();
+            let Some(worker) = idle else {
+                hedging.skipped_busy.inc();
+                continue;
+            };
+            let Some(fanout) = self.inflight.get_mut(&id) else {
+                continue;
+            };
+            let copy = tagged_request(
+                &worker,
+                &fanout.client,
+                encode_hedge_tag(id),
+                &fanout.payload,
+            );
+            match dispatch(copy) {
+                Ok(()) => {
+                    hedging.credit -= 100;
+                    hedging.hedged.inc();
+                    fanout.dispatched.push(worker.clone());
+                    fanout.hedge = Some(worker);
+                    sent += 1;
+                }
+                Err(zmq::Error::EHOSTUNREACH) => self.workers.remove(&worker),
+                Err(_) => {}
+            }
+        }
+        sent
+    }
+
+    // Finish every fan-out whose deadline has passed with what it has.
+    pub fn expire(&mut self, now: Instant) -> Vec<Multipart> {
+        if self.inflight.is_empty() {
+            return Vec::new();
+        }
+        let due: Vec<u64> = self
+            .inflight
+            .iter()
+            .filter(|(_, f)| f.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut replies = Vec::with_capacity(due.len());
+        for id in due {
+            if let Some(fanout) = self.inflight.remove(&id) {
+                replies.push(self.finish(fanout));
+            }
+        }
+        replies
+    }
+
+    fn finish(&self, fanout: Fanout) -> Multipart {
+        if fanout.is_partial() {
+            self.partial.inc();
+        }
+        fanout.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(reply: &Multipart) -> serde_json::Value {
+        serde_json::from_slice::<serde_json::Value>(&reply[1]).unwrap()["fanout"].clone()
+    }
+
+    fn pool(sg: &mut ScatterGather, workers: &[&str]) {
+        for w in workers {
+            sg.workers.add(w.as_bytes());
+        }
+    }
+
+    #[test]
+    fn parses_fanout_headers() {
+        let spec = FanoutSpec::parse(br#"{"fanout": {"count": 2, "timeout_ms": 50}}"#);
+        assert_eq!(
+            spec,
+            Some(Ok(FanoutSpec {
+                count: FanoutCount::Workers(2),
+                timeout_ms: 50,
+                mode: FanoutMode::Collect,
+            }))
+        );
+        let all = FanoutSpec::parse(br#"{"fanout": {"count": "all", "mode": "first"}}"#);
+        let all = all.unwrap().unwrap();
+        assert_eq!(all.count, FanoutCount::All(AllWorkers::All));
+        assert_eq!(all.timeout_ms, DEFAULT_FANOUT_TIMEOUT_MS);
+        assert_eq!(all.mode, FanoutMode::First);
+        assert!(FanoutSpec::parse(br#"{"fanout": {"count": 0}}"#)
+            .unwrap()
+            .is_err());
+        assert!(FanoutSpec::parse(br#"{"fanout": {"count": "some"}}"#)
+            .unwrap()
+            .is_err());
+        assert_eq!(FanoutSpec::parse(b"plain payload"), None);
+        assert_eq!(FanoutSpec::parse(br#"{"other": 1}"#), None);
+        assert_eq!(parse_tag(&encode_tag(42)), Some(42));
+    }
+
+    #[test]
+    fn collects_replies_from_distinct_workers() {
+        let mut sg = ScatterGather::new(&Registry::new());
+        pool(&mut sg, &["a", "b", "c"]);
+        let spec = FanoutSpec::parse(br#"{"fanout": {"count": 2}}"#)
+            .unwrap()
+            .unwrap();
+        let mut sent = Vec::new();
+        let now = Instant::now();
+        let reply = sg.start(b"cli", spec, &[b"q".to_vec()], now, |m| {
+            sent.push(m);
+            Ok(())
+        });
+        assert!(reply.is_none());
+        assert_eq!(sent.len(), 2);
+        assert_ne!(sent[0][0], sent[1][0]);
+        let id = parse_tag(&sent[0][2]).unwrap();
+        assert_eq!(
+            &sent[0][1..],
+            &[b"cli".to_vec(), encode_tag(id), b"q".to_vec()]
+        );
+
+        assert!(sg.on_reply(id, &sent[0][0], vec![b"r0".to_vec()]).is_none());
+        // Duplicates and workers outside the fan-out are ignored.
+        assert!(sg
+            .on_reply(id, &sent[0][0], vec![b"again".to_vec()])
+            .is_none());
+        assert!(sg.on_reply(id, b"zzz", vec![b"x".to_vec()]).is_none());
+        let done = sg.on_reply(id, &sent[1][0], vec![b"r1".to_vec()]).unwrap();
+        assert_eq!(done[0], b"cli");
+        assert_eq!(&done[2..], &[b"r0".to_vec(), b"r1".to_vec()]);
+        let s = summary(&done);
+        assert_eq!(s["received"], 2);
+        assert_eq!(s["partial"], false);
+        assert_eq!(sg.inflight(), 0);
+        // The next fan-out starts with the worker skipped this time.
+        let mut next = Vec::new();
+        sg.start(b"cli", spec, &[], now, |m| {
+            next.push(m[0].clone());
+            Ok(())
+        });
+        assert_eq!(next[0], b"c");
+    }
+
+    #[test]
+    fn deadline_returns_partial_results() {
+        let mut sg = ScatterGather::new(&Registry::new());
+        pool(&mut sg, &["a", "b"]);
+        let spec = FanoutSpec::parse(br#"{"fanout": {"count": "all", "timeout_ms": 10}}"#);
+        let now = Instant::now();
+        let mut sent = Vec::new();
+        sg.start(b"cli", spec.unwrap().unwrap(), &[], now, |m| {
+            sent.push(m);
+            Ok(())
+        });
+        let id = parse_tag(&sent[0][2]).unwrap();
+        sg.on_reply(id, b"a", vec![b"ok".to_vec()]);
+        assert!(sg.expire(now + Duration::from_millis(5)).is_empty());
+        let done = sg.expire(now + Duration::from_millis(10));
+        let s = summary(&done[0]);
+        assert_eq!(s["partial"], true);
+        assert_eq!(s["missing"], json!(["b"]));
+        // A straggler after the deadline goes nowhere.
+        assert!(sg.on_reply(id, b"b", vec![b"late".to_vec()]).is_none());
+
+        // A timeout too large for an Instant waits "forever" instead.
+        let forever = r#"{"fanout": {"count": 1, "timeout_ms": 18446744073709551615}}"#;
+        let spec = FanoutSpec::parse(forever.as_bytes()).unwrap().unwrap();
+        assert!(sg.start(b"cli", spec, &[], now, |_| Ok(())).is_none());
+        assert!(sg.expire(now + Duration::from_secs(86_400)).is_empty());
+    }
+
+    #[test]
+    fn unreachable_workers_are_replaced_and_dropped() {
+        let mut sg = ScatterGather::new(&Registry::new());
+        pool(&mut sg, &["dead", "busy", "b"]);
+        let spec = FanoutSpec::parse(br#"{"fanout": {"count": 1}}"#)
+            .unwrap()
+            .unwrap();
+        let mut sent = Vec::new();
+        sg.start(b"cli", spec, &[], Instant::now(), |m| {
+            sent.push(m[0].clone());
+            match m[0].as_slice() {
+                b"dead" => Err(zmq::Error::EHOSTUNREACH),
+                b"busy" => Err(zmq::Error::EAGAIN),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(sent, [b"dead".to_vec(), b"busy".to_vec(), b"b".to_vec()]);
+        // Only the unreachable worker leaves the pool.
+        assert_eq!(sg.workers.len(), 2);
+        // An empty pool answers at once with a partial result.
+        let mut sg = ScatterGather::new(&Registry::new());
+        let reply = sg
+            .start(b"cli", spec, &[], Instant::now(), |_| Ok(()))
+            .unwrap();
+        assert_eq!(summary(&reply)["dispatched"], 0);
+        assert_eq!(summary(&reply)["partial"], true);
+    }
+
+    #[test]
+    fn a_disconnecting_worker_hands_its_fan_outs_on() {
+        let metrics = Registry::new();
+        let mut sg = ScatterGather::new(&metrics);
+        pool(&mut sg, &["a", "b", "c"]);
+        let one = FanoutSpec::parse(br#"{"fanout": {"count": 1}}"#)
+            .unwrap()
+            .unwrap();
+        let mut sent = Vec::new();
+        sg.start(b"cli", one, &[b"q".to_vec()], Instant::now(), |m| {
+            sent.push(m);
+            Ok(())
+        });
+        assert_eq!(sent[0][0], b"a");
+        let id = parse_tag(&sent[0][2]).unwrap();
+        // The request moves to the next pool worker, payload and tag intact.
+        let (done, known) = sg.worker_left(b"a", |m| {
+            sent.push(m);
+            Ok(())
+        });
+        assert!(done.is_empty() && known);
+        assert_eq!(sent[1][0], b"b");
+        assert_eq!(&sent[1][1..], &sent[0][1..]);
+        assert!(!sg.workers.contains(b"a"));
+        let reply = sg.on_reply(id, b"b", vec![b"r".to_vec()]).unwrap();
+        assert_eq!(summary(&reply)["partial"], false);
+
+        // With nobody left to take over, the fan-out completes without it.
+        let all = FanoutSpec::parse(br#"{"fanout": {"count": "all"}}"#)
+            .unwrap()
+            .unwrap();
+        let mut sent = Vec::new();
+        sg.start(b"cli", all, &[], Instant::now(), |m| {
+            sent.push(m);
+            Ok(())
+        });
+        let id = parse_tag(&sent[0][2]).unwrap();
+        assert!(sg.on_reply(id, b"b", vec![b"r".to_vec()]).is_none());
+        let (done, _) = sg.worker_left(b"c", |_| panic!("no replacement expected"));
+        assert_eq!(summary(&done[0])["received"], 1);
+        assert_eq!(sg.inflight(), 0);
+
+        // Leaving twice, or without ever joining, changes nothing.
+        assert_eq!(sg.worker_left(b"c", |_| Ok(())), (Vec::new(), false));
+        assert_eq!(sg.worker_left(b"zzz", |_| Ok(())), (Vec::new(), false));
+        let disconnects = metrics.counter("corky_fanout_worker_disconnects_total", &[]);
+        assert_eq!(disconnects.get(), 2);
+    }
+
+    #[test]
+    fn slow_first_mode_fan_outs_are_hedged_once() {
+        let metrics = Registry::new();
+        let config = HedgeConfig {
+            enabled: true,
+            delay_ms: 20,
+            max_percent: 50,
+        };
+        let mut sg = ScatterGather::new(&metrics).with_hedging(&config, &metrics);
+        pool(&mut sg, &["a", "b", "c"]);
+        let first = FanoutSpec::parse(br#"{"fanout": {"count": 1, "mode": "first"}}"#)
+            .unwrap()
+            .unwrap();
+        let now = Instant::now();
+        let mut sent = Vec::new();
+        let mut record = |m: Multipart| {
+            sent.push(m);
+            Ok(())
+        };
+        sg.start(b"cli", first, &[b"q".to_vec()], now, &mut record);
+        // Half a hedge earned so far.
+        assert_eq!(sg.hedge(now + Duration::from_millis(20), &mut record), 0);
+        sg.start(b"cli", first, &[b"q".to_vec()], now, &mut record);
+        assert_eq!(sg.hedge(now + Duration::from_millis(10), &mut record), 0);
+        assert_eq!(sg.hedge(now + Duration::from_millis(20), &mut record), 1);
+        // Considered once only.
+        assert_eq!(sg.hedge(now + Duration::from_millis(40), &mut record), 0);
+
+        // The idle worker gets the copy under a hedge tag with the same id.
+        let (original, copy) = (&sent[1], &sent[2]);
+        assert_eq!(copy[0], b"c");
+        assert!(is_hedge_tag(&copy[2]) && !is_hedge_tag(&original[2]));
+        assert_eq!(parse_tag(&copy[2]), parse_tag(&original[2]));
+        let id = parse_tag(&copy[2]).unwrap();
+        let done = sg.on_reply(id, b"c", vec![b"r".to_vec()]).unwrap();
+        assert_eq!(summary(&done)["hedged"], true);
+        assert!(sg.on_reply(id, b"b", vec![b"late".to_vec()]).is_none());
+        assert_eq!(
+            metrics.counter("corky_fanout_hedge_wins_total", &[]).get(),
+            1
+        );
+        let capped = metrics.counter("corky_fanout_hedges_skipped_total", &[("reason", "cap")]);
+        assert_eq!(capped.get(), 1);
+    }
+}
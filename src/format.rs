@@ -0,0 +1,2249 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::sync::{LazyLock, PoisonError, RwLock};
+
+use flate2::bufread::{GzDecoder, ZlibDecoder};
+use log::LevelFilter;
+
+use serde::Serialize;
+use serde_json::ser::{CharEscape, CompactFormatter, Formatter, PrettyFormatter};
+use serde_json::{self, Number, Value};
+use sha2::{Digest, Sha256};
+
+use crate::cbor;
+use crate::config::{DeepStrategy, FormattingConfig};
+use crate::msgpack;
+
+//
+// ------------------------------- Constants -----------------------------------
+//
+// The defaults of the [formatting] knobs (crate::config::FormattingConfig).
+
+pub const BYTES_PREVIEW_LEN: usize = 20; // byte preview length for non-UTF8 parts
+pub const HEXDUMP_MAX_BYTES: usize = 4096; // bytes of a non-UTF8 part a hex dump shows
+pub const MSGPACK_MAX_BYTES: usize = 1 << 20; // larger parts are not tried as MessagePack
+pub const CBOR_MAX_BYTES: usize = 1 << 20; // larger parts are not tried as CBOR
+pub const DECOMPRESS_MAX_BYTES: usize = 1 << 20; // of a gzip or zlib part, decompressed
+pub const MAX_OUTPUT_BYTES: usize = 16 * 1024; // of a whole rendered message
+pub const MAX_OBJECT_KEYS: usize = 10; // keys to show when trimming top-level objects
+pub const INNER_MAX_OBJECT_KEYS: usize = 20; // keys to show when trimming nested objects
+pub const MAX_STRING_CHARS: usize = 256; // chars of a JSON string value to show
+pub const BASE64_MIN_CHARS: usize = 256; // shorter strings are never taken for base64
+pub const MAX_TEXT_CHARS: usize = 512; // chars of a plain-text part to show
+pub const NDJSON_MAX_LINES: usize = 1000; // longer text is not tried as NDJSON below trace
+
+// Cropping controls (arrays)
+pub const MAX_DEPTH: usize = 2;             // limit recursion for performance
+pub const OUTER_HEAD: usize = 1;            // top-level array head items
+pub const OUTER_TAIL: usize = 1;            // top-level array tail items
+pub const OUTER_MIN_CROP_LEN: usize = 5;    // DO NOT crop arrays smaller than this at depth 0
+
+pub const INNER_MIN_CROP_LEN: usize = 30;   // DO NOT crop inner arrays smaller than this
+pub const ROW_LIST_HEAD: usize = 1;         // arrays-of-arrays (e.g., OHLCV rows) head
+pub const ROW_LIST_TAIL: usize = 1;         // arrays-of-arrays (e.g., OHLCV rows) tail
+pub const SCALAR_LIST_HEAD: usize = 3;      // arrays of scalars/strings head (e.g., colors)
+pub const SCALAR_LIST_TAIL: usize = 1;      // arrays of scalars/strings tail
+
+// Values nested deeper than this are not copied below MAX_DEPTH: serde_json
+// refuses to parse them anyway, and cloning or printing one recurses once per
+// level, so a value built in code could exhaust the stack.
+const MAX_NESTING: usize = 128;
+const TOO_DEEP: &str = "... (nested too deep) ...";
+
+// Logged in place of the values of redacted keys.
+pub const REDACTED: &str = "<redacted>";
+// Keys whose values are never logged, matched in any case.
+pub const REDACT_KEYS: &[&str] = &["password", "api_key", "token"];
+
+// Keys whose epoch times humanize_timestamps annotates; `*` stands for any
+// prefix or suffix.
+pub const TIMESTAMP_KEYS: &[&str] = &["ts", "time", "timestamp", "*_at"];
+
+// Preferred keys to keep when trimming large top-level objects
+pub const IMPORTANT_KEYS: &[&str] = &[
+    "id", "symbol", "ticker", "type", "status", "desc", "timeframe", "title",
+];
+
+//
+// ------------------------------- Settings ------------------------------------
+//
+// The plain functions below use the process-wide settings, set at startup
+// and on each config reload; the `_with` ones take them explicitly.
+
+static SETTINGS: LazyLock<RwLock<FormattingConfig>> =
+    LazyLock::new(|| RwLock::new(FormattingConfig::default()));
+
+pub fn set_formatting(config: &FormattingConfig) {
+    *SETTINGS.write().unwrap_or_else(PoisonError::into_inner) = config.clone();
+}
+
+fn with_settings<T>(f: impl FnOnce(&FormattingConfig) -> T) -> T {
+    f(&SETTINGS.read().unwrap_or_else(PoisonError::into_inner))
+}
+
+//
+// --------------------- Recursive JSON array cropping -------------------------
+//
+
+// Robust "row-like" detection: treat as list-of-arrays if >=80% of sampled
+// elements are arrays. Sample up to 32 elements, evenly spread.
+fn is_mostly_arrays(arr: &[Value]) -> bool {
+    let len = arr.len();
+    if len == 0 {
+        return false;
+    }
+    let sample_n = len.clamp(1, 32);
+    let step = len.div_ceil(sample_n);
+    let mut arrays = 0usize;
+    let mut taken = 0usize;
+    let mut i = 0usize;
+    while i < len && taken < sample_n {
+        if matches!(arr[i], Value::Array(_)) {
+            arrays += 1;
+        }
+        taken += 1;
+        i += step;
+    }
+    arrays * 100 >= taken * 80
+}
+
+// Whether `value` nests more than `limit` levels, found without recursing.
+fn nests_deeper_than(value: &Value, limit: usize) -> bool {
+    let mut stack = vec![(value, 0usize)];
+    while let Some((value, depth)) = stack.pop() {
+        match value {
+            Value::Array(items) => stack.extend(items.iter().map(|v| (v, depth + 1))),
+            Value::Object(map) => stack.extend(map.values().map(|v| (v, depth + 1))),
+            _ => continue,
+        }
+        if depth >= limit {
+            return true;
+        }
+    }
+    false
+}
+
+// How a rendered JSON document is laid out. The caller picks it for the
+// level it logs at: one line per message at debug, where grep works, and the
+// indented form at trace. `pretty = true` in [formatting] indents either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderStyle {
+    Compact,
+    Pretty,
+}
+
+pub fn format_json_pretty(value: &Value) -> String {
+    with_settings(|config| format_json_pretty_with(value, config))
+}
+
+pub fn format_json_pretty_with(value: &Value, config: &FormattingConfig) -> String {
+    format_json_with(value, RenderStyle::Pretty, config)
+}
+
+// With `enabled = false` the whole document, uncropped but still redacted.
+pub fn format_json_with(value: &Value, style: RenderStyle, config: &FormattingConfig) -> String {
+    write_json(&crop_value_with(value, 0, config), style, config)
+}
+
+// A value as it is, already cropped, laid out and escaped for the log.
+fn write_json(cropped: &Value, style: RenderStyle, config: &FormattingConfig) -> String {
+    let mut out = Vec::new();
+    let written = match style == RenderStyle::Pretty || config.pretty {
+        true => {
+            let escaping = Escaping(PrettyFormatter::new());
+            cropped.serialize(&mut serde_json::Serializer::with_formatter(&mut out, escaping))
+        }
+        false => {
+            let escaping = Escaping(CompactFormatter);
+            cropped.serialize(&mut serde_json::Serializer::with_formatter(&mut out, escaping))
+        }
+    };
+    match written {
+        Ok(()) => String::from_utf8(out).unwrap_or_else(|_| cropped.to_string()),
+        Err(_) => cropped.to_string(),
+    }
+}
+
+pub fn crop_value(value: &Value, depth: usize) -> Value {
+    with_settings(|config| crop_value_with(value, depth, config))
+}
+
+// Cropped, strings included, and with the values of redact_keys replaced.
+pub fn crop_value_with(value: &Value, depth: usize, config: &FormattingConfig) -> Value {
+    let mut cropped = crop(value, depth, config);
+    if config.enabled && config.base64_min_chars > 0 {
+        mark_base64(&mut cropped, config.base64_min_chars);
+    }
+    if config.enabled && config.max_string_chars > 0 {
+        crop_strings(&mut cropped, config.max_string_chars);
+    }
+    if config.humanize_timestamps && !config.timestamp_keys.is_empty() {
+        let keys: Vec<String> = config.timestamp_keys.iter().map(|k| k.to_lowercase()).collect();
+        humanize_timestamps(&mut cropped, &keys);
+    }
+    if !config.redact_keys.is_empty() {
+        let keys: Vec<String> = config.redact_keys.iter().map(|k| k.to_lowercase()).collect();
+        redact(&mut cropped, &keys);
+    }
+    cropped
+}
+
+// With numeric_summary, an array of numbers only, in place of its items:
+// "[10000 numbers, min=0.0012, max=98.4, first=1.2, last=3.4]". Integers
+// show as integers and floats as floats; JSON has no NaN to skip. None for
+// any other array.
+fn numeric_summary(items: &[Value]) -> Option<Value> {
+    let numbers: Vec<&Number> = items.iter().map(Value::as_number).collect::<Option<_>>()?;
+    let (first, last) = (numbers.first()?, numbers.last()?);
+    // Integers compared as such, so that large ones keep their order.
+    let order = |a: &&Number, b: &&Number| match (integer(a), integer(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => float(a).total_cmp(&float(b)),
+    };
+    let min = numbers.iter().copied().min_by(order)?;
+    let max = numbers.iter().copied().max_by(order)?;
+    let count = match numbers.len() {
+        1 => "1 number".to_string(),
+        n => format!("{} numbers", n),
+    };
+    Some(Value::String(format!(
+        "[{}, min={}, max={}, first={}, last={}]",
+        count, min, max, first, last
+    )))
+}
+
+fn integer(n: &Number) -> Option<i128> {
+    n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from))
+}
+
+fn float(n: &Number) -> f64 {
+    n.as_f64().unwrap_or(0.0)
+}
+
+// What a value past max_depth is logged as with deep_strategy = "summarize":
+// its size, however large it is.
+fn summarize(value: &Value) -> Value {
+    let plural = |n: usize, one: &str| match n {
+        1 => format!("1 {}", one),
+        n => format!("{} {}s", n, one),
+    };
+    match value {
+        Value::Array(items) => Value::String(format!("[array: {}]", plural(items.len(), "item"))),
+        Value::Object(map) => Value::String(format!("{{object: {}}}", plural(map.len(), "key"))),
+        _ => value.clone(),
+    }
+}
+
+fn crop(value: &Value, depth: usize, config: &FormattingConfig) -> Value {
+    if depth > config.max_depth || !config.enabled {
+        if config.enabled && config.deep_strategy == DeepStrategy::Summarize {
+            if let (true, Value::Array(items)) = (config.numeric_summary, value) {
+                if let Some(summary) = numeric_summary(items) {
+                    return summary;
+                }
+            }
+            return summarize(value);
+        }
+        if nests_deeper_than(value, MAX_NESTING) {
+            return Value::String(TOO_DEEP.to_string());
+        }
+        return value.clone();
+    }
+
+    match value {
+        Value::Array(arr) => {
+            // Decide strategy based on depth and element "shape"
+            let row_like = is_mostly_arrays(arr);
+            let (min_len, head, tail) = if depth == 0 {
+                (config.outer_min_crop_len, config.outer_head, config.outer_tail)
+            } else if row_like {
+                (config.inner_min_crop_len, config.row_list_head, config.row_list_tail)
+            } else {
+                (config.inner_min_crop_len, config.scalar_list_head, config.scalar_list_tail)
+            };
+
+            // If small or near head+tail window, don't crop — but still recurse.
+            if arr.len() < min_len || arr.len() <= head + tail {
+                return Value::Array(
+                    arr.iter().map(|v| crop(v, depth + 1, config)).collect::<Vec<_>>(),
+                );
+            }
+
+            if config.numeric_summary {
+                if let Some(summary) = numeric_summary(arr) {
+                    return summary;
+                }
+            }
+
+            // Crop large lists only.
+            let mut out = Vec::with_capacity(head + 1 + tail);
+            for v in arr.iter().take(head) {
+                out.push(crop(v, depth + 1, config));
+            }
+            let omitted = arr.len() - (head + tail);
+            out.push(Value::String(format!("... ({} more) ...", omitted)));
+            let tail_start = arr.len() - tail;
+            for v in arr.iter().skip(tail_start) {
+                out.push(crop(v, depth + 1, config));
+            }
+            Value::Array(out)
+        }
+        Value::Object(map) => {
+            // Trim objects by key count at any depth; always recurse into values.
+            // Only the top level puts important_keys first, and nested objects
+            // keep their first keys in map order.
+            let (max_keys, important) = match depth {
+                0 => (config.max_object_keys, &config.important_keys[..]),
+                _ => (config.inner_max_object_keys, &[][..]),
+            };
+            if map.len() > max_keys {
+                let mut trimmed = serde_json::Map::with_capacity(max_keys + 1);
+
+                // 1) Insert prioritized keys in order, if present.
+                for k in important {
+                    if let Some(v) = map.get(k) {
+                        if trimmed.len() < max_keys && !trimmed.contains_key(k) {
+                            trimmed.insert(k.clone(), crop(v, depth + 1, config));
+                        }
+                    }
+                }
+                // 2) Fill the remaining budget with other keys in map order.
+                for (k, v) in map.iter() {
+                    if trimmed.len() >= max_keys {
+                        break;
+                    }
+                    if !trimmed.contains_key(k) {
+                        trimmed.insert(k.clone(), crop(v, depth + 1, config));
+                    }
+                }
+                // 3) Ellipsis marker with remaining count.
+                if map.len() > trimmed.len() {
+                    trimmed.insert(
+                        "...".to_string(),
+                        Value::String(format!("{} more keys", map.len() - trimmed.len())),
+                    );
+                }
+
+                Value::Object(trimmed)
+            } else {
+                let mut new_map = serde_json::Map::with_capacity(map.len());
+                for (k, v) in map.iter() {
+                    new_map.insert(k.clone(), crop(v, depth + 1, config));
+                }
+                Value::Object(new_map)
+            }
+        }
+        _ => value.clone(),
+    }
+}
+
+// Cuts, at any depth, strings of more than `max` chars to their first `max`,
+// followed by how many more there were: "AAAA… (+52,113 more chars)". Chars,
+// not bytes, so no UTF-8 sequence is split.
+fn crop_strings(value: &mut Value, max: usize) {
+    match value {
+        Value::String(text) => {
+            if let Some((end, _)) = text.char_indices().nth(max) {
+                let more = text[end..].chars().count();
+                text.truncate(end);
+                let _ = write!(text, "… (+{} more chars)", grouped(more as u64));
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| crop_strings(item, max)),
+        Value::Object(map) => map.values_mut().for_each(|item| crop_strings(item, max)),
+        _ => {}
+    }
+}
+
+// Replaces, at any depth, strings of at least `min` chars that are base64,
+// alone or in a data: URI, with what they hold, as in
+// "<base64, 48,212 chars ≈ 35KB decoded, sha256:ab12cd34…>". The hash, of the
+// decoded bytes, tells the same blob apart across messages.
+fn mark_base64(value: &mut Value, min: usize) {
+    match value {
+        Value::String(text) => {
+            if let Some(marker) = base64_marker(text, min) {
+                *text = marker;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| mark_base64(item, min)),
+        Value::Object(map) => map.values_mut().for_each(|item| mark_base64(item, min)),
+        _ => {}
+    }
+}
+
+fn base64_marker(text: &str, min: usize) -> Option<String> {
+    if text.len() < min {
+        return None;
+    }
+    let uri = text.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,"));
+    let (kind, encoded) = match uri {
+        Some((mime, encoded)) => (format!("base64 {}", mime), encoded),
+        None => ("base64".to_string(), text),
+    };
+    let decoded = decode_base64(encoded)?;
+    let mut hash = String::new();
+    for b in &Sha256::digest(&decoded)[..4] {
+        let _ = write!(hash, "{:02x}", b);
+    }
+    Some(format!(
+        "<{}, {} chars ≈ {} decoded, sha256:{}…>",
+        kind,
+        grouped(encoded.len() as u64),
+        short_size(decoded.len()),
+        hash
+    ))
+}
+
+// The bytes of standard or URL-safe base64, padded to a multiple of 4, when
+// `text` looks like it. Upper and lower case, digits and 32 distinct symbols
+// at least: a long word, a hex digest or an identifier has fewer.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let body = bytes
+        .strip_suffix(b"==")
+        .or_else(|| bytes.strip_suffix(b"="))
+        .unwrap_or(bytes);
+    let url_safe = body.iter().any(|b| matches!(b, b'-' | b'_'));
+    let sextet = |b: u8| match b {
+        b'A'..=b'Z' => Some(b - b'A'),
+        b'a'..=b'z' => Some(b - b'a' + 26),
+        b'0'..=b'9' => Some(b - b'0' + 52),
+        b'+' if !url_safe => Some(62),
+        b'/' if !url_safe => Some(63),
+        b'-' if url_safe => Some(62),
+        b'_' if url_safe => Some(63),
+        _ => None,
+    };
+    let mut seen = [false; 64];
+    let mut out = Vec::with_capacity(body.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for &b in body {
+        let value = sextet(b)?;
+        seen[value as usize] = true;
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    let mixed = [0..26, 26..52, 52..62].into_iter().all(|class| seen[class].contains(&true));
+    let distinct = seen.iter().filter(|&&s| s).count();
+    (mixed && distinct >= 32).then_some(out)
+}
+
+// Annotates, at any depth, the epoch times under keys that match `patterns`
+// (lowercase), as in "1717439022123 (2024-06-03T18:23:42.123Z)": the number
+// stays, with its time in UTC after it. Only whole numbers that fall from
+// 2001 to 2100 as seconds or as milliseconds count, so that prices, counts
+// and negative numbers under those keys are left alone, and so is any number
+// under another key.
+fn humanize_timestamps(value: &mut Value, patterns: &[String]) {
+    match value {
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| humanize_timestamps(item, patterns)),
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let key = key.to_lowercase();
+                match item.as_i64().and_then(epoch_time) {
+                    Some(time) if patterns.iter().any(|p| key_matches(p, &key)) => {
+                        *item = Value::String(format!("{} ({})", item, time));
+                    }
+                    _ => humanize_timestamps(item, patterns),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// "ts" matches itself only, "*_at" any key ending in "_at", "ts_*" any key
+// starting with "ts_".
+fn key_matches(pattern: &str, key: &str) -> bool {
+    match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+        (Some(suffix), _) => key.ends_with(suffix),
+        (_, Some(prefix)) => key.starts_with(prefix),
+        _ => key == pattern,
+    }
+}
+
+fn epoch_time(n: i64) -> Option<String> {
+    // 2001-01-01 and 2100-01-01, in seconds.
+    const EPOCH_RANGE: std::ops::Range<i64> = 978_307_200..4_102_444_800;
+    if EPOCH_RANGE.contains(&n) {
+        let time = jiff::Timestamp::from_second(n).ok()?;
+        Some(time.strftime("%Y-%m-%dT%H:%M:%SZ").to_string())
+    } else if EPOCH_RANGE.contains(&n.div_euclid(1000)) {
+        let time = jiff::Timestamp::from_millisecond(n).ok()?;
+        Some(time.strftime("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+    } else {
+        None
+    }
+}
+
+// Replaces, at any depth, the value of every key in `keys` (lowercase),
+// whatever its case. It runs on the cropped copy, so nothing cropping kept
+// can bring the original back.
+fn redact(value: &mut Value, keys: &[String]) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, keys)),
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                if keys.contains(&key.to_lowercase()) {
+                    *item = Value::String(REDACTED.to_string());
+                } else {
+                    redact(item, keys);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+//
+// -------------------------- Control characters -------------------------------
+//
+// Text from the wire is logged with its control characters escaped, so that
+// a frame cannot move the cursor, retitle the terminal or start a line that
+// looks like a log line of its own: \n, \r and \t as such, and the others,
+// C0, DEL and C1 alike, as \u{1b}. Everything printable, in any script, is
+// logged as it is. This holds for string frames and for the strings inside
+// JSON payloads.
+
+// `text` as it is logged between quotes: quotes and control characters
+// escaped.
+pub fn escape_text(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(|c| c == '"' || c.is_control()) {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+// JSON laid out by `F`, with the strings escaped as escape_text() does.
+struct Escaping<F>(F);
+
+impl<F: Formatter> Formatter for Escaping<F> {
+    // serde_json leaves DEL and C1 to these.
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        writer.write_all(escape_text(fragment).as_bytes())
+    }
+
+    fn write_char_escape<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        escape: CharEscape,
+    ) -> io::Result<()> {
+        match escape {
+            CharEscape::Quote => writer.write_all(b"\\\""),
+            CharEscape::ReverseSolidus => writer.write_all(b"\\\\"),
+            CharEscape::Solidus => writer.write_all(b"\\/"),
+            CharEscape::LineFeed => writer.write_all(b"\\n"),
+            CharEscape::CarriageReturn => writer.write_all(b"\\r"),
+            CharEscape::Tab => writer.write_all(b"\\t"),
+            CharEscape::Backspace => writer.write_all(b"\\u{8}"),
+            CharEscape::FormFeed => writer.write_all(b"\\u{c}"),
+            CharEscape::AsciiControl(byte) => write!(writer, "\\u{{{:x}}}", byte),
+        }
+    }
+
+    fn begin_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_array(writer)
+    }
+
+    fn end_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array(writer)
+    }
+
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.0.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array_value(writer)
+    }
+
+    fn begin_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object(writer)
+    }
+
+    fn end_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object(writer)
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.0.begin_object_key(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object_value(writer)
+    }
+
+    fn end_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object_value(writer)
+    }
+}
+
+//
+// ------------------------ Message formatting ---------------------------------
+//
+
+fn try_parse_json_bytes(part: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(part).ok()
+}
+
+fn try_parse_json_str(s: &str) -> Option<Value> {
+    serde_json::from_str::<Value>(s).ok()
+}
+
+// A text part quoted, its first `max` chars at most (0: all of it), then how
+// much was left out, as in `"GET /quotes"… (+4,812 more chars, 5,377 bytes
+// total)`. What is shown is the text as it starts, escaped but otherwise
+// exact, so a search for a prefix still finds the line.
+fn quote_text(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max).filter(|_| max > 0) {
+        Some((end, _)) => format!(
+            "\"{}\"… (+{} more chars, {} bytes total)",
+            escape_text(&s[..end]),
+            grouped(s[end..].chars().count() as u64),
+            grouped(s.len() as u64)
+        ),
+        None => format!("\"{}\"", escape_text(s)),
+    }
+}
+
+// Text of 2 or more lines, each a JSON object or array, blank lines aside:
+// one line that is anything else and the frame is plain text. Numbers and
+// strings on their own do not count, or any list of figures would. Past
+// ndjson_max_lines the frame is not parsed at all, except at trace.
+fn try_parse_ndjson(s: &str, config: &FormattingConfig) -> Option<Vec<Value>> {
+    if config.ndjson_max_lines == 0 || !s.trim_start().starts_with(['{', '[']) {
+        return None;
+    }
+    let lines: Vec<&str> = s.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let too_many = lines.len() > config.ndjson_max_lines && log::max_level() != LevelFilter::Trace;
+    if lines.len() < 2 || too_many {
+        return None;
+    }
+    lines
+        .into_iter()
+        .map(|line| match try_parse_json_str(line)? {
+            doc @ (Value::Object(_) | Value::Array(_)) => Some(doc),
+            _ => None,
+        })
+        .collect()
+}
+
+// The documents of an NDJSON frame as one array, windowed like a top-level
+// array with outer_head and outer_tail, each document cropped as a payload
+// of its own.
+fn format_ndjson(docs: &[Value], style: RenderStyle, config: &FormattingConfig) -> String {
+    let (head, tail) = (config.outer_head, config.outer_tail);
+    let cropped = |doc| crop_value_with(doc, 0, config);
+    let window = docs.len() >= config.outer_min_crop_len && docs.len() > head + tail;
+    let shown: Vec<Value> = match config.enabled && window {
+        true => {
+            let omitted = docs.len() - head - tail;
+            let marker = Value::String(format!("… {} more lines …", omitted));
+            let mut shown: Vec<Value> = docs[..head].iter().map(cropped).collect();
+            shown.push(marker);
+            shown.extend(docs[docs.len() - tail..].iter().map(cropped));
+            shown
+        }
+        false => docs.iter().map(cropped).collect(),
+    };
+    write_json(&Value::Array(shown), style, config)
+}
+
+// Only a map or an array: a frame that decodes to a bare scalar, such as any
+// single byte up to 0x7f, is as likely to be something else.
+fn try_parse_msgpack(part: &[u8], config: &FormattingConfig) -> Option<Value> {
+    if part.len() < 2 || part.len() > config.msgpack_max_bytes {
+        return None;
+    }
+    match msgpack::decode(part)? {
+        value @ (Value::Array(_) | Value::Object(_)) => Some(value),
+        _ => None,
+    }
+}
+
+fn try_parse_cbor(part: &[u8], config: &FormattingConfig) -> Option<Value> {
+    if part.len() > config.cbor_max_bytes || !cbor::looks_like_cbor(part) {
+        return None;
+    }
+    cbor::decode(part)
+}
+
+// A gzip member (RFC 1952) or zlib stream (RFC 1950), decompressed, unless it
+// would come to more than decompress_max_bytes. Output past the limit stops
+// the decoding, so a small frame cannot expand into gigabytes. A frame that is
+// truncated, malformed, fails its checksum or is followed by more bytes is
+// not taken for compressed.
+fn try_decompress(part: &[u8], config: &FormattingConfig) -> Option<(&'static str, Vec<u8>)> {
+    let limit = config.decompress_max_bytes;
+    if limit == 0 {
+        return None;
+    }
+    let mut rest = part;
+    let (name, data) = match part {
+        [0x1f, 0x8b, ..] => ("gzip", read_at_most(GzDecoder::new(&mut rest), limit)?),
+        // Deflate, a window of at most 32KB, the header check, and no preset
+        // dictionary, which a log cannot know.
+        [cmf, flg, ..]
+            if cmf & 0x0f == 8
+                && cmf >> 4 <= 7
+                && flg & 0x20 == 0
+                && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 =>
+        {
+            ("zlib", read_at_most(ZlibDecoder::new(&mut rest), limit)?)
+        }
+        _ => return None,
+    };
+    rest.is_empty().then_some((name, data))
+}
+
+fn read_at_most(reader: impl Read, limit: usize) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)
+        .ok()?;
+    (data.len() <= limit).then_some(data)
+}
+
+// "312B", "1.2KB", "14KB", "3MB": a size at a glance.
+fn short_size(bytes: usize) -> String {
+    let (value, unit) = match bytes {
+        0..=1023 => return format!("{}B", bytes),
+        1024..=0xfffff => (bytes as f64 / 1024.0, "KB"),
+        _ => (bytes as f64 / (1 << 20) as f64, "MB"),
+    };
+    let value = match value < 10.0 {
+        true => format!("{:.1}", value),
+        false => format!("{:.0}", value),
+    };
+    format!("{}{}", value.trim_end_matches(".0"), unit)
+}
+
+pub fn format_part(part: &[u8], style: RenderStyle) -> String {
+    with_settings(|config| format_part_with(part, style, config))
+}
+
+pub fn format_part_with(part: &[u8], style: RenderStyle, config: &FormattingConfig) -> String {
+    render_part(part, style, config, true)
+}
+
+// What a compressed frame holds is rendered like any frame, as in
+// `gzip(1.2KB→14KB): {...}`, but not decompressed again.
+fn render_part(
+    part: &[u8],
+    style: RenderStyle,
+    config: &FormattingConfig,
+    decompress: bool,
+) -> String {
+    if let Some(v) = try_parse_json_bytes(part) {
+        return format_json_with(&v, style, config);
+    }
+
+    if let Ok(s) = std::str::from_utf8(part) {
+        let t = s.trim();
+        if (t.starts_with('{') && t.ends_with('}')) || (t.starts_with('[') && t.ends_with(']')) {
+            if let Some(v) = try_parse_json_str(t) {
+                return format_json_with(&v, style, config);
+            }
+        }
+        if let Some(docs) = try_parse_ndjson(s, config) {
+            return format!("ndjson: {}", format_ndjson(&docs, style, config));
+        }
+        return quote_text(s, config.max_text_chars);
+    }
+
+    if let Some((name, data)) = decompress.then(|| try_decompress(part, config)).flatten() {
+        return format!(
+            "{}({}→{}): {}",
+            name,
+            short_size(part.len()),
+            short_size(data.len()),
+            render_part(&data, style, config, false)
+        );
+    }
+    if let Some(v) = try_parse_msgpack(part, config) {
+        return format!("msgpack: {}", format_json_with(&v, style, config));
+    }
+    if let Some(v) = try_parse_cbor(part, config) {
+        return format!("cbor: {}", format_json_with(&v, style, config));
+    }
+
+    // Anything logging at trace wants the whole frame.
+    if config.hexdump || log::max_level() == LevelFilter::Trace {
+        return format!("[{} bytes]\n{}", part.len(), hex_dump(part, config.hexdump_max_bytes));
+    }
+    let preview = config.bytes_preview_len;
+    if part.len() > preview {
+        format!("[{} bytes: {:?}...]", part.len(), &part[..preview])
+    } else {
+        format!("{:?}", part)
+    }
+}
+
+// What the first frame of a message is, which only the caller knows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Envelope {
+    // Data, or a topic as on PUB and proxy sockets: rendered like the rest.
+    Topic,
+    // The peer identity a ROUTER socket puts first: rendered as an id tag.
+    Routed,
+    // Pub/sub traffic, as on the XSUB/XPUB path: a [topic][payload] pair is
+    // rendered as `topic "ticker.BTCUSD": {...}`, and a subscription as
+    // `SUBSCRIBE "ticker."`.
+    PubSub,
+}
+
+// Topics longer than this are taken for data.
+const TOPIC_MAX_BYTES: usize = 256;
+
+// The topic of a [topic][payload] pair: short printable text.
+fn pubsub_topic(parts: &[Vec<u8>]) -> Option<&str> {
+    let [topic, _] = parts else {
+        return None;
+    };
+    let topic = std::str::from_utf8(topic).ok()?;
+    let printable = !topic.is_empty() && !topic.chars().any(char::is_control);
+    (printable && topic.len() <= TOPIC_MAX_BYTES).then_some(topic)
+}
+
+// A frame an XSUB socket sends upstream: 0x01 and the topic to subscribe to,
+// or 0x00 and the one to drop. The empty topic is every topic.
+fn format_subscription(frame: &[u8], config: &FormattingConfig) -> Option<String> {
+    let (verb, topic) = match frame.split_first()? {
+        (1, topic) => ("SUBSCRIBE", topic),
+        (0, topic) => ("UNSUBSCRIBE", topic),
+        _ => return None,
+    };
+    let shown = match std::str::from_utf8(topic) {
+        Ok("") => "\"\" (all topics)".to_string(),
+        Ok(text) => quote_text(text, config.max_text_chars),
+        Err(_) => format_part_with(topic, RenderStyle::Compact, config),
+    };
+    Some(format!("{} {}", verb, shown))
+}
+
+// A ROUTER identity as a hex tag. libzmq's own are a zero byte and four
+// more, shown as "id:6b084165"; any other is cut to 8 hex digits with its
+// length noted, as in "id:636c6965…(8 bytes)".
+pub fn format_identity(identity: &[u8]) -> String {
+    let shown = match identity {
+        [0, rest @ ..] if rest.len() == 4 => rest,
+        _ => identity,
+    };
+    let mut out = "id:".to_string();
+    for b in shown.iter().take(4) {
+        let _ = write!(out, "{:02x}", b);
+    }
+    match shown.len() {
+        0 => out.push_str("<empty>"),
+        1..=4 => {}
+        _ => {
+            let _ = write!(out, "…({} bytes)", grouped(identity.len() as u64));
+        }
+    }
+    out
+}
+
+pub fn format_message(parts: &[Vec<u8>], envelope: Envelope, style: RenderStyle) -> String {
+    with_settings(|config| format_message_with(parts, envelope, style, config))
+}
+
+// "16KB", or the bytes when they are no whole number of KB.
+fn size_name(bytes: usize) -> String {
+    match bytes % 1024 {
+        0 => format!("{}KB", bytes / 1024),
+        _ => format!("{} bytes", grouped(bytes as u64)),
+    }
+}
+
+// The frames rendered and joined, up to max_output_bytes across all of them:
+// the frame that reaches it is cut at a char boundary, the rest are not
+// rendered at all, and a trailer says how many were shown. With frame_labels,
+// a message of more than 2 frames has each led by its index and size, as in
+// `[#0(5B): id:6b084165 | #1(0B): <empty> | #2(312B): {...}]`.
+// Envelope::PubSub renders a topic and its payload as `topic "t": {...}`.
+pub fn format_message_with(
+    parts: &[Vec<u8>],
+    envelope: Envelope,
+    style: RenderStyle,
+    config: &FormattingConfig,
+) -> String {
+    if parts.is_empty() {
+        return "[empty message]".to_string();
+    }
+    if let (Envelope::PubSub, [frame]) = (envelope, parts) {
+        if let Some(line) = format_subscription(frame, config) {
+            return line;
+        }
+    }
+    let topic = (envelope == Envelope::PubSub).then(|| pubsub_topic(parts)).flatten();
+    let budget = match config.max_output_bytes {
+        0 => usize::MAX,
+        n => n,
+    };
+    let bracketed = parts.len() > 1 && topic.is_none();
+    let labelled = config.frame_labels && parts.len() > 2;
+    let joiner = if topic.is_some() { ": " } else { " | " };
+    let mut used = if bracketed { 2 } else { 0 };
+    let mut rendered: Vec<String> = Vec::new();
+    let mut truncated = false;
+    for (i, part) in parts.iter().enumerate() {
+        let separator = if rendered.is_empty() { 0 } else { joiner.len() };
+        let mut text = match (i, envelope, topic) {
+            _ if labelled && part.is_empty() => "<empty>".to_string(),
+            (0, _, Some(topic)) => format!("topic \"{}\"", escape_text(topic)),
+            (0, Envelope::Routed, _) => format_identity(part),
+            _ => format_part_with(part, style, config),
+        };
+        if labelled {
+            text.insert_str(0, &format!("#{}({}B): ", i, grouped(part.len() as u64)));
+        }
+        let room = budget.saturating_sub(used + separator);
+        if text.len() > room {
+            let end = (0..=room).rev().find(|&i| text.is_char_boundary(i));
+            text.truncate(end.unwrap_or(0));
+            if !text.is_empty() {
+                rendered.push(text);
+            }
+            truncated = true;
+            break;
+        }
+        used += separator + text.len();
+        rendered.push(text);
+    }
+    let shown = rendered.len();
+    let mut out = match bracketed {
+        true => format!("[{}]", rendered.join(joiner)),
+        false => rendered.join(joiner),
+    };
+    if truncated {
+        let _ = write!(
+            out,
+            "… output truncated at {} ({} of {} frames shown)",
+            size_name(budget),
+            grouped(shown as u64),
+            grouped(parts.len() as u64)
+        );
+    }
+    out
+}
+
+//
+// -------------------------------- Hex dumps ----------------------------------
+//
+
+// `bytes` as `hexdump -C` shows them, 16 to a line with the offset first and
+// the printable ones in the gutter, cut off after `max_bytes`:
+//
+//     00000000  89 50 4e 47 0d 0a 1a 0a  00 00 00 0d 49 48 44 52  |.PNG........IHDR|
+//     ... 4,000 more bytes
+pub fn hex_dump(bytes: &[u8], max_bytes: usize) -> String {
+    let shown = &bytes[..bytes.len().min(max_bytes)];
+    let mut lines: Vec<String> = shown
+        .chunks(16)
+        .enumerate()
+        .map(|(i, row)| {
+            let mut hex = String::new();
+            for column in 0..16 {
+                if column == 8 {
+                    hex.push(' ');
+                }
+                match row.get(column) {
+                    Some(byte) => write!(hex, "{:02x} ", byte).unwrap(),
+                    None => hex.push_str("   "),
+                }
+            }
+            let gutter: String = row
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+            format!("{:08x}  {} |{}|", i * 16, hex, gutter)
+        })
+        .collect();
+    if bytes.len() > shown.len() {
+        let more = (bytes.len() - shown.len()) as u64;
+        lines.push(format!("... {} more bytes", grouped(more)));
+    }
+    lines.join("\n")
+}
+
+//
+// ----------------------------- Size summaries --------------------------------
+//
+// How big a message is, without rendering it: what the broker logs at info
+// level, and cheap enough for anything else that counts frames and bytes.
+
+// 41932 -> "41,932"
+pub fn grouped(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+// "[3 frames, 41,932 bytes]"
+pub fn size_summary(parts: &[Vec<u8>]) -> String {
+    let bytes: usize = parts.iter().map(Vec::len).sum();
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    format!(
+        "[{} frame{}, {} byte{}]",
+        parts.len(),
+        plural(parts.len()),
+        grouped(bytes as u64),
+        plural(bytes)
+    )
+}
+
+// What a frame looks like from its bytes alone, nothing parsed: text that
+// opens and closes like a JSON object or array is Json, without being read
+// as JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Empty,
+    // A ROUTER identity, which only the caller can tell; see routed().
+    Identity,
+    Utf8,
+    Json,
+    Binary,
+}
+
+impl FrameKind {
+    pub fn of(frame: &[u8]) -> Self {
+        let trimmed = frame.trim_ascii();
+        let json = matches!(
+            (trimmed.first(), trimmed.last()),
+            (Some(b'{'), Some(b'}')) | (Some(b'['), Some(b']'))
+        );
+        match std::str::from_utf8(frame) {
+            _ if frame.is_empty() => FrameKind::Empty,
+            Ok(_) if json => FrameKind::Json,
+            Ok(_) => FrameKind::Utf8,
+            Err(_) => FrameKind::Binary,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameKind::Empty => "empty",
+            FrameKind::Identity => "id",
+            FrameKind::Utf8 => "utf8",
+            FrameKind::Json => "json",
+            FrameKind::Binary => "binary",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameSummary {
+    pub bytes: usize,
+    pub kind: FrameKind,
+}
+
+// A message's frames by size and kind, as the broker logs it at info level:
+// "3 frames, 41KB [id, empty, json]".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageSummary {
+    pub frames: Vec<FrameSummary>,
+    pub total_bytes: usize,
+}
+
+pub fn summarize_message(parts: &[Vec<u8>]) -> MessageSummary {
+    let frames = parts
+        .iter()
+        .map(|part| FrameSummary {
+            bytes: part.len(),
+            kind: FrameKind::of(part),
+        })
+        .collect();
+    MessageSummary {
+        frames,
+        total_bytes: parts.iter().map(Vec::len).sum(),
+    }
+}
+
+impl MessageSummary {
+    // As read off a ROUTER socket, the first frame being the peer identity.
+    pub fn routed(mut self) -> Self {
+        if let Some(first) = self.frames.first_mut() {
+            first.kind = FrameKind::Identity;
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for MessageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.frames.len();
+        let plural = if count == 1 { "" } else { "s" };
+        write!(f, "{} frame{}, {}", count, plural, short_size(self.total_bytes))?;
+        if count > 0 {
+            let kinds: Vec<&str> = self.frames.iter().map(|frame| frame.kind.name()).collect();
+            write!(f, " [{}]", kinds.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn no_crop_small_top_level_triplet() {
+        // ["qb-python", "rustcharts", ["ok", {...}]]
+        let v = json!(["qb-python", "rustcharts", ["ok", { "x": 1 }]]);
+        let s = format_json_pretty(&v);
+        assert!(
+            !s.contains("... ("),
+            "should not crop small outer arrays; got: {s}"
+        );
+    }
+
+    #[test]
+    fn crop_large_row_list_first_and_last() {
+        // data: many rows -> expect 1 head, ellipsis, 1 tail
+        let mut rows: Vec<Value> = Vec::new();
+        for i in 0..35 {
+            rows.push(json!([i, i + 1, i + 2, i + 3, i + 4, i + 5]));
+        }
+        let v = json!({ "data": rows });
+        let s = format_json_pretty(&v);
+        assert!(
+            s.matches("... (").count() >= 1,
+            "expected an ellipsis for large data arrays"
+        );
+        // Pretty output spreads each row over several lines, so compare the
+        // rendered structure rather than substrings.
+        let out: Value = serde_json::from_str(&s).unwrap();
+        let data = out["data"].as_array().unwrap();
+        assert_eq!(data.first(), Some(&json!([0, 1, 2, 3, 4, 5])));
+        assert_eq!(data.last(), Some(&json!([34, 35, 36, 37, 38, 39])));
+    }
+
+    #[test]
+    fn crop_large_scalar_list_show_head_tail() {
+        let colors: Vec<Value> = (0..64)
+            .map(|i| Value::String(format!("#{:06X}", i)))
+            .collect();
+        let v = json!({ "candle_colors": colors });
+        let s = format_json_pretty(&v);
+        assert!(
+            s.matches("... (").count() >= 1,
+            "expected an ellipsis for large scalar arrays"
+        );
+    }
+
+    #[test]
+    fn adversarial_nesting_is_cut_off_instead_of_copied() {
+        let mut deep = json!(0);
+        for _ in 0..1000 {
+            deep = Value::Array(vec![deep]);
+        }
+        let clone = FormattingConfig {
+            deep_strategy: DeepStrategy::Clone,
+            ..FormattingConfig::default()
+        };
+        // Three levels survive, then the marker.
+        assert_eq!(crop_value_with(&deep, 0, &clone), json!([[[TOO_DEEP]]]));
+        assert_eq!(crop_value(&deep, 0), json!([[["[array: 1 item]"]]]));
+        assert!(!nests_deeper_than(&json!([[1], {"a": [2]}]), 3));
+        assert!(nests_deeper_than(&json!([[1], {"a": [[2]]}]), 3));
+
+        // Too deep for serde_json to parse at all: shown as a string.
+        let text = include_str!("../tests/fixtures/adversarial/deep_nesting.json");
+        let shown = format_part(text.as_bytes(), RenderStyle::Pretty);
+        assert!(shown.starts_with("\"[[[["), "{}", &shown[..20]);
+    }
+
+    #[test]
+    fn wide_objects_keep_more_keys_when_allowed() {
+        let wide: serde_json::Map<String, Value> =
+            (0..300).map(|i| (format!("k{:03}", i), json!(i))).collect();
+        let wide = Value::Object(wide);
+        let config = FormattingConfig {
+            max_object_keys: 500,
+            ..FormattingConfig::default()
+        };
+        assert_eq!(crop_value_with(&wide, 0, &config), wide);
+
+        let config = FormattingConfig {
+            max_object_keys: 2,
+            important_keys: vec!["k299".to_string()],
+            ..FormattingConfig::default()
+        };
+        let cropped = crop_value_with(&wide, 0, &config);
+        let keys: Vec<&String> = cropped.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["...", "k000", "k299"]);
+        assert_eq!(cropped["..."], "298 more keys");
+    }
+
+    #[test]
+    fn number_lists_can_be_summarised_instead_of_sampled() {
+        let config = FormattingConfig {
+            numeric_summary: true,
+            ..FormattingConfig::default()
+        };
+        let floats: Vec<f64> = (0..10_000).map(|i| 1.2 + (i % 97) as f64 * 0.5).collect();
+        let doc = json!({ "closes": floats, "ids": [3, -7, 12, 5, 0, 9, 1, 4, 2, 8, 6, 11, 10,
+            13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 3] });
+        let cropped = crop_value_with(&doc, 0, &config);
+        assert_eq!(
+            cropped["closes"],
+            "[10000 numbers, min=1.2, max=49.2, first=1.2, last=5.2]"
+        );
+        assert_eq!(
+            cropped["ids"],
+            "[31 numbers, min=-7, max=29, first=3, last=3]"
+        );
+        // Integers beyond f64 keep their order and their digits; mixed lists
+        // compare as floats.
+        let big = json!([(0..40).map(|i| u64::MAX - i).collect::<Vec<_>>()]);
+        assert_eq!(
+            crop_value_with(&big, 0, &config)[0],
+            "[40 numbers, min=18446744073709551576, max=18446744073709551615, \
+             first=18446744073709551615, last=18446744073709551576]"
+        );
+        let mut mixed: Vec<Value> = (0..30).map(|i| json!(i)).collect();
+        mixed[5] = json!(-0.5);
+        assert_eq!(
+            crop_value_with(&json!({ "m": mixed }), 0, &config)["m"],
+            "[30 numbers, min=-0.5, max=29, first=0, last=29]"
+        );
+
+        // Short lists and lists with anything else in them are cropped as before.
+        let short = json!({ "xs": [1.5, 2.5, 3.5] });
+        assert_eq!(crop_value_with(&short, 0, &config), short);
+        let mut with_null: Vec<Value> = (0..40).map(|i| json!(i)).collect();
+        with_null[7] = Value::Null;
+        let sampled = crop_value_with(&json!({ "xs": with_null }), 0, &config);
+        assert_eq!(sampled["xs"][3], "... (36 more) ...");
+        // Top-level arrays follow their own threshold.
+        assert_eq!(
+            crop_value_with(&json!([1, 2, 3, 4, 5]), 0, &config),
+            "[5 numbers, min=1, max=5, first=1, last=5]"
+        );
+
+        // Past max_depth a summary stands in whatever the length, unless
+        // values there are cloned.
+        let deep = json!({ "a": { "b": { "c": [2.5, 1] } } });
+        assert_eq!(
+            crop_value_with(&deep, 0, &config)["a"]["b"]["c"],
+            "[2 numbers, min=1, max=2.5, first=2.5, last=1]"
+        );
+        let clone = FormattingConfig {
+            deep_strategy: DeepStrategy::Clone,
+            ..config
+        };
+        assert_eq!(crop_value_with(&deep, 0, &clone), deep);
+
+        // Off by default.
+        let sampled = crop_value_with(&doc, 0, &FormattingConfig::default());
+        assert_eq!(sampled["closes"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn nested_objects_are_trimmed_to_their_first_keys() {
+        let wide: serde_json::Map<String, Value> =
+            (0..500).map(|i| (format!("k{:03}", i), json!(i))).collect();
+        let wide = Value::Object(wide);
+        let first_keys =
+            |v: &Value| -> Vec<String> { v.as_object().unwrap().keys().cloned().collect() };
+        let kept: Vec<String> = std::iter::once("...".to_string())
+            .chain((0..INNER_MAX_OBJECT_KEYS).map(|i| format!("k{:03}", i)))
+            .collect();
+        let config = FormattingConfig {
+            max_depth: 4,
+            ..FormattingConfig::default()
+        };
+
+        // Two and three levels deep, and inside an array of rows.
+        let doc = json!({
+            "result": wide,
+            "a": { "b": { "c": wide } },
+            "rows": [wide, wide],
+        });
+        let cropped = crop_value_with(&doc, 0, &config);
+        for nested in [&cropped["result"], &cropped["a"]["b"]["c"], &cropped["rows"][1]] {
+            assert_eq!(first_keys(nested), kept);
+            assert_eq!(nested["..."], "480 more keys");
+            assert_eq!(nested["k019"], 19);
+        }
+        // important_keys lead only at the top level.
+        let led = FormattingConfig {
+            important_keys: vec!["k499".to_string()],
+            ..config.clone()
+        };
+        assert_eq!(first_keys(&crop_value_with(&doc, 0, &led)["result"]), kept);
+        let top = crop_value_with(&wide, 0, &led);
+        assert_eq!(top["k499"], 499);
+        assert_eq!(top["..."], "490 more keys");
+
+        // Small objects, and a nested limit set apart from the top one.
+        let small = json!({ "result": { "x": 1, "y": 2 } });
+        assert_eq!(crop_value_with(&small, 0, &config), small);
+        let tight = FormattingConfig {
+            inner_max_object_keys: 1,
+            max_object_keys: 50,
+            ..config
+        };
+        assert_eq!(
+            crop_value_with(&small, 0, &tight),
+            json!({ "result": { "...": "1 more keys", "x": 1 } })
+        );
+    }
+
+    #[test]
+    fn list_windows_and_byte_previews_follow_the_config() {
+        let config = FormattingConfig {
+            inner_min_crop_len: 8,
+            scalar_list_head: 2,
+            scalar_list_tail: 2,
+            bytes_preview_len: 4,
+            ..FormattingConfig::default()
+        };
+        let v = json!({ "levels": (0..10).collect::<Vec<u32>>() });
+        assert_eq!(
+            crop_value_with(&v, 0, &config),
+            json!({ "levels": [0, 1, "... (6 more) ...", 8, 9] })
+        );
+        // The default window leaves a list this short alone.
+        assert_eq!(crop_value(&v, 0), v);
+        assert_eq!(
+            format_part_with(&[0xff; 10], RenderStyle::Pretty, &config),
+            "[10 bytes: [255, 255, 255, 255]...]"
+        );
+    }
+
+    #[test]
+    fn disabled_formatting_prints_the_whole_document() {
+        let config = FormattingConfig {
+            enabled: false,
+            ..FormattingConfig::default()
+        };
+        let rows: Vec<Value> = (0..100).map(|i| json!([[[i]]])).collect();
+        let v = json!({ "rows": rows });
+        let shown: Value =
+            serde_json::from_str(&format_json_pretty_with(&v, &config)).unwrap();
+        assert_eq!(shown, v);
+    }
+
+    #[test]
+    fn sizes_are_summarised_with_grouped_digits() {
+        assert_eq!(grouped(7), "7");
+        assert_eq!(grouped(999), "999");
+        assert_eq!(grouped(1000), "1,000");
+        assert_eq!(grouped(1_234_567), "1,234,567");
+
+        let message = vec![b"client-1".to_vec(), vec![], vec![b'x'; 41_924]];
+        assert_eq!(size_summary(&message), "[3 frames, 41,932 bytes]");
+        assert_eq!(size_summary(&[b"x".to_vec()]), "[1 frame, 1 byte]");
+        assert_eq!(size_summary(&[]), "[0 frames, 0 bytes]");
+    }
+
+    #[test]
+    fn messages_are_summarised_by_frame_size_and_kind() {
+        let message = vec![b"\0k\x08Ae".to_vec(), vec![], vec![b'['; 41_924]];
+        let summary = summarize_message(&message);
+        assert_eq!(summary.total_bytes, 41_929);
+        let sizes: Vec<usize> = summary.frames.iter().map(|frame| frame.bytes).collect();
+        assert_eq!(sizes, [5, 0, 41_924]);
+        assert_eq!(summary.to_string(), "3 frames, 41KB [utf8, empty, utf8]");
+
+        let message = vec![b"\0k\x08Ae".to_vec(), vec![], b" {\"id\": 7}\n".to_vec()];
+        let summary = summarize_message(&message).routed();
+        assert_eq!(summary.to_string(), "3 frames, 16B [id, empty, json]");
+        assert_eq!(summarize_message(&[b"ok".to_vec()]).to_string(), "1 frame, 2B [utf8]");
+        assert_eq!(summarize_message(&[]).routed().to_string(), "0 frames, 0B");
+
+        let kinds = [
+            (&b""[..], FrameKind::Empty),
+            (b"[1, 2]", FrameKind::Json),
+            (b"{not json}", FrameKind::Json),
+            (b"{", FrameKind::Utf8),
+            (b"caf\xc3\xa9", FrameKind::Utf8),
+            (b"{\xff}", FrameKind::Binary),
+            (b"\x93\x01\x02\x03", FrameKind::Binary),
+        ];
+        for (frame, kind) in kinds {
+            assert_eq!(FrameKind::of(frame), kind, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn redacted_keys_stay_hidden_at_any_depth_and_through_cropping() {
+        let config = FormattingConfig::default();
+        let v = json!({
+            "user": "alice",
+            "Password": "hunter2",
+            "auth": { "API_KEY": "k-123", "scopes": ["read"] },
+            "deep": { "a": { "b": { "c": { "token": "t-1", "ok": 1 } } } },
+            "sessions": [{ "id": 1, "token": { "value": "s-1" } }],
+        });
+        let cropped = crop_value_with(&v, 0, &config);
+        assert_eq!(cropped["user"], "alice");
+        assert_eq!(cropped["Password"], REDACTED);
+        assert_eq!(cropped["auth"]["API_KEY"], REDACTED);
+        assert_eq!(cropped["auth"]["scopes"], json!(["read"]));
+        // Past max_depth values are summarised, or copied whole and redacted
+        // all the same.
+        assert_eq!(cropped["deep"]["a"]["b"], "{object: 1 key}");
+        let clone = FormattingConfig {
+            deep_strategy: DeepStrategy::Clone,
+            ..FormattingConfig::default()
+        };
+        let cloned = crop_value_with(&v, 0, &clone);
+        assert_eq!(cloned["deep"]["a"]["b"]["c"]["token"], REDACTED);
+        assert_eq!(cropped["sessions"][0]["token"], REDACTED);
+
+        // A long list keeps its first and last rows: the last one's secret
+        // must not come back with it.
+        let rows: Vec<Value> = (0..40)
+            .map(|i| json!({ "row": i, "password": format!("secret-{}", i) }))
+            .collect();
+        let text = format_json_pretty_with(&json!({ "rows": rows }), &config);
+        assert!(!text.contains("secret-"), "{}", text);
+        assert!(text.contains("\"row\": 39") && text.contains("36 more"), "{}", text);
+
+        // Wide top-level objects are trimmed with important keys first.
+        let mut wide = serde_json::Map::new();
+        wide.insert("id".to_string(), json!(7));
+        wide.insert("token".to_string(), json!("t-2"));
+        for i in 0..20 {
+            wide.insert(format!("k{:02}", i), json!(i));
+        }
+        let important = FormattingConfig {
+            important_keys: vec!["token".to_string()],
+            ..FormattingConfig::default()
+        };
+        let trimmed = crop_value_with(&Value::Object(wide), 0, &important);
+        assert_eq!(trimmed["token"], REDACTED);
+
+        let whole = FormattingConfig {
+            enabled: false,
+            ..FormattingConfig::default()
+        };
+        assert_eq!(crop_value_with(&v, 0, &whole)["Password"], REDACTED);
+        let none = FormattingConfig {
+            redact_keys: Vec::new(),
+            ..FormattingConfig::default()
+        };
+        assert_eq!(crop_value_with(&v, 0, &none)["Password"], "hunter2");
+    }
+
+    #[test]
+    fn long_strings_are_cut_by_chars_at_any_depth() {
+        let config = FormattingConfig {
+            max_string_chars: 4,
+            ..FormattingConfig::default()
+        };
+        let crop = |v: Value| crop_value_with(&v, 0, &config);
+        // Up to the limit, strings are left alone.
+        assert_eq!(crop(json!("abcd")), json!("abcd"));
+        assert_eq!(crop(json!("")), json!(""));
+        assert_eq!(crop(json!("abcde")), json!("abcd… (+1 more chars)"));
+        // Multi-byte chars count once and are never split.
+        assert_eq!(crop(json!("äöüß")), json!("äöüß"));
+        assert_eq!(crop(json!("日本語のテキスト")), json!("日本語の… (+4 more chars)"));
+        assert_eq!(crop(json!("ab😀😀cd")), json!("ab😀😀… (+2 more chars)"));
+        // Past max_depth too, whether values there are summarised or cloned.
+        let deep = json!({ "a": { "b": { "blob": "QUJDREVGRw==" } } });
+        assert_eq!(crop(deep.clone())["a"]["b"]["blob"], "QUJD… (+8 more chars)");
+        let clone = FormattingConfig {
+            deep_strategy: DeepStrategy::Clone,
+            ..config.clone()
+        };
+        let deeper = json!({ "a": { "b": { "c": { "blob": "QUJDREVGRw==" } } } });
+        let cloned = crop_value_with(&deeper, 0, &clone);
+        assert_eq!(cloned["a"]["b"]["c"]["blob"], "QUJD… (+8 more chars)");
+        assert_eq!(crop(json!([1, true, null])), json!([1, true, null]));
+
+        let blob = "A".repeat(52_113 + MAX_STRING_CHARS);
+        let defaults = FormattingConfig::default();
+        let cropped = crop_value_with(&json!({ "image": blob }), 0, &defaults);
+        let image = cropped["image"].as_str().unwrap();
+        assert!(image.ends_with("A… (+52,113 more chars)"), "{}", image);
+        assert_eq!(image.chars().count(), MAX_STRING_CHARS + 22);
+
+        let whole = FormattingConfig {
+            enabled: false,
+            ..config.clone()
+        };
+        assert_eq!(crop_value_with(&json!("abcde"), 0, &whole), json!("abcde"));
+        let uncut = FormattingConfig {
+            max_string_chars: 0,
+            ..config
+        };
+        assert_eq!(crop_value_with(&json!("abcde"), 0, &uncut), json!("abcde"));
+    }
+
+    #[test]
+    fn control_characters_are_escaped_in_text_and_json() {
+        // A terminal title escape, a colour, embedded NULs, a forged log line.
+        let part = |bytes: &[u8]| format_part(bytes, RenderStyle::Pretty);
+        assert_eq!(
+            part(b"\x1b]0;pwned\x07hi"),
+            "\"\\u{1b}]0;pwned\\u{7}hi\""
+        );
+        assert_eq!(part(b"\x1b[31mred\x1b[0m"), "\"\\u{1b}[31mred\\u{1b}[0m\"");
+        assert_eq!(part(b"a\0b\0"), "\"a\\u{0}b\\u{0}\"");
+        assert_eq!(
+            part(b"ok\r\n[ERROR] forged\tline\x7f"),
+            "\"ok\\r\\n[ERROR] forged\\tline\\u{7f}\""
+        );
+        // C1 controls too; printable Unicode, quotes aside, is left alone.
+        assert_eq!(part("a\u{9b}b".as_bytes()), "\"a\\u{9b}b\"");
+        assert_eq!(part("日本語 \"é\" 😀 \\".as_bytes()), "\"日本語 \\\"é\\\" 😀 \\\"");
+        assert!(matches!(escape_text("plain ü"), Cow::Borrowed(_)));
+
+        let doc = json!({ "msg": "\u{1b}[2Jgone\u{0}\u{8}\u{c}\u{85}", "k\n": "\"q\" \\ /" });
+        let pretty = format_json_pretty(&doc);
+        assert!(pretty.contains("\"\\u{1b}[2Jgone\\u{0}\\u{8}\\u{c}\\u{85}\""), "{}", pretty);
+        assert!(pretty.contains("\"k\\n\": \"\\\"q\\\" \\\\ /\""), "{}", pretty);
+        assert!(!pretty.chars().any(|c| c.is_control() && c != '\n'), "{}", pretty);
+        // The layout is still pretty_json's.
+        let plain = json!({ "a": [1, { "b": "c" }] });
+        assert_eq!(
+            format_json_pretty(&plain),
+            serde_json::to_string_pretty(&plain).unwrap()
+        );
+    }
+
+    fn base64(bytes: &[u8], alphabet: &[u8; 64]) -> String {
+        let mut out = String::new();
+        for chunk in bytes.chunks(3) {
+            let block = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |v, (i, &b)| v | u32::from(b) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                out.push(alphabet[(block >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        while !out.len().is_multiple_of(4) {
+            out.push('=');
+        }
+        out
+    }
+
+    #[test]
+    fn base64_blobs_are_shown_as_their_size_and_hash() {
+        const STANDARD: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        const URL_SAFE: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let data: Vec<u8> = (0..3000u32).map(|i| ((i * 31 + 7) % 251) as u8).collect();
+        let config = FormattingConfig::default();
+        let crop = |v: Value| crop_value_with(&v, 0, &config);
+
+        let image = base64(&data, STANDARD);
+        assert!(image.ends_with("yukNLEtq"));
+        let marker = "<base64, 4,000 chars ≈ 2.9KB decoded, sha256:fc70246f…>";
+        assert_eq!(crop(json!({ "image": image })), json!({ "image": marker }));
+        // Padded, URL-safe, in a data: URI, and in arrays below the top.
+        let padded = base64(&data[..2999], STANDARD);
+        assert!(padded.ends_with("LEs="));
+        assert_eq!(
+            crop(json!([padded])),
+            json!(["<base64, 4,000 chars ≈ 2.9KB decoded, sha256:720cd633…>"])
+        );
+        let url = base64(&data, URL_SAFE);
+        assert_eq!(crop(json!({ "a": { "b": [url] } }))["a"]["b"][0], marker);
+        let uri = format!("data:image/png;base64,{}", image);
+        assert_eq!(
+            crop(json!({ "src": uri }))["src"],
+            "<base64 image/png, 4,000 chars ≈ 2.9KB decoded, sha256:fc70246f…>"
+        );
+
+        // Near misses stay text, cut like any long string.
+        let near_misses = [
+            // Prose, a long word, a hex digest and an identifier.
+            "the quick brown fox jumps over the lazy dog ".repeat(8),
+            "a".repeat(400),
+            "0123456789abcdef".repeat(20),
+            "AbCdEf0123".repeat(30),
+            // Both alphabets, a length off by one, padding in the middle.
+            format!("{}-/", &image[..398]),
+            image[..399].to_string(),
+            format!("{}=={}", &image[..200], &image[202..400]),
+            // Too short.
+            image[..252].to_string(),
+        ];
+        for text in near_misses {
+            let cropped = crop(json!({ "s": text }));
+            let shown = cropped["s"].as_str().unwrap();
+            assert!(!shown.starts_with("<base64"), "{}", shown);
+        }
+        // Off.
+        let off = FormattingConfig {
+            base64_min_chars: 0,
+            ..config.clone()
+        };
+        let cut = crop_value_with(&json!({ "image": image }), 0, &off);
+        assert!(cut["image"].as_str().unwrap().ends_with("… (+3,744 more chars)"));
+    }
+
+    #[test]
+    fn epoch_times_under_timestamp_keys_get_their_utc_time() {
+        let config = FormattingConfig {
+            humanize_timestamps: true,
+            ..FormattingConfig::default()
+        };
+        let doc = json!({
+            "ts": 1717439022123i64,
+            "time": 1717439022,
+            "created_at": 978307200000i64,
+            "Timestamp": 4102444799999i64,
+            "fills": [{ "ts": 1717439022123i64, "price": 1717439022123i64 }],
+            "meta": { "updated_at": 1717439022 },
+        });
+        let cropped = crop_value_with(&doc, 0, &config);
+        assert_eq!(cropped["ts"], "1717439022123 (2024-06-03T18:23:42.123Z)");
+        assert_eq!(cropped["time"], "1717439022 (2024-06-03T18:23:42Z)");
+        assert_eq!(cropped["created_at"], "978307200000 (2001-01-01T00:00:00.000Z)");
+        assert_eq!(cropped["Timestamp"], "4102444799999 (2099-12-31T23:59:59.999Z)");
+        assert_eq!(cropped["fills"][0]["ts"], "1717439022123 (2024-06-03T18:23:42.123Z)");
+        assert_eq!(cropped["meta"]["updated_at"], "1717439022 (2024-06-03T18:23:42Z)");
+        // Never a number under another key, such as a price.
+        assert_eq!(cropped["fills"][0]["price"], 1717439022123i64);
+
+        // Outside 2001-2100 in both units, negative, fractional or not a
+        // number: left as it is.
+        for value in [
+            json!(0),
+            json!(978307199),
+            json!(4102444800i64),
+            json!(978307199999i64),
+            json!(4102444800000i64),
+            json!(-1717439022123i64),
+            json!(1717439022.5),
+            json!("1717439022123"),
+            json!(u64::MAX),
+        ] {
+            let v = json!({ "ts": value });
+            assert_eq!(crop_value_with(&v, 0, &config), v);
+        }
+
+        // The keys, and whether it happens at all, are the config's.
+        let keys = FormattingConfig {
+            timestamp_keys: vec!["ts_*".to_string(), "when".to_string()],
+            ..config
+        };
+        let v = json!({ "ts_open": 1717439022, "when": 1717439022, "ts": 1717439022 });
+        let cropped = crop_value_with(&v, 0, &keys);
+        assert_eq!(cropped["ts_open"], "1717439022 (2024-06-03T18:23:42Z)");
+        assert_eq!(cropped["when"], "1717439022 (2024-06-03T18:23:42Z)");
+        assert_eq!(cropped["ts"], 1717439022);
+        assert_eq!(crop_value_with(&doc, 0, &FormattingConfig::default()), doc);
+    }
+
+    #[test]
+    fn values_past_max_depth_are_summarised_whatever_their_size() {
+        let wide: serde_json::Map<String, Value> =
+            (0..37).map(|i| (format!("k{}", i), json!(i))).collect();
+        let v = json!({
+            "a": { "b": {
+                "rows": (0..100_000).collect::<Vec<u32>>(),
+                "meta": wide,
+                "one": [[1]],
+                "n": 7,
+                "ok": true,
+                "none": null,
+                "label": "x".repeat(300),
+            } },
+        });
+        let cropped = crop_value(&v, 0);
+        let b = &cropped["a"]["b"];
+        assert_eq!(b["rows"], "[array: 100000 items]");
+        assert_eq!(b["meta"], "{object: 37 keys}");
+        assert_eq!(b["one"], "[array: 1 item]");
+        assert_eq!((&b["n"], &b["ok"], &b["none"]), (&json!(7), &json!(true), &Value::Null));
+        assert!(b["label"].as_str().unwrap().ends_with("… (+44 more chars)"));
+
+        // However much there is several levels down, the output stays small.
+        let mut deep = json!((0..100_000).map(|i| json!({ "i": i })).collect::<Vec<_>>());
+        for _ in 0..6 {
+            deep = json!({ "next": deep, "pad": [vec![0; 64]] });
+        }
+        let text = format_json_pretty(&deep);
+        assert!(text.len() < 300, "{}", text);
+
+        let clone = FormattingConfig {
+            deep_strategy: DeepStrategy::Clone,
+            ..FormattingConfig::default()
+        };
+        let cloned = crop_value_with(&v, 0, &clone);
+        assert_eq!(cloned["a"]["b"]["meta"].as_object().unwrap().len(), 37);
+        assert_eq!(cloned["a"]["b"]["rows"].as_array().unwrap().len(), 100_000);
+    }
+
+    #[test]
+    fn messagepack_frames_are_shown_as_json() {
+        let config = FormattingConfig::default();
+        let show = |part: &[u8]| format_part_with(part, RenderStyle::Pretty, &config);
+
+        let order = show(include_bytes!("../tests/fixtures/msgpack/order.msgpack"));
+        let json = order.strip_prefix("msgpack: ").unwrap();
+        let order: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(order["id"], 4711);
+        assert_eq!(order["symbol"], "BTC-USD");
+        assert_eq!(order["price"], 64250.5);
+        assert_eq!(order["venue"]["fees"]["maker"], -0.0001);
+        assert_eq!(order["fills"][1]["qty"], 0.15);
+        assert_eq!(order["note"], Value::Null);
+
+        // Binary fields as their size and hex, cut like other long strings.
+        let snapshot = show(include_bytes!("../tests/fixtures/msgpack/snapshot_bin.msgpack"));
+        let snapshot: Value = serde_json::from_str(&snapshot["msgpack: ".len()..]).unwrap();
+        assert_eq!(snapshot["checksum"], "<4 bytes> deadbeef");
+        let png = snapshot["frame"]["png"].as_str().unwrap();
+        assert!(png.starts_with("<308 bytes> 89504e470d0a1a0a"), "{}", png);
+        assert!(png.ends_with("… (+372 more chars)"), "{}", png);
+
+        // Cropped as JSON payloads are.
+        let rows = show(include_bytes!("../tests/fixtures/msgpack/ohlcv_rows.msgpack"));
+        assert!(rows.contains("\"timeframe\": \"1m\""), "{}", rows);
+        assert!(rows.contains("... (38 more) ..."), "{}", rows);
+    }
+
+    #[test]
+    fn frames_that_only_might_be_messagepack_stay_bytes() {
+        let config = FormattingConfig::default();
+        let show = |part: &[u8]| format_part_with(part, RenderStyle::Pretty, &config);
+        // Bare scalars: -1, a uint8, a float64.
+        assert_eq!(show(b"\xff"), "[255]");
+        assert_eq!(show(b"\xcc\x05"), "[204, 5]");
+        assert_eq!(
+            show(b"\xcb\x40\x09\x21\xfb\x54\x44\x2d\x18"),
+            "[203, 64, 9, 33, 251, 84, 68, 45, 24]"
+        );
+        // Not MessagePack at all, or cut short.
+        let noise: Vec<u8> = (0..64u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        assert!(show(&noise).starts_with("[64 bytes: ["), "{}", show(&noise));
+        assert_eq!(show(b"\x92\x01"), "[146, 1]");
+
+        let order = include_bytes!("../tests/fixtures/msgpack/order.msgpack");
+        assert!(show(order).starts_with("msgpack: "));
+        let small = FormattingConfig {
+            msgpack_max_bytes: order.len() - 1,
+            ..FormattingConfig::default()
+        };
+        let shown = format_part_with(order, RenderStyle::Pretty, &small);
+        assert!(shown.starts_with("[222 bytes: [138, 162,"));
+    }
+
+    fn cbor(value: &impl serde::Serialize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn cbor_frames_are_shown_as_json() {
+        let config = FormattingConfig::default();
+        let show = |part: &[u8]| format_part_with(part, RenderStyle::Pretty, &config);
+
+        let reading = json!({
+            "sensor": "dock-3/temp",
+            "seq": 18_446_744_073_709_551_615u64,
+            "ok": true,
+            "limits": { "low": -20, "high": 85.5 },
+        });
+        let shown = show(&cbor(&reading));
+        let json = shown.strip_prefix("cbor: ").unwrap();
+        assert_eq!(serde_json::from_str::<Value>(json).unwrap(), reading);
+
+        let floats: Vec<f64> = (0..40).map(|i| f64::from(i) * 0.25 - 3.0).collect();
+        let shown = show(&cbor(&floats));
+        assert!(shown.starts_with("cbor: [\n  -3.0,"), "{}", shown);
+        assert!(shown.contains("... (38 more) ...") && shown.contains("6.75"), "{}", shown);
+
+        // Byte strings, as in MessagePack frames.
+        let blob = show(b"\xa1\x63png\x44\x89PNG");
+        assert_eq!(blob, "cbor: {\n  \"png\": \"<4 bytes> 89504e47\"\n}");
+    }
+
+    #[test]
+    fn random_bytes_are_not_taken_for_cbor() {
+        let config = FormattingConfig::default();
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..2000 {
+            let frame: Vec<u8> = (0..48)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            let shown = format_part_with(&frame, RenderStyle::Pretty, &config);
+            assert!(shown.starts_with("[48 bytes: ["), "{:02x?}: {}", frame, shown);
+        }
+        // A plausible first byte is not enough.
+        assert_eq!(format_part_with(b"\x82\x01", RenderStyle::Pretty, &config), "[130, 1]");
+        let scalar = cbor(&1.5f64);
+        let shown = format_part_with(&scalar, RenderStyle::Pretty, &config);
+        assert!(shown.starts_with("[249, 62, 0]"));
+        let small = FormattingConfig {
+            cbor_max_bytes: 3,
+            ..FormattingConfig::default()
+        };
+        assert_eq!(
+            format_part_with(b"\x82\x01\x02\x03", RenderStyle::Pretty, &small),
+            "[130, 1, 2, 3]"
+        );
+    }
+
+    #[test]
+    fn gzip_and_zlib_frames_are_shown_decompressed() {
+        let config = FormattingConfig::default();
+        let show = |part: &[u8]| format_part_with(part, RenderStyle::Compact, &config);
+        let order = include_bytes!("../tests/fixtures/order_object.json");
+        let plain = show(order);
+        let gzip = show(include_bytes!("../tests/fixtures/gzip/order.json.gz"));
+        assert_eq!(gzip, format!("gzip(571B→1.9KB): {}", plain));
+        let zlib = show(include_bytes!("../tests/fixtures/gzip/order.json.zz"));
+        assert_eq!(zlib, format!("zlib(559B→1.9KB): {}", plain));
+        // Text that is not JSON, as text.
+        let hello = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xcb\x48\xcd\xc9\xc9\x07\x00\
+                      \x86\xa6\x10\x36\x05\x00\x00\x00";
+        assert_eq!(show(hello), "gzip(25B→5B): \"hello\"");
+        // zlib.compress(b"hello", 0): a stored block.
+        let stored = b"\x78\x01\x01\x05\x00\xfa\xffhello\x06\x2c\x02\x15";
+        assert_eq!(show(stored), "zlib(16B→5B): \"hello\"");
+        let exact = FormattingConfig {
+            decompress_max_bytes: 5,
+            ..config.clone()
+        };
+        assert_eq!(try_decompress(hello, &exact), Some(("gzip", b"hello".to_vec())));
+        // Truncated, with a bad checksum, or with more after it.
+        for end in 0..hello.len() {
+            assert_eq!(try_decompress(&hello[..end], &config), None, "{}", end);
+        }
+        for end in 0..stored.len() {
+            assert_eq!(try_decompress(&stored[..end], &config), None, "{}", end);
+        }
+        let mut bad_crc = hello.to_vec();
+        bad_crc[19] ^= 1;
+        assert_eq!(try_decompress(&bad_crc, &config), None);
+        let mut longer = hello.to_vec();
+        longer.push(0);
+        assert_eq!(try_decompress(&longer, &config), None);
+        // A reserved block type, and bytes that only start like zlib.
+        assert_eq!(try_decompress(b"\x78\x01\x07", &config), None);
+        assert_eq!(try_decompress(b"\x78\x9d", &config), None);
+
+        // Truncated, corrupt, or past the limit: bytes, as before.
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gzip");
+        for name in ["truncated", "corrupt", "bomb"] {
+            let part = std::fs::read(fixtures.join(format!("{}.json.gz", name))).unwrap();
+            let shown = show(&part);
+            let preview = format!("[{} bytes: [31, 139, 8,", part.len());
+            assert!(shown.starts_with(&preview), "{}: {}", name, shown);
+        }
+        let off = FormattingConfig {
+            decompress_max_bytes: 0,
+            ..config.clone()
+        };
+        assert!(format_part_with(hello, RenderStyle::Compact, &off).starts_with("[25 bytes: [31"));
+        let small = FormattingConfig {
+            decompress_max_bytes: 4,
+            ..config
+        };
+        assert!(format_part_with(hello, RenderStyle::Compact, &small).starts_with("[25 bytes:"));
+
+        let sizes = [(0, "0B"), (1023, "1023B"), (1024, "1KB"), (1229, "1.2KB"), (14_336, "14KB")];
+        for (bytes, name) in sizes {
+            assert_eq!(short_size(bytes), name);
+        }
+        assert_eq!(short_size(3 << 20), "3MB");
+    }
+
+    #[test]
+    fn long_text_frames_are_cut_after_max_text_chars() {
+        let config = FormattingConfig {
+            max_text_chars: 8,
+            ..FormattingConfig::default()
+        };
+        let show = |text: &str| format_part_with(text.as_bytes(), RenderStyle::Compact, &config);
+        assert_eq!(show("abcdefgh"), "\"abcdefgh\"");
+        assert_eq!(
+            show("GET /quotes?symbol=BTC"),
+            "\"GET /quo\"… (+14 more chars, 22 bytes total)"
+        );
+        // Chars, not bytes; escapes are for what is shown and do not count.
+        assert_eq!(
+            show("日本語のテキストです"),
+            "\"日本語のテキスト\"… (+2 more chars, 30 bytes total)"
+        );
+        assert_eq!(
+            show("a\tb\"c\nd\re"),
+            "\"a\\tb\\\"c\\nd\\r\"… (+1 more chars, 9 bytes total)"
+        );
+
+        // A 5MB frame shows its start and its size.
+        let big = "x".repeat(5 << 20);
+        let default = FormattingConfig::default();
+        let shown = format_part_with(big.as_bytes(), RenderStyle::Compact, &default);
+        assert_eq!(
+            shown,
+            format!("\"{}\"… (+5,242,368 more chars, 5,242,880 bytes total)", "x".repeat(512))
+        );
+        // Independent of max_string_chars, for JSON strings.
+        let strings = FormattingConfig {
+            max_string_chars: 0,
+            ..config.clone()
+        };
+        let json = format_part_with(b"[\"abcdefghij\"]", RenderStyle::Compact, &strings);
+        assert_eq!(json, "[\"abcdefghij\"]");
+        let text = format_part_with(b"abcdefghij", RenderStyle::Compact, &strings);
+        assert_eq!(text, "\"abcdefgh\"… (+2 more chars, 10 bytes total)");
+        let whole = FormattingConfig {
+            max_text_chars: 0,
+            ..config
+        };
+        assert_eq!(
+            format_part_with(big.as_bytes(), RenderStyle::Compact, &whole).len(),
+            big.len() + 2
+        );
+    }
+
+    #[test]
+    fn ndjson_frames_are_shown_as_an_array_of_documents() {
+        let config = FormattingConfig::default();
+        let show = |text: &str| format_part_with(text.as_bytes(), RenderStyle::Compact, &config);
+        assert_eq!(
+            show("{\"a\":1}\n{\"a\":2}\n"),
+            "ndjson: [{\"a\":1},{\"a\":2}]"
+        );
+        assert_eq!(
+            show("\n{\"a\":1}\r\n\n  \n[2, 3]\n\n"),
+            "ndjson: [{\"a\":1},[2,3]]"
+        );
+
+        // Windowed like a top-level array, each document cropped on its own.
+        let px: Vec<usize> = (0..40).collect();
+        let lines: Vec<String> = (0..40)
+            .map(|i| json!({ "seq": i, "px": px, "password": "x" }).to_string())
+            .collect();
+        let shown = show(&lines.join("\n"));
+        let first = "{\"password\":\"<redacted>\",\"px\":[0,1,2,\"... (36 more) ...\",39],\
+                     \"seq\":0}";
+        let head = format!("ndjson: [{},\"… 38 more lines …\",", first);
+        assert!(shown.starts_with(&head), "{}", shown);
+        assert!(shown.ends_with("\"seq\":39}]"), "{}", shown);
+
+        // One line that is not a document and the frame is text.
+        assert_eq!(
+            show("{\"a\":1}\n{oops\n{\"a\":3}\n"),
+            "\"{\\\"a\\\":1}\\n{oops\\n{\\\"a\\\":3}\\n\""
+        );
+        assert!(show("{\"a\":1}\n42\n").starts_with('"'));
+        // A single document stays a document.
+        assert_eq!(show("{\"a\":1}\n"), "{\"a\":1}");
+
+        let limited = FormattingConfig {
+            ndjson_max_lines: 2,
+            ..config.clone()
+        };
+        let three = "{}\n{}\n{}".as_bytes();
+        assert!(format_part_with(three, RenderStyle::Compact, &limited).starts_with('"'));
+        let off = FormattingConfig {
+            ndjson_max_lines: 0,
+            ..config
+        };
+        assert!(format_part_with(b"{}\n{}", RenderStyle::Compact, &off).starts_with('"'));
+    }
+
+    #[test]
+    fn the_output_cap_spans_all_frames() {
+        let page = json!({ "rows": (0..20).map(|i| format!("row {}", i)).collect::<Vec<_>>() });
+        let frames: Vec<Vec<u8>> = (0..200).map(|_| page.to_string().into_bytes()).collect();
+        // Labels would make frames differ in length.
+        let config = FormattingConfig {
+            frame_labels: false,
+            ..FormattingConfig::default()
+        };
+        let one = format_part_with(&frames[0], RenderStyle::Pretty, &config).len();
+        let out = format_message_with(&frames, Envelope::Topic, RenderStyle::Pretty, &config);
+        let (shown, trailer) = out.split_once("… output truncated").unwrap();
+        assert!(shown.len() <= MAX_OUTPUT_BYTES, "{}", shown.len());
+        let frames_shown = MAX_OUTPUT_BYTES / (one + 3) + 1;
+        assert_eq!(
+            trailer,
+            format!(" at 16KB ({} of 200 frames shown)", frames_shown)
+        );
+        assert_eq!(shown.matches(" | ").count(), frames_shown - 1);
+
+        // Under the cap, or without one, nothing changes.
+        let two = &frames[..2];
+        let under = format_message_with(two, Envelope::Topic, RenderStyle::Pretty, &config);
+        assert!(!under.contains("truncated"));
+        let uncapped = FormattingConfig {
+            max_output_bytes: 0,
+            ..config
+        };
+        let all = format_message_with(&frames, Envelope::Topic, RenderStyle::Pretty, &uncapped);
+        assert_eq!(all.len(), 200 * one + 199 * 3 + 2);
+    }
+
+    #[test]
+    fn the_output_cap_never_splits_a_char() {
+        let config = |max_output_bytes| FormattingConfig {
+            max_output_bytes,
+            ..FormattingConfig::default()
+        };
+        let render = |parts: &[Vec<u8>], cap| {
+            format_message_with(parts, Envelope::Topic, RenderStyle::Pretty, &config(cap))
+        };
+        // "\"日本\"": each char is 3 bytes, after a 1-byte quote.
+        let text = "日本".as_bytes().to_vec();
+        for cap in 1..=7 {
+            let out = render(std::slice::from_ref(&text), cap);
+            let shown = out.split('…').next().unwrap();
+            assert!(shown.len() <= cap, "{}: {}", cap, out);
+            assert_eq!(shown, ["\"", "\"日", "\"日本"][(cap - 1) / 3], "{}", cap);
+            let trailer = format!("… output truncated at {} bytes (1 of 1 frames shown)", cap);
+            assert_eq!(&out[shown.len()..], trailer);
+        }
+        assert_eq!(render(std::slice::from_ref(&text), 8), "\"日本\"");
+        assert_eq!(
+            render(&[b"abc".to_vec(), text], 2048),
+            "[\"abc\" | \"日本\"]"
+        );
+        let cut = render(&[b"abcdef".to_vec(), b"x".to_vec()], 5);
+        assert_eq!(cut, "[\"ab]… output truncated at 5 bytes (1 of 2 frames shown)");
+    }
+
+    #[test]
+    fn routed_messages_start_with_an_identity_tag() {
+        // libzmq's generated identities, and ones peers set themselves.
+        assert_eq!(format_identity(b"\0k\x08Ae"), "id:6b084165");
+        assert_eq!(format_identity(b"\0\0\0\0\x01"), "id:00000001");
+        assert_eq!(format_identity(b"w1"), "id:7731");
+        assert_eq!(format_identity(b"client-7"), "id:636c6965…(8 bytes)");
+        assert_eq!(format_identity(&[0xab; 255]), "id:abababab…(255 bytes)");
+        assert_eq!(format_identity(b"\0\x01\x02\x03"), "id:00010203");
+        assert_eq!(format_identity(b""), "id:<empty>");
+
+        let message = [b"\0k\x08Ae".to_vec(), Vec::new(), b"ping".to_vec()];
+        let config = FormattingConfig {
+            frame_labels: false,
+            ..FormattingConfig::default()
+        };
+        assert_eq!(
+            format_message_with(&message, Envelope::Routed, RenderStyle::Pretty, &config),
+            "[id:6b084165 | \"\" | \"ping\"]"
+        );
+        // On PUB and proxy sockets the first frame is a topic.
+        let published = [b"ticks.BTC".to_vec(), b"buy".to_vec()];
+        assert_eq!(
+            format_message_with(&published, Envelope::Topic, RenderStyle::Pretty, &config),
+            "[\"ticks.BTC\" | \"buy\"]"
+        );
+        assert_eq!(
+            format_message_with(&message[..1], Envelope::Routed, RenderStyle::Pretty, &config),
+            "id:6b084165"
+        );
+    }
+
+    #[test]
+    fn pubsub_messages_name_their_topic_and_subscriptions() {
+        let config = FormattingConfig::default();
+        let show = |parts: &[&[u8]]| {
+            let parts: Vec<Vec<u8>> = parts.iter().map(|p| p.to_vec()).collect();
+            format_message_with(&parts, Envelope::PubSub, RenderStyle::Compact, &config)
+        };
+        assert_eq!(
+            show(&[b"ticker.BTCUSD", b"{\"px\": 64000.5}"]),
+            "topic \"ticker.BTCUSD\": {\"px\":64000.5}"
+        );
+        assert_eq!(show(&[b"a \"b\"", b"x"]), "topic \"a \\\"b\\\"\": \"x\"");
+        assert_eq!(show(&[b"\x01ticker.", b""]), "[\"\\u{1}ticker.\" | \"\"]");
+        assert_eq!(show(&[b"", b"x"]), "[\"\" | \"x\"]");
+        let long = vec![b'a'; TOPIC_MAX_BYTES + 1];
+        assert!(show(&[&long, b"x"]).starts_with("[\"aaa"));
+
+        assert_eq!(show(&[b"\x01ticker."]), "SUBSCRIBE \"ticker.\"");
+        assert_eq!(show(&[b"\x00ticker."]), "UNSUBSCRIBE \"ticker.\"");
+        // The empty topic subscribes to everything.
+        assert_eq!(show(&[b"\x01"]), "SUBSCRIBE \"\" (all topics)");
+        assert_eq!(show(&[b"\x00"]), "UNSUBSCRIBE \"\" (all topics)");
+        assert_eq!(show(&[b"\x02ticker."]), "\"\\u{2}ticker.\"");
+
+        // Other envelopes are unaffected, ROUTER traffic above all.
+        let routed = [b"\x01k\x08Ae".to_vec(), b"ticker.".to_vec()];
+        let shown = format_message_with(&routed, Envelope::Routed, RenderStyle::Compact, &config);
+        assert_eq!(shown, "[id:016b0841…(5 bytes) | \"ticker.\"]");
+        let published = [b"ticker.BTCUSD".to_vec(), b"1".to_vec()];
+        let shown = format_message_with(&published, Envelope::Topic, RenderStyle::Compact, &config);
+        assert_eq!(shown, "[\"ticker.BTCUSD\" | 1]");
+        let subscription = [b"\x01ticker.".to_vec()];
+        assert_eq!(
+            format_message_with(&subscription, Envelope::Topic, RenderStyle::Compact, &config),
+            "\"\\u{1}ticker.\""
+        );
+    }
+
+    #[test]
+    fn compact_rendering_keeps_a_message_on_one_line() {
+        let config = FormattingConfig::default();
+        let nested = json!({
+            "id": 7,
+            "result": { "rows": [[1, 2], [3, 4]], "meta": { "note": "a\nb", "tags": [] } },
+            "items": (0..40).collect::<Vec<_>>(),
+        });
+        let frame = nested.to_string().into_bytes();
+        let compact = format_part_with(&frame, RenderStyle::Compact, &config);
+        assert!(!compact.contains('\n'), "{}", compact);
+        assert!(compact.starts_with("{\"id\":7,\"items\":[0,1,2,"), "{}", compact);
+        assert!(compact.contains("\"note\":\"a\\nb\""), "{}", compact);
+        // The same document as the indented form, minus the layout.
+        let pretty = format_part_with(&frame, RenderStyle::Pretty, &config);
+        assert!(pretty.lines().count() > 10, "{}", pretty);
+        let reparsed: Value = serde_json::from_str(&compact).unwrap();
+        assert_eq!(reparsed, serde_json::from_str::<Value>(&pretty).unwrap());
+
+        // Across frames, and for MessagePack too.
+        let order = include_bytes!("../tests/fixtures/msgpack/order.msgpack").to_vec();
+        let message = [b"\0k\x08Ae".to_vec(), Vec::new(), frame.clone(), order];
+        let line = format_message_with(&message, Envelope::Routed, RenderStyle::Compact, &config);
+        assert!(!line.contains('\n'), "{}", line);
+        assert!(line.contains(" | #3(222B): msgpack: {\""), "{}", line);
+
+        // `pretty = true` indents whatever the caller asks for.
+        let indented = FormattingConfig {
+            pretty: true,
+            ..config
+        };
+        assert_eq!(format_part_with(&frame, RenderStyle::Compact, &indented), pretty);
+    }
+
+    #[test]
+    fn frames_of_longer_messages_are_labelled_with_index_and_size() {
+        let config = FormattingConfig::default();
+        let message = [
+            b"\0k\x08Ae".to_vec(),
+            Vec::new(),
+            b"{\"op\":1}".to_vec(),
+            vec![b'x'; 1500],
+        ];
+        let out = format_message_with(&message, Envelope::Routed, RenderStyle::Pretty, &config);
+        assert!(
+            out.starts_with("[#0(5B): id:6b084165 | #1(0B): <empty> | #2(8B): {\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains(" | #3(1,500B): \"xxx"), "{}", out);
+        // 2 frames or fewer, or labels off: as before.
+        let pair = [b"ticks.BTC".to_vec(), Vec::new()];
+        assert_eq!(
+            format_message_with(&pair, Envelope::Topic, RenderStyle::Pretty, &config),
+            "[\"ticks.BTC\" | \"\"]"
+        );
+        assert_eq!(
+            format_message_with(&pair[..1], Envelope::Topic, RenderStyle::Pretty, &config),
+            "\"ticks.BTC\""
+        );
+        let bare = FormattingConfig {
+            frame_labels: false,
+            ..config.clone()
+        };
+        let three = [Vec::new(), b"a".to_vec(), b"b".to_vec()];
+        assert_eq!(
+            format_message_with(&three, Envelope::Topic, RenderStyle::Pretty, &bare),
+            "[\"\" | \"a\" | \"b\"]"
+        );
+        // Labels count toward max_output_bytes.
+        let capped = FormattingConfig {
+            max_output_bytes: 24,
+            ..config
+        };
+        assert_eq!(
+            format_message_with(&three, Envelope::Topic, RenderStyle::Pretty, &capped),
+            "[#0(0B): <empty> | #1(1]… output truncated at 24 bytes (2 of 3 frames shown)"
+        );
+    }
+
+    #[test]
+    fn hex_dumps_pad_the_last_line_and_stop_at_the_cap() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDRodd";
+        assert_eq!(
+            hex_dump(png, 4096),
+            "00000000  89 50 4e 47 0d 0a 1a 0a  00 00 00 0d 49 48 44 52  |.PNG........IHDR|\n\
+             00000010  6f 64 64                                          |odd|"
+        );
+        assert_eq!(
+            hex_dump(&[0x41; 5], 5),
+            "00000000  41 41 41 41 41                                    |AAAAA|"
+        );
+        assert_eq!(hex_dump(&[], 16), "");
+
+        let frame: Vec<u8> = (0..=255u8).cycle().take(5000).collect();
+        let dump = hex_dump(&frame, 40);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("00000020  20 21 22 23 24 25 26 27  "), "{}", lines[2]);
+        assert!(lines[2].ends_with("| !\"#$%&'|"), "{}", lines[2]);
+        assert_eq!(lines[3], "... 4,960 more bytes");
+        assert_eq!(hex_dump(&frame, 0), "... 5,000 more bytes");
+
+        let config = FormattingConfig {
+            hexdump: true,
+            ..FormattingConfig::default()
+        };
+        assert_eq!(
+            format_part_with(&[0xff, 0x00], RenderStyle::Pretty, &config),
+            "[2 bytes]\n00000000  ff 00                                             |..|"
+        );
+        // The short preview stays the default below trace level.
+        assert_eq!(format_part(&[0xff, 0x00], RenderStyle::Pretty), "[255, 0]");
+    }
+}
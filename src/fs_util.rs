@@ -0,0 +1,96 @@
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+//
+// ------------------------------ Atomic writes --------------------------------
+//
+// State files, the store, the endpoints file and migrated configs are all
+// replaced the same way: the bytes go to `<path>.tmp` in the same directory,
+// are synced to disk, and the temporary file is renamed over `path`. A crash
+// or a power loss then leaves either the old file or the new one, never half
+// of one.
+
+// Replaces `path` with `bytes`, creating its directory first. With `mode`
+// (unix only) the temporary file is created with it before anything is
+// written, so content that may hold secrets is never readable by others. A
+// failed replacement removes the temporary file.
+pub fn replace_file(path: &Path, bytes: &[u8], mode: Option<u32>) -> Result<(), String> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let temporary = temporary_path(path);
+    // One left by a crash may have other permissions.
+    let _ = fs::remove_file(&temporary);
+    let replaced = write_synced(&temporary, bytes, mode).and_then(|_| fs::rename(&temporary, path));
+    if replaced.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    replaced.map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+fn write_synced(path: &Path, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    // The umask may have narrowed `mode`.
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_replaced_file_holds_the_new_bytes_and_no_temporary_is_left() {
+        let dir = std::env::temp_dir().join(format!("corky-fs-util-{}", std::process::id()));
+        let path = dir.join("state").join("rules.toml");
+        replace_file(&path, b"first", None).unwrap();
+        replace_file(&path, b"second", None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!temporary_path(&path).exists());
+
+        // Renaming over a directory fails, and takes the temporary file along.
+        let occupied = dir.join("occupied");
+        fs::create_dir_all(occupied.join("entry")).unwrap();
+        assert!(replace_file(&occupied, b"secret", None).is_err());
+        assert!(!temporary_path(&occupied).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_mode_applies_from_the_start() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("corky-fs-mode-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        replace_file(&path, b"password = \"x\"\n", Some(0o600)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::config::GcConfig;
+use crate::fanout::WorkerPool;
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+use crate::peers::{peer_key, split_key, PeerKey, PeerRole, PeerTable};
+
+//
+// ------------------------------- Idle GC -------------------------------------
+//
+// One collector for every registry that remembers peers, run from the
+// broker's peer sweep. Each category has its own TTL measured against the
+// peer's last activity in the peer table:
+//
+//     peer    the peer table entry and everything stored in it (counters,
+//             chunked transfers); [broker] peer_idle_ttl_ms
+//     worker  fan-out pool membership; [gc] worker_ttl_ms
+//
+// Work is bounded: a tick examines at most `max_work_per_tick` entries across
+// all categories, so a backlog of stale entries is spread over later ticks
+// instead of stalling the loop. Expirations are counted in
+// corky_gc_expired_total{category} and, when notify_endpoint is set,
+// published as
+//
+//     ["peer_gone", {"category", "role", "identity", "idle_ms"}]
+
+pub const PEER_GONE_TOPIC: &[u8] = b"peer_gone";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcCategory {
+    Peer,
+    Worker,
+}
+
+impl GcCategory {
+    pub fn label(self) -> &'static str {
+        match self {
+            GcCategory::Peer => "peer",
+            GcCategory::Worker => "worker",
+        }
+    }
+}
+
+// One expired entry, for the presence notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerGone {
+    pub category: GcCategory,
+    pub role: PeerRole,
+    pub identity: Vec<u8>,
+    pub idle: Duration,
+}
+
+impl PeerGone {
+    pub fn into_message(self) -> Multipart {
+        let event = json!({
+            "category": self.category.label(),
+            "role": self.role.label(),
+            "identity": String::from_utf8_lossy(&self.identity),
+            "idle_ms": self.idle.as_millis() as u64,
+        });
+        Multipart::new(vec![
+            PEER_GONE_TOPIC.to_vec(),
+            event.to_string().into_bytes(),
+        ])
+    }
+}
+
+pub struct IdleGc {
+    peer_ttl: Duration,
+    worker_ttl: Duration,
+    max_work: usize,
+    notify: bool,
+    gone: Vec<PeerGone>,
+    expired_peers: Counter,
+    expired_workers: Counter,
+}
+
+impl IdleGc {
+    pub fn new(config: &GcConfig, peer_ttl: Duration, metrics: &Registry) -> Self {
+        let expired = |category: GcCategory| {
+            metrics.counter("corky_gc_expired_total", &[("category", category.label())])
+        };
+        Self {
+            peer_ttl,
+            worker_ttl: Duration::from_millis(config.worker_ttl_ms),
+            max_work: config.max_work_per_tick.max(1),
+            notify: !config.notify_endpoint.is_empty(),
+            gone: Vec::new(),
+            expired_peers: expired(GcCategory::Peer),
+            expired_workers: expired(GcCategory::Worker),
+        }
+    }
+
+    // One tick: expire idle peers oldest first, then sweep the worker pool
+    // with whatever work is left. Expired peer states are returned so the
+    // caller can release what they hold.
+    pub fn collect<T: Default>(
+        &mut self,
+        now: Instant,
+        peers: &mut PeerTable<T>,
+        workers: &mut WorkerPool,
+    ) -> Vec<(PeerKey, T)> {
+        let mut budget = self.max_work;
+        let mut expired = Vec::new();
+        while budget > 0 {
+            let Some((_, last_seen)) = peers.oldest() else {
+                break;
+            };
+            let idle = now.saturating_duration_since(last_seen);
+            if idle < self.peer_ttl {
+                break;
+            }
+            budget -= 1;
+            let Some((key, state)) = peers.evict_idle(now, self.peer_ttl, 1).pop() else {
+                break;
+            };
+            self.expired_peers.inc();
+            if let Some((role, identity)) = split_key(&key) {
+                self.record_gone(GcCategory::Peer, role, identity, idle);
+            }
+            expired.push((key, state));
+        }
+
+        let worker_ttl = self.worker_ttl;
+        let peer_ttl = self.peer_ttl;
+        let idle_workers = workers.expire_idle(budget, |worker| {
+            match peers.last_seen(&peer_key(PeerRole::Worker, worker)) {
+                Some(seen) => {
+                    let idle = now.saturating_duration_since(seen);
+                    (idle >= worker_ttl).then_some(idle)
+                }
+                // Already forgotten, so silent for at least the peer TTL.
+                None => Some(peer_ttl),
+            }
+        });
+        for (worker, idle) in idle_workers {
+            self.expired_workers.inc();
+            self.record_gone(GcCategory::Worker, PeerRole::Worker, &worker, idle);
+        }
+        expired
+    }
+
+    fn record_gone(
+        &mut self,
+        category: GcCategory,
+        role: PeerRole,
+        identity: &[u8],
+        idle: Duration,
+    ) {
+        if self.notify {
+            self.gone.push(PeerGone {
+                category,
+                role,
+                identity: identity.to_vec(),
+                idle,
+            });
+        }
+    }
+
+    // Queue notifications without notify_endpoint, for the event stream.
+    pub fn queue_gone(&mut self) {
+        self.notify = true;
+    }
+
+    // Notifications queued since the last call; empty unless notify_endpoint
+    // is set or `queue_gone` was called.
+    pub fn take_gone(&mut self) -> Vec<PeerGone> {
+        std::mem::take(&mut self.gone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn gc(max_work: usize, metrics: &Registry) -> IdleGc {
+        let config = GcConfig {
+            max_work_per_tick: max_work,
+            worker_ttl_ms: 5_000,
+            notify_endpoint: "inproc://gc".to_string(),
+        };
+        IdleGc::new(&config, 10 * SEC, metrics)
+    }
+
+    fn expired_total(metrics: &Registry, category: &'static str) -> u64 {
+        metrics
+            .counter("corky_gc_expired_total", &[("category", category)])
+            .get()
+    }
+
+    #[test]
+    fn stale_peers_expire_in_bounded_stages() {
+        let metrics = Registry::new();
+        let mut gc = gc(4, &metrics);
+        let start = Instant::now();
+        let mut peers: PeerTable<u32> = PeerTable::new(100);
+        let mut workers = WorkerPool::default();
+        for i in 0..10u8 {
+            peers.touch(&peer_key(PeerRole::Client, &[b'a' + i]), start);
+        }
+        let active = peer_key(PeerRole::Client, b"busy");
+
+        // The mock clock: the busy client keeps talking while the rest go
+        // quiet, and each tick may expire at most four peers.
+        let mut stages = Vec::new();
+        for tick in 1..=5 {
+            let now = start + SEC * (10 + tick as u32);
+            peers.touch(&active, now);
+            stages.push(gc.collect(now, &mut peers, &mut workers).len());
+        }
+        assert_eq!(stages, vec![4, 4, 2, 0, 0]);
+        assert_eq!(peers.len(), 1);
+        assert!(peers.get(&active).is_some(), "active peer was expired");
+        assert_eq!(expired_total(&metrics, "peer"), 10);
+
+        let gone = gc.take_gone();
+        assert_eq!(gone.len(), 10);
+        assert_eq!(gone[0].role, PeerRole::Client);
+        assert_eq!(gone[0].identity, b"a");
+        assert!(gone.iter().all(|g| g.idle >= 10 * SEC));
+        assert!(gc.take_gone().is_empty());
+    }
+
+    #[test]
+    fn pool_workers_expire_on_their_own_ttl() {
+        let metrics = Registry::new();
+        let mut gc = gc(100, &metrics);
+        let start = Instant::now();
+        let mut peers: PeerTable<()> = PeerTable::new(100);
+        let mut workers = WorkerPool::default();
+        for id in [&b"w1"[..], b"w2", b"w3"] {
+            peers.touch(&peer_key(PeerRole::Worker, id), start);
+            workers.add(id);
+        }
+        // w2 heartbeats; w1 and w3 go silent past the worker TTL but not the
+        // peer TTL, so they leave the pool and stay in the peer table.
+        let now = start + 6 * SEC;
+        peers.touch(&peer_key(PeerRole::Worker, b"w2"), now);
+        assert!(gc.collect(now, &mut peers, &mut workers).is_empty());
+        assert_eq!(workers.len(), 1);
+        assert_eq!(peers.len(), 3);
+        assert_eq!(expired_total(&metrics, "worker"), 2);
+
+        let gone = gc.take_gone();
+        let ids: Vec<&[u8]> = gone.iter().map(|g| g.identity.as_slice()).collect();
+        assert_eq!(ids, vec![&b"w1"[..], b"w3"]);
+        assert!(gone.iter().all(|g| g.category == GcCategory::Worker));
+    }
+
+    #[test]
+    fn worker_sweep_resumes_where_it_stopped() {
+        let metrics = Registry::new();
+        let mut gc = gc(1, &metrics);
+        let start = Instant::now();
+        let mut peers: PeerTable<()> = PeerTable::new(100);
+        let mut workers = WorkerPool::default();
+        for id in [&b"w1"[..], b"w2", b"w3"] {
+            peers.touch(&peer_key(PeerRole::Worker, id), start);
+            workers.add(id);
+        }
+        // Only w3 is idle; with one check per tick it takes three ticks to
+        // reach it, and the active workers are never dropped.
+        let mut now = start;
+        let mut pool_sizes = Vec::new();
+        for _ in 0..3 {
+            now += 2 * SEC;
+            peers.touch(&peer_key(PeerRole::Worker, b"w1"), now);
+            peers.touch(&peer_key(PeerRole::Worker, b"w2"), now);
+            gc.collect(now, &mut peers, &mut workers);
+            pool_sizes.push(workers.len());
+        }
+        assert_eq!(pool_sizes, vec![3, 3, 2]);
+    }
+
+    #[test]
+    fn notifications_are_off_without_an_endpoint() {
+        let metrics = Registry::new();
+        let config = GcConfig {
+            notify_endpoint: String::new(),
+            ..GcConfig::default()
+        };
+        let mut gc = IdleGc::new(&config, SEC, &metrics);
+        let start = Instant::now();
+        let mut peers: PeerTable<()> = PeerTable::new(10);
+        peers.touch(&peer_key(PeerRole::Direct, b"x"), start);
+        let expired = gc.collect(start + 2 * SEC, &mut peers, &mut WorkerPool::default());
+        assert_eq!(expired.len(), 1);
+        assert!(gc.take_gone().is_empty());
+        assert_eq!(expired_total(&metrics, "peer"), 1);
+    }
+
+    #[test]
+    fn peer_gone_event_is_json() {
+        let gone = PeerGone {
+            category: GcCategory::Peer,
+            role: PeerRole::Client,
+            identity: b"c-1".to_vec(),
+            idle: Duration::from_millis(1500),
+        };
+        let message = gone.into_message();
+        assert_eq!(message[0], PEER_GONE_TOPIC);
+        let event: serde_json::Value = serde_json::from_slice(&message[1]).unwrap();
+        assert_eq!(event["category"], "peer");
+        assert_eq!(event["role"], "client");
+        assert_eq!(event["identity"], "c-1");
+        assert_eq!(event["idle_ms"], 1500);
+    }
+}
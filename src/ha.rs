@@ -0,0 +1,386 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{HaConfig, ResolveConfig, RetryProfile};
+use crate::resolve::{Reconnector, SystemResolver};
+use crate::socket::bind_with_retry;
+
+//
+// ---------------------------- Binary Star pair -------------------------------
+//
+// Active/passive broker pairing after the Binary Star pattern. Both instances
+// bind their public endpoints. Only the active one routes traffic; the passive
+// one drops it. Each instance publishes its state to the peer every
+// heartbeat_ms. A passive instance only promotes itself when the peer has been
+// silent for failover_ms *and* a client or worker is talking to it. A network
+// split between the two brokers alone therefore never produces two actives:
+// clients only reach the passive instance once they have given up on the
+// active one.
+//
+// Nothing is replicated. Peer tracking, in-flight chunked transfers and any
+// queued messages of the failed instance are lost; clients and workers
+// reconnect and resend through their own retry logic.
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HaRole {
+    // Becomes active on startup once the backup is seen.
+    #[default]
+    Primary,
+    // Stays passive until the primary fails.
+    Backup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    // Starting up as primary, peer not seen yet
+    Primary,
+    // Starting up as backup, peer not seen yet
+    Backup,
+    Active,
+    Passive,
+}
+
+impl State {
+    pub const ALL: [State; 4] = [State::Primary, State::Backup, State::Active, State::Passive];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            State::Primary => "primary",
+            State::Backup => "backup",
+            State::Active => "active",
+            State::Passive => "passive",
+        }
+    }
+
+    pub fn from_label(label: &[u8]) -> Option<State> {
+        State::ALL
+            .into_iter()
+            .find(|s| s.label().as_bytes() == label)
+    }
+
+    fn initial(role: HaRole) -> State {
+        match role {
+            HaRole::Primary => State::Primary,
+            HaRole::Backup => State::Backup,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    // A heartbeat from the peer, carrying its state
+    Peer(State),
+    // A message from a client or worker on one of the public endpoints
+    ClientRequest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // A client request that this instance must not serve
+    Rejected,
+    // Both instances claim the active role
+    DualActive,
+    // Neither instance will ever promote
+    DualPassive,
+}
+
+// The Binary Star state table. `peer_expired` is whether the peer has been
+// silent for longer than the failover delay.
+pub fn transition(state: State, event: Event, peer_expired: bool) -> Result<State, Fault> {
+    use Event::*;
+    use State::*;
+    match (state, event) {
+        (Primary, Peer(Backup)) => Ok(Active),
+        (Primary, Peer(Active)) => Ok(Passive),
+        (Primary, Peer(Primary) | Peer(Passive)) => Ok(Primary),
+        (Primary, ClientRequest) if peer_expired => Ok(Active),
+        (Primary, ClientRequest) => Err(Fault::Rejected),
+
+        (Backup, Peer(Active)) => Ok(Passive),
+        (Backup, Peer(_)) => Ok(Backup),
+        (Backup, ClientRequest) => Err(Fault::Rejected),
+
+        (Active, Peer(Active)) => Err(Fault::DualActive),
+        (Active, Peer(_) | ClientRequest) => Ok(Active),
+
+        // The peer restarted and has no clients yet: take over.
+        (Passive, Peer(Primary) | Peer(Backup)) => Ok(Active),
+        (Passive, Peer(Active)) => Ok(Passive),
+        (Passive, Peer(Passive)) => Err(Fault::DualPassive),
+        (Passive, ClientRequest) if peer_expired => Ok(Active),
+        (Passive, ClientRequest) => Err(Fault::Rejected),
+    }
+}
+
+// One instance's view of the pair.
+pub struct BinaryStar {
+    role: HaRole,
+    state: State,
+    failover: Duration,
+    // The peer counts as failed from this instant on.
+    peer_expiry: Instant,
+}
+
+impl BinaryStar {
+    pub fn new(role: HaRole, failover: Duration, now: Instant) -> Self {
+        Self {
+            role,
+            state: State::initial(role),
+            failover,
+            peer_expiry: now + failover,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == State::Active
+    }
+
+    fn apply(&mut self, event: Event, now: Instant) -> Result<State, Fault> {
+        let next = transition(self.state, event, now >= self.peer_expiry);
+        match next {
+            Ok(state) => {
+                if state != self.state {
+                    info!(
+                        "(Broker) HA {}: {} -> {}",
+                        self.role_label(),
+                        self.state.label(),
+                        state.label()
+                    );
+                }
+                self.state = state;
+            }
+            Err(Fault::Rejected) => {}
+            Err(fault) => {
+                // Both sides fall back to their startup state; the primary
+                // then wins as soon as heartbeats flow again.
+                error!(
+                    "(Broker) HA {}: {:?} with peer, restarting role negotiation",
+                    self.role_label(),
+                    fault
+                );
+                self.state = State::initial(self.role);
+            }
+        }
+        next
+    }
+
+    pub fn on_peer(&mut self, peer: State, now: Instant) -> Result<State, Fault> {
+        self.peer_expiry = now + self.failover;
+        self.apply(Event::Peer(peer), now)
+    }
+
+    // Whether a client or worker message received now may be served.
+    pub fn on_client_request(&mut self, now: Instant) -> bool {
+        self.is_active() || self.apply(Event::ClientRequest, now).is_ok()
+    }
+
+    fn role_label(&self) -> &'static str {
+        match self.role {
+            HaRole::Primary => "primary",
+            HaRole::Backup => "backup",
+        }
+    }
+}
+
+// Heartbeat sockets between the two instances: a DEALER bound locally that
+// receives the peer's state and a DEALER connected to the peer that sends
+// ours. The inbox keeps only the newest heartbeat, the outbox holds at most
+// one, and nothing is queued while the peer is down, so a returning peer never
+// acts on stale state. (Conflating the outbox would also swallow the routing
+// id handshake.) A peer_endpoint naming a host is re-resolved per [resolve].
+pub struct HaLink {
+    inbox: zmq::Socket,
+    outbox: zmq::Socket,
+    // Borrowed mutably on the heartbeat only; the poll set holds the link.
+    peer: RefCell<Reconnector>,
+}
+
+impl HaLink {
+    pub fn new(
+        context: &zmq::Context,
+        config: &HaConfig,
+        resolve: &ResolveConfig,
+        bind_retry: &RetryProfile,
+        routing_id: &[u8],
+    ) -> Result<Self, zmq::Error> {
+        if config.peer_endpoint.is_empty() {
+            error!("(Broker) [ha] enabled but peer_endpoint is not set");
+            return Err(zmq::Error::EINVAL);
+        }
+        let inbox = context.socket(zmq::DEALER)?;
+        inbox.set_linger(0)?;
+        inbox.set_conflate(true)?;
+        bind_with_retry(&inbox, &config.local_endpoint, bind_retry, "Broker")?;
+        let outbox = context.socket(zmq::DEALER)?;
+        outbox.set_linger(0)?;
+        outbox.set_sndhwm(1)?;
+        outbox.set_immediate(true)?;
+        outbox.set_identity(routing_id)?;
+        let peer = Reconnector::connect(
+            context,
+            &outbox,
+            &config.peer_endpoint,
+            "HA peer",
+            resolve,
+            Arc::new(SystemResolver),
+        )?;
+        info!(
+            "(Broker) HA {:?}: heartbeats on {}, peer at {} as {}",
+            config.role,
+            config.local_endpoint,
+            config.peer_endpoint,
+            String::from_utf8_lossy(routing_id)
+        );
+        Ok(Self {
+            inbox,
+            outbox,
+            peer: RefCell::new(peer),
+        })
+    }
+
+    pub fn as_poll_item(&self) -> zmq::PollItem<'_> {
+        self.inbox.as_poll_item(zmq::POLLIN)
+    }
+
+    pub fn send_state(&self, state: State) {
+        self.peer.borrow_mut().poll(&self.outbox);
+        match self.outbox.send(state.label(), zmq::DONTWAIT) {
+            Ok(()) | Err(zmq::Error::EAGAIN) => {}
+            Err(e) => warn!("(Broker) HA heartbeat send failed: {}", e),
+        }
+    }
+
+    // Next heartbeat from the peer, if one is waiting.
+    pub fn recv_state(&self) -> Option<State> {
+        loop {
+            let frame = self.inbox.recv_bytes(zmq::DONTWAIT).ok()?;
+            match State::from_label(&frame) {
+                Some(state) => return Some(state),
+                None => warn!("(Broker) Ignoring malformed HA heartbeat: {:?}", frame),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::*;
+    use State::*;
+
+    #[test]
+    fn transition_table_is_exhaustive() {
+        let rejected = Err(Fault::Rejected);
+        let (dual_active, dual_passive) = (Err(Fault::DualActive), Err(Fault::DualPassive));
+        // (state, event, expected with live peer, expected with expired peer)
+        let table = [
+            (Primary, Peer(Primary), Ok(Primary), Ok(Primary)),
+            (Primary, Peer(Backup), Ok(Active), Ok(Active)),
+            (Primary, Peer(Active), Ok(Passive), Ok(Passive)),
+            (Primary, Peer(Passive), Ok(Primary), Ok(Primary)),
+            (Primary, ClientRequest, rejected, Ok(Active)),
+            (Backup, Peer(Primary), Ok(Backup), Ok(Backup)),
+            (Backup, Peer(Backup), Ok(Backup), Ok(Backup)),
+            (Backup, Peer(Active), Ok(Passive), Ok(Passive)),
+            (Backup, Peer(Passive), Ok(Backup), Ok(Backup)),
+            (Backup, ClientRequest, rejected, rejected),
+            (Active, Peer(Primary), Ok(Active), Ok(Active)),
+            (Active, Peer(Backup), Ok(Active), Ok(Active)),
+            (Active, Peer(Active), dual_active, dual_active),
+            (Active, Peer(Passive), Ok(Active), Ok(Active)),
+            (Active, ClientRequest, Ok(Active), Ok(Active)),
+            (Passive, Peer(Primary), Ok(Active), Ok(Active)),
+            (Passive, Peer(Backup), Ok(Active), Ok(Active)),
+            (Passive, Peer(Active), Ok(Passive), Ok(Passive)),
+            (Passive, Peer(Passive), dual_passive, dual_passive),
+            (Passive, ClientRequest, rejected, Ok(Active)),
+        ];
+        let events: Vec<Event> = State::ALL
+            .into_iter()
+            .map(Peer)
+            .chain([ClientRequest])
+            .collect();
+        assert_eq!(table.len(), State::ALL.len() * events.len());
+        for state in State::ALL {
+            for &event in &events {
+                let row = table
+                    .iter()
+                    .find(|r| r.0 == state && r.1 == event)
+                    .unwrap_or_else(|| panic!("no row for {:?} {:?}", state, event));
+                assert_eq!(
+                    transition(state, event, false),
+                    row.2,
+                    "{:?} {:?} live",
+                    state,
+                    event
+                );
+                assert_eq!(
+                    transition(state, event, true),
+                    row.3,
+                    "{:?} {:?} expired",
+                    state,
+                    event
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pair_settles_with_primary_active() {
+        let t0 = Instant::now();
+        let failover = Duration::from_millis(100);
+        let mut primary = BinaryStar::new(HaRole::Primary, failover, t0);
+        let mut backup = BinaryStar::new(HaRole::Backup, failover, t0);
+        backup.on_peer(primary.state(), t0).unwrap();
+        primary.on_peer(backup.state(), t0).unwrap();
+        backup.on_peer(primary.state(), t0).unwrap();
+        assert_eq!(primary.state(), Active);
+        assert_eq!(backup.state(), Passive);
+        assert!(primary.on_client_request(t0));
+        assert!(!backup.on_client_request(t0));
+    }
+
+    #[test]
+    fn passive_promotes_only_with_client_activity_after_failover() {
+        let t0 = Instant::now();
+        let failover = Duration::from_millis(100);
+        let mut backup = BinaryStar::new(HaRole::Backup, failover, t0);
+        backup.on_peer(Active, t0).unwrap();
+        // Peer silent, but within the failover delay.
+        assert!(!backup.on_client_request(t0 + Duration::from_millis(50)));
+        // Peer silent for longer: no promotion without a client...
+        let later = t0 + Duration::from_millis(500);
+        assert_eq!(backup.state(), Passive);
+        // ...and the first client request promotes and is served.
+        assert!(backup.on_client_request(later));
+        assert_eq!(backup.state(), Active);
+        // A heartbeat refreshes the expiry.
+        let mut other = BinaryStar::new(HaRole::Backup, failover, t0);
+        other.on_peer(Active, t0).unwrap();
+        other.on_peer(Active, later).unwrap();
+        assert!(!other.on_client_request(later + Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn split_brain_falls_back_to_startup_state() {
+        let t0 = Instant::now();
+        let mut backup = BinaryStar::new(HaRole::Backup, Duration::from_millis(100), t0);
+        backup.on_peer(Active, t0).unwrap();
+        assert!(backup.on_client_request(t0 + Duration::from_secs(1)));
+        // The old primary comes back believing it is active.
+        let t1 = t0 + Duration::from_secs(2);
+        assert_eq!(backup.on_peer(Active, t1), Err(Fault::DualActive));
+        assert_eq!(backup.state(), Backup);
+        backup.on_peer(Active, t1).unwrap();
+        assert_eq!(backup.state(), Passive);
+    }
+}
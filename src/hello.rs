@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::multipart::Multipart;
+
+//
+// ---------------------------- Hello exchange ---------------------------------
+//
+// A client may open with a hello on the client-facing socket to find out what
+// the broker supports instead of guessing:
+//
+//     ["__corky_hello__", {"version": 1, "capabilities": ["compression", ..]}]
+//
+// The broker answers it itself, without involving a worker:
+//
+//     ["__corky_hello__", {"hello": {"version", "features", "capabilities",
+//      "unsupported", "limits": {"max_message_bytes", "max_frame_bytes"}}}]
+//
+// `version` is the protocol both sides speak (the lower of the two),
+// `features` everything this broker has enabled, and `capabilities` the
+// requested ones it agreed to; requested names it does not know are listed in
+// `unsupported`. A limit is null when there is none. The agreed set is kept
+// per client identity in the peer table, and a later hello replaces it:
+//
+//     compression  zstd frames pass through to the client untouched rather
+//                  than being decompressed for it (crate::compress)
+//     chunking     chunked transfers (crate::chunk)
+//     fanout       scatter-gather requests (crate::fanout)
+//
+// A client that never says hello gets the conservative defaults: replies are
+// decompressed for it unless it sends a compressed frame itself. Malformed
+// hellos are answered with ["__corky_hello__", {"hello": {"error": ...}}].
+
+pub const HELLO: &[u8] = b"__corky_hello__";
+pub const PROTOCOL_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Compression,
+    Chunking,
+    Fanout,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::Compression,
+        Capability::Chunking,
+        Capability::Fanout,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Capability::Compression => "compression",
+            Capability::Chunking => "chunking",
+            Capability::Fanout => "fanout",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Capability> {
+        Capability::ALL.into_iter().find(|c| c.label() == label)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// A set of capabilities, small enough to keep in every peer-table entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub fn of(capabilities: &[Capability]) -> Self {
+        Self(capabilities.iter().fold(0, |bits, c| bits | c.bit()))
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    pub fn intersect(self, other: Capabilities) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn labels(self) -> Vec<&'static str> {
+        Capability::ALL
+            .into_iter()
+            .filter(|c| self.contains(*c))
+            .map(Capability::label)
+            .collect()
+    }
+}
+
+// What the broker puts on the table for one client-facing endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offer {
+    pub features: Capabilities,
+    // Negative for no limit, as in [[network.client_facing]] maxmsgsize.
+    pub max_message_bytes: i64,
+    pub max_frame_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct HelloRequest {
+    #[serde(default = "default_version")]
+    version: u64,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+fn default_version() -> u64 {
+    PROTOCOL_VERSION
+}
+
+// Answer a hello. Returns the reply frames after the client identity, and the
+// agreed capabilities unless the hello was rejected.
+pub fn negotiate(request: Option<&[u8]>, offer: &Offer) -> (Vec<u8>, Option<Capabilities>) {
+    let parsed = match request {
+        None => Ok(HelloRequest {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        }),
+        Some(frame) => serde_json::from_slice::<HelloRequest>(frame)
+            .map_err(|e| format!("invalid hello: {}", e)),
+    };
+    let request = match parsed {
+        Ok(request) if request.version == 0 => Err("unsupported protocol version 0".to_string()),
+        other => other,
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            let reply = json!({"hello": {"error": e}});
+            return (reply.to_string().into_bytes(), None);
+        }
+    };
+    let (known, unsupported): (Vec<_>, Vec<_>) = request
+        .capabilities
+        .iter()
+        .partition(|name| Capability::from_label(name).is_some());
+    let wanted: Vec<Capability> = known
+        .iter()
+        .filter_map(|name| Capability::from_label(name))
+        .collect();
+    let agreed = Capabilities::of(&wanted).intersect(offer.features);
+    let max_message_bytes = (offer.max_message_bytes >= 0).then_some(offer.max_message_bytes);
+    let max_frame_bytes = agreed
+        .contains(Capability::Compression)
+        .then_some(offer.max_frame_bytes);
+    let reply = json!({"hello": {
+        "version": request.version.min(PROTOCOL_VERSION),
+        "features": offer.features.labels(),
+        "capabilities": agreed.labels(),
+        "unsupported": unsupported,
+        "limits": {
+            "max_message_bytes": max_message_bytes,
+            "max_frame_bytes": max_frame_bytes,
+        },
+    }});
+    (reply.to_string().into_bytes(), Some(agreed))
+}
+
+//
+// ----------------------------- Client side -----------------------------------
+//
+
+// The broker's answer, as a client sees it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u64,
+    pub features: Vec<String>,
+    pub capabilities: Vec<String>,
+    pub unsupported: Vec<String>,
+    pub limits: NegotiatedLimits,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NegotiatedLimits {
+    pub max_message_bytes: Option<i64>,
+    pub max_frame_bytes: Option<u64>,
+}
+
+impl Negotiated {
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.iter().any(|c| c == capability.label())
+    }
+}
+
+pub fn hello_request(capabilities: &[&str]) -> Multipart {
+    let body = json!({"version": PROTOCOL_VERSION, "capabilities": capabilities});
+    Multipart::new(vec![HELLO.to_vec(), body.to_string().into_bytes()])
+}
+
+pub fn parse_hello_reply(frames: &[Vec<u8>]) -> Result<Negotiated, String> {
+    let [tag, body] = frames else {
+        return Err(format!(
+            "hello reply has {} frames, expected 2",
+            frames.len()
+        ));
+    };
+    if tag != HELLO {
+        return Err("not a hello reply".to_string());
+    }
+    let mut body: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid hello reply: {}", e))?;
+    let hello = body["hello"].take();
+    if let Some(error) = hello["error"].as_str() {
+        return Err(format!("hello rejected: {}", error));
+    }
+    serde_json::from_value(hello).map_err(|e| format!("invalid hello reply: {}", e))
+}
+
+// The hello a client sends right after connecting, waiting for the answer
+// within the socket's receive timeout.
+pub fn handshake(socket: &zmq::Socket, capabilities: &[&str]) -> Result<Negotiated, String> {
+    hello_request(capabilities)
+        .send(socket, 0)
+        .map_err(|e| format!("cannot send hello: {}", e))?;
+    let reply = socket
+        .recv_multipart(0)
+        .map_err(|e| format!("no hello reply: {}", e))?;
+    parse_hello_reply(&reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: Offer = Offer {
+        features: Capabilities(0b111),
+        max_message_bytes: 4096,
+        max_frame_bytes: 1 << 20,
+    };
+
+    fn ask(capabilities: &[&str], offer: &Offer) -> (Negotiated, Capabilities) {
+        let request = hello_request(capabilities);
+        let (reply, agreed) = negotiate(Some(&request[1]), offer);
+        let reply = parse_hello_reply(&[HELLO.to_vec(), reply]).unwrap();
+        (reply, agreed.unwrap())
+    }
+
+    #[test]
+    fn full_overlap_agrees_on_everything_asked() {
+        let (reply, agreed) = ask(&["fanout", "compression", "chunking"], &OFFER);
+        assert_eq!(agreed, OFFER.features);
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert_eq!(reply.capabilities, ["compression", "chunking", "fanout"]);
+        assert!(reply.unsupported.is_empty());
+        assert_eq!(
+            reply.limits,
+            NegotiatedLimits {
+                max_message_bytes: Some(4096),
+                max_frame_bytes: Some(1 << 20),
+            }
+        );
+    }
+
+    #[test]
+    fn partial_and_zero_overlap() {
+        let offer = Offer {
+            features: Capabilities::of(&[Capability::Chunking, Capability::Fanout]),
+            max_message_bytes: -1,
+            ..OFFER
+        };
+        let (reply, agreed) = ask(&["compression", "fanout", "acks"], &offer);
+        assert_eq!(agreed, Capabilities::of(&[Capability::Fanout]));
+        assert_eq!(reply.features, ["chunking", "fanout"]);
+        assert_eq!(reply.unsupported, ["acks"]);
+        assert!(reply.has(Capability::Fanout));
+        assert!(!reply.has(Capability::Compression));
+        assert_eq!(reply.limits, NegotiatedLimits::default());
+
+        let (reply, agreed) = ask(&["acks", "tracing"], &offer);
+        assert_eq!(agreed, Capabilities::default());
+        assert!(reply.capabilities.is_empty());
+        assert_eq!(reply.unsupported, ["acks", "tracing"]);
+    }
+
+    #[test]
+    fn versions_and_malformed_hellos() {
+        let (reply, agreed) = negotiate(Some(br#"{"version": 7}"#), &OFFER);
+        let reply = parse_hello_reply(&[HELLO.to_vec(), reply]).unwrap();
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert_eq!(agreed, Some(Capabilities::default()));
+        // A bare hello asks for nothing.
+        let (_, agreed) = negotiate(None, &OFFER);
+        assert_eq!(agreed, Some(Capabilities::default()));
+
+        for bad in [&br#"{"version": 0}"#[..], b"not json"] {
+            let (reply, agreed) = negotiate(Some(bad), &OFFER);
+            assert_eq!(agreed, None);
+            let err = parse_hello_reply(&[HELLO.to_vec(), reply]).unwrap_err();
+            assert!(err.starts_with("hello rejected: "), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::IdentityConfig;
+
+//
+// --------------------------- Socket identities -------------------------------
+//
+// Sockets the broker connects with (the HA heartbeat link today) present a
+// fixed ZMQ_ROUTING_ID so the remote side can whitelist and correlate the
+// broker across restarts. Each socket uses its configured `routing_id` when
+// set; otherwise "<base>/<socket>", where the base identity is generated on
+// first use and persisted in `[identity] file` (~/.corky/state/identity).
+
+pub const MAX_IDENTITY_LEN: usize = 255;
+
+// ZeroMQ limits: 1-255 bytes, and a leading zero byte is reserved for the
+// identities ROUTER sockets generate themselves.
+pub fn validate_identity(identity: &[u8]) -> Result<(), String> {
+    if identity.is_empty() || identity.len() > MAX_IDENTITY_LEN {
+        return Err(format!(
+            "routing id must be 1-{} bytes, got {}",
+            MAX_IDENTITY_LEN,
+            identity.len()
+        ));
+    }
+    if identity[0] == 0 {
+        return Err("routing id must not start with a zero byte".to_string());
+    }
+    Ok(())
+}
+
+fn default_identity_file() -> Result<PathBuf, String> {
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    Ok(home.join(".corky").join("state").join("identity"))
+}
+
+fn generate_identity() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    format!("corky-{:016x}", hasher.finish())
+}
+
+// The persisted base identity, created on first use.
+pub fn load_or_create(path: &Path) -> Result<String, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let identity = content.trim().to_string();
+            validate_identity(identity.as_bytes())
+                .map_err(|e| format!("{} in {}", e, path.display()))?;
+            Ok(identity)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let identity = generate_identity();
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+            }
+            fs::write(path, format!("{}\n", identity))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            Ok(identity)
+        }
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+// The routing id for the connect-mode socket named `socket`.
+pub fn routing_id(
+    config: &IdentityConfig,
+    configured: Option<&str>,
+    socket: &str,
+) -> Result<Vec<u8>, String> {
+    let identity = match configured {
+        Some(identity) => identity.to_string(),
+        None => {
+            let path = match &config.file {
+                Some(file) => PathBuf::from(file),
+                None => default_identity_file()?,
+            };
+            format!("{}/{}", load_or_create(&path)?, socket)
+        }
+    };
+    validate_identity(identity.as_bytes())?;
+    Ok(identity.into_bytes())
+}
+
+// Effective routing ids by socket name, for the admin `stats` command.
+#[derive(Default)]
+pub struct Identities {
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Identities {
+    pub fn set(&self, socket: &str, identity: &[u8]) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.insert(socket.to_string(), identity.to_vec());
+    }
+
+    pub fn list(&self) -> Vec<(String, Vec<u8>)> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("corky-identity-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("state").join("identity")
+    }
+
+    #[test]
+    fn validates_identity_limits() {
+        assert!(validate_identity(b"broker-1").is_ok());
+        assert!(validate_identity(&[b'x'; 255]).is_ok());
+        assert!(validate_identity(&[b'x'; 256]).is_err());
+        assert!(validate_identity(b"").is_err());
+        assert!(validate_identity(b"\0abc").is_err());
+    }
+
+    #[test]
+    fn generated_identity_survives_restarts() {
+        let file = temp_file("restart");
+        let config = IdentityConfig {
+            file: Some(file.to_string_lossy().into_owned()),
+        };
+        let first = routing_id(&config, None, "ha").unwrap();
+        assert!(file.exists());
+        // A later run reads the same base identity back.
+        assert_eq!(routing_id(&config, None, "ha").unwrap(), first);
+        assert!(first.ends_with(b"/ha"));
+        assert_ne!(routing_id(&config, None, "bridge").unwrap(), first);
+        // An explicit routing id wins and is validated.
+        assert_eq!(
+            routing_id(&config, Some("edge-7"), "ha").unwrap(),
+            b"edge-7"
+        );
+        assert!(routing_id(&config, Some(""), "ha").is_err());
+        let _ = fs::remove_dir_all(file.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn rejects_a_corrupt_identity_file() {
+        let file = temp_file("corrupt");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "\0bad").unwrap();
+        assert!(load_or_create(&file).unwrap_err().contains("zero byte"));
+        let _ = fs::remove_dir_all(file.parent().unwrap().parent().unwrap());
+    }
+}
@@ -0,0 +1,208 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::EndpointConfig;
+use crate::multipart::Multipart;
+
+//
+// --------------------------- Ingress metadata --------------------------------
+//
+// With [ingress] metadata on, the broker tells workers how and when each
+// request reached it, so they need not trust client-supplied fields for it.
+// A metadata frame goes in right after the client identity, so a worker
+// receives
+//
+//     [metadata, payload..]
+//
+// where the frame is CRKMETA1 followed by JSON:
+//
+//     {"received_ms", "monotonic_us", "endpoint", "principal", "address"}
+//
+// `received_ms` is the wall clock at receipt (ms since the epoch), for telling
+// how stale a request already was; `monotonic_us` counts from broker start and
+// only orders requests against each other. `endpoint` is the client-facing
+// endpoint's name (its address unless [[network.client_facing]] names it),
+// `principal` the connection's ZAP User-Id (a CURVE client's public key, see
+// crate::zap) and `address` the peer address libzmq reports for TCP; both are
+// null when not known. Fan-out copies carry no metadata.
+//
+// Frames starting with CRKMETA1 are the broker's alone: they are removed from
+// client requests before the real one goes in, and from every reply on its
+// way back to a client, so a worker that echoes what it received cannot leak
+// the metadata. Workers should take it off (`IngressMetadata::take`) before
+// handing a message to a crate::chunk::ChunkAssembler, as it differs between
+// the chunks of one transfer.
+
+pub const INGRESS_MAGIC: &[u8; 8] = b"CRKMETA1";
+// Message properties libzmq attaches from the connection.
+pub const USER_ID: &str = "User-Id";
+pub const PEER_ADDRESS: &str = "Peer-Address";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IngressMetadata {
+    pub received_ms: u64,
+    pub monotonic_us: u64,
+    pub endpoint: String,
+    pub principal: Option<String>,
+    pub address: Option<String>,
+}
+
+impl IngressMetadata {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = INGRESS_MAGIC.to_vec();
+        serde_json::to_writer(&mut frame, self).expect("metadata serializes");
+        frame
+    }
+
+    pub fn parse(frame: &[u8]) -> Result<IngressMetadata, String> {
+        let body = frame
+            .strip_prefix(INGRESS_MAGIC)
+            .ok_or("not an ingress metadata frame")?;
+        serde_json::from_slice(body).map_err(|e| format!("invalid ingress metadata: {}", e))
+    }
+
+    // Worker side: remove the metadata frame from the front of a received
+    // request and return it. None, with `frames` untouched, when the broker
+    // added none.
+    pub fn take(frames: &mut Vec<Vec<u8>>) -> Option<IngressMetadata> {
+        if !frames.first().is_some_and(|f| is_metadata(f)) {
+            return None;
+        }
+        IngressMetadata::parse(&frames.remove(0)).ok()
+    }
+}
+
+pub fn is_metadata(frame: &[u8]) -> bool {
+    frame.starts_with(INGRESS_MAGIC)
+}
+
+// Remove metadata frames from `message`, leaving the first `keep` (identities)
+// alone whatever they hold.
+pub fn strip(message: Multipart, keep: usize) -> Multipart {
+    if !message.iter().skip(keep).any(|f| is_metadata(f)) {
+        return message;
+    }
+    let mut index = 0;
+    let mut frames = message.into_frames();
+    frames.retain(|frame| {
+        index += 1;
+        index <= keep || !is_metadata(frame)
+    });
+    Multipart::new(frames)
+}
+
+// Stamps requests in the broker loop; one per broker, knowing every ingress.
+pub struct IngressStamper {
+    started: Instant,
+    endpoints: Vec<String>,
+}
+
+impl IngressStamper {
+    pub fn new(endpoints: &[EndpointConfig], started: Instant) -> Self {
+        let endpoints = endpoints
+            .iter()
+            .map(|e| match e.name.is_empty() {
+                true => e.address.clone(),
+                false => e.name.clone(),
+            })
+            .collect();
+        Self { started, endpoints }
+    }
+
+    // Replace whatever metadata the client sent with the broker's own, right
+    // after the client identity.
+    pub fn stamp(
+        &self,
+        message: Multipart,
+        ingress: usize,
+        principal: Option<String>,
+        address: Option<String>,
+        now: Instant,
+    ) -> Multipart {
+        let mut message = strip(message, 1);
+        let metadata = IngressMetadata {
+            received_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            monotonic_us: now.saturating_duration_since(self.started).as_micros() as u64,
+            endpoint: self.endpoints[ingress].clone(),
+            principal: principal.filter(|p| !p.is_empty()),
+            address: address.filter(|a| !a.is_empty()),
+        };
+        message.insert(1, metadata.encode());
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamper() -> IngressStamper {
+        let endpoints = [
+            EndpointConfig {
+                address: "tcp://*:5559".into(),
+                ..EndpointConfig::default()
+            },
+            EndpointConfig {
+                address: "ipc:///run/corky.sock".into(),
+                name: "local".into(),
+                ..EndpointConfig::default()
+            },
+        ];
+        IngressStamper::new(&endpoints, Instant::now())
+    }
+
+    #[test]
+    fn stamping_replaces_client_metadata() {
+        let forged = IngressMetadata {
+            received_ms: 0,
+            monotonic_us: 0,
+            endpoint: "forged".into(),
+            principal: Some("root".into()),
+            address: None,
+        };
+        let message = Multipart::new(vec![b"cli".to_vec(), forged.encode(), b"job".to_vec()]);
+        let address = Some("10.0.0.7".to_string());
+        let stamped = stamper().stamp(message, 1, Some(String::new()), address, Instant::now());
+        let mut frames = stamped.into_frames().split_off(1);
+        let metadata = IngressMetadata::take(&mut frames).unwrap();
+        assert_eq!(frames, [b"job".to_vec()]);
+        assert_eq!(metadata.endpoint, "local");
+        assert_eq!(metadata.principal, None);
+        assert_eq!(metadata.address.as_deref(), Some("10.0.0.7"));
+        assert!(metadata.received_ms > 0);
+        // Nothing left to take.
+        assert_eq!(IngressMetadata::take(&mut frames), None);
+    }
+
+    #[test]
+    fn strip_spares_identities() {
+        let meta = IngressMetadata::parse(
+            &stamper().stamp(
+                Multipart::new(vec![b"cli".to_vec()]),
+                0,
+                None,
+                None,
+                Instant::now(),
+            )[1],
+        )
+        .unwrap();
+        assert_eq!(meta.endpoint, "tcp://*:5559");
+        // A worker echoing [client, metadata, payload]; the identities stay
+        // even if they happen to look like metadata.
+        let identity = b"CRKMETA1-worker".to_vec();
+        let echoed = Multipart::new(vec![
+            identity.clone(),
+            b"cli".to_vec(),
+            meta.encode(),
+            b"done".to_vec(),
+            meta.encode(),
+        ]);
+        assert_eq!(
+            strip(echoed, 2).into_frames(),
+            [identity, b"cli".to_vec(), b"done".to_vec()]
+        );
+    }
+}
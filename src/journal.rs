@@ -0,0 +1,911 @@
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::JournalConfig;
+use crate::format::{format_message, Envelope, RenderStyle};
+use crate::metrics::{Counter, Registry};
+use crate::seal::Keyring;
+
+//
+// ------------------------------ Traffic journal ------------------------------
+//
+// An append-only record of the requests and replies that crossed the broker,
+// for proving afterwards what was exchanged. Requests are journaled as they
+// arrive from clients, replies as they arrive from workers on their way to a
+// client. The broker thread hands each record to a writer thread over a
+// bounded queue and never waits for the disk; a record that finds the queue
+// full is dropped and counted in corky_journal_dropped_total, and the gap in
+// sequence numbers shows up in `replay journal verify`.
+//
+// The journal is a directory of segments named journal-<first seq>.log,
+// rotated at max_segment_bytes. Each starts with a fixed header
+//
+//     "CRKJRNL1" | version u32 | flags u32 | first seq u64 | created ms u64
+//
+// followed by records, all integers little-endian:
+//
+//     len u32 | crc32 u32 | seq u64 | payload            (len and crc cover
+//                                                          seq and payload)
+//     payload = ts ms u64 | direction u8 | peer u16+bytes
+//               | correlation u16+bytes | frames u32 | (len u32+bytes)..
+//
+// With [encryption] on (FLAG_SEALED), the payload is sealed by crate::seal
+// with "<segment name>:<seq>" as associated data. Old segments are deleted
+// beyond max_segments or once older than max_age_ms. A restarted broker
+// continues the sequence in a new segment.
+
+pub const SEGMENT_MAGIC: &[u8; 8] = b"CRKJRNL1";
+pub const SEGMENT_VERSION: u32 = 1;
+pub const SEGMENT_HEADER_LEN: usize = 32;
+pub const FLAG_SEALED: u32 = 1;
+const RECORD_HEADER_LEN: usize = 8;
+const SEQ_LEN: usize = 8;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    // Leave it to the OS.
+    Never,
+    // When a segment is closed.
+    Rotate,
+    // Every fsync_interval_ms while records are being written.
+    #[default]
+    Interval,
+    // After every record; the slowest.
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalDirection {
+    Request,
+    Reply,
+}
+
+impl JournalDirection {
+    pub fn label(self) -> &'static str {
+        match self {
+            JournalDirection::Request => "request",
+            JournalDirection::Reply => "reply",
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(JournalDirection::Request),
+            1 => Some(JournalDirection::Reply),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalRecord {
+    pub seq: u64,
+    pub ts_ms: u64,
+    pub direction: JournalDirection,
+    // The client the request came from or the reply is addressed to.
+    pub peer: Vec<u8>,
+    pub correlation: Option<String>,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl JournalRecord {
+    fn encode_payload(&self) -> Vec<u8> {
+        let size = self.frames.iter().map(|f| f.len() + 4).sum::<usize>();
+        let mut out = Vec::with_capacity(32 + self.peer.len() + size);
+        out.extend_from_slice(&self.ts_ms.to_le_bytes());
+        out.push(self.direction as u8);
+        put_short(&mut out, &self.peer);
+        put_short(
+            &mut out,
+            self.correlation.as_deref().unwrap_or("").as_bytes(),
+        );
+        out.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in &self.frames {
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(frame);
+        }
+        out
+    }
+
+    pub fn decode_payload(seq: u64, mut payload: &[u8]) -> Result<Self, String> {
+        let ts_ms = u64::from_le_bytes(take(&mut payload, 8)?.try_into().unwrap());
+        let direction =
+            JournalDirection::from_byte(take(&mut payload, 1)?[0]).ok_or("unknown direction")?;
+        let peer = take_short(&mut payload)?.to_vec();
+        let correlation = take_short(&mut payload)?;
+        let correlation =
+            (!correlation.is_empty()).then(|| String::from_utf8_lossy(correlation).into_owned());
+        let count = u32::from_le_bytes(take(&mut payload, 4)?.try_into().unwrap());
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let len = u32::from_le_bytes(take(&mut payload, 4)?.try_into().unwrap());
+            frames.push(take(&mut payload, len as usize)?.to_vec());
+        }
+        if !payload.is_empty() {
+            return Err("trailing bytes".to_string());
+        }
+        Ok(Self {
+            seq,
+            ts_ms,
+            direction,
+            peer,
+            correlation,
+            frames,
+        })
+    }
+
+    // One line for `replay journal inspect`.
+    pub fn render(&self) -> String {
+        format!(
+            "#{} {} {} peer={} correlation={} {}",
+            self.seq,
+            self.ts_ms,
+            self.direction.label(),
+            String::from_utf8_lossy(&self.peer),
+            self.correlation.as_deref().unwrap_or("-"),
+            format_message(&self.frames, Envelope::Topic, RenderStyle::Compact)
+        )
+    }
+}
+
+fn put_short(out: &mut Vec<u8>, bytes: &[u8]) {
+    let bytes = &bytes[..bytes.len().min(u16::MAX as usize)];
+    out.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if input.len() < len {
+        return Err("payload too short".to_string());
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn take_short<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = u16::from_le_bytes(take(input, 2)?.try_into().unwrap());
+    take(input, len as usize)
+}
+
+// CRC-32 (IEEE 802.3), as used by zip and gzip.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub fn default_dir() -> Result<PathBuf, String> {
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    Ok(home.join(".corky").join("journal"))
+}
+
+pub fn journal_dir(config: &JournalConfig) -> Result<PathBuf, String> {
+    match &config.dir {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => default_dir(),
+    }
+}
+
+fn segment_name(first_seq: u64) -> String {
+    format!("journal-{:020}.log", first_seq)
+}
+
+// Segment files in sequence order.
+pub fn segments(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("journal-") && n.ends_with(".log"))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+//
+// ---------------------------------- Reading ----------------------------------
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub version: u32,
+    pub flags: u32,
+    pub first_seq: u64,
+    pub created_ms: u64,
+}
+
+impl SegmentHeader {
+    fn encode(&self) -> [u8; SEGMENT_HEADER_LEN] {
+        let mut out = [0u8; SEGMENT_HEADER_LEN];
+        out[..8].copy_from_slice(SEGMENT_MAGIC);
+        out[8..12].copy_from_slice(&self.version.to_le_bytes());
+        out[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        out[16..24].copy_from_slice(&self.first_seq.to_le_bytes());
+        out[24..32].copy_from_slice(&self.created_ms.to_le_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < SEGMENT_HEADER_LEN {
+            return Err("truncated segment header".to_string());
+        }
+        if &bytes[..8] != SEGMENT_MAGIC {
+            return Err("not a journal segment".to_string());
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let header = Self {
+            version: u32_at(8),
+            flags: u32_at(12),
+            first_seq: u64_at(16),
+            created_ms: u64_at(24),
+        };
+        if header.version != SEGMENT_VERSION {
+            return Err(format!("unsupported segment version {}", header.version));
+        }
+        Ok(header)
+    }
+}
+
+// What could be read from one segment: the intact records, and a description
+// of each damaged or missing part.
+#[derive(Debug, Default)]
+pub struct SegmentScan {
+    pub header: Option<SegmentHeader>,
+    pub records: Vec<JournalRecord>,
+    pub problems: Vec<String>,
+}
+
+// Read a segment, skipping records that fail their CRC and stopping at a
+// truncated tail, as a crash mid-write leaves behind.
+pub fn read_segment(path: &Path, keyring: Option<&Keyring>) -> SegmentScan {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let mut scan = SegmentScan::default();
+            scan.problems.push(format!("cannot read: {}", e));
+            return scan;
+        }
+    };
+    let name = path
+        .file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+    scan_segment(&bytes, &name, keyring)
+}
+
+// The records of a segment file's contents; `name` is its file name, which
+// sealed records are bound to.
+pub fn scan_segment(bytes: &[u8], name: &str, keyring: Option<&Keyring>) -> SegmentScan {
+    let mut scan = SegmentScan::default();
+    let header = match SegmentHeader::decode(bytes) {
+        Ok(header) => header,
+        Err(e) => {
+            scan.problems.push(e);
+            return scan;
+        }
+    };
+    scan.header = Some(header);
+    let sealed = header.flags & FLAG_SEALED != 0;
+    let mut at = SEGMENT_HEADER_LEN;
+    while at < bytes.len() {
+        if bytes.len() - at < RECORD_HEADER_LEN {
+            scan.problems
+                .push(format!("truncated record header at offset {}", at));
+            break;
+        }
+        let len = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap());
+        let start = at + RECORD_HEADER_LEN;
+        if len < SEQ_LEN || bytes.len() - start < len {
+            scan.problems
+                .push(format!("truncated record at offset {}", at));
+            break;
+        }
+        let body = &bytes[start..start + len];
+        at = start + len;
+        if crc32(body) != crc {
+            scan.problems
+                .push(format!("CRC mismatch in record at offset {}", start - 8));
+            continue;
+        }
+        let seq = u64::from_le_bytes(body[..SEQ_LEN].try_into().unwrap());
+        let payload = &body[SEQ_LEN..];
+        let opened = match (sealed, keyring) {
+            (false, _) => Ok(payload.to_vec()),
+            (true, Some(keyring)) => keyring.open(payload, format!("{}:{}", name, seq).as_bytes()),
+            (true, None) => Err("sealed, and no key configured".to_string()),
+        };
+        match opened.and_then(|payload| JournalRecord::decode_payload(seq, &payload)) {
+            Ok(record) => scan.records.push(record),
+            Err(e) => scan.problems.push(format!("record #{}: {}", seq, e)),
+        }
+    }
+    scan
+}
+
+// Selects records for `replay journal inspect`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JournalFilter {
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    pub peer: Option<Vec<u8>>,
+    pub correlation: Option<String>,
+}
+
+impl JournalFilter {
+    pub fn matches(&self, record: &JournalRecord) -> bool {
+        self.since_ms.is_none_or(|since| record.ts_ms >= since)
+            && self.until_ms.is_none_or(|until| record.ts_ms <= until)
+            && self.peer.as_ref().is_none_or(|peer| *peer == record.peer)
+            && self
+                .correlation
+                .as_ref()
+                .is_none_or(|id| record.correlation.as_ref() == Some(id))
+    }
+}
+
+// Matching records across all segments, with the problems met on the way
+// prefixed by their segment.
+pub fn inspect(
+    dir: &Path,
+    keyring: Option<&Keyring>,
+    filter: &JournalFilter,
+) -> io::Result<(Vec<JournalRecord>, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut problems = Vec::new();
+    for path in segments(dir)? {
+        let scan = read_segment(&path, keyring);
+        records.extend(scan.records.into_iter().filter(|r| filter.matches(r)));
+        problems.extend(
+            scan.problems
+                .into_iter()
+                .map(|p| format!("{}: {}", path.display(), p)),
+        );
+    }
+    Ok((records, problems))
+}
+
+// Every problem in the journal: damaged records, truncated segments, and
+// breaks in the sequence within and across segments. Empty when intact.
+pub fn verify(dir: &Path, keyring: Option<&Keyring>) -> io::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut expected: Option<u64> = None;
+    for path in segments(dir)? {
+        let scan = read_segment(&path, keyring);
+        let label = path.display();
+        problems.extend(scan.problems.iter().map(|p| format!("{}: {}", label, p)));
+        if let Some(header) = scan.header {
+            if let Some(first) = scan.records.first() {
+                if first.seq != header.first_seq {
+                    problems.push(format!(
+                        "{}: starts at #{}, header says #{}",
+                        label, first.seq, header.first_seq
+                    ));
+                }
+            }
+            if expected.is_some_and(|next| header.first_seq > next) {
+                let next = expected.unwrap_or_default();
+                problems.push(format!(
+                    "{}: records #{}..#{} missing before this segment",
+                    label,
+                    next,
+                    header.first_seq - 1
+                ));
+            }
+            expected = Some(header.first_seq);
+        }
+        for record in &scan.records {
+            match expected {
+                Some(next) if record.seq > next => problems.push(format!(
+                    "{}: records #{}..#{} missing",
+                    label,
+                    next,
+                    record.seq - 1
+                )),
+                Some(next) if record.seq < next => problems.push(format!(
+                    "{}: record #{} out of order, expected #{}",
+                    label, record.seq, next
+                )),
+                _ => {}
+            }
+            expected = Some(record.seq + 1);
+        }
+    }
+    Ok(problems)
+}
+
+//
+// ---------------------------------- Writing ----------------------------------
+//
+
+struct Segment {
+    path: PathBuf,
+    name: String,
+    file: BufWriter<File>,
+    bytes: u64,
+}
+
+struct Writer {
+    dir: PathBuf,
+    config: JournalConfig,
+    keyring: Option<Arc<Keyring>>,
+    segment: Option<Segment>,
+    dirty: bool,
+    last_sync: Instant,
+}
+
+impl Writer {
+    fn open_segment(&mut self, first_seq: u64) -> io::Result<()> {
+        self.close_segment();
+        let name = segment_name(first_seq);
+        let path = self.dir.join(&name);
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+        let mut file = BufWriter::new(file);
+        let header = SegmentHeader {
+            version: SEGMENT_VERSION,
+            flags: if self.keyring.is_some() {
+                FLAG_SEALED
+            } else {
+                0
+            },
+            first_seq,
+            created_ms: unix_ms(),
+        };
+        file.write_all(&header.encode())?;
+        debug!("(Journal) Opened segment {}", path.display());
+        self.segment = Some(Segment {
+            path,
+            name,
+            file,
+            bytes: SEGMENT_HEADER_LEN as u64,
+        });
+        self.enforce_retention();
+        Ok(())
+    }
+
+    fn close_segment(&mut self) {
+        if let Some(mut segment) = self.segment.take() {
+            let synced = segment.file.flush().and_then(|()| match self.config.fsync {
+                FsyncPolicy::Never => Ok(()),
+                _ => segment.file.get_ref().sync_all(),
+            });
+            if let Err(e) = synced {
+                error!("(Journal) Cannot close {}: {}", segment.path.display(), e);
+            }
+        }
+    }
+
+    fn write(&mut self, record: &JournalRecord) -> io::Result<()> {
+        let full = self
+            .segment
+            .as_ref()
+            .is_none_or(|s| s.bytes >= self.config.max_segment_bytes);
+        if full {
+            self.open_segment(record.seq)?;
+        }
+        let segment = self.segment.as_mut().expect("open segment");
+        let payload = record.encode_payload();
+        let payload = match &self.keyring {
+            Some(keyring) => keyring.seal(
+                &payload,
+                format!("{}:{}", segment.name, record.seq).as_bytes(),
+            ),
+            None => payload,
+        };
+        let mut body = Vec::with_capacity(SEQ_LEN + payload.len());
+        body.extend_from_slice(&record.seq.to_le_bytes());
+        body.extend_from_slice(&payload);
+        segment.file.write_all(&(body.len() as u32).to_le_bytes())?;
+        segment.file.write_all(&crc32(&body).to_le_bytes())?;
+        segment.file.write_all(&body)?;
+        segment.bytes += (RECORD_HEADER_LEN + body.len()) as u64;
+        self.dirty = true;
+        if self.config.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Make what was written readable, and durable where the policy asks.
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(segment) = self.segment.as_mut() else {
+            return Ok(());
+        };
+        segment.file.flush()?;
+        let interval = Duration::from_millis(self.config.fsync_interval_ms);
+        if self.config.fsync == FsyncPolicy::Interval
+            && self.dirty
+            && self.last_sync.elapsed() >= interval
+        {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segment.as_mut() {
+            segment.file.flush()?;
+            segment.file.get_ref().sync_data()?;
+        }
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Delete the oldest closed segments beyond max_segments, and those last
+    // written before max_age_ms.
+    fn enforce_retention(&self) {
+        let Ok(paths) = segments(&self.dir) else {
+            return;
+        };
+        let current = self.segment.as_ref().map(|s| s.path.as_path());
+        let closed: Vec<&PathBuf> = paths
+            .iter()
+            .filter(|p| Some(p.as_path()) != current)
+            .collect();
+        let keep = self.config.max_segments.saturating_sub(1);
+        let excess = closed.len().saturating_sub(keep);
+        let max_age = Duration::from_millis(self.config.max_age_ms);
+        for (i, path) in closed.iter().enumerate() {
+            let aged = self.config.max_age_ms > 0
+                && fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|t| t.elapsed().is_ok_and(|age| age > max_age));
+            if i < excess || aged {
+                match fs::remove_file(path) {
+                    Ok(()) => info!("(Journal) Removed old segment {}", path.display()),
+                    Err(e) => warn!("(Journal) Cannot remove {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    fn run(mut self, records: mpsc::Receiver<JournalRecord>) {
+        let tick = Duration::from_millis(self.config.fsync_interval_ms.max(1));
+        loop {
+            match records.recv_timeout(tick) {
+                Ok(record) => {
+                    if let Err(e) = self.write(&record) {
+                        error!("(Journal) Cannot write record #{}: {}", record.seq, e);
+                    }
+                    // Keep writing while more are queued; flush once drained.
+                    if let Ok(record) = records.try_recv() {
+                        if let Err(e) = self.write(&record) {
+                            error!("(Journal) Cannot write record #{}: {}", record.seq, e);
+                        }
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if let Err(e) = self.flush() {
+                error!("(Journal) Cannot flush: {}", e);
+            }
+        }
+        self.close_segment();
+    }
+}
+
+// The sequence number after the last intact record, so that a restarted
+// broker continues where the previous one stopped.
+fn next_seq(dir: &Path, keyring: Option<&Keyring>) -> io::Result<u64> {
+    for path in segments(dir)?.iter().rev() {
+        let scan = read_segment(path, keyring);
+        if let Some(last) = scan.records.last() {
+            return Ok(last.seq + 1);
+        }
+        if let Some(header) = scan.header {
+            return Ok(header.first_seq);
+        }
+    }
+    Ok(0)
+}
+
+// The broker's handle. Dropping it writes out what is queued and closes the
+// segment.
+pub struct Journal {
+    sender: Option<SyncSender<JournalRecord>>,
+    thread: Option<thread::JoinHandle<()>>,
+    next_seq: Cell<u64>,
+    written: Counter,
+    dropped: Counter,
+}
+
+impl Journal {
+    pub fn open(
+        config: &JournalConfig,
+        keyring: Option<Arc<Keyring>>,
+        metrics: &Registry,
+    ) -> Result<Self, String> {
+        let dir = journal_dir(config)?;
+        fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        let next_seq = next_seq(&dir, keyring.as_deref())
+            .map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+        info!(
+            "(Journal) Journaling to {} from record #{}",
+            dir.display(),
+            next_seq
+        );
+        let writer = Writer {
+            dir,
+            config: config.clone(),
+            keyring,
+            segment: None,
+            dirty: false,
+            last_sync: Instant::now(),
+        };
+        let (sender, receiver) = mpsc::sync_channel(config.queue.max(1));
+        let thread = thread::Builder::new()
+            .name("journal-thread".to_string())
+            .spawn(move || writer.run(receiver))
+            .map_err(|e| format!("cannot start the journal thread: {}", e))?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+            next_seq: Cell::new(next_seq),
+            written: metrics.counter("corky_journal_records_total", &[]),
+            dropped: metrics.counter("corky_journal_dropped_total", &[]),
+        })
+    }
+
+    // Queue one record; `frames` are the message without the peer's identity.
+    pub fn record(
+        &self,
+        direction: JournalDirection,
+        peer: &[u8],
+        correlation: Option<String>,
+        frames: &[Vec<u8>],
+    ) {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq + 1);
+        let record = JournalRecord {
+            seq,
+            ts_ms: unix_ms(),
+            direction,
+            peer: peer.to_vec(),
+            correlation,
+            frames: frames.to_vec(),
+        };
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(record) {
+            Ok(()) => self.written.inc(),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.inc();
+                debug!("(Journal) Queue full, dropped record #{}", seq);
+            }
+        }
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::Key;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("corky-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config(dir: &Path) -> JournalConfig {
+        JournalConfig {
+            enabled: true,
+            dir: Some(dir.display().to_string()),
+            fsync: FsyncPolicy::Never,
+            ..JournalConfig::default()
+        }
+    }
+
+    fn write(config: &JournalConfig, keyring: Option<Arc<Keyring>>, count: usize) {
+        let journal = Journal::open(config, keyring, &Registry::new()).unwrap();
+        for i in 0..count {
+            let direction = if i % 2 == 0 {
+                JournalDirection::Request
+            } else {
+                JournalDirection::Reply
+            };
+            let id = format!("r-{}", i / 2);
+            journal.record(
+                direction,
+                b"alice",
+                Some(id),
+                &[format!("body {}", i).into_bytes()],
+            );
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn records_round_trip_and_continue_after_a_restart() {
+        let dir = temp_dir("roundtrip");
+        let config = config(&dir);
+        write(&config, None, 4);
+        write(&config, None, 2);
+        assert_eq!(segments(&dir).unwrap().len(), 2);
+        let (records, problems) = inspect(&dir, None, &JournalFilter::default()).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        let seqs: Vec<u64> = records.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(records[1].direction, JournalDirection::Reply);
+        assert_eq!(records[1].correlation.as_deref(), Some("r-0"));
+        assert_eq!(records[1].frames, [b"body 1".to_vec()]);
+        assert!(verify(&dir, None).unwrap().is_empty());
+
+        let filter = JournalFilter {
+            correlation: Some("r-1".to_string()),
+            ..JournalFilter::default()
+        };
+        let (matched, _) = inspect(&dir, None, &filter).unwrap();
+        assert_eq!(matched.iter().map(|r| r.seq).collect::<Vec<_>>(), [2, 3]);
+        let filter = JournalFilter {
+            peer: Some(b"bob".to_vec()),
+            ..JournalFilter::default()
+        };
+        assert!(inspect(&dir, None, &filter).unwrap().0.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_truncated_tail_keeps_the_intact_records() {
+        let dir = temp_dir("truncated");
+        let config = config(&dir);
+        write(&config, None, 3);
+        let path = segments(&dir).unwrap().remove(0);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+
+        let scan = read_segment(&path, None);
+        assert_eq!(scan.records.len(), 2);
+        assert!(
+            scan.problems[0].starts_with("truncated record"),
+            "{:?}",
+            scan.problems
+        );
+        // A restart continues after the last intact record, and verify
+        // reports the record lost with the tail.
+        write(&config, None, 1);
+        let problems = verify(&dir, None).unwrap();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("truncated record"));
+        let (records, _) = inspect(&dir, None, &JournalFilter::default()).unwrap();
+        assert_eq!(records.iter().map(|r| r.seq).collect::<Vec<_>>(), [0, 1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupted_record_is_skipped_and_reported() {
+        let dir = temp_dir("corrupted");
+        let config = config(&dir);
+        write(&config, None, 3);
+        let path = segments(&dir).unwrap().remove(0);
+        let mut bytes = fs::read(&path).unwrap();
+        // Flip a payload byte of the second record.
+        let first_len = u32::from_le_bytes(bytes[32..36].try_into().unwrap()) as usize;
+        let second = SEGMENT_HEADER_LEN + RECORD_HEADER_LEN + first_len;
+        bytes[second + RECORD_HEADER_LEN + SEQ_LEN + 2] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let scan = read_segment(&path, None);
+        assert_eq!(
+            scan.records.iter().map(|r| r.seq).collect::<Vec<_>>(),
+            [0, 2]
+        );
+        let problems = verify(&dir, None).unwrap();
+        assert!(problems[0].contains("CRC mismatch"), "{:?}", problems);
+        assert!(
+            problems[1].contains("records #1..#1 missing"),
+            "{:?}",
+            problems
+        );
+
+        fs::write(&path, b"garbage").unwrap();
+        let problems = verify(&dir, None).unwrap();
+        assert!(
+            problems[0].contains("truncated segment header"),
+            "{:?}",
+            problems
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_rotate_and_old_ones_are_removed() {
+        let dir = temp_dir("rotate");
+        let config = JournalConfig {
+            max_segment_bytes: 64,
+            max_segments: 3,
+            ..config(&dir)
+        };
+        write(&config, None, 10);
+        let names: Vec<String> = segments(&dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            [segment_name(7), segment_name(8), segment_name(9)],
+            "one record per segment, the newest three kept"
+        );
+        // Verification starts from the oldest segment kept.
+        let problems = verify(&dir, None).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sealed_journals_need_the_key() {
+        let dir = temp_dir("sealed");
+        let config = config(&dir);
+        let keyring = Arc::new(Keyring::new(Key::from_bytes(&[7; 32]), Vec::new()));
+        write(&config, Some(keyring.clone()), 2);
+        let bytes = fs::read(&segments(&dir).unwrap()[0]).unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"body 0"));
+        let (records, problems) = inspect(&dir, Some(&keyring), &JournalFilter::default()).unwrap();
+        assert_eq!((records.len(), problems.len()), (2, 0));
+        let problems = verify(&dir, None).unwrap();
+        assert!(problems[0].contains("no key configured"), "{:?}", problems);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
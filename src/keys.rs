@@ -0,0 +1,303 @@
+use std::fs;
+use std::io::Write;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+
+use crate::seal::check_permissions;
+
+//
+// ------------------------------ CURVE key files ------------------------------
+//
+// `corky-zmq keygen` writes a keypair as two text files, NAME.key with the
+// secret key (mode 0600) and NAME.key.pub with the public key. Each holds one
+// Z85 key, 40 characters, after `#` comment lines:
+//
+//     # CURVE secret key "broker", written by corky-zmq keygen.
+//     # Keep it private; the public key is in broker.key.pub.
+//     D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs
+//
+// Blank lines and comments are skipped when reading, so a file may be
+// annotated by hand. Keys are handled in Z85 throughout, as in
+// auth.curve_secret_key.
+
+pub const KEY_Z85_LEN: usize = 40;
+pub const SECRET_EXTENSION: &str = "key";
+pub const PUBLIC_EXTENSION: &str = "key.pub";
+pub const DEFAULT_KEY_NAME: &str = "broker";
+
+// In libzmq, which zmq-sys links, but not among the functions it exports.
+extern "C" {
+    fn zmq_curve_public(z85_public_key: *mut c_char, z85_secret_key: *const c_char) -> c_int;
+}
+
+pub struct KeyPair {
+    pub public: String,
+    pub secret: String,
+}
+
+fn curve_available() -> Result<(), String> {
+    match zmq::has("curve") {
+        Some(true) => Ok(()),
+        _ => Err("this libzmq was built without CURVE support".to_string()),
+    }
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<Self, String> {
+        curve_available()?;
+        let pair = zmq::CurveKeyPair::new().map_err(|e| format!("keypair: {}", e))?;
+        let encode = |key: &[u8]| zmq::z85_encode(key).map_err(|e| format!("keypair: {}", e));
+        Ok(Self {
+            public: encode(&pair.public_key)?,
+            secret: encode(&pair.secret_key)?,
+        })
+    }
+
+    // The pair of an existing secret key.
+    pub fn from_secret(secret: &str) -> Result<Self, String> {
+        Ok(Self {
+            public: public_key(secret)?,
+            secret: secret.to_string(),
+        })
+    }
+}
+
+// The public key belonging to a Z85 secret key.
+pub fn public_key(secret: &str) -> Result<String, String> {
+    check_key(secret)?;
+    curve_available()?;
+    let mut z85_secret = [0u8; KEY_Z85_LEN + 1];
+    z85_secret[..KEY_Z85_LEN].copy_from_slice(secret.as_bytes());
+    let mut z85_public = [0u8; KEY_Z85_LEN + 1];
+    let rc = unsafe {
+        zmq_curve_public(
+            z85_public.as_mut_ptr() as *mut c_char,
+            z85_secret.as_ptr() as *const c_char,
+        )
+    };
+    if rc != 0 {
+        let e = zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() });
+        return Err(format!("cannot derive the public key: {}", e));
+    }
+    String::from_utf8(z85_public[..KEY_Z85_LEN].to_vec())
+        .map_err(|_| "libzmq returned a key that is not Z85".to_string())
+}
+
+fn check_key(key: &str) -> Result<(), String> {
+    let decoded = (key.len() == KEY_Z85_LEN)
+        .then(|| zmq::z85_decode(key).ok())
+        .flatten();
+    match decoded {
+        Some(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(format!(
+            "a key is {} characters of Z85, not {:?}",
+            KEY_Z85_LEN, key
+        )),
+    }
+}
+
+// The key in a key file's content.
+pub fn parse_key_file(content: &str) -> Result<String, String> {
+    let mut keys = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let key = keys.next().ok_or("no key in the file")?;
+    if keys.next().is_some() {
+        return Err("more than one key in the file".to_string());
+    }
+    check_key(key)?;
+    Ok(key.to_string())
+}
+
+fn read_key_file(path: &Path) -> Result<String, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse_key_file(&content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// A secret key file, which must not be readable by group or others.
+pub fn read_secret_key(path: &Path) -> Result<String, String> {
+    check_permissions(path)?;
+    read_key_file(path)
+}
+
+pub fn read_public_key(path: &Path) -> Result<String, String> {
+    read_key_file(path)
+}
+
+// ~/.corky/keys
+pub fn default_key_dir() -> Result<PathBuf, String> {
+    dirs::home_dir()
+        .map(|home| home.join(".corky").join("keys"))
+        .ok_or_else(|| "Could not determine home directory".to_string())
+}
+
+// The secret and public key files of `name` in `dir`.
+pub fn key_paths(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{}.{}", name, SECRET_EXTENSION)),
+        dir.join(format!("{}.{}", name, PUBLIC_EXTENSION)),
+    )
+}
+
+pub fn secret_key_file(name: &str, secret: &str) -> String {
+    format!(
+        "# CURVE secret key \"{name}\", written by corky-zmq keygen.\n\
+         # Keep it private; the public key is in {name}.{PUBLIC_EXTENSION}.\n\
+         {secret}\n"
+    )
+}
+
+pub fn public_key_file(name: &str, public: &str) -> String {
+    format!("# CURVE public key \"{name}\", written by corky-zmq keygen.\n{public}\n")
+}
+
+fn write_file(path: &Path, content: &str, mode: u32, force: bool) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            format!(
+                "{} already exists; pass --force to overwrite it",
+                path.display()
+            )
+        }
+        _ => format!("Cannot write {}: {}", path.display(), e),
+    })?;
+    // The mode above only applies to a file that is created.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Cannot chmod {}: {}", path.display(), e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.write_all(content.as_bytes())
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+// Writes NAME.key and NAME.key.pub to `dir`, creating it. Without `force`
+// neither file is written when either exists. Returns the two paths.
+pub fn write_keypair(
+    dir: &Path,
+    name: &str,
+    pair: &KeyPair,
+    force: bool,
+) -> Result<(PathBuf, PathBuf), String> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("{:?} is not a key name", name));
+    }
+    let (secret_path, public_path) = key_paths(dir, name);
+    if !force {
+        if let Some(existing) = [&secret_path, &public_path]
+            .iter()
+            .find(|path| path.exists())
+        {
+            return Err(format!(
+                "{} already exists; pass --force to overwrite it",
+                existing.display()
+            ));
+        }
+    }
+    fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    write_file(
+        &secret_path,
+        &secret_key_file(name, &pair.secret),
+        0o600,
+        force,
+    )?;
+    write_file(
+        &public_path,
+        &public_key_file(name, &pair.public),
+        0o644,
+        force,
+    )?;
+    Ok((secret_path, public_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example keys of the ZeroMQ CURVE documentation.
+    const SECRET: &str = "D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs";
+    const PUBLIC: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("corky-keys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn key_files_skip_comments_and_hold_one_key() {
+        let file = secret_key_file("broker", SECRET);
+        assert!(file.starts_with("# CURVE secret key \"broker\""));
+        assert_eq!(parse_key_file(&file).unwrap(), SECRET);
+        assert_eq!(
+            parse_key_file(&format!("\n  {}  \n# note\n", PUBLIC)).unwrap(),
+            PUBLIC
+        );
+        assert_eq!(
+            parse_key_file("# empty\n").unwrap_err(),
+            "no key in the file"
+        );
+        let two = format!("{}\n{}\n", SECRET, PUBLIC);
+        assert_eq!(
+            parse_key_file(&two).unwrap_err(),
+            "more than one key in the file"
+        );
+        assert!(parse_key_file("tooshort")
+            .unwrap_err()
+            .contains("40 characters"));
+    }
+
+    #[test]
+    fn a_keypair_is_written_once_with_a_private_secret() {
+        let dir = scratch("write");
+        let pair = KeyPair {
+            public: PUBLIC.to_string(),
+            secret: SECRET.to_string(),
+        };
+        let (secret, public) = write_keypair(&dir, "broker", &pair, false).unwrap();
+        assert_eq!(secret, dir.join("broker.key"));
+        assert_eq!(read_secret_key(&secret).unwrap(), SECRET);
+        assert_eq!(read_public_key(&public).unwrap(), PUBLIC);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!((mode(&secret), mode(&public)), (0o600, 0o644));
+        }
+
+        let e = write_keypair(&dir, "broker", &pair, false)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(e.contains("broker.key already exists"), "{}", e);
+        assert!(write_keypair(&dir, "broker", &pair, true).is_ok());
+        assert!(write_keypair(&dir, "../broker", &pair, true).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_public_key_comes_from_the_secret() {
+        if zmq::has("curve") != Some(true) {
+            assert!(public_key(SECRET).unwrap_err().contains("without CURVE"));
+            return;
+        }
+        assert_eq!(public_key(SECRET).unwrap(), PUBLIC);
+        let pair = KeyPair::generate().unwrap();
+        assert_eq!(
+            KeyPair::from_secret(&pair.secret).unwrap().public,
+            pair.public
+        );
+    }
+}
@@ -0,0 +1,60 @@
+// Corky ZMQ — combined XSUB/XPUB proxy and ROUTER broker.
+//
+// The binary in main.rs wires these modules together; they live in a library
+// crate so benches and integration tests can exercise them directly.
+
+pub mod acl;
+pub mod audit;
+pub mod admin;
+pub mod broker;
+pub mod budget;
+pub mod cbor;
+pub mod chaos;
+pub mod chunk;
+pub mod cli;
+pub mod compress;
+pub mod config;
+pub mod events;
+pub mod fanout;
+pub mod format;
+pub mod fs_util;
+pub mod gc;
+pub mod ha;
+pub mod hello;
+pub mod identity;
+pub mod ingress;
+pub mod journal;
+pub mod keys;
+pub mod limits;
+pub mod logfile;
+pub mod logfilter;
+pub mod logjson;
+pub mod logtime;
+pub mod metrics;
+pub mod monitor;
+pub mod msgpack;
+pub mod multipart;
+pub mod peers;
+pub mod pipeline;
+pub mod proxy;
+pub mod quiesce;
+pub mod quota;
+pub mod reload;
+pub mod replay;
+pub mod resolve;
+pub mod restart;
+pub mod runtime;
+pub mod sample;
+pub mod schedule;
+pub mod seal;
+pub mod selftest;
+pub mod soak;
+pub mod socket;
+pub mod state;
+pub mod store;
+pub mod suppress;
+pub mod syslog;
+pub mod timer;
+pub mod topics;
+pub mod watch;
+pub mod zap;
@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::broker::{CLIENT_ROUTER, DIRECT_ROUTER, WORKER_ROUTER};
+use crate::config::LimitsConfig;
+use crate::metrics::Registry;
+use crate::monitor::{peer_address, ConnectionEvent, ConnectionTap, SocketMonitor};
+
+//
+// --------------------------- Connection limits -------------------------------
+//
+// Caps on TCP connections to the broker's sockets: one per socket, and one
+// per source address across all of them. The broker's sockets use the NULL
+// mechanism, so the source address is the only principal they have.
+//
+// Connections are counted from socket monitor events: ACCEPTED, with the
+// peer looked up on the accepted fd, and DISCONNECTED. libzmq reports the
+// accept before it asks the ZAP handler (crate::zap) about the handshake, so
+// the handler drains the monitors first and sees the new connection counted;
+// past a cap it refuses with status 400, which libzmq clients do not retry by
+// themselves. A connection over a cap that is still open after GRACE (no ZAP
+// handler, or a cap lowered meanwhile) is shut down under libzmq instead.
+// During a burst of connects, connections that would have fitted may be
+// refused while the refused ones are still counted.
+//
+// Each connection counted is also passed to the taps added with add_tap(),
+// such as the audit log, as the broker's sockets have no room for a monitor
+// of their own. inproc connections raise no monitor events and are neither
+// counted nor limited. Rejections are counted in corky_connections_rejected_total
+// {socket, reason} and logged at most once per ALERT_INTERVAL per socket and
+// reason.
+
+pub const LIMITED_SOCKETS: [&str; 3] = [DIRECT_ROUTER, CLIENT_ROUTER, WORKER_ROUTER];
+pub const SOCKET_LIMIT: &str = "socket connection limit";
+pub const ADDRESS_LIMIT: &str = "address connection limit";
+// `health` reports near_limit from this share of a cap.
+pub const NEAR_LIMIT_PERCENT: u64 = 90;
+const GRACE: Duration = Duration::from_millis(250);
+const ALERT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn socket_name(name: &str) -> Option<&'static str> {
+    LIMITED_SOCKETS.into_iter().find(|s| *s == name)
+}
+
+// Shut a connection down under libzmq, which then sees it close. The peer is
+// compared first, in case libzmq closed the fd and it was reused meanwhile.
+#[cfg(unix)]
+fn force_close(fd: i32, peer: SocketAddr) -> bool {
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+    // SAFETY: as in monitor::peer_address.
+    let stream = ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+    stream.peer_addr().ok() == Some(peer) && stream.shutdown(std::net::Shutdown::Both).is_ok()
+}
+
+#[cfg(not(unix))]
+fn force_close(_fd: i32, _peer: SocketAddr) -> bool {
+    false
+}
+
+#[derive(Default)]
+struct Alert {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+struct Peer {
+    address: Option<SocketAddr>,
+    // Accepted over a cap and not refused by the ZAP handler yet.
+    over_since: Option<Instant>,
+}
+
+impl Peer {
+    fn ip(&self) -> Option<String> {
+        self.address.map(|a| a.ip().to_string())
+    }
+}
+
+// The monitor events of one broker socket.
+struct Monitor {
+    events: SocketMonitor,
+    peers: HashMap<u32, Peer>,
+}
+
+#[derive(Default)]
+struct State {
+    // 0 is unlimited.
+    max_per_socket: HashMap<&'static str, u32>,
+    max_per_address: u32,
+    live: HashMap<&'static str, u32>,
+    by_address: HashMap<String, u32>,
+    alerts: HashMap<(&'static str, &'static str), Alert>,
+}
+
+impl State {
+    fn live(&self, socket: &str) -> u32 {
+        self.live.get(socket).copied().unwrap_or(0)
+    }
+
+    fn max(&self, socket: &str) -> u32 {
+        self.max_per_socket.get(socket).copied().unwrap_or(0)
+    }
+
+    // The cap `socket` or `address` is over, counting every open connection.
+    fn over(&self, socket: &str, address: Option<&str>) -> Option<&'static str> {
+        let max = self.max(socket);
+        if max > 0 && self.live(socket) > max {
+            return Some(SOCKET_LIMIT);
+        }
+        let count = address.and_then(|a| self.by_address.get(a)).copied();
+        if self.max_per_address > 0 && count.unwrap_or(0) > self.max_per_address {
+            return Some(ADDRESS_LIMIT);
+        }
+        None
+    }
+
+    fn add(&mut self, socket: &'static str, address: Option<&str>) {
+        *self.live.entry(socket).or_default() += 1;
+        if let Some(address) = address {
+            *self.by_address.entry(address.to_string()).or_default() += 1;
+        }
+    }
+
+    fn remove(&mut self, socket: &'static str, address: Option<&str>) {
+        if let Some(live) = self.live.get_mut(socket) {
+            *live = live.saturating_sub(1);
+        }
+        if let Some(address) = address {
+            if let Some(count) = self.by_address.get_mut(address) {
+                *count -= 1;
+                if *count == 0 {
+                    self.by_address.remove(address);
+                }
+            }
+        }
+    }
+}
+
+// Shared between the broker, which registers its sockets, the ZAP handler
+// and the admin socket. Lock order: monitors, then state.
+pub struct ConnectionLimits {
+    monitors: Mutex<HashMap<&'static str, Vec<Monitor>>>,
+    state: Mutex<State>,
+    taps: Mutex<Vec<Arc<dyn ConnectionTap>>>,
+    metrics: Registry,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &LimitsConfig, metrics: &Registry) -> Self {
+        let limits = Self {
+            monitors: Mutex::new(HashMap::new()),
+            state: Mutex::new(State {
+                max_per_address: config.max_per_address,
+                ..State::default()
+            }),
+            taps: Mutex::new(Vec::new()),
+            metrics: metrics.clone(),
+        };
+        for name in config.max_per_socket.keys() {
+            if socket_name(name).is_none() {
+                warn!("(Limits) Ignoring limit for unknown socket {:?}", name);
+            }
+        }
+        for socket in LIMITED_SOCKETS {
+            let max = config.max_per_socket.get(socket).copied().unwrap_or(0);
+            limits.set_socket(socket, max);
+        }
+        limits
+    }
+
+    fn monitors(&self) -> MutexGuard<'_, HashMap<&'static str, Vec<Monitor>>> {
+        self.monitors.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Count the connections of a broker socket, or of all the sockets of one
+    // role (the client-facing ingresses). A restarted broker's sockets replace
+    // the old ones, whose counts are dropped.
+    pub fn watch(
+        &self,
+        context: &zmq::Context,
+        sockets: &[&zmq::Socket],
+        name: &'static str,
+    ) -> Result<(), zmq::Error> {
+        let events = [zmq::SocketEvent::ACCEPTED, zmq::SocketEvent::DISCONNECTED];
+        let mut group = Vec::with_capacity(sockets.len());
+        for socket in sockets {
+            group.push(Monitor {
+                events: SocketMonitor::open(context, socket, name, &events)?,
+                peers: HashMap::new(),
+            });
+        }
+        let mut monitors = self.monitors();
+        if let Some(old) = monitors.insert(name, group) {
+            let mut state = self.state();
+            for peer in old.iter().flat_map(|m| m.peers.values()) {
+                state.remove(name, peer.ip().as_deref());
+            }
+        }
+        Ok(())
+    }
+
+    // Apply the queued monitor events, shut down connections left over a cap
+    // and export corky_broker_connections{socket}.
+    pub fn refresh(&self) {
+        let mut monitors = self.monitors();
+        let mut state = self.state();
+        let now = Instant::now();
+        let mut seen = Vec::new();
+        for (&name, group) in monitors.iter_mut() {
+            for monitor in group.iter_mut() {
+                while let Some(event) = monitor.events.recv() {
+                    let fd = event.value;
+                    if event.is(zmq::SocketEvent::ACCEPTED) {
+                        let mut peer = Peer {
+                            address: peer_address(fd as i32),
+                            over_since: None,
+                        };
+                        let ip = peer.ip();
+                        state.add(name, ip.as_deref());
+                        if state.over(name, ip.as_deref()).is_some() {
+                            peer.over_since = Some(now);
+                        }
+                        seen.push((name, ConnectionEvent::Accepted, fd, peer.address));
+                        monitor.peers.insert(fd, peer);
+                    } else if event.is(zmq::SocketEvent::DISCONNECTED) {
+                        if let Some(peer) = monitor.peers.remove(&fd) {
+                            state.remove(name, peer.ip().as_deref());
+                            seen.push((name, ConnectionEvent::Disconnected, fd, peer.address));
+                        }
+                    }
+                }
+                for (&fd, peer) in monitor.peers.iter_mut() {
+                    if peer
+                        .over_since
+                        .is_none_or(|t| now.duration_since(t) < GRACE)
+                    {
+                        continue;
+                    }
+                    peer.over_since = None;
+                    let (Some(address), ip) = (peer.address, peer.ip()) else {
+                        continue;
+                    };
+                    // Connections closed meanwhile may have made room.
+                    if let Some(reason) = state.over(name, ip.as_deref()) {
+                        self.reject(&mut state, name, reason, &address.ip().to_string());
+                        let closed = force_close(fd as i32, address);
+                        debug!(
+                            "(Limits) Over the limit on {}: closing {} ({})",
+                            name,
+                            address,
+                            if closed { "done" } else { "already gone" }
+                        );
+                    }
+                }
+            }
+            self.metrics
+                .gauge("corky_broker_connections", &[("socket", name)])
+                .set(state.live(name) as i64);
+        }
+        drop((state, monitors));
+        if seen.is_empty() {
+            return;
+        }
+        let taps = self.taps.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for (socket, event, fd, peer) in seen {
+            for tap in &taps {
+                tap.connection(socket, event, fd, peer);
+            }
+        }
+    }
+
+    // Pass each connection counted from now on to `tap` too.
+    pub fn add_tap(&self, tap: Arc<dyn ConnectionTap>) {
+        self.taps
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(tap);
+    }
+
+    fn reject(&self, state: &mut State, socket: &'static str, reason: &'static str, address: &str) {
+        let label = if reason == SOCKET_LIMIT {
+            "socket"
+        } else {
+            "address"
+        };
+        self.metrics
+            .counter(
+                "corky_connections_rejected_total",
+                &[("socket", socket), ("reason", label)],
+            )
+            .inc();
+        let alert = state.alerts.entry((socket, reason)).or_default();
+        let now = Instant::now();
+        if alert
+            .last
+            .is_some_and(|t| now.duration_since(t) < ALERT_INTERVAL)
+        {
+            alert.suppressed += 1;
+            return;
+        }
+        warn!(
+            "(Limits) Refusing connection from {} to {}: {} reached ({} more refused since the last report)",
+            address,
+            socket,
+            reason,
+            std::mem::take(&mut alert.suppressed)
+        );
+        alert.last = Some(now);
+    }
+
+    // Asked by the ZAP handler during the handshake of a connection from
+    // `address`, which the refresh counts if it was not counted yet.
+    pub fn check(&self, socket: &'static str, address: &str) -> Result<(), &'static str> {
+        self.refresh();
+        let mut monitors = self.monitors();
+        let mut state = self.state();
+        let Some(reason) = state.over(socket, Some(address)) else {
+            return Ok(());
+        };
+        self.reject(&mut state, socket, reason, address);
+        // Refused here, so not to be shut down later.
+        let waiting = monitors
+            .get_mut(socket)
+            .into_iter()
+            .flatten()
+            .flat_map(|m| m.peers.values_mut())
+            .filter(|p| p.over_since.is_some() && p.ip().as_deref() == Some(address))
+            .max_by_key(|p| p.over_since);
+        if let Some(peer) = waiting {
+            peer.over_since = None;
+        }
+        Err(reason)
+    }
+
+    pub fn open(&self, socket: &str) -> u32 {
+        self.state().live(socket)
+    }
+
+    // 0 removes the cap.
+    pub fn set_socket(&self, socket: &'static str, max: u32) {
+        self.state().max_per_socket.insert(socket, max);
+        self.metrics
+            .gauge("corky_connection_limit", &[("socket", socket)])
+            .set(max as i64);
+    }
+
+    pub fn set_address(&self, max: u32) {
+        self.state().max_per_address = max;
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let state = self.state();
+        let mut lines: Vec<String> = LIMITED_SOCKETS
+            .into_iter()
+            .map(|socket| {
+                format!(
+                    "{} max={} connections={}",
+                    socket,
+                    state.max(socket),
+                    state.live(socket)
+                )
+            })
+            .collect();
+        let busiest = state.by_address.values().max().copied().unwrap_or(0);
+        lines.push(format!(
+            "address max={} busiest={}",
+            state.max_per_address, busiest
+        ));
+        lines
+    }
+
+    // "<socket> <connections>/<max>" for each capped socket, and whether any
+    // cap is NEAR_LIMIT_PERCENT full.
+    pub fn usage(&self) -> (Vec<String>, bool) {
+        let state = self.state();
+        let mut near = false;
+        let mut lines = Vec::new();
+        let mut near_cap = |name: &str, used: u32, max: u32| {
+            if max == 0 {
+                return;
+            }
+            near |= u64::from(used) * 100 >= u64::from(max) * NEAR_LIMIT_PERCENT;
+            lines.push(format!("{} {}/{}", name, used, max));
+        };
+        for socket in LIMITED_SOCKETS {
+            near_cap(socket, state.live(socket), state.max(socket));
+        }
+        let busiest = state.by_address.values().max().copied().unwrap_or(0);
+        near_cap("address", busiest, state.max_per_address);
+        (lines, near)
+    }
+
+    // Count a connection as the monitor would, for tests without sockets.
+    #[cfg(test)]
+    pub(crate) fn accepted(&self, socket: &'static str, address: &str) {
+        self.state().add(socket, Some(address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::thread;
+
+    use super::*;
+
+    fn limits(per_socket: u32, per_address: u32) -> ConnectionLimits {
+        let config = LimitsConfig {
+            max_per_socket: BTreeMap::from([(CLIENT_ROUTER.to_string(), per_socket)]),
+            max_per_address: per_address,
+        };
+        ConnectionLimits::new(&config, &Registry::new())
+    }
+
+    #[test]
+    fn caps_count_the_connection_being_checked() {
+        let limits = limits(2, 0);
+        for ip in ["10.0.0.1", "10.0.0.2"] {
+            limits.accepted(CLIENT_ROUTER, ip);
+            assert_eq!(limits.check(CLIENT_ROUTER, ip), Ok(()));
+        }
+        limits.accepted(CLIENT_ROUTER, "10.0.0.3");
+        assert_eq!(limits.check(CLIENT_ROUTER, "10.0.0.3"), Err(SOCKET_LIMIT));
+        // Other sockets are not capped.
+        limits.accepted(DIRECT_ROUTER, "10.0.0.3");
+        assert_eq!(limits.check(DIRECT_ROUTER, "10.0.0.3"), Ok(()));
+
+        let limits = self::limits(0, 1);
+        limits.accepted(CLIENT_ROUTER, "10.0.0.1");
+        limits.accepted(DIRECT_ROUTER, "10.0.0.1");
+        assert_eq!(limits.check(DIRECT_ROUTER, "10.0.0.1"), Err(ADDRESS_LIMIT));
+        limits.state().remove(DIRECT_ROUTER, Some("10.0.0.1"));
+        limits.accepted(DIRECT_ROUTER, "10.0.0.2");
+        assert_eq!(limits.check(DIRECT_ROUTER, "10.0.0.2"), Ok(()));
+        let rejected = limits
+            .metrics
+            .counter(
+                "corky_connections_rejected_total",
+                &[("socket", DIRECT_ROUTER), ("reason", "address")],
+            )
+            .get();
+        assert_eq!(rejected, 1);
+    }
+
+    #[test]
+    fn usage_reports_proximity_to_the_caps() {
+        let limits = limits(10, 0);
+        assert_eq!(
+            limits.usage(),
+            (vec!["client_router 0/10".to_string()], false)
+        );
+        for i in 0..9 {
+            limits.accepted(CLIENT_ROUTER, &format!("10.0.0.{}", i));
+        }
+        assert_eq!(
+            limits.usage(),
+            (vec!["client_router 9/10".to_string()], true)
+        );
+        limits.set_socket(CLIENT_ROUTER, 0);
+        assert_eq!(limits.usage(), (Vec::new(), false));
+        limits.set_address(1);
+        assert_eq!(limits.usage(), (vec!["address 1/1".to_string()], true));
+    }
+
+    // Without a ZAP handler to refuse it, the connection over the cap is
+    // shut down after GRACE; the first one keeps working.
+    #[test]
+    fn monitor_counts_and_closes_tcp_connections() {
+        let context = zmq::Context::new();
+        let metrics = Registry::new();
+        let limits = ConnectionLimits::new(&LimitsConfig::default(), &metrics);
+        limits.set_socket(CLIENT_ROUTER, 1);
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_linger(0).unwrap();
+        limits.watch(&context, &[&router], CLIENT_ROUTER).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = router.get_last_endpoint().unwrap().unwrap();
+
+        let dealer = |identity: &[u8]| {
+            let dealer = context.socket(zmq::DEALER).unwrap();
+            dealer.set_linger(0).unwrap();
+            dealer.set_identity(identity).unwrap();
+            dealer.connect(&endpoint).unwrap();
+            dealer
+        };
+        let wait_for = |open: u32| {
+            for _ in 0..200 {
+                limits.refresh();
+                if limits.open(CLIENT_ROUTER) == open {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{} open, wanted {}", limits.open(CLIENT_ROUTER), open);
+        };
+        let first = dealer(b"first");
+        wait_for(1);
+        let second = dealer(b"second");
+        wait_for(2);
+        thread::sleep(GRACE);
+        wait_for(1);
+        first.send("hello", 0).unwrap();
+        router.set_rcvtimeo(1000).unwrap();
+        assert_eq!(router.recv_multipart(0).unwrap()[0], b"first");
+        let rejected = metrics
+            .counter(
+                "corky_connections_rejected_total",
+                &[("socket", CLIENT_ROUTER), ("reason", "socket")],
+            )
+            .get();
+        assert!(rejected >= 1);
+
+        drop((first, second));
+        wait_for(0);
+        let gauge = metrics.gauge("corky_broker_connections", &[("socket", CLIENT_ROUTER)]);
+        assert_eq!(gauge.get(), 0);
+    }
+    #[derive(Default)]
+    struct Seen(Mutex<Vec<(&'static str, ConnectionEvent, Option<SocketAddr>)>>);
+
+    impl ConnectionTap for Seen {
+        fn connection(
+            &self,
+            socket: &'static str,
+            event: ConnectionEvent,
+            _fd: u32,
+            peer: Option<SocketAddr>,
+        ) {
+            self.0.lock().unwrap().push((socket, event, peer));
+        }
+    }
+
+    #[test]
+    fn taps_see_each_connection_with_its_peer() {
+        let context = zmq::Context::new();
+        let limits = ConnectionLimits::new(&LimitsConfig::default(), &Registry::new());
+        let seen = Arc::new(Seen::default());
+        limits.add_tap(seen.clone());
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_linger(0).unwrap();
+        limits.watch(&context, &[&router], DIRECT_ROUTER).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = router.get_last_endpoint().unwrap().unwrap();
+
+        let wait_for = |events: usize| {
+            for _ in 0..200 {
+                limits.refresh();
+                if seen.0.lock().unwrap().len() == events {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{} events, wanted {}", seen.0.lock().unwrap().len(), events);
+        };
+        let dealer = context.socket(zmq::DEALER).unwrap();
+        dealer.set_linger(0).unwrap();
+        dealer.connect(&endpoint).unwrap();
+        wait_for(1);
+        drop(dealer);
+        wait_for(2);
+        let seen = seen.0.lock().unwrap();
+        let (socket, event, peer) = seen[0];
+        assert_eq!((socket, event), (DIRECT_ROUTER, ConnectionEvent::Accepted));
+        assert!(peer.unwrap().ip().is_loopback());
+        assert_eq!(
+            seen[1],
+            (DIRECT_ROUTER, ConnectionEvent::Disconnected, peer)
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::{Log, Metadata, Record};
+
+use crate::config::{LogColor, LogTarget, LoggingConfig};
+use crate::logfilter;
+
+//
+// --------------------------------- Log file ----------------------------------
+//
+// With [logging] file_path set, every log line goes to stderr and to that
+// file too, or to the file alone with target = "file". A line that would
+// take the file past max_size_mb first rotates it: FILE.1 becomes FILE.2 and
+// so on up to max_files, the file becomes FILE.1, and a new one is started.
+// max_size_mb = 0 never rotates. A file that cannot be written is dropped
+// with one warning on stderr, where logging carries on even with target =
+// "file", rather than taking the process down. Lines reach the file with
+// their color codes taken out, so it stays plain whatever stderr gets. A
+// path starting with ~/ is taken from the home directory.
+
+const MB: u64 = 1024 * 1024;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    // Opens `path` for appending, creating it and its directory.
+    pub fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // FILE.n
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let moved = match self.max_files {
+            0 => fs::remove_file(&self.path),
+            n => {
+                for i in (1..n).rev() {
+                    match fs::rename(self.rotated(i), self.rotated(i + 1)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                fs::rename(&self.path, self.rotated(1))
+            }
+        };
+        match moved {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    // Writes one formatted record, rotating first if it would not fit.
+    pub fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        let len = record.len() as u64;
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+// `record` without ANSI escape sequences (ESC [ ... final byte).
+fn without_color(record: &[u8]) -> Cow<'_, [u8]> {
+    if !record.contains(&0x1b) {
+        return Cow::Borrowed(record);
+    }
+    let mut out = Vec::with_capacity(record.len());
+    let mut bytes = record.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != 0x1b {
+            out.push(byte);
+            continue;
+        }
+        if bytes.next() == Some(b'[') {
+            // Parameters and intermediates, up to the final byte.
+            for byte in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&byte) {
+                    break;
+                }
+            }
+        }
+    }
+    Cow::Owned(out)
+}
+
+// The logger's output: stderr unless the file is the only target, and the
+// file until writing to it fails.
+pub struct Tee {
+    stderr: bool,
+    file: Option<RotatingFile>,
+}
+
+impl Tee {
+    pub fn new(file: RotatingFile, stderr: bool) -> Self {
+        Self {
+            stderr,
+            file: Some(file),
+        }
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stderr = io::stderr().lock();
+        if self.stderr {
+            stderr.write_all(buf)?;
+        }
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_record(&without_color(buf)) {
+                let _ = writeln!(
+                    stderr,
+                    "Log file {} cannot be written ({}); logging to stderr only",
+                    file.path().display(),
+                    e
+                );
+                self.file = None;
+                if !self.stderr {
+                    stderr.write_all(buf)?;
+                    self.stderr = true;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            let _ = file.file.flush();
+        }
+        io::stderr().flush()
+    }
+}
+
+// `path` with a leading ~/ in the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+// `path` opened with the rotation settings of `logging`.
+fn open_file(path: &str, logging: &LoggingConfig) -> Result<RotatingFile, String> {
+    let path = expand_home(path);
+    RotatingFile::open(
+        &path,
+        logging.max_size_mb.saturating_mul(MB),
+        logging.max_files,
+    )
+    .map_err(|e| format!("Cannot open log file {}: {}", path.display(), e))
+}
+
+// Where env_logger should write for `logging`: a Tee when it names a file,
+// None for plain stderr. Err when the file cannot be opened.
+pub fn log_target(logging: &LoggingConfig) -> Result<Option<env_logger::Target>, String> {
+    let Some(path) = &logging.file_path else {
+        return Ok(None);
+    };
+    let file = open_file(path, logging)?;
+    let stderr = logging.target != LogTarget::File;
+    Ok(Some(env_logger::Target::Pipe(Box::new(Tee::new(
+        file, stderr,
+    )))))
+}
+
+//
+// ------------------------------ Component files ------------------------------
+//
+// [logging.components] gives components a file of their own, such as broker
+// = "~/.corky/logs/broker.log": their records go there alone, rotated like
+// file_path, and those of the other components to stderr or file_path as
+// before. Components are named as in [logging.filters], by the module a
+// record is logged from.
+
+// Where env_logger should write for the file of one component.
+pub fn component_target(path: &str, logging: &LoggingConfig) -> Result<env_logger::Target, String> {
+    let file = open_file(path, logging)?;
+    Ok(env_logger::Target::Pipe(Box::new(Tee::new(file, false))))
+}
+
+// Passes each record to the logger of its component, or else to `default`.
+pub struct Dispatch<L> {
+    default: L,
+    components: BTreeMap<String, L>,
+}
+
+impl<L: Log> Dispatch<L> {
+    pub fn new(default: L, components: BTreeMap<String, L>) -> Self {
+        Self {
+            default,
+            components,
+        }
+    }
+
+    fn logger(&self, target: &str) -> &L {
+        if self.components.is_empty() {
+            return &self.default;
+        }
+        self.components
+            .get(logfilter::component(target))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl<L: Log> Log for Dispatch<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger(metadata.target()).enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger(record.target()).log(record);
+    }
+
+    fn flush(&self) {
+        self.default.flush();
+        self.components.values().for_each(Log::flush);
+    }
+}
+
+//
+// ---------------------------------- Color ------------------------------------
+//
+// --no-color wins, then an explicit [logging] color, then a non-empty
+// NO_COLOR (no-color.org), and otherwise whether stderr is a terminal.
+
+pub fn use_color(
+    color: LogColor,
+    no_color_flag: bool,
+    no_color_env: Option<&str>,
+    stderr_is_terminal: bool,
+) -> bool {
+    if no_color_flag {
+        return false;
+    }
+    match color {
+        LogColor::Always => true,
+        LogColor::Never => false,
+        LogColor::Auto => no_color_env.is_none_or(str::is_empty) && stderr_is_terminal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("corky-logfile-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn a_full_file_rotates_and_old_ones_are_dropped() {
+        let dir = scratch("rotate");
+        let path = dir.join("nested").join("corky.log");
+        let mut file = RotatingFile::open(&path, 8, 2).unwrap();
+        for record in ["one\n", "two\n", "three\n"] {
+            file.write_record(record.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join("nested").join(name)).unwrap();
+        assert_eq!(read("corky.log"), "three\n");
+        assert_eq!(read("corky.log.1"), "one\ntwo\n");
+        file.write_record(b"four\n").unwrap();
+        assert_eq!(read("corky.log.2"), "one\ntwo\n");
+
+        // Reopened, it counts what is on disk: "four\n" leaves no room.
+        let mut file = RotatingFile::open(&path, 8, 2).unwrap();
+        file.write_record(b"six\n").unwrap();
+        assert_eq!(read("corky.log"), "six\n");
+        assert_eq!(read("corky.log.1"), "four\n");
+        assert_eq!(read("corky.log.2"), "three\n");
+        assert!(!dir.join("nested").join("corky.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_rotation_or_history_the_file_keeps_growing_or_restarts() {
+        let dir = scratch("limits");
+        let path = dir.join("corky.log");
+        let mut unlimited = RotatingFile::open(&path, 0, 5).unwrap();
+        for _ in 0..3 {
+            unlimited.write_record(b"0123456789\n").unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 33);
+
+        let mut no_history = RotatingFile::open(&path, 20, 0).unwrap();
+        no_history.write_record(b"fresh\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fresh\n");
+        assert!(!dir.join("corky.log.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_log_file_is_only_used_when_configured() {
+        assert!(log_target(&LoggingConfig::default()).unwrap().is_none());
+        let dir = scratch("target");
+        fs::create_dir_all(&dir).unwrap();
+        let logging = LoggingConfig {
+            file_path: Some(dir.display().to_string()),
+            ..LoggingConfig::default()
+        };
+        let e = log_target(&logging).map(|_| ()).unwrap_err();
+        assert!(e.starts_with("Cannot open log file"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_file_gets_no_color_codes() {
+        let colored = b"[\x1b[2m2026-10-14T11:05:27Z\x1b[0m \x1b[32mINFO \x1b[0m] hello\n";
+        assert_eq!(
+            &*without_color(colored),
+            b"[2026-10-14T11:05:27Z INFO ] hello\n"
+        );
+        assert!(matches!(without_color(b"plain\n"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn component_records_land_only_in_their_file() {
+        let dir = scratch("components");
+        let logging = LoggingConfig::default();
+        let logger = |path: &Path| {
+            env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Trace)
+                .format(|buf, record| writeln!(buf, "{} {}", record.target(), record.args()))
+                .target(component_target(&path.display().to_string(), &logging).unwrap())
+                .build()
+        };
+        let (proxy, rest) = (dir.join("proxy.log"), dir.join("rest.log"));
+        let components = BTreeMap::from([("proxy".to_string(), logger(&proxy))]);
+        let dispatch = Dispatch::new(logger(&rest), components);
+        for (target, message) in [
+            ("corky_zmq::proxy", "forwarded"),
+            ("corky_zmq::broker", "relayed"),
+            ("corky_zmq::proxy::inner", "subscribed"),
+            ("corky_zmq", "started"),
+        ] {
+            dispatch.log(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(log::Level::Info)
+                    .target(target)
+                    .build(),
+            );
+        }
+        dispatch.flush();
+        assert_eq!(
+            fs::read_to_string(&proxy).unwrap(),
+            "corky_zmq::proxy forwarded\ncorky_zmq::proxy::inner subscribed\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&rest).unwrap(),
+            "corky_zmq::broker relayed\ncorky_zmq started\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            expand_home("~/.corky/logs/a.log"),
+            home.join(".corky/logs/a.log")
+        );
+        assert_eq!(
+            expand_home("/var/log/a.log"),
+            PathBuf::from("/var/log/a.log")
+        );
+    }
+
+    #[test]
+    fn no_color_beats_the_setting_which_beats_the_environment() {
+        assert!(use_color(LogColor::Auto, false, None, true));
+        assert!(!use_color(LogColor::Auto, false, None, false));
+        assert!(!use_color(LogColor::Auto, false, Some("1"), true));
+        assert!(use_color(LogColor::Auto, false, Some(""), true));
+        assert!(use_color(LogColor::Always, false, Some("1"), false));
+        assert!(!use_color(LogColor::Never, false, None, true));
+        assert!(!use_color(LogColor::Always, true, None, true));
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::config::LoggingConfig;
+use crate::reload::level_filter;
+
+//
+// -------------------------- Per-component log levels -------------------------
+//
+// [logging.filters] gives components a level of their own, say broker =
+// "debug" with proxy = "warn", and logging.level stays the level of the rest.
+// A component is the module an event is logged from: corky_zmq::broker is
+// "broker", and the binary, which logs under the bare crate name, is "main".
+// RUST_LOG, when set, replaces all of this and env_logger filters alone.
+//
+// The levels live in a static that a reload, or `log level` on the admin
+// socket, can swap, and log's max level follows the most verbose of them, so
+// an event no component wants is not formatted.
+
+const CRATE: &str = env!("CARGO_CRATE_NAME");
+
+// The components that log, and so can be given a level.
+pub const COMPONENTS: &[&str] = &[
+    "main", "admin", "audit", "broker", "budget", "chaos", "compress", "events", "ha", "journal",
+    "limits", "pipeline", "proxy", "quota", "reload", "replay", "resolve", "restart", "schedule",
+    "soak", "socket", "state", "topics", "watch", "zap",
+];
+
+// The component of a log target: its module in this crate, else the crate
+// it comes from.
+pub fn component(target: &str) -> &str {
+    if target == CRATE {
+        return "main";
+    }
+    let path = target
+        .strip_prefix(CRATE)
+        .and_then(|rest| rest.strip_prefix("::"))
+        .unwrap_or(target);
+    path.split("::").next().unwrap_or(path)
+}
+
+// A level name as [logging] and the admin socket take it.
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    match name.to_lowercase().as_str() {
+        "trace" => Some(LevelFilter::Trace),
+        "debug" => Some(LevelFilter::Debug),
+        "info" => Some(LevelFilter::Info),
+        "warn" => Some(LevelFilter::Warn),
+        "error" => Some(LevelFilter::Error),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Levels {
+    default: LevelFilter,
+    components: BTreeMap<String, LevelFilter>,
+}
+
+impl Levels {
+    pub fn new(logging: &LoggingConfig) -> Self {
+        Self {
+            default: level_filter(&logging.level),
+            components: logging
+                .filters
+                .iter()
+                .map(|(name, level)| (name.clone(), level_filter(level)))
+                .collect(),
+        }
+    }
+
+    // Every component at `level`.
+    pub fn uniform(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            components: BTreeMap::new(),
+        }
+    }
+
+    // The same levels with component `name` at `level`.
+    pub fn with(mut self, name: &str, level: LevelFilter) -> Self {
+        self.components.insert(name.to_string(), level);
+        self
+    }
+
+    // The default level, then "broker debug" for each component with its own.
+    pub fn describe(&self) -> Vec<String> {
+        let name = |level: &LevelFilter| level.as_str().to_lowercase();
+        std::iter::once(name(&self.default))
+            .chain(
+                self.components
+                    .iter()
+                    .map(|(component, level)| format!("{} {}", component, name(level))),
+            )
+            .collect()
+    }
+
+    // The level events logged under `target` must reach.
+    pub fn of(&self, target: &str) -> LevelFilter {
+        self.components
+            .get(component(target))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub fn max(&self) -> LevelFilter {
+        self.components
+            .values()
+            .copied()
+            .fold(self.default, Ord::max)
+    }
+}
+
+static LEVELS: RwLock<Levels> = RwLock::new(Levels {
+    default: LevelFilter::Trace,
+    components: BTreeMap::new(),
+});
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// The levels in force; None when the logger is RUST_LOG's.
+pub fn current() -> Option<Levels> {
+    INSTALLED
+        .load(Ordering::Relaxed)
+        .then(|| LEVELS.read().unwrap_or_else(|e| e.into_inner()).clone())
+}
+
+// Applies `levels` to the logger init() installed.
+pub fn set_levels(levels: Levels) {
+    log::set_max_level(levels.max());
+    *LEVELS.write().unwrap_or_else(|e| e.into_inner()) = levels;
+}
+
+struct Filtered(Box<dyn Log>);
+
+impl Log for Filtered {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = LEVELS
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .of(metadata.target());
+        metadata.level() <= level && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+// Installs `logger`, passing it what the levels of `logging` let through;
+// its env_logger filters must let everything through.
+pub fn init(logger: Box<dyn Log>, logging: &LoggingConfig) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(Filtered(logger)))?;
+    set_levels(Levels::new(logging));
+    INSTALLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_take_their_own_level_and_the_rest_the_default() {
+        assert_eq!(component("corky_zmq"), "main");
+        assert_eq!(component("corky_zmq::broker"), "broker");
+        assert_eq!(component("corky_zmq::broker::inner"), "broker");
+        assert_eq!(component("corky_zmq_soak"), "corky_zmq_soak");
+        assert_eq!(component("mio::poll"), "mio");
+
+        let mut logging = LoggingConfig {
+            level: "info".to_string(),
+            ..LoggingConfig::default()
+        };
+        logging
+            .filters
+            .insert("broker".to_string(), "debug".to_string());
+        logging
+            .filters
+            .insert("proxy".to_string(), "warn".to_string());
+        let levels = Levels::new(&logging);
+        assert_eq!(levels.of("corky_zmq::broker"), LevelFilter::Debug);
+        assert_eq!(levels.of("corky_zmq::proxy"), LevelFilter::Warn);
+        assert_eq!(levels.of("corky_zmq"), LevelFilter::Info);
+        assert_eq!(levels.of("corky_zmq::admin"), LevelFilter::Info);
+        assert_eq!(levels.max(), LevelFilter::Debug);
+
+        assert_eq!(levels.describe(), ["info", "broker debug", "proxy warn"]);
+
+        logging.filters.clear();
+        assert_eq!(Levels::new(&logging).max(), LevelFilter::Info);
+        let one = Levels::uniform(LevelFilter::Warn).with("broker", LevelFilter::Trace);
+        assert_eq!(one.of("corky_zmq::broker"), LevelFilter::Trace);
+        assert_eq!(one.of("corky_zmq::proxy"), LevelFilter::Warn);
+        assert_eq!(parse_level("DEBUG"), Some(LevelFilter::Debug));
+        assert_eq!(parse_level("loud"), None);
+    }
+}
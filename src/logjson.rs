@@ -0,0 +1,171 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::kv::{self, VisitSource};
+use serde_json::{Map, Value};
+
+use crate::logfilter;
+use crate::logtime;
+
+//
+// ------------------------------ JSON log lines -------------------------------
+//
+// logging.format = "json" writes each log event as one JSON object per line,
+// for shippers such as Loki that would otherwise parse the plain lines:
+//
+//     {"bytes":12,"component":"broker","dst":"worker_facing_dealer",
+//      "frames":2,"level":"DEBUG","msg":"Forwarding","payload":"[...]",
+//      "src":"client_facing_router","target":"corky_zmq::broker",
+//      "ts":"2026-10-14T11:05:27.481Z"}
+//
+// `component` comes from the "(Broker)" style prefix of the message, which is
+// left out of `msg`, or else from the module, as [logging.filters] names it. The
+// record's key-value pairs become fields of their own, so call sites that
+// check json() can pass what they would otherwise format into the message.
+// Strings are escaped, newlines included, so a pretty-printed payload stays
+// on its line. `ts` follows logging.timestamp, as in crate::logtime.
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// Set once at startup when the logger is built with format().
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+// The component an event belongs to and its message without the prefix.
+fn component<'a>(message: &'a str, target: &str) -> (String, &'a str) {
+    let prefix = message
+        .strip_prefix('(')
+        .and_then(|rest| rest.split_once(')'))
+        .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphabetic()));
+    match prefix {
+        Some((name, rest)) => (name.to_lowercase(), rest.trim_start()),
+        None => (logfilter::component(target).to_string(), message),
+    }
+}
+
+fn to_json(value: &kv::Value) -> Value {
+    if let Some(flag) = value.to_bool() {
+        return Value::Bool(flag);
+    }
+    if let Some(number) = value.to_u64() {
+        return number.into();
+    }
+    if let Some(number) = value.to_i64() {
+        return number.into();
+    }
+    if let Some(number) = value.to_f64().and_then(serde_json::Number::from_f64) {
+        return Value::Number(number);
+    }
+    match value.to_borrowed_str() {
+        Some(text) => Value::String(text.to_string()),
+        None => Value::String(value.to_string()),
+    }
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        // The fixed fields win over a pair of the same name.
+        self.0
+            .entry(key.as_str().to_string())
+            .or_insert_with(|| to_json(&value));
+        Ok(())
+    }
+}
+
+// The JSON object for `record`, without a newline; `ts` is left out when
+// None.
+pub fn event(record: &log::Record, ts: Option<Value>) -> String {
+    let message = record.args().to_string();
+    let (component, message) = component(&message, record.target());
+    let mut fields = Map::new();
+    if let Some(ts) = ts {
+        fields.insert("ts".to_string(), ts);
+    }
+    fields.insert(
+        "level".to_string(),
+        Value::String(record.level().to_string()),
+    );
+    fields.insert(
+        "target".to_string(),
+        Value::String(record.target().to_string()),
+    );
+    fields.insert("component".to_string(), Value::String(component));
+    fields.insert("msg".to_string(), Value::String(message.to_string()));
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    Value::Object(fields).to_string()
+}
+
+// An env_logger format writing event() lines.
+pub fn format(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let line = event(record, logtime::now());
+    writeln!(buf, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: String) -> Value {
+        assert!(!line.contains('\n'), "{}", line);
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn the_prefix_becomes_the_component() {
+        let line = event(
+            &log::Record::builder()
+                .args(format_args!("(Broker) Worker {} joined", "alice"))
+                .level(log::Level::Info)
+                .target("corky_zmq::broker")
+                .build(),
+            Some(Value::from("2026-10-14T11:05:27Z")),
+        );
+        let event = parsed(line);
+        assert_eq!(event["component"], "broker");
+        assert_eq!(event["msg"], "Worker alice joined");
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["ts"], "2026-10-14T11:05:27Z");
+
+        let (name, message) = component("Shutdown signal received...", "corky_zmq");
+        assert_eq!(
+            (name.as_str(), message),
+            ("main", "Shutdown signal received...")
+        );
+        assert_eq!(component("no prefix", "corky_zmq::proxy").0, "proxy");
+    }
+
+    #[test]
+    fn pairs_become_fields_and_payloads_stay_on_one_line() {
+        let payload = "{\n  \"x\": 1\n}";
+        let pairs: [(&str, kv::Value); 5] = [
+            ("src", kv::Value::from("client_facing_router")),
+            ("frames", kv::Value::from(2usize)),
+            ("bytes", kv::Value::from(12u64)),
+            ("payload", kv::Value::from(payload)),
+            ("msg", kv::Value::from("ignored")),
+        ];
+        let line = event(
+            &log::Record::builder()
+                .args(format_args!("(Broker) Forwarding"))
+                .level(log::Level::Debug)
+                .target("corky_zmq::broker")
+                .key_values(&pairs)
+                .build(),
+            None,
+        );
+        let event = parsed(line);
+        assert_eq!(event["msg"], "Forwarding");
+        assert_eq!(event["src"], "client_facing_router");
+        assert_eq!(event["frames"], 2);
+        assert_eq!(event["bytes"], 12);
+        assert_eq!(event["payload"], payload);
+        assert!(event.get("ts").is_none());
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use env_logger::fmt::style::{AnsiColor, Style};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use log::Level;
+use serde_json::Value;
+
+use crate::config::LogTimestamp;
+
+//
+// ------------------------------ Log timestamps -------------------------------
+//
+// logging.timestamp decides how each log line gives its time:
+//
+//     rfc3339      2026-10-14T13:05:27.481+02:00   local time with its offset
+//     rfc3339_utc  2026-10-14T11:05:27.481Z        the default
+//     epoch_ms     1791975927481                   milliseconds since 1970
+//     none                                         for the journal, which
+//                                                  adds its own
+//
+// The plain format below writes lines as env_logger's own does, as in
+// `[2026-10-14T11:05:27.481Z INFO  corky_zmq::broker] (Broker) ...`, and the
+// JSON format puts the same text in `ts`, or epoch_ms as a number.
+
+static STYLE: AtomicU8 = AtomicU8::new(LogTimestamp::Rfc3339Utc as u8);
+
+// Set once at startup, before the logger is installed.
+pub fn set_timestamp(style: LogTimestamp) {
+    STYLE.store(style as u8, Ordering::Relaxed);
+}
+
+fn style() -> LogTimestamp {
+    match STYLE.load(Ordering::Relaxed) {
+        s if s == LogTimestamp::Rfc3339 as u8 => LogTimestamp::Rfc3339,
+        s if s == LogTimestamp::EpochMs as u8 => LogTimestamp::EpochMs,
+        s if s == LogTimestamp::None as u8 => LogTimestamp::None,
+        _ => LogTimestamp::Rfc3339Utc,
+    }
+}
+
+// `now` as `style` shows it in `tz`; None for none.
+pub fn timestamp(style: LogTimestamp, now: Timestamp, tz: &TimeZone) -> Option<Value> {
+    match style {
+        LogTimestamp::Rfc3339 => {
+            let local = now.to_zoned(tz.clone());
+            Some(Value::String(
+                local.strftime("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+            ))
+        }
+        LogTimestamp::Rfc3339Utc => Some(Value::String(
+            now.strftime("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        )),
+        LogTimestamp::EpochMs => Some(Value::from(now.as_millisecond())),
+        LogTimestamp::None => None,
+    }
+}
+
+// The time of a line logged now, as set_timestamp() says.
+pub fn now() -> Option<Value> {
+    let style = style();
+    match style {
+        LogTimestamp::Rfc3339 => timestamp(style, Timestamp::now(), &TimeZone::system()),
+        _ => timestamp(style, Timestamp::now(), &TimeZone::UTC),
+    }
+}
+
+// An env_logger format for plain lines.
+pub fn format(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let level = buf.default_level_style(record.level());
+    // Brackets are dimmed only when the formatter writes color at all.
+    let subtle = match buf.default_level_style(Level::Info) == Style::new() {
+        true => Style::new(),
+        false => AnsiColor::BrightBlack.on_default(),
+    };
+    write!(buf, "{subtle}[{subtle:#}")?;
+    match now() {
+        Some(Value::String(ts)) => write!(buf, "{} ", ts)?,
+        Some(ts) => write!(buf, "{} ", ts)?,
+        None => {}
+    }
+    write!(
+        buf,
+        "{level}{:<5}{level:#} {}",
+        record.level(),
+        record.target()
+    )?;
+    writeln!(buf, "{subtle}]{subtle:#} {}", record.args())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_style_shows_the_same_instant() {
+        let now: Timestamp = "2026-10-14T11:05:27.481Z".parse().unwrap();
+        let plus_two = TimeZone::fixed(jiff::tz::offset(2));
+        let show = |style| timestamp(style, now, &plus_two);
+        assert_eq!(
+            show(LogTimestamp::Rfc3339),
+            Some(Value::from("2026-10-14T13:05:27.481+02:00"))
+        );
+        assert_eq!(
+            show(LogTimestamp::Rfc3339Utc),
+            Some(Value::from("2026-10-14T11:05:27.481Z"))
+        );
+        assert_eq!(
+            show(LogTimestamp::EpochMs),
+            Some(Value::from(1791975927481i64))
+        );
+        assert_eq!(show(LogTimestamp::None), None);
+
+        // Whole seconds keep their milliseconds, and UTC its offset.
+        let whole: Timestamp = "2026-10-14T11:05:27Z".parse().unwrap();
+        assert_eq!(
+            timestamp(LogTimestamp::Rfc3339, whole, &TimeZone::UTC),
+            Some(Value::from("2026-10-14T11:05:27.000+00:00"))
+        );
+    }
+}
@@ -1,548 +1,358 @@
-use std::fs;
+use std::io::IsTerminal;
 use std::panic;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error, info, warn};
-use serde::Deserialize;
-use serde_json::{self, Value};
-
-//
-// ------------------------------- Constants -----------------------------------
-//
-
-const POLL_TIMEOUT_MS: i64 = 10; // poll timeout for low latency
-const RETRY_BACKOFF_MS: u64 = 3000; // initial backoff between connection retries (ms)
-const MAX_RETRIES: u32 = 10; // maximum retry attempts before giving up
-const MAX_BACKOFF_MS: u64 = 30_000; // maximum backoff interval (ms)
-
-const BYTES_PREVIEW_LEN: usize = 20; // byte preview length for non-UTF8 parts
-const MAX_OBJECT_KEYS: usize = 10; // keys to show when trimming top-level objects
-
-const DEFAULT_PROXY_XSUB_ENDPOINT: &str = "tcp://*:5557";
-const DEFAULT_PROXY_XPUB_ENDPOINT: &str = "tcp://*:5558";
-const DEFAULT_CLIENT_TO_CLIENT_ENDPOINT: &str = "tcp://*:6565";
-const DEFAULT_CLIENT_FACING_ENDPOINT: &str = "tcp://*:5559";
-const DEFAULT_WORKER_FACING_ENDPOINT: &str = "tcp://*:5560";
-
-const PROXY_CONTROL_ENDPOINT: &str = "inproc://proxy-control";
-
-// Poll index constants for broker
-const IDX_DIRECT_ROUTER: usize = 0;
-const IDX_CLIENT_ROUTER: usize = 1;
-const IDX_WORKER_ROUTER: usize = 2;
-
-// Cropping controls (arrays)
-const MAX_DEPTH: usize = 2;                 // limit recursion for performance
-const OUTER_HEAD: usize = 1;                // top-level array head items
-const OUTER_TAIL: usize = 1;                // top-level array tail items
-const OUTER_MIN_CROP_LEN: usize = 5;        // DO NOT crop arrays smaller than this at depth 0
-
-const INNER_MIN_CROP_LEN: usize = 30;       // DO NOT crop inner arrays smaller than this
-const ROW_LIST_HEAD: usize = 1;             // arrays-of-arrays (e.g., OHLCV rows) head
-const ROW_LIST_TAIL: usize = 1;             // arrays-of-arrays (e.g., OHLCV rows) tail
-const SCALAR_LIST_HEAD: usize = 3;          // arrays of scalars/strings head (e.g., colors)
-const SCALAR_LIST_TAIL: usize = 1;          // arrays of scalars/strings tail
-
-// Preferred keys to keep when trimming large top-level objects
-const IMPORTANT_KEYS: &[&str] = &[
-    "id", "symbol", "ticker", "type", "status", "desc", "timeframe", "title",
-];
-
-//
-// ------------------------------- Config --------------------------------------
-//
-
-#[derive(Deserialize, Clone, Default)]
-struct Config {
-    #[serde(default)]
-    logging: LoggingConfig,
-    #[serde(default)]
-    network: NetworkConfig,
-}
-
-#[derive(Deserialize, Clone)]
-struct LoggingConfig {
-    level: String,
-}
-
-impl Default for LoggingConfig {
-    fn default() -> Self {
-        Self {
-            level: "info".to_string(),
-        }
-    }
-}
-
-#[derive(Deserialize, Clone)]
-#[serde(default)] // missing fields inherit from NetworkConfig::default()
-struct NetworkConfig {
-    proxy_xsub_endpoint: String,
-    proxy_xpub_endpoint: String,
-    client_to_client_endpoint: String,
-    client_facing_endpoint: String,
-    worker_facing_endpoint: String,
-}
-
-impl Default for NetworkConfig {
-    fn default() -> Self {
-        Self {
-            proxy_xsub_endpoint: DEFAULT_PROXY_XSUB_ENDPOINT.to_string(),
-            proxy_xpub_endpoint: DEFAULT_PROXY_XPUB_ENDPOINT.to_string(),
-            client_to_client_endpoint: DEFAULT_CLIENT_TO_CLIENT_ENDPOINT.to_string(),
-            client_facing_endpoint: DEFAULT_CLIENT_FACING_ENDPOINT.to_string(),
-            worker_facing_endpoint: DEFAULT_WORKER_FACING_ENDPOINT.to_string(),
-        }
-    }
-}
-
-fn load_config() -> Result<Config, String> {
-    let home_dir = match dirs::home_dir() {
-        Some(dir) => dir,
-        None => return Err("Could not determine home directory".to_string()),
-    };
-    let config_path = home_dir.join(".corky").join("config.toml");
-
-    if !config_path.exists() {
-        return Err(format!(
-            "Configuration file not found at: {}",
-            config_path.display()
-        ));
-    }
-
-    let config_content = fs::read_to_string(&config_path)
-        .map_err(|e| format!("Failed to read config: {}", e))?;
-    let config: Config = toml::from_str(&config_content)
-        .map_err(|e| format!("Failed to parse config: {}", e))?;
-    Ok(config)
-}
+use log::{error, info, warn};
+
+use corky_zmq::admin::run_admin;
+use corky_zmq::audit::AuditLog;
+use corky_zmq::broker::{pin_to_core, run_broker};
+use corky_zmq::chaos::{self, ALLOW_ENV};
+use corky_zmq::cli::{parse_args, Args, Command, USAGE};
+use corky_zmq::config::{
+    chosen_config_path, config_files_in, config_path, endpoint_sources, find_config_path,
+    load_config, load_config_from, migrate_config, print_config, read_config_files,
+    replace_config_file, set_config_flag, set_endpoint_flags, set_lenient_config, set_profile,
+    set_use_defaults, write_default_config, Config, ConfigFormat, LatencyMode, LogFormat,
+    LogTarget, CONFIG_ENV, CONFIG_VERSION,
+};
+use corky_zmq::events::{self, EVENT_TOPIC_PREFIX};
+use corky_zmq::format;
+use corky_zmq::keys::{self, KeyPair, DEFAULT_KEY_NAME};
+use corky_zmq::logfile::{component_target, log_target, use_color, Dispatch};
+use corky_zmq::logfilter;
+use corky_zmq::logjson;
+use corky_zmq::logtime;
+use corky_zmq::proxy::{run_proxy, PROXY_CONTROL_ENDPOINT};
+use corky_zmq::reload::{self, Reloader};
+use corky_zmq::restart::run_with_retries;
+use corky_zmq::runtime::Runtime;
+use corky_zmq::sample::{self, SAMPLE_TOPIC};
+use corky_zmq::seal::Keyring;
+use corky_zmq::socket::{build_context, remove_ipc_files, set_ipc_dir_mode};
+use corky_zmq::store;
+use corky_zmq::suppress;
+use corky_zmq::syslog::{self, SyslogWriter};
+use corky_zmq::zap::ZapHandler;
 
 //
 // ----------------------------- Logger setup ----------------------------------
 //
 
-fn setup_logger(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    // Prefer RUST_LOG; otherwise fall back to config.logging.level.
+// A logger builder that leaves filtering to RUST_LOG when it is set, and
+// otherwise to logfilter. Its lines are plain ones with logging.timestamp.
+fn log_builder(rust_log: bool) -> env_logger::Builder {
     let mut builder = env_logger::Builder::from_env(env_logger::Env::default());
-    if std::env::var("RUST_LOG").is_err() {
-        let level = match config.logging.level.to_lowercase().as_str() {
-            "trace" => log::LevelFilter::Trace,
-            "debug" => log::LevelFilter::Debug,
-            "info" => log::LevelFilter::Info,
-            "warn" => log::LevelFilter::Warn,
-            "error" => log::LevelFilter::Error,
-            _ => log::LevelFilter::Info,
-        };
-        builder.filter_level(level);
-    }
-    builder.init();
-    Ok(())
-}
-
-//
-// -------------------------- Socket configuration -----------------------------
-//
-
-fn configure_socket(socket: &zmq::Socket) -> Result<(), zmq::Error> {
-    socket.set_sndhwm(10_000)?; // Send high water mark (default 1000)
-    socket.set_rcvhwm(10_000)?; // Receive high water mark
-    socket.set_linger(1000)?; // 1s linger on close
-    socket.set_tcp_keepalive(1)?; // Enable TCP keepalive
-    socket.set_tcp_keepalive_idle(60)?;
-    socket.set_tcp_keepalive_intvl(10)?;
-    Ok(())
-}
-
-//
-// --------------------- Recursive JSON array cropping -------------------------
-//
-
-// Robust "row-like" detection: treat as list-of-arrays if >=80% of sampled
-// elements are arrays. Sample up to 32 elements, evenly spread.
-fn is_mostly_arrays(arr: &[Value]) -> bool {
-    let len = arr.len();
-    if len == 0 {
-        return false;
-    }
-    let sample_n = len.clamp(1, 32);
-    let step = len.div_ceil(sample_n);
-    let mut arrays = 0usize;
-    let mut taken = 0usize;
-    let mut i = 0usize;
-    while i < len && taken < sample_n {
-        if matches!(arr[i], Value::Array(_)) {
-            arrays += 1;
-        }
-        taken += 1;
-        i += step;
+    builder.format(logtime::format);
+    if !rust_log {
+        builder.filter_level(log::LevelFilter::Trace);
     }
-    arrays * 100 >= taken * 80
+    builder
 }
 
-fn format_json_pretty(value: &Value) -> String {
-    let cropped = crop_value(value, 0);
-    serde_json::to_string_pretty(&cropped).unwrap_or_else(|_| cropped.to_string())
-}
-
-fn crop_value(value: &Value, depth: usize) -> Value {
-    if depth > MAX_DEPTH {
-        return value.clone();
-    }
-
-    match value {
-        Value::Array(arr) => {
-            // Decide strategy based on depth and element "shape"
-            let row_like = is_mostly_arrays(arr);
-            let (min_len, head, tail) = if depth == 0 {
-                (OUTER_MIN_CROP_LEN, OUTER_HEAD, OUTER_TAIL)
-            } else if row_like {
-                (INNER_MIN_CROP_LEN, ROW_LIST_HEAD, ROW_LIST_TAIL)
-            } else {
-                (INNER_MIN_CROP_LEN, SCALAR_LIST_HEAD, SCALAR_LIST_TAIL)
-            };
-
-            // If small or near head+tail window, don't crop — but still recurse.
-            if arr.len() < min_len || arr.len() <= head + tail {
-                return Value::Array(
-                    arr.iter().map(|v| crop_value(v, depth + 1)).collect::<Vec<_>>(),
-                );
-            }
-
-            // Crop large lists only.
-            let mut out = Vec::with_capacity(head + 1 + tail);
-            for v in arr.iter().take(head) {
-                out.push(crop_value(v, depth + 1));
+fn setup_logger(config: &Config, no_color: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // Prefer RUST_LOG; otherwise fall back to config.logging.level and
+    // [logging.filters], enforced by logfilter so that a reload can change them.
+    let rust_log = std::env::var("RUST_LOG").is_ok();
+    logtime::set_timestamp(config.logging.timestamp);
+    let mut builder = log_builder(rust_log);
+    // A log file or syslog socket that cannot be opened leaves logging on
+    // stderr alone.
+    let target = config.logging.target;
+    let target_error = match target {
+        LogTarget::Syslog => match SyslogWriter::connect(Path::new(syslog::SOCKET)) {
+            Ok(writer) => {
+                builder.target(env_logger::Target::Pipe(Box::new(writer)));
+                builder.format(syslog::format);
+                None
             }
-            let omitted = arr.len() - (head + tail);
-            out.push(Value::String(format!("... ({} more) ...", omitted)));
-            let tail_start = arr.len() - tail;
-            for v in arr.iter().skip(tail_start) {
-                out.push(crop_value(v, depth + 1));
+            Err(e) => Some(format!("Cannot reach syslog at {}: {}", syslog::SOCKET, e)),
+        },
+        LogTarget::Stderr | LogTarget::File => match log_target(&config.logging) {
+            Ok(Some(target)) => {
+                builder.target(target);
+                None
             }
-            Value::Array(out)
+            Ok(None) => None,
+            Err(e) => Some(e),
+        },
+    };
+    let to_stderr = target_error.is_some() || target == LogTarget::Stderr;
+    suppress::configure(&config.logging);
+    // Decided here, as env_logger's own check would look at the Tee.
+    let color = to_stderr
+        && use_color(
+            config.logging.color,
+            no_color,
+            std::env::var("NO_COLOR").ok().as_deref(),
+            std::io::stderr().is_terminal(),
+        );
+    builder.write_style(match color {
+        true => env_logger::WriteStyle::Always,
+        false => env_logger::WriteStyle::Never,
+    });
+    let json = config.logging.format == LogFormat::Json;
+    if json {
+        // Syslog lines wrap the JSON object in their own format.
+        if !(target == LogTarget::Syslog && target_error.is_none()) {
+            builder.format(logjson::format);
         }
-        Value::Object(map) => {
-            // Trim only *top-level* objects by key count; always recurse into values.
-            if depth == 0 && map.len() > MAX_OBJECT_KEYS {
-                let mut trimmed = serde_json::Map::with_capacity(MAX_OBJECT_KEYS + 1);
-
-                // 1) Insert prioritized keys in order, if present.
-                for k in IMPORTANT_KEYS {
-                    if let Some(v) = map.get(*k) {
-                        if trimmed.len() < MAX_OBJECT_KEYS {
-                            trimmed.insert((*k).to_string(), crop_value(v, depth + 1));
-                        }
-                    }
-                }
-                // 2) Fill the remaining budget with other keys in map order.
-                for (k, v) in map.iter() {
-                    if trimmed.len() >= MAX_OBJECT_KEYS {
-                        break;
-                    }
-                    if !trimmed.contains_key(k) {
-                        trimmed.insert(k.clone(), crop_value(v, depth + 1));
-                    }
-                }
-                // 3) Ellipsis marker with remaining count.
-                if map.len() > trimmed.len() {
-                    trimmed.insert(
-                        "...".to_string(),
-                        Value::String(format!("{} more keys", map.len() - trimmed.len())),
-                    );
-                }
-
-                Value::Object(trimmed)
-            } else {
-                let mut new_map = serde_json::Map::with_capacity(map.len());
-                for (k, v) in map.iter() {
-                    new_map.insert(k.clone(), crop_value(v, depth + 1));
+        logjson::set_json(true);
+    }
+    // A component file that cannot be opened leaves its lines with the rest.
+    let mut components = std::collections::BTreeMap::new();
+    let mut component_files = Vec::new();
+    for (name, path) in &config.logging.components {
+        match component_target(path, &config.logging) {
+            Ok(target) => {
+                let mut builder = log_builder(rust_log);
+                builder.target(target);
+                builder.write_style(env_logger::WriteStyle::Never);
+                if json {
+                    builder.format(logjson::format);
                 }
-                Value::Object(new_map)
+                components.insert(name.clone(), builder.build());
+                component_files.push(Ok((name, path)));
             }
+            Err(e) => component_files.push(Err(e)),
         }
-        _ => value.clone(),
     }
-}
-
-//
-// ------------------------ Message formatting ---------------------------------
-//
-
-fn try_parse_json_bytes(part: &[u8]) -> Option<Value> {
-    serde_json::from_slice::<Value>(part).ok()
-}
-
-fn try_parse_json_str(s: &str) -> Option<Value> {
-    serde_json::from_str::<Value>(s).ok()
-}
-
-fn format_part(part: &[u8]) -> String {
-    if let Some(v) = try_parse_json_bytes(part) {
-        return format_json_pretty(&v);
+    let default = builder.build();
+    let max_level = default.filter();
+    let logger = Box::new(Dispatch::new(default, components));
+    if rust_log {
+        log::set_boxed_logger(logger)?;
+        log::set_max_level(max_level);
+    } else {
+        logfilter::init(logger, &config.logging)?;
     }
-
-    if let Ok(s) = std::str::from_utf8(part) {
-        let t = s.trim();
-        if (t.starts_with('{') && t.ends_with('}')) || (t.starts_with('[') && t.ends_with(']')) {
-            if let Some(v) = try_parse_json_str(t) {
-                return format_json_pretty(&v);
-            }
+    match (target_error, target, &config.logging.file_path) {
+        (Some(e), _, _) => warn!("(Main) {}; logging to stderr only", e),
+        (None, LogTarget::Syslog, _) => info!("(Main) Logging to syslog at {}", syslog::SOCKET),
+        (None, LogTarget::File, Some(path)) => info!("(Main) Logging to {}", path),
+        (None, _, Some(path)) => info!("(Main) Logging to {} as well as stderr", path),
+        (None, _, None) => {}
+    }
+    for file in component_files {
+        match file {
+            Ok((name, path)) => info!("(Main) Logging {} to {}", name, path),
+            Err(e) => warn!("(Main) {}; its component logs with the others", e),
         }
-        return format!("\"{}\"", s.replace('"', "\\\""));
     }
+    Ok(())
+}
 
-    if part.len() > BYTES_PREVIEW_LEN {
-        format!("[{} bytes: {:?}...]", part.len(), &part[..BYTES_PREVIEW_LEN])
-    } else {
-        format!("{:?}", part)
+// The config load_config reads, with the path of its main file; None when
+// --use-defaults or CORKY_ALLOW_MISSING_CONFIG let the service run on the
+// defaults. Anything else that fails to load stops the service.
+fn load_service_config(args: &Args) -> (Config, Option<PathBuf>) {
+    set_config_flag(args.config.clone());
+    match load_config() {
+        Ok(config) => {
+            let path = config.files.first().cloned();
+            (config, path)
+        }
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn format_message(parts: &[Vec<u8>]) -> String {
-    if parts.is_empty() {
-        return "[empty message]".to_string();
-    }
-    let rendered: Vec<String> = parts.iter().map(|p| format_part(p)).collect();
-    if rendered.len() == 1 {
-        rendered[0].clone()
-    } else {
-        format!("[{}]", rendered.join(" | "))
+// Several config files in ~/.corky: only the first in CONFIG_FILE_NAMES order
+// is read, which may not be the one that was edited.
+fn warn_shadowed_configs(loaded: Option<&Path>) {
+    let found = match config_path() {
+        Ok(default) => default.parent().map(config_files_in).unwrap_or_default(),
+        Err(_) => return,
+    };
+    if found.len() < 2 || loaded != Some(found[0].as_path()) {
+        return;
     }
+    let ignored: Vec<String> = found[1..].iter().map(|p| p.display().to_string()).collect();
+    warn!(
+        "(Main) Read {} and ignored {}: config.toml comes before config.json, then config.yaml",
+        found[0].display(),
+        ignored.join(", ")
+    );
 }
 
-//
-// ------------------------------ Proxy ----------------------------------------
-//
-
-fn run_proxy(
-    context: &zmq::Context,
-    config: &Config,
-    control_endpoint: &str,
-) -> Result<(), zmq::Error> {
-    let mut xsub_socket = context.socket(zmq::XSUB)?;
-    configure_socket(&xsub_socket)?;
-    xsub_socket.bind(&config.network.proxy_xsub_endpoint)?;
-    info!("(Proxy) XSUB bound to {}", config.network.proxy_xsub_endpoint);
-
-    let mut xpub_socket = context.socket(zmq::XPUB)?;
-    configure_socket(&xpub_socket)?;
-    xpub_socket.bind(&config.network.proxy_xpub_endpoint)?;
-    info!("(Proxy) XPUB bound to {}", config.network.proxy_xpub_endpoint);
-
-    // Control socket for steerable proxy
-    let mut control_socket = context.socket(zmq::PAIR)?;
-    control_socket.set_linger(0)?;
-    control_socket.bind(control_endpoint)?;
-
-    info!("(Proxy) Starting XSUB/XPUB forwarder...");
-    zmq::proxy_steerable(&mut xpub_socket, &mut xsub_socket, &mut control_socket)
+// Writes the default config to --path, or to where the service would read
+// it from.
+fn init_config(args: &Args, path: Option<&Path>, force: bool) {
+    let env = std::env::var(CONFIG_ENV).ok();
+    let path = match path {
+        Some(path) => std::path::absolute(path).map_err(|e| e.to_string()),
+        None => chosen_config_path(args.config.as_deref(), env)
+            .and_then(|chosen| chosen.map_or_else(config_path, Ok)),
+    };
+    match path.and_then(|path| write_default_config(&path, force).map(|_| path)) {
+        Ok(path) => println!("Wrote {}", path.display()),
+        Err(e) => {
+            eprintln!("init-config: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-//
-// ------------------------------ Broker ---------------------------------------
-//
-
-fn forward_message(src: &zmq::Socket, dst: &zmq::Socket, src_name: &str, dst_name: &str) {
-    match src.recv_multipart(0) {
-        Ok(message) => {
-            if log::log_enabled!(log::Level::Debug) {
-                debug!(
-                    "(Broker) Forwarding {} -> {}: {}",
-                    src_name,
-                    dst_name,
-                    format_message(&message)
-                );
-            }
-            match dst.send_multipart(&message, zmq::DONTWAIT) {
-                Ok(_) => {}
-                Err(zmq::Error::EAGAIN) => {
-                    warn!("(Broker) Send would block, dropping message");
-                }
-                Err(e) => {
-                    error!(
-                        "(Broker) Error forwarding {} -> {}: {}",
-                        src_name, dst_name, e
-                    );
-                }
-            }
-        }
-        Err(zmq::Error::EINTR) => {
-            // Interrupted by signal, not an error
+// Writes a CURVE keypair and prints its public key, or with `show` prints the
+// public key of an existing secret key file.
+fn keygen(out_dir: Option<&Path>, name: Option<&str>, force: bool, show: Option<&Path>) {
+    let result = match show {
+        Some(path) => keys::read_secret_key(path)
+            .and_then(|secret| KeyPair::from_secret(&secret))
+            .map(|pair| pair.public),
+        None => out_dir
+            .map_or_else(keys::default_key_dir, |dir| Ok(dir.to_path_buf()))
+            .and_then(|dir| {
+                let pair = KeyPair::generate()?;
+                let name = name.unwrap_or(DEFAULT_KEY_NAME);
+                let (secret, public) = keys::write_keypair(&dir, name, &pair, force)?;
+                eprintln!("Wrote {} and {}", secret.display(), public.display());
+                Ok(pair.public)
+            }),
+    };
+    match result {
+        Ok(public) => println!("{}", public),
+        Err(e) => {
+            eprintln!("keygen: {}", e);
+            std::process::exit(1);
         }
-        Err(e) => error!("(Broker) Error receiving from {}: {}", src_name, e),
     }
 }
 
-fn route_worker_message(worker_router: &zmq::Socket, client_router: &zmq::Socket) {
-    match worker_router.recv_multipart(0) {
-        Ok(message) => {
-            if message.len() < 2 {
-                warn!(
-                    "(Broker) Worker message too short ({} frames): {}",
-                    message.len(),
-                    format_message(&message)
-                );
-                return;
-            }
-
-            // First frame is the worker's identity (added by ROUTER)
-            let worker_id = &message[0];
-            let payload = &message[1..];
-
-            if log::log_enabled!(log::Level::Debug) {
-                debug!(
-                    "(Broker) Received from worker_router: {}",
-                    format_message(&message)
-                );
-            }
-
-            // Echo back to the worker (for testing/acknowledgment)
-            let mut echo_msg = vec![worker_id.clone()];
-            echo_msg.extend_from_slice(payload);
-            debug!("(Broker) Echoing back to worker: {}", format_message(&echo_msg));
-            match worker_router.send_multipart(&echo_msg, zmq::DONTWAIT) {
-                Ok(_) => {}
-                Err(zmq::Error::EAGAIN) => {
-                    warn!("(Broker) Send would block, dropping message");
-                }
-                Err(e) => {
-                    error!("(Broker) Error echoing to worker: {}", e);
-                }
+// Subscribes to every event published at `events` and every sample published
+// at `sample`, and prints each as it arrives, until interrupted. A message it
+// cannot read is reported and skipped.
+fn monitor(events: Option<&str>, sample: Option<&str>) {
+    let fail = |e: String| -> ! {
+        eprintln!("monitor: {}", e);
+        std::process::exit(1);
+    };
+    let context = zmq::Context::new();
+    let subscribe = |endpoint: &str, topic: &[u8]| -> Result<zmq::Socket, String> {
+        let socket = context.socket(zmq::SUB).map_err(|e| e.to_string())?;
+        socket
+            .connect(endpoint)
+            .map_err(|e| format!("cannot connect to {}: {}", endpoint, e))?;
+        socket.set_subscribe(topic).map_err(|e| e.to_string())?;
+        Ok(socket)
+    };
+    type Describe = fn(&[Vec<u8>]) -> Result<String, String>;
+    let mut streams: Vec<(zmq::Socket, Describe)> = Vec::new();
+    if let Some(endpoint) = events {
+        let socket = subscribe(endpoint, EVENT_TOPIC_PREFIX.as_bytes()).unwrap_or_else(|e| fail(e));
+        streams.push((socket, events::describe));
+        eprintln!("monitor: events from {}", endpoint);
+    }
+    if let Some(endpoint) = sample {
+        let socket = subscribe(endpoint, SAMPLE_TOPIC).unwrap_or_else(|e| fail(e));
+        streams.push((socket, sample::describe));
+        eprintln!("monitor: samples from {}", endpoint);
+    }
+    loop {
+        let mut items: Vec<_> = streams
+            .iter()
+            .map(|(socket, _)| socket.as_poll_item(zmq::POLLIN))
+            .collect();
+        zmq::poll(&mut items, -1).unwrap_or_else(|e| fail(e.to_string()));
+        for (item, (socket, describe)) in items.iter().zip(&streams) {
+            if !item.is_readable() {
+                continue;
             }
-
-            // Forward to client_router if there's a valid routing identity
-            if payload.len() >= 2 {
-                match client_router.send_multipart(payload, zmq::DONTWAIT) {
-                    Ok(_) => {}
-                    Err(zmq::Error::EAGAIN) => {
-                        warn!("(Broker) Send would block, dropping message");
-                    }
-                    Err(e) => {
-                        debug!("(Broker) Cannot forward to client: {}", e);
-                    }
-                }
+            match socket.recv_multipart(0).map_err(|e| e.to_string()) {
+                Ok(message) => match describe(&message) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => eprintln!("monitor: {}", e),
+                },
+                Err(e) => fail(e),
             }
         }
-        Err(zmq::Error::EINTR) => {
-            // Interrupted by signal, not an error
-        }
-        Err(e) => error!("(Broker) Error receiving from worker_router: {}", e),
     }
 }
 
-fn route_direct_message(router: &zmq::Socket) {
-    match router.recv_multipart(0) {
-        Ok(msg) => {
-            if log::log_enabled!(log::Level::Debug) {
-                debug!(
-                    "(Broker) Received from direct_router: {}",
-                    format_message(&msg)
-                );
-            }
-
-            if msg.len() == 3 {
-                // Protocol: client sends [target_id, payload] via DEALER.
-                // ROUTER prepends sender identity → [sender_id, target_id, payload].
-                let sender_id = &msg[0];
-                let target_id = &msg[1];
-                let payload = &msg[2];
-
-                match router.send_multipart([target_id, sender_id, payload], zmq::DONTWAIT) {
-                    Ok(_) => {}
-                    Err(zmq::Error::EAGAIN) => {
-                        warn!("(Broker) Send would block, dropping message");
-                    }
-                    Err(e) => {
-                        error!("(Broker) Error sending to direct_router: {}", e);
-                    }
-                }
-            } else {
-                warn!(
-                    "(Broker) Unexpected direct_router message ({} frames): {}",
-                    msg.len(),
-                    format_message(&msg)
-                );
-            }
+// Loads and validates the config the service would read, binding nothing.
+// Exits 1 if it has problems, listing all that validation finds.
+fn check_config_file(args: &Args, quiet: bool) {
+    let env = |name: &str| std::env::var(name).ok();
+    let path = chosen_config_path(args.config.as_deref(), env(CONFIG_ENV))
+        .and_then(|chosen| chosen.map_or_else(find_config_path, Ok));
+    let (config, path) = match path.and_then(|path| load_config_from(&path).map(|c| (c, path))) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("check-config: {}", e);
+            std::process::exit(1);
         }
-        Err(zmq::Error::EINTR) => {
-            // Interrupted by signal, not an error
+    };
+    if quiet {
+        return;
+    }
+    match &config.profile {
+        Some(profile) => println!("config OK: {} (profile {})", path.display(), profile),
+        None => println!("config OK: {}", path.display()),
+    }
+    if config.files.len() > 1 {
+        println!("  files, merged in this order:");
+        for (i, file) in config.files.iter().enumerate() {
+            println!("    {}. {}", i + 1, file.display());
+        }
+    }
+    for (key, value, source) in endpoint_sources(&config, env) {
+        if !value.is_empty() {
+            println!("  {} = {} ({})", key, value, source.label());
         }
-        Err(e) => error!("(Broker) Error receiving from direct_router: {}", e),
     }
 }
 
-fn run_broker(
-    context: &zmq::Context,
-    config: &Config,
-    shutdown: &Arc<AtomicBool>,
-) -> Result<(), zmq::Error> {
-    // (1) ROUTER for direct client<->client messaging
-    let direct_router = context.socket(zmq::ROUTER)?;
-    configure_socket(&direct_router)?;
-    direct_router.bind(&config.network.client_to_client_endpoint)?;
-    info!(
-        "(Broker) direct_router (ROUTER) bound to {}",
-        config.network.client_to_client_endpoint
-    );
-
-    // (2) Client-facing ROUTER (frontend)
-    let client_router = context.socket(zmq::ROUTER)?;
-    configure_socket(&client_router)?;
-    client_router.set_router_mandatory(true)?; // Fail if routing identity doesn't exist
-    client_router.bind(&config.network.client_facing_endpoint)?;
-    info!(
-        "(Broker) client_router (ROUTER) bound to {}",
-        config.network.client_facing_endpoint
-    );
-
-    // (3) Worker-facing ROUTER (backend)
-    let worker_router = context.socket(zmq::ROUTER)?;
-    configure_socket(&worker_router)?;
-    worker_router.bind(&config.network.worker_facing_endpoint)?;
-    info!(
-        "(Broker) worker_router (ROUTER) bound to {}",
-        config.network.worker_facing_endpoint
-    );
-
-    info!("(Broker) Broker loop started. Polling for messages...");
-
-    let mut poll_items = [
-        direct_router.as_poll_item(zmq::POLLIN),
-        client_router.as_poll_item(zmq::POLLIN),
-        worker_router.as_poll_item(zmq::POLLIN),
-    ];
-
-    loop {
-        if shutdown.load(Ordering::SeqCst) {
-            info!("(Broker) Shutdown requested...");
-            return Ok(());
+// Rewrites the config file the service would read for the current schema,
+// or prints the result with `dry_run`.
+fn migrate_config_file(args: &Args, dry_run: bool) {
+    let path = chosen_config_path(args.config.as_deref(), std::env::var(CONFIG_ENV).ok())
+        .and_then(|chosen| chosen.map_or_else(find_config_path, Ok));
+    let migrated = path.and_then(|path| {
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        let migrated = migrate_config(ConfigFormat::of(&path), &content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok((path, content, migrated))
+    });
+    let result = match migrated {
+        Ok((_, content, migrated)) if dry_run => {
+            print!("{}", migrated.unwrap_or(content));
+            Ok(())
         }
-
-        match zmq::poll(&mut poll_items, POLL_TIMEOUT_MS) {
-            Ok(_) => {}
-            Err(zmq::Error::EINTR) => continue, // Signal interrupted, just retry
-            Err(e) => return Err(e),
+        Ok((path, _, None)) => {
+            println!("{} is at config_version {} already", path.display(), CONFIG_VERSION);
+            Ok(())
         }
+        Ok((path, _, Some(migrated))) => replace_config_file(&path, &migrated).map(|_| {
+            println!("Migrated {} to config_version {}", path.display(), CONFIG_VERSION)
+        }),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("migrate-config: {}", e);
+        std::process::exit(1);
+    }
+}
 
-        for (idx, poll_item) in poll_items.iter().enumerate() {
-            if !poll_item.is_readable() {
-                continue;
-            }
-            match idx {
-                IDX_DIRECT_ROUTER => route_direct_message(&direct_router),
-                IDX_CLIENT_ROUTER => forward_message(
-                    &client_router,
-                    &worker_router,
-                    "client_router",
-                    "worker_router",
-                ),
-                IDX_WORKER_ROUTER => route_worker_message(&worker_router, &client_router),
-                unexpected => {
-                    error!("(Broker) Unexpected poll index {}, skipping", unexpected);
-                }
-            }
+// Prints the config the service would run with, as annotated TOML.
+fn print_config_file(args: &Args) {
+    let (config, path) = load_service_config(args);
+    let env = |name: &str| std::env::var(name).ok();
+    let printed = match &path {
+        Some(path) => read_config_files(path).and_then(|files| print_config(&config, &files, env)),
+        None => print_config(&config, &[], env),
+    };
+    match printed {
+        Ok(printed) => print!("{}", printed),
+        Err(e) => {
+            eprintln!("print-config: {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -563,16 +373,89 @@ fn main() {
 }
 
 fn run_main() {
-    // 1) Load configuration and initialize logging
-    let config = load_config().unwrap_or_else(|e| {
-        eprintln!("Config error: {}. Using defaults.", e);
-        Config::default()
+    // 1) Parse the command line, load configuration and initialize logging
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(2);
     });
-    if let Err(e) = setup_logger(&config) {
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Command::InitConfig { path, force } = &args.command {
+        init_config(&args, path.as_deref(), *force);
+        return;
+    }
+    if let Command::Keygen {
+        out_dir,
+        name,
+        force,
+        show,
+    } = &args.command
+    {
+        keygen(out_dir.as_deref(), name.as_deref(), *force, show.as_deref());
+        return;
+    }
+    if let Command::Monitor { events, sample } = &args.command {
+        monitor(events.as_deref(), sample.as_deref());
+        return;
+    }
+    set_lenient_config(args.lenient_config);
+    set_profile(args.profile.clone());
+    set_use_defaults(args.use_defaults);
+    set_endpoint_flags(args.endpoints.clone());
+    if let Command::CheckConfig { quiet } = args.command {
+        check_config_file(&args, quiet);
+        return;
+    }
+    if let Command::MigrateConfig { dry_run } = args.command {
+        migrate_config_file(&args, dry_run);
+        return;
+    }
+    if args.command == Command::PrintConfig || args.print_config_and_exit {
+        print_config_file(&args);
+        return;
+    }
+    let (config, config_path) = load_service_config(&args);
+    let config = Arc::new(config);
+    format::set_formatting(&config.formatting);
+    set_ipc_dir_mode(config.network.ipc_dir_mode);
+    if let Err(e) = setup_logger(&config, args.no_color) {
         eprintln!("Failed to initialize logger: {}", e);
         std::process::exit(1);
     }
     info!("ZMQ Combined Proxy & Broker (Rust Version) - Starting...");
+    if let Some(profile) = &config.profile {
+        info!("(Main) Config profile {} applied", profile);
+    }
+    if config_path.is_none() {
+        warn!("(Main) No config file found; running on the built-in defaults (--use-defaults)");
+    }
+    if config.files.len() > 1 {
+        let files: Vec<String> = config.files.iter().map(|f| f.display().to_string()).collect();
+        info!("(Main) Config read from {}, merged in that order", files.join(", "));
+    }
+    if !config.defaulted_sections.is_empty() {
+        let sections: Vec<String> = config
+            .defaulted_sections
+            .iter()
+            .map(|section| format!("[{}]", section))
+            .collect();
+        warn!(
+            "(Main) Config file is for config_version {} (this build reads {}); sections added \
+             since run on their defaults: {}. `corky-zmq migrate-config` updates the file",
+            config.schema_version(),
+            CONFIG_VERSION,
+            sections.join(", ")
+        );
+    }
+    warn_shadowed_configs(config_path.as_deref());
+    for (key, value, source) in endpoint_sources(&config, |name| std::env::var(name).ok()) {
+        if !value.is_empty() {
+            info!("(Main) {} = {} ({})", key, value, source.label());
+        }
+    }
 
     // 2) Set up signal handling for graceful shutdown
     let shutdown = Arc::new(AtomicBool::new(false));
@@ -584,69 +467,169 @@ fn run_main() {
         error!("Failed to set Ctrl-C handler: {}. Graceful shutdown via Ctrl-C disabled.", e);
     }
 
-    // 3) Create a global ZMQ context
-    let context = zmq::Context::new();
+    // 3) Create a global ZMQ context and the services shared by both components
+    let context = match build_context(&config.context) {
+        Ok(context) => context,
+        Err(e) => {
+            error!("(Main) Cannot set up the ZMQ context: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "(Main) ZMQ context: io_threads = {}, max_sockets = {}",
+        context.get_io_threads().unwrap_or(config.context.io_threads),
+        config.context.max_sockets
+    );
+    let mut runtime = Runtime::new(&config);
+    runtime.config_path = config_path;
+    // Refuse to start rather than write plaintext when the key is unusable.
+    match Keyring::from_config(&config.encryption) {
+        Ok(Some(keyring)) => {
+            info!("(Main) Encryption at rest enabled");
+            runtime.keyring = Some(Arc::new(keyring));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("(Main) Encryption at rest: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    // 4) Start XSUB/XPUB proxy in a background thread
-    let ctx_for_proxy = context.clone();
-    let config_for_proxy = config.clone();
-    let shutdown_proxy = Arc::clone(&shutdown);
-    let control_endpoint = PROXY_CONTROL_ENDPOINT.to_string();
-    let proxy_thread = thread::Builder::new()
-        .name("proxy-thread".to_string())
-        .spawn(move || {
-            let mut retries = 0u32;
-            while !shutdown_proxy.load(Ordering::SeqCst) {
-                match run_proxy(&ctx_for_proxy, &config_for_proxy, &control_endpoint) {
-                    Ok(_) => break,
-                    Err(_) if shutdown_proxy.load(Ordering::SeqCst) => break,
-                    Err(e) => {
-                        retries += 1;
-                        error!("(Proxy) Error: {} (attempt {}/{})", e, retries, MAX_RETRIES);
-                        if retries >= MAX_RETRIES {
-                            error!("(Proxy) Exceeded max retries, shutting down");
-                            shutdown_proxy.store(true, Ordering::SeqCst);
-                            break;
-                        }
-                        let backoff = (RETRY_BACKOFF_MS * 2u64.pow(retries - 1)).min(MAX_BACKOFF_MS);
-                        thread::sleep(Duration::from_millis(backoff));
-                    }
-                }
-            }
-        });
+    match store::open(&config.storage) {
+        Ok(Some(store)) => {
+            info!("(Main) Persistent state in the {} store", store.backend());
+            runtime.store = Some(store);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("(Main) Storage: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Not written is better than not running: the broker starts without it.
+    match AuditLog::open(&config.logging.audit) {
+        Ok(Some(audit)) => {
+            info!("(Main) Auditing connections to {}", audit.path().display());
+            runtime.limits.add_tap(Arc::new(audit));
+        }
+        Ok(None) => {}
+        Err(e) => error!("(Main) Connection audit log: {}", e),
+    }
+
+    // Fault injection needs the environment's consent as well as the config's.
+    let allow_chaos = std::env::var(ALLOW_ENV).ok();
+    if let Err(e) = chaos::check_allowed(&config.chaos, allow_chaos.as_deref()) {
+        error!("(Main) Chaos mode: {}", e);
+        std::process::exit(1);
+    }
+    if config.chaos.enabled {
+        warn!("(Main) ****************************************************************");
+        warn!("(Main) CHAOS MODE: this broker drops, delays, duplicates and corrupts");
+        warn!("(Main) traffic on purpose. Never run it in production.");
+        for line in runtime.chaos.describe() {
+            warn!("(Main) chaos {}", line);
+        }
+        warn!("(Main) ****************************************************************");
+    }
 
-    let proxy_handle = match proxy_thread {
-        Ok(handle) => Some(handle),
+    // The ZAP handler must be bound before the proxy and broker sockets
+    // accept anyone.
+    let zap_handler = match ZapHandler::start(&context, &config.auth, &runtime) {
+        Ok(handler) => handler,
         Err(e) => {
-            error!("(Main) Failed to spawn proxy thread: {}. Running without proxy.", e);
-            None
+            error!("(Main) Failed to start ZAP handler: {}", e);
+            std::process::exit(1);
         }
     };
 
-    // 5) Run the broker loop with auto-recovery
-    let shutdown_broker = Arc::clone(&shutdown);
-    let mut retries = 0u32;
-    while !shutdown_broker.load(Ordering::SeqCst) {
-        match run_broker(&context, &config, &shutdown_broker) {
-            Ok(_) => break,
-            Err(_) if shutdown_broker.load(Ordering::SeqCst) => break,
+    // 4) Start XSUB/XPUB proxy in a background thread
+    let proxy_handle = if config.proxy.enabled {
+        let ctx_for_proxy = context.clone();
+        let config_for_proxy = Arc::clone(&config);
+        let runtime_for_proxy = runtime.clone();
+        let shutdown_proxy = Arc::clone(&shutdown);
+        let control_endpoint = PROXY_CONTROL_ENDPOINT.to_string();
+        let proxy_thread = thread::Builder::new()
+            .name("proxy-thread".to_string())
+            .spawn(move || {
+                run_with_retries(&config_for_proxy.broker, "Proxy", &shutdown_proxy, || {
+                    run_proxy(
+                        &ctx_for_proxy,
+                        &config_for_proxy,
+                        &runtime_for_proxy,
+                        &control_endpoint,
+                    )
+                });
+            });
+
+        match proxy_thread {
+            Ok(handle) => Some(handle),
             Err(e) => {
-                retries += 1;
-                error!("(Broker) Error: {} (attempt {}/{})", e, retries, MAX_RETRIES);
-                if retries >= MAX_RETRIES {
-                    error!("(Broker) Exceeded max retries, shutting down");
-                    shutdown.store(true, Ordering::SeqCst);
-                    break;
+                error!("(Main) Failed to spawn proxy thread: {}. Running without proxy.", e);
+                None
+            }
+        }
+    } else {
+        info!("(Main) Proxy disabled ([proxy] enabled = false)");
+        None
+    };
+
+    // 5) Admin socket on its own thread
+    let admin_handle = if config.admin.enabled {
+        let (ctx, cfg, rt) = (context.clone(), Arc::clone(&config), runtime.clone());
+        let shutdown_admin = Arc::clone(&shutdown);
+        thread::Builder::new()
+            .name("admin-thread".to_string())
+            .spawn(move || {
+                if let Err(e) = run_admin(&ctx, &cfg, &rt, &shutdown_admin) {
+                    error!("(Admin) Error: {}. Admin socket disabled.", e);
                 }
-                let backoff = (RETRY_BACKOFF_MS * 2u64.pow(retries - 1)).min(MAX_BACKOFF_MS);
-                thread::sleep(Duration::from_millis(backoff));
+            })
+            .map_err(|e| error!("(Main) Failed to spawn admin thread: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    // SIGHUP reloads what can change without a restart.
+    let level_from_env = std::env::var("RUST_LOG").is_ok();
+    let reloader = Reloader::new(
+        runtime.config_path.clone(),
+        &config,
+        level_from_env,
+        &runtime.metrics,
+    );
+    let reload_handle = reload::watch(reloader, Arc::clone(&shutdown))
+        .map_err(|e| warn!("(Main) Config reload on SIGHUP disabled: {}", e))
+        .ok();
+
+    // 6) Run the broker loop with auto-recovery
+    if config.broker.latency_mode == LatencyMode::Low {
+        if let Some(core) = config.broker.cpu_core {
+            match pin_to_core(core) {
+                Ok(()) => info!("(Broker) Pinned broker thread to CPU core {}", core),
+                Err(e) => warn!("(Broker) {}; running unpinned", e),
             }
         }
     }
+    if config.broker.enabled {
+        let shutdown_broker = Arc::clone(&shutdown);
+        run_with_retries(&config.broker, "Broker", &shutdown, || {
+            run_broker(&context, &config, &runtime, &shutdown_broker)
+        });
+    } else {
+        // The proxy alone; the main thread waits for a signal or for the
+        // proxy to give up.
+        info!("(Main) Broker disabled ([broker] enabled = false)");
+        while !shutdown.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
 
-    // 6) Signal proxy thread to terminate and join
+    // 7) Signal proxy thread to terminate and join
     if let Some(handle) = proxy_handle {
-        // Send TERMINATE command to steerable proxy
+        // Send TERMINATE command to the proxy
         match context.socket(zmq::PAIR) {
             Ok(control_socket) => {
                 let _ = control_socket.set_linger(0);
@@ -678,52 +661,22 @@ fn run_main() {
             thread::sleep(Duration::from_millis(100));
         }
     }
-    info!("(Main) Graceful shutdown complete.");
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-
-    #[test]
-    fn no_crop_small_top_level_triplet() {
-        // ["qb-python", "rustcharts", ["ok", {...}]]
-        let v = json!(["qb-python", "rustcharts", ["ok", { "x": 1 }]]);
-        let s = format_json_pretty(&v);
-        assert!(
-            !s.contains("... ("),
-            "should not crop small outer arrays; got: {s}"
-        );
+    if let Some(handle) = admin_handle {
+        if handle.join().is_err() {
+            error!("(Main) Admin thread panicked");
+        }
     }
-
-    #[test]
-    fn crop_large_row_list_first_and_last() {
-        // data: many rows -> expect 1 head, ellipsis, 1 tail
-        let mut rows: Vec<Value> = Vec::new();
-        for i in 0..35 {
-            rows.push(json!([i, i + 1, i + 2, i + 3, i + 4, i + 5]));
+    if let Some(handle) = reload_handle {
+        if handle.join().is_err() {
+            error!("(Main) Reload thread panicked");
         }
-        let v = json!({ "data": rows });
-        let s = format_json_pretty(&v);
-        assert!(
-            s.matches("... (").count() >= 1,
-            "expected an ellipsis for large data arrays"
-        );
-        assert!(s.contains("[0, 1, 2, 3, 4, 5]"));
-        assert!(s.contains("[34, 35, 36, 37, 38, 39]"));
     }
-
-    #[test]
-    fn crop_large_scalar_list_show_head_tail() {
-        let colors: Vec<Value> = (0..64)
-            .map(|i| Value::String(format!("#{:06X}", i)))
-            .collect();
-        let v = json!({ "candle_colors": colors });
-        let s = format_json_pretty(&v);
-        assert!(
-            s.matches("... (").count() >= 1,
-            "expected an ellipsis for large scalar arrays"
-        );
+    drop(zap_handler);
+    for path in remove_ipc_files() {
+        info!("(Main) Removed socket file {}", path.display());
+    }
+    if let Some(path) = runtime.endpoints.remove_file() {
+        info!("(Main) Removed endpoints file {}", path.display());
     }
+    info!("(Main) Graceful shutdown complete.");
 }
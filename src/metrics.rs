@@ -0,0 +1,393 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+//
+// ------------------------------- Metrics -------------------------------------
+//
+// Handles are registered once, when a socket or component is set up, and the
+// hot path only touches the atomics behind them: no name lookup and no lock
+// per message. The registry's mutex guards the list of metrics and is taken
+// by registration and snapshots, never by updates.
+//
+// All updates use Relaxed ordering. A snapshot is not a single point in
+// time, but every value in it is one the metric actually held, counters never
+// go backwards between snapshots, and a histogram's count is derived from its
+// buckets so the two always agree.
+
+// Label pairs attached to a metric, e.g. [("socket", "client_router")].
+pub type Label = (&'static str, &'static str);
+
+// A label value only known at runtime, such as a configured endpoint. Each
+// distinct value is leaked once, so restarts do not leak it again.
+pub fn label_value(value: &str) -> &'static str {
+    static VALUES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+    let mut values = VALUES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(known) = values.iter().find(|v| **v == value) {
+        return known;
+    }
+    let leaked: &'static str = Box::leak(value.to_string().into_boxed_str());
+    values.push(leaked);
+    leaked
+}
+
+// Power-of-two byte buckets from 64B to 16MB, for message sizes.
+pub const SIZE_BUCKETS: &[u64] = &[
+    64,
+    256,
+    1024,
+    4096,
+    16 * 1024,
+    64 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+    16 * 1024 * 1024,
+];
+
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add(&self, delta: i64) {
+        self.0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct HistogramCore {
+    bounds: &'static [u64],
+    // One bucket per bound (value <= bound) plus a final overflow bucket.
+    buckets: Box<[AtomicU64]>,
+    sum: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct Histogram(Arc<HistogramCore>);
+
+impl Histogram {
+    // `bounds` must be sorted ascending.
+    pub fn new(bounds: &'static [u64]) -> Self {
+        debug_assert!(bounds.windows(2).all(|w| w[0] < w[1]), "unsorted bounds");
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self(Arc::new(HistogramCore {
+            bounds,
+            buckets,
+            sum: AtomicU64::new(0),
+        }))
+    }
+
+    #[inline]
+    pub fn observe(&self, value: u64) {
+        let core = &*self.0;
+        let idx = core.bounds.partition_point(|&b| b < value);
+        core.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        core.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let core = &*self.0;
+        let buckets: Vec<u64> = core
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        HistogramSnapshot {
+            bounds: core.bounds,
+            count: buckets.iter().sum(),
+            buckets,
+            sum: core.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [u64],
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(HistogramSnapshot),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Vec<Label>,
+    pub value: Value,
+}
+
+#[derive(Clone)]
+enum Handle {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+struct Registered {
+    name: &'static str,
+    labels: Vec<Label>,
+    handle: Handle,
+}
+
+// Cheap to clone; clones share the same metrics.
+#[derive(Clone, Default)]
+pub struct Registry {
+    metrics: Arc<Mutex<Vec<Registered>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registering an existing name/labels pair returns the existing handle,
+    // so a component restarted by the retry loop keeps counting from where
+    // it left off.
+    pub fn counter(&self, name: &'static str, labels: &[Label]) -> Counter {
+        match self.register(name, labels, || Handle::Counter(Counter::default())) {
+            Handle::Counter(c) => c,
+            _ => panic!("metric {} already registered with another type", name),
+        }
+    }
+
+    pub fn gauge(&self, name: &'static str, labels: &[Label]) -> Gauge {
+        match self.register(name, labels, || Handle::Gauge(Gauge::default())) {
+            Handle::Gauge(g) => g,
+            _ => panic!("metric {} already registered with another type", name),
+        }
+    }
+
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        labels: &[Label],
+        bounds: &'static [u64],
+    ) -> Histogram {
+        match self.register(name, labels, || Handle::Histogram(Histogram::new(bounds))) {
+            Handle::Histogram(h) => h,
+            _ => panic!("metric {} already registered with another type", name),
+        }
+    }
+
+    fn register(
+        &self,
+        name: &'static str,
+        labels: &[Label],
+        make: impl FnOnce() -> Handle,
+    ) -> Handle {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(existing) = metrics
+            .iter()
+            .find(|m| m.name == name && m.labels.as_slice() == labels)
+        {
+            return existing.handle.clone();
+        }
+        let handle = make();
+        metrics.push(Registered {
+            name,
+            labels: labels.to_vec(),
+            handle: handle.clone(),
+        });
+        handle
+    }
+
+    // Read every metric. Writers are never blocked; only registration waits.
+    pub fn snapshot(&self) -> Vec<Sample> {
+        let metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics
+            .iter()
+            .map(|m| Sample {
+                name: m.name,
+                labels: m.labels.clone(),
+                value: match &m.handle {
+                    Handle::Counter(c) => Value::Counter(c.get()),
+                    Handle::Gauge(g) => Value::Gauge(g.get()),
+                    Handle::Histogram(h) => Value::Histogram(h.snapshot()),
+                },
+            })
+            .collect()
+    }
+}
+
+fn write_labels(out: &mut String, labels: &[Label], extra: Option<(&str, &str)>) {
+    if labels.is_empty() && extra.is_none() {
+        return;
+    }
+    out.push('{');
+    let pairs = labels.iter().copied().chain(extra);
+    for (i, (key, value)) in pairs.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}=\"{}\"", key, value);
+    }
+    out.push('}');
+}
+
+// Prometheus text exposition format for a snapshot.
+pub fn render_prometheus(samples: &[Sample]) -> String {
+    let mut out = String::new();
+    let mut last_name = "";
+    for sample in samples {
+        if sample.name != last_name {
+            let kind = match sample.value {
+                Value::Counter(_) => "counter",
+                Value::Gauge(_) => "gauge",
+                Value::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# TYPE {} {}", sample.name, kind);
+            last_name = sample.name;
+        }
+        match &sample.value {
+            Value::Counter(v) => {
+                out.push_str(sample.name);
+                write_labels(&mut out, &sample.labels, None);
+                let _ = writeln!(out, " {}", v);
+            }
+            Value::Gauge(v) => {
+                out.push_str(sample.name);
+                write_labels(&mut out, &sample.labels, None);
+                let _ = writeln!(out, " {}", v);
+            }
+            Value::Histogram(h) => {
+                let mut cumulative = 0;
+                for (i, count) in h.buckets.iter().enumerate() {
+                    cumulative += count;
+                    let le = h
+                        .bounds
+                        .get(i)
+                        .map(|b| b.to_string())
+                        .unwrap_or_else(|| "+Inf".to_string());
+                    let _ = write!(out, "{}_bucket", sample.name);
+                    write_labels(&mut out, &sample.labels, Some(("le", &le)));
+                    let _ = writeln!(out, " {}", cumulative);
+                }
+                let _ = write!(out, "{}_sum", sample.name);
+                write_labels(&mut out, &sample.labels, None);
+                let _ = writeln!(out, " {}", h.sum);
+                let _ = write!(out, "{}_count", sample.name);
+                write_labels(&mut out, &sample.labels, None);
+                let _ = writeln!(out, " {}", h.count);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn registration_is_idempotent() {
+        let registry = Registry::new();
+        let a = registry.counter("requests_total", &[("socket", "a")]);
+        let again = registry.counter("requests_total", &[("socket", "a")]);
+        let b = registry.counter("requests_total", &[("socket", "b")]);
+        a.inc();
+        again.inc();
+        b.add(5);
+        let values: Vec<Value> = registry.snapshot().into_iter().map(|s| s.value).collect();
+        assert_eq!(values, vec![Value::Counter(2), Value::Counter(5)]);
+    }
+
+    #[test]
+    fn histogram_buckets_are_inclusive_upper_bounds() {
+        static BOUNDS: &[u64] = &[10, 100];
+        let h = Histogram::new(BOUNDS);
+        for v in [0, 10, 11, 100, 101, 5000] {
+            h.observe(v);
+        }
+        let snap = h.snapshot();
+        assert_eq!(snap.buckets, vec![2, 2, 2]);
+        assert_eq!(snap.count, 6);
+        assert_eq!(snap.sum, 5222);
+    }
+
+    #[test]
+    fn snapshots_stay_consistent_under_concurrent_writes() {
+        let registry = Registry::new();
+        let counter = registry.counter("events_total", &[]);
+        let histogram = registry.histogram("sizes", &[], SIZE_BUCKETS);
+
+        let writer = {
+            let (counter, histogram) = (counter.clone(), histogram.clone());
+            thread::spawn(move || {
+                for i in 0..200_000u64 {
+                    counter.inc();
+                    histogram.observe(i % 100_000);
+                }
+            })
+        };
+
+        let mut last = 0;
+        while !writer.is_finished() {
+            for sample in registry.snapshot() {
+                match sample.value {
+                    Value::Counter(v) => {
+                        assert!(v >= last, "counter went backwards");
+                        last = v;
+                    }
+                    Value::Histogram(h) => {
+                        assert_eq!(h.buckets.iter().sum::<u64>(), h.count);
+                    }
+                    Value::Gauge(_) => {}
+                }
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(counter.get(), 200_000);
+        assert_eq!(histogram.snapshot().count, 200_000);
+    }
+
+    #[test]
+    fn prometheus_output_is_cumulative() {
+        static BOUNDS: &[u64] = &[1, 2];
+        let registry = Registry::new();
+        registry.gauge("depth", &[("queue", "q")]).set(-3);
+        let h = registry.histogram("latency", &[], BOUNDS);
+        h.observe(1);
+        h.observe(3);
+        let text = render_prometheus(&registry.snapshot());
+        assert!(text.contains("depth{queue=\"q\"} -3\n"));
+        assert!(text.contains("latency_bucket{le=\"2\"} 1\n"));
+        assert!(text.contains("latency_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("latency_count 2\n"));
+    }
+}
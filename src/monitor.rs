@@ -0,0 +1,146 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//
+// ------------------------------ Socket monitors ------------------------------
+//
+// libzmq reports what happens to a socket's connections on a PAIR socket of
+// the monitor's own, as two frames: the event id and value (an fd for most
+// events), then the endpoint. SocketMonitor opens that pair and parses the
+// events. A socket has at most one monitor, so features that watch the same
+// socket share one: ConnectionLimits (crate::limits) owns the monitors of the
+// broker's sockets and passes each accepted and closed connection to the
+// ConnectionTaps given to it, such as the audit log (crate::audit).
+
+pub struct MonitorEvent {
+    pub id: u16,
+    pub value: u32,
+    pub endpoint: String,
+}
+
+impl MonitorEvent {
+    pub fn is(&self, event: zmq::SocketEvent) -> bool {
+        self.id == event.to_raw()
+    }
+}
+
+pub struct SocketMonitor {
+    events: zmq::Socket,
+}
+
+impl SocketMonitor {
+    // Monitors `events` of `socket`; `label` names the inproc endpoint.
+    pub fn open(
+        context: &zmq::Context,
+        socket: &zmq::Socket,
+        label: &str,
+        events: &[zmq::SocketEvent],
+    ) -> Result<Self, zmq::Error> {
+        // Unique per process: sockets may be created again on restart.
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let endpoint = format!(
+            "inproc://corky-monitor-{}-{}",
+            label,
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let mask = events.iter().fold(0, |mask, e| mask | e.to_raw());
+        socket.monitor(&endpoint, mask as i32)?;
+        let pair = context.socket(zmq::PAIR)?;
+        pair.set_linger(0)?;
+        pair.connect(&endpoint)?;
+        Ok(Self { events: pair })
+    }
+
+    // The next queued event, without waiting.
+    pub fn recv(&self) -> Option<MonitorEvent> {
+        loop {
+            let frames = self.events.recv_multipart(zmq::DONTWAIT).ok()?;
+            if let Some(event) = parse(&frames) {
+                return Some(event);
+            }
+        }
+    }
+}
+
+fn parse(frames: &[Vec<u8>]) -> Option<MonitorEvent> {
+    let raw = frames.first()?.get(..6)?;
+    Some(MonitorEvent {
+        id: u16::from_le_bytes([raw[0], raw[1]]),
+        value: u32::from_le_bytes([raw[2], raw[3], raw[4], raw[5]]),
+        endpoint: frames
+            .get(1)
+            .map(|e| String::from_utf8_lossy(e).into_owned())
+            .unwrap_or_default(),
+    })
+}
+
+// The peer of a file descriptor libzmq accepted. The fd is libzmq's, so it is
+// borrowed and never closed here.
+#[cfg(unix)]
+pub fn peer_address(fd: i32) -> Option<SocketAddr> {
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+    // SAFETY: ManuallyDrop keeps the stream from closing an fd it does not own.
+    let stream = ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+    stream.peer_addr().ok()
+}
+
+#[cfg(not(unix))]
+pub fn peer_address(_fd: i32) -> Option<SocketAddr> {
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Accepted,
+    Disconnected,
+}
+
+impl ConnectionEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionEvent::Accepted => "accepted",
+            ConnectionEvent::Disconnected => "disconnected",
+        }
+    }
+}
+
+// Told of each TCP connection a broker socket accepts or loses, with the
+// peer when it is known.
+pub trait ConnectionTap: Send + Sync {
+    fn connection(
+        &self,
+        socket: &'static str,
+        event: ConnectionEvent,
+        fd: u32,
+        peer: Option<SocketAddr>,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_carry_their_id_value_and_endpoint() {
+        let context = zmq::Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_linger(0).unwrap();
+        let monitor =
+            SocketMonitor::open(&context, &router, "test", &[zmq::SocketEvent::LISTENING]).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = router.get_last_endpoint().unwrap().unwrap();
+        let event = (0..200)
+            .find_map(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+                monitor.recv()
+            })
+            .unwrap();
+        assert!(event.is(zmq::SocketEvent::LISTENING));
+        assert!(!event.is(zmq::SocketEvent::ACCEPTED));
+        assert_eq!(event.endpoint, endpoint);
+        assert!(monitor.recv().is_none());
+
+        assert!(parse(&[vec![1, 0]]).is_none());
+    }
+}
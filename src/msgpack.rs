@@ -0,0 +1,197 @@
+use std::fmt::{self, Write};
+
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Number, Value};
+
+//
+// -------------------------------- MessagePack --------------------------------
+//
+// MessagePack frames for the log to show what a worker sent: format_part()
+// tries decode() on frames that are not UTF-8, and shows what rmp-serde
+// decodes as JSON. Strings, numbers, booleans and nil become their JSON
+// counterparts, and map keys that are not strings their JSON text. A bin
+// field, or a str that is not UTF-8, becomes a string with its size and bytes
+// in hex, as in "<4 bytes> 89504e47", and an ext field one naming its type and
+// size. Floats that JSON cannot hold (NaN, infinities) become null. Anything
+// malformed, truncated, nested past MAX_NESTING or followed by more bytes is
+// not MessagePack: decode() returns None and the frame is shown as bytes.
+
+const MAX_NESTING: usize = 128;
+
+// The one MessagePack value `bytes` holds, if that is all it holds.
+pub fn decode(bytes: &[u8]) -> Option<Value> {
+    let mut rest = bytes;
+    let mut deserializer = rmp_serde::Deserializer::new(&mut rest);
+    // rmp-serde counts the outermost value as a level.
+    deserializer.set_max_depth(MAX_NESTING + 1);
+    let Json(value) = Json::deserialize(&mut deserializer).ok()?;
+    rest.is_empty().then_some(value)
+}
+
+// serde_json's own Value refuses bin, ext and keys that are not strings, so
+// the frame is read into one by this visitor instead.
+struct Json(Value);
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(JsonVisitor).map(Json)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a MessagePack value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<Value, E> {
+        Ok(Value::from(n))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<Value, E> {
+        Ok(Value::from(n))
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<Value, E> {
+        Ok(Number::from_f64(f).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E>(self, text: &str) -> Result<Value, E> {
+        Ok(Value::String(text.to_string()))
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Value, E> {
+        let mut text = format!("<{} bytes> ", bytes.len());
+        for b in bytes {
+            let _ = write!(text, "{:02x}", b);
+        }
+        Ok(Value::String(text))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(Json(item)) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<Value, A::Error> {
+        let mut map = Map::new();
+        while let Some((Json(key), Json(value))) = entries.next_entry()? {
+            let key = match key {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    }
+
+    // rmp-serde hands an ext field over as a newtype of its type and data.
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, ext: D) -> Result<Value, D::Error> {
+        let (kind, ByteCount(len)) = <(i8, ByteCount)>::deserialize(ext)?;
+        Ok(Value::String(format!("<ext {}, {} bytes>", kind, len)))
+    }
+}
+
+struct ByteCount(usize);
+
+impl<'de> Deserialize<'de> for ByteCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CountVisitor;
+        impl Visitor<'_> for CountVisitor {
+            type Value = ByteCount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("ext data")
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<ByteCount, E> {
+                Ok(ByteCount(bytes.len()))
+            }
+        }
+        deserializer.deserialize_bytes(CountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decodes_the_spec_example_and_each_family() {
+        // {"compact": true, "schema": 0}, from msgpack.org.
+        let example = b"\x82\xa7compact\xc3\xa6schema\x00";
+        assert_eq!(
+            decode(example),
+            Some(json!({ "compact": true, "schema": 0 }))
+        );
+
+        let cases: &[(&[u8], Value)] = &[
+            (b"\xc0", Value::Null),
+            (b"\xc2", json!(false)),
+            (b"\x7f", json!(127)),
+            (b"\xff", json!(-1)),
+            (b"\xe0", json!(-32)),
+            (b"\xcc\xff", json!(255)),
+            (b"\xcd\x01\x00", json!(256)),
+            (b"\xcf\xff\xff\xff\xff\xff\xff\xff\xff", json!(u64::MAX)),
+            (b"\xd0\x80", json!(-128)),
+            (b"\xd1\xff\x7f", json!(-129)),
+            (b"\xd3\x80\0\0\0\0\0\0\0", json!(i64::MIN)),
+            (b"\xca\x3f\xc0\0\0", json!(1.5)),
+            (
+                b"\xcb\x40\x09\x21\xfb\x54\x44\x2d\x18",
+                json!(std::f64::consts::PI),
+            ),
+            (b"\xcb\x7f\xf8\0\0\0\0\0\0", Value::Null),
+            (b"\xd9\x03abc", json!("abc")),
+            (b"\xc4\x03\x00\x7f\xff", json!("<3 bytes> 007fff")),
+            (b"\xa2\xff\xfe", json!("<2 bytes> fffe")),
+            (b"\xd6\x05\x01\x02\x03\x04", json!("<ext 5, 4 bytes>")),
+            (b"\xc7\x02\xff\xaa\xbb", json!("<ext -1, 2 bytes>")),
+            (b"\xdc\x00\x02\x01\x02", json!([1, 2])),
+            (b"\x82\x01\xa1x\xc0\x90", json!({ "1": "x", "null": [] })),
+        ];
+        for (bytes, value) in cases {
+            assert_eq!(decode(bytes).as_ref(), Some(value), "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn anything_else_is_not_messagepack() {
+        let cases: &[&[u8]] = &[
+            b"",
+            b"\xc1",
+            // Truncated: a str, an array item, a uint.
+            b"\xa5abc",
+            b"\x92\x01",
+            b"\xcd\x01",
+            // More after the value.
+            b"\x01\x02",
+            // More items promised than there are bytes.
+            b"\xdd\xff\xff\xff\xff\x00",
+            b"\xdf\x00\x00\x00\x02\x01\x01\x01",
+        ];
+        for bytes in cases {
+            assert_eq!(decode(bytes), None, "{:02x?}", bytes);
+        }
+        let deep = [vec![0x91; MAX_NESTING + 1], vec![0x01]].concat();
+        assert_eq!(decode(&deep), None);
+        let fits = [vec![0x91; MAX_NESTING], vec![0x01]].concat();
+        assert!(decode(&fits).is_some());
+    }
+}
@@ -0,0 +1,192 @@
+use std::ops::{Deref, DerefMut};
+
+//
+// ------------------------------ Multipart ------------------------------------
+//
+// One ZeroMQ message: an ordered list of frames whose boundary is the end of
+// the list. Every broker and proxy send/receive goes through this type so
+// the MORE flag is derived in exactly one place: set on every frame but the
+// last, never on the last. Once libzmq accepts the first frame of a message
+// the rest is queued atomically, so a failure after the first frame means a
+// partially sent (split) message and is a bug.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Multipart {
+    frames: Vec<Vec<u8>>,
+}
+
+impl Multipart {
+    pub fn new(frames: Vec<Vec<u8>>) -> Self {
+        Self { frames }
+    }
+
+    pub fn into_frames(self) -> Vec<Vec<u8>> {
+        self.frames
+    }
+
+    pub fn push(&mut self, frame: Vec<u8>) {
+        self.frames.push(frame);
+    }
+
+    pub fn insert(&mut self, index: usize, frame: Vec<u8>) {
+        self.frames.insert(index, frame);
+    }
+
+    // Drop the first frame (an identity the ROUTER prepended) without
+    // reallocating the rest.
+    pub fn without_first(mut self) -> Self {
+        if !self.frames.is_empty() {
+            self.frames.remove(0);
+        }
+        self
+    }
+
+    // Receive a complete message, checking MORE after every frame.
+    pub fn recv(socket: &zmq::Socket, flags: i32) -> Result<Self, zmq::Error> {
+        let mut frames = Vec::new();
+        loop {
+            frames.push(socket.recv_bytes(flags)?);
+            if !socket.get_rcvmore()? {
+                return Ok(Self { frames });
+            }
+        }
+    }
+
+    // Like `recv`, also returning a metadata property of the message, e.g.
+    // the "User-Id" libzmq attaches from the peer's ZAP handshake.
+    pub fn recv_with_property(
+        socket: &zmq::Socket,
+        flags: i32,
+        property: &str,
+    ) -> Result<(Self, Option<String>), zmq::Error> {
+        let (message, mut values) = Self::recv_with_properties(socket, flags, &[property])?;
+        Ok((message, values.pop().flatten()))
+    }
+
+    // Like `recv_with_property`, for several properties in the given order.
+    // A ROUTER's identity frame can come without them (libzmq drops them
+    // when the message was prefetched by a poll), so the next frame is asked
+    // too.
+    pub fn recv_with_properties(
+        socket: &zmq::Socket,
+        flags: i32,
+        properties: &[&str],
+    ) -> Result<(Self, Vec<Option<String>>), zmq::Error> {
+        let mut first = socket.recv_msg(flags)?;
+        let mut values: Vec<Option<String>> = properties
+            .iter()
+            .map(|property| first.gets(property).map(str::to_string))
+            .collect();
+        let mut frames = vec![first.to_vec()];
+        while socket.get_rcvmore()? {
+            let mut frame = socket.recv_msg(flags)?;
+            if frames.len() == 1 {
+                for (value, property) in values.iter_mut().zip(properties) {
+                    if value.is_none() {
+                        *value = frame.gets(property).map(str::to_string);
+                    }
+                }
+            }
+            frames.push(frame.to_vec());
+        }
+        Ok((Self { frames }, values))
+    }
+
+    // Send the message, moving each frame into libzmq without copying.
+    pub fn send(self, socket: &zmq::Socket, flags: i32) -> Result<(), zmq::Error> {
+        send_frames(socket, self.frames, flags)
+    }
+
+    // Send a copy of the message and keep the frames.
+    pub fn send_copy(&self, socket: &zmq::Socket, flags: i32) -> Result<(), zmq::Error> {
+        send_frames(socket, self.frames.iter().map(Vec::as_slice), flags)
+    }
+}
+
+impl From<Vec<Vec<u8>>> for Multipart {
+    fn from(frames: Vec<Vec<u8>>) -> Self {
+        Self { frames }
+    }
+}
+
+impl Deref for Multipart {
+    type Target = [Vec<u8>];
+
+    fn deref(&self) -> &[Vec<u8>] {
+        &self.frames
+    }
+}
+
+impl DerefMut for Multipart {
+    fn deref_mut(&mut self) -> &mut [Vec<u8>] {
+        &mut self.frames
+    }
+}
+
+// Send `frames` as one message. Empty messages are not representable in
+// ZeroMQ and are rejected.
+pub fn send_frames<I, T>(socket: &zmq::Socket, frames: I, flags: i32) -> Result<(), zmq::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<zmq::Message>,
+{
+    let mut frames = frames.into_iter().peekable();
+    if frames.peek().is_none() {
+        debug_assert!(false, "attempted to send a message with no frames");
+        return Err(zmq::Error::EINVAL);
+    }
+    let mut first = true;
+    while let Some(frame) = frames.next() {
+        let more = if frames.peek().is_some() {
+            zmq::SNDMORE
+        } else {
+            0
+        };
+        let result = socket.send(frame.into(), flags | more);
+        debug_assert!(
+            first || result.is_ok(),
+            "message split after its first frame: {:?}",
+            result
+        );
+        result?;
+        first = false;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(context: &zmq::Context, name: &str) -> (zmq::Socket, zmq::Socket) {
+        let a = context.socket(zmq::PAIR).unwrap();
+        let b = context.socket(zmq::PAIR).unwrap();
+        a.bind(name).unwrap();
+        b.connect(name).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn boundaries_survive_a_round_trip() {
+        let context = zmq::Context::new();
+        let (a, b) = pair(&context, "inproc://multipart-round-trip");
+        let messages = [
+            vec![vec![]],
+            vec![b"one".to_vec()],
+            vec![vec![], vec![], b"x".to_vec(), vec![]],
+        ];
+        for frames in &messages {
+            Multipart::new(frames.clone()).send(&a, 0).unwrap();
+        }
+        for frames in &messages {
+            assert_eq!(Multipart::recv(&b, 0).unwrap().into_frames(), *frames);
+        }
+    }
+
+    #[test]
+    fn without_first_strips_the_envelope() {
+        let message = Multipart::new(vec![b"id".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(&*message.without_first(), &[b"a".to_vec(), b"b".to_vec()]);
+        assert!(Multipart::default().without_first().is_empty());
+    }
+}
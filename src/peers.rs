@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use smallvec::SmallVec;
+
+//
+// ------------------------------- Peer table ----------------------------------
+//
+// One map from ROUTER identity to all per-peer state, instead of a
+// HashMap<Vec<u8>, _> per feature. Keys are stored once, inline for the usual
+// 5-byte libzmq identities, and the entries live in a slab threaded onto an
+// intrusive LRU list so touching a peer and evicting the least recently seen
+// one are both O(1).
+
+// Role byte + identity. Inline up to 24 bytes, so lookups build the key on the
+// stack and never allocate for libzmq-generated identities.
+pub type PeerKey = SmallVec<[u8; 24]>;
+
+const NIL: u32 = u32::MAX;
+
+// Identities are per-socket, so the key is namespaced by the socket's role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PeerRole {
+    Direct = b'd',
+    Client = b'c',
+    Worker = b'w',
+}
+
+impl PeerRole {
+    pub fn from_tag(tag: u8) -> Option<PeerRole> {
+        match tag {
+            b'd' => Some(PeerRole::Direct),
+            b'c' => Some(PeerRole::Client),
+            b'w' => Some(PeerRole::Worker),
+            _ => None,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PeerRole::Direct => "direct",
+            PeerRole::Client => "client",
+            PeerRole::Worker => "worker",
+        }
+    }
+}
+
+pub fn peer_key(role: PeerRole, identity: &[u8]) -> PeerKey {
+    let mut key = PeerKey::with_capacity(identity.len() + 1);
+    key.push(role as u8);
+    key.extend_from_slice(identity);
+    key
+}
+
+// Splits a key back into its role and identity.
+pub fn split_key(key: &[u8]) -> Option<(PeerRole, &[u8])> {
+    let (tag, identity) = key.split_first()?;
+    Some((PeerRole::from_tag(*tag)?, identity))
+}
+
+struct Entry<T> {
+    key: PeerKey,
+    state: T,
+    last_seen: Instant,
+    prev: u32, // towards most recently used
+    next: u32, // towards least recently used
+}
+
+// Result of touching a peer. Inserting a new peer into a full table evicts
+// the least recently seen one, which is handed back so features can react.
+pub struct Touched<'a, T> {
+    pub state: &'a mut T,
+    pub evicted: Option<(PeerKey, T)>,
+}
+
+pub struct PeerTable<T> {
+    index: HashMap<PeerKey, u32>,
+    slots: Vec<Option<Entry<T>>>,
+    free: Vec<u32>,
+    head: u32, // most recently used
+    tail: u32, // least recently used
+    max_peers: usize,
+    evicted: u64,
+}
+
+impl<T: Default> PeerTable<T> {
+    pub fn new(max_peers: usize) -> Self {
+        let max_peers = max_peers.max(1);
+        Self {
+            index: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            max_peers,
+            evicted: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    // Number of slab slots ever allocated; never exceeds max_peers.
+    pub fn slot_capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    // Total peers evicted by the cap or by idle expiry.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&T> {
+        let idx = *self.index.get(key)?;
+        self.entry(idx).map(|e| &e.state)
+    }
+
+    // Mutable access without counting as activity.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut T> {
+        let idx = *self.index.get(key)?;
+        self.slots[idx as usize].as_mut().map(|e| &mut e.state)
+    }
+
+    pub fn last_seen(&self, key: &[u8]) -> Option<Instant> {
+        let idx = *self.index.get(key)?;
+        self.entry(idx).map(|e| e.last_seen)
+    }
+
+    // The least recently seen peer and when it was last seen.
+    pub fn oldest(&self) -> Option<(&[u8], Instant)> {
+        self.entry(self.tail)
+            .map(|e| (e.key.as_slice(), e.last_seen))
+    }
+
+    // Record activity for `key`, inserting a default state on first sight.
+    pub fn touch(&mut self, key: &[u8], now: Instant) -> Touched<'_, T> {
+        if let Some(&idx) = self.index.get(key) {
+            self.unlink(idx);
+            self.push_front(idx);
+            let entry = self.slots[idx as usize].as_mut().expect("indexed slot");
+            entry.last_seen = now;
+            return Touched {
+                state: &mut entry.state,
+                evicted: None,
+            };
+        }
+
+        let evicted = if self.index.len() >= self.max_peers {
+            self.pop_lru()
+        } else {
+            None
+        };
+        let key = PeerKey::from_slice(key);
+        let entry = Entry {
+            key: key.clone(),
+            state: T::default(),
+            last_seen: now,
+            prev: NIL,
+            next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx as usize] = Some(entry);
+                idx
+            }
+            None => {
+                self.slots.push(Some(entry));
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.index.insert(key, idx);
+        self.push_front(idx);
+        Touched {
+            state: &mut self.slots[idx as usize].as_mut().expect("new slot").state,
+            evicted,
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<T> {
+        let idx = self.index.remove(key)?;
+        self.unlink(idx);
+        self.free.push(idx);
+        self.slots[idx as usize].take().map(|e| e.state)
+    }
+
+    // Evict peers idle for at least `ttl`, oldest first, doing at most
+    // `max_work` evictions so a large backlog is spread over several calls.
+    pub fn evict_idle(
+        &mut self,
+        now: Instant,
+        ttl: Duration,
+        max_work: usize,
+    ) -> Vec<(PeerKey, T)> {
+        let mut out = Vec::new();
+        while out.len() < max_work && self.tail != NIL {
+            let idle = self
+                .entry(self.tail)
+                .map(|e| now.saturating_duration_since(e.last_seen) >= ttl)
+                .unwrap_or(false);
+            if !idle {
+                break;
+            }
+            match self.pop_lru() {
+                Some(evicted) => out.push(evicted),
+                None => break,
+            }
+        }
+        out
+    }
+
+    // Peers from most to least recently seen; cheap enough for admin listings.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &T, Instant)> + '_ {
+        let mut cursor = self.head;
+        std::iter::from_fn(move || {
+            let entry = self.entry(cursor)?;
+            cursor = entry.next;
+            Some((entry.key.as_slice(), &entry.state, entry.last_seen))
+        })
+    }
+
+    // All peers in slab order, for sweeps that mutate state.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&[u8], &mut T)> + '_ {
+        self.slots
+            .iter_mut()
+            .flatten()
+            .map(|e| (e.key.as_slice(), &mut e.state))
+    }
+
+    fn entry(&self, idx: u32) -> Option<&Entry<T>> {
+        self.slots.get(idx as usize).and_then(|s| s.as_ref())
+    }
+
+    fn pop_lru(&mut self) -> Option<(PeerKey, T)> {
+        let idx = self.tail;
+        if idx == NIL {
+            return None;
+        }
+        self.unlink(idx);
+        self.free.push(idx);
+        let entry = self.slots[idx as usize].take()?;
+        self.index.remove(entry.key.as_slice());
+        self.evicted += 1;
+        Some((entry.key, entry.state))
+    }
+
+    fn unlink(&mut self, idx: u32) {
+        let (prev, next) = match self.entry(idx) {
+            Some(e) => (e.prev, e.next),
+            None => return,
+        };
+        match prev {
+            NIL => self.head = next,
+            p => self.slots[p as usize].as_mut().expect("linked slot").next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            n => self.slots[n as usize].as_mut().expect("linked slot").prev = prev,
+        }
+        let entry = self.slots[idx as usize].as_mut().expect("linked slot");
+        entry.prev = NIL;
+        entry.next = NIL;
+    }
+
+    fn push_front(&mut self, idx: u32) {
+        let old_head = self.head;
+        {
+            let entry = self.slots[idx as usize].as_mut().expect("slot");
+            entry.prev = NIL;
+            entry.next = old_head;
+        }
+        if old_head != NIL {
+            self.slots[old_head as usize].as_mut().expect("slot").prev = idx;
+        }
+        self.head = idx;
+        if self.tail == NIL {
+            self.tail = idx;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys<T: Default>(table: &PeerTable<T>) -> Vec<Vec<u8>> {
+        table.iter().map(|(k, _, _)| k.to_vec()).collect()
+    }
+
+    #[test]
+    fn keys_are_namespaced_and_inline() {
+        let key = peer_key(PeerRole::Client, &[0, 1, 2, 3, 4]);
+        assert!(!key.spilled(), "5-byte identities stay on the stack");
+        assert_eq!(
+            split_key(&key),
+            Some((PeerRole::Client, &[0u8, 1, 2, 3, 4][..]))
+        );
+        assert_ne!(key, peer_key(PeerRole::Worker, &[0, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn touch_interns_once_and_tracks_recency() {
+        let now = Instant::now();
+        let mut table: PeerTable<u32> = PeerTable::new(10);
+        *table.touch(b"a", now).state += 1;
+        *table.touch(b"b", now).state += 1;
+        *table.touch(b"a", now).state += 1;
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(b"a"), Some(&2));
+        assert_eq!(keys(&table), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn cap_evicts_least_recently_seen() {
+        let now = Instant::now();
+        let mut table: PeerTable<u32> = PeerTable::new(3);
+        for k in [b"a", b"b", b"c"] {
+            assert!(table.touch(k, now).evicted.is_none());
+        }
+        table.touch(b"a", now); // b is now the oldest
+        let evicted = table.touch(b"d", now).evicted.map(|(k, _)| k.to_vec());
+        assert_eq!(evicted, Some(b"b".to_vec()));
+        assert_eq!(
+            keys(&table),
+            vec![b"d".to_vec(), b"a".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(table.evicted(), 1);
+    }
+
+    #[test]
+    fn idle_eviction_is_oldest_first_and_bounded() {
+        let start = Instant::now();
+        let mut table: PeerTable<()> = PeerTable::new(100);
+        for i in 0..10u8 {
+            table.touch(&[i], start + Duration::from_secs(i as u64));
+        }
+        let now = start + Duration::from_secs(15);
+        let ttl = Duration::from_secs(10); // peers 0..=5 are idle
+        let first = table.evict_idle(now, ttl, 4);
+        let firsts: Vec<Vec<u8>> = first.into_iter().map(|(k, _)| k.to_vec()).collect();
+        assert_eq!(firsts, vec![vec![0], vec![1], vec![2], vec![3]]);
+        assert_eq!(table.evict_idle(now, ttl, 4).len(), 2);
+        assert!(table.evict_idle(now, ttl, 4).is_empty());
+        assert_eq!(table.len(), 4);
+    }
+
+    #[test]
+    fn interleaved_reads_and_writes_stay_consistent() {
+        // Admin reads happen between broker mutations on the same thread.
+        let now = Instant::now();
+        let mut table: PeerTable<u64> = PeerTable::new(50);
+        for round in 0..200u64 {
+            let k = (round % 70).to_be_bytes();
+            *table.touch(&k, now + Duration::from_millis(round)).state += 1;
+            if round % 7 == 0 {
+                table.remove(&((round / 2) % 70).to_be_bytes());
+            }
+            let listed: Vec<_> = table.iter().collect();
+            assert_eq!(listed.len(), table.len());
+            assert!(listed.windows(2).all(|w| w[0].2 >= w[1].2), "MRU order");
+            assert!(table.len() <= 50);
+        }
+        let total: u64 = table.iter_mut().map(|(_, v)| *v).sum();
+        assert!(total > 0);
+    }
+
+    #[test]
+    fn hundred_thousand_peers_respect_the_cap() {
+        let cap = 10_000;
+        let now = Instant::now();
+        let mut table: PeerTable<[u64; 4]> = PeerTable::new(cap);
+        for i in 0..100_000u32 {
+            let id = [0u8, (i >> 16) as u8, (i >> 8) as u8, i as u8, 0x5a];
+            table.touch(&id, now).state[0] = i as u64;
+        }
+        assert_eq!(table.len(), cap);
+        assert!(table.slot_capacity() <= cap, "slab grew past the cap");
+        assert_eq!(table.evicted(), (100_000 - cap) as u64);
+        // The survivors are the most recent inserts.
+        let newest = table.iter().next().map(|(_, s, _)| s[0]);
+        assert_eq!(newest, Some(99_999));
+    }
+}
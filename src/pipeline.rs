@@ -0,0 +1,165 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use log::{info, warn};
+
+use crate::broker::SocketChannel;
+use crate::budget::{BudgetedQueue, MemoryBudget, Pool};
+use crate::config::{PipelineConfig, RetryProfile};
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+use crate::schedule::{Destination, ScheduleSpec, Scheduler};
+use crate::socket::{bind_with_retry, configure_socket};
+
+// Socket labels used in log lines and metrics
+const PIPELINE_PULL: &str = "pipeline_pull";
+const PIPELINE_PUSH: &str = "pipeline_push";
+
+//
+// ------------------------------- Pipeline ------------------------------------
+//
+// Fire-and-forget task distribution: producers PUSH tasks to a PULL socket,
+// consumers PULL them from a PUSH socket, and the broker relays between the
+// two from its poll loop. Tasks have no reply path.
+//
+// Nothing is dropped for being slow. When no consumer can take a task the
+// relay holds it (charged to the in-flight pool), stops reading, and waits
+// for the PUSH socket to become writable. The PULL queue then fills up and
+// producers block on their own high-water mark. Reading also pauses while the
+// memory budget is exceeded. Only tasks over max_task_bytes are dropped.
+//
+// With [schedule] enabled, a task whose first frame is a schedule header is
+// held by the scheduler instead and handed back through `submit` when due.
+
+pub struct PipelineSockets {
+    pub pull: SocketChannel,
+    pub push: SocketChannel,
+}
+
+impl PipelineSockets {
+    pub fn bind(
+        context: &zmq::Context,
+        config: &PipelineConfig,
+        bind_retry: &RetryProfile,
+        metrics: &Registry,
+    ) -> Result<Self, zmq::Error> {
+        let pull = SocketChannel::new(context.socket(zmq::PULL)?, PIPELINE_PULL, metrics);
+        configure_socket(&pull.socket)?;
+        bind_with_retry(
+            &pull.socket,
+            &config.producer_endpoint,
+            bind_retry,
+            "Broker",
+        )?;
+        info!(
+            "(Broker) {} (PULL) bound to {}",
+            pull.name, config.producer_endpoint
+        );
+
+        let push = SocketChannel::new(context.socket(zmq::PUSH)?, PIPELINE_PUSH, metrics);
+        configure_socket(&push.socket)?;
+        bind_with_retry(
+            &push.socket,
+            &config.consumer_endpoint,
+            bind_retry,
+            "Broker",
+        )?;
+        info!(
+            "(Broker) {} (PUSH) bound to {}",
+            push.name, config.consumer_endpoint
+        );
+        Ok(Self { pull, push })
+    }
+}
+
+// Relay state: at most one task waiting for a consumer.
+pub struct TaskRelay {
+    max_task_bytes: u64,
+    budget: Arc<MemoryBudget>,
+    pending: BudgetedQueue<Multipart>,
+    oversized: Counter,
+    stalls: Counter,
+}
+
+impl TaskRelay {
+    pub fn new(config: &PipelineConfig, budget: &Arc<MemoryBudget>, metrics: &Registry) -> Self {
+        Self {
+            max_task_bytes: config.max_task_bytes,
+            budget: Arc::clone(budget),
+            pending: BudgetedQueue::new(Pool::InFlight, budget),
+            oversized: metrics.counter("corky_pipeline_oversized_total", &[]),
+            stalls: metrics.counter("corky_pipeline_stalls_total", &[]),
+        }
+    }
+
+    // Whether the loop should poll the PULL socket.
+    pub fn wants_input(&self) -> bool {
+        self.pending.is_empty() && !self.budget.over_budget()
+    }
+
+    // Whether a task is waiting for the PUSH socket to become writable.
+    pub fn is_blocked(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // The PULL socket is readable: take one task and hand it on.
+    pub fn on_task(&mut self, sockets: &PipelineSockets, scheduler: Option<&Mutex<Scheduler>>) {
+        let Some(task) = sockets.pull.recv() else {
+            return;
+        };
+        let bytes: u64 = task.iter().map(|f| f.len() as u64).sum();
+        if bytes > self.max_task_bytes {
+            warn!(
+                "(Broker) Dropping {}-byte pipeline task (max_task_bytes = {})",
+                bytes, self.max_task_bytes
+            );
+            sockets.pull.record_drop();
+            self.oversized.inc();
+            return;
+        }
+        let spec = scheduler.and_then(|_| task.first().and_then(|h| ScheduleSpec::parse(h)));
+        if let (Some(scheduler), Some(spec)) = (scheduler, spec) {
+            let held = spec.and_then(|spec| {
+                if task.len() < 2 {
+                    return Err("scheduled task has no frames after the header".to_string());
+                }
+                let mut scheduler = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+                let task = task.without_first();
+                let (now, wall) = (Instant::now(), SystemTime::now());
+                scheduler.schedule(Destination::Pipeline, b"", spec, task, now, wall)
+            });
+            if let Err(e) = held {
+                warn!("(Broker) Dropping scheduled pipeline task: {}", e);
+                sockets.pull.record_drop();
+            }
+            return;
+        }
+        self.submit(sockets, task, bytes);
+    }
+
+    // Queue one task for the consumers, e.g. a scheduled one that is due.
+    pub fn submit(&mut self, sockets: &PipelineSockets, task: Multipart, bytes: u64) {
+        self.pending.push_back(task, bytes);
+        if !self.flush(sockets) {
+            self.stalls.inc();
+        }
+    }
+
+    // Send the pending task if a consumer can take it now. Returns false
+    // while it is still waiting.
+    pub fn flush(&mut self, sockets: &PipelineSockets) -> bool {
+        let Some(task) = self.pending.front() else {
+            return true;
+        };
+        match task.send_copy(&sockets.push.socket, zmq::DONTWAIT) {
+            Ok(()) => sockets.push.record_send(),
+            Err(zmq::Error::EAGAIN) => return false,
+            Err(e) => {
+                warn!("(Broker) Dropping pipeline task: {}", e);
+                sockets.push.record_drop();
+            }
+        }
+        self.pending.pop_front();
+        true
+    }
+}
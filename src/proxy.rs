@@ -0,0 +1,475 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn, Level};
+
+use crate::acl::{Acl, AclAction, AclView};
+use crate::budget::Shed;
+use crate::config::{Config, WelcomeMessage};
+use crate::format::{format_message, size_summary, Envelope, RenderStyle};
+use crate::metrics::Counter;
+use crate::multipart::Multipart;
+use crate::replay::{self, Replay};
+use crate::runtime::Runtime;
+use crate::sample::Sampler;
+use crate::socket::{
+    apply_socket_options, bind_all, bind_with_retry, configure_auth, configure_socket,
+    set_xpub_manual, ROLE_PROXY_XPUB, ROLE_PROXY_XSUB,
+};
+use crate::state::{StateCache, SNAPSHOT_COMMAND, SNAPSHOT_END};
+use crate::timer::Periodic;
+use crate::topics::TopicFilter;
+
+pub const PROXY_CONTROL_ENDPOINT: &str = "inproc://proxy-control";
+
+// Poll index constants for the proxy
+const IDX_XSUB: usize = 0;
+const IDX_XPUB: usize = 1;
+const IDX_CONTROL: usize = 2;
+const IDX_SNAPSHOT: usize = 3;
+
+// Topic rule changes reach upstream within this long even with no traffic.
+const RULES_POLL_TIMEOUT_MS: i64 = 100;
+
+// Metadata property carrying the ZAP-authenticated principal
+const USER_ID: &str = "User-Id";
+
+// How often the capture log checks whether the proxy has stopped.
+const CAPTURE_POLL_TIMEOUT_MS: i64 = 100;
+
+//
+// ------------------------------ Proxy ----------------------------------------
+//
+
+pub fn run_proxy(
+    context: &zmq::Context,
+    config: &Arc<Config>,
+    runtime: &Runtime,
+    control_endpoint: &str,
+) -> Result<(), zmq::Error> {
+    let xsub_socket = context.socket(zmq::XSUB)?;
+    configure_socket(&xsub_socket)?;
+    apply_socket_options(&xsub_socket, &config.network.socket_options, "Proxy", "xsub")?;
+    configure_auth(&xsub_socket, &config.auth)?;
+    let bind_retry = &config.retry.bind;
+    let xsub_endpoints = config.network.proxy_xsub_addresses();
+    let bound = bind_all(&xsub_socket, &xsub_endpoints, bind_retry, "Proxy", "XSUB")?;
+    if let Err(e) = runtime.endpoints.set(ROLE_PROXY_XSUB, bound) {
+        warn!("(Proxy) {}", e);
+    }
+
+    let mut xpub_socket = context.socket(zmq::XPUB)?;
+    configure_socket(&xpub_socket)?;
+    apply_socket_options(&xpub_socket, &config.network.socket_options, "Proxy", "xpub")?;
+    configure_auth(&xpub_socket, &config.auth)?;
+    if let Some(welcome) = config.proxy.welcome_message.as_ref().and_then(WelcomeMessage::frame) {
+        xpub_socket.set_xpub_welcome_msg(Some(&welcome))?;
+        info!("(Proxy) Welcoming each subscriber with {:?}", welcome);
+    }
+    // With ACLs the proxy applies each allowed subscription itself, so a
+    // denied one never reaches the XPUB's own filter. Replay needs to apply a
+    // subscription in the same step as publishing the history.
+    let manual = config.acl.enabled || !config.replay.prefixes.is_empty();
+    if manual {
+        set_xpub_manual(&mut xpub_socket, true)?;
+    }
+    // The XPUB is left non-verbose: it keeps each subscriber's subscriptions
+    // and passes on only the first to a topic and the last cancelled, so one
+    // a subscriber repeats is not counted twice (see TopicFilter::subscription).
+    let xpub_endpoints = config.network.proxy_xpub_addresses();
+    let bound = bind_all(&xpub_socket, &xpub_endpoints, bind_retry, "Proxy", "XPUB")?;
+    if let Err(e) = runtime.endpoints.set(ROLE_PROXY_XPUB, bound) {
+        warn!("(Proxy) {}", e);
+    }
+
+    // Control socket, same commands as zmq_proxy_steerable
+    let control_socket = context.socket(zmq::PAIR)?;
+    control_socket.set_linger(0)?;
+    bind_with_retry(&control_socket, control_endpoint, bind_retry, "Proxy")?;
+
+    // Sampled publications, for `corky-zmq monitor --sample`
+    let sample_socket = context.socket(zmq::PUB)?;
+    sample_socket.set_linger(0)?;
+    bind_with_retry(&sample_socket, &config.proxy.sample_endpoint, bind_retry, "Proxy")?;
+    info!("(Proxy) Sample PUB bound to {}", config.proxy.sample_endpoint);
+    let mut sampler = Sampler::new(std::process::id() as u64 ^ 0x9e37_79b9_7f4a_7c15);
+
+    // Optional copies of all forwarded traffic, logged by a thread of their own
+    let capture_endpoint = &config.proxy.capture_endpoint;
+    let (capture, _capture_log) = if config.proxy.capture {
+        let socket = context.socket(zmq::PUB)?;
+        socket.set_linger(0)?;
+        bind_with_retry(&socket, capture_endpoint, bind_retry, "Proxy")?;
+        info!("(Proxy) Capture PUB bound to {}", capture_endpoint);
+        let log = CaptureLog::start(context, capture_endpoint, config.logging.sample_every)?;
+        (Capture(Some(socket)), Some(log))
+    } else {
+        (Capture(None), None)
+    };
+
+    // Optional state service: snapshot ROUTER plus the latest-value store
+    let state = &config.state;
+    let snapshot_socket = if state.enabled {
+        let socket = context.socket(zmq::ROUTER)?;
+        configure_socket(&socket)?;
+        // A whole snapshot is queued at once.
+        socket.set_sndhwm(i32::try_from(state.max_keys + 2).unwrap_or(i32::MAX))?;
+        bind_with_retry(&socket, &state.snapshot_endpoint, bind_retry, "Proxy")?;
+        info!("(Proxy) State snapshot ROUTER bound to {}", state.snapshot_endpoint);
+        Some(socket)
+    } else {
+        None
+    };
+    let mut cache = snapshot_socket.as_ref().map(|_| {
+        let cache = StateCache::new(&state.topic_prefix, state.max_keys, &runtime.budget);
+        match &runtime.store {
+            Some(store) => cache.with_store(Arc::clone(store)),
+            None => cache,
+        }
+    });
+    let mut replay = (!config.replay.prefixes.is_empty())
+        .then(|| Replay::new(&config.replay, &runtime.budget, &runtime.metrics));
+    let mut replay_expiry = Periodic::new(Duration::from_millis(replay::EXPIRE_INTERVAL_MS));
+    // History is kept whether or not anyone is subscribed right now.
+    for prefix in &config.replay.prefixes {
+        let mut subscription = vec![1];
+        subscription.extend_from_slice(prefix.as_bytes());
+        xsub_socket.send(subscription, 0)?;
+    }
+
+    info!("(Proxy) Starting XSUB/XPUB forwarder...");
+
+    // Forwarding is done here rather than in libzmq's proxy so every message
+    // goes through Multipart and keeps its frame boundaries.
+    let mut poll_items = vec![
+        xsub_socket.as_poll_item(zmq::POLLIN),
+        xpub_socket.as_poll_item(zmq::POLLIN),
+        control_socket.as_poll_item(zmq::POLLIN),
+    ];
+    if let Some(socket) = &snapshot_socket {
+        poll_items.push(socket.as_poll_item(zmq::POLLIN));
+    }
+    let mut paused = false;
+    let metrics = &runtime.metrics;
+    let published = metrics.counter("corky_proxy_messages_total", &[("direction", "publish")]);
+    let subscriptions =
+        metrics.counter("corky_proxy_messages_total", &[("direction", "subscribe")]);
+    let mut acl = config.acl.enabled.then(|| AclView::new(&runtime.acl));
+    let denied_publish = metrics.counter("corky_acl_denied_total", &[("action", "publish")]);
+    let denied_subscribe = metrics.counter("corky_acl_denied_total", &[("action", "subscribe")]);
+    let mut topics = TopicFilter::new(&runtime.topics, metrics);
+
+    loop {
+        // While paused only the control socket is polled; traffic queues up.
+        let was_paused = paused;
+        let active = if paused {
+            &mut poll_items[IDX_CONTROL..]
+        } else {
+            &mut poll_items[..]
+        };
+        match zmq::poll(active, RULES_POLL_TIMEOUT_MS) {
+            Ok(_) => {}
+            Err(zmq::Error::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+
+        if poll_items[IDX_CONTROL].is_readable() {
+            let command = Multipart::recv(&control_socket, 0)?;
+            match command.first().map(Vec::as_slice) {
+                Some(b"TERMINATE") => {
+                    info!("(Proxy) TERMINATE received, stopping forwarder");
+                    return Ok(());
+                }
+                Some(b"PAUSE") => paused = true,
+                Some(b"RESUME") => paused = false,
+                _ => warn!("(Proxy) Ignoring unknown control command: {:?}", command),
+            }
+        }
+        if let (Some(socket), Some(cache)) = (&snapshot_socket, &cache) {
+            if poll_items[IDX_SNAPSHOT].is_readable() {
+                let request = Multipart::recv(socket, 0)?;
+                if let Err(e) = serve_snapshot(socket, cache, request) {
+                    warn!("(Proxy) Failed to send state snapshot: {}", e);
+                }
+            }
+        }
+        if was_paused {
+            continue;
+        }
+        // Catch up with rule changes from the admin socket.
+        for frame in topics.refresh(&runtime.topics) {
+            xsub_socket.send(frame, 0)?;
+        }
+        if let Some(replay) = replay.as_mut() {
+            if replay_expiry.poll(Instant::now()) {
+                replay.expire(Instant::now());
+            }
+        }
+
+        // Publications flow XSUB -> XPUB, subscriptions XPUB -> XSUB.
+        if poll_items[IDX_XSUB].is_readable() {
+            let (message, principal) = recv_attributed(&xsub_socket, acl.is_some())?;
+            let topic = message.first().map(Vec::as_slice).unwrap_or_default();
+            let allowed = acl.as_mut().is_none_or(|acl| {
+                acl.allows(&runtime.acl, &principal, AclAction::Publish, topic)
+            });
+            if !allowed {
+                debug!(
+                    "(Proxy) ACL: dropping publication to {:?} from principal {:?}",
+                    String::from_utf8_lossy(topic),
+                    principal
+                );
+                denied_publish.inc();
+            } else if let Some(message) = topics.publication(message) {
+                if let Some(sample) = sampler.sample(&runtime.sampler, &message) {
+                    if let Err(e) = sample.send(&sample_socket, zmq::DONTWAIT) {
+                        warn!("(Proxy) Dropping traffic sample: {}", e);
+                    }
+                }
+                match cache.as_mut() {
+                    Some(cache) if message.first().is_some_and(|t| cache.is_state_topic(t)) => {
+                        if let Some(update) = cache.apply(message) {
+                            capture.copy(&update);
+                            update.send(&xpub_socket, 0)?;
+                            published.inc();
+                        }
+                    }
+                    _ => {
+                        if let Some(replay) = replay.as_mut() {
+                            replay.record(&message, Instant::now());
+                        }
+                        capture.copy(&message);
+                        message.send(&xpub_socket, 0)?;
+                        published.inc();
+                    }
+                }
+                if runtime.budget.over_budget() {
+                    let mut stores: Vec<&mut dyn Shed> = Vec::new();
+                    stores.extend(cache.as_mut().map(|c| c as &mut dyn Shed));
+                    stores.extend(replay.as_mut().map(|r| r as &mut dyn Shed));
+                    runtime.budget.enforce(&mut stores);
+                    for deletion in cache.iter_mut().flat_map(StateCache::take_shed_updates) {
+                        capture.copy(&deletion);
+                        deletion.send(&xpub_socket, 0)?;
+                    }
+                }
+            }
+        }
+        if poll_items[IDX_XPUB].is_readable() {
+            let acl = acl.as_mut().map(|view| (view, &*runtime.acl, &denied_subscribe));
+            let subscriber = Subscriber {
+                xpub: &xpub_socket,
+                manual,
+                replay: replay.as_ref(),
+            };
+            let upstream = Upstream {
+                xsub: &xsub_socket,
+                capture: &capture,
+            };
+            if forward_subscription(subscriber, upstream, acl, &mut topics)? {
+                subscriptions.inc();
+            }
+        }
+    }
+}
+
+// Receive one message, with the sender's principal when ACLs need it.
+// Unauthenticated peers have the empty principal.
+fn recv_attributed(
+    socket: &zmq::Socket,
+    attribute: bool,
+) -> Result<(Multipart, String), zmq::Error> {
+    if !attribute {
+        return Ok((Multipart::recv(socket, 0)?, String::new()));
+    }
+    let (message, principal) = Multipart::recv_with_property(socket, 0, USER_ID)?;
+    Ok((message, principal.unwrap_or_default()))
+}
+
+// The XPUB side of a subscription: in manual mode the proxy applies it, and
+// publishes the replayed history right after.
+struct Subscriber<'a> {
+    xpub: &'a zmq::Socket,
+    manual: bool,
+    replay: Option<&'a Replay>,
+}
+
+// Where subscriptions go: the XSUB, and a copy to the capture socket.
+struct Upstream<'a> {
+    xsub: &'a zmq::Socket,
+    capture: &'a Capture,
+}
+
+impl Upstream<'_> {
+    fn send(&self, message: Multipart) -> Result<(), zmq::Error> {
+        self.capture.copy(&message);
+        message.send(self.xsub, 0)
+    }
+}
+
+// Subscriptions are [0x01 | topic] and unsubscriptions [0x00 | topic]. With
+// ACLs, an allowed one is applied to the subscriber's pipe, and a denied
+// subscription is audited and dropped, and so is the matching unsubscription,
+// so upstream counts stay balanced. What goes upstream is decided by the
+// topic rules. Returns whether anything was forwarded.
+fn forward_subscription(
+    subscriber: Subscriber,
+    upstream: Upstream,
+    acl: Option<(&mut AclView, &Acl, &Counter)>,
+    topics: &mut TopicFilter,
+) -> Result<bool, zmq::Error> {
+    let xpub_socket = subscriber.xpub;
+    let (message, principal) = recv_attributed(xpub_socket, acl.is_some())?;
+    let Some((&kind, topic)) = message.first().and_then(|f| f.split_first()) else {
+        upstream.send(message)?;
+        return Ok(true);
+    };
+    if kind > 1 {
+        upstream.send(message)?;
+        return Ok(true);
+    }
+    if let Some((view, shared, denied)) = acl {
+        if !view.allows(shared, &principal, AclAction::Subscribe, topic) {
+            if kind == 1 {
+                warn!(
+                    "(Proxy) ACL: denied subscription to {:?} for principal {:?}",
+                    String::from_utf8_lossy(topic),
+                    principal
+                );
+                denied.inc();
+            }
+            return Ok(false);
+        }
+    }
+    if subscriber.manual {
+        if kind == 1 {
+            xpub_socket.set_subscribe(topic)?;
+            for message in subscriber.replay.map(|r| r.history(topic)).unwrap_or_default() {
+                message.send(xpub_socket, 0)?;
+            }
+        } else {
+            xpub_socket.set_unsubscribe(topic)?;
+        }
+    }
+    let frames = topics.subscription(kind == 1, topic);
+    let forwarded = !frames.is_empty();
+    for frame in frames {
+        upstream.send(Multipart::new(vec![frame]))?;
+    }
+    Ok(forwarded)
+}
+
+//
+// ------------------------------ Capture --------------------------------------
+//
+// With [proxy] capture = true every message the proxy forwards, publications
+// downstream and subscriptions upstream, is copied to a PUB socket on
+// capture_endpoint, as libzmq's proxy does with its capture socket. The PUB
+// drops copies nobody keeps up with, so capture never holds up traffic.
+// CaptureLog reads them back and logs them at debug; anything else may
+// subscribe too, over tcp:// if the endpoint is one.
+
+struct Capture(Option<zmq::Socket>);
+
+impl Capture {
+    fn copy(&self, message: &Multipart) {
+        if let Some(socket) = &self.0 {
+            if let Err(e) = message.send_copy(socket, zmq::DONTWAIT) {
+                debug!("(Proxy) Dropping captured message: {}", e);
+            }
+        }
+    }
+}
+
+// The thread logging captured messages, rendered by topic, 1 of every
+// [logging] sample_every of them. Stopped when dropped, once the proxy is.
+struct CaptureLog {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl CaptureLog {
+    fn start(context: &zmq::Context, endpoint: &str, every: u64) -> Result<Self, zmq::Error> {
+        let socket = context.socket(zmq::SUB)?;
+        socket.set_linger(0)?;
+        socket.set_subscribe(b"")?;
+        socket.connect(endpoint)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let spawned = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("capture-log".to_string())
+                .spawn(move || log_captured(&socket, &stop, every.max(1)))
+        };
+        let thread = match spawned {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                warn!("(Proxy) Cannot start the capture log: {}; captures go unlogged", e);
+                None
+            }
+        };
+        Ok(Self { stop, thread })
+    }
+}
+
+impl Drop for CaptureLog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn log_captured(socket: &zmq::Socket, stop: &AtomicBool, every: u64) {
+    let mut seen = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        match socket.poll(zmq::POLLIN, CAPTURE_POLL_TIMEOUT_MS) {
+            Ok(0) | Err(zmq::Error::EINTR) => continue,
+            Ok(_) => {}
+            Err(_) => return,
+        }
+        let message = match Multipart::recv(socket, zmq::DONTWAIT) {
+            Ok(message) => message,
+            Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => continue,
+            Err(_) => return,
+        };
+        seen += 1;
+        if !(seen - 1).is_multiple_of(every) || !log::log_enabled!(Level::Debug) {
+            continue;
+        }
+        let style = match log::log_enabled!(Level::Trace) {
+            true => RenderStyle::Pretty,
+            false => RenderStyle::Compact,
+        };
+        debug!(
+            "(Proxy) Captured {}: {}",
+            size_summary(&message),
+            format_message(&message, Envelope::PubSub, style)
+        );
+    }
+}
+
+// Answer one ["SNAPSHOT", topic] request with every current key of the
+// namespace followed by its sequence number.
+fn serve_snapshot(
+    socket: &zmq::Socket,
+    cache: &StateCache,
+    request: Multipart,
+) -> Result<(), zmq::Error> {
+    if request.len() != 3 || request[1] != SNAPSHOT_COMMAND {
+        warn!("(Proxy) Ignoring malformed snapshot request: {:?}", request);
+        return Ok(());
+    }
+    let identity = &request[0];
+    let (entries, seq) = cache.snapshot(&request[2]);
+    for entry in entries {
+        let mut frames = vec![identity.clone()];
+        frames.extend(entry.into_frames());
+        Multipart::new(frames).send(socket, zmq::DONTWAIT)?;
+    }
+    let end = vec![identity.clone(), SNAPSHOT_END.to_vec(), seq.to_be_bytes().to_vec()];
+    Multipart::new(end).send(socket, zmq::DONTWAIT)
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::metrics::{Gauge, Registry};
+
+//
+// ------------------------------- Quiesce -------------------------------------
+//
+// Draining for blue/green cutovers. While draining, the ZAP handler refuses
+// every new connection to the sockets that consult it (see crate::zap) with
+// status 400 "draining": the broker's client-facing and direct ROUTERs ask it
+// about their NULL connections for exactly this, and the proxy sockets do
+// when [auth] is PLAIN. Connections made before keep working, so requests,
+// replies and direct traffic of connected peers finish normally. Workers may
+// still join. Unbinding the endpoints instead is not an option: libzmq closes
+// the connections accepted on an endpoint when it is unbound.
+//
+// Progress is exported as corky_broker_connections{socket} and
+// corky_broker_in_flight_requests. inproc connections never handshake, so
+// they can neither be refused nor counted.
+
+pub const DRAINING_STATUS: &str = "400";
+pub const DRAINING_TEXT: &str = "draining";
+
+// Shared between the admin socket, the ZAP handler and the broker.
+pub struct Quiesce {
+    draining: AtomicBool,
+    gauge: Gauge,
+}
+
+impl Quiesce {
+    pub fn new(metrics: &Registry) -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            gauge: metrics.gauge("corky_quiesce_draining", &[]),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Returns whether the state changed.
+    pub fn set_draining(&self, draining: bool) -> bool {
+        if self.draining.swap(draining, Ordering::SeqCst) == draining {
+            return false;
+        }
+        self.gauge.set(draining as i64);
+        true
+    }
+
+    // What load balancers see from the `health` admin command.
+    pub fn health(&self) -> &'static str {
+        if self.is_draining() {
+            "draining"
+        } else {
+            "ok"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_follows_the_draining_flag() {
+        let metrics = Registry::new();
+        let quiesce = Quiesce::new(&metrics);
+        assert_eq!(quiesce.health(), "ok");
+        assert!(quiesce.set_draining(true));
+        assert!(!quiesce.set_draining(true));
+        assert_eq!(quiesce.health(), "draining");
+        assert_eq!(metrics.gauge("corky_quiesce_draining", &[]).get(), 1);
+        assert!(quiesce.set_draining(false));
+        assert_eq!(quiesce.health(), "ok");
+    }
+}
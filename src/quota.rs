@@ -0,0 +1,527 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::{QuotaConfig, QuotaLimit};
+use crate::fs_util;
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+
+//
+// -------------------------------- Quotas -------------------------------------
+//
+// Request budgets on the broker's client-facing sockets, per principal (the
+// connection's ZAP User-Id, a CURVE client's public key; empty when
+// unauthenticated) and per service (the client's routing id, which names the
+// worker its requests go to). A request is admitted only when both its
+// principal and its service have budget left, and then counts against both.
+// Every client message but a hello is a request, so each chunk of a chunked
+// transfer counts and a fan-out counts once. Names without an entry of their
+// own use the "*" entry, each with its own usage; with neither there is no
+// limit.
+//
+// A "day" window is the UTC calendar day. A "rolling" window of window_ms is
+// kept as ROLLING_SLOTS slots, so a request stops counting between
+// window_ms - window_ms/ROLLING_SLOTS and window_ms after it was made. A
+// rejected client receives
+//
+//     [{"error": {"code": "quota_exceeded", "scope", "name", "limit",
+//                 "window", "reset_ms", "retry_after_ms"}}]
+//
+// where `reset_ms` (ms since the epoch) is when budget is next freed: the next
+// UTC midnight, or when the oldest counted slot leaves a rolling window.
+//
+// Windows follow the wall clock so that usage saved to the state file means
+// the same after a restart. The clock never runs backwards here: a step back
+// is treated as no time passing, so it cannot reopen a window.
+//
+// `quota grant` adds requests on top of a principal's budget. They do not
+// expire and are only used up once a window's budget has been.
+
+pub const ANY: &str = "*";
+pub const ROLLING_SLOTS: u64 = 24;
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaWindow {
+    #[default]
+    Day,
+    Rolling,
+}
+
+impl QuotaWindow {
+    fn label(self) -> &'static str {
+        match self {
+            QuotaWindow::Day => "day",
+            QuotaWindow::Rolling => "rolling",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Principal,
+    Service,
+}
+
+impl Scope {
+    pub fn label(self) -> &'static str {
+        match self {
+            Scope::Principal => "principal",
+            Scope::Service => "service",
+        }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// `slots` consecutive slots of `slot_ms` make up a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slots {
+    slot_ms: u64,
+    slots: u64,
+}
+
+fn slots(limit: &QuotaLimit) -> Slots {
+    match limit.window {
+        QuotaWindow::Day => Slots {
+            slot_ms: DAY_MS,
+            slots: 1,
+        },
+        QuotaWindow::Rolling => Slots {
+            slot_ms: (limit.window_ms / ROLLING_SLOTS).max(1),
+            slots: ROLLING_SLOTS,
+        },
+    }
+}
+
+// One principal's or service's usage, as saved in the state file.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Usage {
+    // Slot length the counts were made with; a change discards them.
+    slot_ms: u64,
+    // Requests per slot index (ms since the epoch / slot_ms).
+    counts: BTreeMap<u64, u64>,
+    // Granted requests not used yet.
+    bonus: u64,
+    // Latest time seen.
+    last_ms: u64,
+}
+
+impl Usage {
+    // Move to `now_ms`, dropping slots that left the window. Returns the
+    // current slot.
+    fn advance(&mut self, slots: Slots, now_ms: u64) -> u64 {
+        if self.slot_ms != slots.slot_ms {
+            self.counts.clear();
+            self.slot_ms = slots.slot_ms;
+        }
+        self.last_ms = self.last_ms.max(now_ms);
+        let current = self.last_ms / slots.slot_ms;
+        let oldest = (current + 1).saturating_sub(slots.slots);
+        self.counts = self.counts.split_off(&oldest);
+        current
+    }
+
+    fn used(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    fn reset_ms(&self, slots: Slots, current: u64) -> u64 {
+        let first = self.counts.keys().next().copied().unwrap_or(current);
+        first
+            .saturating_add(slots.slots)
+            .saturating_mul(slots.slot_ms)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.counts.is_empty() && self.bonus == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub scope: Scope,
+    pub name: String,
+    pub limit: u64,
+    pub window: QuotaWindow,
+    pub reset_ms: u64,
+}
+
+impl QuotaExceeded {
+    // What the client receives instead of a reply.
+    pub fn reply(&self, client: &[u8], now_ms: u64) -> Multipart {
+        let error = json!({"error": {
+            "code": "quota_exceeded",
+            "scope": self.scope.label(),
+            "name": self.name,
+            "limit": self.limit,
+            "window": self.window.label(),
+            "reset_ms": self.reset_ms,
+            "retry_after_ms": self.reset_ms.saturating_sub(now_ms),
+        }});
+        Multipart::new(vec![client.to_vec(), error.to_string().into_bytes()])
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+struct QuotaState {
+    principals: BTreeMap<String, Usage>,
+    services: BTreeMap<String, Usage>,
+}
+
+impl QuotaState {
+    fn usage(&mut self, scope: Scope, name: &str) -> &mut Usage {
+        let table = match scope {
+            Scope::Principal => &mut self.principals,
+            Scope::Service => &mut self.services,
+        };
+        table.entry(name.to_string()).or_default()
+    }
+}
+
+// How an admitted request is paid for.
+enum Charge {
+    Slot(u64),
+    Bonus,
+}
+
+// Configured budgets plus usage, shared by the broker and the admin socket.
+pub struct Quotas {
+    enabled: bool,
+    principals: BTreeMap<String, QuotaLimit>,
+    services: BTreeMap<String, QuotaLimit>,
+    state_file: Option<PathBuf>,
+    state: Mutex<QuotaState>,
+    rejected_principal: Counter,
+    rejected_service: Counter,
+}
+
+impl Quotas {
+    pub fn new(config: &QuotaConfig, metrics: &Registry) -> Self {
+        let state_file = match (&config.state_file, config.enabled) {
+            (_, false) => None,
+            (Some(path), true) => Some(PathBuf::from(path)),
+            (None, true) => default_state_file()
+                .map_err(|e| warn!("(Broker) Quota usage will not be saved: {}", e))
+                .ok(),
+        };
+        let state = match &state_file {
+            Some(path) if path.exists() => load_state(path).unwrap_or_else(|e| {
+                warn!("(Broker) Starting quotas from zero: {}", e);
+                QuotaState::default()
+            }),
+            _ => QuotaState::default(),
+        };
+        let rejected = |scope: Scope| {
+            metrics.counter("corky_quota_rejected_total", &[("scope", scope.label())])
+        };
+        Self {
+            enabled: config.enabled,
+            principals: config.principals.clone(),
+            services: config.services.clone(),
+            state_file,
+            state: Mutex::new(state),
+            rejected_principal: rejected(Scope::Principal),
+            rejected_service: rejected(Scope::Service),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn limit(&self, scope: Scope, name: &str) -> Option<&QuotaLimit> {
+        let table = match scope {
+            Scope::Principal => &self.principals,
+            Scope::Service => &self.services,
+        };
+        table.get(name).or_else(|| table.get(ANY))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QuotaState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Count one request, or say which budget it would exceed. Nothing is
+    // counted for a rejected request.
+    pub fn admit(&self, principal: &str, service: &str, now_ms: u64) -> Result<(), QuotaExceeded> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let budgets = [(Scope::Principal, principal), (Scope::Service, service)];
+        let mut state = self.lock();
+        let mut charges = Vec::with_capacity(budgets.len());
+        for (scope, name) in budgets {
+            let Some(limit) = self.limit(scope, name) else {
+                continue;
+            };
+            let slots = slots(limit);
+            let usage = state.usage(scope, name);
+            let current = usage.advance(slots, now_ms);
+            let charge = if usage.used() < limit.limit {
+                Charge::Slot(current)
+            } else if usage.bonus > 0 {
+                Charge::Bonus
+            } else {
+                match scope {
+                    Scope::Principal => self.rejected_principal.inc(),
+                    Scope::Service => self.rejected_service.inc(),
+                }
+                return Err(QuotaExceeded {
+                    scope,
+                    name: name.to_string(),
+                    limit: limit.limit,
+                    window: limit.window,
+                    reset_ms: usage.reset_ms(slots, current),
+                });
+            };
+            charges.push((scope, name, charge));
+        }
+        for (scope, name, charge) in charges {
+            let usage = state.usage(scope, name);
+            match charge {
+                Charge::Slot(slot) => *usage.counts.entry(slot).or_default() += 1,
+                Charge::Bonus => usage.bonus -= 1,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn grant(&self, principal: &str, requests: u64) -> Result<(), String> {
+        if self.limit(Scope::Principal, principal).is_none() {
+            return Err(format!("no quota applies to principal {:?}", principal));
+        }
+        let mut state = self.lock();
+        let usage = state.usage(Scope::Principal, principal);
+        usage.bonus = usage.bonus.saturating_add(requests);
+        Ok(())
+    }
+
+    // One line about the principal's budget, for the admin `quota show`.
+    pub fn show(&self, principal: &str, now_ms: u64) -> Result<String, String> {
+        let limit = self
+            .limit(Scope::Principal, principal)
+            .ok_or_else(|| format!("no quota applies to principal {:?}", principal))?;
+        let slots = slots(limit);
+        let mut state = self.lock();
+        let usage = state.usage(Scope::Principal, principal);
+        let current = usage.advance(slots, now_ms);
+        Ok(format!(
+            "{} used={} limit={} window={} granted={} reset_ms={}",
+            principal,
+            usage.used(),
+            limit.limit,
+            limit.window.label(),
+            usage.bonus,
+            usage.reset_ms(slots, current)
+        ))
+    }
+
+    // Write usage to the state file, leaving out names with none.
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let text = {
+            let mut state = self.lock();
+            state.principals.retain(|_, u| !u.is_idle());
+            state.services.retain(|_, u| !u.is_idle());
+            serde_json::to_string(&*state).map_err(|e| format!("Failed to encode usage: {}", e))?
+        };
+        fs_util::replace_file(path, text.as_bytes(), None)
+    }
+}
+
+pub fn default_state_file() -> Result<PathBuf, String> {
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    Ok(home.join(".corky").join("quota.json"))
+}
+
+fn load_state(path: &Path) -> Result<QuotaState, String> {
+    let text = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    const HOUR_MS: u64 = 3600 * 1000;
+    // 2026-01-01T00:00:00Z
+    const MIDNIGHT: u64 = 1_767_225_600_000;
+
+    fn config(principals: &[(&str, u64, QuotaWindow)], state_file: &Path) -> QuotaConfig {
+        let limit = |&(name, limit, window): &(&str, u64, QuotaWindow)| {
+            let limit = QuotaLimit {
+                limit,
+                window,
+                window_ms: HOUR_MS * 24,
+            };
+            (name.to_string(), limit)
+        };
+        QuotaConfig {
+            enabled: true,
+            principals: principals.iter().map(limit).collect(),
+            state_file: Some(state_file.display().to_string()),
+            ..QuotaConfig::default()
+        }
+    }
+
+    // Quotas whose state file is never written.
+    fn unsaved(principals: &[(&str, u64, QuotaWindow)]) -> Quotas {
+        let path = std::env::temp_dir().join(format!("corky-quota-unsaved-{}", std::process::id()));
+        Quotas::new(&config(principals, &path), &Registry::new())
+    }
+
+    #[test]
+    fn day_windows_reset_at_utc_midnight() {
+        let quotas = unsaved(&[("alice", 2, QuotaWindow::Day)]);
+        let evening = MIDNIGHT + 23 * HOUR_MS;
+        assert!(quotas.admit("alice", "svc", evening).is_ok());
+        assert!(quotas.admit("alice", "svc", evening + 1).is_ok());
+        let exceeded = quotas.admit("alice", "svc", evening + 2).unwrap_err();
+        assert_eq!(exceeded.scope, Scope::Principal);
+        assert_eq!(exceeded.reset_ms, MIDNIGHT + 24 * HOUR_MS);
+        // Others are not held back, nor is anyone without a quota.
+        assert!(quotas.admit("bob", "svc", evening).is_ok());
+        assert!(quotas
+            .admit("alice", "svc", MIDNIGHT + 24 * HOUR_MS)
+            .is_ok());
+    }
+
+    #[test]
+    fn rolling_windows_free_budget_slot_by_slot() {
+        let quotas = unsaved(&[("*", 3, QuotaWindow::Rolling)]);
+        // One slot is an hour of the 24h window.
+        let start = MIDNIGHT + 30 * 60 * 1000;
+        assert!(quotas.admit("k", "svc", start).is_ok());
+        assert!(quotas.admit("k", "svc", start + 2 * HOUR_MS).is_ok());
+        assert!(quotas.admit("k", "svc", start + 2 * HOUR_MS).is_ok());
+        let exceeded = quotas.admit("k", "svc", start + 5 * HOUR_MS).unwrap_err();
+        assert_eq!(exceeded.reset_ms, MIDNIGHT + 24 * HOUR_MS);
+        // Each principal under "*" has its own usage.
+        assert!(quotas.admit("other", "svc", start).is_ok());
+        // The first request's slot has left the window, the others' not yet.
+        assert!(quotas.admit("k", "svc", MIDNIGHT + 24 * HOUR_MS).is_ok());
+        assert!(quotas.admit("k", "svc", MIDNIGHT + 25 * HOUR_MS).is_err());
+        assert!(quotas.admit("k", "svc", MIDNIGHT + 26 * HOUR_MS).is_ok());
+    }
+
+    #[test]
+    fn a_clock_stepping_back_does_not_reopen_a_window() {
+        let quotas = unsaved(&[("alice", 1, QuotaWindow::Day)]);
+        let next_day = MIDNIGHT + 24 * HOUR_MS;
+        assert!(quotas.admit("alice", "svc", next_day + 10).is_ok());
+        // Stepped back over midnight: still the later day.
+        let exceeded = quotas.admit("alice", "svc", next_day - 10).unwrap_err();
+        assert_eq!(exceeded.reset_ms, next_day + 24 * HOUR_MS);
+    }
+
+    #[test]
+    fn both_budgets_must_have_room() {
+        let path = std::env::temp_dir().join(format!("corky-quota-unsaved-{}", std::process::id()));
+        let mut config = config(&[("alice", 5, QuotaWindow::Day)], &path);
+        config.services.insert(
+            "billing".into(),
+            QuotaLimit {
+                limit: 1,
+                window: QuotaWindow::Day,
+                window_ms: 0,
+            },
+        );
+        let quotas = Quotas::new(&config, &Registry::new());
+        assert!(quotas.admit("alice", "billing", MIDNIGHT).is_ok());
+        let exceeded = quotas.admit("alice", "billing", MIDNIGHT).unwrap_err();
+        assert_eq!(
+            (exceeded.scope, exceeded.name.as_str()),
+            (Scope::Service, "billing")
+        );
+        // The rejected request did not count against alice.
+        assert!(quotas.show("alice", MIDNIGHT).unwrap().contains(" used=1 "));
+        let reply = exceeded.reply(b"cli", MIDNIGHT + 1000);
+        let error: serde_json::Value = serde_json::from_slice(&reply[1]).unwrap();
+        assert_eq!(error["error"]["code"], "quota_exceeded");
+        assert_eq!(error["error"]["retry_after_ms"], 24 * HOUR_MS - 1000);
+    }
+
+    #[test]
+    fn grants_are_used_after_the_window() {
+        let quotas = unsaved(&[("alice", 1, QuotaWindow::Day)]);
+        assert!(quotas.grant("bob", 5).is_err());
+        quotas.grant("alice", 2).unwrap();
+        assert!(quotas.admit("alice", "svc", MIDNIGHT).is_ok());
+        assert!(quotas.admit("alice", "svc", MIDNIGHT).is_ok());
+        assert!(quotas.admit("alice", "svc", MIDNIGHT).is_ok());
+        assert!(quotas.admit("alice", "svc", MIDNIGHT).is_err());
+        assert_eq!(
+            quotas.show("alice", MIDNIGHT).unwrap(),
+            format!(
+                "alice used=1 limit=1 window=day granted=0 reset_ms={}",
+                MIDNIGHT + 24 * HOUR_MS
+            )
+        );
+    }
+
+    #[test]
+    fn usage_survives_a_restart_mid_window() {
+        let path = std::env::temp_dir().join(format!("corky-quota-{}.json", std::process::id()));
+        let config = config(&[("alice", 2, QuotaWindow::Rolling)], &path);
+        let quotas = Quotas::new(&config, &Registry::new());
+        assert!(quotas.admit("alice", "svc", MIDNIGHT).is_ok());
+        quotas.grant("alice", 1).unwrap();
+        quotas.save().unwrap();
+
+        let restarted = Quotas::new(&config, &Registry::new());
+        assert!(restarted.admit("alice", "svc", MIDNIGHT + HOUR_MS).is_ok());
+        assert!(restarted.admit("alice", "svc", MIDNIGHT + HOUR_MS).is_ok());
+        assert!(restarted.admit("alice", "svc", MIDNIGHT + HOUR_MS).is_err());
+
+        // A different window length starts the counts over, grants kept.
+        let mut longer = config.clone();
+        longer.principals.get_mut("alice").unwrap().window_ms = 48 * HOUR_MS;
+        restarted.save().unwrap();
+        let restarted = Quotas::new(&longer, &Registry::new());
+        assert!(restarted
+            .show("alice", MIDNIGHT)
+            .unwrap()
+            .contains(" used=0 "));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_principals_are_counted_exactly() {
+        let quotas = Arc::new(unsaved(&[("*", 100, QuotaWindow::Day)]));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let quotas = quotas.clone();
+                thread::spawn(move || {
+                    let principal = format!("p{}", t % 2);
+                    (0..100)
+                        .filter(|_| quotas.admit(&principal, "svc", MIDNIGHT).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let admitted: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        // Two principals sharing the load of four threads.
+        assert_eq!(admitted, 200);
+        assert!(quotas.show("p0", MIDNIGHT).unwrap().contains(" used=100 "));
+    }
+}
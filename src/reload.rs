@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, LevelFilter};
+
+use crate::config::{find_config_path, load_config_from, Config, FormattingConfig};
+use crate::format;
+use crate::logfilter::{self, parse_level, Levels};
+use crate::metrics::{Counter, Registry};
+
+const RELOAD_POLL_MS: u64 = 200; // SIGHUP and shutdown check interval
+
+//
+// ------------------------------- Hot reload ----------------------------------
+//
+// SIGHUP rereads the config file and applies the settings that can change
+// while the service runs: the log levels and [formatting]. The proxy and
+// broker loops go on
+// undisturbed and no socket is touched, so identities and subscriptions
+// survive; anything else that changed in the file waits for a restart. A file
+// that no longer loads leaves the current settings in place. With RUST_LOG
+// set, the level stays what RUST_LOG says.
+//
+// env_logger is built to pass everything and the levels are enforced by
+// crate::logfilter, which can move them either way later.
+
+pub fn level_filter(name: &str) -> LevelFilter {
+    parse_level(name).unwrap_or(LevelFilter::Info)
+}
+
+// broker=debug,proxy=warn, or none.
+fn filter_list(filters: &BTreeMap<String, String>) -> String {
+    if filters.is_empty() {
+        return "none".to_string();
+    }
+    let pairs: Vec<String> = filters
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    pairs.join(",")
+}
+
+pub struct Reloader {
+    // None when the service started on defaults; a reload then looks for
+    // the first config file in ~/.corky.
+    path: Option<PathBuf>,
+    level: LevelFilter,
+    filters: BTreeMap<String, String>,
+    level_from_env: bool,
+    formatting: FormattingConfig,
+    reloads: u64,
+    applied: Counter,
+    failed: Counter,
+}
+
+impl Reloader {
+    pub fn new(
+        path: Option<PathBuf>,
+        config: &Config,
+        level_from_env: bool,
+        metrics: &Registry,
+    ) -> Self {
+        Self {
+            path,
+            level: level_filter(&config.logging.level),
+            filters: config.logging.filters.clone(),
+            level_from_env,
+            formatting: config.formatting.clone(),
+            reloads: 0,
+            applied: metrics.counter("corky_config_reloads_total", &[("outcome", "applied")]),
+            failed: metrics.counter("corky_config_reloads_total", &[("outcome", "failed")]),
+        }
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    pub fn reloads(&self) -> u64 {
+        self.reloads
+    }
+
+    // One reload, logged with its number.
+    pub fn reload(&mut self) -> Result<Vec<String>, String> {
+        self.reloads += 1;
+        let result = self.apply();
+        let source = self
+            .path
+            .as_ref()
+            .map_or("the default path".to_string(), |p| p.display().to_string());
+        match &result {
+            Ok(changes) if changes.is_empty() => {
+                self.applied.inc();
+                info!(
+                    "(Main) Reload #{} from {}: nothing changed",
+                    self.reloads, source
+                );
+            }
+            Ok(changes) => {
+                self.applied.inc();
+                info!(
+                    "(Main) Reload #{} from {}: {}",
+                    self.reloads,
+                    source,
+                    changes.join(", ")
+                );
+            }
+            Err(e) => {
+                self.failed.inc();
+                error!(
+                    "(Main) Reload #{} from {} failed, keeping the current settings: {}",
+                    self.reloads, source, e
+                );
+            }
+        }
+        result
+    }
+
+    fn apply(&mut self) -> Result<Vec<String>, String> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => find_config_path()?,
+        };
+        let config = load_config_from(&path)?;
+        let mut changes = Vec::new();
+        let level = level_filter(&config.logging.level);
+        let levels_changed = level != self.level || config.logging.filters != self.filters;
+        if level != self.level {
+            if self.level_from_env {
+                changes.push(format!(
+                    "log level {} ignored, RUST_LOG decides",
+                    level.as_str().to_lowercase()
+                ));
+            } else {
+                changes.push(format!(
+                    "log level {} -> {}",
+                    self.level.as_str().to_lowercase(),
+                    level.as_str().to_lowercase()
+                ));
+            }
+            self.level = level;
+        }
+        if config.logging.filters != self.filters {
+            let filters = filter_list(&config.logging.filters);
+            if self.level_from_env {
+                changes.push(format!("log filters {} ignored, RUST_LOG decides", filters));
+            } else {
+                changes.push(format!(
+                    "log filters {} -> {}",
+                    filter_list(&self.filters),
+                    filters
+                ));
+            }
+            self.filters = config.logging.filters.clone();
+        }
+        if levels_changed && !self.level_from_env {
+            logfilter::set_levels(Levels::new(&config.logging));
+        }
+        if config.formatting != self.formatting {
+            changes.push("formatting limits changed".to_string());
+            format::set_formatting(&config.formatting);
+            self.formatting = config.formatting;
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(unix)]
+fn on_hangup(flag: &Arc<AtomicBool>) -> Result<(), String> {
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(flag))
+        .map(|_| ())
+        .map_err(|e| format!("cannot handle SIGHUP: {}", e))
+}
+
+#[cfg(not(unix))]
+fn on_hangup(_flag: &Arc<AtomicBool>) -> Result<(), String> {
+    Err("SIGHUP is not available on this platform".to_string())
+}
+
+// Reloads on every SIGHUP until `shutdown`, on a thread of its own.
+pub fn watch(
+    mut reloader: Reloader,
+    shutdown: Arc<AtomicBool>,
+) -> Result<thread::JoinHandle<()>, String> {
+    let hangup = Arc::new(AtomicBool::new(false));
+    on_hangup(&hangup)?;
+    thread::Builder::new()
+        .name("reload-thread".to_string())
+        .spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                if hangup.swap(false, Ordering::SeqCst) {
+                    let _ = reloader.reload();
+                }
+                thread::sleep(Duration::from_millis(RELOAD_POLL_MS));
+            }
+        })
+        .map_err(|e| format!("cannot start the reload thread: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn scratch(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("corky-reload-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn config(level: &str) -> Config {
+        let mut config = Config::default();
+        config.logging.level = level.to_string();
+        config
+    }
+
+    #[test]
+    fn a_reload_applies_the_new_level() {
+        let path = scratch("level", "[logging]\nlevel = \"debug\"\n");
+        let metrics = Registry::new();
+        let mut reloader = Reloader::new(Some(path.clone()), &config("info"), false, &metrics);
+        assert_eq!(reloader.reload().unwrap(), ["log level info -> debug"]);
+        assert_eq!(reloader.level(), LevelFilter::Debug);
+        assert!(reloader.reload().unwrap().is_empty());
+        assert_eq!(reloader.reloads(), 2);
+        assert_eq!(
+            metrics
+                .counter("corky_config_reloads_total", &[("outcome", "applied")])
+                .get(),
+            2
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_reload_applies_new_filters() {
+        let path = scratch(
+            "filters",
+            "[logging]\n[logging.filters]\nbroker = \"debug\"\nproxy = \"warn\"\n",
+        );
+        let mut reloader =
+            Reloader::new(Some(path.clone()), &config("info"), false, &Registry::new());
+        assert_eq!(
+            reloader.reload().unwrap(),
+            ["log filters none -> broker=debug,proxy=warn"]
+        );
+        assert!(reloader.reload().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_broken_file_keeps_the_current_settings() {
+        let path = scratch("broken", "[logging\nlevel = \"trace\"\n");
+        let metrics = Registry::new();
+        let mut reloader = Reloader::new(Some(path.clone()), &config("warn"), false, &metrics);
+        let e = reloader.reload().unwrap_err();
+        assert!(e.contains("Failed to parse config"), "{}", e);
+        assert_eq!(reloader.level(), LevelFilter::Warn);
+        assert_eq!(
+            metrics
+                .counter("corky_config_reloads_total", &[("outcome", "failed")])
+                .get(),
+            1
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rust_log_keeps_the_level() {
+        let path = scratch("env", "[logging]\nlevel = \"trace\"\n");
+        let mut reloader =
+            Reloader::new(Some(path.clone()), &config("info"), true, &Registry::new());
+        assert_eq!(
+            reloader.reload().unwrap(),
+            ["log level trace ignored, RUST_LOG decides"]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sighup_triggers_a_reload() {
+        let path = scratch("signal", "[logging]\nlevel = \"info\"\n");
+        let metrics = Registry::new();
+        let reloader = Reloader::new(Some(path.clone()), &config("info"), true, &metrics);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = watch(reloader, shutdown.clone()).unwrap();
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        let reloads = metrics.counter("corky_config_reloads_total", &[("outcome", "applied")]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reloads.get() == 0 {
+            assert!(Instant::now() < deadline, "no reload after SIGHUP");
+            thread::sleep(Duration::from_millis(20));
+        }
+        shutdown.store(true, Ordering::SeqCst);
+        thread.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::budget::{MemoryBudget, Pool, Shed};
+use crate::config::ReplayConfig;
+use crate::metrics::{Counter, Gauge, Registry};
+use crate::multipart::Multipart;
+
+//
+// ------------------------------ Topic replay ---------------------------------
+//
+// The proxy keeps the last `depth` publications of every topic under one of
+// the [replay] prefixes, and when a subscription arrives it publishes the
+// history of every topic the subscription covers, oldest first, before any
+// further live traffic. Replayed messages carry a marker frame after the
+// topic,
+//
+//     [topic, REPLAY_FRAME, ..frames]
+//
+// so a client can tell history from live messages. The XPUB cannot address a
+// single subscriber, so everyone already subscribed to those topics gets the
+// replay too and should drop marked messages once it is live.
+//
+// The handoff has no gaps and no duplicates because the proxy runs the XPUB
+// in manual mode: the subscription is applied and the history published in
+// one step of the proxy loop, so every publication is either in the history
+// (it was forwarded before) or live (it is forwarded after), never both.
+//
+// Histories are charged to the LVC pool of the memory budget and shed oldest
+// message first across all topics; a topic with no publications for
+// idle_ttl_ms is forgotten.
+
+pub const REPLAY_FRAME: &[u8] = b"\xffcorky-replay";
+
+// How often idle histories are looked for.
+pub const EXPIRE_INTERVAL_MS: u64 = 1000;
+
+pub fn is_replay(message: &[Vec<u8>]) -> bool {
+    message.get(1).is_some_and(|f| f == REPLAY_FRAME)
+}
+
+struct Entry {
+    stamp: u64,
+    frames: Vec<Vec<u8>>,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct History {
+    entries: VecDeque<Entry>,
+    bytes: u64,
+    last_publication: Option<Instant>,
+}
+
+pub struct Replay {
+    prefixes: Vec<Vec<u8>>,
+    depth: usize,
+    max_topic_bytes: u64,
+    max_topics: usize,
+    idle_ttl: Duration,
+    topics: HashMap<Vec<u8>, History>,
+    // Arrival order across topics, for shedding: stamp -> topic.
+    age: BTreeMap<u64, Vec<u8>>,
+    next_stamp: u64,
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+    replayed: Counter,
+    expired: Counter,
+    topics_kept: Gauge,
+}
+
+impl Replay {
+    pub fn new(config: &ReplayConfig, budget: &Arc<MemoryBudget>, metrics: &Registry) -> Self {
+        Self {
+            prefixes: config
+                .prefixes
+                .iter()
+                .map(|p| p.as_bytes().to_vec())
+                .collect(),
+            depth: config.depth.max(1),
+            max_topic_bytes: config.max_topic_bytes,
+            max_topics: config.max_topics,
+            idle_ttl: Duration::from_millis(config.idle_ttl_ms),
+            topics: HashMap::new(),
+            age: BTreeMap::new(),
+            next_stamp: 0,
+            budget: Arc::clone(budget),
+            bytes: 0,
+            replayed: metrics.counter("corky_replay_messages_total", &[]),
+            expired: metrics.counter("corky_replay_expired_topics_total", &[]),
+            topics_kept: metrics.gauge("corky_replay_topics", &[]),
+        }
+    }
+
+    pub fn keeps(&self, topic: &[u8]) -> bool {
+        self.prefixes.iter().any(|p| topic.starts_with(p))
+    }
+
+    // Messages kept across all topics.
+    pub fn len(&self) -> usize {
+        self.age.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.age.is_empty()
+    }
+
+    // Keep a copy of a publication that is about to be forwarded.
+    pub fn record(&mut self, message: &[Vec<u8>], now: Instant) {
+        let Some(topic) = message.first().filter(|t| self.keeps(t)) else {
+            return;
+        };
+        let bytes: u64 = message.iter().map(|f| f.len() as u64).sum();
+        if bytes > self.max_topic_bytes {
+            debug!(
+                "(Proxy) Not keeping a {} byte message on {:?} for replay",
+                bytes,
+                String::from_utf8_lossy(topic)
+            );
+            return;
+        }
+        if !self.topics.contains_key(topic.as_slice()) && self.topics.len() >= self.max_topics {
+            debug!(
+                "(Proxy) Replay topic limit reached, not keeping {:?}",
+                String::from_utf8_lossy(topic)
+            );
+            return;
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        let history = self.topics.entry(topic.clone()).or_default();
+        history.last_publication = Some(now);
+        history.bytes += bytes;
+        history.entries.push_back(Entry {
+            stamp,
+            frames: message.to_vec(),
+            bytes,
+        });
+        self.age.insert(stamp, topic.clone());
+        self.budget.charge(Pool::Lvc, bytes);
+        self.bytes += bytes;
+        while history.entries.len() > self.depth || history.bytes > self.max_topic_bytes {
+            let Some(old) = history.entries.pop_front() else {
+                break;
+            };
+            history.bytes -= old.bytes;
+            self.age.remove(&old.stamp);
+            self.budget.release(Pool::Lvc, old.bytes);
+            self.bytes -= old.bytes;
+        }
+        self.topics_kept.set(self.topics.len() as i64);
+    }
+
+    // The marked history of every topic `subscription` covers, oldest first.
+    pub fn history(&self, subscription: &[u8]) -> Vec<Multipart> {
+        let mut entries: Vec<&Entry> = self
+            .topics
+            .iter()
+            .filter(|(topic, _)| topic.starts_with(subscription))
+            .flat_map(|(_, history)| history.entries.iter())
+            .collect();
+        entries.sort_by_key(|entry| entry.stamp);
+        self.replayed.add(entries.len() as u64);
+        entries
+            .into_iter()
+            .map(|entry| {
+                let mut frames = entry.frames.clone();
+                frames.insert(1, REPLAY_FRAME.to_vec());
+                Multipart::new(frames)
+            })
+            .collect()
+    }
+
+    // Forget topics without publications for idle_ttl_ms; returns how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let ttl = self.idle_ttl;
+        let idle: Vec<Vec<u8>> = self
+            .topics
+            .iter()
+            .filter(|(_, h)| {
+                h.last_publication
+                    .is_some_and(|last| now.saturating_duration_since(last) >= ttl)
+            })
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        for topic in &idle {
+            if let Some(history) = self.topics.remove(topic) {
+                for entry in history.entries {
+                    self.age.remove(&entry.stamp);
+                }
+                self.budget.release(Pool::Lvc, history.bytes);
+                self.bytes -= history.bytes;
+            }
+        }
+        self.expired.add(idle.len() as u64);
+        self.topics_kept.set(self.topics.len() as i64);
+        idle.len()
+    }
+}
+
+impl Shed for Replay {
+    fn pool(&self) -> Pool {
+        Pool::Lvc
+    }
+
+    fn shed_oldest(&mut self) -> Option<u64> {
+        let (_, topic) = self.age.pop_first()?;
+        let history = self.topics.get_mut(&topic)?;
+        // Each topic's entries are in stamp order, so the oldest overall is
+        // at the front of its topic.
+        let entry = history.entries.pop_front()?;
+        history.bytes -= entry.bytes;
+        if history.entries.is_empty() {
+            self.topics.remove(&topic);
+            self.topics_kept.set(self.topics.len() as i64);
+        }
+        self.budget.release(Pool::Lvc, entry.bytes);
+        self.budget.record_shed(Pool::Lvc, entry.bytes);
+        self.bytes -= entry.bytes;
+        Some(entry.bytes)
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        self.budget.release(Pool::Lvc, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(depth: usize, max_topic_bytes: u64, budget: &Arc<MemoryBudget>) -> Replay {
+        let config = ReplayConfig {
+            prefixes: vec!["prices.".to_string()],
+            depth,
+            max_topic_bytes,
+            max_topics: 2,
+            idle_ttl_ms: 1000,
+        };
+        Replay::new(&config, budget, &Registry::new())
+    }
+
+    fn budget(limit: u64) -> Arc<MemoryBudget> {
+        MemoryBudget::new(limit, &[Pool::Lvc], &Registry::new())
+    }
+
+    fn publish(replay: &mut Replay, topic: &str, payload: &str, now: Instant) {
+        replay.record(
+            &[topic.as_bytes().to_vec(), payload.as_bytes().to_vec()],
+            now,
+        );
+    }
+
+    // (topic, payload) of each replayed message.
+    fn replayed(replay: &Replay, subscription: &str) -> Vec<(String, String)> {
+        replay
+            .history(subscription.as_bytes())
+            .into_iter()
+            .map(|message| {
+                assert!(is_replay(&message));
+                let text = |f: &Vec<u8>| String::from_utf8(f.clone()).unwrap();
+                (text(&message[0]), text(&message[2]))
+            })
+            .collect()
+    }
+
+    fn entry(topic: &str, payload: &str) -> (String, String) {
+        (topic.to_string(), payload.to_string())
+    }
+
+    #[test]
+    fn keeps_the_last_messages_of_listed_topics_in_order() {
+        let budget = budget(1 << 20);
+        let mut replay = replay(3, 1 << 20, &budget);
+        let now = Instant::now();
+        for i in 0..5 {
+            publish(&mut replay, "prices.eur", &format!("e{}", i), now);
+            if i % 2 == 0 {
+                publish(&mut replay, "prices.usd", &format!("u{}", i), now);
+            }
+        }
+        publish(&mut replay, "news.1", "not kept", now);
+        assert_eq!(
+            replayed(&replay, "prices.eur"),
+            [
+                entry("prices.eur", "e2"),
+                entry("prices.eur", "e3"),
+                entry("prices.eur", "e4"),
+            ]
+        );
+        // A wider subscription interleaves topics in arrival order.
+        assert_eq!(
+            replayed(&replay, "prices."),
+            [
+                entry("prices.usd", "u0"),
+                entry("prices.eur", "e2"),
+                entry("prices.usd", "u2"),
+                entry("prices.eur", "e3"),
+                entry("prices.eur", "e4"),
+                entry("prices.usd", "u4"),
+            ]
+        );
+        assert!(replayed(&replay, "news.").is_empty());
+        assert_eq!(replay.len(), 6);
+        assert_eq!(budget.pool_bytes(Pool::Lvc), 6 * (10 + 2));
+    }
+
+    #[test]
+    fn byte_and_topic_limits() {
+        let budget = budget(1 << 20);
+        let mut replay = replay(100, 30, &budget);
+        let now = Instant::now();
+        // 10 + 5 bytes each: two fit in 30.
+        for payload in ["aaaaa", "bbbbb", "ccccc"] {
+            publish(&mut replay, "prices.eur", payload, now);
+        }
+        assert_eq!(
+            replayed(&replay, "prices.eur"),
+            [entry("prices.eur", "bbbbb"), entry("prices.eur", "ccccc")]
+        );
+        publish(&mut replay, "prices.eur", &"x".repeat(40), now);
+        assert_eq!(replay.len(), 2, "too large to keep at all");
+
+        publish(&mut replay, "prices.usd", "1", now);
+        publish(&mut replay, "prices.gbp", "2", now);
+        assert!(
+            replayed(&replay, "prices.gbp").is_empty(),
+            "max_topics is 2"
+        );
+    }
+
+    #[test]
+    fn idle_topics_expire_and_release_their_bytes() {
+        let budget = budget(1 << 20);
+        let mut replay = replay(10, 1 << 20, &budget);
+        let start = Instant::now();
+        publish(&mut replay, "prices.eur", "old", start);
+        publish(
+            &mut replay,
+            "prices.usd",
+            "new",
+            start + Duration::from_millis(800),
+        );
+        assert_eq!(replay.expire(start + Duration::from_millis(1200)), 1);
+        assert_eq!(replayed(&replay, "prices."), [entry("prices.usd", "new")]);
+        assert_eq!(budget.pool_bytes(Pool::Lvc), 10 + 3);
+        drop(replay);
+        assert_eq!(budget.pool_bytes(Pool::Lvc), 0);
+    }
+
+    #[test]
+    fn the_budget_sheds_the_oldest_messages_first() {
+        let budget = budget(50);
+        let mut replay = replay(10, 1 << 20, &budget);
+        let now = Instant::now();
+        for (topic, payload) in [
+            ("prices.eur", "1"),
+            ("prices.usd", "2"),
+            ("prices.eur", "3"),
+            ("prices.eur", "4"),
+            ("prices.usd", "5"),
+        ] {
+            publish(&mut replay, topic, payload, now);
+        }
+        // 5 * 11 bytes is over 50 by one message.
+        assert_eq!(budget.enforce(&mut [&mut replay]), 1);
+        assert_eq!(
+            replayed(&replay, "prices."),
+            [
+                entry("prices.usd", "2"),
+                entry("prices.eur", "3"),
+                entry("prices.eur", "4"),
+                entry("prices.usd", "5"),
+            ]
+        );
+        assert_eq!(budget.shed_messages(Pool::Lvc), 1);
+    }
+}
@@ -0,0 +1,410 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::config::ResolveConfig;
+use crate::monitor::SocketMonitor;
+
+//
+// ----------------------------- Re-resolution ---------------------------------
+//
+// libzmq resolves the host name of a connect endpoint when it connects and
+// then keeps reconnecting to that address, so a peer whose DNS name moves
+// during a failover is never found again. A `Reconnector` connects to the
+// resolved address itself and looks the name up again every `interval_ms`,
+// or after `after_failed_reconnects` consecutive reconnect attempts (counted
+// from monitor events). When the address set changes and no longer holds
+// the connected address, it disconnects and connects to the first new one.
+//
+// Lookups run on a helper thread so a slow DNS server never stalls the
+// owner's loop; the owner applies the results from `poll`, since only it may
+// touch its socket. Endpoints that are not TCP, name an IP address or bind a
+// source address are connected as given and never re-resolved.
+
+pub trait Resolve: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+// The system resolver, through getaddrinfo.
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+// The host and port of a "tcp://host:port" endpoint whose host is a name.
+pub fn host_name(endpoint: &str) -> Option<(&str, u16)> {
+    let address = endpoint.strip_prefix("tcp://")?;
+    if address.contains(';') {
+        return None;
+    }
+    let (host, port) = address.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host == "*" || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+fn endpoint_for(address: SocketAddr) -> String {
+    format!("tcp://{}", address)
+}
+
+struct Lookups {
+    wake: Sender<()>,
+    results: Receiver<io::Result<Vec<SocketAddr>>>,
+    events: Option<SocketMonitor>,
+    failed_reconnects: u32,
+    after_failed_reconnects: u32,
+    host: String,
+    addresses: Vec<SocketAddr>,
+}
+
+// A connect-mode socket's endpoint, kept pointed at what its name resolves
+// to. Dropping it ends the helper thread after any lookup in progress.
+pub struct Reconnector {
+    label: &'static str,
+    endpoint: String,
+    lookups: Option<Lookups>,
+}
+
+impl Reconnector {
+    pub fn connect(
+        context: &zmq::Context,
+        socket: &zmq::Socket,
+        endpoint: &str,
+        label: &'static str,
+        config: &ResolveConfig,
+        resolver: Arc<dyn Resolve>,
+    ) -> Result<Self, zmq::Error> {
+        let enabled = config.interval_ms > 0 || config.after_failed_reconnects > 0;
+        let Some((host, port)) = host_name(endpoint).filter(|_| enabled) else {
+            socket.connect(endpoint)?;
+            return Ok(Self {
+                label,
+                endpoint: endpoint.to_string(),
+                lookups: None,
+            });
+        };
+
+        // Resolved here the first time, so the socket starts connecting at once.
+        let addresses = match resolver.resolve(host, port) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                warn!("(Resolve) Cannot resolve {} for {}: {}", host, label, e);
+                Vec::new()
+            }
+        };
+        let target = addresses
+            .first()
+            .map_or(endpoint.to_string(), |a| endpoint_for(*a));
+        socket.connect(&target)?;
+
+        let events = if config.after_failed_reconnects > 0 {
+            let events = [
+                zmq::SocketEvent::CONNECTED,
+                zmq::SocketEvent::CONNECT_RETRIED,
+            ];
+            Some(SocketMonitor::open(context, socket, "resolve", &events)?)
+        } else {
+            None
+        };
+
+        let (wake, wakeups) = mpsc::channel();
+        let (results_tx, results) = mpsc::channel();
+        let interval = (config.interval_ms > 0).then(|| Duration::from_millis(config.interval_ms));
+        let name = host.to_string();
+        thread::Builder::new()
+            .name("resolve-thread".to_string())
+            .spawn(move || lookup_loop(&*resolver, &name, port, interval, &wakeups, &results_tx))
+            .map_err(|_| zmq::Error::EFAULT)?;
+
+        info!(
+            "(Resolve) {} connected to {} as {}",
+            label, endpoint, target
+        );
+        Ok(Self {
+            label,
+            endpoint: target,
+            lookups: Some(Lookups {
+                wake,
+                results,
+                events,
+                failed_reconnects: 0,
+                after_failed_reconnects: config.after_failed_reconnects,
+                host: host.to_string(),
+                addresses,
+            }),
+        })
+    }
+
+    // The endpoint the socket is connected to.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    // Apply finished lookups and count reconnect attempts; call it regularly
+    // from the socket's thread. True if the socket was reconnected.
+    pub fn poll(&mut self, socket: &zmq::Socket) -> bool {
+        let Some(lookups) = &mut self.lookups else {
+            return false;
+        };
+        if let Some(events) = &lookups.events {
+            while let Some(event) = events.recv() {
+                if event.is(zmq::SocketEvent::CONNECTED) {
+                    lookups.failed_reconnects = 0;
+                } else if event.is(zmq::SocketEvent::CONNECT_RETRIED) {
+                    lookups.failed_reconnects += 1;
+                    if lookups.failed_reconnects == lookups.after_failed_reconnects {
+                        debug!(
+                            "(Resolve) {} reconnected {} times; resolving {} again",
+                            self.label, lookups.failed_reconnects, lookups.host
+                        );
+                        let _ = lookups.wake.send(());
+                    }
+                }
+            }
+        }
+
+        let mut reconnected = false;
+        while let Ok(result) = lookups.results.try_recv() {
+            // Another round of failures may trigger the next lookup.
+            lookups.failed_reconnects = 0;
+            let addresses = match result {
+                Ok(addresses) if !addresses.is_empty() => addresses,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(
+                        "(Resolve) Cannot resolve {} for {}: {}",
+                        lookups.host, self.label, e
+                    );
+                    continue;
+                }
+            };
+            if addresses == lookups.addresses {
+                continue;
+            }
+            let connected = addresses.iter().any(|a| endpoint_for(*a) == self.endpoint);
+            lookups.addresses = addresses;
+            if connected {
+                continue;
+            }
+            let target = endpoint_for(lookups.addresses[0]);
+            if let Err(e) = socket.disconnect(&self.endpoint) {
+                debug!("(Resolve) Disconnecting {}: {}", self.endpoint, e);
+            }
+            match socket.connect(&target) {
+                Ok(()) => {
+                    info!(
+                        "(Resolve) {} moved from {} to {} ({})",
+                        self.label, self.endpoint, target, lookups.host
+                    );
+                    self.endpoint = target;
+                    reconnected = true;
+                }
+                Err(e) => {
+                    warn!(
+                        "(Resolve) {} cannot connect to {}: {}",
+                        self.label, target, e
+                    );
+                }
+            }
+        }
+        reconnected
+    }
+}
+
+fn lookup_loop(
+    resolver: &dyn Resolve,
+    host: &str,
+    port: u16,
+    interval: Option<Duration>,
+    wakeups: &Receiver<()>,
+    results: &Sender<io::Result<Vec<SocketAddr>>>,
+) {
+    loop {
+        let woken = match interval {
+            Some(interval) => wakeups.recv_timeout(interval),
+            None => wakeups.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        if woken == Err(RecvTimeoutError::Disconnected) {
+            return;
+        }
+        if results.send(resolver.resolve(host, port)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::*;
+
+    // Answers with whatever the test last set.
+    struct MockResolver(Mutex<Vec<SocketAddr>>);
+
+    impl MockResolver {
+        fn set(&self, addresses: &[SocketAddr]) {
+            *self.0.lock().unwrap() = addresses.to_vec();
+        }
+    }
+
+    impl Resolve for MockResolver {
+        fn resolve(&self, host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            assert_eq!(host, "peer.example");
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    fn router(context: &zmq::Context) -> (zmq::Socket, SocketAddr) {
+        let socket = context.socket(zmq::ROUTER).unwrap();
+        socket.set_linger(0).unwrap();
+        socket.set_rcvtimeo(2000).unwrap();
+        socket.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = socket.get_last_endpoint().unwrap().unwrap();
+        let address = endpoint.trim_start_matches("tcp://").parse().unwrap();
+        (socket, address)
+    }
+
+    fn dealer(context: &zmq::Context) -> zmq::Socket {
+        let socket = context.socket(zmq::DEALER).unwrap();
+        socket.set_linger(0).unwrap();
+        socket.set_reconnect_ivl(20).unwrap();
+        socket.set_reconnect_ivl_max(20).unwrap();
+        socket
+    }
+
+    // Poll until the reconnector moves, then prove the new peer is reached.
+    fn moves_to(
+        reconnector: &mut Reconnector,
+        dealer: &zmq::Socket,
+        router: &zmq::Socket,
+        address: SocketAddr,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !reconnector.poll(dealer) {
+            assert!(
+                Instant::now() < deadline,
+                "still at {}",
+                reconnector.endpoint()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reconnector.endpoint(), endpoint_for(address));
+        dealer.send("hello", 0).unwrap();
+        assert_eq!(router.recv_multipart(0).unwrap()[1], b"hello");
+    }
+
+    #[test]
+    fn only_tcp_host_names_are_re_resolved() {
+        assert_eq!(
+            host_name("tcp://peer.example:5563"),
+            Some(("peer.example", 5563))
+        );
+        assert_eq!(host_name("tcp://127.0.0.1:5563"), None);
+        assert_eq!(host_name("tcp://[::1]:5563"), None);
+        assert_eq!(host_name("tcp://eth0;peer.example:5563"), None);
+        assert_eq!(host_name("ipc:///tmp/peer"), None);
+        assert_eq!(host_name("tcp://peer.example"), None);
+    }
+
+    #[test]
+    fn interval_follows_an_address_change() {
+        let context = zmq::Context::new();
+        let (old, old_address) = router(&context);
+        let (new, new_address) = router(&context);
+        let resolver = Arc::new(MockResolver(Mutex::new(vec![old_address])));
+        let config = ResolveConfig {
+            interval_ms: 20,
+            after_failed_reconnects: 0,
+        };
+        let dealer = dealer(&context);
+        let endpoint = format!("tcp://peer.example:{}", old_address.port());
+        let mut reconnector = Reconnector::connect(
+            &context,
+            &dealer,
+            &endpoint,
+            "test peer",
+            &config,
+            resolver.clone(),
+        )
+        .unwrap();
+        assert_eq!(reconnector.endpoint(), endpoint_for(old_address));
+        dealer.send("before", 0).unwrap();
+        assert_eq!(old.recv_multipart(0).unwrap()[1], b"before");
+
+        resolver.set(&[new_address]);
+        moves_to(&mut reconnector, &dealer, &new, new_address);
+        // A set that still holds the connected address changes nothing.
+        resolver.set(&[old_address, new_address]);
+        thread::sleep(Duration::from_millis(100));
+        assert!(!reconnector.poll(&dealer));
+        assert_eq!(reconnector.endpoint(), endpoint_for(new_address));
+    }
+
+    #[test]
+    fn failed_reconnects_trigger_a_lookup() {
+        let context = zmq::Context::new();
+        let (old, old_address) = router(&context);
+        let (new, new_address) = router(&context);
+        let resolver = Arc::new(MockResolver(Mutex::new(vec![old_address])));
+        let config = ResolveConfig {
+            interval_ms: 0,
+            after_failed_reconnects: 3,
+        };
+        let dealer = dealer(&context);
+        let endpoint = format!("tcp://peer.example:{}", old_address.port());
+        let mut reconnector = Reconnector::connect(
+            &context,
+            &dealer,
+            &endpoint,
+            "test peer",
+            &config,
+            resolver.clone(),
+        )
+        .unwrap();
+        dealer.send("before", 0).unwrap();
+        assert_eq!(old.recv_multipart(0).unwrap()[1], b"before");
+
+        // The old peer dies and its name now points at the new one.
+        resolver.set(&[new_address]);
+        drop(old);
+        moves_to(&mut reconnector, &dealer, &new, new_address);
+    }
+
+    #[test]
+    fn disabled_or_literal_endpoints_connect_as_given() {
+        let context = zmq::Context::new();
+        let (router, address) = router(&context);
+        let resolver = Arc::new(MockResolver(Mutex::new(Vec::new())));
+        let dealer = dealer(&context);
+        let endpoint = endpoint_for(address);
+        let enabled = ResolveConfig {
+            interval_ms: 10,
+            after_failed_reconnects: 1,
+        };
+        let mut reconnector = Reconnector::connect(
+            &context,
+            &dealer,
+            &endpoint,
+            "test peer",
+            &enabled,
+            resolver,
+        )
+        .unwrap();
+        assert!(reconnector.lookups.is_none());
+        assert!(!reconnector.poll(&dealer));
+        dealer.send("hello", 0).unwrap();
+        assert_eq!(router.recv_multipart(0).unwrap()[1], b"hello");
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+
+use crate::config::BrokerConfig;
+
+const MAX_BACKOFF_MS: u64 = 30_000; // cap unless retry_backoff_ms is larger
+
+//
+// ------------------------------- Restarts ------------------------------------
+//
+// The proxy and broker loops are run again when they fail, after a doubling
+// backoff, up to [broker] retry_attempts runs in all. Giving up sets the
+// shutdown flag so the rest of the service stops too.
+
+// The wait after failed run `retry` (1 for the first failure).
+pub fn backoff(config: &BrokerConfig, retry: u32) -> Duration {
+    let doubled = config
+        .retry_backoff_ms
+        .saturating_mul(2u64.saturating_pow(retry.saturating_sub(1)));
+    Duration::from_millis(doubled.min(MAX_BACKOFF_MS.max(config.retry_backoff_ms)))
+}
+
+// Runs `run` until it returns Ok, `shutdown` is set, or it has failed
+// retry_attempts times. `component` is the log prefix. Returns the number of
+// failed runs.
+pub fn run_with_retries<E: Display>(
+    config: &BrokerConfig,
+    component: &str,
+    shutdown: &AtomicBool,
+    mut run: impl FnMut() -> Result<(), E>,
+) -> u32 {
+    let mut retries = 0u32;
+    while !shutdown.load(Ordering::SeqCst) {
+        match run() {
+            Ok(_) => break,
+            Err(_) if shutdown.load(Ordering::SeqCst) => break,
+            Err(e) => {
+                retries += 1;
+                error!(
+                    "({}) Error: {} (attempt {}/{})",
+                    component, e, retries, config.retry_attempts
+                );
+                if retries >= config.retry_attempts {
+                    error!("({}) Exceeded max retries, shutting down", component);
+                    shutdown.store(true, Ordering::SeqCst);
+                    break;
+                }
+                thread::sleep(backoff(config, retries));
+            }
+        }
+    }
+    retries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(attempts: u32, backoff_ms: u64) -> BrokerConfig {
+        BrokerConfig {
+            retry_attempts: attempts,
+            retry_backoff_ms: backoff_ms,
+            ..BrokerConfig::default()
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_configured_attempts() {
+        let shutdown = AtomicBool::new(false);
+        let mut runs = 0;
+        let failed = run_with_retries(&config(3, 0), "Broker", &shutdown, || {
+            runs += 1;
+            Err("bind failed")
+        });
+        assert_eq!((runs, failed), (3, 3));
+        assert!(shutdown.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn a_later_success_ends_the_retries() {
+        let shutdown = AtomicBool::new(false);
+        let mut runs = 0;
+        let failed = run_with_retries(&config(5, 1), "Proxy", &shutdown, || {
+            runs += 1;
+            match runs {
+                1 | 2 => Err("not yet"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!((runs, failed), (3, 2));
+        assert!(!shutdown.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn the_backoff_doubles_up_to_the_cap() {
+        let defaults = BrokerConfig::default();
+        assert_eq!(backoff(&defaults, 1), Duration::from_secs(3));
+        assert_eq!(backoff(&defaults, 3), Duration::from_secs(12));
+        assert_eq!(backoff(&defaults, 10), Duration::from_secs(30));
+        assert_eq!(backoff(&config(10, 50), 2), Duration::from_millis(100));
+        assert_eq!(backoff(&config(10, 60_000), 4), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::{Acl, AclRules};
+use crate::budget::MemoryBudget;
+use crate::chaos::ChaosRules;
+use crate::config::Config;
+use crate::identity::Identities;
+use crate::limits::ConnectionLimits;
+use crate::metrics::Registry;
+use crate::quiesce::Quiesce;
+use crate::quota::Quotas;
+use crate::sample::SampleRules;
+use crate::schedule::Scheduler;
+use crate::seal::Keyring;
+use crate::socket::BoundEndpoints;
+use crate::store::StateStore;
+use crate::topics::Topics;
+
+// Process-wide services shared by the proxy and broker threads. Built once in
+// main and cloned into each component, so restarts from the retry loops keep
+// the same metrics and memory accounting.
+#[derive(Clone)]
+pub struct Runtime {
+    pub metrics: Registry,
+    pub budget: Arc<MemoryBudget>,
+    // Traffic sample rules, edited from the admin socket.
+    pub sampler: Arc<SampleRules>,
+    // Routing ids of the sockets the broker connects with.
+    pub identities: Arc<Identities>,
+    // Keys for records written to disk; None when [encryption] is off.
+    pub keyring: Option<Arc<Keyring>>,
+    // Persistent state; None when [storage] is off.
+    pub store: Option<Arc<dyn StateStore>>,
+    // Topic ACL rules, reloadable from the admin socket.
+    pub acl: Arc<Acl>,
+    // Proxy topic allowlist and rewrites, edited from the admin socket.
+    pub topics: Arc<Topics>,
+    // Messages waiting for their delivery time, listed and cancelled from
+    // the admin socket.
+    pub scheduler: Arc<Mutex<Scheduler>>,
+    // Set by the admin `quiesce` command; see crate::quiesce.
+    pub quiesce: Arc<Quiesce>,
+    // Fault injection rates, adjustable from the admin socket.
+    pub chaos: Arc<ChaosRules>,
+    // Connection caps and counts, adjustable from the admin socket.
+    pub limits: Arc<ConnectionLimits>,
+    // Request quotas and their usage, queried and topped up from the admin
+    // socket.
+    pub quotas: Arc<Quotas>,
+    // Where the proxy and broker sockets are bound, wildcards resolved.
+    pub endpoints: Arc<BoundEndpoints>,
+    // The config file the service was started from, for `acl reload`; None
+    // when it runs on built-in defaults.
+    pub config_path: Option<PathBuf>,
+}
+
+impl Runtime {
+    pub fn new(config: &Config) -> Self {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(
+            config.broker.memory_budget_mb.saturating_mul(1024 * 1024),
+            &config.broker.shed_order,
+            &metrics,
+        );
+        let scheduler = Scheduler::new(&config.schedule, &budget, &metrics);
+        let quiesce = Arc::new(Quiesce::new(&metrics));
+        let limits = Arc::new(ConnectionLimits::new(&config.limits, &metrics));
+        let quotas = Arc::new(Quotas::new(&config.quota, &metrics));
+        Self {
+            metrics,
+            budget,
+            sampler: Arc::new(SampleRules::default()),
+            identities: Arc::new(Identities::default()),
+            keyring: None,
+            store: None,
+            acl: Arc::new(Acl::new(AclRules::new(config.acl.principals.clone()))),
+            topics: Arc::new(Topics::new(&config.topics)),
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            quiesce,
+            chaos: Arc::new(ChaosRules::new(&config.chaos)),
+            limits,
+            quotas,
+            endpoints: Arc::new(BoundEndpoints::new(
+                config.network.endpoints_file.as_ref().map(PathBuf::from),
+            )),
+            config_path: None,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+use crate::format::{format_message, size_summary, Envelope, RenderStyle};
+use crate::multipart::Multipart;
+
+//
+// ---------------------------- Traffic sampler --------------------------------
+//
+// Per-topic-prefix sample rates for the pub/sub plane. Rules are edited from
+// the admin socket and copied into the proxy thread whenever their version
+// changes. Each forwarded publication costs one atomic load; with no rules
+// that is all it costs. With rules, the decision is a scan of at most
+// MAX_SAMPLE_RULES prefixes plus one xorshift step.
+//
+// Sampled messages are published as:
+//
+//     ["sample", {"topic", "size", "frames", "skipped"}, ...original frames]
+//
+// where "skipped" counts matching messages not sampled since the previous
+// sample of that rule, an estimate of the gap a viewer is not seeing.
+
+pub const MAX_SAMPLE_RULES: usize = 64;
+pub const SAMPLE_TOPIC: &[u8] = b"sample";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRule {
+    pub prefix: Vec<u8>,
+    pub rate: f64,
+}
+
+// The sampled prefixes and their rates, set and cleared by the admin
+// `sample` commands and read by the proxy when `version` moves.
+#[derive(Default)]
+pub struct SampleRules {
+    version: AtomicU64,
+    rules: Mutex<Vec<SampleRule>>,
+}
+
+impl SampleRules {
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn list(&self) -> Vec<SampleRule> {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Add or replace the rule for `prefix`; a rate of 0 removes it.
+    pub fn set(&self, prefix: &[u8], rate: f64) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("sample rate must be between 0 and 1, got {}", rate));
+        }
+        let mut rules = self.rules.lock().unwrap_or_else(|e| e.into_inner());
+        rules.retain(|r| r.prefix != prefix);
+        if rate > 0.0 {
+            if rules.len() >= MAX_SAMPLE_RULES {
+                return Err(format!("at most {} sample rules", MAX_SAMPLE_RULES));
+            }
+            rules.push(SampleRule {
+                prefix: prefix.to_vec(),
+                rate,
+            });
+            // Longest prefix first, so the most specific rule wins.
+            rules.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+        }
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+struct ActiveRule {
+    prefix: Vec<u8>,
+    // Sample when the next random u64 is below this.
+    threshold: u64,
+    skipped: u64,
+}
+
+// The proxy thread's view of the rules.
+pub struct Sampler {
+    version: u64,
+    rules: Vec<ActiveRule>,
+    rng: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            version: 0,
+            rules: Vec::new(),
+            rng: seed | 1,
+        }
+    }
+
+    fn refresh(&mut self, shared: &SampleRules) {
+        let version = shared.version();
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+        self.rules = shared
+            .list()
+            .into_iter()
+            .map(|r| ActiveRule {
+                prefix: r.prefix,
+                threshold: (r.rate * u64::MAX as f64) as u64,
+                skipped: 0,
+            })
+            .collect();
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+
+    // The sample message for `message`, if it is picked.
+    pub fn sample(&mut self, shared: &SampleRules, message: &[Vec<u8>]) -> Option<Multipart> {
+        self.refresh(shared);
+        if self.rules.is_empty() {
+            return None;
+        }
+        let topic = message.first()?;
+        let idx = self
+            .rules
+            .iter()
+            .position(|r| topic.starts_with(&r.prefix))?;
+        let roll = self.next_random();
+        let rule = &mut self.rules[idx];
+        if roll >= rule.threshold && rule.threshold != u64::MAX {
+            rule.skipped += 1;
+            return None;
+        }
+        let skipped = std::mem::take(&mut rule.skipped);
+        let meta = json!({
+            "topic": String::from_utf8_lossy(topic),
+            "size": message.iter().map(Vec::len).sum::<usize>(),
+            "frames": message.len(),
+            "skipped": skipped,
+        });
+        let mut frames = Vec::with_capacity(message.len() + 2);
+        frames.push(SAMPLE_TOPIC.to_vec());
+        frames.push(meta.to_string().into_bytes());
+        frames.extend(message.iter().cloned());
+        Some(Multipart::new(frames))
+    }
+}
+
+// One sample as `corky-zmq monitor` prints it: its size, the skipped count
+// and the sampled message, such as `sample [2 frames, 19 bytes] skipped=99:
+// topic "prices.eq.AAPL": {"px":189.5}`.
+pub fn describe(message: &[Vec<u8>]) -> Result<String, String> {
+    let [topic, meta, frames @ ..] = message else {
+        return Err(format!(
+            "expected [sample, meta, ...], got {} frames",
+            message.len()
+        ));
+    };
+    if topic != SAMPLE_TOPIC {
+        return Err(format!(
+            "not a sample: {:?}",
+            String::from_utf8_lossy(topic)
+        ));
+    }
+    let meta: Value = serde_json::from_slice(meta).map_err(|e| format!("sample meta: {}", e))?;
+    let skipped = meta["skipped"]
+        .as_u64()
+        .ok_or("sample meta has no skipped count")?;
+    Ok(format!(
+        "sample {} skipped={}: {}",
+        size_summary(frames),
+        skipped,
+        format_message(frames, Envelope::PubSub, RenderStyle::Compact)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(sampler: &mut Sampler, rules: &SampleRules, topic: &str, n: usize) -> usize {
+        let message = vec![topic.as_bytes().to_vec(), b"payload".to_vec()];
+        (0..n)
+            .filter(|_| sampler.sample(rules, &message).is_some())
+            .count()
+    }
+
+    #[test]
+    fn sample_rates_are_approximately_honored() {
+        let rules = SampleRules::default();
+        rules.set(b"prices.", 0.01).unwrap();
+        rules.set(b"prices.fx.", 0.2).unwrap();
+        let mut sampler = Sampler::new(42);
+        let n = 200_000;
+        let coarse = stream(&mut sampler, &rules, "prices.eq.AAPL", n);
+        let fine = stream(&mut sampler, &rules, "prices.fx.EURUSD", n);
+        let none = stream(&mut sampler, &rules, "orders.new", n);
+        assert!((1_600..=2_400).contains(&coarse), "{} of {}", coarse, n);
+        assert!((38_000..=42_000).contains(&fine), "{} of {}", fine, n);
+        assert_eq!(none, 0);
+    }
+
+    #[test]
+    fn rule_changes_apply_without_a_new_sampler() {
+        let rules = SampleRules::default();
+        let mut sampler = Sampler::new(7);
+        assert_eq!(stream(&mut sampler, &rules, "t", 1000), 0);
+        rules.set(b"t", 1.0).unwrap();
+        assert_eq!(stream(&mut sampler, &rules, "t", 1000), 1000);
+        rules.set(b"t", 0.0).unwrap();
+        assert!(rules.list().is_empty());
+        assert_eq!(stream(&mut sampler, &rules, "t", 1000), 0);
+        assert!(rules.set(b"t", 1.5).is_err());
+    }
+
+    #[test]
+    fn sample_message_reports_skipped_messages() {
+        let rules = SampleRules::default();
+        rules.set(b"", 0.5).unwrap();
+        let mut sampler = Sampler::new(99);
+        let message = vec![b"topic".to_vec(), b"abc".to_vec()];
+        let mut seen = 0u64;
+        let mut reported = 0u64;
+        for _ in 0..1000 {
+            seen += 1;
+            if let Some(sample) = sampler.sample(&rules, &message) {
+                let meta: serde_json::Value = serde_json::from_slice(&sample[1]).unwrap();
+                assert_eq!(meta["size"], 8);
+                assert_eq!(&sample[2..], &message[..]);
+                reported += meta["skipped"].as_u64().unwrap() + 1;
+                seen = 0;
+            }
+        }
+        assert_eq!(reported + seen, 1000);
+    }
+
+    #[test]
+    fn monitor_lines_show_the_size_gap_and_message() {
+        let rules = SampleRules::default();
+        rules.set(b"prices.", 1.0).unwrap();
+        let message = vec![b"prices.eq.AAPL".to_vec(), br#"{"px":189.5}"#.to_vec()];
+        let sample = Sampler::new(1).sample(&rules, &message).unwrap();
+        assert_eq!(
+            describe(&sample.into_frames()).unwrap(),
+            r#"sample [2 frames, 26 bytes] skipped=0: topic "prices.eq.AAPL": {"px":189.5}"#
+        );
+        assert!(describe(&[b"prices.eq.AAPL".to_vec(), b"{}".to_vec()]).is_err());
+        assert!(describe(&[SAMPLE_TOPIC.to_vec(), b"{}".to_vec()]).is_err());
+    }
+}
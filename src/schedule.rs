@@ -0,0 +1,838 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::budget::{BudgetedQueue, MemoryBudget, Pool, Shed};
+use crate::config::ScheduleConfig;
+use crate::metrics::{Counter, Gauge, Registry};
+use crate::multipart::Multipart;
+use crate::store::StateStore;
+use crate::timer::deadline_after;
+
+//
+// --------------------------- Delayed delivery --------------------------------
+//
+// Client-to-client messages and pipeline tasks may carry a JSON header frame
+// asking the broker to hold them:
+//
+//     direct:   [target_id, {"delay_ms": N} | {"deliver_at": unix_ms}, payload..]
+//     pipeline: [{"delay_ms": N} | {"deliver_at": unix_ms}, task..]
+//
+// The header may also name a "cancel_id". The broker strips the header and
+// keeps the message in a heap keyed by deadline, charged to the offline pool
+// of the memory budget, and hands it on from the poll loop once due. A due
+// direct message whose target is not connected moves to the offline queue
+// below and is retried until the target shows up or offline_ttl_ms passes.
+//
+// The scheduler itself acts as a direct peer with the identity
+// "__corky_scheduler__". A client cancels its own pending messages with
+//
+//     [__corky_scheduler__, {"cancel_id": "..."}]
+//
+// and gets [__corky_scheduler__, {"cancelled": n}] back. Rejected headers are
+// answered the same way with {"error": {"code": "schedule_rejected",
+// "message"}}. Pending messages live in memory only and are lost when the
+// process exits.
+
+pub const SCHEDULER_ID: &[u8] = b"__corky_scheduler__";
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct ScheduleHeader {
+    delay_ms: Option<u64>,
+    deliver_at: Option<u64>,
+    cancel_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum When {
+    After(Duration),
+    // Wall-clock time, milliseconds since the Unix epoch.
+    At(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleSpec {
+    pub when: When,
+    pub cancel_id: Option<String>,
+}
+
+impl ScheduleSpec {
+    // None when `frame` is not a schedule header at all, like FanoutSpec.
+    pub fn parse(frame: &[u8]) -> Option<Result<ScheduleSpec, String>> {
+        if frame.first() != Some(&b'{')
+            || !(contains(frame, b"\"delay_ms\"") || contains(frame, b"\"deliver_at\""))
+        {
+            return None;
+        }
+        let header = match serde_json::from_slice::<ScheduleHeader>(frame) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(format!("invalid schedule header: {}", e))),
+        };
+        let when = match (header.delay_ms, header.deliver_at) {
+            (Some(delay), None) => When::After(Duration::from_millis(delay)),
+            (None, Some(at)) => When::At(at),
+            _ => return Some(Err("set exactly one of delay_ms and deliver_at".into())),
+        };
+        Some(Ok(ScheduleSpec {
+            when,
+            cancel_id: header.cancel_id,
+        }))
+    }
+
+    // The monotonic deadline; a deliver_at in the past is due immediately.
+    pub fn deadline(&self, now: Instant, wall: SystemTime) -> Instant {
+        match self.when {
+            When::After(delay) => deadline_after(now, delay),
+            When::At(at) => {
+                let wall_ms = wall
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                deadline_after(now, Duration::from_millis(at.saturating_sub(wall_ms)))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CancelCommand {
+    cancel_id: String,
+}
+
+// The cancel_id of a [__corky_scheduler__, {"cancel_id"}] command.
+pub fn parse_cancel(frame: &[u8]) -> Result<String, String> {
+    serde_json::from_slice::<CancelCommand>(frame)
+        .map(|c| c.cancel_id)
+        .map_err(|e| format!("invalid cancel command: {}", e))
+}
+
+pub fn cancel_reply(client: &[u8], cancelled: usize) -> Multipart {
+    let reply = json!({"cancelled": cancelled});
+    Multipart::new(vec![
+        client.to_vec(),
+        SCHEDULER_ID.to_vec(),
+        reply.to_string().into_bytes(),
+    ])
+}
+
+pub fn rejected_reply(client: &[u8], reason: &str) -> Multipart {
+    let reply = json!({"error": {"code": "schedule_rejected", "message": reason}});
+    Multipart::new(vec![
+        client.to_vec(),
+        SCHEDULER_ID.to_vec(),
+        reply.to_string().into_bytes(),
+    ])
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    // Sent on the direct ROUTER; the message starts with the target id.
+    Direct,
+    // Handed to the pipeline relay.
+    Pipeline,
+}
+
+impl Destination {
+    fn label(self) -> &'static str {
+        match self {
+            Destination::Direct => "direct",
+            Destination::Pipeline => "pipeline",
+        }
+    }
+}
+
+// A message waiting for its deadline.
+pub struct Scheduled {
+    pub id: u64,
+    pub destination: Destination,
+    // Who may cancel it: the sender's identity, empty for pipeline tasks.
+    pub owner: Vec<u8>,
+    pub cancel_id: Option<String>,
+    pub deadline: Instant,
+    pub message: Multipart,
+    bytes: u64,
+}
+
+pub struct Scheduler {
+    max_delay: Duration,
+    max_pending: usize,
+    budget: Arc<MemoryBudget>,
+    next_id: u64,
+    // Deadlines of pending entries; cancelled ones are skipped lazily.
+    heap: BinaryHeap<Reverse<(Instant, u64)>>,
+    entries: HashMap<u64, Scheduled>,
+    pending: Gauge,
+    scheduled: Counter,
+    delivered: Counter,
+    cancelled: Counter,
+    rejected: Counter,
+}
+
+impl Scheduler {
+    pub fn new(config: &ScheduleConfig, budget: &Arc<MemoryBudget>, metrics: &Registry) -> Self {
+        let outcome = |label| metrics.counter("corky_schedule_total", &[("outcome", label)]);
+        Self {
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            max_pending: config.max_pending,
+            budget: Arc::clone(budget),
+            next_id: 1,
+            heap: BinaryHeap::new(),
+            entries: HashMap::new(),
+            pending: metrics.gauge("corky_schedule_pending", &[]),
+            scheduled: outcome("scheduled"),
+            delivered: outcome("delivered"),
+            cancelled: outcome("cancelled"),
+            rejected: outcome("rejected"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Hold `message` until the deadline of `spec`. Returns the entry id.
+    pub fn schedule(
+        &mut self,
+        destination: Destination,
+        owner: &[u8],
+        spec: ScheduleSpec,
+        message: Multipart,
+        now: Instant,
+        wall: SystemTime,
+    ) -> Result<u64, String> {
+        let deadline = spec.deadline(now, wall);
+        let refusal = if deadline.saturating_duration_since(now) > self.max_delay {
+            Some(format!(
+                "delivery time is more than {}ms away",
+                self.max_delay.as_millis()
+            ))
+        } else if self.entries.len() >= self.max_pending {
+            Some(format!("{} messages already scheduled", self.max_pending))
+        } else if self.budget.over_budget() {
+            Some("memory budget exceeded".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            self.rejected.inc();
+            return Err(reason);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let bytes: u64 = message.iter().map(|f| f.len() as u64).sum();
+        self.budget.charge(Pool::Offline, bytes);
+        self.heap.push(Reverse((deadline, id)));
+        self.entries.insert(
+            id,
+            Scheduled {
+                id,
+                destination,
+                owner: owner.to_vec(),
+                cancel_id: spec.cancel_id,
+                deadline,
+                message,
+                bytes,
+            },
+        );
+        self.scheduled.inc();
+        self.pending.set(self.entries.len() as i64);
+        Ok(id)
+    }
+
+    // Remove and return every entry due at `now`, earliest first.
+    pub fn due(&mut self, now: Instant) -> Vec<Scheduled> {
+        let mut due = Vec::new();
+        while let Some(&Reverse((deadline, id))) = self.heap.peek() {
+            if deadline > now {
+                break;
+            }
+            self.heap.pop();
+            if let Some(entry) = self.take(id) {
+                due.push(entry);
+            }
+        }
+        self.delivered.add(due.len() as u64);
+        due
+    }
+
+    // The earliest deadline; may be a cancelled entry's, which only wakes
+    // the loop early.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    // Cancel the entries named `cancel_id`, only those of `owner` if given.
+    pub fn cancel(&mut self, owner: Option<&[u8]>, cancel_id: &str) -> usize {
+        let ids: Vec<u64> = self
+            .entries
+            .values()
+            .filter(|e| e.cancel_id.as_deref() == Some(cancel_id))
+            .filter(|e| owner.is_none_or(|o| e.owner == o))
+            .map(|e| e.id)
+            .collect();
+        for &id in &ids {
+            self.take(id);
+        }
+        // Rebuild once stale heap entries dominate.
+        if self.heap.len() > 2 * self.entries.len() + 64 {
+            self.heap = self
+                .entries
+                .values()
+                .map(|e| Reverse((e.deadline, e.id)))
+                .collect();
+        }
+        self.cancelled.add(ids.len() as u64);
+        ids.len()
+    }
+
+    // One line per pending entry in deadline order, for `schedule list`.
+    pub fn describe(&self, now: Instant) -> Vec<String> {
+        let mut entries: Vec<&Scheduled> = self.entries.values().collect();
+        entries.sort_by_key(|e| (e.deadline, e.id));
+        entries
+            .into_iter()
+            .map(|e| {
+                let target = match e.destination {
+                    Destination::Direct => e
+                        .message
+                        .first()
+                        .map(|t| String::from_utf8_lossy(t).into_owned())
+                        .unwrap_or_default(),
+                    Destination::Pipeline => "-".to_string(),
+                };
+                format!(
+                    "{} {} {} due_in_ms={} cancel_id={} bytes={}",
+                    e.id,
+                    e.destination.label(),
+                    target,
+                    e.deadline.saturating_duration_since(now).as_millis(),
+                    e.cancel_id.as_deref().unwrap_or("-"),
+                    e.bytes
+                )
+            })
+            .collect()
+    }
+
+    fn take(&mut self, id: u64) -> Option<Scheduled> {
+        let entry = self.entries.remove(&id)?;
+        self.budget.release(Pool::Offline, entry.bytes);
+        self.pending.set(self.entries.len() as i64);
+        Some(entry)
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        let bytes = self.entries.values().map(|e| e.bytes).sum();
+        self.budget.release(Pool::Offline, bytes);
+        self.pending.set(0);
+    }
+}
+
+//
+// ----------------------------- Offline queue ---------------------------------
+//
+// Direct messages the broker could not deliver because the target was not
+// connected. Each retry tick resends them; entries older than the TTL are
+// dropped, and the memory budget sheds from here oldest-first. With [storage]
+// on, every entry is also kept in the state store until it leaves the queue,
+// so a restarted broker picks up where it left off:
+//
+//     "offline": id u64 BE  ->  queued unix ms u64 | frames u32
+//                               | (len u32 + bytes)..
+
+const STORE_NAMESPACE: &str = "offline";
+
+struct Held {
+    id: u64,
+    queued_at: Instant,
+    message: Multipart,
+}
+
+pub struct OfflineQueue {
+    ttl: Duration,
+    queue: BudgetedQueue<Held>,
+    next_id: u64,
+    store: Option<Arc<dyn StateStore>>,
+    queued: Counter,
+    delivered: Counter,
+    expired: Counter,
+}
+
+impl OfflineQueue {
+    pub fn new(config: &ScheduleConfig, budget: &Arc<MemoryBudget>, metrics: &Registry) -> Self {
+        let outcome = |label| metrics.counter("corky_offline_total", &[("outcome", label)]);
+        Self {
+            ttl: Duration::from_millis(config.offline_ttl_ms),
+            queue: BudgetedQueue::new(Pool::Offline, budget),
+            next_id: 1,
+            store: None,
+            queued: outcome("queued"),
+            delivered: outcome("delivered"),
+            expired: outcome("expired"),
+        }
+    }
+
+    // Load the entries `store` kept from the last run, with their age, and
+    // keep new ones there too.
+    pub fn with_store(
+        mut self,
+        store: Arc<dyn StateStore>,
+        now: Instant,
+        wall: SystemTime,
+    ) -> Self {
+        let stored = store.scan(STORE_NAMESPACE).unwrap_or_else(|e| {
+            warn!("(Broker) Starting the offline queue empty: {}", e);
+            Vec::new()
+        });
+        let wall_ms = unix_ms(wall);
+        for (key, value) in stored {
+            let id = key.as_slice().try_into().map(u64::from_be_bytes);
+            let (Ok(id), Some((queued_ms, message))) = (id, decode_held(&value)) else {
+                warn!("(Broker) Skipping an unreadable offline entry");
+                continue;
+            };
+            let age = Duration::from_millis(wall_ms.saturating_sub(queued_ms));
+            let queued_at = now.checked_sub(age).unwrap_or(now);
+            let bytes = message.iter().map(|f| f.len() as u64).sum();
+            self.queue.push_back(
+                Held {
+                    id,
+                    queued_at,
+                    message,
+                },
+                bytes,
+            );
+            self.next_id = self.next_id.max(id + 1);
+        }
+        self.store = Some(store);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, message: Multipart, now: Instant) {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(store) = &self.store {
+            let value = encode_held(unix_ms(SystemTime::now()), &message);
+            if let Err(e) = store.put(STORE_NAMESPACE, &id.to_be_bytes(), &value) {
+                warn!("(Broker) Offline entry kept in memory only: {}", e);
+            }
+        }
+        let bytes = message.iter().map(|f| f.len() as u64).sum();
+        self.queue.push_back(
+            Held {
+                id,
+                queued_at: now,
+                message,
+            },
+            bytes,
+        );
+        self.queued.inc();
+    }
+
+    // Try every entry with `send`; kept are those it reports as still
+    // unreachable (Ok(false)) and not yet expired.
+    pub fn retry(&mut self, now: Instant, mut send: impl FnMut(&Multipart) -> bool) {
+        let (ttl, delivered, expired) = (self.ttl, &self.delivered, &self.expired);
+        let mut gone = Vec::new();
+        self.queue.retain(|held| {
+            if now.saturating_duration_since(held.queued_at) > ttl {
+                expired.inc();
+                gone.push(held.id);
+                return false;
+            }
+            let sent = send(&held.message);
+            if sent {
+                delivered.inc();
+                gone.push(held.id);
+            }
+            !sent
+        });
+        for id in gone {
+            self.forget(id);
+        }
+    }
+
+    fn forget(&self, id: u64) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.delete(STORE_NAMESPACE, &id.to_be_bytes()) {
+                warn!("(Broker) Offline entry {} left in the store: {}", id, e);
+            }
+        }
+    }
+}
+
+impl Shed for OfflineQueue {
+    fn pool(&self) -> Pool {
+        self.queue.pool()
+    }
+
+    fn shed_oldest(&mut self) -> Option<u64> {
+        let id = self.queue.front()?.id;
+        let bytes = self.queue.shed_oldest()?;
+        self.forget(id);
+        Some(bytes)
+    }
+}
+
+fn unix_ms(wall: SystemTime) -> u64 {
+    wall.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn encode_held(queued_ms: u64, message: &Multipart) -> Vec<u8> {
+    let size = message.iter().map(|f| f.len() + 4).sum::<usize>();
+    let mut out = Vec::with_capacity(12 + size);
+    out.extend_from_slice(&queued_ms.to_le_bytes());
+    out.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    for frame in message.iter() {
+        out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        out.extend_from_slice(frame);
+    }
+    out
+}
+
+fn decode_held(mut bytes: &[u8]) -> Option<(u64, Multipart)> {
+    let mut take = |n: usize| {
+        let (head, rest) = bytes.split_at_checked(n)?;
+        bytes = rest;
+        Some(head)
+    };
+    let queued_ms = u64::from_le_bytes(take(8)?.try_into().ok()?);
+    let count = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let mut frames = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        frames.push(take(len)?.to_vec());
+    }
+    Some((queued_ms, Multipart::new(frames)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::FileStore;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn config() -> ScheduleConfig {
+        ScheduleConfig {
+            enabled: true,
+            max_delay_ms: 60_000,
+            max_pending: 3,
+            ..ScheduleConfig::default()
+        }
+    }
+
+    fn spec(header: &str) -> ScheduleSpec {
+        ScheduleSpec::parse(header.as_bytes()).unwrap().unwrap()
+    }
+
+    fn message(target: &str, payload: &str) -> Multipart {
+        Multipart::new(vec![target.into(), b"sender".to_vec(), payload.into()])
+    }
+
+    fn payloads(due: Vec<Scheduled>) -> Vec<Vec<u8>> {
+        due.into_iter().map(|e| e.message[2].clone()).collect()
+    }
+
+    #[test]
+    fn headers_are_recognised_and_validated() {
+        assert_eq!(ScheduleSpec::parse(b"plain payload"), None);
+        assert_eq!(ScheduleSpec::parse(br#"{"price": 1}"#), None);
+        assert_eq!(
+            spec(r#"{"delay_ms": 30000, "cancel_id": "r1"}"#),
+            ScheduleSpec {
+                when: When::After(Duration::from_secs(30)),
+                cancel_id: Some("r1".into()),
+            }
+        );
+        assert_eq!(spec(r#"{"deliver_at": 5}"#).when, When::At(5));
+        for bad in [
+            r#"{"delay_ms": 1, "deliver_at": 2}"#,
+            r#"{"delay_ms": -1}"#,
+            r#"{"delay_ms": 1, "extra": true}"#,
+        ] {
+            assert!(
+                ScheduleSpec::parse(bad.as_bytes()).unwrap().is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(parse_cancel(br#"{"cancel_id": "r1"}"#).unwrap(), "r1");
+        assert!(parse_cancel(b"r1").is_err());
+    }
+
+    #[test]
+    fn deliver_at_is_mapped_onto_the_mock_clock() {
+        let now = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_secs(1_000);
+        let at = spec(r#"{"deliver_at": 1000250}"#);
+        assert_eq!(at.deadline(now, wall), now + 250 * MS);
+        // Already past: due right away.
+        let past = spec(r#"{"deliver_at": 999000}"#);
+        assert_eq!(past.deadline(now, wall), now);
+    }
+
+    #[test]
+    fn delays_past_the_end_of_time_are_refused() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let mut scheduler = Scheduler::new(&config(), &budget, &metrics);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        for header in [
+            r#"{"delay_ms": 18446744073709551615}"#,
+            r#"{"deliver_at": 18446744073709551615}"#,
+        ] {
+            let result = scheduler.schedule(
+                Destination::Direct,
+                b"sender",
+                spec(header),
+                message("t", "x"),
+                now,
+                wall,
+            );
+            assert!(result.is_err(), "{}", header);
+        }
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn due_entries_come_out_in_deadline_order() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let mut scheduler = Scheduler::new(&config(), &budget, &metrics);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        for (delay, payload) in [(30, "c"), (10, "a"), (20, "b")] {
+            let header = format!(r#"{{"delay_ms": {}}}"#, delay);
+            scheduler
+                .schedule(
+                    Destination::Direct,
+                    b"sender",
+                    spec(&header),
+                    message("t", payload),
+                    now,
+                    wall,
+                )
+                .unwrap();
+        }
+        assert!(budget.pool_bytes(Pool::Offline) > 0);
+        assert_eq!(scheduler.next_deadline(), Some(now + 10 * MS));
+        assert!(scheduler.due(now + 9 * MS).is_empty());
+        assert_eq!(
+            payloads(scheduler.due(now + 20 * MS)),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(
+            scheduler.describe(now + 20 * MS),
+            vec!["1 direct t due_in_ms=10 cancel_id=- bytes=8"]
+        );
+        assert_eq!(payloads(scheduler.due(now + 60 * MS)), vec![b"c".to_vec()]);
+        assert!(scheduler.is_empty());
+        assert_eq!(budget.pool_bytes(Pool::Offline), 0);
+    }
+
+    #[test]
+    fn cancellation_is_scoped_to_the_owner() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let mut scheduler = Scheduler::new(&config(), &budget, &metrics);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let header = spec(r#"{"delay_ms": 10, "cancel_id": "r1"}"#);
+        scheduler
+            .schedule(
+                Destination::Direct,
+                b"alice",
+                header.clone(),
+                message("t", "a"),
+                now,
+                wall,
+            )
+            .unwrap();
+        scheduler
+            .schedule(
+                Destination::Direct,
+                b"bob",
+                header,
+                message("t", "b"),
+                now,
+                wall,
+            )
+            .unwrap();
+
+        assert_eq!(scheduler.cancel(Some(b"mallory"), "r1"), 0);
+        assert_eq!(scheduler.cancel(Some(b"alice"), "r1"), 1);
+        assert_eq!(payloads(scheduler.due(now + 10 * MS)), vec![b"b".to_vec()]);
+        // The admin socket cancels regardless of owner.
+        let header = spec(r#"{"delay_ms": 10, "cancel_id": "r2"}"#);
+        scheduler
+            .schedule(
+                Destination::Pipeline,
+                b"",
+                header,
+                message("t", "c"),
+                now,
+                wall,
+            )
+            .unwrap();
+        assert_eq!(scheduler.cancel(None, "r2"), 1);
+        assert!(scheduler.due(now + 10 * MS).is_empty());
+        let cancelled = metrics.counter("corky_schedule_total", &[("outcome", "cancelled")]);
+        assert_eq!(cancelled.get(), 2);
+    }
+
+    #[test]
+    fn limits_reject_new_entries() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let mut scheduler = Scheduler::new(&config(), &budget, &metrics);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let far = spec(r#"{"delay_ms": 60001}"#);
+        let err = scheduler
+            .schedule(Destination::Direct, b"s", far, message("t", "x"), now, wall)
+            .unwrap_err();
+        assert!(err.contains("more than 60000ms"), "{}", err);
+        for _ in 0..3 {
+            scheduler
+                .schedule(
+                    Destination::Direct,
+                    b"s",
+                    spec(r#"{"delay_ms": 1}"#),
+                    message("t", "x"),
+                    now,
+                    wall,
+                )
+                .unwrap();
+        }
+        assert!(scheduler
+            .schedule(
+                Destination::Direct,
+                b"s",
+                spec(r#"{"delay_ms": 1}"#),
+                message("t", "x"),
+                now,
+                wall
+            )
+            .is_err());
+        let rejected = metrics.counter("corky_schedule_total", &[("outcome", "rejected")]);
+        assert_eq!(rejected.get(), 2);
+    }
+
+    #[test]
+    fn restart_loses_pending_entries_and_their_charge() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        {
+            let mut scheduler = Scheduler::new(&config(), &budget, &metrics);
+            scheduler
+                .schedule(
+                    Destination::Direct,
+                    b"s",
+                    spec(r#"{"delay_ms": 5}"#),
+                    message("t", "x"),
+                    now,
+                    wall,
+                )
+                .unwrap();
+            assert!(budget.used() > 0);
+        }
+        assert_eq!(budget.used(), 0);
+        let mut restarted = Scheduler::new(&config(), &budget, &metrics);
+        assert!(restarted.due(now + 10 * MS).is_empty());
+    }
+
+    #[test]
+    fn offline_entries_retry_until_delivered_or_expired() {
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let config = ScheduleConfig {
+            offline_ttl_ms: 100,
+            ..config()
+        };
+        let mut offline = OfflineQueue::new(&config, &budget, &metrics);
+        let now = Instant::now();
+        offline.push(message("online-later", "a"), now);
+        offline.push(message("never", "b"), now);
+
+        offline.retry(now + 50 * MS, |_| false);
+        assert_eq!(offline.len(), 2);
+        offline.retry(now + 60 * MS, |m| m[0] == b"online-later");
+        assert_eq!(offline.len(), 1);
+        offline.retry(now + 101 * MS, |_| panic!("expired entries are not sent"));
+        assert!(offline.is_empty());
+        assert_eq!(budget.pool_bytes(Pool::Offline), 0);
+        let expired = metrics.counter("corky_offline_total", &[("outcome", "expired")]);
+        assert_eq!(expired.get(), 1);
+    }
+
+    #[test]
+    fn stored_offline_entries_outlive_the_queue() {
+        let dir = std::env::temp_dir().join(format!("corky-offline-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store: Arc<dyn StateStore> = Arc::new(FileStore::open(&dir).unwrap());
+        let metrics = Registry::new();
+        let budget = MemoryBudget::new(1 << 20, &[Pool::Offline], &metrics);
+        let config = ScheduleConfig {
+            offline_ttl_ms: 100,
+            ..config()
+        };
+        let now = Instant::now();
+        {
+            let mut offline = OfflineQueue::new(&config, &budget, &metrics).with_store(
+                store.clone(),
+                now,
+                SystemTime::now(),
+            );
+            offline.push(message("delivered", "a"), now);
+            offline.push(message("shed", "b"), now);
+            offline.push(message("later", "c"), now);
+            offline.retry(now, |m| m[0] == b"delivered");
+        }
+        let mut offline = OfflineQueue::new(&config, &budget, &metrics).with_store(
+            store.clone(),
+            now,
+            SystemTime::now(),
+        );
+        assert_eq!(offline.len(), 2);
+        assert!(offline.shed_oldest().is_some());
+        let mut sent = Vec::new();
+        offline.retry(now, |m| {
+            sent.push(m[2].clone());
+            true
+        });
+        assert_eq!(sent, [b"c".to_vec()]);
+        // Kept with their age: a reload past the TTL expires them.
+        offline.push(message("stale", "d"), now);
+        drop(offline);
+        let wall = SystemTime::now() + 200 * MS;
+        let mut offline =
+            OfflineQueue::new(&config, &budget, &metrics).with_store(store.clone(), now, wall);
+        offline.retry(now, |_| panic!("expired entries are not sent"));
+        assert!(store.scan(STORE_NAMESPACE).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::config::EncryptionConfig;
+
+//
+// --------------------------- Encryption at rest ------------------------------
+//
+// Authenticated encryption for the records of the request journal
+// (crate::journal), the only file the broker seals. Every record is sealed
+// on its own with XChaCha20-Poly1305 and a random 24-byte nonce:
+//
+//     "CRKSEAL1" | key id (8) | nonce (24) | ciphertext | tag (16)
+//
+// The key id is a fingerprint of the key, so a reader picks the right key
+// during rotation: the current key seals new records and previous keys still
+// open old ones. Callers pass associated data (e.g. file name and record
+// sequence) that is authenticated but not stored, so a record moved to
+// another position fails to open.
+//
+// A key comes from a file holding 32 raw bytes or 64 hex characters, which
+// must not be readable by group or others, or is derived with HKDF-SHA256
+// from a secret in an environment variable.
+
+pub const SEAL_MAGIC: &[u8; 8] = b"CRKSEAL1";
+pub const KEY_LEN: usize = 32;
+pub const KEY_ID_LEN: usize = 8;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+pub const SEAL_OVERHEAD: usize = SEAL_MAGIC.len() + KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+const KDF_SALT: &[u8] = b"corky-zmq at-rest v1";
+const KDF_INFO: &[u8] = b"record key";
+const KEY_ID_CONTEXT: &[u8] = b"corky-zmq key id";
+
+pub struct Key {
+    id: [u8; KEY_ID_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    pub fn from_bytes(bytes: &[u8; KEY_LEN]) -> Self {
+        let digest = Sha256::new()
+            .chain_update(KEY_ID_CONTEXT)
+            .chain_update(bytes)
+            .finalize();
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        Self {
+            id,
+            cipher: XChaCha20Poly1305::new(bytes.into()),
+        }
+    }
+
+    // Derive a key from a passphrase-like secret.
+    pub fn derive(secret: &[u8]) -> Result<Self, String> {
+        if secret.is_empty() {
+            return Err("encryption secret is empty".to_string());
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(KDF_SALT), secret)
+            .expand(KDF_INFO, &mut bytes)
+            .map_err(|e| format!("key derivation failed: {}", e))?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        check_permissions(path)?;
+        let content =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let bytes = parse_key(&content).map_err(|e| format!("{} in {}", e, path.display()))?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    pub fn from_env(var: &str) -> Result<Self, String> {
+        let secret = std::env::var(var).map_err(|_| format!("{} is not set", var))?;
+        Self::derive(secret.as_bytes()).map_err(|e| format!("{}: {}", var, e))
+    }
+
+    pub fn id(&self) -> [u8; KEY_ID_LEN] {
+        self.id
+    }
+}
+
+fn parse_key(content: &[u8]) -> Result<[u8; KEY_LEN], String> {
+    if content.len() == KEY_LEN {
+        return Ok(content.try_into().expect("length checked"));
+    }
+    let hex = std::str::from_utf8(content)
+        .map(str::trim)
+        .map_err(|_| "key must be 32 raw bytes or 64 hex characters")?;
+    if hex.len() != KEY_LEN * 2 {
+        return Err("key must be 32 raw bytes or 64 hex characters".to_string());
+    }
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "key is not valid hex".to_string())?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(unix)]
+pub fn check_permissions(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)
+        .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "{} is accessible by group or others (mode {:o}); chmod 600 it",
+            path.display(),
+            mode & 0o777
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_permissions(_path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+// The current key plus the previous ones still accepted for reading.
+pub struct Keyring {
+    current: Key,
+    previous: Vec<Key>,
+}
+
+impl Keyring {
+    pub fn new(current: Key, previous: Vec<Key>) -> Self {
+        Self { current, previous }
+    }
+
+    // None when encryption is off.
+    pub fn from_config(config: &EncryptionConfig) -> Result<Option<Self>, String> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let current = match (&config.key_file, &config.key_env) {
+            (Some(file), None) => Key::from_file(Path::new(file))?,
+            (None, Some(var)) => Key::from_env(var)?,
+            _ => return Err("[encryption] needs exactly one of key_file, key_env".to_string()),
+        };
+        let mut previous = Vec::new();
+        for file in &config.previous_key_files {
+            previous.push(Key::from_file(Path::new(file))?);
+        }
+        for var in &config.previous_key_envs {
+            previous.push(Key::from_env(var)?);
+        }
+        Ok(Some(Self::new(current, previous)))
+    }
+
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        seal_with_nonce(&self.current, &nonce, plaintext, aad)
+    }
+
+    pub fn open(&self, record: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        if record.len() < SEAL_OVERHEAD || !record.starts_with(SEAL_MAGIC) {
+            return Err("not a sealed record".to_string());
+        }
+        let (id, rest) = record[SEAL_MAGIC.len()..].split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|k| k.id[..] == *id)
+            .ok_or_else(|| format!("record sealed with unknown key {}", hex(id)))?;
+        key.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| "record failed authentication (tampered or truncated)".to_string())
+    }
+}
+
+fn seal_with_nonce(key: &Key, nonce: &XNonce, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let ciphertext = key
+        .cipher
+        .encrypt(
+            nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("XChaCha20-Poly1305 encryption cannot fail for in-memory buffers");
+    let mut record = Vec::with_capacity(SEAL_OVERHEAD + plaintext.len());
+    record.extend_from_slice(SEAL_MAGIC);
+    record.extend_from_slice(&key.id);
+    record.extend_from_slice(nonce);
+    record.extend_from_slice(&ciphertext);
+    record
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+//
+// ---------------------------- Sealed record files ----------------------------
+//
+// Files of sealed records, each prefixed with its length as a u32 BE. The
+// record's index in the file is its associated data, so records cannot be
+// reordered or spliced between positions unnoticed.
+
+pub fn write_record(
+    out: &mut impl Write,
+    keyring: &Keyring,
+    index: u64,
+    plaintext: &[u8],
+) -> io::Result<()> {
+    let record = keyring.seal(plaintext, &index.to_be_bytes());
+    let len = u32::try_from(record.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(&record)
+}
+
+// What recovery found in a sealed record file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Recovered {
+    pub records: Vec<Vec<u8>>,
+    // Bytes after the last complete record, e.g. from a crash mid-write.
+    pub truncated_tail: usize,
+}
+
+// Read every record back. A torn final record is reported, not an error; a
+// complete record that fails to open stops recovery with its index.
+pub fn read_records(input: &mut impl Read, keyring: &Keyring) -> Result<Recovered, String> {
+    let mut data = Vec::new();
+    input
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Failed to read records: {}", e))?;
+    let mut recovered = Recovered::default();
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            recovered.truncated_tail = rest.len();
+            break;
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().expect("4 bytes")) as usize;
+        if rest.len() < 4 + len {
+            recovered.truncated_tail = rest.len();
+            break;
+        }
+        let index = recovered.records.len() as u64;
+        let plaintext = keyring
+            .open(&rest[4..4 + len], &index.to_be_bytes())
+            .map_err(|e| format!("record {}: {}", index, e))?;
+        recovered.records.push(plaintext);
+        rest = &rest[4 + len..];
+    }
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(byte: u8) -> Key {
+        Key::from_bytes(&[byte; KEY_LEN])
+    }
+
+    #[test]
+    fn matches_the_xchacha20_poly1305_test_vector() {
+        // draft-irtf-cfrg-xchacha-03, appendix A.3.1
+        let key_bytes: [u8; KEY_LEN] =
+            unhex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+                .try_into()
+                .unwrap();
+        let nonce = unhex("404142434445464748494a4b4c4d4e4f5051525354555657");
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let expected = unhex(concat!(
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb",
+            "731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452",
+            "2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9",
+            "21f9664c97637da9768812f615c68b13b52e",
+            "c0875924c1c7987947deafd8780acf49",
+        ));
+        let key = Key::from_bytes(&key_bytes);
+        let record = seal_with_nonce(&key, XNonce::from_slice(&nonce), plaintext, &aad);
+        assert_eq!(
+            &record[SEAL_MAGIC.len() + KEY_ID_LEN + NONCE_LEN..],
+            &expected[..]
+        );
+
+        let keyring = Keyring::new(key, Vec::new());
+        assert_eq!(keyring.open(&record, &aad).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn derived_keys_are_deterministic() {
+        let a = Key::derive(b"correct horse battery staple").unwrap();
+        let b = Key::derive(b"correct horse battery staple").unwrap();
+        let c = Key::derive(b"another secret").unwrap();
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a.id(), c.id());
+        assert!(Key::derive(b"").is_err());
+
+        let sealed = Keyring::new(a, Vec::new()).seal(b"order 42", b"");
+        assert_eq!(
+            Keyring::new(b, Vec::new()).open(&sealed, b"").unwrap(),
+            b"order 42"
+        );
+    }
+
+    #[test]
+    fn tampering_and_wrong_keys_are_detected() {
+        let keyring = Keyring::new(key(1), Vec::new());
+        let record = keyring.seal(b"customer order", b"dlq:7");
+        assert_ne!(
+            keyring.seal(b"customer order", b"dlq:7"),
+            record,
+            "fresh nonce"
+        );
+        assert!(!record.windows(8).any(|w| w == b"customer"));
+
+        for i in SEAL_MAGIC.len()..record.len() {
+            let mut tampered = record.clone();
+            tampered[i] ^= 0x01;
+            assert!(keyring.open(&tampered, b"dlq:7").is_err(), "byte {}", i);
+        }
+        let err = keyring.open(&record, b"dlq:8").unwrap_err();
+        assert!(err.contains("authentication"), "{}", err);
+        let err = keyring
+            .open(&record[..record.len() - 1], b"dlq:7")
+            .unwrap_err();
+        assert!(err.contains("authentication"), "{}", err);
+
+        let stranger = Keyring::new(key(2), Vec::new());
+        let err = stranger.open(&record, b"dlq:7").unwrap_err();
+        assert!(err.contains("unknown key"), "{}", err);
+        assert!(keyring.open(b"plaintext", b"").is_err());
+    }
+
+    #[test]
+    fn rotation_reads_old_records_and_writes_with_the_new_key() {
+        let old = Keyring::new(key(1), Vec::new());
+        let old_record = old.seal(b"before rotation", b"");
+        let rotated = Keyring::new(key(2), vec![key(1)]);
+        assert_eq!(rotated.open(&old_record, b"").unwrap(), b"before rotation");
+
+        let new_record = rotated.seal(b"after rotation", b"");
+        assert_eq!(&new_record[8..16], &key(2).id()[..]);
+        assert!(old.open(&new_record, b"").is_err());
+    }
+
+    #[test]
+    fn recovery_over_an_encrypted_file() {
+        let keyring = Keyring::new(key(9), Vec::new());
+        let mut file = Vec::new();
+        for i in 0..5u64 {
+            write_record(&mut file, &keyring, i, format!("request {}", i).as_bytes()).unwrap();
+        }
+        let complete = file.len();
+        // A crash mid-write leaves part of a sixth record behind.
+        write_record(&mut file, &keyring, 5, b"request 5").unwrap();
+        file.truncate(complete + 10);
+
+        let recovered = read_records(&mut file.as_slice(), &keyring).unwrap();
+        assert_eq!(recovered.records.len(), 5);
+        assert_eq!(recovered.records[4], b"request 4");
+        assert_eq!(recovered.truncated_tail, 10);
+
+        // Swapping two records breaks their positional binding.
+        let mut swapped = Vec::new();
+        write_record(&mut swapped, &keyring, 1, b"b").unwrap();
+        write_record(&mut swapped, &keyring, 0, b"a").unwrap();
+        let err = read_records(&mut swapped.as_slice(), &keyring).unwrap_err();
+        assert!(err.starts_with("record 0:"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("corky-seal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("at-rest.key");
+        fs::write(&path, format!("{}\n", "ab".repeat(KEY_LEN))).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = Key::from_file(&path).err().unwrap();
+        assert!(err.contains("chmod 600"), "{}", err);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let loaded = Key::from_file(&path).unwrap();
+        assert_eq!(loaded.id(), Key::from_bytes(&[0xab; KEY_LEN]).id());
+
+        fs::write(&path, "too short").unwrap();
+        assert!(Key::from_file(&path).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
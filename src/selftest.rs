@@ -0,0 +1,614 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::acl::AclAction;
+use crate::config::{AuthMechanism, Config};
+use crate::journal::{inspect, journal_dir, JournalDirection, JournalFilter};
+use crate::runtime::Runtime;
+use crate::zap::ZAP_ENDPOINT;
+
+const CHECK_TIMEOUT_MS: u64 = 2000; // how long one check waits for an answer
+const CONNECT_TIMEOUT_MS: u64 = 500; // TCP connect of the bind check
+const RETRY_MS: i64 = 50; // resend interval while a route is being learnt
+const PROBE_PREFIX: &str = "__corky_selftest";
+const STORE_NAMESPACE: &str = "selftest";
+
+//
+// -------------------------------- Self-test ----------------------------------
+//
+// `selftest` on the admin socket exercises the running service the way a
+// client would and reports per check:
+//
+//   bind     every tcp and ipc endpoint accepts a connection from this host
+//   ping     a request through the broker to a worker and its reply back
+//   c2c      a direct message between two peers and the echo back
+//   pubsub   a canary publication through the proxy to a subscriber
+//   store    a write, read and delete in the state store
+//   zap      the ZAP handler accepts a configured PLAIN user and refuses a
+//            wrong password
+//   journal  the ping's request and reply read back intact from the journal
+//
+// Checks of features that are off are skipped, as is a probe the config
+// would refuse anyway (an ACL or topic rule that forbids the canary). The
+// probes use identities and topics starting with "__corky_selftest", and
+// their traffic shows in the metrics like any other. Each check gives up
+// after CHECK_TIMEOUT_MS, so a run takes a few seconds at most, during which
+// the admin socket answers nothing else.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+impl Outcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Pass(_) => "pass",
+            Outcome::Fail(_) => "fail",
+            Outcome::Skip(_) => "skip",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            Outcome::Pass(d) | Outcome::Fail(d) | Outcome::Skip(d) => d,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    // True unless a check failed; skipped ones do not count against it.
+    pub fn ok(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Fail(_)))
+    }
+
+    pub fn outcome(&self, name: &str) -> Option<&Outcome> {
+        self.checks
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| &c.outcome)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let checks: Vec<Value> = self
+            .checks
+            .iter()
+            .map(|c| {
+                json!({
+                    "name": c.name,
+                    "status": c.outcome.label(),
+                    "ms": c.elapsed_ms,
+                    "detail": c.outcome.detail(),
+                })
+            })
+            .collect();
+        json!({
+            "status": if self.ok() { "pass" } else { "fail" },
+            "checks": checks,
+        })
+    }
+
+    fn time(&mut self, name: &'static str, check: impl FnOnce() -> Outcome) -> &Outcome {
+        let started = Instant::now();
+        let outcome = check();
+        self.checks.push(Check {
+            name,
+            outcome,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        });
+        &self.checks.last().expect("just pushed").outcome
+    }
+}
+
+// Runs every check against the service `config` describes. `context` must be
+// the service's own, for the ZAP handler is only reachable in-process.
+pub fn run(context: &zmq::Context, config: &Config, runtime: &Runtime) -> Report {
+    let mut report = Report::default();
+    let probe = probe_id();
+    report.time("bind", || bind_check(config));
+    let pinged = matches!(
+        report.time("ping", || ping_check(context, config, &probe)),
+        Outcome::Pass(_)
+    );
+    report.time("c2c", || c2c_check(context, config, &probe));
+    report.time("pubsub", || pubsub_check(context, config, runtime, &probe));
+    report.time("store", || store_check(runtime, &probe));
+    report.time("zap", || zap_check(context, config));
+    report.time("journal", || journal_check(config, runtime, &probe, pinged));
+    report
+}
+
+// Unique per process and run, so that a probe never meets the leftovers of
+// an earlier one.
+fn probe_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}_{}_{}",
+        PROBE_PREFIX,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Where a local client reaches a bound endpoint: wildcard addresses become
+// the loopback one.
+pub fn loopback(endpoint: &str) -> String {
+    let Some(address) = endpoint.strip_prefix("tcp://") else {
+        return endpoint.to_string();
+    };
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return endpoint.to_string();
+    };
+    let host = match host {
+        "*" | "0.0.0.0" => "127.0.0.1",
+        "[::]" | "[*]" => "[::1]",
+        host => host,
+    };
+    format!("tcp://{}:{}", host, port)
+}
+
+fn timeout() -> Duration {
+    Duration::from_millis(CHECK_TIMEOUT_MS)
+}
+
+//
+// ---- bind ----
+//
+
+fn bound_endpoints(config: &Config) -> Vec<(String, String)> {
+    let network = &config.network;
+    let mut endpoints = Vec::new();
+    let roles = [
+        ("proxy xsub", network.proxy_xsub_addresses()),
+        ("proxy xpub", network.proxy_xpub_addresses()),
+        ("direct", network.client_to_client_addresses()),
+    ];
+    for (role, addresses) in roles {
+        endpoints.extend(addresses.into_iter().map(|a| (role.to_string(), a)));
+    }
+    if network.client_facing.is_empty() {
+        for address in network.client_facing_addresses() {
+            endpoints.push(("client".to_string(), address));
+        }
+    } else {
+        for endpoint in network.client_endpoints() {
+            endpoints.push(("client".to_string(), endpoint.address));
+        }
+    }
+    for address in network.worker_facing_addresses() {
+        endpoints.push(("worker".to_string(), address));
+    }
+    if config.state.enabled {
+        endpoints.push(("state".to_string(), config.state.snapshot_endpoint.clone()));
+    }
+    endpoints
+}
+
+// None when the endpoint takes a connection, otherwise why not.
+fn reach(endpoint: &str) -> Option<String> {
+    if let Some(address) = loopback(endpoint).strip_prefix("tcp://") {
+        let addresses = match address.to_socket_addrs() {
+            Ok(addresses) => addresses.collect::<Vec<_>>(),
+            Err(e) => return Some(e.to_string()),
+        };
+        let wait = Duration::from_millis(CONNECT_TIMEOUT_MS);
+        let mut last = "no address".to_string();
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, wait) {
+                Ok(_) => return None,
+                Err(e) => last = e.to_string(),
+            }
+        }
+        return Some(last);
+    }
+    let path = endpoint.strip_prefix("ipc://")?;
+    match path.starts_with('@') || Path::new(path).exists() {
+        true => None,
+        false => Some("no such socket file".to_string()),
+    }
+}
+
+fn bind_check(config: &Config) -> Outcome {
+    let mut probed = 0;
+    let mut failures = Vec::new();
+    for (name, endpoint) in bound_endpoints(config) {
+        // inproc endpoints have nothing to connect to from outside.
+        if endpoint.starts_with("inproc://") {
+            continue;
+        }
+        probed += 1;
+        if let Some(e) = reach(&endpoint) {
+            failures.push(format!("{} {}: {}", name, endpoint, e));
+        }
+    }
+    match (probed, failures.is_empty()) {
+        (0, _) => Outcome::Skip("every endpoint is inproc".to_string()),
+        (_, true) => Outcome::Pass(format!("{} endpoints reachable", probed)),
+        (_, false) => Outcome::Fail(failures.join("; ")),
+    }
+}
+
+//
+// ---- ping ----
+//
+
+fn dealer(
+    context: &zmq::Context,
+    identity: &[u8],
+    endpoint: &str,
+) -> Result<zmq::Socket, zmq::Error> {
+    let socket = context.socket(zmq::DEALER)?;
+    socket.set_identity(identity)?;
+    socket.set_linger(0)?;
+    socket.set_sndtimeo(CHECK_TIMEOUT_MS as i32)?;
+    socket.connect(&loopback(endpoint))?;
+    Ok(socket)
+}
+
+// Sends `message` every RETRY_MS until `receiver` has something, as a ROUTER
+// only routes to a peer once it has connected; the first message back.
+fn resend_until(
+    sender: &zmq::Socket,
+    message: &[&[u8]],
+    receiver: &zmq::Socket,
+    deadline: Instant,
+) -> Result<Option<Vec<Vec<u8>>>, zmq::Error> {
+    while Instant::now() < deadline {
+        sender
+            .send_multipart(message, zmq::DONTWAIT)
+            .or_else(|e| match e {
+                zmq::Error::EAGAIN => Ok(()),
+                e => Err(e),
+            })?;
+        if receiver.poll(zmq::POLLIN, RETRY_MS)? > 0 {
+            return Ok(Some(receiver.recv_multipart(0)?));
+        }
+    }
+    Ok(None)
+}
+
+fn recv_until(socket: &zmq::Socket, deadline: Instant) -> Result<Option<Vec<Vec<u8>>>, zmq::Error> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    match socket.poll(zmq::POLLIN, left.as_millis() as i64)? {
+        0 => Ok(None),
+        _ => socket.recv_multipart(0).map(Some),
+    }
+}
+
+fn ping_check(context: &zmq::Context, config: &Config, probe: &str) -> Outcome {
+    let Some(client_endpoint) = config
+        .network
+        .client_endpoints()
+        .into_iter()
+        .find(|e| !e.curve)
+    else {
+        return Outcome::Skip("every client endpoint needs CURVE".to_string());
+    };
+    let result = (|| -> Result<Outcome, zmq::Error> {
+        // The broker routes a client's requests to the worker of the same
+        // identity.
+        let worker_endpoint = config.network.worker_facing_addresses().remove(0);
+        let worker = dealer(context, probe.as_bytes(), &worker_endpoint)?;
+        let client = dealer(context, probe.as_bytes(), &client_endpoint.address)?;
+        let deadline = Instant::now() + timeout();
+        let Some(request) = resend_until(&client, &[b"ping"], &worker, deadline)? else {
+            return Ok(Outcome::Fail("no request reached the worker".to_string()));
+        };
+        worker.send_multipart([probe.as_bytes(), &request[0]], 0)?;
+        match recv_until(&client, deadline)? {
+            Some(reply) if reply == [b"ping"] => Ok(Outcome::Pass(client_endpoint.address)),
+            Some(reply) => Ok(Outcome::Fail(format!(
+                "unexpected reply of {} frames",
+                reply.len()
+            ))),
+            None => Ok(Outcome::Fail("no reply reached the client".to_string())),
+        }
+    })();
+    result.unwrap_or_else(|e| Outcome::Fail(e.to_string()))
+}
+
+//
+// ---- c2c ----
+//
+
+fn c2c_check(context: &zmq::Context, config: &Config, probe: &str) -> Outcome {
+    let left = format!("{}_a", probe);
+    let right = format!("{}_b", probe);
+    let endpoint = config.network.client_to_client_addresses().remove(0);
+    let result = (|| -> Result<Outcome, zmq::Error> {
+        let a = dealer(context, left.as_bytes(), &endpoint)?;
+        let b = dealer(context, right.as_bytes(), &endpoint)?;
+        let deadline = Instant::now() + timeout();
+        let Some(message) = resend_until(&a, &[right.as_bytes(), b"echo"], &b, deadline)? else {
+            return Ok(Outcome::Fail(format!("nothing reached {}", right)));
+        };
+        if message != [left.as_bytes(), b"echo"] {
+            return Ok(Outcome::Fail("the message arrived altered".to_string()));
+        }
+        b.send_multipart([left.as_bytes(), b"echo"], 0)?;
+        match recv_until(&a, deadline)? {
+            Some(echo) if echo == [right.as_bytes(), b"echo"] => {
+                Ok(Outcome::Pass(endpoint.to_string()))
+            }
+            Some(_) => Ok(Outcome::Fail("the echo arrived altered".to_string())),
+            None => Ok(Outcome::Fail("no echo came back".to_string())),
+        }
+    })();
+    result.unwrap_or_else(|e| Outcome::Fail(e.to_string()))
+}
+
+//
+// ---- pubsub ----
+//
+
+fn pubsub_check(
+    context: &zmq::Context,
+    config: &Config,
+    runtime: &Runtime,
+    probe: &str,
+) -> Outcome {
+    let topic = format!("{}.{}", PROBE_PREFIX, probe);
+    if !runtime.topics.rules().allows(topic.as_bytes()) {
+        return Outcome::Skip(format!("the topic rules do not forward {}", PROBE_PREFIX));
+    }
+    let credentials = match config.auth.mechanism {
+        AuthMechanism::Null => None,
+        AuthMechanism::Plain => match config.auth.users.iter().next() {
+            Some(user) => Some(user),
+            None => return Outcome::Skip("no PLAIN user to connect as".to_string()),
+        },
+    };
+    if config.acl.enabled {
+        let principal = credentials.map(|(user, _)| user.as_str()).unwrap_or("");
+        let rules = runtime.acl.rules();
+        if !rules.allows(principal, AclAction::Publish, topic.as_bytes())
+            || !rules.allows(principal, AclAction::Subscribe, topic.as_bytes())
+        {
+            return Outcome::Skip(format!("the ACL forbids {:?} the canary", principal));
+        }
+    }
+    let xpub = config.network.proxy_xpub_addresses().remove(0);
+    let result = (|| -> Result<Outcome, zmq::Error> {
+        let publisher = context.socket(zmq::PUB)?;
+        let subscriber = context.socket(zmq::SUB)?;
+        for socket in [&publisher, &subscriber] {
+            socket.set_linger(0)?;
+            if let Some((user, password)) = credentials {
+                socket.set_plain_username(Some(user))?;
+                socket.set_plain_password(Some(password))?;
+            }
+        }
+        subscriber.set_subscribe(topic.as_bytes())?;
+        subscriber.connect(&loopback(&xpub))?;
+        publisher.connect(&loopback(&config.network.proxy_xsub_addresses()[0]))?;
+        // Publications go nowhere until the subscription has reached the
+        // publisher, so keep publishing until one arrives.
+        let deadline = Instant::now() + timeout();
+        let canary = [topic.as_bytes(), b"canary"];
+        match resend_until(&publisher, &canary, &subscriber, deadline)? {
+            Some(message) if message.last().map(Vec::as_slice) == Some(b"canary") => {
+                Ok(Outcome::Pass(xpub.clone()))
+            }
+            Some(_) => Ok(Outcome::Fail("the canary arrived altered".to_string())),
+            None => Ok(Outcome::Fail("the canary never arrived".to_string())),
+        }
+    })();
+    result.unwrap_or_else(|e| Outcome::Fail(e.to_string()))
+}
+
+//
+// ---- store ----
+//
+
+fn store_check(runtime: &Runtime, probe: &str) -> Outcome {
+    let Some(store) = &runtime.store else {
+        return Outcome::Skip("storage is disabled".to_string());
+    };
+    let key = probe.as_bytes();
+    let cycle = || -> Result<Outcome, String> {
+        store.put(STORE_NAMESPACE, key, b"probe")?;
+        let read = store.get(STORE_NAMESPACE, key)?;
+        store.delete(STORE_NAMESPACE, key)?;
+        if read.as_deref() != Some(&b"probe"[..]) {
+            return Ok(Outcome::Fail("read back something else".to_string()));
+        }
+        if store.get(STORE_NAMESPACE, key)?.is_some() {
+            return Ok(Outcome::Fail("still there after the delete".to_string()));
+        }
+        Ok(Outcome::Pass(store.backend().to_string()))
+    };
+    cycle().unwrap_or_else(Outcome::Fail)
+}
+
+//
+// ---- zap ----
+//
+
+// The status code the ZAP handler answers for PLAIN `user` and `password`.
+fn zap_status(
+    socket: &zmq::Socket,
+    domain: &str,
+    user: &str,
+    password: &str,
+) -> Result<Option<String>, zmq::Error> {
+    socket.send_multipart(
+        [
+            &b"1.0"[..],
+            PROBE_PREFIX.as_bytes(),
+            domain.as_bytes(),
+            b"127.0.0.1",
+            b"",
+            b"PLAIN",
+            user.as_bytes(),
+            password.as_bytes(),
+        ],
+        0,
+    )?;
+    let deadline = Instant::now() + timeout();
+    Ok(recv_until(socket, deadline)?.and_then(|reply| {
+        reply
+            .get(2)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+    }))
+}
+
+fn zap_check(context: &zmq::Context, config: &Config) -> Outcome {
+    if config.auth.mechanism == AuthMechanism::Null {
+        return Outcome::Skip("authentication is off".to_string());
+    }
+    let domain = &config.auth.zap_domain;
+    let result = (|| -> Result<Outcome, zmq::Error> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.set_linger(0)?;
+        socket.connect(ZAP_ENDPOINT)?;
+        if let Some((user, password)) = config.auth.users.iter().next() {
+            match zap_status(&socket, domain, user, password)? {
+                Some(status) if status == "200" => {}
+                Some(status) => {
+                    return Ok(Outcome::Fail(format!("{} was refused ({})", user, status)))
+                }
+                None => return Ok(Outcome::Fail("the ZAP handler did not answer".to_string())),
+            }
+        }
+        let wrong = format!("{}-wrong", PROBE_PREFIX);
+        match zap_status(&socket, domain, PROBE_PREFIX, &wrong)? {
+            Some(status) if status == "200" => {
+                Ok(Outcome::Fail("a wrong password was accepted".to_string()))
+            }
+            Some(_) => Ok(Outcome::Pass(domain.clone())),
+            None => Ok(Outcome::Fail("the ZAP handler did not answer".to_string())),
+        }
+    })();
+    result.unwrap_or_else(|e| Outcome::Fail(e.to_string()))
+}
+
+//
+// ---- journal ----
+//
+
+fn journal_check(config: &Config, runtime: &Runtime, probe: &str, pinged: bool) -> Outcome {
+    if !config.journal.enabled {
+        return Outcome::Skip("the journal is disabled".to_string());
+    }
+    if !pinged {
+        return Outcome::Fail("no ping to look for, as the ping failed".to_string());
+    }
+    let dir = match journal_dir(&config.journal) {
+        Ok(dir) => dir,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let filter = JournalFilter {
+        peer: Some(probe.as_bytes().to_vec()),
+        ..JournalFilter::default()
+    };
+    // The journal thread writes out what is queued within moments.
+    let deadline = Instant::now() + timeout();
+    loop {
+        let found = match inspect(&dir, runtime.keyring.as_deref(), &filter) {
+            Ok((records, _)) => records,
+            Err(e) => return Outcome::Fail(format!("cannot read {}: {}", dir.display(), e)),
+        };
+        let has = |direction| found.iter().any(|r| r.direction == direction);
+        if has(JournalDirection::Request) && has(JournalDirection::Reply) {
+            return Outcome::Pass(dir.display().to_string());
+        }
+        if Instant::now() >= deadline {
+            return Outcome::Fail(format!(
+                "the ping's request and reply are not in {}",
+                dir.display()
+            ));
+        }
+        thread::sleep(Duration::from_millis(RETRY_MS as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_become_loopback() {
+        assert_eq!(loopback("tcp://*:5555"), "tcp://127.0.0.1:5555");
+        assert_eq!(loopback("tcp://0.0.0.0:5555"), "tcp://127.0.0.1:5555");
+        assert_eq!(loopback("tcp://[::]:5555"), "tcp://[::1]:5555");
+        assert_eq!(loopback("tcp://10.0.0.1:5555"), "tcp://10.0.0.1:5555");
+        assert_eq!(loopback("ipc:///tmp/corky"), "ipc:///tmp/corky");
+    }
+
+    #[test]
+    fn a_failed_check_fails_the_report() {
+        let check = |name, outcome| Check {
+            name,
+            outcome,
+            elapsed_ms: 1,
+        };
+        let mut report = Report {
+            checks: vec![
+                check("bind", Outcome::Pass("2 endpoints reachable".to_string())),
+                check("store", Outcome::Skip("storage is disabled".to_string())),
+            ],
+        };
+        assert!(report.ok());
+        assert_eq!(
+            report.to_json(),
+            json!({
+                "status": "pass",
+                "checks": [
+                    {"name": "bind", "status": "pass", "ms": 1,
+                     "detail": "2 endpoints reachable"},
+                    {"name": "store", "status": "skip", "ms": 1,
+                     "detail": "storage is disabled"},
+                ],
+            })
+        );
+        report
+            .checks
+            .push(check("ping", Outcome::Fail("no reply".to_string())));
+        assert!(!report.ok());
+        assert_eq!(report.to_json()["status"], "fail");
+    }
+
+    #[test]
+    fn only_the_journal_waits_for_the_ping() {
+        let config = Config::default();
+        let runtime = Runtime::new(&config);
+        assert_eq!(
+            store_check(&runtime, "probe").label(),
+            "skip",
+            "no store without [storage]"
+        );
+        assert_eq!(zap_check(&zmq::Context::new(), &config).label(), "skip");
+        assert_eq!(
+            journal_check(&config, &runtime, "probe", false).label(),
+            "skip"
+        );
+        let mut journaled = config.clone();
+        journaled.journal.enabled = true;
+        assert_eq!(
+            journal_check(&journaled, &runtime, "probe", false).label(),
+            "fail"
+        );
+    }
+}
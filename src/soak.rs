@@ -0,0 +1,881 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+
+use crate::broker::{run_broker, CLIENT_ROUTER, DIRECT_ROUTER, WORKER_ROUTER};
+use crate::config::Config;
+use crate::metrics::{render_prometheus, Counter};
+use crate::proxy::run_proxy;
+use crate::runtime::Runtime;
+use crate::timer::Periodic;
+
+//
+// -------------------------------- Soak run -----------------------------------
+//
+// Runs the broker and the proxy in-process on inproc endpoints and drives a
+// weighted mix of traffic through them for a fixed time:
+//
+//     request/reply   client -> broker -> worker with the same identity and
+//                     back, one outstanding entry per request
+//     client-to-client  one DEALER to another through the direct router
+//     pub/sub         one publisher and one subscriber through the proxy
+//
+// Every `churn_every` one client/worker pair or direct pair is retired once
+// it has nothing in flight and replaced by a new connection. Replacements get
+// fresh identities: a ROUTER refuses a second connection under an identity it
+// still holds, so reusing one would race the old pipe's teardown. Every
+// `check_every` the invariants are checked:
+//
+//     - no request has been unanswered for longer than request_timeout
+//     - the broker's received counters never go backwards and stay between
+//       what was answered and what was sent
+//     - resident memory (VmRSS) has not grown more than max_rss_growth_kb
+//       past the first sample
+//     - direct messages and publications arrive once, in order
+//
+// After the run, sending stops and outstanding traffic gets request_timeout
+// to drain; then every request must have had its reply and the counters must
+// match the traffic exactly. On the first violation the run stops and writes
+// violations.txt, recent.txt (the last RECENT_EVENTS sends and receives),
+// metrics.prom and summary.txt to a new directory under report_dir. The
+// broker has no dead-letter queue, so there is none to dump.
+
+const RECENT_EVENTS: usize = 512;
+// A new connection is used only after this long, so the ROUTER on the other
+// end knows its identity.
+const CONNECT_SETTLE: Duration = Duration::from_millis(50);
+// Subscriptions must reach the publisher side before the first publication.
+const SUBSCRIBE_SETTLE: Duration = Duration::from_millis(300);
+const MAX_OPS_PER_PASS: u64 = 1000;
+const TOPIC: &[u8] = b"soak.tick";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakConfig {
+    pub duration: Duration,
+    // Operations per second across all kinds of traffic.
+    pub rate: u32,
+    // Relative weights of request/reply, client-to-client and pub/sub.
+    pub mix: [u32; 3],
+    pub pairs: usize,
+    pub direct_pairs: usize,
+    pub churn_every: Duration,
+    pub check_every: Duration,
+    pub request_timeout: Duration,
+    pub max_rss_growth_kb: u64,
+    pub report_dir: PathBuf,
+    pub seed: u64,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(3600),
+            rate: 2000,
+            mix: [6, 3, 1],
+            pairs: 8,
+            direct_pairs: 4,
+            churn_every: Duration::from_secs(5),
+            check_every: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(5),
+            max_rss_growth_kb: 64 * 1024,
+            report_dir: PathBuf::from("soak-reports"),
+            seed: 1,
+        }
+    }
+}
+
+impl SoakConfig {
+    // A few seconds of the same code, for the test suite.
+    pub fn smoke() -> Self {
+        Self {
+            duration: Duration::from_secs(3),
+            rate: 500,
+            pairs: 3,
+            direct_pairs: 2,
+            churn_every: Duration::from_millis(400),
+            check_every: Duration::from_millis(250),
+            request_timeout: Duration::from_secs(2),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SoakTally {
+    pub requests: u64,
+    pub replies: u64,
+    pub worker_replies: u64,
+    pub direct_sent: u64,
+    pub direct_received: u64,
+    pub published: u64,
+    pub delivered: u64,
+    pub churned: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SoakReport {
+    pub tally: SoakTally,
+    pub elapsed: Duration,
+    pub rss_growth_kb: u64,
+    pub violations: Vec<String>,
+    // Where the report went, when there was a violation.
+    pub report: Option<PathBuf>,
+}
+
+impl SoakReport {
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+// Resident set size of this process, from /proc/self/status.
+pub fn rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// xorshift64*: reproducible from the seed, and all a soak run needs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n.max(1)
+    }
+
+    // An index into `weights`, chosen in proportion to them.
+    fn weighted(&mut self, weights: &[u32]) -> usize {
+        let total: u32 = weights.iter().sum();
+        let mut pick = self.below(total.max(1) as usize) as u32;
+        for (i, &w) in weights.iter().enumerate() {
+            if pick < w {
+                return i;
+            }
+            pick -= w;
+        }
+        0
+    }
+}
+
+//
+// ---- Environment ----
+//
+
+// The broker and proxy threads, stopped on drop.
+struct Environment {
+    context: zmq::Context,
+    config: Arc<Config>,
+    runtime: Runtime,
+    shutdown: Arc<AtomicBool>,
+    control_endpoint: String,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Environment {
+    fn start() -> Result<Self, String> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let tag = format!(
+            "soak-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut config = Config::default();
+        let network = &mut config.network;
+        network.client_to_client_endpoint = format!("inproc://{tag}-direct");
+        network.client_facing_endpoint = format!("inproc://{tag}-client");
+        network.worker_facing_endpoint = format!("inproc://{tag}-worker");
+        network.proxy_xsub_endpoint = format!("inproc://{tag}-xsub");
+        network.proxy_xpub_endpoint = format!("inproc://{tag}-xpub");
+        config.state.snapshot_endpoint = format!("inproc://{tag}-snapshot");
+        config.proxy.sample_endpoint = format!("inproc://{tag}-sample");
+        let control_endpoint = format!("inproc://{tag}-proxy-control");
+
+        let context = zmq::Context::new();
+        let runtime = Runtime::new(&config);
+        let config = Arc::new(config);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let broker = {
+            let (context, config, runtime) = (context.clone(), config.clone(), runtime.clone());
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                if let Err(e) = run_broker(&context, &config, &runtime, &shutdown) {
+                    warn!("(Soak) Broker stopped: {}", e);
+                }
+            })
+        };
+        let proxy = {
+            let (context, config, runtime) = (context.clone(), config.clone(), runtime.clone());
+            let control_endpoint = control_endpoint.clone();
+            thread::spawn(move || {
+                if let Err(e) = run_proxy(&context, &config, &runtime, &control_endpoint) {
+                    warn!("(Soak) Proxy stopped: {}", e);
+                }
+            })
+        };
+        // inproc requires the bind to happen before connect.
+        thread::sleep(Duration::from_millis(100));
+        Ok(Self {
+            context,
+            config,
+            runtime,
+            shutdown,
+            control_endpoint,
+            threads: vec![broker, proxy],
+        })
+    }
+
+    fn socket(&self, kind: zmq::SocketType, endpoint: &str) -> Result<zmq::Socket, String> {
+        let socket = self
+            .context
+            .socket(kind)
+            .map_err(|e| format!("socket: {}", e))?;
+        socket.set_linger(0).map_err(|e| format!("linger: {}", e))?;
+        socket
+            .connect(endpoint)
+            .map_err(|e| format!("connect {}: {}", endpoint, e))?;
+        Ok(socket)
+    }
+
+    fn dealer(&self, identity: &[u8], endpoint: &str) -> Result<zmq::Socket, String> {
+        let socket = self
+            .context
+            .socket(zmq::DEALER)
+            .map_err(|e| format!("socket: {}", e))?;
+        socket
+            .set_identity(identity)
+            .map_err(|e| format!("identity: {}", e))?;
+        socket.set_linger(0).map_err(|e| format!("linger: {}", e))?;
+        socket
+            .connect(endpoint)
+            .map_err(|e| format!("connect {}: {}", endpoint, e))?;
+        Ok(socket)
+    }
+
+    fn received(&self, socket: &'static str) -> Counter {
+        self.runtime
+            .metrics
+            .counter("corky_broker_received_total", &[("socket", socket)])
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Ok(control) = self.context.socket(zmq::PAIR) {
+            let _ = control.set_linger(0);
+            if control.connect(&self.control_endpoint).is_ok() {
+                let _ = control.send("TERMINATE", 0);
+            }
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+//
+// ---- Traffic ----
+//
+
+// A client and the worker its requests are routed to.
+struct Pair {
+    identity: Vec<u8>,
+    client: zmq::Socket,
+    worker: zmq::Socket,
+    ready_at: Instant,
+    next_seq: u64,
+    outstanding: HashMap<u64, Instant>,
+    retiring: bool,
+}
+
+// A sender and the peer it messages through the direct router.
+struct DirectPair {
+    target_id: Vec<u8>,
+    sender: zmq::Socket,
+    target: zmq::Socket,
+    sender_id: Vec<u8>,
+    ready_at: Instant,
+    sent: u64,
+    received: u64,
+    retiring: bool,
+}
+
+struct Feed {
+    publisher: zmq::Socket,
+    subscriber: zmq::Socket,
+    ready_at: Instant,
+    last_seen: Option<u64>,
+}
+
+// Broker counters as last checked.
+#[derive(Default)]
+struct Counted {
+    client: u64,
+    worker: u64,
+    direct: u64,
+}
+
+struct Soak<'a> {
+    config: &'a SoakConfig,
+    env: &'a Environment,
+    started: Instant,
+    rng: Rng,
+    pairs: Vec<Pair>,
+    direct: Vec<DirectPair>,
+    feed: Feed,
+    generation: u64,
+    tally: SoakTally,
+    counted: Counted,
+    rss_baseline: Option<u64>,
+    rss_growth_kb: u64,
+    recent: VecDeque<String>,
+    violations: Vec<String>,
+}
+
+impl<'a> Soak<'a> {
+    fn new(config: &'a SoakConfig, env: &'a Environment) -> Result<Self, String> {
+        let now = Instant::now();
+        let network = &env.config.network;
+        let subscriber = env.socket(zmq::SUB, &network.proxy_xpub_endpoint)?;
+        subscriber
+            .set_subscribe(TOPIC)
+            .map_err(|e| format!("subscribe: {}", e))?;
+        let feed = Feed {
+            publisher: env.socket(zmq::PUB, &network.proxy_xsub_endpoint)?,
+            subscriber,
+            ready_at: now + SUBSCRIBE_SETTLE,
+            last_seen: None,
+        };
+        let mut soak = Self {
+            config,
+            env,
+            started: now,
+            rng: Rng::new(config.seed),
+            pairs: Vec::new(),
+            direct: Vec::new(),
+            feed,
+            generation: 0,
+            tally: SoakTally::default(),
+            counted: Counted::default(),
+            rss_baseline: None,
+            rss_growth_kb: 0,
+            recent: VecDeque::with_capacity(RECENT_EVENTS),
+            violations: Vec::new(),
+        };
+        for _ in 0..config.pairs {
+            soak.add_pair(now)?;
+        }
+        for _ in 0..config.direct_pairs {
+            soak.add_direct(now)?;
+        }
+        Ok(soak)
+    }
+
+    fn next_identity(&mut self, prefix: &str) -> Vec<u8> {
+        self.generation += 1;
+        format!("soak-{}-{}", prefix, self.generation).into_bytes()
+    }
+
+    fn add_pair(&mut self, now: Instant) -> Result<(), String> {
+        let identity = self.next_identity("rr");
+        let network = &self.env.config.network;
+        let worker = self
+            .env
+            .dealer(&identity, &network.worker_facing_endpoint)?;
+        let client = self
+            .env
+            .dealer(&identity, &network.client_facing_endpoint)?;
+        self.pairs.push(Pair {
+            identity,
+            client,
+            worker,
+            ready_at: now + CONNECT_SETTLE,
+            next_seq: 0,
+            outstanding: HashMap::new(),
+            retiring: false,
+        });
+        Ok(())
+    }
+
+    fn add_direct(&mut self, now: Instant) -> Result<(), String> {
+        let sender_id = self.next_identity("c2c");
+        let target_id = self.next_identity("c2c");
+        let endpoint = &self.env.config.network.client_to_client_endpoint;
+        self.direct.push(DirectPair {
+            sender: self.env.dealer(&sender_id, endpoint)?,
+            target: self.env.dealer(&target_id, endpoint)?,
+            sender_id,
+            target_id,
+            ready_at: now + CONNECT_SETTLE,
+            sent: 0,
+            received: 0,
+            retiring: false,
+        });
+        Ok(())
+    }
+
+    fn note(&mut self, event: String) {
+        if self.recent.len() >= RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        let at = self.started.elapsed().as_millis();
+        self.recent.push_back(format!("{:>8}ms {}", at, event));
+    }
+
+    fn violation(&mut self, violation: String) {
+        warn!("(Soak) {}", violation);
+        self.note(format!("VIOLATION {}", violation));
+        self.violations.push(violation);
+    }
+
+    // One operation of a kind picked by the mix.
+    fn step(&mut self, now: Instant) {
+        match self.rng.weighted(&self.config.mix) {
+            0 => self.send_request(now),
+            1 => self.send_direct(now),
+            _ => self.publish(now),
+        }
+    }
+
+    fn send_request(&mut self, now: Instant) {
+        let live: Vec<usize> = (0..self.pairs.len())
+            .filter(|&i| !self.pairs[i].retiring && self.pairs[i].ready_at <= now)
+            .collect();
+        if live.is_empty() {
+            return;
+        }
+        let pair = &mut self.pairs[live[self.rng.below(live.len())]];
+        let payload = format!("r:{}", pair.next_seq);
+        if pair.client.send(payload.as_bytes(), zmq::DONTWAIT).is_err() {
+            return;
+        }
+        pair.outstanding.insert(pair.next_seq, now);
+        pair.next_seq += 1;
+        self.tally.requests += 1;
+        let event = format!(
+            "request {} {}",
+            String::from_utf8_lossy(&pair.identity),
+            payload
+        );
+        self.note(event);
+    }
+
+    fn send_direct(&mut self, now: Instant) {
+        let live: Vec<usize> = (0..self.direct.len())
+            .filter(|&i| !self.direct[i].retiring && self.direct[i].ready_at <= now)
+            .collect();
+        if live.is_empty() {
+            return;
+        }
+        let pair = &mut self.direct[live[self.rng.below(live.len())]];
+        let payload = format!("d:{}", pair.sent);
+        let frames = [pair.target_id.as_slice(), payload.as_bytes()];
+        if pair.sender.send_multipart(frames, zmq::DONTWAIT).is_err() {
+            return;
+        }
+        pair.sent += 1;
+        self.tally.direct_sent += 1;
+        let event = format!(
+            "direct {} -> {} {}",
+            String::from_utf8_lossy(&pair.sender_id),
+            String::from_utf8_lossy(&pair.target_id),
+            payload
+        );
+        self.note(event);
+    }
+
+    fn publish(&mut self, now: Instant) {
+        if self.feed.ready_at > now {
+            return;
+        }
+        let seq = self.tally.published.to_string();
+        let frames = [TOPIC, seq.as_bytes()];
+        if self
+            .feed
+            .publisher
+            .send_multipart(frames, zmq::DONTWAIT)
+            .is_ok()
+        {
+            self.tally.published += 1;
+            self.note(format!("publish {}", seq));
+        }
+    }
+
+    // Answer requests and take in everything that has arrived.
+    fn drain(&mut self) {
+        let mut events = Vec::new();
+        let mut violations = Vec::new();
+        for pair in &mut self.pairs {
+            let id = String::from_utf8_lossy(&pair.identity).into_owned();
+            while let Ok(message) = pair.worker.recv_multipart(zmq::DONTWAIT) {
+                // [payload] is a request; [client_id, payload] the echo of a
+                // reply.
+                if message.len() != 1 {
+                    continue;
+                }
+                let reply = [pair.identity.as_slice(), message[0].as_slice()];
+                if pair.worker.send_multipart(reply, zmq::DONTWAIT).is_ok() {
+                    self.tally.worker_replies += 1;
+                }
+            }
+            while let Ok(message) = pair.client.recv_multipart(zmq::DONTWAIT) {
+                let seq = message
+                    .first()
+                    .and_then(|f| std::str::from_utf8(f).ok())
+                    .and_then(|s| s.strip_prefix("r:"))
+                    .and_then(|s| s.parse::<u64>().ok());
+                match seq.and_then(|seq| pair.outstanding.remove(&seq).map(|_| seq)) {
+                    Some(seq) => {
+                        self.tally.replies += 1;
+                        events.push(format!("reply {} r:{}", id, seq));
+                    }
+                    None => violations.push(format!(
+                        "client {} got a reply it was not waiting for: {:?}",
+                        id,
+                        message
+                            .iter()
+                            .map(|f| String::from_utf8_lossy(f).into_owned())
+                            .collect::<Vec<_>>()
+                    )),
+                }
+            }
+        }
+        for pair in &mut self.direct {
+            while let Ok(message) = pair.target.recv_multipart(zmq::DONTWAIT) {
+                let expected = format!("d:{}", pair.received);
+                if message.len() != 2
+                    || message[0] != pair.sender_id
+                    || message[1] != expected.as_bytes()
+                {
+                    violations.push(format!(
+                        "direct {} expected {} from {}, got {:?}",
+                        String::from_utf8_lossy(&pair.target_id),
+                        expected,
+                        String::from_utf8_lossy(&pair.sender_id),
+                        message
+                            .iter()
+                            .map(|f| String::from_utf8_lossy(f).into_owned())
+                            .collect::<Vec<_>>()
+                    ));
+                }
+                pair.received += 1;
+                self.tally.direct_received += 1;
+                events.push(format!(
+                    "delivered {} {}",
+                    String::from_utf8_lossy(&pair.target_id),
+                    expected
+                ));
+            }
+        }
+        while let Ok(message) = self.feed.subscriber.recv_multipart(zmq::DONTWAIT) {
+            let seq = message
+                .get(1)
+                .and_then(|f| std::str::from_utf8(f).ok())
+                .and_then(|s| s.parse::<u64>().ok());
+            match (seq, self.feed.last_seen) {
+                (None, _) => violations.push(format!("malformed publication {:?}", message)),
+                (Some(seq), Some(last)) if seq != last + 1 => {
+                    violations.push(format!("publication {} arrived after {}", seq, last))
+                }
+                (Some(seq), _) => {
+                    self.feed.last_seen = Some(seq);
+                    self.tally.delivered += 1;
+                    events.push(format!("received {}", seq));
+                }
+            }
+        }
+        for event in events {
+            self.note(event);
+        }
+        for violation in violations {
+            self.violation(violation);
+        }
+    }
+
+    // Retire one pair of either kind and start its replacement.
+    fn churn(&mut self, now: Instant) -> Result<(), String> {
+        let direct =
+            self.rng.below(self.config.pairs + self.config.direct_pairs) >= self.config.pairs;
+        let retired = if direct {
+            let live: Vec<usize> = (0..self.direct.len())
+                .filter(|&i| !self.direct[i].retiring)
+                .collect();
+            let Some(&i) = live.get(self.rng.below(live.len())) else {
+                return Ok(());
+            };
+            self.direct[i].retiring = true;
+            self.add_direct(now)?;
+            String::from_utf8_lossy(&self.direct[i].sender_id).into_owned()
+        } else {
+            let live: Vec<usize> = (0..self.pairs.len())
+                .filter(|&i| !self.pairs[i].retiring)
+                .collect();
+            let Some(&i) = live.get(self.rng.below(live.len())) else {
+                return Ok(());
+            };
+            self.pairs[i].retiring = true;
+            self.add_pair(now)?;
+            String::from_utf8_lossy(&self.pairs[i].identity).into_owned()
+        };
+        self.tally.churned += 1;
+        self.note(format!("churn {}", retired));
+        Ok(())
+    }
+
+    // Close retired connections once nothing is in flight on them.
+    fn reap(&mut self) {
+        self.pairs
+            .retain(|p| !(p.retiring && p.outstanding.is_empty()));
+        self.direct
+            .retain(|p| !(p.retiring && p.sent == p.received));
+    }
+
+    fn check(&mut self, now: Instant) {
+        let timeout = self.config.request_timeout;
+        let mut stuck = Vec::new();
+        for pair in &mut self.pairs {
+            pair.outstanding.retain(|seq, sent| {
+                let waiting = now.duration_since(*sent);
+                if waiting <= timeout {
+                    return true;
+                }
+                stuck.push(format!(
+                    "request r:{} from {} unanswered after {}ms",
+                    seq,
+                    String::from_utf8_lossy(&pair.identity),
+                    waiting.as_millis()
+                ));
+                false
+            });
+        }
+        for violation in stuck {
+            self.violation(violation);
+        }
+
+        let counted = Counted {
+            client: self.env.received(CLIENT_ROUTER).get(),
+            worker: self.env.received(WORKER_ROUTER).get(),
+            direct: self.env.received(DIRECT_ROUTER).get(),
+        };
+        let tally = self.tally.clone();
+        let bounds = [
+            (
+                "client_router",
+                self.counted.client,
+                counted.client,
+                tally.replies,
+                tally.requests,
+            ),
+            (
+                "worker_router",
+                self.counted.worker,
+                counted.worker,
+                0,
+                tally.worker_replies,
+            ),
+            (
+                "direct_router",
+                self.counted.direct,
+                counted.direct,
+                tally.direct_received,
+                tally.direct_sent,
+            ),
+        ];
+        for (socket, before, now, low, high) in bounds {
+            if now < before {
+                self.violation(format!(
+                    "{} received counter went from {} to {}",
+                    socket, before, now
+                ));
+            } else if now < low || now > high {
+                self.violation(format!(
+                    "{} received counter is {}, outside the observed {}..={}",
+                    socket, now, low, high
+                ));
+            }
+        }
+        self.counted = counted;
+
+        if let Some(rss) = rss_kb() {
+            let baseline = *self.rss_baseline.get_or_insert(rss);
+            self.rss_growth_kb = self.rss_growth_kb.max(rss.saturating_sub(baseline));
+            if self.rss_growth_kb > self.config.max_rss_growth_kb {
+                self.violation(format!(
+                    "resident memory grew {} kB past {} kB, limit {} kB",
+                    self.rss_growth_kb, baseline, self.config.max_rss_growth_kb
+                ));
+            }
+        }
+    }
+
+    fn in_flight(&self) -> bool {
+        self.pairs.iter().any(|p| !p.outstanding.is_empty())
+            || self.direct.iter().any(|p| p.sent != p.received)
+            || self.tally.published > 0 && self.feed.last_seen != Some(self.tally.published - 1)
+    }
+
+    // Once drained, the counters must match the traffic exactly.
+    fn final_check(&mut self) {
+        for pair in &self.pairs {
+            if !pair.outstanding.is_empty() {
+                let violation = format!(
+                    "{} requests from {} never answered",
+                    pair.outstanding.len(),
+                    String::from_utf8_lossy(&pair.identity)
+                );
+                self.violations.push(violation);
+            }
+        }
+        let tally = self.tally.clone();
+        let expected = [
+            (CLIENT_ROUTER, tally.requests, tally.replies),
+            (WORKER_ROUTER, tally.worker_replies, tally.worker_replies),
+            (DIRECT_ROUTER, tally.direct_sent, tally.direct_received),
+        ];
+        for (socket, sent, answered) in expected {
+            let counted = self.env.received(socket).get();
+            if counted != sent || answered != sent {
+                self.violation(format!(
+                    "{}: sent {}, broker received {}, delivered {}",
+                    socket, sent, counted, answered
+                ));
+            }
+        }
+        if tally.published > 0 && self.feed.last_seen != Some(tally.published - 1) {
+            self.violation(format!(
+                "published {} messages, last delivered {:?}",
+                tally.published, self.feed.last_seen
+            ));
+        }
+    }
+
+    fn write_report(&self) -> Result<PathBuf, String> {
+        let dir = self.config.report_dir.join(format!("soak-{}", unix_ms()));
+        let write = |name: &str, contents: String| {
+            let path = dir.join(name);
+            fs::write(&path, contents)
+                .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+        };
+        fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        write("violations.txt", self.violations.join("\n") + "\n")?;
+        let recent: Vec<&str> = self.recent.iter().map(String::as_str).collect();
+        write("recent.txt", recent.join("\n") + "\n")?;
+        write(
+            "metrics.prom",
+            render_prometheus(&self.env.runtime.metrics.snapshot()),
+        )?;
+        write(
+            "summary.txt",
+            format!(
+                "{:#?}\nelapsed: {:?}\nrss_growth_kb: {}\n",
+                self.tally,
+                self.started.elapsed(),
+                self.rss_growth_kb
+            ),
+        )?;
+        Ok(dir)
+    }
+}
+
+// Run one soak. Err only when the run could not be set up; violations are in
+// the report.
+pub fn run(config: &SoakConfig) -> Result<SoakReport, String> {
+    let env = Environment::start()?;
+    let mut soak = Soak::new(config, &env)?;
+    info!(
+        "(Soak) Running for {:?} at {} ops/s, mix {:?}",
+        config.duration, config.rate, config.mix
+    );
+    let started = soak.started;
+    let end = started + config.duration;
+    let mut churn = Periodic::new(config.churn_every);
+    let mut check = Periodic::new(config.check_every);
+    let mut ops: u64 = 0;
+    while soak.violations.is_empty() {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        let due = (now.duration_since(started).as_secs_f64() * f64::from(config.rate)) as u64;
+        for _ in 0..due.saturating_sub(ops).min(MAX_OPS_PER_PASS) {
+            soak.step(now);
+            ops += 1;
+        }
+        ops = ops.max(due.saturating_sub(MAX_OPS_PER_PASS));
+        soak.drain();
+        if churn.poll(now) {
+            soak.churn(now)?;
+        }
+        soak.reap();
+        if check.poll(now) {
+            soak.check(now);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    if soak.violations.is_empty() {
+        let deadline = Instant::now() + config.request_timeout;
+        while soak.in_flight() && Instant::now() < deadline {
+            soak.drain();
+            thread::sleep(Duration::from_millis(1));
+        }
+        soak.check(Instant::now());
+        soak.final_check();
+    }
+
+    let report = match soak.violations.is_empty() {
+        true => None,
+        false => Some(soak.write_report()?),
+    };
+    if let Some(dir) = &report {
+        warn!("(Soak) Violations reported in {}", dir.display());
+    }
+    Ok(SoakReport {
+        tally: soak.tally.clone(),
+        elapsed: started.elapsed(),
+        rss_growth_kb: soak.rss_growth_kb,
+        violations: soak.violations.clone(),
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_picks_follow_the_mix() {
+        let mut rng = Rng::new(7);
+        let mut seen = [0u32; 3];
+        for _ in 0..10_000 {
+            seen[rng.weighted(&[6, 3, 0])] += 1;
+        }
+        assert_eq!(seen[2], 0);
+        assert!((6200..7100).contains(&seen[0]), "{:?}", seen);
+        let mut again = Rng::new(7);
+        let mut rng = Rng::new(7);
+        assert_eq!(rng.below(1000), again.below(1000));
+        if cfg!(target_os = "linux") {
+            assert!(rss_kb().unwrap() > 0);
+        }
+    }
+}
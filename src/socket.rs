@@ -0,0 +1,530 @@
+use std::collections::BTreeMap;
+use std::os::raw::{c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::config::{
+    AuthConfig, AuthMechanism, ContextConfig, EndpointConfig, RetryProfile, SocketOptionsConfig,
+    DEFAULT_IPC_DIR_MODE,
+};
+use crate::fs_util;
+
+//
+// -------------------------------- ZMQ context --------------------------------
+//
+
+// The context main hands to the proxy and the broker, sized by [context].
+// io_threads has to be set before the first socket is created.
+pub fn build_context(config: &ContextConfig) -> Result<zmq::Context, zmq::Error> {
+    let context = zmq::Context::new();
+    context.set_io_threads(config.io_threads)?;
+    Ok(context)
+}
+
+//
+// -------------------------- Socket configuration -----------------------------
+//
+
+pub fn configure_socket(socket: &zmq::Socket) -> Result<(), zmq::Error> {
+    socket.set_sndhwm(10_000)?; // Send high water mark (default 1000)
+    socket.set_rcvhwm(10_000)?; // Receive high water mark
+    socket.set_linger(1000)?; // 1s linger on close
+    socket.set_tcp_keepalive(1)?; // Enable TCP keepalive
+    socket.set_tcp_keepalive_idle(60)?;
+    socket.set_tcp_keepalive_intvl(10)?;
+    Ok(())
+}
+
+// [network.socket_options] for the socket `name`, after configure_socket so
+// they take its place. Logged at debug level, set or not.
+pub fn apply_socket_options(
+    socket: &zmq::Socket,
+    options: &SocketOptionsConfig,
+    component: &str,
+    name: &str,
+) -> Result<(), zmq::Error> {
+    let options = options.for_socket(name);
+    let int = |value: i64| i32::try_from(value).map_err(|_| zmq::Error::EINVAL);
+    if let Some(hwm) = options.sndhwm {
+        socket.set_sndhwm(int(hwm)?)?;
+    }
+    if let Some(hwm) = options.rcvhwm {
+        socket.set_rcvhwm(int(hwm)?)?;
+    }
+    if let Some(linger) = options.linger_ms {
+        socket.set_linger(int(linger)?)?;
+    }
+    if let Some(keepalive) = options.tcp_keepalive {
+        socket.set_tcp_keepalive(i32::from(keepalive))?;
+    }
+    if let Some(idle) = options.tcp_keepalive_idle {
+        socket.set_tcp_keepalive_idle(int(idle)?)?;
+    }
+    if let Some(interval) = options.reconnect_ivl_ms {
+        socket.set_reconnect_ivl(int(interval)?)?;
+    }
+    debug!("({}) {} socket options: {}", component, name, options.describe());
+    Ok(())
+}
+
+// Bind, retrying while the address is still in use, as it is for a moment
+// when a restarted service finds the old process's sockets not yet released.
+// Other errors (EACCES, a bad endpoint) fail at once. `component` is the log
+// prefix. Returns the address bound, which differs from `endpoint` for a
+// wildcard: tcp port 0 or *, ipc://*.
+pub fn bind_with_retry(
+    socket: &zmq::Socket,
+    endpoint: &str,
+    profile: &RetryProfile,
+    component: &str,
+) -> Result<String, zmq::Error> {
+    let deadline = Instant::now() + Duration::from_millis(profile.deadline_ms);
+    let mut backoff = Duration::from_millis(profile.backoff_ms);
+    let mut attempt = 1;
+    loop {
+        let e = match prepare_ipc_bind(endpoint, component).and_then(|_| socket.bind(endpoint)) {
+            Ok(()) => {
+                let bound = last_endpoint(socket).unwrap_or_else(|| endpoint.to_string());
+                record_ipc_file(&bound);
+                return Ok(bound);
+            }
+            Err(e) => e,
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if e != zmq::Error::EADDRINUSE || attempt >= profile.attempts || left.is_zero() {
+            return Err(e);
+        }
+        let wait = backoff.min(left);
+        warn!(
+            "({}) {} in use; retrying in {}ms ({} attempts, {}ms left)",
+            component,
+            endpoint,
+            wait.as_millis(),
+            profile.attempts - attempt,
+            left.as_millis()
+        );
+        thread::sleep(wait);
+        backoff = (backoff * 2).min(Duration::from_millis(profile.max_backoff_ms));
+        attempt += 1;
+    }
+}
+
+// The address the socket was last bound to, as libzmq resolved it.
+fn last_endpoint(socket: &zmq::Socket) -> Option<String> {
+    match socket.get_last_endpoint() {
+        Ok(Ok(last)) if !last.is_empty() => Some(last),
+        _ => None,
+    }
+}
+
+// Bind every address of one socket, logging each as `what` bound to it. The
+// zmq::Error alone cannot say which address failed, so that is logged too.
+// Returns the addresses bound, wildcards resolved.
+pub fn bind_all(
+    socket: &zmq::Socket,
+    endpoints: &[String],
+    profile: &RetryProfile,
+    component: &str,
+    what: &str,
+) -> Result<Vec<String>, zmq::Error> {
+    let mut bound = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        let address = match bind_with_retry(socket, endpoint, profile, component) {
+            Ok(address) => address,
+            Err(e) => {
+                error!("({}) {} cannot bind {}: {}", component, what, endpoint, e);
+                return Err(e);
+            }
+        };
+        match &address == endpoint {
+            true => info!("({}) {} bound to {}", component, what, endpoint),
+            false => info!("({}) {} bound to {} ({})", component, what, address, endpoint),
+        }
+        bound.push(address);
+    }
+    Ok(bound)
+}
+
+//
+// ----------------------------- Bound endpoints -------------------------------
+//
+// The addresses the proxy and broker sockets ended up on, by role (the
+// [network] key without _endpoint), so tests and clients can find a port the
+// OS picked. With [network] endpoints_file set they are written there as a
+// JSON object of role -> addresses after every bind, through a temporary file
+// so a reader never sees half of it, and the file is removed at shutdown.
+
+pub const ROLE_PROXY_XSUB: &str = "proxy_xsub";
+pub const ROLE_PROXY_XPUB: &str = "proxy_xpub";
+pub const ROLE_CLIENT_TO_CLIENT: &str = "client_to_client";
+pub const ROLE_CLIENT_FACING: &str = "client_facing";
+pub const ROLE_WORKER_FACING: &str = "worker_facing";
+
+pub struct BoundEndpoints {
+    file: Option<PathBuf>,
+    roles: Mutex<BTreeMap<String, Vec<String>>>,
+}
+
+impl BoundEndpoints {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        Self {
+            file,
+            roles: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Replaces the addresses of `role`, as a restarted loop binds again.
+    pub fn set(&self, role: &str, addresses: Vec<String>) -> Result<(), String> {
+        let mut roles = self.roles.lock().unwrap_or_else(|e| e.into_inner());
+        roles.insert(role.to_string(), addresses);
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        let text = serde_json::to_string_pretty(&*roles)
+            .map_err(|e| format!("Failed to encode the bound endpoints: {}", e))?;
+        fs_util::replace_file(path, text.as_bytes(), None)
+    }
+
+    pub fn get(&self, role: &str) -> Vec<String> {
+        let roles = self.roles.lock().unwrap_or_else(|e| e.into_inner());
+        roles.get(role).cloned().unwrap_or_default()
+    }
+
+    // Deletes the endpoints file, if there is one; returns its path.
+    pub fn remove_file(&self) -> Option<&Path> {
+        let path = self.file.as_deref()?;
+        std::fs::remove_file(path).ok().map(|_| path)
+    }
+}
+
+//
+// ------------------------------ ipc endpoints --------------------------------
+//
+// libzmq binds an ipc:// endpoint over whatever file is at its path, even a
+// socket another process still listens on, and fails if the directory is
+// missing. So before each bind the directory is created with
+// [network] ipc_dir_mode, a socket file nobody answers on (left by a crashed
+// run) is removed, and a live one is EADDRINUSE, which bind_with_retry waits
+// out as it does for a tcp port. libzmq leaves the file behind on close;
+// remove_ipc_files deletes the bound ones at graceful shutdown.
+
+static IPC_DIR_MODE: AtomicU32 = AtomicU32::new(DEFAULT_IPC_DIR_MODE);
+static IPC_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// Process-wide, from [network] ipc_dir_mode at startup.
+pub fn set_ipc_dir_mode(mode: u32) {
+    IPC_DIR_MODE.store(mode, Ordering::Relaxed);
+}
+
+// The socket file of an ipc endpoint; None for other transports, ipc://*
+// and abstract (@) names, which have no file.
+pub fn ipc_path(endpoint: &str) -> Option<&Path> {
+    let path = endpoint.strip_prefix("ipc://")?;
+    (!path.is_empty() && path != "*" && !path.starts_with('@')).then(|| Path::new(path))
+}
+
+fn prepare_ipc_bind(endpoint: &str, component: &str) -> Result<(), zmq::Error> {
+    if !endpoint.starts_with("ipc://") {
+        return Ok(());
+    }
+    if !cfg!(unix) {
+        error!("({}) ipc:// endpoints are not supported on this platform", component);
+        return Err(zmq::Error::EPROTONOSUPPORT);
+    }
+    match ipc_path(endpoint) {
+        Some(path) => prepare_ipc(path, IPC_DIR_MODE.load(Ordering::Relaxed), component),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn io_error(e: &std::io::Error) -> zmq::Error {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => zmq::Error::EACCES,
+        std::io::ErrorKind::NotFound => zmq::Error::ENOENT,
+        _ => zmq::Error::EINVAL,
+    }
+}
+
+// Makes `path` ready for libzmq to bind: its directory exists, created with
+// `dir_mode` if it did not, and no stale socket file is in the way.
+#[cfg(unix)]
+pub fn prepare_ipc(path: &Path, dir_mode: u32, component: &str) -> Result<(), zmq::Error> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty() && !d.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(dir_mode)
+            .create(dir)
+            .and_then(|_| std::fs::set_permissions(dir, std::fs::Permissions::from_mode(dir_mode)))
+            .map_err(|e| {
+                error!("({}) Cannot create {}: {}", component, dir.display(), e);
+                io_error(&e)
+            })?;
+        info!("({}) Created {} ({:#o}) for ipc sockets", component, dir.display(), dir_mode);
+    }
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        error!(
+            "({}) {} exists and is not a socket; not replacing it",
+            component,
+            path.display()
+        );
+        return Err(zmq::Error::ENOTSOCK);
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(zmq::Error::EADDRINUSE),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path).map_err(|e| io_error(&e))?;
+            warn!("({}) Removed stale socket file {}", component, path.display());
+            Ok(())
+        }
+        Err(e) => {
+            error!("({}) Cannot check {}: {}", component, path.display(), e);
+            Err(io_error(&e))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn prepare_ipc(_path: &Path, _dir_mode: u32, _component: &str) -> Result<(), zmq::Error> {
+    Err(zmq::Error::EPROTONOSUPPORT)
+}
+
+fn record_ipc_file(endpoint: &str) {
+    if let Some(path) = ipc_path(endpoint) {
+        let mut files = IPC_FILES.lock().unwrap_or_else(|e| e.into_inner());
+        if !files.iter().any(|f| f == path) {
+            files.push(path.to_path_buf());
+        }
+    }
+}
+
+// Deletes the socket files of every ipc endpoint bound so far; call once the
+// sockets are closed. Returns the files removed.
+pub fn remove_ipc_files() -> Vec<PathBuf> {
+    let files = std::mem::take(&mut *IPC_FILES.lock().unwrap_or_else(|e| e.into_inner()));
+    files
+        .into_iter()
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .collect()
+}
+
+// Sockets facing authenticated peers: PLAIN server with our ZAP domain.
+pub fn configure_auth(socket: &zmq::Socket, auth: &AuthConfig) -> Result<(), zmq::Error> {
+    if auth.mechanism == AuthMechanism::Plain {
+        socket.set_plain_server(true)?;
+        socket.set_zap_domain(&auth.zap_domain)?;
+    }
+    Ok(())
+}
+
+// Options of one listening endpoint from [[network.client_facing]]. CURVE
+// needs a libzmq built with it and a Z85 secret key in [auth]; without either
+// the endpoint fails to come up rather than listening unencrypted.
+pub fn configure_endpoint(
+    socket: &zmq::Socket,
+    endpoint: &EndpointConfig,
+    auth: &AuthConfig,
+) -> Result<(), zmq::Error> {
+    if endpoint.maxmsgsize >= 0 {
+        socket.set_maxmsgsize(endpoint.maxmsgsize)?;
+    }
+    if endpoint.curve {
+        if zmq::has("curve") != Some(true) {
+            error!(
+                "(Broker) {} wants CURVE, which this libzmq lacks",
+                endpoint.address
+            );
+            return Err(zmq::Error::ENOTSUP);
+        }
+        let key = zmq::z85_decode(&auth.curve_secret_key)
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| {
+                error!(
+                    "(Broker) {} wants CURVE; auth.curve_secret_key must be a Z85 secret key",
+                    endpoint.address
+                );
+                zmq::Error::EINVAL
+            })?;
+        socket.set_curve_server(true)?;
+        socket.set_curve_secretkey(&key)?;
+    }
+    Ok(())
+}
+
+// ZMQ_XPUB_MANUAL, which the zmq crate does not wrap: the XPUB stops applying
+// subscriptions itself and the application calls set_subscribe on behalf of
+// the peer whose subscription it just received.
+pub fn set_xpub_manual(socket: &mut zmq::Socket, manual: bool) -> Result<(), zmq::Error> {
+    let value = c_int::from(manual);
+    // SAFETY: the socket pointer is valid for the lifetime of `socket`, and
+    // optval points at a live c_int of the length passed.
+    let rc = unsafe {
+        zmq_sys::zmq_setsockopt(
+            socket.as_mut_ptr(),
+            zmq_sys::ZMQ_XPUB_MANUAL as c_int,
+            &value as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>(),
+        )
+    };
+    if rc == -1 {
+        return Err(zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_context_gets_its_io_threads() {
+        let default = build_context(&ContextConfig::default()).unwrap();
+        assert_eq!(default.get_io_threads().unwrap(), 1);
+        let config = ContextConfig {
+            io_threads: 4,
+            ..ContextConfig::default()
+        };
+        let context = build_context(&config).unwrap();
+        assert_eq!(context.get_io_threads().unwrap(), 4);
+        assert!(context.socket(zmq::PAIR).is_ok());
+    }
+
+    #[test]
+    fn socket_options_override_the_common_ones() {
+        let options: SocketOptionsConfig = toml::from_str(
+            "sndhwm = 2000\nlinger_ms = 0\n[worker_router]\nsndhwm = 50\nreconnect_ivl_ms = 250\n",
+        )
+        .unwrap();
+        let context = zmq::Context::new();
+        let worker = context.socket(zmq::ROUTER).unwrap();
+        configure_socket(&worker).unwrap();
+        apply_socket_options(&worker, &options, "Broker", "worker_router").unwrap();
+        assert_eq!(worker.get_sndhwm().unwrap(), 50);
+        assert_eq!(worker.get_linger().unwrap(), 0);
+        assert_eq!(worker.get_reconnect_ivl().unwrap(), 250);
+        // Left out, so as configure_socket has it.
+        assert_eq!(worker.get_rcvhwm().unwrap(), 10_000);
+
+        let xsub = context.socket(zmq::XSUB).unwrap();
+        apply_socket_options(&xsub, &options, "Proxy", "xsub").unwrap();
+        assert_eq!(xsub.get_sndhwm().unwrap(), 2000);
+        assert_eq!(xsub.get_reconnect_ivl().unwrap(), 100); // libzmq default
+    }
+
+    #[cfg(unix)]
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("corky-ipc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ipc_paths_get_a_directory_and_lose_stale_files() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixListener;
+
+        assert_eq!(ipc_path("ipc:///run/corky/w.sock"), Some(Path::new("/run/corky/w.sock")));
+        assert_eq!(ipc_path("ipc://*"), None);
+        assert_eq!(ipc_path("ipc://@corky"), None);
+        assert_eq!(ipc_path("tcp://*:5560"), None);
+
+        let root = scratch("prepare");
+        let dir = root.join("run").join("corky");
+        prepare_ipc(&dir.join("w.sock"), 0o750, "Broker").unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o750);
+
+        // A crashed run's file: nobody accepts on it any more.
+        let stale = dir.join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        prepare_ipc(&stale, 0o750, "Broker").unwrap();
+        assert!(!stale.exists());
+
+        let live = dir.join("live.sock");
+        let _listener = UnixListener::bind(&live).unwrap();
+        assert_eq!(prepare_ipc(&live, 0o750, "Broker"), Err(zmq::Error::EADDRINUSE));
+        assert!(live.exists());
+
+        let regular = dir.join("notes.sock");
+        std::fs::write(&regular, "keep me").unwrap();
+        assert_eq!(prepare_ipc(&regular, 0o750, "Broker"), Err(zmq::Error::ENOTSOCK));
+        assert_eq!(std::fs::read_to_string(&regular).unwrap(), "keep me");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bound_ipc_files_are_removed_at_shutdown() {
+        use std::os::unix::net::UnixListener;
+
+        let root = scratch("bind");
+        let path = root.join("nested").join("workers.sock");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        let endpoint = format!("ipc://{}", path.display());
+        let context = zmq::Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        bind_with_retry(&router, &endpoint, &RetryProfile::default(), "Broker").unwrap();
+
+        let dealer = context.socket(zmq::DEALER).unwrap();
+        dealer.set_linger(0).unwrap();
+        dealer.connect(&endpoint).unwrap();
+        dealer.send("ping", 0).unwrap();
+        router.set_rcvtimeo(2000).unwrap();
+        assert_eq!(router.recv_multipart(0).unwrap()[1], b"ping");
+
+        // A second socket on the same path is refused while the first listens.
+        let other = context.socket(zmq::ROUTER).unwrap();
+        let once = RetryProfile {
+            attempts: 1,
+            ..RetryProfile::default()
+        };
+        assert_eq!(
+            bind_with_retry(&other, &endpoint, &once, "Broker"),
+            Err(zmq::Error::EADDRINUSE)
+        );
+        drop((router, dealer, other));
+        assert!(path.exists());
+        assert!(remove_ipc_files().contains(&path));
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn wildcard_binds_are_resolved_and_recorded_by_role() {
+        let context = zmq::Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        let endpoints = ["tcp://127.0.0.1:0".to_string(), "inproc://resolved".to_string()];
+        let retry = RetryProfile::default();
+        let bound = bind_all(&router, &endpoints, &retry, "Broker", "test").unwrap();
+        let port = bound[0].strip_prefix("tcp://127.0.0.1:").unwrap();
+        assert_ne!(port.parse::<u16>().unwrap(), 0);
+        assert_eq!(bound[1], "inproc://resolved");
+
+        let root = scratch("endpoints");
+        let file = root.join("runtime-endpoints.json");
+        let published = BoundEndpoints::new(Some(file.clone()));
+        published.set(ROLE_WORKER_FACING, bound.clone()).unwrap();
+        published.set(ROLE_WORKER_FACING, bound[..1].to_vec()).unwrap();
+        assert_eq!(published.get(ROLE_WORKER_FACING), &bound[..1]);
+        let written: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(written[ROLE_WORKER_FACING], &bound[..1]);
+        assert_eq!(published.remove_file(), Some(file.as_path()));
+        assert_eq!(published.remove_file(), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
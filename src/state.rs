@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::budget::{MemoryBudget, Pool, Shed};
+use crate::multipart::Multipart;
+use crate::store::StateStore;
+
+//
+// ---------------------------- State service ----------------------------------
+//
+// Snapshot-plus-updates distribution of keyed reference data (the "clone"
+// pattern). Publishers send updates on topics under the state prefix:
+//
+//     set:    [topic, key, value]
+//     delete: [topic, key]
+//
+// The proxy keeps the latest value per key and stamps every update with the
+// namespace's sequence number before forwarding it to subscribers:
+//
+//     set:    [topic, key, seq: u64 BE, value]
+//     delete: [topic, key, seq: u64 BE]
+//
+// Late joiners subscribe first, then ask the snapshot ROUTER for the
+// namespace and apply live updates newer than the snapshot:
+//
+//     request: ["SNAPSHOT", namespace]
+//     reply:   ["KV", key, value] ... ["END", seq: u64 BE]
+//
+// Sequence numbers wrap; ordering uses serial-number arithmetic.
+//
+// With [storage] on, the latest values and each namespace's sequence number
+// are written through to the state store, so a restarted proxy serves the
+// same snapshot and carries on the same sequence:
+//
+//     "lvc":     topic len u32 | topic | key  ->  stamp u64 | value
+//     "lvc-seq": topic                        ->  seq u64
+
+pub const SNAPSHOT_COMMAND: &[u8] = b"SNAPSHOT";
+pub const SNAPSHOT_ENTRY: &[u8] = b"KV";
+pub const SNAPSHOT_END: &[u8] = b"END";
+const STORE_VALUES: &str = "lvc";
+const STORE_SEQS: &str = "lvc-seq";
+
+// True when `a` comes after `b`, allowing for wraparound.
+pub fn seq_after(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) > 0
+}
+
+fn entry_bytes(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+struct Entry {
+    value: Vec<u8>,
+    stamp: u64,
+}
+
+#[derive(Default)]
+struct Namespace {
+    seq: u64,
+    entries: HashMap<Vec<u8>, Entry>,
+}
+
+// The proxy-side store, charged to the LVC pool of the memory budget. When
+// the budget sheds from it, the oldest keys are deleted and the deletions are
+// published like any other update so subscribers stay consistent.
+pub struct StateCache {
+    prefix: Vec<u8>,
+    max_keys: usize,
+    namespaces: HashMap<Vec<u8>, Namespace>,
+    // Global update order across namespaces: stamp -> (topic, key).
+    age: BTreeMap<u64, (Vec<u8>, Vec<u8>)>,
+    next_stamp: u64,
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+    shed_updates: Vec<Multipart>,
+    store: Option<Arc<dyn StateStore>>,
+}
+
+impl StateCache {
+    pub fn new(prefix: &str, max_keys: usize, budget: &Arc<MemoryBudget>) -> Self {
+        Self {
+            prefix: prefix.as_bytes().to_vec(),
+            max_keys,
+            namespaces: HashMap::new(),
+            age: BTreeMap::new(),
+            next_stamp: 0,
+            budget: Arc::clone(budget),
+            bytes: 0,
+            shed_updates: Vec::new(),
+            store: None,
+        }
+    }
+
+    // Load what `store` kept from the last run and write through to it from
+    // now on. Beyond `max_keys`, the oldest stored keys are dropped.
+    pub fn with_store(mut self, store: Arc<dyn StateStore>) -> Self {
+        let (seqs, values) = match (store.scan(STORE_SEQS), store.scan(STORE_VALUES)) {
+            (Ok(seqs), Ok(values)) => (seqs, values),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("(Proxy) Starting the state service empty: {}", e);
+                (Vec::new(), Vec::new())
+            }
+        };
+        for (topic, seq) in seqs {
+            if let Ok(seq) = seq.as_slice().try_into().map(u64::from_le_bytes) {
+                self.namespaces.entry(topic).or_default().seq = seq;
+            }
+        }
+        let mut stored: Vec<_> = values
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let (topic, key) = decode_store_key(&key)?;
+                let stamp = u64::from_le_bytes(value.get(..8)?.try_into().ok()?);
+                Some((stamp, topic, key, value[8..].to_vec()))
+            })
+            .collect();
+        stored.sort_by_key(|(stamp, ..)| *stamp);
+        let excess = stored.len().saturating_sub(self.max_keys);
+        for (_, topic, key, _) in stored.drain(..excess) {
+            if let Err(e) = store.delete(STORE_VALUES, &store_key(&topic, &key)) {
+                warn!("(Proxy) State store: {}", e);
+            }
+        }
+        for (stamp, topic, key, value) in stored {
+            let bytes = entry_bytes(&key, &value);
+            self.budget.charge(Pool::Lvc, bytes);
+            self.bytes += bytes;
+            self.age.insert(stamp, (topic.clone(), key.clone()));
+            let ns = self.namespaces.entry(topic).or_default();
+            ns.entries.insert(key, Entry { value, stamp });
+            self.next_stamp = stamp + 1;
+        }
+        self.store = Some(store);
+        self
+    }
+
+    pub fn is_state_topic(&self, topic: &[u8]) -> bool {
+        topic.starts_with(&self.prefix) && topic.len() > self.prefix.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.age.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.age.is_empty()
+    }
+
+    pub fn seq(&self, topic: &[u8]) -> u64 {
+        self.namespaces.get(topic).map(|ns| ns.seq).unwrap_or(0)
+    }
+
+    // Apply a publisher update and return the sequenced message to forward,
+    // or None if it is malformed or would add a key beyond `max_keys`.
+    pub fn apply(&mut self, message: Multipart) -> Option<Multipart> {
+        let mut frames = message.into_frames();
+        let value = match frames.len() {
+            3 => frames.pop(),
+            2 => None,
+            n => {
+                warn!("(Proxy) Dropping state update with {} frames", n);
+                return None;
+            }
+        };
+        let key = frames.pop()?;
+        let topic = frames.pop()?;
+
+        let total = self.age.len();
+        let ns = self.namespaces.entry(topic.clone()).or_default();
+        let existing = ns.entries.remove(&key);
+        if existing.is_none() && value.is_some() && total >= self.max_keys {
+            warn!(
+                "(Proxy) State store full ({} keys), dropping new key in {}",
+                self.max_keys,
+                String::from_utf8_lossy(&topic)
+            );
+            return None;
+        }
+        if let Some(old) = existing {
+            self.age.remove(&old.stamp);
+            let bytes = entry_bytes(&key, &old.value);
+            self.budget.release(Pool::Lvc, bytes);
+            self.bytes -= bytes;
+        }
+
+        ns.seq = ns.seq.wrapping_add(1);
+        let seq = ns.seq;
+        if let Some(value) = &value {
+            let stamp = self.next_stamp;
+            self.next_stamp += 1;
+            let bytes = entry_bytes(&key, value);
+            self.budget.charge(Pool::Lvc, bytes);
+            self.bytes += bytes;
+            ns.entries.insert(
+                key.clone(),
+                Entry {
+                    value: value.clone(),
+                    stamp,
+                },
+            );
+            self.age.insert(stamp, (topic.clone(), key.clone()));
+        }
+        self.write_through(&topic, &key, seq, value.as_deref());
+
+        let mut out = vec![topic, key, seq.to_be_bytes().to_vec()];
+        out.extend(value);
+        Some(Multipart::new(out))
+    }
+
+    // Record an update in the store; a failure only costs persistence.
+    fn write_through(&self, topic: &[u8], key: &[u8], seq: u64, value: Option<&[u8]>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let stored_key = store_key(topic, key);
+        let written = match value {
+            Some(value) => {
+                let stamp = self.namespaces[topic].entries[key].stamp;
+                let mut stored = stamp.to_le_bytes().to_vec();
+                stored.extend_from_slice(value);
+                store.put(STORE_VALUES, &stored_key, &stored)
+            }
+            None => store.delete(STORE_VALUES, &stored_key),
+        };
+        if let Err(e) = written.and_then(|_| store.put(STORE_SEQS, topic, &seq.to_le_bytes())) {
+            warn!("(Proxy) State store: {}", e);
+        }
+    }
+
+    // Deletions produced by budget shedding, to be published to subscribers.
+    pub fn take_shed_updates(&mut self) -> Vec<Multipart> {
+        std::mem::take(&mut self.shed_updates)
+    }
+
+    // Snapshot reply frames for `topic`, without the ROUTER envelope.
+    pub fn snapshot(&self, topic: &[u8]) -> (Vec<Multipart>, u64) {
+        let Some(ns) = self.namespaces.get(topic) else {
+            return (Vec::new(), 0);
+        };
+        let entries = ns
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+                Multipart::new(vec![
+                    SNAPSHOT_ENTRY.to_vec(),
+                    key.clone(),
+                    entry.value.clone(),
+                ])
+            })
+            .collect();
+        (entries, ns.seq)
+    }
+}
+
+impl Shed for StateCache {
+    fn pool(&self) -> Pool {
+        Pool::Lvc
+    }
+
+    fn shed_oldest(&mut self) -> Option<u64> {
+        let (_, (topic, key)) = self.age.pop_first()?;
+        let ns = self.namespaces.get_mut(&topic)?;
+        let entry = ns.entries.remove(&key)?;
+        let bytes = entry_bytes(&key, &entry.value);
+        self.budget.release(Pool::Lvc, bytes);
+        self.budget.record_shed(Pool::Lvc, bytes);
+        self.bytes -= bytes;
+        ns.seq = ns.seq.wrapping_add(1);
+        let seq = ns.seq;
+        self.write_through(&topic, &key, seq, None);
+        self.shed_updates
+            .push(Multipart::new(vec![topic, key, seq.to_be_bytes().to_vec()]));
+        Some(bytes)
+    }
+}
+
+fn store_key(topic: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + topic.len() + key.len());
+    out.extend_from_slice(&(topic.len() as u32).to_le_bytes());
+    out.extend_from_slice(topic);
+    out.extend_from_slice(key);
+    out
+}
+
+fn decode_store_key(stored: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let len = u32::from_le_bytes(stored.get(..4)?.try_into().ok()?) as usize;
+    let topic = stored.get(4..4 + len)?;
+    Some((topic.to_vec(), stored[4 + len..].to_vec()))
+}
+
+impl Drop for StateCache {
+    fn drop(&mut self) {
+        self.budget.release(Pool::Lvc, self.bytes);
+    }
+}
+
+//
+// ------------------------------ Replica --------------------------------------
+//
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateUpdate {
+    pub topic: Vec<u8>,
+    pub key: Vec<u8>,
+    pub seq: u64,
+    // None for a deletion.
+    pub value: Option<Vec<u8>>,
+}
+
+impl StateUpdate {
+    pub fn parse(message: &[Vec<u8>]) -> Option<StateUpdate> {
+        if !(3..=4).contains(&message.len()) {
+            return None;
+        }
+        Some(StateUpdate {
+            topic: message[0].clone(),
+            key: message[1].clone(),
+            seq: u64::from_be_bytes(message[2].as_slice().try_into().ok()?),
+            value: message.get(3).cloned(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Applied {
+    Updated,
+    // Already covered by the snapshot or an earlier update.
+    Stale,
+    // Updates were missed; the replica must re-snapshot.
+    Gap { expected: u64, got: u64 },
+}
+
+// Subscriber-side copy of one namespace. Updates received before the
+// snapshot completes are buffered and replayed on top of it.
+#[derive(Debug, Default)]
+pub struct StateReplica {
+    pub topic: Vec<u8>,
+    pub entries: HashMap<Vec<u8>, Vec<u8>>,
+    seq: Option<u64>,
+    pending: Vec<StateUpdate>,
+}
+
+impl StateReplica {
+    pub fn new(topic: &[u8]) -> Self {
+        Self {
+            topic: topic.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    pub fn request_snapshot(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        socket.send_multipart([SNAPSHOT_COMMAND, self.topic.as_slice()], 0)
+    }
+
+    // Read a snapshot reply from the DEALER `socket`, replacing the current
+    // state, then replay buffered updates.
+    pub fn recv_snapshot(&mut self, socket: &zmq::Socket) -> Result<Option<Applied>, zmq::Error> {
+        let mut entries = HashMap::new();
+        loop {
+            let mut frames = Multipart::recv(socket, 0)?.into_frames();
+            match frames.first().map(Vec::as_slice) {
+                Some(SNAPSHOT_ENTRY) if frames.len() == 3 => {
+                    let value = frames.pop().unwrap_or_default();
+                    let key = frames.pop().unwrap_or_default();
+                    entries.insert(key, value);
+                }
+                Some(SNAPSHOT_END) if frames.len() == 2 => {
+                    let seq = frames[1].as_slice().try_into().map(u64::from_be_bytes);
+                    self.entries = entries;
+                    self.seq = Some(seq.unwrap_or(0));
+                    break;
+                }
+                _ => return Err(zmq::Error::EPROTO),
+            }
+        }
+        let mut outcome = None;
+        for update in std::mem::take(&mut self.pending) {
+            if let Applied::Gap { expected, got } = self.apply(update) {
+                outcome = Some(Applied::Gap { expected, got });
+            }
+        }
+        Ok(outcome)
+    }
+
+    pub fn apply(&mut self, update: StateUpdate) -> Applied {
+        if update.topic != self.topic {
+            return Applied::Stale;
+        }
+        let Some(seq) = self.seq else {
+            self.pending.push(update);
+            return Applied::Stale;
+        };
+        if !seq_after(update.seq, seq) {
+            return Applied::Stale;
+        }
+        let expected = seq.wrapping_add(1);
+        if update.seq != expected {
+            self.seq = None;
+            return Applied::Gap {
+                expected,
+                got: update.seq,
+            };
+        }
+        match update.value {
+            Some(value) => self.entries.insert(update.key, value),
+            None => self.entries.remove(&update.key),
+        };
+        self.seq = Some(update.seq);
+        Applied::Updated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::DEFAULT_SHED_ORDER;
+    use crate::metrics::Registry;
+    use crate::store::FileStore;
+
+    fn set(topic: &str, key: &str, value: &str) -> Multipart {
+        Multipart::new(vec![topic.into(), key.into(), value.into()])
+    }
+
+    fn budget(limit: u64) -> Arc<MemoryBudget> {
+        MemoryBudget::new(limit, &DEFAULT_SHED_ORDER, &Registry::new())
+    }
+
+    #[test]
+    fn sequence_comparison_wraps() {
+        assert!(seq_after(1, 0));
+        assert!(seq_after(0, u64::MAX));
+        assert!(!seq_after(u64::MAX, 0));
+        assert!(!seq_after(5, 5));
+    }
+
+    #[test]
+    fn updates_are_sequenced_and_deletes_forwarded() {
+        let budget = budget(u64::MAX);
+        let mut cache = StateCache::new("$state/", 10, &budget);
+        let out = cache.apply(set("$state/fx", "EURUSD", "1.08")).unwrap();
+        assert_eq!(out[2], 1u64.to_be_bytes());
+        let delete = Multipart::new(vec![b"$state/fx".to_vec(), b"EURUSD".to_vec()]);
+        let out = cache.apply(delete).unwrap();
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[2], 2u64.to_be_bytes());
+        assert!(cache.is_empty());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn key_cap_drops_new_keys_only() {
+        let budget = budget(u64::MAX);
+        let mut cache = StateCache::new("$state/", 2, &budget);
+        assert!(cache.apply(set("$state/a", "k1", "v")).is_some());
+        assert!(cache.apply(set("$state/b", "k2", "v")).is_some());
+        assert!(cache.apply(set("$state/a", "k3", "v")).is_none());
+        assert!(cache.apply(set("$state/a", "k1", "v2")).is_some());
+        assert_eq!(cache.seq(b"$state/a"), 2);
+    }
+
+    #[test]
+    fn shedding_publishes_tombstones_for_oldest_keys() {
+        let budget = budget(10);
+        let mut cache = StateCache::new("$state/", 100, &budget);
+        cache.apply(set("$state/a", "k1", "vvvv")); // 6 bytes
+        cache.apply(set("$state/a", "k2", "vvvv")); // 12 bytes total
+        assert_eq!(budget.enforce(&mut [&mut cache]), 1);
+        let shed = cache.take_shed_updates();
+        assert_eq!(shed.len(), 1);
+        assert_eq!(&shed[0][..2], &[b"$state/a".to_vec(), b"k1".to_vec()]);
+        assert_eq!(shed[0][2], 3u64.to_be_bytes());
+        assert_eq!(budget.used(), 6);
+    }
+
+    #[test]
+    fn a_stored_cache_comes_back_with_its_sequence() {
+        let dir = std::env::temp_dir().join(format!("corky-lvc-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store: Arc<dyn StateStore> = Arc::new(FileStore::open(&dir).unwrap());
+        let budget = budget(u64::MAX);
+        {
+            let mut cache = StateCache::new("$state/", 2, &budget).with_store(store.clone());
+            cache.apply(set("$state/a", "k1", "v1"));
+            cache.apply(set("$state/a", "k2", "v2"));
+            cache.apply(set("$state/a", "k1", "v3"));
+            let delete = Multipart::new(vec![b"$state/a".to_vec(), b"k2".to_vec()]);
+            cache.apply(delete);
+            cache.apply(set("$state/b", "k4", "v4"));
+        }
+        assert_eq!(budget.used(), 0);
+        let mut cache = StateCache::new("$state/", 1, &budget).with_store(store.clone());
+        // Only the newest key fits the smaller cap.
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.snapshot(b"$state/a"), (Vec::new(), 4));
+        let (entries, seq) = cache.snapshot(b"$state/b");
+        assert_eq!((entries[0][1].as_slice(), seq), (&b"k4"[..], 1));
+        let out = cache.apply(set("$state/b", "k4", "v5")).unwrap();
+        assert_eq!(out[2], 2u64.to_be_bytes());
+        drop(cache);
+        assert_eq!(store.scan(STORE_VALUES).unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replica_detects_gaps_and_ignores_stale() {
+        let mut replica = StateReplica::new(b"t");
+        let update = |seq, value: Option<&str>| StateUpdate {
+            topic: b"t".to_vec(),
+            key: b"k".to_vec(),
+            seq,
+            value: value.map(|v| v.as_bytes().to_vec()),
+        };
+        assert_eq!(replica.apply(update(5, Some("a"))), Applied::Stale); // buffered
+        replica.seq = Some(u64::MAX);
+        assert_eq!(replica.apply(update(0, Some("b"))), Applied::Updated);
+        assert_eq!(replica.apply(update(0, Some("c"))), Applied::Stale);
+        assert_eq!(replica.apply(update(1, None)), Applied::Updated);
+        assert!(replica.entries.is_empty());
+        assert_eq!(
+            replica.apply(update(3, Some("d"))),
+            Applied::Gap {
+                expected: 2,
+                got: 3
+            }
+        );
+        assert_eq!(replica.seq(), None);
+    }
+}
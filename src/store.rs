@@ -0,0 +1,645 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::config::{StorageBackend, StorageConfig};
+use crate::fs_util;
+use crate::journal::crc32;
+
+//
+// ------------------------------ State store ----------------------------------
+//
+// Somewhere for state that should outlive the process: namespaced byte keys
+// with get/put/delete/scan, and append-only logs of numbered records. Each
+// user owns its namespaces ("lvc", "offline", ...) and encodes its own keys
+// and values; names are limited to [A-Za-z0-9._-] and may not start with a
+// dot. Every call is durable once it returns Ok, as far as the backend goes:
+// a crash loses at most the write in progress, never an earlier one, and
+// never leaves half a value behind.
+//
+// Two backends, picked with [storage] backend:
+//
+//   files     under [storage] dir (~/.corky/state), no dependencies:
+//               kv/<namespace>/k<hex key>   the value, written to a .tmp
+//                                           file and renamed into place
+//               log/<log>.log               "CRKLOG01" | base seq u64, then
+//                                           len u32 | crc32 u32 | seq u64 |
+//                                           record (len and crc cover seq
+//                                           and record), little-endian
+//             Keys are at most 120 bytes. A torn record at the end of a
+//             log is cut off when the log is first used. Nothing is
+//             fsync'd, so a crash of the machine rather than the process
+//             may lose recent writes.
+//   embedded  one redb database, <dir>/corky.redb, with every write in its
+//             own fsync'd transaction. Needs the `embedded` cargo feature.
+//
+// Log records are numbered from 1 and the numbering continues across restarts
+// and truncations.
+
+// Key-value pairs of a namespace.
+pub type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub trait StateStore: Send + Sync {
+    fn backend(&self) -> &'static str;
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), String>;
+    // Deleting a missing key is not an error.
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), String>;
+    // Every entry of `namespace`, in key order.
+    fn scan(&self, namespace: &str) -> Result<Entries, String>;
+    // Returns the new record's sequence number.
+    fn append(&self, log: &str, record: &[u8]) -> Result<u64, String>;
+    // Calls `each` for the records numbered `from` onwards, in order.
+    fn replay(&self, log: &str, from: u64, each: &mut dyn FnMut(u64, &[u8])) -> Result<(), String>;
+    // Forgets the records numbered below `before`.
+    fn truncate(&self, log: &str, before: u64) -> Result<(), String>;
+}
+
+// The store [storage] asks for, or None when it is disabled.
+pub fn open(config: &StorageConfig) -> Result<Option<Arc<dyn StateStore>>, String> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    let dir = match &config.dir {
+        Some(dir) => PathBuf::from(dir),
+        None => default_dir()?,
+    };
+    let store: Arc<dyn StateStore> = match config.backend {
+        StorageBackend::Files => Arc::new(FileStore::open(&dir)?),
+        #[cfg(feature = "embedded")]
+        StorageBackend::Embedded => Arc::new(EmbeddedStore::open(&dir)?),
+        #[cfg(not(feature = "embedded"))]
+        StorageBackend::Embedded => {
+            return Err("backend \"embedded\" needs a build with `--features embedded`".to_string())
+        }
+    };
+    Ok(Some(store))
+}
+
+pub fn default_dir() -> Result<PathBuf, String> {
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    Ok(home.join(".corky").join("state"))
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid store name {:?}", name))
+    }
+}
+
+//
+// ---- Files ----
+//
+
+const LOG_MAGIC: &[u8; 8] = b"CRKLOG01";
+const LOG_HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 8;
+const SEQ_LEN: usize = 8;
+// Keys become file names of twice their length.
+const MAX_FILE_KEY: usize = 120;
+
+pub struct FileStore {
+    dir: PathBuf,
+    // Next sequence number of each log used so far. Also serialises writers,
+    // which share .tmp file names.
+    logs: Mutex<HashMap<String, u64>>,
+}
+
+// A log file's valid contents.
+struct LogScan {
+    base: u64,
+    records: Vec<(u64, Vec<u8>)>,
+    // Where the valid part ends; anything after is a torn write.
+    valid_len: usize,
+}
+
+impl FileStore {
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        for sub in ["kv", "log"] {
+            let path = dir.join(sub);
+            fs::create_dir_all(&path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            logs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.logs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn key_path(&self, namespace: &str, key: &[u8]) -> Result<PathBuf, String> {
+        check_name(namespace)?;
+        if key.len() > MAX_FILE_KEY {
+            return Err(format!(
+                "keys of the files backend are at most {} bytes",
+                MAX_FILE_KEY
+            ));
+        }
+        let mut name = String::with_capacity(1 + 2 * key.len());
+        name.push('k');
+        for b in key {
+            name.push_str(&format!("{:02x}", b));
+        }
+        Ok(self.dir.join("kv").join(namespace).join(name))
+    }
+
+    fn log_path(&self, log: &str) -> Result<PathBuf, String> {
+        check_name(log)?;
+        Ok(self.dir.join("log").join(format!("{}.log", log)))
+    }
+
+    // The next sequence number of `log`, reading the file the first time and
+    // cutting off a torn tail.
+    fn next_seq(&self, logs: &mut HashMap<String, u64>, log: &str) -> Result<u64, String> {
+        if let Some(&next) = logs.get(log) {
+            return Ok(next);
+        }
+        let path = self.log_path(log)?;
+        let next = match read_log(&path)? {
+            None => 1,
+            Some(scan) => {
+                let on_disk = fs::metadata(&path).map_or(0, |m| m.len() as usize);
+                if scan.valid_len < on_disk {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|f| f.set_len(scan.valid_len as u64))
+                        .map_err(|e| format!("Failed to repair {}: {}", path.display(), e))?;
+                }
+                scan.records.last().map_or(scan.base, |(seq, _)| seq + 1)
+            }
+        };
+        logs.insert(log.to_string(), next);
+        Ok(next)
+    }
+}
+
+impl StateStore for FileStore {
+    fn backend(&self) -> &'static str {
+        "files"
+    }
+
+    fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let path = self.key_path(namespace, key)?;
+        match fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let path = self.key_path(namespace, key)?;
+        let _writer = self.lock();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        fs_util::replace_file(&path, value, None)
+    }
+
+    fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), String> {
+        let path = self.key_path(namespace, key)?;
+        let _writer = self.lock();
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to delete {}: {}", path.display(), e)),
+        }
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Entries, String> {
+        check_name(namespace)?;
+        let dir = self.dir.join("kv").join(namespace);
+        let listing = match fs::read_dir(&dir) {
+            Ok(listing) => listing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to list {}: {}", dir.display(), e)),
+        };
+        let mut entries = Vec::new();
+        for file in listing.flatten() {
+            let name = file.file_name();
+            // Leftover .tmp files are writes that never completed.
+            let Some(key) = name.to_str().and_then(decode_key) else {
+                continue;
+            };
+            let path = file.path();
+            match fs::read(&path) {
+                Ok(value) => entries.push((key, value)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn append(&self, log: &str, record: &[u8]) -> Result<u64, String> {
+        let path = self.log_path(log)?;
+        let mut logs = self.lock();
+        let seq = self.next_seq(&mut logs, log)?;
+        let mut bytes = Vec::with_capacity(LOG_HEADER_LEN + RECORD_HEADER_LEN + SEQ_LEN);
+        if fs::metadata(&path).map_or(true, |m| m.len() == 0) {
+            bytes.extend_from_slice(LOG_MAGIC);
+            bytes.extend_from_slice(&seq.to_le_bytes());
+        }
+        put_record(&mut bytes, seq, record);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&bytes))
+            .map_err(|e| format!("Failed to append to {}: {}", path.display(), e))?;
+        logs.insert(log.to_string(), seq + 1);
+        Ok(seq)
+    }
+
+    fn replay(&self, log: &str, from: u64, each: &mut dyn FnMut(u64, &[u8])) -> Result<(), String> {
+        let path = self.log_path(log)?;
+        let scan = {
+            let mut logs = self.lock();
+            self.next_seq(&mut logs, log)?;
+            read_log(&path)?
+        };
+        for (seq, record) in scan.map(|s| s.records).unwrap_or_default() {
+            if seq >= from {
+                each(seq, &record);
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, log: &str, before: u64) -> Result<(), String> {
+        let path = self.log_path(log)?;
+        let mut logs = self.lock();
+        let next = self.next_seq(&mut logs, log)?;
+        let Some(scan) = read_log(&path)? else {
+            return Ok(());
+        };
+        let mut bytes = LOG_MAGIC.to_vec();
+        bytes.extend_from_slice(&scan.base.max(before.min(next)).to_le_bytes());
+        for (seq, record) in scan.records.iter().filter(|(seq, _)| *seq >= before) {
+            put_record(&mut bytes, *seq, record);
+        }
+        fs_util::replace_file(&path, &bytes, None)
+    }
+}
+
+fn put_record(out: &mut Vec<u8>, seq: u64, record: &[u8]) {
+    let mut body = Vec::with_capacity(SEQ_LEN + record.len());
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.extend_from_slice(record);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&crc32(&body).to_le_bytes());
+    out.extend_from_slice(&body);
+}
+
+// None when the log has never been written.
+fn read_log(path: &Path) -> Result<Option<LogScan>, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    if bytes.len() < LOG_HEADER_LEN || &bytes[..8] != LOG_MAGIC {
+        // Only the very first append can be torn this early.
+        if bytes.len() < LOG_HEADER_LEN && LOG_MAGIC.starts_with(&bytes[..bytes.len().min(8)]) {
+            return Ok(Some(LogScan {
+                base: 1,
+                records: Vec::new(),
+                valid_len: 0,
+            }));
+        }
+        return Err(format!("{} is not a corky log", path.display()));
+    }
+    let base = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+    let mut scan = LogScan {
+        base,
+        records: Vec::new(),
+        valid_len: LOG_HEADER_LEN,
+    };
+    let mut at = LOG_HEADER_LEN;
+    while bytes.len() - at >= RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap());
+        let start = at + RECORD_HEADER_LEN;
+        if len < SEQ_LEN || bytes.len() - start < len {
+            break;
+        }
+        let body = &bytes[start..start + len];
+        if crc32(body) != crc {
+            break;
+        }
+        let seq = u64::from_le_bytes(body[..SEQ_LEN].try_into().unwrap());
+        scan.records.push((seq, body[SEQ_LEN..].to_vec()));
+        at = start + len;
+        scan.valid_len = at;
+    }
+    Ok(Some(scan))
+}
+
+fn decode_key(name: &str) -> Option<Vec<u8>> {
+    let hex = name.strip_prefix('k')?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+//
+// ---- Embedded ----
+//
+
+#[cfg(feature = "embedded")]
+pub use embedded::EmbeddedStore;
+
+#[cfg(feature = "embedded")]
+mod embedded {
+    use std::fs;
+    use std::path::Path;
+
+    use redb::{Database, ReadableTable, TableDefinition};
+
+    use super::{check_name, Entries, StateStore};
+
+    const KV: TableDefinition<(&str, &[u8]), &[u8]> = TableDefinition::new("kv");
+    const LOG: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("log");
+    // Next sequence number per log, kept apart so truncation cannot reset it.
+    const LOG_NEXT: TableDefinition<&str, u64> = TableDefinition::new("log_next");
+
+    pub struct EmbeddedStore {
+        db: Database,
+    }
+
+    fn fail(e: impl std::fmt::Display) -> String {
+        format!("redb: {}", e)
+    }
+
+    impl EmbeddedStore {
+        pub fn open(dir: &Path) -> Result<Self, String> {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+            let path = dir.join("corky.redb");
+            let db = Database::create(&path)
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+            // Create the tables so readers never meet a missing one.
+            let txn = db.begin_write().map_err(fail)?;
+            txn.open_table(KV).map_err(fail)?;
+            txn.open_table(LOG).map_err(fail)?;
+            txn.open_table(LOG_NEXT).map_err(fail)?;
+            txn.commit().map_err(fail)?;
+            Ok(Self { db })
+        }
+    }
+
+    impl StateStore for EmbeddedStore {
+        fn backend(&self) -> &'static str {
+            "embedded"
+        }
+
+        fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            check_name(namespace)?;
+            let txn = self.db.begin_read().map_err(fail)?;
+            let table = txn.open_table(KV).map_err(fail)?;
+            let value = table.get((namespace, key)).map_err(fail)?;
+            Ok(value.map(|v| v.value().to_vec()))
+        }
+
+        fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<(), String> {
+            check_name(namespace)?;
+            let txn = self.db.begin_write().map_err(fail)?;
+            txn.open_table(KV)
+                .map_err(fail)?
+                .insert((namespace, key), value)
+                .map_err(fail)?;
+            txn.commit().map_err(fail)
+        }
+
+        fn delete(&self, namespace: &str, key: &[u8]) -> Result<(), String> {
+            check_name(namespace)?;
+            let txn = self.db.begin_write().map_err(fail)?;
+            txn.open_table(KV)
+                .map_err(fail)?
+                .remove((namespace, key))
+                .map_err(fail)?;
+            txn.commit().map_err(fail)
+        }
+
+        fn scan(&self, namespace: &str) -> Result<Entries, String> {
+            check_name(namespace)?;
+            let txn = self.db.begin_read().map_err(fail)?;
+            let table = txn.open_table(KV).map_err(fail)?;
+            let start: (&str, &[u8]) = (namespace, &[]);
+            let mut entries = Vec::new();
+            for item in table.range(start..).map_err(fail)? {
+                let (key, value) = item.map_err(fail)?;
+                let (ns, key) = key.value();
+                if ns != namespace {
+                    break;
+                }
+                entries.push((key.to_vec(), value.value().to_vec()));
+            }
+            Ok(entries)
+        }
+
+        fn append(&self, log: &str, record: &[u8]) -> Result<u64, String> {
+            check_name(log)?;
+            let txn = self.db.begin_write().map_err(fail)?;
+            let seq = {
+                let mut next = txn.open_table(LOG_NEXT).map_err(fail)?;
+                let seq = next.get(log).map_err(fail)?.map_or(1, |v| v.value());
+                next.insert(log, seq + 1).map_err(fail)?;
+                let mut table = txn.open_table(LOG).map_err(fail)?;
+                table.insert((log, seq), record).map_err(fail)?;
+                seq
+            };
+            txn.commit().map_err(fail)?;
+            Ok(seq)
+        }
+
+        fn replay(
+            &self,
+            log: &str,
+            from: u64,
+            each: &mut dyn FnMut(u64, &[u8]),
+        ) -> Result<(), String> {
+            check_name(log)?;
+            let txn = self.db.begin_read().map_err(fail)?;
+            let table = txn.open_table(LOG).map_err(fail)?;
+            for item in table.range((log, from)..=(log, u64::MAX)).map_err(fail)? {
+                let (key, record) = item.map_err(fail)?;
+                each(key.value().1, record.value());
+            }
+            Ok(())
+        }
+
+        fn truncate(&self, log: &str, before: u64) -> Result<(), String> {
+            check_name(log)?;
+            let txn = self.db.begin_write().map_err(fail)?;
+            {
+                let mut table = txn.open_table(LOG).map_err(fail)?;
+                table
+                    .retain_in((log, 0)..(log, before), |_, _| false)
+                    .map_err(fail)?;
+            }
+            txn.commit().map_err(fail)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // A write transaction dropped before its commit is the embedded
+        // equivalent of a crash mid-write.
+        #[test]
+        fn an_uncommitted_write_is_rolled_back() {
+            let dir = super::super::tests::scratch("embedded-crash");
+            {
+                let store = EmbeddedStore::open(&dir).unwrap();
+                store.put("ns", b"kept", b"1").unwrap();
+                let txn = store.db.begin_write().unwrap();
+                txn.open_table(KV)
+                    .unwrap()
+                    .insert(("ns", &b"lost"[..]), &b"2"[..])
+                    .unwrap();
+                drop(txn);
+            }
+            let store = EmbeddedStore::open(&dir).unwrap();
+            assert_eq!(
+                store.scan("ns").unwrap(),
+                [(b"kept".to_vec(), b"1".to_vec())]
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("corky-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn records(store: &dyn StateStore, log: &str, from: u64) -> Vec<(u64, Vec<u8>)> {
+        let mut out = Vec::new();
+        store
+            .replay(log, from, &mut |seq, record| {
+                out.push((seq, record.to_vec()))
+            })
+            .unwrap();
+        out
+    }
+
+    // The suite every backend passes; `open` reopens the same store.
+    fn conformance(open: impl Fn() -> Box<dyn StateStore>) {
+        {
+            let store = open();
+            assert_eq!(store.get("a", b"k").unwrap(), None);
+            store.put("a", b"k2", b"two").unwrap();
+            store.put("a", b"k1", b"one").unwrap();
+            store.put("a", b"", b"empty key").unwrap();
+            store.put("b", b"k1", b"other namespace").unwrap();
+            store.put("a", b"k1", b"uno").unwrap();
+            assert_eq!(store.get("a", b"k1").unwrap().as_deref(), Some(&b"uno"[..]));
+            store.delete("a", b"k2").unwrap();
+            store.delete("a", b"missing").unwrap();
+            assert!(store.put("../escape", b"k", b"v").is_err());
+            assert!(store.append("", b"r").is_err());
+
+            assert_eq!(store.append("events", b"r1").unwrap(), 1);
+            assert_eq!(store.append("events", b"r2").unwrap(), 2);
+            assert_eq!(store.append("events", b"r3").unwrap(), 3);
+            assert_eq!(store.append("other", b"x").unwrap(), 1);
+        }
+        let store = open();
+        assert_eq!(
+            store.scan("a").unwrap(),
+            [
+                (b"".to_vec(), b"empty key".to_vec()),
+                (b"k1".to_vec(), b"uno".to_vec()),
+            ]
+        );
+        assert_eq!(store.scan("b").unwrap().len(), 1);
+        assert!(store.scan("never").unwrap().is_empty());
+        assert_eq!(
+            records(&*store, "events", 2),
+            [(2, b"r2".to_vec()), (3, b"r3".to_vec())]
+        );
+        // Numbering carries on past a restart and a truncation.
+        store.truncate("events", 4).unwrap();
+        assert!(records(&*store, "events", 0).is_empty());
+        drop(store);
+        let store = open();
+        assert_eq!(store.append("events", b"r4").unwrap(), 4);
+        store.truncate("events", 2).unwrap();
+        assert_eq!(records(&*store, "events", 0), [(4, b"r4".to_vec())]);
+        assert!(records(&*store, "never", 0).is_empty());
+    }
+
+    #[test]
+    fn files_conform() {
+        let dir = scratch("files");
+        conformance(|| Box::new(FileStore::open(&dir).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "embedded")]
+    #[test]
+    fn embedded_conforms() {
+        let dir = scratch("embedded");
+        conformance(|| Box::new(EmbeddedStore::open(&dir).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // What the files backend finds after a crash in the middle of a put and
+    // of an append: the half-written value and record are ignored and the
+    // log carries on from the last whole record.
+    #[test]
+    fn files_survive_torn_writes() {
+        let dir = scratch("files-crash");
+        {
+            let store = FileStore::open(&dir).unwrap();
+            store.put("ns", b"k", b"old").unwrap();
+            store.append("log", b"whole").unwrap();
+        }
+        let key = dir.join("kv").join("ns").join("k6b");
+        fs::write(key.with_extension("tmp"), b"ne").unwrap();
+        let log = dir.join("log").join("log.log");
+        let mut torn = fs::read(&log).unwrap();
+        put_record(&mut torn, 2, b"torn");
+        torn.truncate(torn.len() - 3);
+        fs::write(&log, &torn).unwrap();
+
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(
+            store.scan("ns").unwrap(),
+            [(b"k".to_vec(), b"old".to_vec())]
+        );
+        assert_eq!(store.append("log", b"next").unwrap(), 2);
+        assert_eq!(
+            records(&store, "log", 0),
+            [(1, b"whole".to_vec()), (2, b"next".to_vec())]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::Level;
+
+use crate::config::{LoggingConfig, DEFAULT_REPEAT_LIMIT, DEFAULT_REPEAT_SUMMARY_SECS};
+use crate::format::grouped;
+
+//
+// ---------------------------- Repeated log lines -----------------------------
+//
+// An error path that can fire thousands of times a minute, such as sends to a
+// worker endpoint that is down, logs through log() with a Callsite of its
+// own. The same message from the same callsite is logged as usual the first
+// [logging] repeat_limit times; after that it is counted, and every
+// repeat_summary_secs flush() logs one line saying how often it repeated.
+// A message that has not come again for that long is forgotten, so the next
+// occurrence is logged in full again. repeat_limit = 0 logs every line.
+
+// One log statement that summarises its repeats.
+pub struct Callsite {
+    pub name: &'static str,
+    pub level: Level,
+    // The module it is in, from module_path!(), for [logging.filters].
+    pub target: &'static str,
+}
+
+struct Run {
+    site: &'static Callsite,
+    logged: u64,
+    repeated: u64,
+    since: Instant,
+    last: Instant,
+}
+
+// What flush() logs for a message that kept repeating.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub message: String,
+    pub repeated: u64,
+    pub window: Duration,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (previous error repeated {} times in the last {}s)",
+            self.message,
+            grouped(self.repeated),
+            self.window.as_secs()
+        )
+    }
+}
+
+pub struct Suppressor {
+    limit: u64,
+    interval: Duration,
+    runs: BTreeMap<(&'static str, String), Run>,
+}
+
+impl Suppressor {
+    pub fn new(limit: u64, interval: Duration) -> Self {
+        Self {
+            limit,
+            interval,
+            runs: BTreeMap::new(),
+        }
+    }
+
+    // Counts one occurrence; true when it should be logged.
+    pub fn record(&mut self, site: &'static Callsite, message: &str, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let run = self
+            .runs
+            .entry((site.name, message.to_string()))
+            .or_insert(Run {
+                site,
+                logged: 0,
+                repeated: 0,
+                since: now,
+                last: now,
+            });
+        run.last = now;
+        if run.logged < self.limit {
+            run.logged += 1;
+            return true;
+        }
+        if run.repeated == 0 {
+            run.since = now;
+        }
+        run.repeated += 1;
+        false
+    }
+
+    // The summaries due at `now`, each with its callsite; messages that
+    // stopped repeating are forgotten.
+    fn take_due(&mut self, now: Instant) -> Vec<(&'static Callsite, Summary)> {
+        let interval = self.interval;
+        let mut due = Vec::new();
+        self.runs.retain(|(_, message), run| {
+            let stopped = now.saturating_duration_since(run.last) >= interval;
+            let window = now.saturating_duration_since(run.since);
+            if run.repeated > 0 && (stopped || window >= interval) {
+                let summary = Summary {
+                    message: message.clone(),
+                    repeated: run.repeated,
+                    window,
+                };
+                due.push((run.site, summary));
+                run.repeated = 0;
+                run.since = now;
+            }
+            !stopped
+        });
+        due
+    }
+}
+
+static SUPPRESSOR: LazyLock<Mutex<Suppressor>> = LazyLock::new(|| {
+    Mutex::new(Suppressor::new(
+        DEFAULT_REPEAT_LIMIT,
+        Duration::from_secs(DEFAULT_REPEAT_SUMMARY_SECS),
+    ))
+});
+
+fn suppressor() -> std::sync::MutexGuard<'static, Suppressor> {
+    SUPPRESSOR.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Set once at startup from [logging].
+pub fn configure(logging: &LoggingConfig) {
+    *suppressor() = Suppressor::new(
+        logging.repeat_limit,
+        Duration::from_secs(logging.repeat_summary_secs),
+    );
+}
+
+// Logs `message` at `site` unless it is a repeat to be summarised.
+pub fn log(site: &'static Callsite, message: fmt::Arguments) {
+    if !log::log_enabled!(target: site.target, site.level) {
+        return;
+    }
+    let message = message.to_string();
+    if suppressor().record(site, &message, Instant::now()) {
+        log::log!(target: site.target, site.level, "{}", message);
+    }
+}
+
+// Logs the summaries due; the broker loop calls this about once a second.
+pub fn flush(now: Instant) {
+    let due = suppressor().take_due(now);
+    for (site, summary) in due {
+        log::log!(target: site.target, site.level, "{}", summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEND: Callsite = Callsite {
+        name: "send",
+        level: Level::Error,
+        target: module_path!(),
+    };
+    const OTHER: Callsite = Callsite {
+        name: "other",
+        level: Level::Warn,
+        target: module_path!(),
+    };
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn due(suppressor: &mut Suppressor, now: Instant) -> Vec<Summary> {
+        suppressor
+            .take_due(now)
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect()
+    }
+
+    #[test]
+    fn the_first_repeats_are_logged_and_the_rest_counted() {
+        let start = Instant::now();
+        let mut suppressor = Suppressor::new(2, secs(30));
+        let logged: Vec<bool> = (0..5)
+            .map(|_| suppressor.record(&SEND, "send failed", start))
+            .collect();
+        assert_eq!(logged, [true, true, false, false, false]);
+        // Keyed on callsite and message: nothing else is held back.
+        assert!(suppressor.record(&SEND, "send failed: other", start));
+        assert!(suppressor.record(&OTHER, "send failed", start));
+
+        let mut off = Suppressor::new(0, secs(30));
+        assert!((0..5).all(|_| off.record(&SEND, "send failed", start)));
+        assert!(due(&mut off, start + secs(60)).is_empty());
+    }
+
+    #[test]
+    fn summaries_come_each_interval_until_the_error_stops() {
+        let start = Instant::now();
+        let mut suppressor = Suppressor::new(1, secs(30));
+        for i in 0..1244 {
+            suppressor.record(&SEND, "send failed", start + Duration::from_millis(i * 10));
+        }
+        // The window opened with the first repeat, 10ms in.
+        assert!(due(&mut suppressor, start + secs(30)).is_empty());
+        let summaries = due(&mut suppressor, start + secs(31));
+        assert_eq!(
+            summaries,
+            [Summary {
+                message: "send failed".to_string(),
+                repeated: 1243,
+                window: Duration::from_millis(30_990),
+            }]
+        );
+        assert_eq!(
+            summaries[0].to_string(),
+            "send failed (previous error repeated 1,243 times in the last 30s)"
+        );
+        // Nothing repeated since that summary: none until it stops for good.
+        suppressor.record(&SEND, "send failed", start + secs(40));
+        assert!(due(&mut suppressor, start + secs(45)).is_empty());
+        let last = due(&mut suppressor, start + secs(70));
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].repeated, 1);
+
+        // Forgotten, so it is logged in full once it comes back.
+        assert!(suppressor.record(&SEND, "send failed", start + secs(71)));
+        assert!(!suppressor.record(&SEND, "send failed", start + secs(71)));
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use log::Level;
+
+use crate::logjson;
+use crate::logtime;
+
+//
+// ---------------------------------- Syslog -----------------------------------
+//
+// logging.target = "syslog" sends each log event as one datagram to the local
+// syslog socket, which journald listens on too. The event's level becomes the
+// severity, under the daemon facility, and the tag is corky-zmq with the
+// process id: `<30>corky-zmq[4242]: (Broker) Started`. The daemon adds the
+// time and host. A socket that goes away, as when the daemon restarts, is
+// connected again for the next line; a line that still cannot be sent goes
+// to stderr.
+
+pub const SOCKET: &str = "/dev/log";
+pub const APP_NAME: &str = "corky-zmq";
+
+const FACILITY_DAEMON: u8 = 3;
+
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// The priority and tag a line at `level` starts with.
+pub fn header(level: Level, pid: u32) -> String {
+    let priority = FACILITY_DAEMON * 8 + severity(level);
+    format!("<{}>{}[{}]: ", priority, APP_NAME, pid)
+}
+
+// The logger's output with the syslog target: one datagram per line.
+pub struct SyslogWriter {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+fn connect(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+impl SyslogWriter {
+    // Err when nothing listens at `path`.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            socket: connect(path)?,
+        })
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = buf.strip_suffix(b"\n").unwrap_or(buf);
+        if self.socket.send(line).is_err() {
+            let resent = connect(&self.path).and_then(|socket| {
+                socket.send(line)?;
+                Ok(socket)
+            });
+            match resent {
+                Ok(socket) => self.socket = socket,
+                Err(_) => io::stderr().write_all(buf)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// An env_logger format for syslog lines: the header, then the message, or
+// with logging.format = "json" the event's JSON object.
+pub fn format(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let header = header(record.level(), std::process::id());
+    match logjson::json() {
+        true => {
+            let event = logjson::event(record, logtime::now());
+            write!(buf, "{}{}", header, event)
+        }
+        false => write!(buf, "{}{}", header, record.args()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_become_daemon_severities() {
+        assert_eq!(header(Level::Error, 7), "<27>corky-zmq[7]: ");
+        assert_eq!(header(Level::Warn, 7), "<28>corky-zmq[7]: ");
+        assert_eq!(header(Level::Info, 7), "<30>corky-zmq[7]: ");
+        assert_eq!(header(Level::Debug, 7), "<31>corky-zmq[7]: ");
+        assert_eq!(severity(Level::Trace), 7);
+    }
+
+    #[test]
+    fn each_line_is_one_datagram_and_a_missing_socket_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("corky-syslog-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        assert!(SyslogWriter::connect(&path).is_err());
+
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let mut writer = SyslogWriter::connect(&path).unwrap();
+        writer.write_all(b"<30>corky-zmq[7]: one\n").unwrap();
+        writer.write_all(b"<28>corky-zmq[7]: two").unwrap();
+        let mut buf = [0u8; 64];
+        let n = daemon.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<30>corky-zmq[7]: one");
+        let n = daemon.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<28>corky-zmq[7]: two");
+
+        // The daemon restarted: the next line finds the new socket.
+        drop(daemon);
+        std::fs::remove_file(&path).unwrap();
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        writer.write_all(b"<30>corky-zmq[7]: three\n").unwrap();
+        let n = daemon.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<30>corky-zmq[7]: three");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
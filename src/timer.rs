@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+//
+// ------------------------------ Periodic -------------------------------------
+//
+
+// Deadline-based periodic trigger. The caller polls it with the current time
+// on every loop iteration; it fires once per elapsed interval regardless of
+// how often it is polled. Missed ticks are skipped rather than replayed, so a
+// stalled loop produces one catch-up firing instead of a burst.
+pub struct Periodic {
+    interval: Duration,
+    next_due: Instant,
+}
+
+impl Periodic {
+    pub fn new(interval: Duration) -> Self {
+        Self::starting_at(interval, Instant::now())
+    }
+
+    pub fn starting_at(interval: Duration, start: Instant) -> Self {
+        Self {
+            interval,
+            next_due: start + interval,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if now < self.next_due {
+            return false;
+        }
+        self.next_due += self.interval;
+        if self.next_due <= now {
+            // Fell more than one interval behind; realign to now.
+            self.next_due = now + self.interval;
+        }
+        true
+    }
+
+    pub fn next_due(&self) -> Instant {
+        self.next_due
+    }
+}
+
+// About thirty years: later than any deadline anyone waits for, and early
+// enough to be representable on every platform.
+const FAR_FUTURE: Duration = Duration::from_secs(30 * 365 * 24 * 3600);
+
+// `now + delay` for delays taken from the wire, which may be large enough to
+// overflow an Instant; those saturate to the far future instead of panicking.
+pub fn deadline_after(now: Instant, delay: Duration) -> Instant {
+    now.checked_add(delay.min(FAR_FUTURE))
+        .or_else(|| now.checked_add(FAR_FUTURE))
+        .unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_once_per_interval() {
+        let start = Instant::now();
+        let mut p = Periodic::starting_at(Duration::from_millis(100), start);
+        assert!(!p.poll(start));
+        assert!(!p.poll(start + Duration::from_millis(99)));
+        assert!(p.poll(start + Duration::from_millis(100)));
+        assert!(!p.poll(start + Duration::from_millis(150)));
+        assert!(p.poll(start + Duration::from_millis(205)));
+        // Deadlines stay on the original grid despite the late poll.
+        assert_eq!(p.next_due(), start + Duration::from_millis(300));
+    }
+
+    #[test]
+    fn huge_delays_saturate() {
+        let now = Instant::now();
+        let far = deadline_after(now, Duration::from_millis(u64::MAX));
+        assert_eq!(far, now + FAR_FUTURE);
+        assert_eq!(deadline_after(now, Duration::MAX), far);
+        let soon = Duration::from_millis(5);
+        assert_eq!(deadline_after(now, soon), now + soon);
+    }
+
+    #[test]
+    fn skips_missed_ticks_after_a_stall() {
+        let start = Instant::now();
+        let mut p = Periodic::starting_at(Duration::from_millis(10), start);
+        let late = start + Duration::from_millis(1000);
+        assert!(p.poll(late));
+        assert!(!p.poll(late));
+        assert_eq!(p.next_due(), late + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn tight_loop_fires_on_wall_clock() {
+        // Spin as the low-latency broker does and count firings.
+        let mut p = Periodic::new(Duration::from_millis(25));
+        let end = Instant::now() + Duration::from_millis(200);
+        let mut fired = 0;
+        while Instant::now() < end {
+            if p.poll(Instant::now()) {
+                fired += 1;
+            }
+        }
+        assert!((7..=8).contains(&fired), "fired {} times", fired);
+    }
+}
@@ -0,0 +1,549 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{TopicRewrite, TopicsConfig};
+use crate::format::escape_text;
+use crate::fs_util;
+use crate::metrics::{Counter, Registry};
+use crate::multipart::Multipart;
+
+//
+// ---------------------------- Topic rules ------------------------------------
+//
+// A proxy-wide allowlist and rewrite table for the pub/sub plane, applied
+// after any ACL (see crate::acl). With an allowlist, only publications whose
+// topic starts with one of its prefixes are forwarded; an empty list forwards
+// everything. A rewrite replaces the `from` prefix of a publication's topic
+// with `to`, the first matching rule winning; the allowlist is checked against
+// the topic as published.
+//
+// Subscriptions are translated into what the proxy needs from upstream: one
+// to "orders." also subscribes to "legacy." when that is rewritten to
+// "orders.", and one to "" under an allowlist only subscribes to the allowed
+// prefixes. The proxy remembers each downstream subscription, so when the
+// rules change it unsubscribes upstream from what is no longer needed
+// (logging a warning) and subscribes to what is newly allowed, without
+// touching the subscribers themselves.
+//
+// The rules are an immutable snapshot swapped by the admin socket and picked
+// up by the proxy thread when their version changes. Changes are written to
+// the state file before they take effect unless [topics] ephemeral is set.
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TopicRules {
+    pub allow: Vec<String>,
+    pub rewrite: Vec<TopicRewrite>,
+}
+
+impl TopicRules {
+    pub fn allows(&self, topic: &[u8]) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|p| topic.starts_with(p.as_bytes()))
+    }
+
+    pub fn rewrite(&self, topic: &[u8]) -> Option<Vec<u8>> {
+        let rule = self
+            .rewrite
+            .iter()
+            .find(|r| topic.starts_with(r.from.as_bytes()))?;
+        Some([rule.to.as_bytes(), &topic[rule.from.len()..]].concat())
+    }
+
+    // Upstream subscriptions standing in for a downstream one to `topic`,
+    // sorted and without any covered by another.
+    pub fn upstream(&self, topic: &[u8]) -> Vec<Vec<u8>> {
+        let mut sources = Vec::new();
+        // Publications all rewritten elsewhere can never match `topic`.
+        if self.rewrite(topic).is_none() {
+            sources.push(topic.to_vec());
+        }
+        for rule in &self.rewrite {
+            let (from, to) = (rule.from.as_bytes(), rule.to.as_bytes());
+            if let Some(rest) = topic.strip_prefix(to) {
+                sources.push([from, rest].concat());
+            } else if to.starts_with(topic) {
+                sources.push(from.to_vec());
+            }
+        }
+        let mut upstream = Vec::new();
+        for source in sources {
+            if self.allows(&source) {
+                upstream.push(source);
+            } else {
+                let narrower = self.allow.iter().map(|p| p.as_bytes());
+                upstream.extend(
+                    narrower
+                        .filter(|p| p.starts_with(&source))
+                        .map(<[u8]>::to_vec),
+                );
+            }
+        }
+        upstream.sort();
+        let mut kept: Vec<Vec<u8>> = Vec::with_capacity(upstream.len());
+        for topic in upstream {
+            if !kept.iter().any(|k| topic.starts_with(k)) {
+                kept.push(topic);
+            }
+        }
+        kept
+    }
+
+    pub fn allow_add(&mut self, prefix: &str) -> Result<(), String> {
+        if self.allow.iter().any(|p| p == prefix) {
+            return Err(format!("{:?} is already allowed", prefix));
+        }
+        self.allow.push(prefix.to_string());
+        Ok(())
+    }
+
+    pub fn allow_remove(&mut self, prefix: &str) -> Result<(), String> {
+        let before = self.allow.len();
+        self.allow.retain(|p| p != prefix);
+        match self.allow.len() < before {
+            true => Ok(()),
+            false => Err(format!("no allow rule for {:?}", prefix)),
+        }
+    }
+
+    // A rule for an existing `from` gets the new target and keeps its place.
+    pub fn rewrite_add(&mut self, from: &str, to: &str) -> Result<(), String> {
+        if from == to {
+            return Err(format!("cannot rewrite {:?} to itself", from));
+        }
+        match self.rewrite.iter_mut().find(|r| r.from == from) {
+            Some(rule) => rule.to = to.to_string(),
+            None => self.rewrite.push(TopicRewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+        }
+        Ok(())
+    }
+
+    pub fn rewrite_remove(&mut self, from: &str) -> Result<(), String> {
+        let before = self.rewrite.len();
+        self.rewrite.retain(|r| r.from != from);
+        match self.rewrite.len() < before {
+            true => Ok(()),
+            false => Err(format!("no rewrite rule for {:?}", from)),
+        }
+    }
+
+    // One line per rule, for the admin `topics rules list` command.
+    pub fn describe(&self) -> Vec<String> {
+        let allow = self.allow.iter().map(|p| format!("allow {:?}", p));
+        let rewrite = (self.rewrite.iter()).map(|r| format!("rewrite {:?} -> {:?}", r.from, r.to));
+        allow.chain(rewrite).collect()
+    }
+}
+
+pub fn default_state_file() -> Result<PathBuf, String> {
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    Ok(home.join(".corky").join("topics.toml"))
+}
+
+pub fn load_state(path: &Path) -> Result<TopicRules, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+fn save_state(path: &Path, rules: &TopicRules) -> Result<(), String> {
+    let text = toml::to_string(rules).map_err(|e| format!("Failed to encode rules: {}", e))?;
+    fs_util::replace_file(path, text.as_bytes(), None)
+}
+
+// The topic allowlist and rewrites in force, swapped by the admin `topics`
+// commands (and saved to the state file) and read by the proxy when
+// `version` moves.
+pub struct Topics {
+    version: AtomicU64,
+    rules: Mutex<Arc<TopicRules>>,
+    // None when changes are ephemeral.
+    state_file: Option<PathBuf>,
+}
+
+impl Topics {
+    // The state file, when there is one, replaces the configured rules.
+    pub fn new(config: &TopicsConfig) -> Self {
+        let configured = TopicRules {
+            allow: config.allow.clone(),
+            rewrite: config.rewrite.clone(),
+        };
+        let state_file = match (&config.state_file, config.ephemeral) {
+            (_, true) => None,
+            (Some(path), false) => Some(PathBuf::from(path)),
+            (None, false) => default_state_file()
+                .map_err(|e| warn!("(Proxy) Topic rule changes will not be saved: {}", e))
+                .ok(),
+        };
+        let rules = match &state_file {
+            Some(path) if path.exists() => load_state(path).unwrap_or_else(|e| {
+                warn!("(Proxy) Using the configured topic rules: {}", e);
+                configured
+            }),
+            _ => configured,
+        };
+        Self {
+            version: AtomicU64::new(0),
+            rules: Mutex::new(Arc::new(rules)),
+            state_file,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn rules(&self) -> Arc<TopicRules> {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Apply `change` to a copy of the rules, save it and swap it in. Nothing
+    // changes when either step fails.
+    pub fn update<F>(&self, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut TopicRules) -> Result<(), String>,
+    {
+        let mut current = self.rules.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rules = TopicRules::clone(&current);
+        change(&mut rules)?;
+        if let Some(path) = &self.state_file {
+            save_state(path, &rules)?;
+        }
+        *current = Arc::new(rules);
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+}
+
+struct Subscription {
+    // Downstream subscriptions not yet cancelled.
+    count: usize,
+    upstream: Vec<Vec<u8>>,
+}
+
+// The proxy thread's view: the current snapshot plus every downstream
+// subscription and what it was translated into upstream.
+pub struct TopicFilter {
+    version: u64,
+    rules: Arc<TopicRules>,
+    subscriptions: BTreeMap<Vec<u8>, Subscription>,
+    denied_publish: Counter,
+    denied_subscribe: Counter,
+}
+
+impl TopicFilter {
+    pub fn new(shared: &Topics, metrics: &Registry) -> Self {
+        Self {
+            version: shared.version(),
+            rules: shared.rules(),
+            subscriptions: BTreeMap::new(),
+            denied_publish: metrics.counter("corky_topics_denied_total", &[("action", "publish")]),
+            denied_subscribe: metrics
+                .counter("corky_topics_denied_total", &[("action", "subscribe")]),
+        }
+    }
+
+    // Pick up changed rules. Returns the subscription frames to send upstream
+    // so existing subscriptions follow them, keeping XSUB's counts balanced.
+    pub fn refresh(&mut self, shared: &Topics) -> Vec<Vec<u8>> {
+        let version = shared.version();
+        if version == self.version {
+            return Vec::new();
+        }
+        self.version = version;
+        self.rules = shared.rules();
+        let mut frames = Vec::new();
+        for (topic, subscription) in &mut self.subscriptions {
+            let upstream = self.rules.upstream(topic);
+            for removed in subscription
+                .upstream
+                .iter()
+                .filter(|u| !upstream.contains(u))
+            {
+                warn!(
+                    "(Proxy) Topic rules: unsubscribing {:?} upstream, {} subscriber(s) of {:?}",
+                    String::from_utf8_lossy(removed),
+                    subscription.count,
+                    String::from_utf8_lossy(topic)
+                );
+                frames.extend((0..subscription.count).map(|_| [&[0], &removed[..]].concat()));
+            }
+            for added in upstream
+                .iter()
+                .filter(|u| !subscription.upstream.contains(u))
+            {
+                debug!(
+                    "(Proxy) Topic rules: subscribing {:?} upstream for {:?}",
+                    String::from_utf8_lossy(added),
+                    String::from_utf8_lossy(topic)
+                );
+                frames.extend((0..subscription.count).map(|_| [&[1], &added[..]].concat()));
+            }
+            subscription.upstream = upstream;
+        }
+        frames
+    }
+
+    // The publication to forward, rewritten, or None when it is not allowed.
+    pub fn publication(&self, message: Multipart) -> Option<Multipart> {
+        let topic = message.first().map(Vec::as_slice).unwrap_or_default();
+        if !self.rules.allows(topic) {
+            debug!(
+                "(Proxy) Topic rules: dropping publication to {:?}",
+                String::from_utf8_lossy(topic)
+            );
+            self.denied_publish.inc();
+            return None;
+        }
+        let Some(rewritten) = self.rules.rewrite(topic) else {
+            return Some(message);
+        };
+        let mut frames = message.into_frames();
+        if let Some(first) = frames.first_mut() {
+            *first = rewritten;
+        }
+        Some(Multipart::new(frames))
+    }
+
+    // Downstream subscriptions to `topic` not yet cancelled.
+    pub fn subscribers(&self, topic: &[u8]) -> usize {
+        self.subscriptions.get(topic).map_or(0, |s| s.count)
+    }
+
+    // Track a downstream (un)subscription, logged at info, and return the
+    // frames to send upstream for it. Unsubscriptions from topics never
+    // subscribed to are dropped.
+    //
+    // The XPUB de-duplicates per subscriber (see crate::proxy): only the
+    // first subscription to a topic arrives here, and the unsubscription that
+    // leaves it without subscribers, including the one libzmq makes when the
+    // last of them disconnects. A topic's count is then 1 while anyone is
+    // subscribed. In manual mode, with ACLs or replay, every subscription and
+    // unsubscription arrives and the counts are of subscriptions.
+    pub fn subscription(&mut self, subscribe: bool, topic: &[u8]) -> Vec<Vec<u8>> {
+        let kind = u8::from(subscribe);
+        let shown = escape_text(&String::from_utf8_lossy(topic)).into_owned();
+        let upstream = if subscribe {
+            let entry = self
+                .subscriptions
+                .entry(topic.to_vec())
+                .or_insert_with(|| Subscription {
+                    count: 0,
+                    upstream: self.rules.upstream(topic),
+                });
+            entry.count += 1;
+            match entry.count {
+                1 => info!("(Proxy) SUBSCRIBE topic=\"{}\" (first subscriber)", shown),
+                n => info!("(Proxy) SUBSCRIBE topic=\"{}\" ({} subscribers)", shown, n),
+            }
+            if entry.upstream.is_empty() {
+                warn!(
+                    "(Proxy) Topic rules: nothing allowed for subscription to {:?}",
+                    String::from_utf8_lossy(topic)
+                );
+                self.denied_subscribe.inc();
+            }
+            entry.upstream.clone()
+        } else {
+            let Some(entry) = self.subscriptions.get_mut(topic) else {
+                debug!(
+                    "(Proxy) UNSUBSCRIBE topic=\"{}\" without a subscription",
+                    shown
+                );
+                return Vec::new();
+            };
+            entry.count -= 1;
+            match entry.count {
+                0 => {
+                    info!(
+                        "(Proxy) UNSUBSCRIBE topic=\"{}\" (last subscriber gone)",
+                        shown
+                    );
+                    self.subscriptions.remove(topic).unwrap().upstream
+                }
+                n => {
+                    info!(
+                        "(Proxy) UNSUBSCRIBE topic=\"{}\" ({} subscribers left)",
+                        shown, n
+                    );
+                    entry.upstream.clone()
+                }
+            }
+        };
+        upstream
+            .iter()
+            .map(|u| [&[kind], &u[..]].concat())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], rewrite: &[(&str, &str)]) -> TopicRules {
+        let mut rules = TopicRules::default();
+        for prefix in allow {
+            rules.allow_add(prefix).unwrap();
+        }
+        for (from, to) in rewrite {
+            rules.rewrite_add(from, to).unwrap();
+        }
+        rules
+    }
+
+    fn topics(list: &[&str]) -> Vec<Vec<u8>> {
+        list.iter().map(|t| t.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn subscriptions_are_translated_for_upstream() {
+        let open = rules(&[], &[]);
+        assert_eq!(open.upstream(b"news."), topics(&["news."]));
+
+        let allowlist = rules(&["news.", "orders."], &[]);
+        assert_eq!(allowlist.upstream(b"news.eu"), topics(&["news.eu"]));
+        assert_eq!(allowlist.upstream(b""), topics(&["news.", "orders."]));
+        assert_eq!(allowlist.upstream(b"n"), topics(&["news."]));
+        assert!(allowlist.upstream(b"prices.").is_empty());
+
+        let rewrites = rules(&[], &[("legacy.", "orders."), ("old", "orders.eu.")]);
+        assert_eq!(
+            rewrites.upstream(b"orders.eu.1"),
+            topics(&["legacy.eu.1", "old1", "orders.eu.1"])
+        );
+        assert_eq!(
+            rewrites.upstream(b"orders."),
+            topics(&["legacy.", "old", "orders."])
+        );
+        // Everything published on legacy. is forwarded as orders.
+        assert!(rewrites.upstream(b"legacy.x").is_empty());
+        assert_eq!(rewrites.upstream(b""), topics(&[""]));
+    }
+
+    #[test]
+    fn publications_are_filtered_then_rewritten() {
+        let shared = Topics::new(&TopicsConfig {
+            allow: vec!["news.".into(), "legacy.".into()],
+            rewrite: vec![TopicRewrite {
+                from: "legacy.".into(),
+                to: "news.".into(),
+            }],
+            ephemeral: true,
+            ..TopicsConfig::default()
+        });
+        let filter = TopicFilter::new(&shared, &Registry::new());
+        let message = |topic: &str| Multipart::new(vec![topic.as_bytes().to_vec(), b"x".to_vec()]);
+        assert_eq!(
+            filter.publication(message("news.1")),
+            Some(message("news.1"))
+        );
+        assert_eq!(
+            filter.publication(message("legacy.1")),
+            Some(message("news.1"))
+        );
+        assert_eq!(filter.publication(message("orders.1")), None);
+    }
+
+    #[test]
+    fn rule_changes_move_subscriptions_upstream() {
+        let shared = Topics::new(&TopicsConfig {
+            ephemeral: true,
+            ..TopicsConfig::default()
+        });
+        let mut filter = TopicFilter::new(&shared, &Registry::new());
+        assert_eq!(filter.subscription(true, b""), topics(&["\x01"]));
+        assert_eq!(filter.subscription(true, b""), topics(&["\x01"]));
+        assert_eq!(
+            filter.subscription(true, b"orders."),
+            topics(&["\x01orders."])
+        );
+        assert!(filter.refresh(&shared).is_empty());
+
+        shared.update(|r| r.allow_add("news.")).unwrap();
+        // Both subscribers of "" move to news.; orders. loses its only one.
+        assert_eq!(
+            filter.refresh(&shared),
+            topics(&["\x00", "\x00", "\x01news.", "\x01news.", "\x00orders."])
+        );
+        assert_eq!(filter.subscription(false, b""), topics(&["\x00news."]));
+
+        shared.update(|r| r.allow_remove("news.")).unwrap();
+        assert_eq!(
+            filter.refresh(&shared),
+            topics(&["\x00news.", "\x01", "\x01orders."])
+        );
+        assert_eq!(filter.subscription(false, b""), topics(&["\x00"]));
+        assert!(filter.subscription(false, b"").is_empty());
+        assert_eq!(
+            filter.subscription(false, b"orders."),
+            topics(&["\x00orders."])
+        );
+    }
+
+    #[test]
+    fn subscriptions_are_counted_per_topic() {
+        let shared = Topics::new(&TopicsConfig {
+            ephemeral: true,
+            ..TopicsConfig::default()
+        });
+        let mut filter = TopicFilter::new(&shared, &Registry::new());
+        assert_eq!(filter.subscribers(b"ticker."), 0);
+        // Each one goes upstream, where XSUB counts them as well.
+        for expected in 1..=3 {
+            assert_eq!(
+                filter.subscription(true, b"ticker."),
+                topics(&["\x01ticker."])
+            );
+            assert_eq!(filter.subscribers(b"ticker."), expected);
+        }
+        assert_eq!(filter.subscription(true, b""), topics(&["\x01"]));
+        assert_eq!(
+            filter.subscription(false, b"ticker."),
+            topics(&["\x00ticker."])
+        );
+        assert_eq!(filter.subscribers(b"ticker."), 2);
+        assert_eq!(filter.subscribers(b""), 1);
+        filter.subscription(false, b"ticker.");
+        filter.subscription(false, b"ticker.");
+        assert_eq!(filter.subscribers(b"ticker."), 0);
+        // One too many, as from a subscriber that never subscribed.
+        assert!(filter.subscription(false, b"ticker.").is_empty());
+        assert_eq!(filter.subscribers(b"ticker."), 0);
+        assert_eq!(filter.subscribers(b""), 1);
+    }
+
+    #[test]
+    fn changes_are_saved_unless_ephemeral() {
+        let path = std::env::temp_dir().join(format!("corky-topics-{}.toml", std::process::id()));
+        let config = TopicsConfig {
+            allow: vec!["configured.".into()],
+            state_file: Some(path.display().to_string()),
+            ..TopicsConfig::default()
+        };
+        let shared = Topics::new(&config);
+        assert_eq!(shared.rules().allow, ["configured."]);
+        shared.update(|r| r.rewrite_add("a.", "b.")).unwrap();
+        assert!(shared.update(|r| r.allow_add("configured.")).is_err());
+        assert!(shared.update(|r| r.rewrite_remove("c.")).is_err());
+        assert_eq!(shared.version(), 1);
+
+        let restarted = Topics::new(&config);
+        assert_eq!(*restarted.rules(), rules(&["configured."], &[("a.", "b.")]));
+        let ephemeral = Topics::new(&TopicsConfig {
+            ephemeral: true,
+            ..config.clone()
+        });
+        assert!(ephemeral.rules().rewrite.is_empty());
+        ephemeral.update(|r| r.allow_remove("configured.")).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::config::WatchConfig;
+use crate::events::{unix_ms, BrokerEvent};
+use crate::metrics::{Counter, Gauge, Registry};
+use crate::multipart::Multipart;
+use crate::peers::PeerRole;
+
+//
+// ------------------------------ Presence watch -------------------------------
+//
+// Push notifications about peers coming and going, for clients on the
+// client-to-client socket that would rather not poll. With [watch] enabled a
+// client subscribes by sending one frame,
+//
+//     {"cmd": "watch", "names": ["pricing-svc", "quotes.*", "*"]}
+//
+// and is answered [WATCH_ID, {"watching": [..]}] with everything it now
+// watches. Names are peer identities on any of the broker's sockets: "*"
+// matches every peer and a trailing "*" matches by prefix. {"cmd": "unwatch",
+// "names": [..]} drops names, all of them when "names" is left out. Each
+// matching presence event from the event stream (crate::events) is sent as
+//
+//     [WATCH_ID, {"version", "event", "ts_ms", "identity", ..}]
+//
+// for client|worker|direct.connected (the first message since the broker
+// last forgot the peer), worker.disconnected (DISCONNECT), and
+// client|worker|direct.expired and worker.pool_expired (collected by the idle
+// GC, or missing heartbeats for longer than [gc] worker_ttl_ms). A watcher is
+// not told about itself.
+//
+// Every watcher has its own queue of at most [watch] queue notifications. A
+// watcher that does not keep up loses the oldest ones, counted in
+// corky_watch_dropped_total. At most [watch] max_watchers peers watch at
+// once; a "watch" from one more is rejected. A watch lasts as long as the
+// watcher: it is dropped when a notification finds the watcher disconnected,
+// or when the peer table forgets it, idle or evicted to make room (the broker
+// calls forget); sending "watch" again keeps an otherwise silent watcher
+// alive.
+
+pub const WATCH_ID: &[u8] = b"__corky_watch__";
+pub const WILDCARD: &str = "*";
+const MAX_NAMES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchCommand {
+    Watch(Vec<String>),
+    // None drops every name.
+    Unwatch(Option<Vec<String>>),
+}
+
+impl WatchCommand {
+    // None when `frame` is not a watch command at all.
+    pub fn parse(frame: &[u8]) -> Option<Result<Self, String>> {
+        let request: Value = serde_json::from_slice(frame).ok()?;
+        let cmd = request.get("cmd")?.as_str()?;
+        let names = match request.get("names") {
+            None => None,
+            Some(names) => match parse_names(names) {
+                Ok(names) => Some(names),
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        Some(match (cmd, names) {
+            ("watch", Some(names)) if !names.is_empty() => Ok(WatchCommand::Watch(names)),
+            ("watch", _) => Err("watch needs a list of names".to_string()),
+            ("unwatch", names) => Ok(WatchCommand::Unwatch(names)),
+            (cmd, _) => Err(format!("unknown command {:?}", cmd)),
+        })
+    }
+}
+
+fn parse_names(names: &Value) -> Result<Vec<String>, String> {
+    let names = names.as_array().ok_or("names must be a list")?;
+    if names.len() > MAX_NAMES {
+        return Err(format!("at most {} names", MAX_NAMES));
+    }
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            Some(name) if !name.is_empty() => Ok(name.to_string()),
+            _ => Err("names must be non-empty strings".to_string()),
+        })
+        .collect()
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix(WILDCARD) {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+// The peer a presence event is about; None for other events.
+fn subject(event: &BrokerEvent) -> Option<(PeerRole, &[u8])> {
+    match event {
+        BrokerEvent::Connected { role, identity } | BrokerEvent::Expired { role, identity, .. } => {
+            Some((*role, identity))
+        }
+        BrokerEvent::PoolExpired { identity, .. } | BrokerEvent::Disconnected { identity } => {
+            Some((PeerRole::Worker, identity))
+        }
+        _ => None,
+    }
+}
+
+fn notification(watcher: &[u8], body: &[u8]) -> Multipart {
+    Multipart::new(vec![watcher.to_vec(), WATCH_ID.to_vec(), body.to_vec()])
+}
+
+pub fn rejected_reply(watcher: &[u8], reason: &str) -> Multipart {
+    let reply = json!({"error": {"code": "watch_rejected", "message": reason}});
+    notification(watcher, reply.to_string().as_bytes())
+}
+
+#[derive(Default)]
+struct Watcher {
+    names: BTreeSet<String>,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl Watcher {
+    fn wants(&self, name: &str) -> bool {
+        self.names.iter().any(|pattern| matches(pattern, name))
+    }
+}
+
+pub struct Watches {
+    watchers: HashMap<Vec<u8>, Watcher>,
+    capacity: usize,
+    max_watchers: usize,
+    watching: Gauge,
+    sent: Counter,
+    dropped: Counter,
+}
+
+impl Watches {
+    pub fn new(config: &WatchConfig, metrics: &Registry) -> Self {
+        Self {
+            watchers: HashMap::new(),
+            capacity: config.queue.max(1),
+            max_watchers: config.max_watchers.max(1),
+            watching: metrics.gauge("corky_watch_watchers", &[]),
+            sent: metrics.counter("corky_watch_notifications_total", &[]),
+            dropped: metrics.counter("corky_watch_dropped_total", &[]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.watchers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    // Apply one command from `watcher` and build its reply.
+    pub fn command(&mut self, watcher: &[u8], command: WatchCommand) -> Multipart {
+        let names = match command {
+            WatchCommand::Watch(names) => {
+                if !self.watchers.contains_key(watcher) && self.watchers.len() >= self.max_watchers
+                {
+                    let reason = format!("at most {} watchers", self.max_watchers);
+                    return rejected_reply(watcher, &reason);
+                }
+                let entry = self.watchers.entry(watcher.to_vec()).or_default();
+                entry.names.extend(names);
+                entry.names.iter().cloned().collect()
+            }
+            WatchCommand::Unwatch(names) => {
+                let remaining = match (self.watchers.get_mut(watcher), names) {
+                    (Some(entry), Some(names)) => {
+                        for name in &names {
+                            entry.names.remove(name);
+                        }
+                        entry.names.iter().cloned().collect()
+                    }
+                    _ => Vec::new(),
+                };
+                if remaining.is_empty() {
+                    self.watchers.remove(watcher);
+                }
+                remaining
+            }
+        };
+        self.watching.set(self.watchers.len() as i64);
+        let reply = json!({ "watching": names });
+        notification(watcher, reply.to_string().as_bytes())
+    }
+
+    // Queue `event` for every watcher interested in its peer.
+    pub fn notify(&mut self, event: &BrokerEvent) {
+        let Some((role, identity)) = subject(event) else {
+            return;
+        };
+        if self.watchers.is_empty() {
+            return;
+        }
+        let name = String::from_utf8_lossy(identity);
+        let mut body = None;
+        for (watcher, entry) in self.watchers.iter_mut() {
+            let itself = role == PeerRole::Direct && watcher.as_slice() == identity;
+            if itself || !entry.wants(&name) {
+                continue;
+            }
+            if entry.queue.len() >= self.capacity {
+                entry.queue.pop_front();
+                self.dropped.inc();
+            }
+            let body = body.get_or_insert_with(|| event.to_json(unix_ms()).to_string());
+            entry.queue.push_back(body.clone().into_bytes());
+        }
+    }
+
+    // Send what is queued until each watcher's queue is empty or it stops
+    // accepting. A watcher that is no longer connected is dropped.
+    pub fn flush(&mut self, mut send: impl FnMut(&Multipart) -> Result<(), zmq::Error>) {
+        let mut gone = Vec::new();
+        for (watcher, entry) in self.watchers.iter_mut() {
+            while let Some(body) = entry.queue.front() {
+                match send(&notification(watcher, body)) {
+                    Ok(()) => self.sent.inc(),
+                    // Try again on the next pass.
+                    Err(zmq::Error::EAGAIN) => break,
+                    Err(zmq::Error::EHOSTUNREACH) => {
+                        gone.push(watcher.clone());
+                        break;
+                    }
+                    Err(e) => {
+                        debug!("(Broker) Cannot notify watcher: {}", e);
+                        self.dropped.inc();
+                    }
+                }
+                entry.queue.pop_front();
+            }
+        }
+        for watcher in gone {
+            info!(
+                "(Broker) Watcher {} disconnected, dropping its watch",
+                String::from_utf8_lossy(&watcher)
+            );
+            self.forget(&watcher);
+        }
+    }
+
+    // Drop the watch of a peer the peer table no longer knows.
+    pub fn forget(&mut self, watcher: &[u8]) {
+        if let Some(entry) = self.watchers.remove(watcher) {
+            self.dropped.add(entry.queue.len() as u64);
+        }
+        self.watching.set(self.watchers.len() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watches(queue: usize, metrics: &Registry) -> Watches {
+        Watches::new(
+            &WatchConfig {
+                enabled: true,
+                queue,
+                ..WatchConfig::default()
+            },
+            metrics,
+        )
+    }
+
+    fn watch(watches: &mut Watches, watcher: &[u8], names: &[&str]) -> Value {
+        let names = names.iter().map(|n| n.to_string()).collect();
+        let reply = watches.command(watcher, WatchCommand::Watch(names));
+        assert_eq!((&reply[0][..], &reply[1][..]), (watcher, WATCH_ID));
+        serde_json::from_slice(&reply[2]).unwrap()
+    }
+
+    fn connected(role: PeerRole, identity: &[u8]) -> BrokerEvent {
+        BrokerEvent::Connected {
+            role,
+            identity: identity.to_vec(),
+        }
+    }
+
+    // Drain everything queued, as (watcher, event name, identity).
+    fn delivered(watches: &mut Watches) -> Vec<(String, String, String)> {
+        let mut out = Vec::new();
+        watches.flush(|message| {
+            let event: Value = serde_json::from_slice(&message[2]).unwrap();
+            out.push((
+                String::from_utf8_lossy(&message[0]).into_owned(),
+                event["event"].as_str().unwrap().to_string(),
+                event["identity"].as_str().unwrap().to_string(),
+            ));
+            Ok(())
+        });
+        out.sort();
+        out
+    }
+
+    fn entry(watcher: &str, event: &str, identity: &str) -> (String, String, String) {
+        (watcher.to_string(), event.to_string(), identity.to_string())
+    }
+
+    #[test]
+    fn commands_parse_and_leave_other_frames_alone() {
+        assert_eq!(
+            WatchCommand::parse(br#"{"cmd":"watch","names":["a","*"]}"#),
+            Some(Ok(WatchCommand::Watch(vec!["a".into(), "*".into()])))
+        );
+        assert_eq!(
+            WatchCommand::parse(br#"{"cmd":"unwatch"}"#),
+            Some(Ok(WatchCommand::Unwatch(None)))
+        );
+        assert!(matches!(
+            WatchCommand::parse(br#"{"cmd":"watch","names":[]}"#),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            WatchCommand::parse(br#"{"cmd":"watch","names":["a",""]}"#),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            WatchCommand::parse(br#"{"cmd":"stare"}"#),
+            Some(Err(_))
+        ));
+        assert_eq!(WatchCommand::parse(b"hello"), None);
+        assert_eq!(WatchCommand::parse(br#"{"price": 3}"#), None);
+    }
+
+    #[test]
+    fn names_exact_prefix_and_wildcard() {
+        let metrics = Registry::new();
+        let mut watches = watches(16, &metrics);
+        assert_eq!(
+            watch(&mut watches, b"ui-1", &["pricing-svc"]),
+            json!({"watching": ["pricing-svc"]})
+        );
+        watch(&mut watches, b"ui-2", &["quotes.*"]);
+        watch(&mut watches, b"ui-3", &["*"]);
+
+        watches.notify(&connected(PeerRole::Worker, b"pricing-svc"));
+        watches.notify(&connected(PeerRole::Direct, b"quotes.eu"));
+        watches.notify(&BrokerEvent::Disconnected {
+            identity: b"pricing-svc".to_vec(),
+        });
+        assert_eq!(
+            delivered(&mut watches),
+            vec![
+                entry("ui-1", "worker.connected", "pricing-svc"),
+                entry("ui-1", "worker.disconnected", "pricing-svc"),
+                entry("ui-2", "direct.connected", "quotes.eu"),
+                entry("ui-3", "direct.connected", "quotes.eu"),
+                entry("ui-3", "worker.connected", "pricing-svc"),
+                entry("ui-3", "worker.disconnected", "pricing-svc"),
+            ]
+        );
+        assert_eq!(
+            metrics
+                .counter("corky_watch_notifications_total", &[])
+                .get(),
+            6
+        );
+        // Other events are not presence events.
+        watches.notify(&BrokerEvent::RequestTimeout {
+            client: b"pricing-svc".to_vec(),
+        });
+        assert!(delivered(&mut watches).is_empty());
+    }
+
+    #[test]
+    fn watchers_are_not_told_about_themselves() {
+        let metrics = Registry::new();
+        let mut watches = watches(16, &metrics);
+        watch(&mut watches, b"ui", &["*"]);
+        watches.notify(&connected(PeerRole::Direct, b"ui"));
+        // The same identity on another socket is another peer.
+        watches.notify(&connected(PeerRole::Client, b"ui"));
+        assert_eq!(
+            delivered(&mut watches),
+            vec![entry("ui", "client.connected", "ui")]
+        );
+    }
+
+    #[test]
+    fn unwatch_drops_names_and_then_the_watcher() {
+        let metrics = Registry::new();
+        let mut watches = watches(16, &metrics);
+        watch(&mut watches, b"ui", &["a", "b"]);
+        assert_eq!(
+            watch(&mut watches, b"ui", &["c", "a"]),
+            json!({"watching": ["a", "b", "c"]})
+        );
+        let reply = watches.command(b"ui", WatchCommand::Unwatch(Some(vec!["a".into()])));
+        let reply: Value = serde_json::from_slice(&reply[2]).unwrap();
+        assert_eq!(reply, json!({"watching": ["b", "c"]}));
+        assert_eq!(metrics.gauge("corky_watch_watchers", &[]).get(), 1);
+        watches.command(b"ui", WatchCommand::Unwatch(None));
+        assert!(watches.is_empty());
+        assert_eq!(metrics.gauge("corky_watch_watchers", &[]).get(), 0);
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest() {
+        let metrics = Registry::new();
+        let mut watches = watches(2, &metrics);
+        watch(&mut watches, b"ui", &["*"]);
+        for peer in [&b"w1"[..], b"w2", b"w3"] {
+            watches.notify(&connected(PeerRole::Worker, peer));
+        }
+        // The watcher is not reading: nothing leaves the queue.
+        watches.flush(|_| Err(zmq::Error::EAGAIN));
+        assert_eq!(
+            delivered(&mut watches),
+            vec![
+                entry("ui", "worker.connected", "w2"),
+                entry("ui", "worker.connected", "w3"),
+            ]
+        );
+        assert_eq!(metrics.counter("corky_watch_dropped_total", &[]).get(), 1);
+    }
+
+    #[test]
+    fn gone_watchers_are_cleaned_up() {
+        let metrics = Registry::new();
+        let mut watches = watches(16, &metrics);
+        watch(&mut watches, b"ui-1", &["*"]);
+        watch(&mut watches, b"ui-2", &["*"]);
+        watches.notify(&connected(PeerRole::Worker, b"w"));
+        watches.flush(|message| match &message[0][..] {
+            b"ui-1" => Err(zmq::Error::EHOSTUNREACH),
+            _ => Ok(()),
+        });
+        assert_eq!(watches.len(), 1);
+
+        // Forgotten by the peer table.
+        watches.forget(b"ui-2");
+        assert!(watches.is_empty());
+        assert_eq!(metrics.gauge("corky_watch_watchers", &[]).get(), 0);
+    }
+
+    #[test]
+    fn watchers_are_capped() {
+        let metrics = Registry::new();
+        let config = WatchConfig {
+            enabled: true,
+            max_watchers: 2,
+            ..WatchConfig::default()
+        };
+        let mut watches = Watches::new(&config, &metrics);
+        watch(&mut watches, b"ui-1", &["a"]);
+        watch(&mut watches, b"ui-2", &["a"]);
+        let reply = watches.command(b"ui-3", WatchCommand::Watch(vec!["a".into()]));
+        let reply: Value = serde_json::from_slice(&reply[2]).unwrap();
+        assert_eq!(reply["error"]["message"], "at most 2 watchers");
+        assert_eq!(watches.len(), 2);
+
+        // A watcher already in can still add names, and leaving makes room.
+        assert_eq!(
+            watch(&mut watches, b"ui-1", &["b"]),
+            json!({"watching": ["a", "b"]})
+        );
+        watches.forget(b"ui-2");
+        watch(&mut watches, b"ui-3", &["a"]);
+        assert_eq!(watches.len(), 2);
+    }
+}
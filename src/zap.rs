@@ -0,0 +1,343 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, info, warn};
+
+use crate::broker::WORKER_ROUTER;
+use crate::config::AuthConfig;
+use crate::limits::{socket_name, ConnectionLimits, ADDRESS_LIMIT, SOCKET_LIMIT};
+use crate::quiesce::{DRAINING_STATUS, DRAINING_TEXT};
+use crate::runtime::Runtime;
+
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+// Broker sockets use "corky-broker/<socket name>" as their domain.
+pub const BROKER_ZAP_DOMAIN: &str = "corky-broker";
+const ZAP_VERSION: &[u8] = b"1.0";
+const ZAP_POLL_TIMEOUT_MS: i64 = 100; // shutdown check interval
+
+//
+// ------------------------------ ZAP handler ----------------------------------
+//
+// Authenticates PLAIN connections to the sockets that set `plain_server`
+// (the proxy's XSUB and XPUB) against `[auth] users`. libzmq asks the handler
+// on the context's well-known inproc endpoint during each handshake; the
+// accepted username becomes the connection's User-Id, which crate::acl uses
+// as the principal. The broker's sockets consult it with the NULL mechanism
+// (CURVE on client endpoints that enable it) so that new connections can be refused while the service drains
+// (crate::quiesce, workers excepted) or once a connection cap is reached
+// (crate::limits). A CURVE client's User-Id is its public key in Z85, the
+// principal crate::ingress reports to workers. inproc connections never
+// authenticate.
+
+pub fn broker_domain(socket: &str) -> String {
+    format!("{}/{}", BROKER_ZAP_DOMAIN, socket)
+}
+
+fn broker_socket(domain: &[u8]) -> Option<&'static str> {
+    let name = domain
+        .strip_prefix(BROKER_ZAP_DOMAIN.as_bytes())?
+        .strip_prefix(b"/")?;
+    socket_name(std::str::from_utf8(name).ok()?)
+}
+
+// One ZAP reply: status code, status text and the User-Id to attach.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ZapReply {
+    pub status: &'static str,
+    pub text: &'static str,
+    pub user_id: String,
+}
+
+impl ZapReply {
+    fn deny(status: &'static str, text: &'static str) -> Self {
+        Self {
+            status,
+            text,
+            user_id: String::new(),
+        }
+    }
+}
+
+// Decide one request: [version, request_id, domain, address, identity,
+// mechanism, credentials..], after the REP envelope.
+pub fn authenticate(
+    config: &AuthConfig,
+    draining: bool,
+    limits: &ConnectionLimits,
+    request: &[Vec<u8>],
+) -> ZapReply {
+    if request.len() < 6 || request[0] != ZAP_VERSION {
+        return ZapReply::deny("500", "malformed request");
+    }
+    if let Some(socket) = broker_socket(&request[2]) {
+        // libzmq has checked a CURVE client's key exchange by now.
+        if request[5] != b"NULL" && request[5] != b"CURVE" {
+            return ZapReply::deny("400", "unsupported mechanism");
+        }
+        if draining && socket != WORKER_ROUTER {
+            return ZapReply::deny(DRAINING_STATUS, DRAINING_TEXT);
+        }
+        if let Err(reason) = limits.check(socket, &String::from_utf8_lossy(&request[3])) {
+            return ZapReply::deny("400", reason);
+        }
+        let curve_key = match &request[5][..] {
+            b"CURVE" => request.get(6).filter(|key| key.len() == 32),
+            _ => None,
+        };
+        return ZapReply {
+            status: "200",
+            text: "OK",
+            user_id: curve_key
+                .and_then(|key| zmq::z85_encode(key).ok())
+                .unwrap_or_default(),
+        };
+    }
+    if draining {
+        return ZapReply::deny(DRAINING_STATUS, DRAINING_TEXT);
+    }
+    if request[2] != config.zap_domain.as_bytes() {
+        return ZapReply::deny("400", "unknown domain");
+    }
+    match (&request[5][..], &request[6..]) {
+        (b"PLAIN", [username, password]) => {
+            let username = String::from_utf8_lossy(username);
+            match config.users.get(username.as_ref()) {
+                Some(expected) if expected.as_bytes() == &password[..] => ZapReply {
+                    status: "200",
+                    text: "OK",
+                    user_id: username.into_owned(),
+                },
+                _ => ZapReply::deny("400", "invalid username or password"),
+            }
+        }
+        _ => ZapReply::deny("400", "unsupported mechanism"),
+    }
+}
+
+// Runs the handler thread until dropped.
+pub struct ZapHandler {
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ZapHandler {
+    // Binds in the calling thread so the handler exists before any socket
+    // accepts a connection.
+    pub fn start(
+        context: &zmq::Context,
+        config: &AuthConfig,
+        runtime: &Runtime,
+    ) -> Result<Self, zmq::Error> {
+        let socket = context.socket(zmq::REP)?;
+        socket.set_linger(0)?;
+        socket.bind(ZAP_ENDPOINT)?;
+        info!(
+            "(Auth) ZAP handler bound to {} for {} PLAIN users",
+            ZAP_ENDPOINT,
+            config.users.len()
+        );
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (config, shutdown) = (config.clone(), shutdown.clone());
+            let runtime = runtime.clone();
+            thread::Builder::new()
+                .name("zap-thread".to_string())
+                .spawn(move || serve(socket, &config, &runtime, &shutdown))
+                .map_err(|_| zmq::Error::EFAULT)?
+        };
+        Ok(Self {
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ZapHandler {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(socket: zmq::Socket, config: &AuthConfig, runtime: &Runtime, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        match socket.poll(zmq::POLLIN, ZAP_POLL_TIMEOUT_MS) {
+            Ok(0) | Err(zmq::Error::EINTR) => continue,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("(Auth) ZAP handler stopped: {}", e);
+                return;
+            }
+        }
+        let request = match socket.recv_multipart(0) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("(Auth) Failed to read ZAP request: {}", e);
+                continue;
+            }
+        };
+        let draining = runtime.quiesce.is_draining();
+        let reply = authenticate(config, draining, &runtime.limits, &request);
+        if reply.text == DRAINING_TEXT {
+            debug!("(Auth) Refusing connection while draining");
+        } else if reply.text == SOCKET_LIMIT || reply.text == ADDRESS_LIMIT {
+            // Reported by crate::limits, which rate-limits the warning.
+        } else if reply.status != "200" {
+            let address = request
+                .get(3)
+                .map(|a| String::from_utf8_lossy(a).into_owned());
+            warn!(
+                "(Auth) Rejected connection from {}: {}",
+                address.unwrap_or_default(),
+                reply.text
+            );
+        }
+        let request_id = request.get(1).cloned().unwrap_or_default();
+        let frames: [&[u8]; 6] = [
+            ZAP_VERSION,
+            &request_id,
+            reply.status.as_bytes(),
+            reply.text.as_bytes(),
+            reply.user_id.as_bytes(),
+            b"",
+        ];
+        if let Err(e) = socket.send_multipart(frames, 0) {
+            warn!("(Auth) Failed to send ZAP reply: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::broker::CLIENT_ROUTER;
+    use crate::config::{AuthMechanism, LimitsConfig};
+    use crate::metrics::Registry;
+
+    fn config() -> AuthConfig {
+        let mut config = AuthConfig {
+            mechanism: AuthMechanism::Plain,
+            ..AuthConfig::default()
+        };
+        config.users.insert("alice".into(), "s3cret".into());
+        config
+    }
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits::new(&LimitsConfig::default(), &Registry::new())
+    }
+
+    fn request(domain: &str, mechanism: &str, credentials: &[&str]) -> Vec<Vec<u8>> {
+        let mut frames: Vec<Vec<u8>> = vec![
+            ZAP_VERSION.to_vec(),
+            b"1".to_vec(),
+            domain.into(),
+            b"127.0.0.1".to_vec(),
+            Vec::new(),
+            mechanism.into(),
+        ];
+        frames.extend(credentials.iter().map(|c| c.as_bytes().to_vec()));
+        frames
+    }
+
+    #[test]
+    fn plain_users_are_checked() {
+        let config = config();
+        let domain = config.zap_domain.clone();
+        let ok = authenticate(
+            &config,
+            false,
+            &limits(),
+            &request(&domain, "PLAIN", &["alice", "s3cret"]),
+        );
+        assert_eq!((ok.status, ok.user_id.as_str()), ("200", "alice"));
+        let bad = authenticate(
+            &config,
+            false,
+            &limits(),
+            &request(&domain, "PLAIN", &["alice", "guess"]),
+        );
+        assert_eq!((bad.status, bad.user_id.as_str()), ("400", ""));
+        let unknown = authenticate(
+            &config,
+            false,
+            &limits(),
+            &request(&domain, "PLAIN", &["mallory", "x"]),
+        );
+        assert_eq!(unknown.status, "400");
+    }
+
+    #[test]
+    fn other_domains_mechanisms_and_garbage_are_rejected() {
+        let config = config();
+        let domain = config.zap_domain.clone();
+        assert_eq!(
+            authenticate(
+                &config,
+                false,
+                &limits(),
+                &request("other", "PLAIN", &["alice", "s3cret"])
+            )
+            .status,
+            "400"
+        );
+        assert_eq!(
+            authenticate(&config, false, &limits(), &request(&domain, "NULL", &[])).status,
+            "400"
+        );
+        assert_eq!(
+            authenticate(&config, false, &limits(), &[b"2.0".to_vec()]).status,
+            "500"
+        );
+    }
+
+    #[test]
+    fn broker_null_connections_pass_until_draining() {
+        let config = config();
+        let null = request(&broker_domain(CLIENT_ROUTER), "NULL", &[]);
+        assert_eq!(authenticate(&config, false, &limits(), &null).status, "200");
+        let curve = request(&broker_domain(CLIENT_ROUTER), "CURVE", &["key"]);
+        assert_eq!(
+            authenticate(&config, false, &limits(), &curve).status,
+            "200"
+        );
+        let mut curve = request(&broker_domain(CLIENT_ROUTER), "CURVE", &[]);
+        curve.push(vec![0; 32]);
+        let key = authenticate(&config, false, &limits(), &curve).user_id;
+        assert_eq!(key, zmq::z85_encode(&[0; 32]).unwrap());
+        let refused = authenticate(&config, true, &limits(), &null);
+        assert_eq!((refused.status, refused.text), ("400", "draining"));
+        let domain = config.zap_domain.clone();
+        let plain = request(&domain, "PLAIN", &["alice", "s3cret"]);
+        assert_eq!(authenticate(&config, true, &limits(), &plain).status, "400");
+        // Workers may still join a draining broker.
+        let worker = request(&broker_domain(WORKER_ROUTER), "NULL", &[]);
+        assert_eq!(
+            authenticate(&config, true, &limits(), &worker).status,
+            "200"
+        );
+    }
+
+    #[test]
+    fn broker_connections_over_a_cap_are_refused() {
+        let config = config();
+        let limits = limits();
+        limits.set_socket(CLIENT_ROUTER, 1);
+        let null = request(&broker_domain(CLIENT_ROUTER), "NULL", &[]);
+        // The connection being authenticated is already counted.
+        limits.accepted(CLIENT_ROUTER, "127.0.0.1");
+        assert_eq!(authenticate(&config, false, &limits, &null).status, "200");
+        limits.accepted(CLIENT_ROUTER, "127.0.0.1");
+        let refused = authenticate(&config, false, &limits, &null);
+        assert_eq!((refused.status, refused.text), ("400", SOCKET_LIMIT));
+        let unknown = request(&format!("{}/nope", BROKER_ZAP_DOMAIN), "NULL", &[]);
+        assert_eq!(
+            authenticate(&config, false, &limits, &unknown).text,
+            "unknown domain"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Topic ACLs in the proxy over TCP with PLAIN authentication: a principal
+// sees nothing on a topic it may not subscribe to even while another
+// principal subscribes to it, and publications outside a principal's
+// prefixes are dropped. inproc never authenticates, hence the TCP endpoints.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::config::{AuthMechanism, PrincipalAcl};
+use corky_zmq::zap::ZapHandler;
+
+fn free_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp://{}", listener.local_addr().unwrap())
+}
+
+fn entry(subscribe: &[&str], publish: &[&str]) -> PrincipalAcl {
+    PrincipalAcl {
+        subscribe: subscribe.iter().map(|s| s.to_string()).collect(),
+        publish: publish.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+struct Secured {
+    proxy: ProxyHarness,
+    _zap: ZapHandler,
+}
+
+fn start() -> Secured {
+    let proxy = ProxyHarness::start(|cfg| {
+        cfg.network.proxy_xsub_endpoint = free_endpoint();
+        cfg.network.proxy_xpub_endpoint = free_endpoint();
+        cfg.auth.mechanism = AuthMechanism::Plain;
+        cfg.auth.users = BTreeMap::from([
+            ("alice".to_string(), "alice-pw".to_string()),
+            ("bob".to_string(), "bob-pw".to_string()),
+        ]);
+        cfg.acl.enabled = true;
+        cfg.acl
+            .principals
+            .insert("alice".into(), entry(&["alice.", "public."], &["alice."]));
+        cfg.acl
+            .principals
+            .insert("bob".into(), entry(&["public."], &["public."]));
+    });
+    let zap = ZapHandler::start(&proxy.context, &proxy.config.auth, &proxy.runtime).unwrap();
+    Secured { proxy, _zap: zap }
+}
+
+fn login(socket: &zmq::Socket, user: &str) {
+    socket.set_plain_username(Some(user)).unwrap();
+    socket
+        .set_plain_password(Some(&format!("{}-pw", user)))
+        .unwrap();
+}
+
+fn publisher(proxy: &ProxyHarness, user: &str) -> zmq::Socket {
+    let socket = proxy.context.socket(zmq::PUB).unwrap();
+    socket.set_linger(0).unwrap();
+    login(&socket, user);
+    socket
+        .connect(&proxy.config.network.proxy_xsub_endpoint)
+        .unwrap();
+    socket
+}
+
+fn subscriber(proxy: &ProxyHarness, user: &str, topic: &[u8]) -> zmq::Socket {
+    let socket = proxy.context.socket(zmq::SUB).unwrap();
+    socket.set_rcvtimeo(500).unwrap();
+    socket.set_linger(0).unwrap();
+    login(&socket, user);
+    socket.set_subscribe(topic).unwrap();
+    socket
+        .connect(&proxy.config.network.proxy_xpub_endpoint)
+        .unwrap();
+    socket
+}
+
+fn denied(proxy: &ProxyHarness, action: &'static str) -> u64 {
+    proxy
+        .runtime
+        .metrics
+        .counter("corky_acl_denied_total", &[("action", action)])
+        .get()
+}
+
+#[test]
+fn denied_subscription_gets_nothing_while_allowed_one_does() {
+    let secured = start();
+    let proxy = &secured.proxy;
+    let alice = subscriber(proxy, "alice", b"alice.");
+    let bob = subscriber(proxy, "bob", b"alice.");
+    let publisher = publisher(proxy, "alice");
+    propagate();
+    assert_eq!(denied(proxy, "subscribe"), 1);
+
+    publisher
+        .send_multipart([&b"alice.orders"[..], b"{}"], 0)
+        .unwrap();
+    assert_eq!(
+        alice.recv_multipart(0).unwrap(),
+        vec![b"alice.orders".to_vec(), b"{}".to_vec()]
+    );
+    assert!(bob.recv_multipart(0).is_err(), "bob may not see alice.");
+}
+
+#[test]
+fn publications_outside_the_principals_prefixes_are_dropped() {
+    let secured = start();
+    let proxy = &secured.proxy;
+    let alice = subscriber(proxy, "alice", b"");
+    let mallory = publisher(proxy, "bob");
+    propagate();
+    // Subscribing to everything is not something alice is allowed either.
+    assert_eq!(denied(proxy, "subscribe"), 1);
+    alice.set_subscribe(b"alice.").unwrap();
+    alice.set_subscribe(b"public.").unwrap();
+    propagate();
+
+    mallory
+        .send_multipart([&b"alice.fake"[..], b"{}"], 0)
+        .unwrap();
+    mallory
+        .send_multipart([&b"public.news"[..], b"{}"], 0)
+        .unwrap();
+    assert_eq!(
+        alice.recv_multipart(0).unwrap(),
+        vec![b"public.news".to_vec(), b"{}".to_vec()]
+    );
+    assert!(alice.recv_multipart(0).is_err());
+    assert_eq!(denied(proxy, "publish"), 1);
+}
+
+#[test]
+fn wrong_password_never_connects() {
+    let secured = start();
+    let proxy = &secured.proxy;
+    let intruder = proxy.context.socket(zmq::SUB).unwrap();
+    intruder.set_rcvtimeo(500).unwrap();
+    intruder.set_linger(0).unwrap();
+    intruder.set_plain_username(Some("alice")).unwrap();
+    intruder.set_plain_password(Some("guess")).unwrap();
+    intruder.set_subscribe(b"public.").unwrap();
+    intruder
+        .connect(&proxy.config.network.proxy_xpub_endpoint)
+        .unwrap();
+    let publisher = publisher(proxy, "bob");
+    propagate();
+
+    publisher
+        .send_multipart([&b"public.news"[..], b"{}"], 0)
+        .unwrap();
+    assert!(intruder.recv_multipart(0).is_err());
+}
@@ -0,0 +1,42 @@
+// Runs every bench input through the formatter once so the bench fixtures and
+// support code stay compiling (and sane) under a plain `cargo test`.
+
+use corky_zmq::format::{crop_value, format_message, format_part, Envelope, RenderStyle};
+
+#[path = "../benches/support/mod.rs"]
+mod support;
+
+#[test]
+fn format_part_handles_bench_payloads() {
+    let rows = support::json_rows_1mb();
+    assert!(rows.len() >= 1024 * 1024);
+    assert!(
+        format_part(&rows, RenderStyle::Compact).contains("more"),
+        "1MB row list should be cropped"
+    );
+
+    let blob = support::binary_blob_1mb();
+    assert!(format_part(&blob, RenderStyle::Compact).starts_with("[1048576 bytes:"));
+
+    let object = format_part(&support::object_2kb(), RenderStyle::Pretty);
+    assert!(object.contains("\"symbol\": \"ETHUSD\""));
+}
+
+#[test]
+fn crop_value_handles_deep_and_wide() {
+    let deep = support::deep_value(64);
+    assert!(crop_value(&deep, 0).is_object());
+
+    let wide = crop_value(&support::wide_value(200, 500), 0);
+    let keys = wide.as_object().map(|m| m.len()).unwrap_or(0);
+    assert!(keys < 200, "wide top-level object should be trimmed");
+}
+
+#[test]
+fn format_message_renders_envelope() {
+    let envelope = support::broker_envelope();
+    let rendered = format_message(&envelope, Envelope::Routed, RenderStyle::Compact);
+    assert_eq!(rendered.matches(" | ").count(), 3);
+    assert!(!rendered.contains('\n'), "{}", rendered);
+    assert!(rendered.starts_with("[#0(5B): id:"), "{}", rendered);
+}
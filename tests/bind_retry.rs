@@ -0,0 +1,79 @@
+// Startup binds over TCP: a port still held by another socket is retried
+// until it is released, while fail-fast settings and errors other than
+// EADDRINUSE give up at once.
+
+mod common;
+
+use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::BrokerHarness;
+use corky_zmq::broker::run_broker;
+use corky_zmq::config::Config;
+use corky_zmq::runtime::Runtime;
+
+fn held_port() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+    (listener, endpoint)
+}
+
+fn run_once(customize: impl FnOnce(&mut Config)) -> Result<(), zmq::Error> {
+    let mut config = Config::default();
+    config.network.client_to_client_endpoint = "inproc://bind-retry-direct".into();
+    config.network.client_facing_endpoint = "inproc://bind-retry-client".into();
+    config.network.worker_facing_endpoint = "inproc://bind-retry-worker".into();
+    customize(&mut config);
+    let runtime = Runtime::new(&config);
+    // Set already, so a broker that does start returns at once.
+    let shutdown = Arc::new(AtomicBool::new(true));
+    run_broker(&zmq::Context::new(), &Arc::new(config), &runtime, &shutdown)
+}
+
+#[test]
+fn broker_starts_once_the_port_is_released() {
+    let (listener, endpoint) = held_port();
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        drop(listener);
+    });
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.network.client_facing_endpoint = endpoint;
+        cfg.retry.bind.deadline_ms = 5000;
+    });
+    release.join().unwrap();
+
+    let worker = broker.worker(b"late");
+    let client = broker.client(b"late");
+    client.send("request", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+}
+
+#[test]
+fn fail_fast_and_other_errors_do_not_retry() {
+    let (_listener, endpoint) = held_port();
+    let started = Instant::now();
+    let result = run_once(|cfg| {
+        cfg.network.client_facing_endpoint = endpoint.clone();
+        cfg.retry.bind.deadline_ms = 0;
+    });
+    assert_eq!(result, Err(zmq::Error::EADDRINUSE));
+
+    let result = run_once(|cfg| {
+        cfg.network.client_facing_endpoint = "tcp://no-such-interface:5559".into();
+    });
+    assert!(matches!(result, Err(e) if e != zmq::Error::EADDRINUSE));
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // A port that is never released gives up after the deadline.
+    let started = Instant::now();
+    let result = run_once(|cfg| {
+        cfg.network.client_facing_endpoint = endpoint;
+        cfg.retry.bind.deadline_ms = 300;
+    });
+    assert_eq!(result, Err(zmq::Error::EADDRINUSE));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
@@ -0,0 +1,34 @@
+// [proxy] capture: copies of what the proxy forwards, subscriptions upstream
+// and publications downstream, on a PUB socket of their own.
+
+mod common;
+
+use common::{propagate, ProxyHarness};
+
+#[test]
+fn forwarded_traffic_is_copied_to_the_capture_socket() {
+    let proxy = ProxyHarness::start(|cfg| cfg.proxy.capture = true);
+    let capture = proxy.context.socket(zmq::SUB).unwrap();
+    capture.set_rcvtimeo(5000).unwrap();
+    capture.set_linger(0).unwrap();
+    capture.set_subscribe(b"").unwrap();
+    capture
+        .connect(&proxy.config.proxy.capture_endpoint)
+        .unwrap();
+    propagate();
+
+    let publisher = proxy.publisher();
+    let subscriber = proxy.subscriber(b"ticker.");
+    propagate();
+    assert_eq!(
+        capture.recv_multipart(0).unwrap(),
+        [b"\x01ticker.".to_vec()]
+    );
+
+    publisher
+        .send_multipart(["ticker.BTCUSD", "{\"px\": 64000.5}"], 0)
+        .unwrap();
+    let received = subscriber.recv_multipart(0).unwrap();
+    assert_eq!(received, [&b"ticker.BTCUSD"[..], b"{\"px\": 64000.5}"]);
+    assert_eq!(capture.recv_multipart(0).unwrap(), received);
+}
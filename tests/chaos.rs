@@ -0,0 +1,117 @@
+// Fault injection through the broker, seeded so every run sees the same
+// faults: each fault type shows up on the wire for requests and replies, and
+// zero probabilities leave traffic untouched.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{settle, BrokerHarness};
+use corky_zmq::admin::handle_command;
+use corky_zmq::chaos::CHAOS_FRAME;
+use corky_zmq::config::FaultRates;
+
+fn broker(requests: FaultRates, replies: FaultRates) -> BrokerHarness {
+    BrokerHarness::start(|cfg| {
+        cfg.chaos.enabled = true;
+        cfg.chaos.seed = 7;
+        cfg.chaos.requests = requests;
+        cfg.chaos.replies = replies;
+    })
+}
+
+fn faults(broker: &BrokerHarness, direction: &'static str, fault: &'static str) -> u64 {
+    broker
+        .runtime
+        .metrics
+        .counter(
+            "corky_chaos_faults_total",
+            &[("direction", direction), ("fault", fault)],
+        )
+        .get()
+}
+
+fn certain(apply: impl FnOnce(&mut FaultRates)) -> FaultRates {
+    let mut rates = FaultRates::default();
+    apply(&mut rates);
+    rates
+}
+
+// Requests reach the worker with the client's identity.
+fn pair(broker: &BrokerHarness, identity: &[u8]) -> (zmq::Socket, zmq::Socket) {
+    let worker = broker.worker(identity);
+    let client = broker.client(identity);
+    worker.set_rcvtimeo(300).unwrap();
+    client.set_rcvtimeo(300).unwrap();
+    settle();
+    (client, worker)
+}
+
+#[test]
+fn zero_probabilities_pass_everything_through() {
+    let broker = broker(FaultRates::default(), FaultRates::default());
+    let (client, worker) = pair(&broker, b"alice");
+    for i in 0..50 {
+        let body = format!("request-{}", i);
+        client.send(body.as_str(), 0).unwrap();
+        assert_eq!(worker.recv_multipart(0).unwrap(), vec![body.into_bytes()]);
+    }
+    worker.send_multipart([&b"alice"[..], b"reply"], 0).unwrap();
+    assert_eq!(client.recv_multipart(0).unwrap(), vec![b"reply".to_vec()]);
+    assert!(client.recv_multipart(0).is_err());
+    for direction in ["requests", "replies"] {
+        for fault in ["drop", "duplicate", "malformed", "delay"] {
+            assert_eq!(faults(&broker, direction, fault), 0);
+        }
+    }
+}
+
+#[test]
+fn dropped_and_duplicated_requests() {
+    let broker = broker(certain(|r| r.drop = 1.0), FaultRates::default());
+    let (client, worker) = pair(&broker, b"alice");
+    client.send("lost", 0).unwrap();
+    assert!(worker.recv_multipart(0).is_err());
+    assert_eq!(faults(&broker, "requests", "drop"), 1);
+
+    handle_command(&broker.runtime, "chaos off");
+    handle_command(&broker.runtime, "chaos set requests duplicate 1");
+    client.send("twice", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"twice");
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"twice");
+    assert!(worker.recv_multipart(0).is_err());
+    assert_eq!(faults(&broker, "requests", "duplicate"), 1);
+}
+
+#[test]
+fn malformed_reply_carries_an_extra_frame() {
+    let broker = broker(FaultRates::default(), certain(|r| r.malformed = 1.0));
+    let (client, worker) = pair(&broker, b"alice");
+    worker.send_multipart([&b"alice"[..], b"reply"], 0).unwrap();
+    assert_eq!(
+        client.recv_multipart(0).unwrap(),
+        vec![b"reply".to_vec(), CHAOS_FRAME.to_vec()]
+    );
+    assert_eq!(faults(&broker, "replies", "malformed"), 1);
+}
+
+#[test]
+fn delayed_reply_arrives_within_the_range() {
+    let broker = broker(
+        FaultRates::default(),
+        certain(|r| {
+            r.delay = 1.0;
+            r.delay_min_ms = 200;
+            r.delay_max_ms = 300;
+        }),
+    );
+    let (client, worker) = pair(&broker, b"alice");
+    client.set_rcvtimeo(2000).unwrap();
+    let sent = Instant::now();
+    worker.send_multipart([&b"alice"[..], b"late"], 0).unwrap();
+    assert_eq!(client.recv_bytes(0).unwrap(), b"late");
+    let waited = sent.elapsed();
+    assert!(waited >= Duration::from_millis(200), "{:?}", waited);
+    assert!(waited < Duration::from_millis(1000), "{:?}", waited);
+    assert_eq!(faults(&broker, "replies", "delay"), 1);
+}
@@ -0,0 +1,157 @@
+// `corky-zmq check-config` against files on disk: the summary it prints for a
+// good config, the problems it lists for a bad one, --quiet, and the endpoint
+// flags and included files laid over the file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn scratch(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("corky-check-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn check_config(path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_corky-zmq"))
+        .arg("check-config")
+        .arg("--config")
+        .arg(path)
+        .args(args)
+        .env_remove("CORKY_PROFILE")
+        .env_remove("CORKY_CLIENT_FACING_ENDPOINT")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn a_good_config_lists_its_endpoints() {
+    let path = scratch(
+        "good.toml",
+        "[network]\nclient_facing_endpoint = \"tcp://127.0.0.1:7559\"\n\
+         [profile.dev.network]\nworker_facing_endpoint = \"ipc:///tmp/corky-check-w\"\n",
+    );
+    let out = check_config(&path, &["--profile", "dev"]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.starts_with(&format!("config OK: {} (profile dev)\n", path.display())),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("  network.client_facing_endpoint = tcp://127.0.0.1:7559 (file)"));
+    assert!(stdout.contains("network.worker_facing_endpoint = ipc:///tmp/corky-check-w"));
+
+    let quiet = check_config(&path, &["--quiet"]);
+    assert!(quiet.status.success(), "{:?}", quiet);
+    assert!(quiet.stdout.is_empty() && quiet.stderr.is_empty());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn every_problem_is_printed_and_the_exit_code_is_nonzero() {
+    let path = scratch(
+        "bad.json",
+        r#"{
+            "network": {
+                "client_facing_endpoint": "tcp://*:65536",
+                "worker_facing_endpoint": "udp://*:5560",
+                "socket_options": {"sndhwm": -1}
+            },
+            "broker": {"poll_timeout_ms": 0}
+        }"#,
+    );
+    let out = check_config(&path, &["--quiet"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    for key in [
+        "network.client_facing_endpoint",
+        "network.worker_facing_endpoint",
+        "network.socket_options.sndhwm",
+        "broker.poll_timeout_ms",
+    ] {
+        assert!(stderr.contains(key), "{} missing from {}", key, stderr);
+    }
+    fs::remove_file(&path).unwrap();
+
+    let missing = check_config(Path::new("/nonexistent/corky.toml"), &[]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("not found"));
+}
+
+#[test]
+fn endpoint_flags_win_and_clash_like_the_file() {
+    let path = scratch(
+        "flags.toml",
+        "[network]\nclient_facing_endpoint = \"tcp://127.0.0.1:7559\"\n",
+    );
+    let out = check_config(&path, &["--client-facing-endpoint", "tcp://127.0.0.1:7601"]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        stdout.contains("  network.client_facing_endpoint = tcp://127.0.0.1:7601 (cli)"),
+        "{}",
+        stdout
+    );
+
+    let clash = check_config(
+        &path,
+        &[
+            "--client-facing-endpoint=tcp://*:7601",
+            "--worker-facing-endpoint=tcp://127.0.0.1:7601",
+        ],
+    );
+    assert_eq!(clash.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&clash.stderr);
+    assert!(stderr.contains("binds the same address as"), "{}", stderr);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn included_files_are_listed_in_merge_order() {
+    let included = scratch(
+        "included.toml",
+        "[network]\nworker_facing_endpoint = \"tcp://127.0.0.1:7603\"\n",
+    );
+    let path = scratch(
+        "includes.toml",
+        &format!(
+            "include = [{:?}]\n[network]\nworker_facing_endpoint = \"tcp://127.0.0.1:7602\"\n",
+            included.file_name().unwrap()
+        ),
+    );
+    let out = check_config(&path, &[]);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let listed = format!(
+        "  files, merged in this order:\n    1. {}\n    2. {}\n",
+        path.display(),
+        included.display()
+    );
+    assert!(stdout.contains(&listed), "{}", stdout);
+    assert!(stdout.contains("worker_facing_endpoint = tcp://127.0.0.1:7603 (file)"));
+
+    let printed = Command::new(env!("CARGO_BIN_EXE_corky-zmq"))
+        .arg("print-config")
+        .arg("--config")
+        .arg(&path)
+        .env_remove("CORKY_PROFILE")
+        .env_remove("CORKY_WORKER_FACING_ENDPOINT")
+        .output()
+        .unwrap();
+    assert!(printed.status.success(), "{:?}", printed);
+    let stdout = String::from_utf8_lossy(&printed.stdout);
+    let origin = format!(
+        "worker_facing_endpoint = \"tcp://127.0.0.1:7603\"  # include {}",
+        included.display()
+    );
+    assert!(stdout.contains(&origin), "{}", stdout);
+    assert!(
+        stdout.contains("# Files merged in this order: "),
+        "{}",
+        stdout
+    );
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&included).unwrap();
+}
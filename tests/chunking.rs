@@ -0,0 +1,122 @@
+use std::thread;
+
+use corky_zmq::chunk::{
+    recv_chunked, send_chunked, ChunkAssembler, ChunkError, ChunkHeader, ChunkOptions,
+};
+
+mod common;
+use common::{settle, BrokerHarness};
+
+const MB: usize = 1024 * 1024;
+
+fn payload(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[test]
+fn fifty_megabytes_round_trip_in_one_megabyte_chunks() {
+    let broker = BrokerHarness::start(|_| {});
+    // Client and worker share an identity: the broker routes client traffic
+    // to the worker with the same id and worker replies back by client id.
+    let client = broker.client(b"peer");
+    let worker = broker.worker(b"peer");
+    settle();
+
+    let options = ChunkOptions {
+        threshold: MB,
+        chunk_size: MB,
+    };
+    let request = payload(50 * MB, 7);
+
+    let echo = thread::spawn(move || {
+        let mut assembler = ChunkAssembler::new();
+        loop {
+            let delivery = recv_chunked(&worker, &mut assembler).expect("worker recv");
+            // Skip the broker's acknowledgement echoes of our own replies.
+            if delivery.envelope.is_empty() {
+                send_chunked(&worker, &[b"peer"], &delivery.payload, options).unwrap();
+                return delivery.payload.len();
+            }
+        }
+    });
+
+    send_chunked(&client, &[], &request, options).unwrap();
+    let mut assembler = ChunkAssembler::new();
+    let reply = recv_chunked(&client, &mut assembler).expect("client recv");
+
+    assert_eq!(echo.join().unwrap(), request.len());
+    assert!(reply.envelope.is_empty());
+    assert!(reply.payload == request, "payload corrupted in transit");
+    assert_eq!(assembler.pending(), 0);
+}
+
+#[test]
+fn interrupted_transfer_times_out_with_error_frame() {
+    let broker = BrokerHarness::start(|c| c.broker.chunk_timeout_ms = 200);
+    let sender = broker.direct_peer(b"sender");
+    let receiver = broker.direct_peer(b"receiver");
+    settle();
+
+    // First of three chunks, then the sender goes quiet.
+    let header = ChunkHeader {
+        transfer_id: 42,
+        index: 0,
+        total: 3,
+    };
+    sender
+        .send_multipart([&b"receiver"[..], &header.encode(), b"part-0"], 0)
+        .unwrap();
+
+    let mut assembler = ChunkAssembler::new();
+    match recv_chunked(&receiver, &mut assembler) {
+        Err(ChunkError::Aborted {
+            transfer_id,
+            reason,
+        }) => {
+            assert_eq!(transfer_id, 42);
+            assert!(reason.contains("1/3"), "reason: {reason}");
+        }
+        other => panic!("expected an aborted transfer, got {other:?}"),
+    }
+    assert_eq!(assembler.pending(), 0, "aborted transfer state released");
+}
+
+#[test]
+fn interleaved_transfers_from_two_senders_stay_separate() {
+    let broker = BrokerHarness::start(|_| {});
+    let alice = broker.direct_peer(b"alice");
+    let bob = broker.direct_peer(b"bob");
+    let carol = broker.direct_peer(b"carol");
+    settle();
+
+    let a = payload(8 * 1024, 1);
+    let b = payload(8 * 1024, 2);
+    let total = 8;
+    // Same transfer id on purpose: transfers are keyed per sender.
+    for index in 0..total {
+        for (socket, data) in [(&alice, &a), (&bob, &b)] {
+            let header = ChunkHeader {
+                transfer_id: 1,
+                index,
+                total,
+            };
+            let chunk = &data[index as usize * 1024..(index as usize + 1) * 1024];
+            socket
+                .send_multipart([&b"carol"[..], &header.encode(), chunk], 0)
+                .unwrap();
+        }
+    }
+
+    let mut assembler = ChunkAssembler::new();
+    let mut got = Vec::new();
+    for _ in 0..2 {
+        got.push(recv_chunked(&carol, &mut assembler).unwrap());
+    }
+    got.sort_by(|x, y| x.envelope.cmp(&y.envelope));
+    assert_eq!(got[0].envelope, vec![b"alice".to_vec()]);
+    assert_eq!(got[0].payload, a);
+    assert_eq!(got[1].envelope, vec![b"bob".to_vec()]);
+    assert_eq!(got[1].payload, b);
+}
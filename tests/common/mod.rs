@@ -0,0 +1,253 @@
+// Shared harness for integration tests: runs the broker on inproc endpoints
+// in a background thread and stops it on drop. See `tcp` for the same over
+// real sockets.
+
+#![allow(dead_code)]
+
+pub mod tcp;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use corky_zmq::broker::run_broker;
+use corky_zmq::config::Config;
+use corky_zmq::hello::{handshake, Negotiated};
+use corky_zmq::proxy::run_proxy;
+use corky_zmq::runtime::Runtime;
+use corky_zmq::store;
+
+pub struct BrokerHarness {
+    pub context: zmq::Context,
+    pub config: Arc<Config>,
+    pub runtime: Runtime,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+// Unique inproc endpoint names per harness within one test binary.
+fn next_tag() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+impl BrokerHarness {
+    pub fn start(customize: impl FnOnce(&mut Config)) -> Self {
+        Self::start_in(zmq::Context::new(), customize)
+    }
+
+    // Like `start`, on a shared context so several brokers can talk inproc.
+    pub fn start_in(context: zmq::Context, customize: impl FnOnce(&mut Config)) -> Self {
+        let tag = next_tag();
+        let mut config = Config::default();
+        config.network.client_to_client_endpoint = format!("inproc://test-{tag}-direct");
+        config.network.client_facing_endpoint = format!("inproc://test-{tag}-client");
+        config.network.worker_facing_endpoint = format!("inproc://test-{tag}-worker");
+        customize(&mut config);
+        Self::launch(context, config)
+    }
+
+    fn launch(context: zmq::Context, config: Config) -> Self {
+        let mut runtime = Runtime::new(&config);
+        runtime.store = store::open(&config.storage).expect("storage");
+        let config = Arc::new(config);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (context, config, shutdown) = (context.clone(), config.clone(), shutdown.clone());
+            let runtime = runtime.clone();
+            thread::spawn(move || {
+                run_broker(&context, &config, &runtime, &shutdown).expect("broker failed")
+            })
+        };
+        // inproc requires the bind to happen before connect.
+        thread::sleep(Duration::from_millis(100));
+        Self {
+            context,
+            config,
+            runtime,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    // False once the broker thread has ended, e.g. because a bind failed.
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    // Stop the broker and start a fresh one, with new runtime state, on the
+    // same endpoints and context, as a restarted process would.
+    pub fn restart(self) -> Self {
+        let context = self.context.clone();
+        let config = restart_config(&self.config);
+        drop(self);
+        Self::launch(context, config)
+    }
+
+    // DEALER with a fixed identity, connected to `endpoint`.
+    pub fn dealer(&self, identity: &[u8], endpoint: &str) -> zmq::Socket {
+        let socket = self.context.socket(zmq::DEALER).unwrap();
+        socket.set_identity(identity).unwrap();
+        socket.set_rcvtimeo(5000).unwrap();
+        socket.set_linger(0).unwrap();
+        socket.connect(endpoint).unwrap();
+        socket
+    }
+
+    pub fn direct_peer(&self, identity: &[u8]) -> zmq::Socket {
+        self.dealer(identity, &self.config.network.client_to_client_endpoint)
+    }
+
+    // The broker relays a client's request to the worker whose identity is
+    // the client's, so a test pairs `client(id)` with `worker(id)`.
+    pub fn client(&self, identity: &[u8]) -> zmq::Socket {
+        self.dealer(identity, &self.config.network.client_facing_endpoint)
+    }
+
+    // A client that says hello on connect, as client libraries do.
+    pub fn hello_client(
+        &self,
+        identity: &[u8],
+        capabilities: &[&str],
+    ) -> (zmq::Socket, Negotiated) {
+        let client = self.client(identity);
+        let negotiated = handshake(&client, capabilities).expect("hello failed");
+        (client, negotiated)
+    }
+
+    pub fn worker(&self, identity: &[u8]) -> zmq::Socket {
+        self.dealer(identity, &self.config.network.worker_facing_endpoint)
+    }
+}
+
+impl Drop for BrokerHarness {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Runs the XSUB/XPUB proxy on inproc endpoints; stopped with TERMINATE on
+// drop.
+pub struct ProxyHarness {
+    pub context: zmq::Context,
+    pub config: Arc<Config>,
+    pub runtime: Runtime,
+    control_endpoint: String,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ProxyHarness {
+    pub fn start(customize: impl FnOnce(&mut Config)) -> Self {
+        Self::start_in(zmq::Context::new(), customize)
+    }
+
+    // Like BrokerHarness::start_in.
+    pub fn start_in(context: zmq::Context, customize: impl FnOnce(&mut Config)) -> Self {
+        let tag = next_tag();
+        let mut config = Config::default();
+        config.network.proxy_xsub_endpoint = format!("inproc://test-{tag}-xsub");
+        config.network.proxy_xpub_endpoint = format!("inproc://test-{tag}-xpub");
+        config.state.snapshot_endpoint = format!("inproc://test-{tag}-snapshot");
+        config.proxy.sample_endpoint = format!("inproc://test-{tag}-sample");
+        config.proxy.capture_endpoint = format!("inproc://test-{tag}-capture");
+        customize(&mut config);
+        let control_endpoint = format!("inproc://test-{tag}-proxy-control");
+        Self::launch(context, config, control_endpoint)
+    }
+
+    fn launch(context: zmq::Context, config: Config, control_endpoint: String) -> Self {
+        let mut runtime = Runtime::new(&config);
+        runtime.store = store::open(&config.storage).expect("storage");
+        let config = Arc::new(config);
+        let thread = {
+            let (context, config, runtime) = (context.clone(), config.clone(), runtime.clone());
+            let control_endpoint = control_endpoint.clone();
+            thread::spawn(move || {
+                run_proxy(&context, &config, &runtime, &control_endpoint).expect("proxy failed")
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        Self {
+            context,
+            config,
+            runtime,
+            control_endpoint,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    // Like BrokerHarness::restart.
+    pub fn restart(mut self) -> Self {
+        let context = self.context.clone();
+        let config = restart_config(&self.config);
+        let control_endpoint = std::mem::take(&mut self.control_endpoint);
+        self.stop(&control_endpoint);
+        Self::launch(context, config, control_endpoint)
+    }
+
+    fn stop(&mut self, control_endpoint: &str) {
+        if let Ok(control) = self.context.socket(zmq::PAIR) {
+            let _ = control.set_linger(0);
+            if control.connect(control_endpoint).is_ok() {
+                let _ = control.send("TERMINATE", 0);
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    pub fn publisher(&self) -> zmq::Socket {
+        let socket = self.context.socket(zmq::PUB).unwrap();
+        socket.set_linger(0).unwrap();
+        socket
+            .connect(&self.config.network.proxy_xsub_endpoint)
+            .unwrap();
+        socket
+    }
+
+    pub fn subscriber(&self, topic: &[u8]) -> zmq::Socket {
+        let socket = self.context.socket(zmq::SUB).unwrap();
+        socket.set_rcvtimeo(5000).unwrap();
+        socket.set_linger(0).unwrap();
+        socket.set_subscribe(topic).unwrap();
+        socket
+            .connect(&self.config.network.proxy_xpub_endpoint)
+            .unwrap();
+        socket
+    }
+}
+
+impl Drop for ProxyHarness {
+    fn drop(&mut self) {
+        let control_endpoint = self.control_endpoint.clone();
+        self.stop(&control_endpoint);
+    }
+}
+
+// The old sockets may still be letting go of their ports, so a restart waits
+// for them rather than failing fast.
+fn restart_config(config: &Config) -> Config {
+    let mut config = config.clone();
+    config.retry.bind.deadline_ms = config.retry.bind.deadline_ms.max(5000);
+    config
+}
+
+// Subscriptions take a moment to travel SUB -> XPUB -> XSUB -> PUB.
+pub fn propagate() {
+    thread::sleep(Duration::from_millis(200));
+}
+
+// Give freshly connected peers time to finish the handshake so ROUTER
+// sockets know their identities.
+pub fn settle() {
+    thread::sleep(Duration::from_millis(50));
+}
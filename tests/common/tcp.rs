@@ -0,0 +1,132 @@
+// The harnesses over real TCP on localhost, for what inproc cannot show: slow
+// joiners, reconnects, ports that are still or already in use. Ports come
+// from binding to port 0; each is handed out once per test binary, and a
+// harness that finds its port taken after all starts over on fresh ones.
+//
+// Timing still varies more than over inproc, so sockets wait up to
+// TIMEOUT_MS, conditions are polled with `wait_for`, and a scenario can be
+// wrapped in `retry_once`.
+
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use corky_zmq::config::Config;
+
+use super::{BrokerHarness, ProxyHarness};
+
+pub const TIMEOUT_MS: i32 = 10_000;
+
+// A localhost port that was free a moment ago and that no other test in this
+// binary has been given.
+pub fn free_port() -> u16 {
+    static GIVEN: Mutex<Option<HashSet<u16>>> = Mutex::new(None);
+    let mut given = GIVEN.lock().unwrap_or_else(|e| e.into_inner());
+    let given = given.get_or_insert_with(HashSet::new);
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("a free port");
+        let port = listener.local_addr().unwrap().port();
+        if given.insert(port) {
+            return port;
+        }
+    }
+}
+
+pub fn endpoint() -> String {
+    format!("tcp://127.0.0.1:{}", free_port())
+}
+
+// The port of a tcp:// endpoint.
+pub fn port(endpoint: &str) -> u16 {
+    endpoint.rsplit(':').next().unwrap().parse().unwrap()
+}
+
+// Runs `scenario`, and once more if it panics; a second failure is a real
+// one.
+pub fn retry_once<T>(name: &str, scenario: impl Fn() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(&scenario)) {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("{}: failed once over TCP, retrying", name);
+            scenario()
+        }
+    }
+}
+
+// Polls `done` until it holds, failing the test after `timeout`.
+pub fn wait_for(what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Starts a harness with `start`, twice at most: a port somebody else bound
+// between free_port and the harness's own bind shows up as a harness that
+// stopped at once.
+fn started<H>(start: impl Fn() -> H, running: impl Fn(&H) -> bool) -> H {
+    let harness = start();
+    if running(&harness) {
+        return harness;
+    }
+    drop(harness);
+    let harness = start();
+    assert!(running(&harness), "harness failed to start on fresh ports");
+    harness
+}
+
+// Fresh ports fail fast instead of waiting out someone else's listener;
+// `restart` lifts that again.
+fn fail_fast(cfg: &mut Config) {
+    cfg.retry.bind.deadline_ms = 0;
+}
+
+pub fn broker(customize: impl Fn(&mut Config)) -> BrokerHarness {
+    broker_in(&zmq::Context::new(), customize)
+}
+
+// Like `broker`, on a shared context, e.g. one with a ZAP handler.
+pub fn broker_in(context: &zmq::Context, customize: impl Fn(&mut Config)) -> BrokerHarness {
+    started(
+        || {
+            BrokerHarness::start_in(context.clone(), |cfg| {
+                cfg.network.client_to_client_endpoint = endpoint();
+                cfg.network.client_facing_endpoint = endpoint();
+                cfg.network.worker_facing_endpoint = endpoint();
+                fail_fast(cfg);
+                customize(cfg);
+            })
+        },
+        BrokerHarness::is_running,
+    )
+}
+
+pub fn proxy(customize: impl Fn(&mut Config)) -> ProxyHarness {
+    proxy_in(&zmq::Context::new(), customize)
+}
+
+pub fn proxy_in(context: &zmq::Context, customize: impl Fn(&mut Config)) -> ProxyHarness {
+    started(
+        || {
+            ProxyHarness::start_in(context.clone(), |cfg| {
+                cfg.network.proxy_xsub_endpoint = endpoint();
+                cfg.network.proxy_xpub_endpoint = endpoint();
+                fail_fast(cfg);
+                customize(cfg);
+            })
+        },
+        ProxyHarness::is_running,
+    )
+}
+
+// A DEALER on `broker` with the TCP timeout instead of the inproc one.
+pub fn dealer(broker: &BrokerHarness, identity: &[u8], endpoint: &str) -> zmq::Socket {
+    let socket = broker.dealer(identity, endpoint);
+    socket.set_rcvtimeo(TIMEOUT_MS).unwrap();
+    socket.set_sndtimeo(TIMEOUT_MS).unwrap();
+    socket
+}
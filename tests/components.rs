@@ -0,0 +1,26 @@
+// A broker with [broker] direct_messaging = false: clients and workers are
+// served as before while the client-to-client endpoint is left unbound.
+
+mod common;
+
+use common::{propagate, BrokerHarness};
+
+#[test]
+fn disabling_direct_messaging_leaves_its_endpoint_free() {
+    let broker = BrokerHarness::start(|cfg| cfg.broker.direct_messaging = false);
+    let worker = broker.worker(b"alice");
+    let client = broker.client(b"alice");
+    propagate();
+
+    client.send("job", 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), [b"job".to_vec()]);
+    worker.send_multipart(["alice", "done"], 0).unwrap();
+    assert_eq!(client.recv_multipart(0).unwrap(), [b"done".to_vec()]);
+
+    // Nobody holds the inproc name, so it can be bound again.
+    let squatter = broker.context.socket(zmq::ROUTER).unwrap();
+    squatter
+        .bind(&broker.config.network.client_to_client_endpoint)
+        .unwrap();
+    assert!(broker.is_running());
+}
@@ -0,0 +1,137 @@
+// Compression negotiation through the broker: which side sees compressed
+// frames for each combination of worker support and client opt-in, and what
+// a client receives when a compressed reply is corrupt.
+
+mod common;
+
+use common::{settle, BrokerHarness};
+use corky_zmq::compress::{compress_frame, decompress_frames, is_compressed, COMPRESSED_MAGIC};
+use corky_zmq::fanout::WORKER_READY;
+
+fn payload() -> Vec<u8> {
+    br#"{"symbol":"EURUSD","bid":1.0842,"ask":1.0844,"venue":"LMAX"}"#.repeat(100)
+}
+
+fn broker() -> BrokerHarness {
+    BrokerHarness::start(|cfg| {
+        cfg.compression.enabled = true;
+        cfg.compression.threshold_bytes = 1024;
+    })
+}
+
+// The broker routes client traffic to the worker with the same identity.
+fn pair(
+    broker: &BrokerHarness,
+    identity: &[u8],
+    worker_caps: Option<&str>,
+) -> (zmq::Socket, zmq::Socket) {
+    let client = broker.client(identity);
+    let worker = broker.worker(identity);
+    match worker_caps {
+        Some(caps) => worker
+            .send_multipart([WORKER_READY, caps.as_bytes()], 0)
+            .unwrap(),
+        None => worker.send(WORKER_READY, 0).unwrap(),
+    }
+    settle();
+    (client, worker)
+}
+
+// Worker side of one request: reply with `frames` and swallow the echo.
+fn reply(worker: &zmq::Socket, identity: &[u8], frames: Vec<Vec<u8>>) {
+    let mut message = vec![identity.to_vec()];
+    message.extend(frames);
+    worker.send_multipart(&message, 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), message);
+}
+
+fn saved_bytes(broker: &BrokerHarness) -> u64 {
+    broker
+        .runtime
+        .metrics
+        .counter("corky_compression_saved_bytes_total", &[])
+        .get()
+}
+
+#[test]
+fn capable_worker_gets_compressed_frames_and_plain_client_gets_plain_replies() {
+    let broker = broker();
+    let (client, worker) = pair(&broker, b"c1", Some(r#"{"compression": ["zstd"]}"#));
+
+    client
+        .send_multipart([&b"small"[..], &payload()], 0)
+        .unwrap();
+    let mut request = worker.recv_multipart(0).unwrap();
+    assert_eq!(request[0], b"small", "below the threshold");
+    assert!(is_compressed(&request[1]));
+    assert!(request[1].len() * 5 < payload().len());
+    assert!(saved_bytes(&broker) > 0);
+
+    // The worker answers compressed; the client never opted in.
+    decompress_frames(&mut request, 1 << 20).unwrap();
+    assert_eq!(request[1], payload());
+    let packed = compress_frame(&payload(), 3).unwrap();
+    reply(&worker, b"c1", vec![packed]);
+    assert_eq!(client.recv_multipart(0).unwrap(), vec![payload()]);
+}
+
+#[test]
+fn worker_without_support_gets_plain_frames() {
+    let broker = broker();
+    let (client, worker) = pair(&broker, b"c2", None);
+    client.send(payload(), 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), vec![payload()]);
+
+    // Advertising an unknown codec is the same as advertising nothing.
+    worker
+        .send_multipart([WORKER_READY, br#"{"compression": ["lz4"]}"#], 0)
+        .unwrap();
+    settle();
+    client.send(payload(), 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), vec![payload()]);
+    assert_eq!(saved_bytes(&broker), 0);
+}
+
+#[test]
+fn opted_in_client_frames_pass_through_untouched() {
+    let broker = broker();
+    let (client, worker) = pair(&broker, b"c3", None);
+    let packed = compress_frame(&payload(), 19).unwrap();
+
+    client.send(&packed, 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), vec![packed.clone()]);
+
+    reply(&worker, b"c3", vec![packed.clone()]);
+    assert_eq!(client.recv_multipart(0).unwrap(), vec![packed]);
+}
+
+#[test]
+fn corrupt_compressed_reply_becomes_a_structured_error() {
+    let broker = broker();
+    let (client, worker) = pair(&broker, b"c4", Some(r#"{"compression": ["zstd"]}"#));
+    client.send("request", 0).unwrap();
+    worker.recv_multipart(0).unwrap();
+
+    let mut corrupt = COMPRESSED_MAGIC.to_vec();
+    corrupt.extend_from_slice(b"\x28\xb5\x2f\xfd garbage after a valid magic");
+    reply(&worker, b"c4", vec![corrupt]);
+
+    let error = client.recv_multipart(0).unwrap();
+    assert_eq!(error.len(), 1);
+    let error: serde_json::Value = serde_json::from_slice(&error[0]).unwrap();
+    assert_eq!(error["error"]["code"], "decompression_failed");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("corrupt"));
+    let errors = broker
+        .runtime
+        .metrics
+        .counter("corky_compression_errors_total", &[])
+        .get();
+    assert_eq!(errors, 1);
+
+    // The broker keeps serving afterwards.
+    client.send("again", 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), vec![b"again".to_vec()]);
+}
@@ -0,0 +1,250 @@
+// Several client-facing endpoints with their own options: a TCP frontend
+// with a small max message size next to an ipc one without a limit. Replies
+// go out on the frontend the client's last request came in on, even when
+// the same identity is connected to both. Also one socket bound to several
+// addresses through the *_endpoints lists.
+
+mod common;
+
+use std::net::TcpListener;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use common::{propagate, BrokerHarness, ProxyHarness};
+use corky_zmq::broker::run_broker;
+use corky_zmq::config::{Config, EndpointConfig};
+use corky_zmq::runtime::Runtime;
+
+const MAX_TCP_MESSAGE: i64 = 64;
+
+fn free_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp://{}", listener.local_addr().unwrap())
+}
+
+fn ipc_endpoint(name: &str) -> String {
+    format!("ipc:///tmp/corky-test-{}-{}.sock", process::id(), name)
+}
+
+fn two_frontends(name: &str) -> BrokerHarness {
+    let tcp = free_endpoint();
+    let ipc = ipc_endpoint(name);
+    BrokerHarness::start(move |cfg| {
+        cfg.network.client_facing = vec![
+            EndpointConfig {
+                address: tcp,
+                maxmsgsize: MAX_TCP_MESSAGE,
+                ..EndpointConfig::default()
+            },
+            EndpointConfig {
+                address: ipc,
+                ..EndpointConfig::default()
+            },
+        ];
+    })
+}
+
+fn frontend(broker: &BrokerHarness, index: usize, identity: &[u8]) -> zmq::Socket {
+    broker.dealer(
+        identity,
+        &broker.config.network.client_facing[index].address,
+    )
+}
+
+#[test]
+fn each_frontend_applies_its_own_max_message_size() {
+    let broker = two_frontends("maxmsgsize");
+    let worker = broker.worker(b"big");
+    worker.set_rcvtimeo(500).unwrap();
+    let over_tcp = frontend(&broker, 0, b"big");
+    propagate();
+    let big = vec![b'x'; 1024];
+    over_tcp.send(&big, 0).unwrap();
+    assert!(worker.recv_bytes(0).is_err(), "dropped over the TCP limit");
+
+    let over_ipc = frontend(&broker, 1, b"big");
+    propagate();
+    over_ipc.send(&big, 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), big);
+    worker.send_multipart([&b"big"[..], b"reply"], 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+    assert_eq!(over_ipc.recv_bytes(0).unwrap(), b"reply");
+}
+
+#[test]
+fn replies_leave_on_the_frontend_the_request_came_in_on() {
+    let broker = two_frontends("routing");
+    let worker = broker.worker(b"same");
+    let over_tcp = frontend(&broker, 0, b"same");
+    let over_ipc = frontend(&broker, 1, b"same");
+    over_tcp.set_rcvtimeo(300).unwrap();
+    over_ipc.set_rcvtimeo(300).unwrap();
+    propagate();
+
+    for (client, other) in [(&over_ipc, &over_tcp), (&over_tcp, &over_ipc)] {
+        client.send("request", 0).unwrap();
+        assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+        worker.send_multipart([&b"same"[..], b"reply"], 0).unwrap();
+        assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+        assert_eq!(client.recv_bytes(0).unwrap(), b"reply");
+        assert!(other.recv_bytes(0).is_err());
+    }
+
+    let received = |endpoint: &str| {
+        broker
+            .runtime
+            .metrics
+            .counter(
+                "corky_broker_received_total",
+                &[
+                    ("socket", "client_router"),
+                    ("endpoint", corky_zmq::metrics::label_value(endpoint)),
+                ],
+            )
+            .get()
+    };
+    for endpoint in &broker.config.network.client_facing {
+        assert_eq!(received(&endpoint.address), 1);
+    }
+}
+
+#[test]
+fn curve_endpoints_need_curve_support_and_a_key() {
+    let mut config = Config::default();
+    config.network.client_to_client_endpoint = "inproc://curve-direct".to_string();
+    config.network.worker_facing_endpoint = "inproc://curve-worker".to_string();
+    config.network.client_facing = vec![EndpointConfig {
+        address: free_endpoint(),
+        curve: true,
+        ..EndpointConfig::default()
+    }];
+    let context = zmq::Context::new();
+    let runtime = Runtime::new(&config);
+    let shutdown = Arc::new(AtomicBool::new(true));
+    let expected = match zmq::has("curve") {
+        // Without a secret key there is nothing to serve CURVE with.
+        Some(true) => zmq::Error::EINVAL,
+        _ => zmq::Error::ENOTSUP,
+    };
+    let result = run_broker(&context, &Arc::new(config), &runtime, &shutdown);
+    assert_eq!(result, Err(expected));
+}
+
+#[test]
+fn one_client_router_binds_every_listed_address() {
+    let addresses = vec![free_endpoint(), ipc_endpoint("listed")];
+    let broker = {
+        let addresses = addresses.clone();
+        BrokerHarness::start(move |cfg| cfg.network.client_facing_endpoints = addresses)
+    };
+    for (address, identity) in addresses.iter().zip([&b"listed-a"[..], b"listed-b"]) {
+        let worker = broker.worker(identity);
+        let client = broker.dealer(identity, address);
+        propagate();
+        client.send("request", 0).unwrap();
+        assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+        worker.send_multipart([identity, b"reply"], 0).unwrap();
+        assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+        assert_eq!(client.recv_bytes(0).unwrap(), b"reply");
+    }
+    // Both arrived on the one unlabelled client ROUTER.
+    let received = broker
+        .runtime
+        .metrics
+        .counter(
+            "corky_broker_received_total",
+            &[("socket", "client_router")],
+        )
+        .get();
+    assert_eq!(received, 2);
+}
+
+#[test]
+fn a_listed_address_that_cannot_bind_stops_the_broker() {
+    let mut config = Config::default();
+    config.network.client_to_client_endpoint = "inproc://listed-direct".to_string();
+    config.network.worker_facing_endpoint = "inproc://listed-worker".to_string();
+    config.network.client_facing_endpoints = vec![
+        "inproc://listed-client".to_string(),
+        "tcp://no-such-interface:5559".to_string(),
+    ];
+    let runtime = Runtime::new(&config);
+    let shutdown = Arc::new(AtomicBool::new(true));
+    let result = run_broker(&zmq::Context::new(), &Arc::new(config), &runtime, &shutdown);
+    assert!(result.is_err());
+}
+
+#[test]
+fn the_proxy_publishes_on_every_xpub_address() {
+    let proxy = ProxyHarness::start(|cfg| {
+        cfg.network.proxy_xpub_endpoints = vec![
+            "inproc://listed-xpub-a".to_string(),
+            "inproc://listed-xpub-b".to_string(),
+        ];
+    });
+    let subscribers: Vec<zmq::Socket> = proxy
+        .config
+        .network
+        .proxy_xpub_endpoints
+        .iter()
+        .map(|address| {
+            let socket = proxy.context.socket(zmq::SUB).unwrap();
+            socket.set_rcvtimeo(5000).unwrap();
+            socket.set_subscribe(b"news").unwrap();
+            socket.connect(address).unwrap();
+            socket
+        })
+        .collect();
+    let publisher = proxy.publisher();
+    propagate();
+    publisher
+        .send_multipart([&b"news"[..], b"hello"], 0)
+        .unwrap();
+    for subscriber in &subscribers {
+        assert_eq!(subscriber.recv_multipart(0).unwrap()[1], b"hello");
+    }
+}
+
+#[test]
+fn wildcard_ports_are_resolved_and_published() {
+    let file = std::env::temp_dir().join(format!("corky-endpoints-{}.json", process::id()));
+    let broker = {
+        let file = file.display().to_string();
+        BrokerHarness::start(move |cfg| {
+            cfg.network.client_facing_endpoint = "tcp://127.0.0.1:0".to_string();
+            cfg.network.worker_facing_endpoint = "tcp://127.0.0.1:*".to_string();
+            cfg.network.endpoints_file = Some(file);
+        })
+    };
+    let published: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+    let address = |role: &str| published[role][0].as_str().unwrap().to_string();
+    for role in ["client_facing", "worker_facing"] {
+        assert!(
+            address(role).starts_with("tcp://127.0.0.1:"),
+            "{}",
+            published
+        );
+        assert!(!address(role).ends_with(":0") && !address(role).ends_with(":*"));
+    }
+    assert_eq!(
+        address("client_to_client"),
+        broker.config.network.client_to_client_endpoint
+    );
+    assert_eq!(
+        broker.runtime.endpoints.get("client_facing"),
+        [address("client_facing")]
+    );
+
+    let worker = broker.dealer(b"wild", &address("worker_facing"));
+    let client = broker.dealer(b"wild", &address("client_facing"));
+    propagate();
+    client.send("request", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+    worker.send_multipart([&b"wild"[..], b"reply"], 0).unwrap();
+    assert_eq!(client.recv_bytes(0).unwrap(), b"reply");
+
+    assert_eq!(broker.runtime.endpoints.remove_file(), Some(file.as_path()));
+    assert!(!file.exists());
+}
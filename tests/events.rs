@@ -0,0 +1,67 @@
+// The event stream seen by a subscriber: a worker and a client appear, the
+// client's request is answered, and the client goes quiet until it is
+// collected, while the worker keeps announcing itself.
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{propagate, BrokerHarness};
+use corky_zmq::events::EVENT_SCHEMA_VERSION;
+use corky_zmq::fanout::WORKER_READY;
+
+#[test]
+fn a_client_lifecycle_is_published_in_order() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.broker.peer_idle_ttl_ms = 300;
+        cfg.events.endpoint = "inproc://test-events".into();
+    });
+    let events = broker.context.socket(zmq::SUB).unwrap();
+    events.set_rcvtimeo(100).unwrap();
+    events.set_subscribe(b"event.").unwrap();
+    events.connect(&broker.config.events.endpoint).unwrap();
+    propagate();
+
+    let worker = broker.worker(b"c-1");
+    worker.send(WORKER_READY, 0).unwrap();
+    propagate();
+    let client = broker.client(b"c-1");
+    propagate();
+    client.send("request", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+    worker.send_multipart([&b"c-1"[..], b"reply"], 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+    assert_eq!(client.recv_bytes(0).unwrap(), b"reply");
+
+    let deadline = Instant::now() + Duration::from_millis(2500);
+    while Instant::now() < deadline {
+        worker.send(WORKER_READY, 0).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut seen = Vec::new();
+    while let Ok(message) = events.recv_multipart(0) {
+        let event: serde_json::Value = serde_json::from_slice(&message[1]).unwrap();
+        assert_eq!(
+            message[0],
+            format!("event.{}", event["event"].as_str().unwrap()).as_bytes()
+        );
+        assert_eq!(event["version"], EVENT_SCHEMA_VERSION);
+        assert!(event["ts_ms"].as_u64().unwrap() > 0);
+        seen.push((
+            event["event"].as_str().unwrap().to_string(),
+            event["identity"].as_str().unwrap_or_default().to_string(),
+        ));
+    }
+    let expected = [
+        ("worker.connected", "c-1"),
+        ("client.connected", "c-1"),
+        ("client.expired", "c-1"),
+    ];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|(e, id)| (e.to_string(), id.to_string()))
+        .collect();
+    assert_eq!(seen, expected);
+}
@@ -0,0 +1,92 @@
+// Binary Star pairing: the primary serves while both run; once it dies, the
+// backup takes over on the first client request after the failover delay.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{settle, BrokerHarness};
+use corky_zmq::config::Config;
+use corky_zmq::ha::HaRole;
+use corky_zmq::metrics::Value;
+
+const HEARTBEAT_MS: u64 = 20;
+const FAILOVER_MS: u64 = 200;
+
+fn ha(role: HaRole, local: &str, peer: &str) -> impl FnOnce(&mut Config) {
+    let (local, peer) = (local.to_string(), peer.to_string());
+    move |cfg| {
+        cfg.ha.enabled = true;
+        cfg.ha.role = role;
+        cfg.ha.local_endpoint = local;
+        cfg.ha.peer_endpoint = peer;
+        cfg.ha.heartbeat_ms = HEARTBEAT_MS;
+        cfg.ha.failover_ms = FAILOVER_MS;
+        // Keep the generated identity out of the home directory.
+        cfg.ha.routing_id = Some(format!("{:?}", role));
+    }
+}
+
+fn is_active(broker: &BrokerHarness) -> bool {
+    broker
+        .runtime
+        .metrics
+        .snapshot()
+        .into_iter()
+        .any(|s| s.name == "corky_ha_active" && s.value == Value::Gauge(1))
+}
+
+// One request/reply through the broker: client -> worker -> client.
+fn round_trip(client: &zmq::Socket, worker: &zmq::Socket, payload: &str) -> Option<String> {
+    client.send_multipart([payload], 0).unwrap();
+    let request = worker.recv_multipart(0).ok()?;
+    assert_eq!(request, [payload.as_bytes()]);
+    worker
+        .send_multipart([b"svc".as_slice(), payload.as_bytes()], 0)
+        .unwrap();
+    worker.recv_multipart(0).unwrap(); // the broker's echo
+    let reply = client.recv_multipart(0).ok()?;
+    Some(String::from_utf8(reply[0].clone()).unwrap())
+}
+
+#[test]
+fn backup_takes_over_when_the_primary_dies() {
+    let context = zmq::Context::new();
+    let primary = BrokerHarness::start_in(
+        context.clone(),
+        ha(HaRole::Primary, "inproc://ha-primary", "inproc://ha-backup"),
+    );
+    let backup = BrokerHarness::start_in(
+        context.clone(),
+        ha(HaRole::Backup, "inproc://ha-backup", "inproc://ha-primary"),
+    );
+    thread::sleep(Duration::from_millis(FAILOVER_MS));
+    assert!(is_active(&primary));
+    assert!(!is_active(&backup));
+
+    let client = primary.client(b"svc");
+    let worker = primary.worker(b"svc");
+    settle();
+    assert_eq!(round_trip(&client, &worker, "one").as_deref(), Some("one"));
+
+    // The passive backup drops traffic while the primary is alive.
+    let backup_client = backup.client(b"svc");
+    let backup_worker = backup.worker(b"svc");
+    backup_worker.set_rcvtimeo(200).unwrap();
+    settle();
+    backup_client.send_multipart(["ignored"], 0).unwrap();
+    assert!(backup_worker.recv_multipart(0).is_err());
+    assert!(!is_active(&backup));
+
+    // Kill the primary; the backup promotes on the next request and serves it.
+    drop((client, worker, primary));
+    thread::sleep(Duration::from_millis(FAILOVER_MS * 2));
+    assert!(!is_active(&backup));
+    backup_worker.set_rcvtimeo(5000).unwrap();
+    assert_eq!(
+        round_trip(&backup_client, &backup_worker, "two").as_deref(),
+        Some("two")
+    );
+    assert!(is_active(&backup));
+}
@@ -0,0 +1,249 @@
+// Scatter-gather through the broker with three echo workers: collect-all,
+// first-wins, partial results at the deadline, and workers leaving mid-request
+// with and without DISCONNECT.
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{settle, BrokerHarness};
+use corky_zmq::fanout::{WORKER_DISCONNECT, WORKER_READY};
+
+// Echoes every request after `delay`, or never answers when `delay` is None.
+// Dropping it closes the socket without a word; `drain` deregisters first.
+struct EchoWorker {
+    stop: Arc<AtomicBool>,
+    graceful: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl EchoWorker {
+    fn start(broker: &BrokerHarness, identity: &str, delay: Option<Duration>) -> Self {
+        let socket = broker.worker(identity.as_bytes());
+        socket.set_rcvtimeo(50).unwrap();
+        socket.send(WORKER_READY, 0).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let graceful = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (stop, graceful) = (stop.clone(), graceful.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let Ok(request) = socket.recv_multipart(0) else {
+                        continue;
+                    };
+                    if let Some(delay) = delay {
+                        thread::sleep(delay);
+                        socket.send_multipart(request, 0).unwrap();
+                    }
+                }
+                if graceful.load(Ordering::SeqCst) {
+                    socket.send(WORKER_DISCONNECT, 0).unwrap();
+                }
+            })
+        };
+        Self {
+            stop,
+            graceful,
+            thread: Some(thread),
+        }
+    }
+
+    fn drain(self) {
+        self.graceful.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for EchoWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn fanout(client: &zmq::Socket, header: &str, payload: &str) -> (serde_json::Value, Vec<Vec<u8>>) {
+    client.send_multipart([header, payload], 0).unwrap();
+    let mut reply = client.recv_multipart(0).unwrap();
+    let summary: serde_json::Value = serde_json::from_slice(&reply.remove(0)).unwrap();
+    (summary["fanout"].clone(), reply)
+}
+
+fn workers(summary: &serde_json::Value) -> Vec<String> {
+    let mut names: Vec<String> = summary["replies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["worker"].as_str().unwrap().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn collect_all_gathers_every_worker() {
+    let broker = BrokerHarness::start(|_| {});
+    let _workers: Vec<_> = ["w1", "w2", "w3"]
+        .iter()
+        .map(|id| EchoWorker::start(&broker, id, Some(Duration::ZERO)))
+        .collect();
+    let client = broker.client(b"client");
+    settle();
+
+    let (summary, replies) = fanout(&client, r#"{"fanout": {"count": "all"}}"#, "ping");
+    assert_eq!(summary["partial"], false);
+    assert_eq!(summary["received"], 3);
+    assert_eq!(workers(&summary), ["w1", "w2", "w3"]);
+    assert_eq!(replies, vec![b"ping".to_vec(); 3]);
+
+    let (summary, replies) = fanout(&client, r#"{"fanout": {"count": 2}}"#, "pong");
+    assert_eq!(summary["dispatched"], 2);
+    assert_eq!(replies.len(), 2);
+}
+
+#[test]
+fn first_mode_returns_the_fastest_reply() {
+    let broker = BrokerHarness::start(|_| {});
+    let _workers = [
+        EchoWorker::start(&broker, "slow1", Some(Duration::from_millis(300))),
+        EchoWorker::start(&broker, "fast", Some(Duration::from_millis(10))),
+        EchoWorker::start(&broker, "slow2", Some(Duration::from_millis(300))),
+    ];
+    let client = broker.client(b"client");
+    settle();
+
+    let (summary, replies) = fanout(
+        &client,
+        r#"{"fanout": {"count": "all", "mode": "first"}}"#,
+        "q",
+    );
+    assert_eq!(summary["partial"], false);
+    assert_eq!(summary["dispatched"], 3);
+    assert_eq!(workers(&summary), ["fast"]);
+    assert_eq!(replies, [b"q".to_vec()]);
+    // The slow replies are dropped rather than delivered later.
+    client.set_rcvtimeo(500).unwrap();
+    assert!(client.recv_multipart(0).is_err());
+}
+
+#[test]
+fn deadline_returns_partial_results() {
+    let broker = BrokerHarness::start(|_| {});
+    let _workers = [
+        EchoWorker::start(&broker, "w1", Some(Duration::ZERO)),
+        EchoWorker::start(&broker, "w2", Some(Duration::ZERO)),
+        EchoWorker::start(&broker, "silent", None),
+    ];
+    let client = broker.client(b"client");
+    settle();
+
+    let started = Instant::now();
+    let (summary, replies) = fanout(
+        &client,
+        r#"{"fanout": {"count": 3, "timeout_ms": 200}}"#,
+        "q",
+    );
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(summary["partial"], true);
+    assert_eq!(summary["received"], 2);
+    assert_eq!(summary["missing"], serde_json::json!(["silent"]));
+    assert_eq!(replies.len(), 2);
+}
+
+#[test]
+fn worker_gone_before_dispatch_is_skipped() {
+    let broker = BrokerHarness::start(|_| {});
+    let live = EchoWorker::start(&broker, "live", Some(Duration::ZERO));
+    let gone = EchoWorker::start(&broker, "gone", Some(Duration::ZERO));
+    let client = broker.client(b"client");
+    settle();
+    // Stopping the thread closes the worker's socket.
+    drop(gone);
+    settle();
+
+    let (summary, replies) = fanout(
+        &client,
+        r#"{"fanout": {"count": "all", "timeout_ms": 500}}"#,
+        "q",
+    );
+    assert_eq!(summary["partial"], false);
+    assert_eq!(workers(&summary), ["live"]);
+    assert_eq!(replies.len(), 1);
+    drop(live);
+
+    let (summary, _) = fanout(&client, r#"{"fanout": {"count": "bogus"}}"#, "q");
+    assert!(summary["error"]
+        .as_str()
+        .unwrap()
+        .contains("invalid fanout header"));
+}
+
+// Dispatch follows READY order, so "leaving" gets the request and "stays"
+// is the one left to take it over. Returns the summary and how long the
+// client waited.
+fn leave_mid_request(graceful: bool) -> (serde_json::Value, Duration) {
+    let broker = BrokerHarness::start(|_| {});
+    let leaving = EchoWorker::start(&broker, "leaving", None);
+    settle();
+    let _stays = EchoWorker::start(&broker, "stays", Some(Duration::ZERO));
+    let client = broker.client(b"client");
+    settle();
+
+    let started = Instant::now();
+    client
+        .send_multipart([r#"{"fanout": {"count": 1, "timeout_ms": 1000}}"#, "q"], 0)
+        .unwrap();
+    settle();
+    if graceful {
+        leaving.drain();
+    } else {
+        drop(leaving);
+    }
+    let mut reply = client.recv_multipart(0).unwrap();
+    let summary: serde_json::Value = serde_json::from_slice(&reply.remove(0)).unwrap();
+    (summary["fanout"].clone(), started.elapsed())
+}
+
+#[test]
+fn a_draining_worker_hands_its_request_on() {
+    let (summary, waited) = leave_mid_request(true);
+    assert!(waited < Duration::from_millis(1000), "{:?}", waited);
+    assert_eq!(summary["partial"], false);
+    assert_eq!(workers(&summary), ["stays"]);
+}
+
+#[test]
+fn a_killed_worker_leaves_its_request_to_time_out() {
+    let (summary, waited) = leave_mid_request(false);
+    assert!(waited >= Duration::from_millis(1000), "{:?}", waited);
+    assert_eq!(summary["partial"], true);
+    assert_eq!(summary["missing"], serde_json::json!(["leaving"]));
+}
+
+#[test]
+fn repeated_and_unknown_disconnects_are_harmless() {
+    let broker = BrokerHarness::start(|_| {});
+    let stranger = broker.worker(b"stranger");
+    stranger.send(WORKER_DISCONNECT, 0).unwrap();
+    let worker = broker.worker(b"client");
+    worker.send(WORKER_READY, 0).unwrap();
+    worker.send(WORKER_DISCONNECT, 0).unwrap();
+    worker.send(WORKER_DISCONNECT, 0).unwrap();
+    settle();
+    let disconnects = broker
+        .runtime
+        .metrics
+        .counter("corky_fanout_worker_disconnects_total", &[]);
+    assert_eq!(disconnects.get(), 1);
+
+    // The broker still routes, and the pool is empty until READY again.
+    let client = broker.client(b"client");
+    settle();
+    client.send("ping", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"ping");
+    let (summary, _) = fanout(&client, r#"{"fanout": {"count": 1}}"#, "q");
+    assert_eq!(summary["dispatched"], 0);
+}
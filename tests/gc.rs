@@ -0,0 +1,53 @@
+// Idle-state collection seen from outside: silent peers are announced on
+// the notify endpoint while active ones are left alone.
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{propagate, settle, BrokerHarness};
+
+#[test]
+fn silent_peers_are_announced_as_gone() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.broker.peer_idle_ttl_ms = 300;
+        cfg.gc.notify_endpoint = "inproc://test-gc-events".into();
+    });
+    let events = broker.context.socket(zmq::SUB).unwrap();
+    events.set_rcvtimeo(5000).unwrap();
+    events.set_subscribe(b"peer_gone").unwrap();
+    events.connect(&broker.config.gc.notify_endpoint).unwrap();
+    propagate();
+
+    let quiet = broker.client(b"quiet");
+    let busy = broker.client(b"busy");
+    settle();
+    quiet.send("hello", 0).unwrap();
+
+    // Keep the busy client talking across a few sweeps, then read back the
+    // notifications: only the quiet client is gone.
+    let deadline = Instant::now() + Duration::from_millis(2500);
+    while Instant::now() < deadline {
+        busy.send("ping", 0).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut gone = Vec::new();
+    events.set_rcvtimeo(100).unwrap();
+    while let Ok(message) = events.recv_multipart(0) {
+        assert_eq!(message[0], b"peer_gone");
+        gone.push(serde_json::from_slice::<serde_json::Value>(&message[1]).unwrap());
+    }
+    assert_eq!(gone.len(), 1, "{:?}", gone);
+    assert_eq!(gone[0]["category"], "peer");
+    assert_eq!(gone[0]["role"], "client");
+    assert_eq!(gone[0]["identity"], "quiet");
+    assert!(gone[0]["idle_ms"].as_u64().unwrap() >= 300);
+
+    let expired = broker
+        .runtime
+        .metrics
+        .counter("corky_gc_expired_total", &[("category", "peer")])
+        .get();
+    assert_eq!(expired, 1);
+}
@@ -0,0 +1,162 @@
+// Request hedging through the broker with one slow and one fast echo worker:
+// a "first"-mode request stuck on the slow worker is answered by the fast one,
+// the client sees exactly one reply, and hedges stay within the cap and out
+// of the way when every worker is busy.
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{settle, BrokerHarness};
+use corky_zmq::fanout::{is_hedge_tag, WORKER_READY};
+use corky_zmq::metrics::Label;
+
+const FIRST: &str = r#"{"fanout": {"count": 1, "mode": "first", "timeout_ms": 2000}}"#;
+
+// Echoes every request after `delay` and reports whether it was a hedge.
+struct EchoWorker {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl EchoWorker {
+    fn start(broker: &BrokerHarness, identity: &str, delay: Duration) -> (Self, Receiver<bool>) {
+        let socket = broker.worker(identity.as_bytes());
+        socket.set_rcvtimeo(50).unwrap();
+        socket.send(WORKER_READY, 0).unwrap();
+        settle();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (seen, hedges) = channel();
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let Ok(request) = socket.recv_multipart(0) else {
+                        continue;
+                    };
+                    let _ = seen.send(is_hedge_tag(&request[1]));
+                    thread::sleep(delay);
+                    socket.send_multipart(request, 0).unwrap();
+                }
+            })
+        };
+        let worker = Self {
+            stop,
+            thread: Some(thread),
+        };
+        (worker, hedges)
+    }
+}
+
+impl Drop for EchoWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn hedging_broker(delay_ms: u64, max_percent: u32) -> BrokerHarness {
+    BrokerHarness::start(|cfg| {
+        cfg.hedge.enabled = true;
+        cfg.hedge.delay_ms = delay_ms;
+        cfg.hedge.max_percent = max_percent;
+    })
+}
+
+fn counter(broker: &BrokerHarness, name: &'static str, labels: &[Label]) -> u64 {
+    broker.runtime.metrics.counter(name, labels).get()
+}
+
+fn request(client: &zmq::Socket, payload: &str) -> (serde_json::Value, Vec<Vec<u8>>) {
+    client.send_multipart([FIRST, payload], 0).unwrap();
+    let mut reply = client.recv_multipart(0).unwrap();
+    let summary: serde_json::Value = serde_json::from_slice(&reply.remove(0)).unwrap();
+    (summary["fanout"].clone(), reply)
+}
+
+fn nothing_more(client: &zmq::Socket, wait_ms: i64) {
+    assert_eq!(client.poll(zmq::POLLIN, wait_ms).unwrap(), 0, "extra reply");
+}
+
+#[test]
+fn a_slow_request_is_answered_by_the_hedge() {
+    let broker = hedging_broker(20, 100);
+    // The slow worker joins first, so it gets the request.
+    let (_slow, slow_seen) = EchoWorker::start(&broker, "slow", Duration::from_millis(400));
+    let (_fast, fast_seen) = EchoWorker::start(&broker, "fast", Duration::ZERO);
+    let client = broker.client(b"cli");
+    settle();
+
+    let started = Instant::now();
+    let (summary, reply) = request(&client, "job");
+    assert!(started.elapsed() < Duration::from_millis(300));
+    assert_eq!(reply, [b"job".to_vec()]);
+    assert_eq!(summary["hedged"], true);
+    assert_eq!(summary["replies"][0]["worker"], "fast");
+    assert!(!slow_seen.recv().unwrap());
+    assert!(fast_seen.recv().unwrap());
+
+    // The slow worker's reply is dropped.
+    nothing_more(&client, 600);
+    assert_eq!(counter(&broker, "corky_fanout_hedged_total", &[]), 1);
+    assert_eq!(counter(&broker, "corky_fanout_hedge_wins_total", &[]), 1);
+}
+
+#[test]
+fn hedges_stay_within_the_cap() {
+    let broker = hedging_broker(10, 10);
+    let (_slow, _) = EchoWorker::start(&broker, "slow", Duration::from_millis(100));
+    let (_fast, _) = EchoWorker::start(&broker, "fast", Duration::ZERO);
+    let client = broker.client(b"cli");
+    settle();
+
+    for i in 0..20 {
+        let payload = format!("job-{}", i);
+        let (summary, reply) = request(&client, &payload);
+        assert_eq!(reply, [payload.into_bytes()]);
+        assert_eq!(summary["received"], 1);
+    }
+    nothing_more(&client, 300);
+    // 10% of 20 requests.
+    let hedged = counter(&broker, "corky_fanout_hedged_total", &[]);
+    assert!((1..=2).contains(&hedged), "{} hedges", hedged);
+    let capped = counter(
+        &broker,
+        "corky_fanout_hedges_skipped_total",
+        &[("reason", "cap")],
+    );
+    assert!(capped >= 1, "{} capped", capped);
+}
+
+#[test]
+fn no_hedges_while_every_worker_is_busy() {
+    let broker = hedging_broker(10, 100);
+    let (_a, _) = EchoWorker::start(&broker, "a", Duration::from_millis(150));
+    let (_b, _) = EchoWorker::start(&broker, "b", Duration::from_millis(150));
+    let client = broker.client(b"cli");
+    settle();
+
+    client.send_multipart([FIRST, "one"], 0).unwrap();
+    client.send_multipart([FIRST, "two"], 0).unwrap();
+    for _ in 0..2 {
+        let reply = client.recv_multipart(0).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&reply[0]).unwrap();
+        assert_eq!(summary["fanout"]["hedged"], false);
+    }
+    nothing_more(&client, 300);
+    assert_eq!(counter(&broker, "corky_fanout_hedged_total", &[]), 0);
+    assert_eq!(
+        counter(
+            &broker,
+            "corky_fanout_hedges_skipped_total",
+            &[("reason", "busy")]
+        ),
+        2
+    );
+}
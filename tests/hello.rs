@@ -0,0 +1,90 @@
+// The hello exchange through the broker: what a client is told for full,
+// partial and zero overlap with what it asks for, that the broker answers the
+// hello itself, and how the outcome decides whether a compressed reply
+// reaches the client as it is.
+
+mod common;
+
+use common::{settle, BrokerHarness};
+use corky_zmq::compress::{compress_frame, is_compressed};
+use corky_zmq::config::EndpointConfig;
+use corky_zmq::fanout::WORKER_READY;
+use corky_zmq::hello::Capability;
+
+fn broker(compression: bool) -> BrokerHarness {
+    BrokerHarness::start(move |cfg| {
+        cfg.compression.enabled = compression;
+        cfg.network.client_facing = vec![EndpointConfig {
+            address: cfg.network.client_facing_endpoint.clone(),
+            maxmsgsize: 1 << 20,
+            ..EndpointConfig::default()
+        }];
+    })
+}
+
+// The worker with the client's identity answers with one compressed frame;
+// returns the frame the client receives.
+fn compressed_reply(broker: &BrokerHarness, client: &zmq::Socket, identity: &[u8]) -> Vec<u8> {
+    let worker = broker.worker(identity);
+    worker
+        .send_multipart([WORKER_READY, br#"{"compression": ["zstd"]}"#], 0)
+        .unwrap();
+    settle();
+    let packed = compress_frame(&b"quote ".repeat(100), 3).unwrap();
+    worker.send_multipart([identity, &packed], 0).unwrap();
+    let mut reply = client.recv_multipart(0).unwrap();
+    assert_eq!(reply.len(), 1);
+    reply.remove(0)
+}
+
+#[test]
+fn full_overlap_passes_compressed_replies_through() {
+    let broker = broker(true);
+    let (client, hello) = broker.hello_client(b"full", &["compression", "chunking", "fanout"]);
+    assert_eq!(hello.features, ["compression", "chunking", "fanout"]);
+    assert_eq!(hello.capabilities, hello.features);
+    assert!(hello.unsupported.is_empty());
+    assert_eq!(hello.limits.max_message_bytes, Some(1 << 20));
+    assert_eq!(
+        hello.limits.max_frame_bytes,
+        Some(broker.config.compression.max_frame_bytes)
+    );
+    assert!(is_compressed(&compressed_reply(&broker, &client, b"full")));
+}
+
+#[test]
+fn partial_overlap_keeps_what_the_broker_has() {
+    let broker = broker(false);
+    let (_client, hello) = broker.hello_client(b"partial", &["compression", "fanout", "acks"]);
+    assert_eq!(hello.features, ["chunking", "fanout"]);
+    assert_eq!(hello.capabilities, ["fanout"]);
+    assert_eq!(hello.unsupported, ["acks"]);
+    assert!(hello.has(Capability::Fanout) && !hello.has(Capability::Compression));
+    assert_eq!(hello.limits.max_frame_bytes, None);
+}
+
+#[test]
+fn zero_overlap_and_no_hello_get_conservative_defaults() {
+    let broker = broker(true);
+    let (client, hello) = broker.hello_client(b"none", &["acks", "tracing"]);
+    assert!(hello.capabilities.is_empty());
+    assert_eq!(hello.unsupported, ["acks", "tracing"]);
+    assert_eq!(
+        compressed_reply(&broker, &client, b"none"),
+        b"quote ".repeat(100)
+    );
+
+    // No hello at all: the hello never reached a worker, and replies are
+    // decompressed just the same.
+    let silent = broker.client(b"silent");
+    settle();
+    assert_eq!(
+        compressed_reply(&broker, &silent, b"silent"),
+        b"quote ".repeat(100)
+    );
+    let worker = broker.worker(b"full");
+    worker.set_rcvtimeo(200).unwrap();
+    settle();
+    broker.hello_client(b"full", &[]);
+    assert!(worker.recv_multipart(0).is_err());
+}
@@ -0,0 +1,82 @@
+// Counts heap allocations on the forwarding hot path. With logging off, the
+// only allocations per relayed message should be the received frames and the
+// Vec holding them; socket labels and counters must not allocate.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use corky_zmq::broker::{LogDetail, SocketChannel};
+use corky_zmq::metrics::Registry;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(|c| c.get()) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const MESSAGES: usize = 200;
+const FRAMES: usize = 3;
+
+#[test]
+fn forwarding_does_not_allocate_for_naming() {
+    let context = zmq::Context::new();
+    let producer = context.socket(zmq::PAIR).unwrap();
+    let src = context.socket(zmq::PAIR).unwrap();
+    src.bind("inproc://alloc-src").unwrap();
+    producer.connect("inproc://alloc-src").unwrap();
+
+    let consumer = context.socket(zmq::PAIR).unwrap();
+    let dst = context.socket(zmq::PAIR).unwrap();
+    dst.bind("inproc://alloc-dst").unwrap();
+    consumer.connect("inproc://alloc-dst").unwrap();
+
+    let metrics = Registry::new();
+    let src = SocketChannel::new(src, "client_router", &metrics);
+    let dst = SocketChannel::new(dst, "worker_router", &metrics);
+
+    for i in 0..MESSAGES {
+        let payload = format!("payload {i}");
+        let frames: [&[u8]; FRAMES] = [b"id", b"header", payload.as_bytes()];
+        producer.send_multipart(frames, 0).unwrap();
+    }
+
+    COUNTING.with(|c| c.set(true));
+    for _ in 0..MESSAGES {
+        src.forward_to(&dst, LogDetail::Off);
+    }
+    COUNTING.with(|c| c.set(false));
+
+    // One allocation per non-empty frame plus one for the frame Vec.
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    assert!(
+        allocations <= MESSAGES * (FRAMES + 1),
+        "{} allocations for {} messages",
+        allocations,
+        MESSAGES
+    );
+    assert_eq!(src.received(), MESSAGES as u64);
+    assert_eq!(dst.sent(), MESSAGES as u64);
+
+    for _ in 0..MESSAGES {
+        assert_eq!(consumer.recv_multipart(0).unwrap().len(), FRAMES);
+    }
+}
@@ -0,0 +1,54 @@
+// Routing ids of the broker's connecting sockets, as seen by the remote
+// ROUTER: the configured id, or a generated one that survives restarts.
+
+mod common;
+
+use std::fs;
+
+use common::BrokerHarness;
+use corky_zmq::runtime::Runtime;
+
+// A ROUTER standing in for the HA peer; returns the identity of the first
+// heartbeat it receives from a broker started with `customize`.
+fn observed_identity(customize: impl FnOnce(&mut corky_zmq::config::Config)) -> (Vec<u8>, Runtime) {
+    let context = zmq::Context::new();
+    let peer = context.socket(zmq::ROUTER).unwrap();
+    peer.set_rcvtimeo(5000).unwrap();
+    let endpoint = format!("inproc://identity-peer-{}", std::process::id());
+    peer.bind(&endpoint).unwrap();
+    let broker = BrokerHarness::start_in(context.clone(), |cfg| {
+        cfg.ha.enabled = true;
+        cfg.ha.heartbeat_ms = 10;
+        cfg.ha.local_endpoint = "inproc://identity-local".into();
+        cfg.ha.peer_endpoint = endpoint;
+        customize(cfg);
+    });
+    let heartbeat = peer.recv_multipart(0).unwrap();
+    assert_eq!(heartbeat[1], b"primary");
+    (heartbeat[0].clone(), broker.runtime.clone())
+}
+
+#[test]
+fn remote_router_sees_the_configured_identity() {
+    let (identity, runtime) = observed_identity(|cfg| cfg.ha.routing_id = Some("edge-1".into()));
+    assert_eq!(identity, b"edge-1");
+    assert_eq!(
+        runtime.identities.list(),
+        [("ha".to_string(), b"edge-1".to_vec())]
+    );
+}
+
+#[test]
+fn generated_identity_is_stable_across_restarts() {
+    let dir = std::env::temp_dir().join(format!("corky-identity-it-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let file = dir.join("state").join("identity");
+    let file_name = file.to_string_lossy().into_owned();
+
+    let (first, _) = observed_identity(|cfg| cfg.identity.file = Some(file_name.clone()));
+    let base = fs::read_to_string(&file).unwrap();
+    assert_eq!(first, format!("{}/ha", base.trim()).into_bytes());
+    let (second, _) = observed_identity(|cfg| cfg.identity.file = Some(file_name.clone()));
+    assert_eq!(first, second);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,154 @@
+// Ingress metadata end to end: what a worker is told about a request over
+// TCP, that clients cannot forge it, and that a worker echoing every frame it
+// received does not hand the metadata back to the client.
+
+mod common;
+
+use std::net::TcpListener;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{propagate, BrokerHarness};
+use corky_zmq::config::EndpointConfig;
+use corky_zmq::ingress::{is_metadata, IngressMetadata};
+use corky_zmq::zap::ZapHandler;
+
+fn free_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp://{}", listener.local_addr().unwrap())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn stamping_broker(curve_secret_key: Option<String>) -> BrokerHarness {
+    let address = free_endpoint();
+    BrokerHarness::start(move |cfg| {
+        cfg.ingress.metadata = true;
+        cfg.network.client_facing = vec![EndpointConfig {
+            address,
+            name: "front".to_string(),
+            curve: curve_secret_key.is_some(),
+            ..EndpointConfig::default()
+        }];
+        cfg.auth.curve_secret_key = curve_secret_key.unwrap_or_default();
+    })
+}
+
+fn front(broker: &BrokerHarness) -> &str {
+    &broker.config.network.client_facing[0].address
+}
+
+#[test]
+fn workers_are_told_when_and_where_a_request_arrived() {
+    let broker = stamping_broker(None);
+    let worker = broker.worker(b"alice");
+    let client = broker.dealer(b"alice", front(&broker));
+    propagate();
+
+    let before = now_ms();
+    client.send("job", 0).unwrap();
+    let mut request = worker.recv_multipart(0).unwrap();
+    let metadata = IngressMetadata::take(&mut request).expect("metadata frame");
+    assert_eq!(request, [b"job".to_vec()]);
+    assert_eq!(metadata.endpoint, "front");
+    assert_eq!(metadata.address.as_deref(), Some("127.0.0.1"));
+    // NULL connections have no principal.
+    assert_eq!(metadata.principal, None);
+    assert!((before..=now_ms()).contains(&metadata.received_ms));
+
+    // Another request is stamped later on the broker's monotonic clock.
+    client.send("next", 0).unwrap();
+    let mut next = worker.recv_multipart(0).unwrap();
+    let later = IngressMetadata::take(&mut next).unwrap();
+    assert!(later.monotonic_us > metadata.monotonic_us);
+}
+
+#[test]
+fn clients_cannot_forge_metadata() {
+    let broker = stamping_broker(None);
+    let worker = broker.worker(b"mallory");
+    let client = broker.dealer(b"mallory", front(&broker));
+    propagate();
+
+    let forged = IngressMetadata {
+        received_ms: now_ms() + 3_600_000,
+        monotonic_us: 0,
+        endpoint: "internal".to_string(),
+        principal: Some("admin".to_string()),
+        address: None,
+    };
+    client
+        .send_multipart([forged.encode(), b"job".to_vec(), forged.encode()], 0)
+        .unwrap();
+    let mut request = worker.recv_multipart(0).unwrap();
+    let metadata = IngressMetadata::take(&mut request).unwrap();
+    assert_eq!(metadata.endpoint, "front");
+    assert_eq!(metadata.principal, None);
+    assert_eq!(request, [b"job".to_vec()]);
+}
+
+#[test]
+fn a_legacy_worker_echoing_everything_does_not_leak_metadata() {
+    let broker = stamping_broker(None);
+    let worker = broker.worker(b"bob");
+    let client = broker.dealer(b"bob", front(&broker));
+    propagate();
+
+    client.send("job", 0).unwrap();
+    let request = worker.recv_multipart(0).unwrap();
+    assert_eq!(request.len(), 2);
+    // Sends back [client_id] + everything it was given, metadata included.
+    let mut echo = vec![b"bob".to_vec()];
+    echo.extend(request);
+    worker.send_multipart(echo, 0).unwrap();
+    assert_eq!(
+        worker.recv_multipart(0).unwrap(),
+        [b"bob".to_vec(), b"job".to_vec()]
+    );
+    let reply = client.recv_multipart(0).unwrap();
+    assert_eq!(reply, [b"job".to_vec()]);
+    assert!(!reply.iter().any(|f| is_metadata(f)));
+}
+
+#[test]
+fn nothing_is_added_unless_enabled() {
+    let broker = BrokerHarness::start(|_| {});
+    let worker = broker.worker(b"carol");
+    let client = broker.client(b"carol");
+    propagate();
+    client.send("job", 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap(), [b"job".to_vec()]);
+}
+
+#[test]
+fn a_curve_client_is_identified_by_its_public_key() {
+    if zmq::has("curve") != Some(true) {
+        return;
+    }
+    let server = zmq::CurveKeyPair::new().unwrap();
+    let broker = stamping_broker(Some(zmq::z85_encode(&server.secret_key).unwrap()));
+    let _zap = ZapHandler::start(&broker.context, &broker.config.auth, &broker.runtime).unwrap();
+    let worker = broker.worker(b"dave");
+    let keys = zmq::CurveKeyPair::new().unwrap();
+    let client = broker.context.socket(zmq::DEALER).unwrap();
+    client.set_identity(b"dave").unwrap();
+    client.set_linger(0).unwrap();
+    client.set_rcvtimeo(5000).unwrap();
+    client.set_curve_serverkey(&server.public_key).unwrap();
+    client.set_curve_publickey(&keys.public_key).unwrap();
+    client.set_curve_secretkey(&keys.secret_key).unwrap();
+    client.connect(front(&broker)).unwrap();
+    propagate();
+
+    client.send("job", 0).unwrap();
+    let mut request = worker.recv_multipart(0).unwrap();
+    let metadata = IngressMetadata::take(&mut request).unwrap();
+    assert_eq!(
+        metadata.principal,
+        Some(zmq::z85_encode(&keys.public_key).unwrap())
+    );
+}
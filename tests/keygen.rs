@@ -0,0 +1,48 @@
+// `corky-zmq keygen`: the keypair it writes, that it keeps existing files
+// without --force, and that --show prints the same public key again. A libzmq
+// without CURVE makes it fail with a message instead.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn keygen(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_corky-zmq"))
+        .arg("keygen")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).trim().to_string()
+}
+
+#[test]
+fn keygen_writes_a_pair_and_shows_its_public_key() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("corky-keygen-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let out_dir = dir.to_str().unwrap();
+    let out = keygen(&["--out-dir", out_dir, "--name", "edge"]);
+    if zmq::has("curve") != Some(true) {
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("without CURVE"));
+        assert!(!dir.exists());
+        return;
+    }
+    assert!(out.status.success(), "{:?}", out);
+    let public = stdout(&out);
+    assert_eq!(public.len(), 40);
+    let published = fs::read_to_string(dir.join("edge.key.pub")).unwrap();
+    assert!(published.ends_with(&format!("\n{}\n", public)));
+
+    let again = keygen(&["--out-dir", out_dir, "--name", "edge"]);
+    assert_eq!(again.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&again.stderr).contains("--force"));
+
+    let secret = dir.join("edge.key");
+    let shown = keygen(&["--show", secret.to_str().unwrap()]);
+    assert!(shown.status.success(), "{:?}", shown);
+    assert_eq!(stdout(&shown), public);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,79 @@
+// Connection limits over TCP: once client_router holds its cap, the next
+// client is refused during the handshake while the connected ones keep
+// exchanging requests and replies, and a cap raised through the admin
+// socket lets a new client in. inproc is never counted, hence the TCP
+// endpoint; workers stay on inproc.
+
+mod common;
+
+use std::net::TcpListener;
+
+use common::{propagate, BrokerHarness};
+use corky_zmq::admin::handle_command;
+use corky_zmq::zap::ZapHandler;
+
+fn free_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp://{}", listener.local_addr().unwrap())
+}
+
+fn round_trip(client: &zmq::Socket, worker: &zmq::Socket, id: &[u8]) {
+    client.send("request", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+    worker.send_multipart([id, b"reply"], 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+    assert_eq!(client.recv_bytes(0).unwrap(), b"reply");
+}
+
+#[test]
+fn clients_over_the_cap_are_refused_while_others_keep_working() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.network.client_facing_endpoint = free_endpoint();
+        cfg.limits
+            .max_per_socket
+            .insert("client_router".to_string(), 2);
+    });
+    let _zap = ZapHandler::start(&broker.context, &broker.config.auth, &broker.runtime).unwrap();
+
+    let mut pairs = Vec::new();
+    for id in [&b"one"[..], b"two"] {
+        let worker = broker.worker(id);
+        let client = broker.client(id);
+        propagate();
+        pairs.push((id, client, worker));
+    }
+    assert!(handle_command(&broker.runtime, "health").starts_with("near_limit\n"));
+
+    let late_worker = broker.worker(b"three");
+    late_worker.set_rcvtimeo(500).unwrap();
+    let late = broker.client(b"three");
+    propagate();
+    // Once refused the socket may have no pipe left, and a blocking send
+    // would wait for one forever.
+    let _ = late.send("request", zmq::DONTWAIT);
+    assert!(late_worker.recv_bytes(0).is_err(), "refused over the cap");
+    let rejected = broker
+        .runtime
+        .metrics
+        .counter(
+            "corky_connections_rejected_total",
+            &[("socket", "client_router"), ("reason", "socket")],
+        )
+        .get();
+    assert_eq!(rejected, 1);
+    for (id, client, worker) in &pairs {
+        round_trip(client, worker, id);
+    }
+    assert!(handle_command(&broker.runtime, "limits").contains("client_router max=2 connections=2"));
+
+    // The refused socket stays refused; a new one fits under the raised cap.
+    assert_eq!(
+        handle_command(&broker.runtime, "limits socket client_router 3"),
+        "OK"
+    );
+    let worker = broker.worker(b"four");
+    let client = broker.client(b"four");
+    propagate();
+    round_trip(&client, &worker, b"four");
+    assert!(late_worker.recv_bytes(0).is_err());
+}
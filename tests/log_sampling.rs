@@ -0,0 +1,81 @@
+// [logging] sample_every: the broker logs 1 of every N messages from each
+// socket and still forwards all of them. Its own binary, as it installs a
+// logger and sets the process-wide rate.
+
+use std::sync::Mutex;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use corky_zmq::broker::{set_sample_every, LogDetail, SocketChannel};
+use corky_zmq::metrics::Registry;
+
+static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        LINES.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture;
+
+// A channel named `name` and the peers feeding and draining it.
+fn pair(context: &zmq::Context, name: &str) -> (zmq::Socket, zmq::Socket) {
+    let outside = context.socket(zmq::PAIR).unwrap();
+    let inside = context.socket(zmq::PAIR).unwrap();
+    inside.bind(&format!("inproc://sampling-{}", name)).unwrap();
+    outside
+        .connect(&format!("inproc://sampling-{}", name))
+        .unwrap();
+    (outside, inside)
+}
+
+#[test]
+fn each_socket_logs_one_in_n_and_everything_is_forwarded() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(LevelFilter::Info);
+    set_sample_every(3);
+
+    let context = zmq::Context::new();
+    let metrics = Registry::new();
+    let (busy_peer, busy) = pair(&context, "busy");
+    let (quiet_peer, quiet) = pair(&context, "quiet");
+    let (sink, dst) = pair(&context, "dst");
+    let busy = SocketChannel::new(busy, "client_router", &metrics);
+    let quiet = SocketChannel::new(quiet, "worker_router", &metrics);
+    let dst = SocketChannel::new(dst, "direct_router", &metrics);
+
+    for i in 0..7 {
+        busy_peer.send(format!("busy {}", i).as_str(), 0).unwrap();
+        busy.forward_to(&dst, LogDetail::Summary);
+    }
+    quiet_peer.send("quiet", 0).unwrap();
+    quiet.forward_to(&dst, LogDetail::Summary);
+    for _ in 0..8 {
+        sink.recv_multipart(0).unwrap();
+    }
+
+    let lines = LINES.lock().unwrap().clone();
+    let forwarded = |from: &str| {
+        let prefix = format!("(Broker) Forwarding {} -> direct_router", from);
+        lines.iter().filter(|l| l.starts_with(&prefix)).count()
+    };
+    assert_eq!(forwarded("client_router"), 3, "{:#?}", lines);
+    assert_eq!(forwarded("worker_router"), 1, "{:#?}", lines);
+    assert!(lines.contains(
+        &"(Broker) Sampled 1 of every 3 messages from client_router, 1 seen so far".to_string()
+    ));
+    assert!(lines
+        .iter()
+        .any(|l| l.contains("messages from worker_router, 1 seen")));
+    assert_eq!(busy.received(), 7);
+    assert_eq!(dst.sent(), 8);
+}
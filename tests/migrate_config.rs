@@ -0,0 +1,82 @@
+// `corky-zmq migrate-config`: an old file gains config_version with its
+// comments and permissions kept, --dry-run leaves it alone, and a file newer
+// than the binary is refused by every command.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const OLD: &str = "\
+# Broker A, kept since the first release
+[logging]
+level = \"debug\"  # while testing
+";
+
+fn scratch(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("corky-migrate-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn corky(command: &str, path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_corky-zmq"))
+        .arg(command)
+        .arg("--config")
+        .arg(path)
+        .args(args)
+        .env_remove("CORKY_PROFILE")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn an_old_file_is_migrated_in_place() {
+    let path = scratch("old.toml", OLD);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let dry = corky("migrate-config", &path, &["--dry-run"]);
+    assert!(dry.status.success(), "{:?}", dry);
+    assert!(String::from_utf8_lossy(&dry.stdout).contains("config_version = 2\n"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), OLD);
+
+    let out = corky("migrate-config", &path, &[]);
+    assert!(out.status.success(), "{:?}", out);
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with("Migrated "));
+    let migrated = fs::read_to_string(&path).unwrap();
+    assert!(
+        migrated.starts_with("# Schema of this file"),
+        "{}",
+        migrated
+    );
+    assert!(migrated.ends_with(OLD));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let again = corky("migrate-config", &path, &[]);
+    assert!(String::from_utf8_lossy(&again.stdout).contains("at config_version 2 already"));
+    assert!(corky("check-config", &path, &["-q"]).status.success());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn a_newer_file_asks_for_a_newer_binary() {
+    let path = scratch("new.toml", "config_version = 3\n[logging]\n");
+    for command in ["check-config", "migrate-config"] {
+        let out = corky(command, &path, &[]);
+        assert_eq!(out.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("upgrade corky-zmq"), "{}", stderr);
+    }
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "config_version = 3\n[logging]\n"
+    );
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,81 @@
+// The service binary with no config file in ~/.corky: it refuses to start
+// unless --use-defaults or CORKY_ALLOW_MISSING_CONFIG lets it run on the
+// defaults, and a broken file is never replaced by them.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn home(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("corky-home-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// --print-config-and-exit loads the config exactly as a run would, then exits.
+fn print_config(home: &PathBuf, args: &[&str], allow: Option<&str>) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_corky-zmq"));
+    command
+        .arg("--print-config-and-exit")
+        .args(args)
+        .env("HOME", home)
+        .env_remove("CORKY_CONFIG")
+        .env_remove("CORKY_PROFILE")
+        .env_remove("CORKY_ALLOW_MISSING_CONFIG");
+    if let Some(allow) = allow {
+        command.env("CORKY_ALLOW_MISSING_CONFIG", allow);
+    }
+    command.output().unwrap()
+}
+
+#[test]
+fn a_missing_config_fails_unless_defaults_are_allowed() {
+    let home = home("missing");
+    let refused = print_config(&home, &[], None);
+    assert_eq!(refused.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(
+        stderr.contains("Configuration file not found"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("--use-defaults"), "{}", stderr);
+
+    for (args, allow) in [(&["--use-defaults"][..], None), (&[][..], Some("1"))] {
+        let out = print_config(&home, args, allow);
+        assert!(out.status.success(), "{:?}", out);
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.starts_with("# Effective config: defaults, no file\n"));
+        assert!(stdout.contains("level = \"info\"  # default"), "{}", stdout);
+    }
+    assert_eq!(print_config(&home, &[], Some("0")).status.code(), Some(1));
+
+    let profile = print_config(&home, &["--use-defaults", "--profile", "dev"], None);
+    assert_eq!(profile.status.code(), Some(1));
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn a_broken_config_is_not_replaced_by_the_defaults() {
+    let home = home("broken");
+    fs::create_dir_all(home.join(".corky")).unwrap();
+    fs::write(home.join(".corky").join("config.toml"), "[logging\n").unwrap();
+    let out = print_config(&home, &["--use-defaults"], None);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("Failed to parse config as TOML"),
+        "{}",
+        stderr
+    );
+
+    // A file named explicitly must exist, defaults or not.
+    let named = print_config(
+        &home,
+        &["--use-defaults", "--config", "/nonexistent.toml"],
+        None,
+    );
+    assert_eq!(named.status.code(), Some(1));
+    fs::remove_dir_all(&home).unwrap();
+}
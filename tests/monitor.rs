@@ -0,0 +1,73 @@
+// `corky-zmq monitor`: it subscribes to the event stream and the traffic
+// samples and prints each message as a line of its own.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use corky_zmq::events::BrokerEvent;
+use corky_zmq::multipart::Multipart;
+use corky_zmq::sample::{SampleRules, Sampler};
+
+// The first line the monitor prints with `flag` set to a PUB socket that
+// keeps publishing `message`, as the monitor may still be connecting.
+fn first_line(flag: &str, message: Multipart) -> String {
+    let context = zmq::Context::new();
+    let publisher = context.socket(zmq::PUB).unwrap();
+    publisher.set_linger(0).unwrap();
+    publisher.bind("tcp://127.0.0.1:*").unwrap();
+    let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+
+    let mut monitor = Command::new(env!("CARGO_BIN_EXE_corky-zmq"))
+        .args(["monitor", flag, &endpoint])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = monitor.stdout.take().unwrap();
+    let (lines, printed) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if lines.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let line = loop {
+        assert!(Instant::now() < deadline, "the monitor printed nothing");
+        message.send_copy(&publisher, 0).unwrap();
+        if let Ok(line) = printed.recv_timeout(Duration::from_millis(100)) {
+            break line;
+        }
+    };
+    monitor.kill().unwrap();
+    monitor.wait().unwrap();
+    line
+}
+
+#[test]
+fn monitor_prints_each_event_it_receives() {
+    let event = BrokerEvent::RequestTimeout {
+        client: b"c-1".to_vec(),
+    };
+    assert_eq!(
+        first_line("--events", event.to_message(0)),
+        "1970-01-01T00:00:00.000Z request.timeout client=c-1"
+    );
+}
+
+#[test]
+fn monitor_prints_each_sample_it_receives() {
+    let rules = SampleRules::default();
+    rules.set(b"ticker.", 1.0).unwrap();
+    let message = [b"ticker.BTCUSD".to_vec(), b"64000.5".to_vec()];
+    let sample = Sampler::new(1).sample(&rules, &message).unwrap();
+    assert_eq!(
+        first_line("--sample", sample),
+        r#"sample [2 frames, 20 bytes] skipped=0: topic "ticker.BTCUSD": 64000.5"#
+    );
+}
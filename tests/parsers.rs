@@ -0,0 +1,175 @@
+// Property tests over the parsers that see untrusted bytes: the log formatter,
+// the protocol headers, the chunk reassembler and the journal reader must
+// return an answer or an error for any input, never panic. The regression
+// cases at the bottom are the inputs that did panic or over-allocate when the
+// fuzz targets (fuzz/) were first run.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::{Config as ProptestConfig, TestRunner};
+
+use corky_zmq::chunk::{ChunkAssembler, ChunkHeader};
+use corky_zmq::fanout::{FanoutSpec, ScatterGather};
+use corky_zmq::format::{format_message, format_part, Envelope, RenderStyle};
+use corky_zmq::journal::{scan_segment, JournalRecord, SEGMENT_MAGIC};
+use corky_zmq::metrics::Registry;
+use corky_zmq::schedule::ScheduleSpec;
+
+fn frame() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        vec(any::<u8>(), 0..64),
+        // Mostly-valid JSON, to get past the first byte checks.
+        "\\{\"(fanout|delay_ms|deliver_at|count|timeout_ms)\": [0-9a-z\\[\\]{}\":, ]{0,40}\\}"
+            .prop_map(String::into_bytes),
+        // A gzip or zlib header, then anything, for the decompressor.
+        vec(any::<u8>(), 0..64).prop_map(|b| [&b"\x1f\x8b\x08\0\0\0\0\0\0\x03"[..], &b].concat()),
+        vec(any::<u8>(), 0..64).prop_map(|b| [&b"\x78\x9c"[..], &b].concat()),
+        Just(
+            ChunkHeader {
+                transfer_id: 1,
+                index: 0,
+                total: 2
+            }
+            .encode()
+            .to_vec()
+        ),
+    ]
+}
+
+fn runner() -> TestRunner {
+    TestRunner::new(ProptestConfig {
+        cases: 512,
+        ..ProptestConfig::default()
+    })
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!(
+        "{}/tests/fixtures/adversarial/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    std::fs::read(path).unwrap()
+}
+
+fn use_headers(frame: &[u8]) {
+    let now = Instant::now();
+    if let Some(Ok(spec)) = FanoutSpec::parse(frame) {
+        let mut sg = ScatterGather::new(&Registry::new());
+        sg.workers.add(b"w1");
+        let _ = sg.start(b"cli", spec, &[frame.to_vec()], now, |_| Ok(()));
+        let _ = sg.expire(now);
+    }
+    if let Some(Ok(spec)) = ScheduleSpec::parse(frame) {
+        let _ = spec.deadline(now, SystemTime::now());
+    }
+}
+
+#[test]
+fn formatter_accepts_anything() {
+    runner()
+        .run(&vec(frame(), 0..6), |message| {
+            for part in &message {
+                let _ = format_part(part, RenderStyle::Compact);
+                let _ = format_part(part, RenderStyle::Pretty);
+            }
+            let _ = format_message(&message, Envelope::Topic, RenderStyle::Compact);
+            let _ = format_message(&message, Envelope::Routed, RenderStyle::Pretty);
+            let _ = format_message(&message, Envelope::PubSub, RenderStyle::Compact);
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn headers_parse_or_refuse_anything() {
+    runner()
+        .run(&frame(), |frame| {
+            use_headers(&frame);
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn reassembly_accepts_anything() {
+    runner()
+        .run(&vec(vec(frame(), 0..4), 1..8), |messages| {
+            let mut assembler = ChunkAssembler::new();
+            for message in messages {
+                let _ = assembler.push(message);
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn journal_reader_accepts_anything() {
+    runner()
+        .run(&vec(any::<u8>(), 0..256), |bytes| {
+            let _ = JournalRecord::decode_payload(0, &bytes);
+            let mut segment = SEGMENT_MAGIC.to_vec();
+            segment.extend_from_slice(&1u32.to_le_bytes());
+            segment.extend_from_slice(&bytes);
+            let _ = scan_segment(&segment, "prop", None);
+            Ok(())
+        })
+        .unwrap();
+}
+
+//
+// ---- Regressions ----
+//
+
+#[test]
+fn huge_timeouts_and_delays_do_not_overflow_the_clock() {
+    let max = u64::MAX;
+    let headers = [
+        format!(r#"{{"fanout": {{"count": 1, "timeout_ms": {}}}}}"#, max),
+        format!(r#"{{"delay_ms": {}}}"#, max),
+        format!(r#"{{"deliver_at": {}}}"#, max),
+    ];
+    for header in &headers {
+        use_headers(header.as_bytes());
+    }
+    let spec = ScheduleSpec::parse(headers[1].as_bytes()).unwrap().unwrap();
+    let now = Instant::now();
+    assert!(spec.deadline(now, SystemTime::now()) > now + Duration::from_secs(86_400));
+}
+
+#[test]
+fn an_announced_chunk_total_is_not_preallocated() {
+    let header = ChunkHeader {
+        transfer_id: 7,
+        index: 0,
+        total: u32::MAX,
+    };
+    let mut assembler = ChunkAssembler::new();
+    // A 1MB first chunk would have reserved 4PB.
+    let message = vec![header.encode().to_vec(), vec![0u8; 1 << 20]];
+    assert!(assembler.push(message).unwrap().is_none());
+    assert_eq!(assembler.pending(), 1);
+}
+
+#[test]
+fn deeply_nested_json_is_formatted() {
+    let nested = fixture("deep_nesting.json");
+    assert!(!format_part(&nested, RenderStyle::Pretty).is_empty());
+    assert!(!format_message(
+        &[b"id".to_vec(), nested],
+        Envelope::Routed,
+        RenderStyle::Pretty
+    )
+    .is_empty());
+}
+
+#[test]
+fn a_huge_record_length_is_reported_as_truncation() {
+    let scan = scan_segment(&fixture("journal_huge_length.seg"), "huge", None);
+    assert!(scan.header.is_some());
+    assert!(scan.records.is_empty());
+    assert_eq!(scan.problems, ["truncated record at offset 32"]);
+}
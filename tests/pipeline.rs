@@ -0,0 +1,174 @@
+// PUSH/PULL pipeline relayed by the broker: every task is delivered exactly
+// once across several producers and consumers, and stalled consumers push
+// back on producers instead of losing tasks.
+
+mod common;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use common::{settle, BrokerHarness};
+use corky_zmq::metrics::Value;
+
+fn pipeline_broker() -> BrokerHarness {
+    let tag = next_tag();
+    BrokerHarness::start(|cfg| {
+        cfg.pipeline.enabled = true;
+        cfg.pipeline.producer_endpoint = format!("inproc://pipeline-{tag}-in");
+        cfg.pipeline.consumer_endpoint = format!("inproc://pipeline-{tag}-out");
+        cfg.pipeline.max_task_bytes = 1024;
+    })
+}
+
+fn next_tag() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+// High-water marks only apply to connections made after they are set.
+fn producer(broker: &BrokerHarness, hwm: i32) -> zmq::Socket {
+    let socket = broker.context.socket(zmq::PUSH).unwrap();
+    socket.set_linger(0).unwrap();
+    socket.set_sndhwm(hwm).unwrap();
+    socket
+        .connect(&broker.config.pipeline.producer_endpoint)
+        .unwrap();
+    socket
+}
+
+fn consumer(broker: &BrokerHarness, hwm: i32) -> zmq::Socket {
+    let socket = broker.context.socket(zmq::PULL).unwrap();
+    socket.set_linger(0).unwrap();
+    socket.set_rcvhwm(hwm).unwrap();
+    socket.set_rcvtimeo(5000).unwrap();
+    socket
+        .connect(&broker.config.pipeline.consumer_endpoint)
+        .unwrap();
+    socket
+}
+
+fn counter(broker: &BrokerHarness, name: &str, socket: &str) -> u64 {
+    broker
+        .runtime
+        .metrics
+        .snapshot()
+        .into_iter()
+        .find(|s| s.name == name && s.labels.iter().all(|l| l.1 == socket))
+        .map(|s| match s.value {
+            Value::Counter(v) => v,
+            _ => 0,
+        })
+        .unwrap_or(0)
+}
+
+#[test]
+fn tasks_are_delivered_exactly_once() {
+    const PRODUCERS: usize = 3;
+    const CONSUMERS: usize = 2;
+    const TASKS: usize = 2000;
+    let broker = pipeline_broker();
+    let consumers: Vec<_> = (0..CONSUMERS).map(|_| consumer(&broker, 1000)).collect();
+    let producers: Vec<_> = (0..PRODUCERS).map(|_| producer(&broker, 1000)).collect();
+    settle();
+
+    let senders: Vec<_> = producers
+        .into_iter()
+        .enumerate()
+        .map(|(p, socket)| {
+            thread::spawn(move || {
+                for t in 0..TASKS {
+                    socket
+                        .send_multipart([p.to_string().as_bytes(), t.to_string().as_bytes()], 0)
+                        .unwrap();
+                }
+                socket
+            })
+        })
+        .collect();
+    let receivers: Vec<_> = consumers
+        .into_iter()
+        .map(|socket| {
+            thread::spawn(move || {
+                socket.set_rcvtimeo(500).unwrap();
+                let mut tasks = Vec::new();
+                while let Ok(task) = socket.recv_multipart(0) {
+                    tasks.push(task);
+                }
+                tasks
+            })
+        })
+        .collect();
+    let _producers: Vec<_> = senders.into_iter().map(|t| t.join().unwrap()).collect();
+    let mut seen = HashSet::new();
+    let mut per_consumer = Vec::new();
+    for receiver in receivers {
+        let tasks = receiver.join().unwrap();
+        per_consumer.push(tasks.len());
+        for task in tasks {
+            assert!(seen.insert(task), "task delivered twice");
+        }
+    }
+    assert_eq!(seen.len(), PRODUCERS * TASKS);
+    assert!(per_consumer.iter().all(|&n| n > 0), "{:?}", per_consumer);
+    assert_eq!(
+        counter(&broker, "corky_broker_sent_total", "pipeline_push"),
+        6000
+    );
+    assert_eq!(
+        counter(&broker, "corky_broker_dropped_total", "pipeline_pull"),
+        0
+    );
+}
+
+#[test]
+fn stalled_consumers_push_back_on_producers() {
+    let broker = pipeline_broker();
+    let consumer = consumer(&broker, 100);
+    let producer = producer(&broker, 100);
+    settle();
+
+    // Nobody reads: the broker holds one task, the queues fill, and the
+    // producer eventually cannot send.
+    let mut sent = 0u64;
+    let mut blocked = false;
+    for i in 0..200_000u64 {
+        match producer.send(&i.to_be_bytes()[..], zmq::DONTWAIT) {
+            Ok(()) => sent += 1,
+            Err(zmq::Error::EAGAIN) => {
+                // Give the broker a moment to drain what it can.
+                thread::sleep(Duration::from_millis(100));
+                if producer.send(&i.to_be_bytes()[..], zmq::DONTWAIT).is_ok() {
+                    sent += 1;
+                    continue;
+                }
+                blocked = true;
+                break;
+            }
+            Err(e) => panic!("send failed: {}", e),
+        }
+    }
+    assert!(
+        blocked,
+        "producer never saw backpressure after {} tasks",
+        sent
+    );
+    assert!(counter(&broker, "corky_pipeline_stalls_total", "") > 0);
+
+    // Once the consumer resumes, every accepted task arrives in order.
+    for expected in 0..sent {
+        let task = consumer.recv_bytes(0).unwrap();
+        assert_eq!(task, expected.to_be_bytes());
+    }
+    assert_eq!(
+        counter(&broker, "corky_broker_dropped_total", "pipeline_push"),
+        0
+    );
+
+    // Oversized tasks are the only ones dropped.
+    producer.send(vec![0u8; 2048], 0).unwrap();
+    producer.send("small", 0).unwrap();
+    assert_eq!(consumer.recv_bytes(0).unwrap(), b"small");
+    assert_eq!(counter(&broker, "corky_pipeline_oversized_total", ""), 1);
+}
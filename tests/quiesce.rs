@@ -0,0 +1,118 @@
+// Quiesce over TCP: a client connected before draining keeps its request and
+// reply, a client connecting while draining never gets through, and new
+// clients connect again after unquiesce. inproc never consults the ZAP
+// handler, hence the TCP endpoints; workers stay on inproc.
+
+mod common;
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use common::{propagate, BrokerHarness};
+use corky_zmq::admin::handle_command;
+use corky_zmq::zap::ZapHandler;
+
+fn free_endpoint() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp://{}", listener.local_addr().unwrap())
+}
+
+fn gauge(
+    broker: &BrokerHarness,
+    name: &'static str,
+    labels: &[(&'static str, &'static str)],
+) -> i64 {
+    broker.runtime.metrics.gauge(name, labels).get()
+}
+
+// Gauges are refreshed on a timer, so poll until one reaches `value`.
+fn wait_for_gauge(
+    broker: &BrokerHarness,
+    name: &'static str,
+    labels: &[(&'static str, &'static str)],
+    value: i64,
+) {
+    for _ in 0..50 {
+        if gauge(broker, name, labels) == value {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!(
+        "{} is {}, wanted {}",
+        name,
+        gauge(broker, name, labels),
+        value
+    );
+}
+
+#[test]
+fn draining_keeps_existing_clients_and_refuses_new_ones() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.network.client_facing_endpoint = free_endpoint();
+        cfg.network.client_to_client_endpoint = free_endpoint();
+    });
+    let _zap = ZapHandler::start(&broker.context, &broker.config.auth, &broker.runtime).unwrap();
+    let connections = [("socket", "client_router")];
+
+    let worker = broker.worker(b"early");
+    let early = broker.client(b"early");
+    propagate();
+    early.send("request", 0).unwrap();
+    assert_eq!(worker.recv_bytes(0).unwrap(), b"request");
+    wait_for_gauge(&broker, "corky_broker_in_flight_requests", &[], 1);
+
+    assert_eq!(handle_command(&broker.runtime, "quiesce"), "OK");
+    assert_eq!(handle_command(&broker.runtime, "health"), "draining");
+    assert_eq!(gauge(&broker, "corky_quiesce_draining", &[]), 1);
+
+    let late_worker = broker.worker(b"late");
+    late_worker.set_rcvtimeo(500).unwrap();
+    let late = broker.client(b"late");
+    propagate();
+    late.send("request", 0).unwrap();
+    assert!(late_worker.recv_bytes(0).is_err(), "refused while draining");
+
+    // The early client's reply still arrives.
+    worker.send_multipart([&b"early"[..], b"reply"], 0).unwrap();
+    assert_eq!(worker.recv_multipart(0).unwrap().len(), 2); // echo
+    assert_eq!(early.recv_bytes(0).unwrap(), b"reply");
+    wait_for_gauge(&broker, "corky_broker_in_flight_requests", &[], 0);
+    wait_for_gauge(&broker, "corky_broker_connections", &connections, 1);
+
+    // A refused socket stays refused; a new one connects after unquiesce.
+    assert_eq!(handle_command(&broker.runtime, "unquiesce"), "OK");
+    assert_eq!(handle_command(&broker.runtime, "health"), "ok");
+    let again_worker = broker.worker(b"again");
+    let again = broker.client(b"again");
+    propagate();
+    again.send("request", 0).unwrap();
+    assert_eq!(again_worker.recv_bytes(0).unwrap(), b"request");
+    wait_for_gauge(&broker, "corky_broker_connections", &connections, 2);
+}
+
+#[test]
+fn direct_peers_are_refused_while_draining() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.network.client_to_client_endpoint = free_endpoint();
+    });
+    let _zap = ZapHandler::start(&broker.context, &broker.config.auth, &broker.runtime).unwrap();
+    let alice = broker.direct_peer(b"alice");
+    propagate();
+    handle_command(&broker.runtime, "quiesce");
+    let bob = broker.direct_peer(b"bob");
+    bob.set_rcvtimeo(500).unwrap();
+    propagate();
+
+    alice.send_multipart([&b"bob"[..], b"hi"], 0).unwrap();
+    bob.send_multipart([&b"alice"[..], b"hi"], 0).unwrap();
+    assert!(bob.recv_bytes(0).is_err());
+    assert!(alice.recv_multipart(zmq::DONTWAIT).is_err());
+    wait_for_gauge(
+        &broker,
+        "corky_broker_connections",
+        &[("socket", "direct_router")],
+        1,
+    );
+}
@@ -0,0 +1,113 @@
+// Request quotas in the broker: a tiny rolling quota is used up, further
+// requests get a quota_exceeded reply with the reset time, and requests are
+// admitted again once it has passed; usage outlives a broker restart.
+
+mod common;
+
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use common::{propagate, BrokerHarness};
+use corky_zmq::config::{Config, QuotaLimit};
+use corky_zmq::quota::{now_ms, QuotaWindow};
+
+fn with_quota(cfg: &mut Config, limit: u64, window: QuotaWindow, window_ms: u64, state: &Path) {
+    cfg.quota.enabled = true;
+    cfg.quota.state_file = Some(state.display().to_string());
+    cfg.quota.services.insert(
+        "svc".to_string(),
+        QuotaLimit {
+            limit,
+            window,
+            window_ms,
+        },
+    );
+}
+
+fn state_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("corky-quota-{}-{}.json", name, std::process::id()))
+}
+
+// Sends one request and returns what the client got back: the worker's answer
+// or the broker's rejection.
+fn request(client: &zmq::Socket, worker: &zmq::Socket, payload: &str) -> Vec<Vec<u8>> {
+    client.send(payload, 0).unwrap();
+    // A rejection comes straight back; anything else goes to the worker.
+    let mut items = [
+        client.as_poll_item(zmq::POLLIN),
+        worker.as_poll_item(zmq::POLLIN),
+    ];
+    zmq::poll(&mut items, 500).unwrap();
+    if items[1].is_readable() {
+        let request = worker.recv_multipart(0).unwrap();
+        worker
+            .send_multipart([b"svc".to_vec(), request[0].clone()], 0)
+            .unwrap();
+        // The broker's echo to the worker.
+        worker.recv_multipart(0).unwrap();
+    }
+    client.recv_multipart(0).unwrap()
+}
+
+fn rejection(reply: &[Vec<u8>]) -> serde_json::Value {
+    let error: serde_json::Value = serde_json::from_slice(&reply[0]).expect("a JSON rejection");
+    assert_eq!(error["error"]["code"], "quota_exceeded");
+    error["error"].clone()
+}
+
+#[test]
+fn a_used_up_quota_rejects_until_it_resets() {
+    let state = state_file("reset");
+    let broker = BrokerHarness::start(|cfg| {
+        with_quota(cfg, 2, QuotaWindow::Rolling, 480, &state);
+    });
+    let worker = broker.worker(b"svc");
+    let client = broker.client(b"svc");
+    propagate();
+
+    assert_eq!(request(&client, &worker, "one"), [b"one".to_vec()]);
+    assert_eq!(request(&client, &worker, "two"), [b"two".to_vec()]);
+    let error = rejection(&request(&client, &worker, "three"));
+    assert_eq!(error["scope"], "service");
+    assert_eq!(error["name"], "svc");
+    assert_eq!(error["limit"], 2);
+    assert_eq!(error["window"], "rolling");
+    let reset_ms = error["reset_ms"].as_u64().unwrap();
+    assert!(reset_ms > now_ms() && reset_ms <= now_ms() + 480);
+    assert!(error["retry_after_ms"].as_u64().unwrap() <= 480);
+
+    thread::sleep(Duration::from_millis(
+        reset_ms.saturating_sub(now_ms()) + 20,
+    ));
+    assert_eq!(request(&client, &worker, "four"), [b"four".to_vec()]);
+    let _ = std::fs::remove_file(&state);
+}
+
+#[test]
+fn usage_outlives_a_restart() {
+    let state = state_file("restart");
+    let start = || {
+        BrokerHarness::start(|cfg| {
+            with_quota(cfg, 2, QuotaWindow::Day, 0, &state);
+        })
+    };
+    {
+        let broker = start();
+        let worker = broker.worker(b"svc");
+        let client = broker.client(b"svc");
+        propagate();
+        assert_eq!(request(&client, &worker, "one"), [b"one".to_vec()]);
+        assert_eq!(request(&client, &worker, "two"), [b"two".to_vec()]);
+    }
+    // Saved on shutdown.
+    assert!(state.exists());
+    let broker = start();
+    let worker = broker.worker(b"svc");
+    let client = broker.client(b"svc");
+    propagate();
+    let error = rejection(&request(&client, &worker, "three"));
+    assert_eq!(error["window"], "day");
+    assert_eq!(error["reset_ms"].as_u64().unwrap() % (24 * 3600 * 1000), 0);
+    std::fs::remove_file(&state).unwrap();
+}
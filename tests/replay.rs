@@ -0,0 +1,99 @@
+// Topic replay through the proxy: a new subscriber gets the recent history of
+// a listed topic, marked, before live messages, and when subscribing while a
+// publisher is busy it sees every message exactly once across the handoff.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::replay::is_replay;
+
+fn replaying_proxy(depth: usize) -> ProxyHarness {
+    ProxyHarness::start(move |cfg| {
+        cfg.replay.prefixes = vec!["prices.".to_string()];
+        cfg.replay.depth = depth;
+    })
+}
+
+fn publish(publisher: &zmq::Socket, topic: &str, n: u64) {
+    publisher
+        .send_multipart([topic.as_bytes(), &n.to_be_bytes()[..]], 0)
+        .unwrap();
+}
+
+// (replayed, n) of the next message.
+fn next(subscriber: &zmq::Socket) -> (bool, u64) {
+    let message = subscriber.recv_multipart(0).expect("a message");
+    let replayed = is_replay(&message);
+    let payload = message.last().unwrap();
+    (
+        replayed,
+        u64::from_be_bytes(payload[..].try_into().unwrap()),
+    )
+}
+
+#[test]
+fn history_comes_first_and_marked() {
+    let proxy = replaying_proxy(5);
+    let publisher = proxy.publisher();
+    propagate();
+    // Kept with nobody subscribed.
+    for n in 0..10 {
+        publish(&publisher, "prices.eur", n);
+        publish(&publisher, "news.eur", n);
+    }
+    propagate();
+
+    let subscriber = proxy.subscriber(b"prices.");
+    let history: Vec<(bool, u64)> = (0..5).map(|_| next(&subscriber)).collect();
+    assert_eq!(
+        history,
+        [(true, 5), (true, 6), (true, 7), (true, 8), (true, 9)]
+    );
+    publish(&publisher, "prices.eur", 10);
+    assert_eq!(next(&subscriber), (false, 10));
+    // news. is not listed, so a subscriber to it gets live messages only.
+    let news = proxy.subscriber(b"news.");
+    propagate();
+    publish(&publisher, "news.eur", 11);
+    assert_eq!(next(&news), (false, 11));
+}
+
+#[test]
+fn no_gaps_or_duplicates_while_publishing_during_the_subscription() {
+    const TOTAL: u64 = 20_000;
+    let proxy = replaying_proxy(500);
+    let publisher = proxy.publisher();
+    propagate();
+    let busy = thread::spawn(move || {
+        for n in 0..TOTAL {
+            publish(&publisher, "prices.eur", n);
+            if n % 100 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        publisher
+    });
+    // Join somewhere in the middle of the stream.
+    thread::sleep(Duration::from_millis(50));
+    let subscriber = proxy.subscriber(b"prices.eur");
+
+    let (replayed, first) = next(&subscriber);
+    assert!(replayed, "history comes first");
+    let mut expected = first + 1;
+    let mut live = false;
+    let mut replays = 1;
+    while expected < TOTAL {
+        let (replayed, n) = next(&subscriber);
+        assert_eq!(n, expected, "gap or duplicate (replayed: {})", replayed);
+        assert!(!(live && replayed), "history after live messages");
+        live |= !replayed;
+        replays += replayed as u64;
+        expected += 1;
+    }
+    assert!(live, "the subscription saw live traffic");
+    assert!(replays <= 500);
+    let _publisher = busy.join().unwrap();
+}
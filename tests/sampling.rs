@@ -0,0 +1,136 @@
+// Traffic sampling in the proxy: approximate proportions over a synthetic
+// stream, and rule changes through the admin socket taking effect while the
+// proxy keeps running.
+
+mod common;
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::admin::run_admin;
+use corky_zmq::metrics::Value;
+
+fn published(proxy: &ProxyHarness) -> u64 {
+    proxy
+        .runtime
+        .metrics
+        .snapshot()
+        .into_iter()
+        .find(|s| s.name == "corky_proxy_messages_total" && s.labels == [("direction", "publish")])
+        .map(|s| match s.value {
+            Value::Counter(v) => v,
+            _ => 0,
+        })
+        .unwrap_or(0)
+}
+
+fn wait_for_published(proxy: &ProxyHarness, count: u64) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while published(proxy) < count {
+        assert!(
+            Instant::now() < deadline,
+            "proxy forwarded {}",
+            published(proxy)
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Count samples until the socket goes quiet.
+fn drain(socket: &zmq::Socket) -> Vec<Vec<Vec<u8>>> {
+    let mut samples = Vec::new();
+    while let Ok(sample) = socket.recv_multipart(0) {
+        samples.push(sample);
+    }
+    samples
+}
+
+fn admin(request: &zmq::Socket, command: &str) -> String {
+    request.send(command, 0).unwrap();
+    request.recv_string(0).unwrap().unwrap()
+}
+
+#[test]
+fn samples_follow_rules_set_at_runtime() {
+    let proxy = ProxyHarness::start(|cfg| cfg.admin.endpoint = "inproc://sampling-admin".into());
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let admin_thread = {
+        let (context, config, runtime) = (
+            proxy.context.clone(),
+            proxy.config.clone(),
+            proxy.runtime.clone(),
+        );
+        let shutdown = shutdown.clone();
+        thread::spawn(move || run_admin(&context, &config, &runtime, &shutdown).unwrap())
+    };
+    thread::sleep(Duration::from_millis(50));
+    let request = proxy.context.socket(zmq::REQ).unwrap();
+    request.set_rcvtimeo(5000).unwrap();
+    request.connect(&proxy.config.admin.endpoint).unwrap();
+
+    let publisher = proxy.publisher();
+    publisher.set_sndhwm(0).unwrap();
+    // Publications only reach the proxy while someone subscribes.
+    let subscriber = proxy.subscriber(b"");
+    subscriber.set_rcvhwm(0).unwrap();
+    let samples = proxy.context.socket(zmq::SUB).unwrap();
+    samples.set_rcvhwm(0).unwrap();
+    samples.set_rcvtimeo(300).unwrap();
+    samples.set_subscribe(b"").unwrap();
+    samples
+        .connect(&proxy.config.proxy.sample_endpoint)
+        .unwrap();
+    propagate();
+
+    // No rules: nothing sampled.
+    for _ in 0..1000 {
+        publisher
+            .send_multipart(["prices.fx.EURUSD", "1.08"], 0)
+            .unwrap();
+    }
+    wait_for_published(&proxy, 1000);
+    assert!(drain(&samples).is_empty());
+
+    assert_eq!(admin(&request, "sample set prices. 0.02"), "OK");
+    for i in 0..20_000 {
+        let topic = if i % 2 == 0 {
+            "prices.eq.AAPL"
+        } else {
+            "orders.new"
+        };
+        publisher.send_multipart([topic, "payload"], 0).unwrap();
+    }
+    wait_for_published(&proxy, 21_000);
+    let first = drain(&samples);
+    // 10k matching messages at 2%: expect ~200.
+    assert!(
+        (120..=280).contains(&first.len()),
+        "{} samples",
+        first.len()
+    );
+    for sample in &first {
+        assert_eq!(sample[0], b"sample");
+        let meta: serde_json::Value = serde_json::from_slice(&sample[1]).unwrap();
+        assert_eq!(meta["topic"], "prices.eq.AAPL");
+        assert_eq!(meta["size"], 21);
+        assert_eq!(sample[2], b"prices.eq.AAPL");
+    }
+
+    // Raising the rate applies to the running proxy.
+    assert_eq!(admin(&request, "sample set prices. 0.5"), "OK");
+    for _ in 0..1000 {
+        publisher
+            .send_multipart(["prices.eq.MSFT", "payload"], 0)
+            .unwrap();
+    }
+    wait_for_published(&proxy, 22_000);
+    let second = drain(&samples).len();
+    assert!((400..=600).contains(&second), "{} samples", second);
+
+    assert_eq!(admin(&request, "sample clear"), "OK");
+    shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
+    admin_thread.join().unwrap();
+}
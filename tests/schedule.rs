@@ -0,0 +1,129 @@
+// Delayed delivery through the broker: direct messages and pipeline tasks
+// held until their deadline, a target that connects only after its message
+// fell due, cancellation by the sender, and headers the broker refuses.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{settle, BrokerHarness};
+use corky_zmq::schedule::SCHEDULER_ID;
+
+fn broker() -> BrokerHarness {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let tag = NEXT.fetch_add(1, Ordering::Relaxed);
+    BrokerHarness::start(|cfg| {
+        cfg.schedule.enabled = true;
+        cfg.schedule.max_delay_ms = 60_000;
+        cfg.pipeline.enabled = true;
+        cfg.pipeline.producer_endpoint = format!("inproc://schedule-{tag}-in");
+        cfg.pipeline.consumer_endpoint = format!("inproc://schedule-{tag}-out");
+    })
+}
+
+fn reply_json(socket: &zmq::Socket) -> serde_json::Value {
+    let reply = socket.recv_multipart(0).unwrap();
+    assert_eq!(reply[0], SCHEDULER_ID);
+    serde_json::from_slice(&reply[1]).unwrap()
+}
+
+#[test]
+fn direct_message_arrives_after_its_delay() {
+    let broker = broker();
+    let alice = broker.direct_peer(b"alice");
+    let bob = broker.direct_peer(b"bob");
+    settle();
+
+    let sent = Instant::now();
+    alice
+        .send_multipart([&b"bob"[..], br#"{"delay_ms": 200}"#, b"remind"], 0)
+        .unwrap();
+    assert_eq!(
+        bob.recv_multipart(0).unwrap(),
+        vec![b"alice".to_vec(), b"remind".to_vec()]
+    );
+    let waited = sent.elapsed();
+    assert!(waited >= Duration::from_millis(200), "{:?}", waited);
+    assert!(waited < Duration::from_millis(1000), "{:?}", waited);
+}
+
+#[test]
+fn offline_target_gets_the_message_once_it_connects() {
+    let broker = broker();
+    let alice = broker.direct_peer(b"alice");
+    settle();
+    alice
+        .send_multipart([&b"carol"[..], br#"{"delay_ms": 20}"#, b"later"], 0)
+        .unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(broker.runtime.scheduler.lock().unwrap().is_empty());
+
+    let carol = broker.direct_peer(b"carol");
+    assert_eq!(
+        carol.recv_multipart(0).unwrap(),
+        vec![b"alice".to_vec(), b"later".to_vec()]
+    );
+}
+
+#[test]
+fn sender_cancels_a_pending_message() {
+    let broker = broker();
+    let alice = broker.direct_peer(b"alice");
+    let mallory = broker.direct_peer(b"mallory");
+    let bob = broker.direct_peer(b"bob");
+    bob.set_rcvtimeo(400).unwrap();
+    settle();
+
+    let header = br#"{"delay_ms": 200, "cancel_id": "r1"}"#;
+    alice
+        .send_multipart([&b"bob"[..], header, b"never"], 0)
+        .unwrap();
+    let cancel = br#"{"cancel_id": "r1"}"#;
+    mallory.send_multipart([SCHEDULER_ID, cancel], 0).unwrap();
+    assert_eq!(reply_json(&mallory)["cancelled"], 0);
+    alice.send_multipart([SCHEDULER_ID, cancel], 0).unwrap();
+    assert_eq!(reply_json(&alice)["cancelled"], 1);
+    assert!(bob.recv_multipart(0).is_err());
+}
+
+#[test]
+fn refused_headers_are_answered_with_an_error() {
+    let broker = broker();
+    let alice = broker.direct_peer(b"alice");
+    settle();
+    for header in [&br#"{"delay_ms": 3600000}"#[..], br#"{"delay_ms": "soon"}"#] {
+        alice
+            .send_multipart([&b"bob"[..], header, b"x"], 0)
+            .unwrap();
+        let error = reply_json(&alice);
+        assert_eq!(error["error"]["code"], "schedule_rejected", "{}", error);
+    }
+}
+
+#[test]
+fn pipeline_task_is_held_until_due() {
+    let broker = broker();
+    let producer = broker.context.socket(zmq::PUSH).unwrap();
+    producer.set_linger(0).unwrap();
+    producer
+        .connect(&broker.config.pipeline.producer_endpoint)
+        .unwrap();
+    let consumer = broker.context.socket(zmq::PULL).unwrap();
+    consumer.set_linger(0).unwrap();
+    consumer.set_rcvtimeo(5000).unwrap();
+    consumer
+        .connect(&broker.config.pipeline.consumer_endpoint)
+        .unwrap();
+    settle();
+
+    let sent = Instant::now();
+    producer
+        .send_multipart([&br#"{"delay_ms": 150}"#[..], b"job"], 0)
+        .unwrap();
+    producer.send("now", 0).unwrap();
+    assert_eq!(consumer.recv_multipart(0).unwrap(), vec![b"now".to_vec()]);
+    assert_eq!(consumer.recv_multipart(0).unwrap(), vec![b"job".to_vec()]);
+    assert!(sent.elapsed() >= Duration::from_millis(150));
+}
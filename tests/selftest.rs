@@ -0,0 +1,179 @@
+// The self-test against a broker and proxy over TCP: which checks pass, fail
+// or are skipped as features are switched on and off, and the admin command
+// that runs it.
+
+mod common;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use common::tcp::{self, retry_once};
+use common::{BrokerHarness, ProxyHarness};
+use corky_zmq::admin::run_admin;
+use corky_zmq::config::{AuthMechanism, Config};
+use corky_zmq::runtime::Runtime;
+use corky_zmq::selftest::{self, Report};
+use corky_zmq::zap::ZapHandler;
+
+struct Service {
+    context: zmq::Context,
+    // The broker's config with the proxy's endpoints, as one process has.
+    config: Config,
+    broker: BrokerHarness,
+    _proxy: Option<ProxyHarness>,
+    _zap: Option<ZapHandler>,
+}
+
+impl Service {
+    fn start(with_proxy: bool, customize: impl Fn(&mut Config)) -> Self {
+        let context = zmq::Context::new();
+        let mut defaults = Config::default();
+        customize(&mut defaults);
+        let zap = (defaults.auth.mechanism != AuthMechanism::Null).then(|| {
+            ZapHandler::start(&context, &defaults.auth, &Runtime::new(&defaults)).unwrap()
+        });
+        let proxy = with_proxy.then(|| tcp::proxy_in(&context, &customize));
+        let broker = tcp::broker_in(&context, &customize);
+        // Without a proxy its endpoints are ports nobody listens on.
+        let (xsub, xpub) = match &proxy {
+            Some(proxy) => (
+                proxy.config.network.proxy_xsub_endpoint.clone(),
+                proxy.config.network.proxy_xpub_endpoint.clone(),
+            ),
+            None => (tcp::endpoint(), tcp::endpoint()),
+        };
+        let mut config = (*broker.config).clone();
+        config.network.proxy_xsub_endpoint = xsub;
+        config.network.proxy_xpub_endpoint = xpub;
+        Self {
+            context,
+            config,
+            broker,
+            _proxy: proxy,
+            _zap: zap,
+        }
+    }
+
+    fn selftest(&self) -> Report {
+        selftest::run(&self.context, &self.config, &self.broker.runtime)
+    }
+}
+
+fn statuses(report: &Report) -> Vec<(&'static str, &'static str)> {
+    report
+        .checks
+        .iter()
+        .map(|c| (c.name, c.outcome.label()))
+        .collect()
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("corky-selftest-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn optional_features_are_skipped_by_default() {
+    retry_once("optional_features_are_skipped_by_default", || {
+        let service = Service::start(true, |_| {});
+        let report = service.selftest();
+        assert_eq!(
+            statuses(&report),
+            [
+                ("bind", "pass"),
+                ("ping", "pass"),
+                ("c2c", "pass"),
+                ("pubsub", "pass"),
+                ("store", "skip"),
+                ("zap", "skip"),
+                ("journal", "skip"),
+            ],
+            "{:?}",
+            report
+        );
+        assert!(report.ok());
+    });
+}
+
+#[test]
+fn every_enabled_feature_passes() {
+    let (state, journal) = (scratch("state"), scratch("journal"));
+    retry_once("every_enabled_feature_passes", || {
+        let service = Service::start(true, |cfg| {
+            cfg.storage.enabled = true;
+            cfg.storage.dir = Some(state.display().to_string());
+            cfg.journal.enabled = true;
+            cfg.journal.dir = Some(journal.display().to_string());
+            cfg.auth.mechanism = AuthMechanism::Plain;
+            cfg.auth
+                .users
+                .insert("ops".to_string(), "secret".to_string());
+        });
+        let report = service.selftest();
+        assert!(
+            report.checks.iter().all(|c| c.outcome.label() == "pass"),
+            "{:?}",
+            report
+        );
+        assert_eq!(report.checks.len(), 7);
+    });
+    std::fs::remove_dir_all(&state).unwrap();
+    std::fs::remove_dir_all(&journal).unwrap();
+}
+
+#[test]
+fn a_missing_proxy_fails_the_canary() {
+    retry_once("a_missing_proxy_fails_the_canary", || {
+        let service = Service::start(false, |_| {});
+        let report = service.selftest();
+        assert_eq!(report.outcome("bind").unwrap().label(), "fail");
+        assert_eq!(report.outcome("pubsub").unwrap().label(), "fail");
+        assert_eq!(report.outcome("ping").unwrap().label(), "pass");
+        assert!(!report.ok());
+        assert_eq!(report.to_json()["status"], "fail");
+    });
+}
+
+#[test]
+fn a_canary_the_acl_forbids_is_skipped() {
+    let service = Service::start(true, |cfg| cfg.acl.enabled = true);
+    let report = service.selftest();
+    assert_eq!(report.outcome("pubsub").unwrap().label(), "skip");
+}
+
+#[test]
+fn the_admin_command_answers_with_json() {
+    retry_once("the_admin_command_answers_with_json", || {
+        let mut service = Service::start(true, |_| {});
+        service.config.admin.endpoint = tcp::endpoint();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let admin = {
+            let (context, config) = (service.context.clone(), Arc::new(service.config.clone()));
+            let (runtime, shutdown) = (service.broker.runtime.clone(), shutdown.clone());
+            thread::spawn(move || run_admin(&context, &config, &runtime, &shutdown))
+        };
+        let request = service.context.socket(zmq::REQ).unwrap();
+        request.set_rcvtimeo(tcp::TIMEOUT_MS).unwrap();
+        request.set_linger(0).unwrap();
+        request.connect(&service.config.admin.endpoint).unwrap();
+        request.send("selftest", 0).unwrap();
+        let reply: serde_json::Value =
+            serde_json::from_slice(&request.recv_bytes(0).unwrap()).unwrap();
+        shutdown.store(true, Ordering::SeqCst);
+        admin.join().unwrap().unwrap();
+
+        assert_eq!(reply["status"], "pass", "{}", reply);
+        let checks = reply["checks"].as_array().unwrap();
+        assert_eq!(checks.len(), 7);
+        for check in checks {
+            assert!(check["name"].is_string());
+            assert!(check["ms"].is_u64());
+            assert!(check["detail"].is_string());
+        }
+        assert_eq!(checks[1]["name"], "ping");
+        assert_eq!(checks[1]["status"], "pass");
+    });
+}
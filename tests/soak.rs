@@ -0,0 +1,67 @@
+// The smoke configuration of the soak run: a few seconds of mixed traffic
+// with churn that must come out clean, and a run whose timeout is too tight
+// to keep, which must leave a report behind.
+
+use std::fs;
+use std::time::Duration;
+
+use corky_zmq::soak::{run, SoakConfig};
+
+fn report_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("corky-soak-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn smoke_run_keeps_every_invariant() {
+    let dir = report_dir("clean");
+    let config = SoakConfig {
+        report_dir: dir.clone(),
+        ..SoakConfig::smoke()
+    };
+    let report = run(&config).unwrap();
+    assert!(report.passed(), "{:?}", report.violations);
+    assert_eq!(report.report, None);
+    let tally = &report.tally;
+    assert!(tally.requests > 0 && tally.replies == tally.requests);
+    assert!(tally.direct_sent > 0 && tally.direct_received == tally.direct_sent);
+    assert!(tally.published > 0 && tally.delivered == tally.published);
+    assert!(tally.churned > 0);
+    assert!(!dir.exists());
+}
+
+#[test]
+fn a_violation_leaves_a_report() {
+    let dir = report_dir("violation");
+    // No reply can arrive within a timeout of zero.
+    let config = SoakConfig {
+        duration: Duration::from_secs(1),
+        request_timeout: Duration::ZERO,
+        mix: [1, 0, 0],
+        report_dir: dir.clone(),
+        ..SoakConfig::smoke()
+    };
+    let report = run(&config).unwrap();
+    assert!(!report.passed());
+    assert!(
+        report.violations[0].contains("unanswered"),
+        "{:?}",
+        report.violations
+    );
+    let written = report.report.unwrap();
+    assert!(written.starts_with(&dir));
+    for file in [
+        "violations.txt",
+        "recent.txt",
+        "metrics.prom",
+        "summary.txt",
+    ] {
+        assert!(written.join(file).is_file(), "{}", file);
+    }
+    let recent = fs::read_to_string(written.join("recent.txt")).unwrap();
+    assert!(recent.contains("request soak-rr-"));
+    let metrics = fs::read_to_string(written.join("metrics.prom")).unwrap();
+    assert!(metrics.contains("corky_broker_received_total"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,116 @@
+// Drives a scripted stream of keyed state updates through the proxy and
+// checks that a late joiner (snapshot + live updates) converges to the same
+// state as a subscriber that saw every update.
+
+mod common;
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::state::{Applied, StateReplica, StateUpdate};
+
+const TOPIC: &[u8] = b"$state/instruments";
+const UPDATES: usize = 400;
+
+// Sets, overwrites and deletes over a small key space.
+fn scripted_update(i: usize) -> Vec<Vec<u8>> {
+    let key = format!("SYM{}", (i * 7) % 23).into_bytes();
+    if i % 5 == 4 {
+        vec![TOPIC.to_vec(), key]
+    } else {
+        vec![TOPIC.to_vec(), key, format!("px={i}").into_bytes()]
+    }
+}
+
+fn expected_state() -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut state = HashMap::new();
+    for i in 0..UPDATES {
+        let mut update = scripted_update(i);
+        match update.len() {
+            3 => {
+                let value = update.pop().unwrap();
+                state.insert(update.pop().unwrap(), value);
+            }
+            _ => {
+                state.remove(&update.pop().unwrap());
+            }
+        }
+    }
+    state
+}
+
+fn drain_until(subscriber: &zmq::Socket, replica: &mut StateReplica, seq: u64) {
+    while replica.seq() != Some(seq) {
+        let message = subscriber.recv_multipart(0).expect("update");
+        let update = StateUpdate::parse(&message).expect("sequenced update");
+        assert!(!matches!(replica.apply(update), Applied::Gap { .. }));
+    }
+}
+
+#[test]
+fn late_joiner_converges_with_always_connected_subscriber() {
+    let proxy = ProxyHarness::start(|cfg| cfg.state.enabled = true);
+    let snapshot_endpoint = proxy.config.state.snapshot_endpoint.clone();
+
+    let publisher = proxy.publisher();
+    let early_sub = proxy.subscriber(TOPIC);
+    propagate();
+    let mut early = StateReplica::new(TOPIC);
+    let early_dealer = proxy.context.socket(zmq::DEALER).unwrap();
+    early_dealer.set_rcvtimeo(5000).unwrap();
+    early_dealer.connect(&snapshot_endpoint).unwrap();
+    early.request_snapshot(&early_dealer).unwrap();
+    early.recv_snapshot(&early_dealer).unwrap();
+    assert_eq!(early.seq(), Some(0));
+
+    // Publish the first half, then let a late joiner in while the second
+    // half is still streaming.
+    let stream = thread::spawn(move || {
+        for i in 0..UPDATES {
+            publisher.send_multipart(scripted_update(i), 0).unwrap();
+            if i >= UPDATES / 2 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        publisher
+    });
+
+    thread::sleep(Duration::from_millis(50));
+    let late_sub = proxy.subscriber(TOPIC);
+    propagate();
+    let late_dealer = proxy.context.socket(zmq::DEALER).unwrap();
+    late_dealer.set_rcvtimeo(5000).unwrap();
+    late_dealer.connect(&snapshot_endpoint).unwrap();
+    let mut late = StateReplica::new(TOPIC);
+    // Updates that arrive before the snapshot are buffered by the replica.
+    late.request_snapshot(&late_dealer).unwrap();
+    assert_eq!(late.recv_snapshot(&late_dealer).unwrap(), None);
+    assert!(
+        late.seq().unwrap() > 0,
+        "late joiner should get a non-empty snapshot"
+    );
+
+    let _publisher = stream.join().unwrap();
+    drain_until(&early_sub, &mut early, UPDATES as u64);
+    drain_until(&late_sub, &mut late, UPDATES as u64);
+
+    let expected = expected_state();
+    assert_eq!(early.entries, expected);
+    assert_eq!(late.entries, expected);
+}
+
+#[test]
+fn plain_topics_pass_through_unchanged() {
+    let proxy = ProxyHarness::start(|cfg| cfg.state.enabled = true);
+    let publisher = proxy.publisher();
+    let subscriber = proxy.subscriber(b"");
+    propagate();
+    publisher.send_multipart(["ticks", "a", "b"], 0).unwrap();
+    let received = subscriber.recv_multipart(0).unwrap();
+    assert_eq!(
+        received,
+        vec![b"ticks".to_vec(), b"a".to_vec(), b"b".to_vec()]
+    );
+}
@@ -0,0 +1,146 @@
+// Persistent state across restarts: the state service's latest values and
+// sequence come back when the proxy restarts, and a direct message waiting in
+// the offline queue reaches its target after a broker restart, on each
+// storage backend.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use common::{propagate, settle, BrokerHarness, ProxyHarness};
+use corky_zmq::config::{Config, StorageBackend};
+use corky_zmq::state::StateReplica;
+
+const TOPIC: &[u8] = b"$state/fx";
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("corky-storage-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn with_storage(cfg: &mut Config, backend: StorageBackend, dir: &Path) {
+    cfg.storage.enabled = true;
+    cfg.storage.backend = backend;
+    cfg.storage.dir = Some(dir.display().to_string());
+}
+
+fn snapshot(proxy: &ProxyHarness) -> StateReplica {
+    let dealer = proxy.context.socket(zmq::DEALER).unwrap();
+    dealer.set_rcvtimeo(5000).unwrap();
+    dealer
+        .connect(&proxy.config.state.snapshot_endpoint)
+        .unwrap();
+    let mut replica = StateReplica::new(TOPIC);
+    replica.request_snapshot(&dealer).unwrap();
+    replica.recv_snapshot(&dealer).unwrap();
+    replica
+}
+
+fn latest_values_outlive_the_proxy(backend: StorageBackend, dir: &Path) {
+    let start = || {
+        ProxyHarness::start(|cfg| {
+            cfg.state.enabled = true;
+            with_storage(cfg, backend, dir);
+        })
+    };
+    {
+        let proxy = start();
+        let publisher = proxy.publisher();
+        // Updates only reach the proxy while someone is subscribed.
+        let _subscriber = proxy.subscriber(TOPIC);
+        propagate();
+        publisher
+            .send_multipart([TOPIC, b"EURUSD", b"1.08"], 0)
+            .unwrap();
+        publisher
+            .send_multipart([TOPIC, b"GBPUSD", b"1.27"], 0)
+            .unwrap();
+        publisher.send_multipart([TOPIC, b"GBPUSD"], 0).unwrap();
+        propagate();
+        assert_eq!(snapshot(&proxy).seq(), Some(3));
+    }
+
+    let proxy = start();
+    let replica = snapshot(&proxy);
+    assert_eq!(replica.seq(), Some(3));
+    assert_eq!(replica.entries.len(), 1);
+    assert_eq!(replica.entries[&b"EURUSD".to_vec()], b"1.08");
+    // Live updates carry on the stored sequence.
+    let subscriber = proxy.subscriber(TOPIC);
+    let publisher = proxy.publisher();
+    propagate();
+    publisher
+        .send_multipart([TOPIC, b"EURUSD", b"1.09"], 0)
+        .unwrap();
+    let update = subscriber.recv_multipart(0).unwrap();
+    assert_eq!(update[2], 4u64.to_be_bytes());
+}
+
+fn offline_messages_outlive_the_broker(backend: StorageBackend, dir: &Path) {
+    let start = || {
+        BrokerHarness::start(|cfg| {
+            cfg.schedule.enabled = true;
+            with_storage(cfg, backend, dir);
+        })
+    };
+    {
+        let broker = start();
+        let alice = broker.direct_peer(b"alice");
+        settle();
+        alice
+            .send_multipart(
+                [&b"carol"[..], br#"{"delay_ms": 0}"#, b"while you were out"],
+                0,
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let queued = broker
+            .runtime
+            .metrics
+            .counter("corky_offline_total", &[("outcome", "queued")]);
+        assert_eq!(queued.get(), 1);
+    }
+
+    let broker = start();
+    let carol = broker.direct_peer(b"carol");
+    carol.set_rcvtimeo(3000).unwrap();
+    // Say hello so the broker learns carol's routing id.
+    carol.send_multipart(["nobody", "hi"], 0).unwrap();
+    assert_eq!(
+        carol.recv_multipart(0).unwrap(),
+        vec![b"alice".to_vec(), b"while you were out".to_vec()]
+    );
+}
+
+#[test]
+fn files_keep_latest_values() {
+    let dir = scratch("files-lvc");
+    latest_values_outlive_the_proxy(StorageBackend::Files, &dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn files_keep_offline_messages() {
+    let dir = scratch("files-offline");
+    offline_messages_outlive_the_broker(StorageBackend::Files, &dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "embedded")]
+#[test]
+fn embedded_keeps_latest_values() {
+    let dir = scratch("embedded-lvc");
+    latest_values_outlive_the_proxy(StorageBackend::Embedded, &dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "embedded")]
+#[test]
+fn embedded_keeps_offline_messages() {
+    let dir = scratch("embedded-offline");
+    offline_messages_outlive_the_broker(StorageBackend::Embedded, &dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,69 @@
+// The proxy counts the subscribers of each topic: traffic keeps flowing to
+// the subscribers of a topic while others come and go, and publishers are
+// asked to stop once the last has gone.
+
+mod common;
+
+use common::{propagate, ProxyHarness};
+
+fn publish(publisher: &zmq::Socket, topic: &str) {
+    publisher
+        .send_multipart([topic.as_bytes(), b"payload"], 0)
+        .unwrap();
+}
+
+#[test]
+fn a_topic_stays_subscribed_upstream_until_its_last_subscriber_leaves() {
+    let proxy = ProxyHarness::start(|_| {});
+    let publisher = proxy.publisher();
+    let first = proxy.subscriber(b"ticker.");
+    let second = proxy.subscriber(b"ticker.");
+    propagate();
+    publish(&publisher, "ticker.BTCUSD");
+    assert_eq!(first.recv_multipart(0).unwrap()[0], b"ticker.BTCUSD");
+    assert_eq!(second.recv_multipart(0).unwrap()[0], b"ticker.BTCUSD");
+
+    // One unsubscribes and the other disconnects: each time the rest still
+    // receive.
+    second.set_unsubscribe(b"ticker.").unwrap();
+    let third = proxy.subscriber(b"ticker.");
+    propagate();
+    drop(first);
+    propagate();
+    publish(&publisher, "ticker.ETHUSD");
+    assert_eq!(third.recv_multipart(0).unwrap()[0], b"ticker.ETHUSD");
+    second.set_rcvtimeo(200).unwrap();
+    assert!(second.recv_multipart(0).is_err());
+
+    // With nobody left, a new subscriber is the first again.
+    drop(third);
+    propagate();
+    let fourth = proxy.subscriber(b"ticker.");
+    propagate();
+    publish(&publisher, "ticker.SOLUSD");
+    assert_eq!(fourth.recv_multipart(0).unwrap()[0], b"ticker.SOLUSD");
+}
+
+#[test]
+fn a_repeated_subscription_is_cancelled_when_its_subscriber_disconnects() {
+    let proxy = ProxyHarness::start(|_| {});
+    // An XPUB in the publisher's place sees what the proxy asks of upstream.
+    let upstream = proxy.context.socket(zmq::XPUB).unwrap();
+    upstream.set_rcvtimeo(5000).unwrap();
+    upstream.set_linger(0).unwrap();
+    upstream.set_xpub_verbose(true).unwrap();
+    upstream
+        .connect(&proxy.config.network.proxy_xsub_endpoint)
+        .unwrap();
+    let subscriber = proxy.subscriber(b"ticker.");
+    propagate();
+    subscriber.set_subscribe(b"ticker.").unwrap();
+    propagate();
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x01ticker.");
+
+    drop(subscriber);
+    propagate();
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x00ticker.");
+    upstream.set_rcvtimeo(200).unwrap();
+    assert!(upstream.recv_bytes(0).is_err());
+}
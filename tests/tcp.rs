@@ -0,0 +1,232 @@
+// The broker and proxy over real TCP on localhost: a client that reconnects
+// in the middle of a request, a subscriber joining a running stream, restarts
+// on the same ports, and a shutdown while requests are flowing.
+
+mod common;
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::tcp::{self, retry_once, wait_for};
+use common::{propagate, BrokerHarness};
+
+const PATIENCE: Duration = Duration::from_secs(10);
+
+fn worker(broker: &BrokerHarness) -> zmq::Socket {
+    tcp::dealer(
+        broker,
+        b"svc",
+        &broker.config.network.worker_facing_endpoint,
+    )
+}
+
+fn client(broker: &BrokerHarness) -> zmq::Socket {
+    tcp::dealer(
+        broker,
+        b"svc",
+        &broker.config.network.client_facing_endpoint,
+    )
+}
+
+// Reply to `request` as the worker of client "svc".
+fn answer(worker: &zmq::Socket, request: &[u8]) {
+    worker.send_multipart([&b"svc"[..], request], 0).unwrap();
+    // The broker's echo to the worker.
+    worker.recv_multipart(0).unwrap();
+}
+
+fn round_trip(client: &zmq::Socket, worker: &zmq::Socket, payload: &str) -> Vec<u8> {
+    client.send(payload, 0).unwrap();
+    let request = worker.recv_bytes(0).expect("the request");
+    answer(worker, &request);
+    client.recv_bytes(0).expect("the reply")
+}
+
+// Throws away whatever either side still has queued.
+fn drain(sockets: &[&zmq::Socket]) {
+    thread::sleep(Duration::from_millis(300));
+    for socket in sockets {
+        while socket.recv_multipart(zmq::DONTWAIT).is_ok() {}
+    }
+}
+
+// After a restart both DEALERs reconnect on their own; a request reaches the
+// worker once the broker knows it again, and earlier ones are dropped.
+fn wait_for_reconnect(client: &zmq::Socket, worker: &zmq::Socket) {
+    let mut attempt = 0;
+    wait_for("the worker to reconnect", PATIENCE, || {
+        attempt += 1;
+        client
+            .send(format!("ping {}", attempt).as_str(), 0)
+            .unwrap();
+        worker.poll(zmq::POLLIN, 200).unwrap() > 0
+    });
+    drain(&[client, worker]);
+}
+
+#[test]
+fn client_reconnects_in_the_middle_of_a_request() {
+    retry_once("client_reconnects_in_the_middle_of_a_request", || {
+        let broker = tcp::broker(|_| {});
+        let worker = worker(&broker);
+        let client = client(&broker);
+        assert_eq!(round_trip(&client, &worker, "one"), b"one");
+
+        client.send("two", 0).unwrap();
+        let pending = worker.recv_bytes(0).unwrap();
+        drop(client);
+        // The broker refuses a second connection with the identity of one it
+        // still has, so let the old one close first.
+        thread::sleep(Duration::from_millis(200));
+        let client = self::client(&broker);
+        client.send("three", 0).unwrap();
+        let next = worker.recv_bytes(0).unwrap();
+        assert_eq!(next, b"three");
+
+        // The answer to the request made before the reconnect reaches the
+        // new connection.
+        answer(&worker, &pending);
+        answer(&worker, &next);
+        assert_eq!(client.recv_bytes(0).unwrap(), b"two");
+        assert_eq!(client.recv_bytes(0).unwrap(), b"three");
+    });
+}
+
+#[test]
+fn a_late_subscriber_joins_a_running_stream() {
+    retry_once("a_late_subscriber_joins_a_running_stream", || {
+        let proxy = tcp::proxy(|_| {});
+        let publisher = proxy.publisher();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stream = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut n = 0u64;
+                while !stop.load(Ordering::SeqCst) {
+                    publisher
+                        .send_multipart([&b"ticks"[..], &n.to_be_bytes()], 0)
+                        .unwrap();
+                    n += 1;
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+
+        let subscriber = proxy.subscriber(b"ticks");
+        subscriber.set_rcvtimeo(tcp::TIMEOUT_MS).unwrap();
+        let next = || {
+            let message = subscriber.recv_multipart(0).expect("a tick");
+            u64::from_be_bytes(message[1][..].try_into().unwrap())
+        };
+        // Whatever was published before the subscription arrived is missed
+        // (the slow joiner), but from the first message on nothing is.
+        let first = next();
+        assert!(first > 0);
+        for expected in first + 1..first + 200 {
+            assert_eq!(next(), expected);
+        }
+        stop.store(true, Ordering::SeqCst);
+        stream.join().unwrap();
+    });
+}
+
+#[test]
+fn broker_restarts_on_the_same_ports() {
+    retry_once("broker_restarts_on_the_same_ports", || {
+        let broker = tcp::broker(|_| {});
+        let worker = worker(&broker);
+        let client = client(&broker);
+        assert_eq!(round_trip(&client, &worker, "before"), b"before");
+        let endpoints = broker.config.network.clone();
+
+        let broker = broker.restart();
+        assert!(broker.is_running());
+        assert_eq!(
+            broker.config.network.client_facing_endpoint,
+            endpoints.client_facing_endpoint
+        );
+        wait_for_reconnect(&client, &worker);
+        assert_eq!(round_trip(&client, &worker, "after"), b"after");
+    });
+}
+
+#[test]
+fn proxy_restarts_on_the_same_ports() {
+    retry_once("proxy_restarts_on_the_same_ports", || {
+        let proxy = tcp::proxy(|_| {});
+        let publisher = proxy.publisher();
+        let subscriber = proxy.subscriber(b"news");
+        subscriber.set_rcvtimeo(200).unwrap();
+        let delivered = || {
+            wait_for("a publication to get through", PATIENCE, || {
+                publisher.send_multipart(["news", "flash"], 0).unwrap();
+                subscriber.recv_multipart(0).is_ok()
+            });
+        };
+        propagate();
+        delivered();
+
+        // Both ends reconnect, and the subscriber subscribes again.
+        let proxy = proxy.restart();
+        assert!(proxy.is_running());
+        delivered();
+    });
+}
+
+#[test]
+fn shutdown_under_traffic_is_prompt_and_frees_the_ports() {
+    let broker = tcp::broker(|_| {});
+    let stop = Arc::new(AtomicBool::new(false));
+    let replies = Arc::new(AtomicU64::new(0));
+    let worker = {
+        let (socket, stop) = (worker(&broker), stop.clone());
+        socket.set_rcvtimeo(50).unwrap();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                if let Ok(request) = socket.recv_multipart(0) {
+                    // Requests and echoes alike; only requests are answered.
+                    if request.len() == 1 {
+                        let _ = socket.send_multipart([&b"svc"[..], &request[0]], 0);
+                    }
+                }
+            }
+        })
+    };
+    let client = {
+        let (socket, stop, replies) = (client(&broker), stop.clone(), replies.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let _ = socket.send("request", zmq::DONTWAIT);
+                while socket.recv_bytes(zmq::DONTWAIT).is_ok() {
+                    replies.fetch_add(1, Ordering::Relaxed);
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    wait_for("traffic", PATIENCE, || replies.load(Ordering::Relaxed) > 50);
+
+    let network = broker.config.network.clone();
+    let stopping = Instant::now();
+    drop(broker);
+    let took = stopping.elapsed();
+    assert!(took < Duration::from_secs(5), "shutdown took {:?}", took);
+    stop.store(true, Ordering::SeqCst);
+    worker.join().unwrap();
+    client.join().unwrap();
+
+    for endpoint in [
+        &network.client_to_client_endpoint,
+        &network.client_facing_endpoint,
+        &network.worker_facing_endpoint,
+    ] {
+        let port = tcp::port(endpoint);
+        wait_for("the port to be released", PATIENCE, || {
+            TcpListener::bind(("127.0.0.1", port)).is_ok()
+        });
+    }
+}
@@ -0,0 +1,179 @@
+// Topic allowlist and rewrite rules changed on a running proxy through the
+// admin commands: traffic on a topic stops and starts again without touching
+// its subscribers or other topics, upstream subscriptions follow the rules,
+// and changes survive a restart unless they are ephemeral.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::admin::handle_command;
+
+fn state_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("corky-topics-{}-{}.toml", name, std::process::id()))
+}
+
+fn start(state_file: &Path) -> ProxyHarness {
+    let path = state_file.display().to_string();
+    ProxyHarness::start(move |cfg| cfg.topics.state_file = Some(path))
+}
+
+fn ephemeral() -> ProxyHarness {
+    ProxyHarness::start(|cfg| cfg.topics.ephemeral = true)
+}
+
+fn admin(proxy: &ProxyHarness, command: &str) {
+    assert_eq!(handle_command(&proxy.runtime, command), "OK", "{}", command);
+    // Long enough for the proxy to move its upstream subscriptions.
+    propagate();
+}
+
+fn publish(publisher: &zmq::Socket, topic: &str) {
+    publisher
+        .send_multipart([topic.as_bytes(), b"payload"], 0)
+        .unwrap();
+}
+
+fn topic(subscriber: &zmq::Socket) -> String {
+    let message = subscriber.recv_multipart(0).unwrap();
+    assert_eq!(message[1], b"payload");
+    String::from_utf8(message[0].clone()).unwrap()
+}
+
+#[test]
+fn allowlist_changes_stop_and_restart_one_topic() {
+    let proxy = ephemeral();
+    let everything = proxy.subscriber(b"");
+    let news = proxy.subscriber(b"news.");
+    let publisher = proxy.publisher();
+    propagate();
+
+    publish(&publisher, "orders.1");
+    publish(&publisher, "news.1");
+    assert_eq!(topic(&everything), "orders.1");
+    assert_eq!(topic(&everything), "news.1");
+    assert_eq!(topic(&news), "news.1");
+
+    admin(&proxy, "topics allow add news.");
+    publish(&publisher, "orders.2");
+    publish(&publisher, "news.2");
+    assert_eq!(topic(&everything), "news.2");
+    assert_eq!(topic(&news), "news.2");
+
+    // The same subscribers get orders. again, no resubscribing needed.
+    admin(&proxy, "topics allow add orders.");
+    publish(&publisher, "orders.3");
+    publish(&publisher, "prices.3");
+    publish(&publisher, "news.3");
+    assert_eq!(topic(&everything), "orders.3");
+    assert_eq!(topic(&everything), "news.3");
+    assert_eq!(topic(&news), "news.3");
+}
+
+#[test]
+fn upstream_subscriptions_follow_the_rules() {
+    let proxy = ephemeral();
+    let upstream = proxy.context.socket(zmq::XPUB).unwrap();
+    upstream.set_linger(0).unwrap();
+    upstream.set_rcvtimeo(5000).unwrap();
+    upstream
+        .connect(&proxy.config.network.proxy_xsub_endpoint)
+        .unwrap();
+    let _orders = proxy.subscriber(b"orders.");
+    propagate();
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x01orders.");
+    let _news = proxy.subscriber(b"news.");
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x01news.");
+
+    // No traffic needed for the proxy to notice.
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics allow add news."),
+        "OK"
+    );
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x00orders.");
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics rewrite add legacy. news."),
+        "OK"
+    );
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics allow add legacy."),
+        "OK"
+    );
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x01legacy.");
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics allow remove news."),
+        "OK"
+    );
+    assert_eq!(upstream.recv_bytes(0).unwrap(), b"\x00news.");
+}
+
+#[test]
+fn rewrites_apply_mid_stream() {
+    let proxy = ephemeral();
+    let orders = proxy.subscriber(b"orders.");
+    let legacy = proxy.subscriber(b"legacy.");
+    let publisher = proxy.publisher();
+    propagate();
+
+    publish(&publisher, "legacy.1");
+    publish(&publisher, "orders.1");
+    assert_eq!(topic(&legacy), "legacy.1");
+    assert_eq!(topic(&orders), "orders.1");
+
+    admin(&proxy, "topics rewrite add legacy. orders.");
+    publish(&publisher, "legacy.2");
+    publish(&publisher, "orders.2");
+    assert_eq!(topic(&orders), "orders.2");
+    assert_eq!(topic(&orders), "orders.2");
+
+    admin(&proxy, "topics rewrite remove legacy.");
+    publish(&publisher, "legacy.3");
+    publish(&publisher, "orders.3");
+    assert_eq!(topic(&orders), "orders.3");
+    assert_eq!(topic(&legacy), "legacy.3");
+}
+
+#[test]
+fn changes_survive_a_restart_unless_ephemeral() {
+    let path = state_file("restart");
+    {
+        let proxy = start(&path);
+        assert_eq!(
+            handle_command(&proxy.runtime, "topics allow add news."),
+            "OK"
+        );
+        assert_eq!(
+            handle_command(&proxy.runtime, "topics rewrite add old. news."),
+            "OK"
+        );
+        // The allowlist applies to topics as published.
+        assert_eq!(
+            handle_command(&proxy.runtime, "topics allow add old."),
+            "OK"
+        );
+    }
+    let proxy = start(&path);
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics rules list"),
+        "allow \"news.\"\nallow \"old.\"\nrewrite \"old.\" -> \"news.\""
+    );
+    let news = proxy.subscriber(b"news.");
+    let publisher = proxy.publisher();
+    propagate();
+    publish(&publisher, "orders.1");
+    publish(&publisher, "old.1");
+    assert_eq!(topic(&news), "news.1");
+    drop(proxy);
+    std::fs::remove_file(&path).unwrap();
+
+    let proxy = ProxyHarness::start(|cfg| {
+        cfg.topics.state_file = Some(path.display().to_string());
+        cfg.topics.ephemeral = true;
+    });
+    assert_eq!(
+        handle_command(&proxy.runtime, "topics allow add news."),
+        "OK"
+    );
+    assert!(!path.exists());
+}
@@ -0,0 +1,157 @@
+// Presence watches on the client-to-client socket: a watcher is told when a
+// scripted worker joins, says goodbye, comes back and goes silent, wildcard
+// and prefix watches pick up peers on every socket, and a watcher that
+// disconnects or is evicted from the peer table has its watch dropped.
+
+mod common;
+
+use common::{propagate, settle, BrokerHarness};
+use corky_zmq::fanout::{WORKER_DISCONNECT, WORKER_READY};
+use corky_zmq::watch::WATCH_ID;
+
+fn watching_broker() -> BrokerHarness {
+    BrokerHarness::start(|cfg| {
+        cfg.watch.enabled = true;
+        cfg.gc.worker_ttl_ms = 200;
+    })
+}
+
+fn watcher(broker: &BrokerHarness, identity: &[u8], names: &[&str]) -> zmq::Socket {
+    let socket = broker.direct_peer(identity);
+    let command = serde_json::json!({"cmd": "watch", "names": names});
+    socket.send(command.to_string().as_str(), 0).unwrap();
+    let reply = next(&socket);
+    let mut expected: Vec<&str> = names.to_vec();
+    expected.sort();
+    assert_eq!(reply, serde_json::json!({ "watching": expected }));
+    socket
+}
+
+fn next(watcher: &zmq::Socket) -> serde_json::Value {
+    let message = watcher.recv_multipart(0).expect("a notification");
+    assert_eq!(message[0], WATCH_ID);
+    serde_json::from_slice(&message[1]).unwrap()
+}
+
+// The next notification's event and identity.
+fn event(watcher: &zmq::Socket) -> (String, String) {
+    let event = next(watcher);
+    (
+        event["event"].as_str().unwrap().to_string(),
+        event["identity"].as_str().unwrap().to_string(),
+    )
+}
+
+fn pair(event: &str, identity: &str) -> (String, String) {
+    (event.to_string(), identity.to_string())
+}
+
+fn nothing_more(watcher: &zmq::Socket) {
+    assert_eq!(
+        watcher.poll(zmq::POLLIN, 300).unwrap(),
+        0,
+        "extra notification"
+    );
+}
+
+fn watchers(broker: &BrokerHarness) -> i64 {
+    broker
+        .runtime
+        .metrics
+        .gauge("corky_watch_watchers", &[])
+        .get()
+}
+
+#[test]
+fn a_scripted_worker_lifecycle() {
+    let broker = watching_broker();
+    let ui = watcher(&broker, b"ui", &["pricing-svc"]);
+
+    let worker = broker.worker(b"pricing-svc");
+    worker.send(WORKER_READY, 0).unwrap();
+    assert_eq!(event(&ui), pair("worker.connected", "pricing-svc"));
+    worker.send(WORKER_DISCONNECT, 0).unwrap();
+    assert_eq!(event(&ui), pair("worker.disconnected", "pricing-svc"));
+
+    // Back, then silent past the worker TTL.
+    worker.send(WORKER_READY, 0).unwrap();
+    assert_eq!(event(&ui), pair("worker.connected", "pricing-svc"));
+    let gone = next(&ui);
+    assert_eq!(gone["event"], "worker.pool_expired");
+    assert!(gone["idle_ms"].as_u64().unwrap() >= 200);
+
+    // Other workers are not watched.
+    let other = broker.worker(b"other-svc");
+    other.send(WORKER_READY, 0).unwrap();
+    nothing_more(&ui);
+}
+
+#[test]
+fn wildcard_and_prefix_watches() {
+    let broker = watching_broker();
+    let everything = watcher(&broker, b"ui-all", &["*"]);
+    let quotes = watcher(&broker, b"ui-quotes", &["quotes.*"]);
+    // Each watcher's own first message is news to the other.
+    assert_eq!(event(&everything), pair("direct.connected", "ui-quotes"));
+
+    let client = broker.client(b"quotes.eu");
+    client.send("request", 0).unwrap();
+    let peer = broker.direct_peer(b"chat");
+    peer.send_multipart(["nobody", "hi"], 0).unwrap();
+
+    let mut seen = vec![event(&everything), event(&everything)];
+    seen.sort();
+    assert_eq!(
+        seen,
+        [
+            pair("client.connected", "quotes.eu"),
+            pair("direct.connected", "chat"),
+        ]
+    );
+    assert_eq!(event(&quotes), pair("client.connected", "quotes.eu"));
+    nothing_more(&quotes);
+
+    // Unwatching everything ends the watch.
+    quotes.send(r#"{"cmd": "unwatch"}"#, 0).unwrap();
+    assert_eq!(next(&quotes), serde_json::json!({"watching": []}));
+    assert_eq!(watchers(&broker), 1);
+}
+
+#[test]
+fn a_disconnected_watcher_is_cleaned_up() {
+    let broker = BrokerHarness::start(|cfg| {
+        cfg.watch.enabled = true;
+        cfg.gc.worker_ttl_ms = 200;
+        cfg.broker.max_peers = 4;
+    });
+    let stays = watcher(&broker, b"ui-1", &["svc"]);
+    let leaves = watcher(&broker, b"ui-2", &["svc"]);
+    assert_eq!(watchers(&broker), 2);
+    drop(leaves);
+    propagate();
+
+    let worker = broker.worker(b"svc");
+    worker.send(WORKER_READY, 0).unwrap();
+    assert_eq!(event(&stays), pair("worker.connected", "svc"));
+    settle();
+    assert_eq!(watchers(&broker), 1);
+
+    // Bad commands are answered, not forwarded.
+    stays.send(r#"{"cmd": "watch"}"#, 0).unwrap();
+    assert_eq!(next(&stays)["error"]["code"], "watch_rejected");
+
+    // Then quiet: the worker's expiry is the last thing the watcher hears.
+    assert_eq!(event(&stays), pair("worker.pool_expired", "svc"));
+    // Peers it does not watch push it out of the table, and its watch goes
+    // with it although no notification for it follows.
+    let others: Vec<zmq::Socket> = (1..=4)
+        .map(|i| broker.direct_peer(format!("other-{i}").as_bytes()))
+        .collect();
+    for other in &others {
+        other.send_multipart(["nobody", "hi"], 0).unwrap();
+        settle();
+    }
+    propagate();
+    assert_eq!(watchers(&broker), 0);
+    nothing_more(&stays);
+}
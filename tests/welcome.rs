@@ -0,0 +1,40 @@
+// [proxy] welcome_message: a frame each subscriber receives as it connects,
+// before any publication.
+
+mod common;
+
+use common::{propagate, ProxyHarness};
+use corky_zmq::config::WelcomeMessage;
+
+#[test]
+fn a_new_subscriber_is_welcomed_before_any_data() {
+    let proxy = ProxyHarness::start(|cfg| {
+        cfg.proxy.welcome_message = Some(WelcomeMessage::Text("corky:welcome".into()));
+    });
+    let publisher = proxy.publisher();
+    let early = proxy.subscriber(b"");
+    propagate();
+    assert_eq!(early.recv_bytes(0).unwrap(), b"corky:welcome");
+    publisher.send_multipart(["ticker.BTCUSD", "1"], 0).unwrap();
+    assert_eq!(early.recv_multipart(0).unwrap()[0], b"ticker.BTCUSD");
+
+    // A late one too, while data is flowing, and only the welcome comes
+    // first.
+    let late = proxy.subscriber(b"");
+    publisher.send_multipart(["ticker.BTCUSD", "2"], 0).unwrap();
+    assert_eq!(late.recv_bytes(0).unwrap(), b"corky:welcome");
+    assert_eq!(early.recv_multipart(0).unwrap()[1], b"2");
+}
+
+#[test]
+fn without_one_subscribers_only_see_data() {
+    let proxy = ProxyHarness::start(|_| {});
+    let publisher = proxy.publisher();
+    let subscriber = proxy.subscriber(b"");
+    propagate();
+    publisher.send_multipart(["ticker.BTCUSD", "1"], 0).unwrap();
+    assert_eq!(
+        subscriber.recv_multipart(0).unwrap(),
+        [&b"ticker.BTCUSD"[..], b"1"]
+    );
+}